@@ -0,0 +1,173 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module retries keystore calls that fail with `BACKEND_BUSY`. Blind retries in a tight
+//! loop add to the load that made keystore busy in the first place, so the delay between
+//! attempts follows the retry hint that keystore sends with the error, if any, and backs off
+//! exponentially otherwise. Random jitter keeps clients that were refused at the same time from
+//! retrying in lockstep, and the delay is capped, so that a client never stalls for long.
+
+use std::time::Duration;
+
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+use binder::{ExceptionCode, Status};
+
+/// Precedes the retry hint in the message of the service specific error. This must match
+/// `RETRY_AFTER_MS_PREFIX` in keystore2's error module.
+const RETRY_AFTER_MS_PREFIX: &str = "retry_after_ms=";
+
+/// Decides how often and after which delays a failed call is retried.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry if keystore sent no hint. It doubles with every retry.
+    pub initial_backoff: Duration,
+    /// No delay is longer than this, regardless of the hint.
+    pub max_delay: Duration,
+    /// Up to this fraction of the delay is added at random.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.25,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before retry number `retry`, counting from 0. `hint` is the retry hint
+    /// of the failed call, and `random` a random number in [0, 1) that scales the jitter.
+    pub fn delay(&self, retry: u32, hint: Option<Duration>, random: f64) -> Duration {
+        let base = hint.unwrap_or_else(|| {
+            self.initial_backoff.saturating_mul(1u32.checked_shl(retry).unwrap_or(u32::MAX))
+        });
+        // The jitter only lengthens the delay, because retrying before the hint is futile.
+        base.mul_f64(1.0 + self.jitter * random.clamp(0.0, 1.0)).min(self.max_delay)
+    }
+
+    /// Calls `f` until it succeeds, fails with an error other than `BACKEND_BUSY`, or
+    /// `max_attempts` attempts have failed. Returns the result of the last attempt.
+    pub fn call<T, F>(&self, mut f: F) -> binder::Result<T>
+    where
+        F: FnMut() -> binder::Result<T>,
+    {
+        let mut retry = 0;
+        loop {
+            match f() {
+                Err(status) if is_busy(&status) && retry + 1 < self.max_attempts => {
+                    let delay = self.delay(retry, retry_hint(&status), rand::random());
+                    log::info!("Keystore is busy, retrying in {:?}.", delay);
+                    std::thread::sleep(delay);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn is_busy(status: &Status) -> bool {
+    status.exception_code() == ExceptionCode::SERVICE_SPECIFIC
+        && status.service_specific_error() == ResponseCode::BACKEND_BUSY.0
+}
+
+/// Returns the retry hint that keystore sent with `status`, if any.
+pub fn retry_hint(status: &Status) -> Option<Duration> {
+    parse_retry_hint(&status.get_description())
+}
+
+fn parse_retry_hint(message: &str) -> Option<Duration> {
+    let (_, rest) = message.split_once(RETRY_AFTER_MS_PREFIX)?;
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    rest[..digits].parse().ok().map(Duration::from_millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn busy(message: &str) -> Status {
+        Status::new_service_specific_error_str(ResponseCode::BACKEND_BUSY.0, Some(message))
+    }
+
+    #[test]
+    fn parses_retry_hint() {
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            parse_retry_hint("retry_after_ms=250\n\nCaused by:\n    Error::Rc(BACKEND_BUSY)")
+        );
+        assert_eq!(Some(Duration::from_millis(7)), parse_retry_hint("retry_after_ms=7"));
+        assert_eq!(None, parse_retry_hint("Error::Rc(BACKEND_BUSY)"));
+        assert_eq!(None, parse_retry_hint("retry_after_ms=soon"));
+        assert_eq!(Some(Duration::from_millis(250)), retry_hint(&busy("retry_after_ms=250")));
+    }
+
+    #[test]
+    fn delay_follows_hint_with_jitter_and_cap() {
+        let policy = RetryPolicy::default();
+        let hint = Some(Duration::from_millis(400));
+        assert_eq!(Duration::from_millis(400), policy.delay(3, hint, 0.0));
+        assert_eq!(Duration::from_millis(500), policy.delay(0, hint, 1.0));
+        assert_eq!(policy.max_delay, policy.delay(0, Some(Duration::from_secs(60)), 0.0));
+    }
+
+    #[test]
+    fn delay_backs_off_without_hint() {
+        let policy = RetryPolicy::default();
+        assert_eq!(Duration::from_millis(100), policy.delay(0, None, 0.0));
+        assert_eq!(Duration::from_millis(200), policy.delay(1, None, 0.0));
+        assert_eq!(Duration::from_millis(800), policy.delay(3, None, 0.0));
+        assert_eq!(policy.max_delay, policy.delay(40, None, 0.0));
+    }
+
+    #[test]
+    fn retries_only_busy_errors() {
+        let policy =
+            RetryPolicy { initial_backoff: Duration::from_millis(1), ..Default::default() };
+
+        let attempts = Cell::new(0);
+        let result = policy.call(|| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                Err(busy("retry_after_ms=1"))
+            } else {
+                Ok(attempts.get())
+            }
+        });
+        assert_eq!(3, result.unwrap());
+
+        attempts.set(0);
+        let result: binder::Result<()> = policy.call(|| {
+            attempts.set(attempts.get() + 1);
+            Err(busy("Error::Rc(BACKEND_BUSY)"))
+        });
+        assert!(is_busy(&result.unwrap_err()));
+        assert_eq!(policy.max_attempts, attempts.get());
+
+        attempts.set(0);
+        let result: binder::Result<()> = policy.call(|| {
+            attempts.set(attempts.get() + 1);
+            Err(Status::new_service_specific_error(ResponseCode::KEY_NOT_FOUND.0, None))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, attempts.get());
+    }
+}
@@ -0,0 +1,32 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "dropbox.hpp"
+
+#include <android/os/DropBoxManager.h>
+#include <binder/IServiceManager.h>
+#include <utils/String16.h>
+
+bool dropbox_add_text(const char* tag, const char* text) {
+    // DropBoxManager does not wait for the service, so reports filed while the system server
+    // is still starting would be lost.
+    if (android::defaultServiceManager()->waitForService(android::String16("dropbox")) ==
+        nullptr) {
+        return false;
+    }
+    android::sp<android::os::DropBoxManager> dropbox = new android::os::DropBoxManager();
+    return dropbox->addText(android::String16(tag), text).isOk();
+}
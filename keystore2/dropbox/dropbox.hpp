@@ -0,0 +1,30 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#pragma once
+
+#include <stdbool.h>
+
+extern "C" {
+    /**
+     * Adds a text entry to the DropBox of the system server. Waits for the DropBox service
+     * if it is not yet running.
+     *
+     * @param tag the DropBox tag of the entry, a nul terminated string.
+     * @param text the content of the entry, a nul terminated string.
+     * @return true if the entry was added.
+     */
+    bool dropbox_add_text(const char* tag, const char* text);
+}
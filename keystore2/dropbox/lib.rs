@@ -0,0 +1,33 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rust binding for adding entries to the DropBox of the system server.
+
+use keystore2_dropbox_bindgen::dropbox_add_text;
+use std::ffi::CString;
+
+/// Adds `text` to the DropBox under `tag`. Blocks until the DropBox service is available.
+/// Returns false if the entry could not be added, or if `tag` or `text` contain a nul
+/// character.
+pub fn add_text(tag: &str, text: &str) -> bool {
+    match (CString::new(tag), CString::new(text)) {
+        (Ok(tag), Ok(text)) => {
+            // Safety:
+            // Both arguments are valid nul terminated strings, which dropbox_add_text only
+            // reads for the duration of the call.
+            unsafe { dropbox_add_text(tag.as_ptr(), text.as_ptr()) }
+        }
+        _ => false,
+    }
+}
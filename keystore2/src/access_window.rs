@@ -0,0 +1,218 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module restricts the use of keys to a window of hours of the day, e.g., to business
+//! hours on kiosk and enterprise devices. A caller requests the restriction by passing a key
+//! parameter with the tag `ACCESS_WINDOW` to `generateKey` or `importKey`. The tag is not a
+//! KeyMint tag, so keystore takes it out of the parameters before they reach KeyMint, stores the
+//! window in the metadata of the key, and reports it in the authorizations of the key at
+//! `SecurityLevel::KEYSTORE`.
+//!
+//! The window is given in minutes after midnight in local time. The start is inclusive and the
+//! end is exclusive. A window whose end is before its start spans midnight. `createOperation`
+//! fails with `KEY_NOT_YET_VALID` outside of the window, but operations that began within the
+//! window may finish after it closed. Keys in `Domain::BLOB` have no metadata and cannot be
+//! restricted.
+
+use crate::database::KeyMetaData;
+use crate::error::Error;
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    ErrorCode::ErrorCode, KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue,
+    SecurityLevel::SecurityLevel, Tag::Tag, TagType::TagType,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Authorization::Authorization, Domain::Domain, KeyDescriptor::KeyDescriptor,
+    ResponseCode::ResponseCode,
+};
+use anyhow::{Context, Result};
+use std::time::SystemTime;
+
+/// The tag of the key parameter that restricts a key to a window of hours. Its value is an
+/// `Integer` that holds the first minute of the window in the upper 16 bits and the first
+/// minute after the window in the lower 16 bits. The tag number is far outside of the range
+/// that KeyMint uses.
+pub const ACCESS_WINDOW: Tag = Tag(TagType::UINT.0 | 20000);
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A window of minutes of the day in which a key may be used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessWindow {
+    start: u32,
+    end: u32,
+}
+
+impl AccessWindow {
+    /// Creates the window from minute `start` up to minute `end` of the day.
+    pub fn new(start: u32, end: u32) -> Result<Self> {
+        if start >= MINUTES_PER_DAY || end >= MINUTES_PER_DAY || start == end {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Invalid access window from minute {start} to {end}."));
+        }
+        Ok(Self { start, end })
+    }
+
+    /// Decodes the value of an `ACCESS_WINDOW` key parameter.
+    pub fn decode(value: i32) -> Result<Self> {
+        let value = value as u32;
+        Self::new(value >> 16, value & 0xffff)
+    }
+
+    /// Encodes the window as the value of an `ACCESS_WINDOW` key parameter.
+    pub fn encode(&self) -> i32 {
+        ((self.start << 16) | self.end) as i32
+    }
+
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    fn check_at(&self, minute: u32) -> Result<()> {
+        if self.contains(minute) {
+            Ok(())
+        } else {
+            Err(Error::Km(ErrorCode::KEY_NOT_YET_VALID)).context(ks_err!(
+                "Minute {minute} of the day is outside of the access window {}-{}.",
+                self.start,
+                self.end
+            ))
+        }
+    }
+
+    /// Checks that the key may be used at the time `now`.
+    pub fn check(&self, now: SystemTime) -> Result<()> {
+        self.check_at(local_minute_of_day(now)?)
+    }
+
+    /// Returns the window as an authorization of the key.
+    pub fn to_authorization(self) -> Authorization {
+        Authorization {
+            securityLevel: SecurityLevel::KEYSTORE,
+            keyParameter: KeyParameter {
+                tag: ACCESS_WINDOW,
+                value: KeyParameterValue::Integer(self.encode()),
+            },
+        }
+    }
+}
+
+fn local_minute_of_day(now: SystemTime) -> Result<u32> {
+    let secs: libc::time_t = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .context(ks_err!("Time is before the epoch."))?
+        .as_secs()
+        .try_into()
+        .context(ks_err!("Time is out of range."))?;
+    // SAFETY: libc::tm is plain data, for which all zeros is a valid value.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: Both pointers come from references, and localtime_r does not retain them beyond
+    // the call.
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return Err(Error::sys()).context(ks_err!("localtime_r failed."));
+    }
+    Ok((tm.tm_hour * 60 + tm.tm_min) as u32)
+}
+
+/// Returns the access window that is stored in the metadata of a key, if any.
+pub fn from_metadata(metadata: &KeyMetaData) -> Result<Option<AccessWindow>> {
+    metadata
+        .access_window()
+        .map(|w| AccessWindow::decode(*w))
+        .transpose()
+        .context(ks_err!("Failed to decode the access window."))
+}
+
+/// Removes `ACCESS_WINDOW` from the parameters of the new key `key` and returns the remaining
+/// parameters along with the window, if any.
+pub fn extract(
+    key: &KeyDescriptor,
+    params: &[KeyParameter],
+) -> Result<(Vec<KeyParameter>, Option<AccessWindow>)> {
+    let mut window = None;
+    let mut rest = Vec::with_capacity(params.len());
+    for param in params {
+        if param.tag != ACCESS_WINDOW {
+            rest.push(param.clone());
+            continue;
+        }
+        match (&param.value, window) {
+            (KeyParameterValue::Integer(value), None) => {
+                window = Some(AccessWindow::decode(*value).context(ks_err!())?)
+            }
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Malformed or repeated access window."));
+            }
+        }
+    }
+    if window.is_some() && key.domain == Domain::BLOB {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Keys in Domain::BLOB cannot have an access window."));
+    }
+    Ok((rest, window))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window_param(start: u32, end: u32) -> KeyParameter {
+        KeyParameter {
+            tag: ACCESS_WINDOW,
+            value: KeyParameterValue::Integer(((start << 16) | end) as i32),
+        }
+    }
+
+    #[test]
+    fn windows_may_span_midnight() -> Result<()> {
+        let business_hours = AccessWindow::new(9 * 60, 17 * 60)?;
+        assert!(business_hours.check_at(9 * 60).is_ok());
+        assert!(business_hours.check_at(17 * 60 - 1).is_ok());
+        assert!(business_hours.check_at(17 * 60).is_err());
+        assert!(business_hours.check_at(0).is_err());
+
+        let night_shift = AccessWindow::new(22 * 60, 6 * 60)?;
+        assert!(night_shift.check_at(23 * 60).is_ok());
+        assert!(night_shift.check_at(0).is_ok());
+        assert!(night_shift.check_at(12 * 60).is_err());
+        assert_eq!(night_shift, AccessWindow::decode(night_shift.encode())?);
+        Ok(())
+    }
+
+    #[test]
+    fn extracts_window_from_params() -> Result<()> {
+        let key = KeyDescriptor { domain: Domain::APP, ..Default::default() };
+        let purpose = KeyParameter { tag: Tag::PURPOSE, value: Default::default() };
+        let (rest, window) = extract(&key, &[purpose.clone(), window_param(60, 120)])?;
+        assert_eq!(vec![purpose.clone()], rest);
+        assert_eq!(Some(AccessWindow::new(60, 120)?), window);
+
+        for params in [
+            vec![window_param(60, 60)],
+            vec![window_param(60, MINUTES_PER_DAY)],
+            vec![window_param(60, 120), window_param(180, 240)],
+        ] {
+            assert!(extract(&key, &params).is_err());
+        }
+        let blob = KeyDescriptor { domain: Domain::BLOB, ..Default::default() };
+        assert!(extract(&blob, &[window_param(60, 120)]).is_err());
+        assert_eq!((vec![purpose.clone()], None), extract(&blob, &[purpose])?);
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module detects abnormal use of keys, such as a sudden burst of signatures, or a series
+//! of failed decryptions that looks like a brute-force attempt. The detector counts the events
+//! of each uid and key within the last `WINDOW`. A key whose count reaches the threshold of an
+//! anomaly is flagged for `FLAG_DURATION`, and the anomaly is written to the security log.
+//!
+//! OEMs can set the system property `REQUIRES_AUTH_PROPERTY` to require fresh user
+//! authentication for the use of flagged keys. Operations with a flagged key then require that
+//! the device is unlocked and that the user authenticated recently. The counts and flags are
+//! kept in memory for at most `MAX_TRACKED` uids and keys, so they start over when keystore
+//! restarts. Keys that are used with `Domain::BLOB` have no key id and are not tracked.
+//! Detection is gated by `Feature::AnomalyDetector`.
+
+use crate::audit_log::log_key_usage_anomaly;
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::feature_flags::{self, Feature};
+use crate::globals::ENFORCEMENTS;
+use crate::ks_err;
+use crate::utils::uid_to_android_user;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The system property that, if true, makes flagged keys require fresh user authentication.
+const REQUIRES_AUTH_PROPERTY: &str = "ro.keystore.anomaly_requires_auth";
+
+/// The window over which events are counted.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a key stays flagged after its last anomaly.
+const FLAG_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of tracked uid and key pairs, and of flagged keys.
+const MAX_TRACKED: usize = 256;
+
+/// An abnormal pattern of key use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Anomaly {
+    /// Many signing operations began within the window.
+    MassSigning,
+    /// Many decryptions failed within the window.
+    DecryptFailures,
+}
+
+impl Anomaly {
+    /// The number of events within `WINDOW` at which the anomaly is flagged.
+    fn threshold(&self) -> usize {
+        match self {
+            Self::MassSigning => 600,
+            Self::DecryptFailures => 20,
+        }
+    }
+
+    /// The name of the anomaly in the security log and the dump.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MassSigning => "mass_signing",
+            Self::DecryptFailures => "decrypt_failures",
+        }
+    }
+}
+
+/// A flagged key.
+struct Flag {
+    uid: u32,
+    anomaly: Anomaly,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// The times of the events of each uid, key, and anomaly within the window, oldest first.
+    recent: HashMap<(u32, i64, Anomaly), VecDeque<Instant>>,
+    /// Flagged keys by key id.
+    flagged: HashMap<i64, Flag>,
+}
+
+/// The dump section of the policy and its statistics.
+pub(crate) const ANOMALIES_SECTION: DumpSection = DumpSection {
+    name: "key_usage_anomalies",
+    header: "Key usage anomalies (requires auth, flagged, denied):",
+    line: "  <requires_auth>, <flagged>, <denied>",
+    fields: dump_fields!(requires_auth: "bool", flagged: "uint64", denied: "uint64"),
+};
+
+/// The dump section of the flagged keys.
+pub(crate) const FLAGGED_KEYS_SECTION: DumpSection = DumpSection {
+    name: "flagged_keys",
+    header: "Flagged keys (key id, uid, anomaly, seconds since last anomaly):",
+    line: "  <key_id>, <uid>, <anomaly>, <seconds>",
+    fields: dump_fields!(key_id: "int64", uid: "uint64", anomaly: "string", seconds: "uint64"),
+};
+
+/// Tracks the rates of key use and flags anomalies. See the module documentation.
+#[derive(Default)]
+pub struct AnomalyDetector {
+    /// Whether flagged keys require fresh user authentication.
+    requires_auth: bool,
+    state: Mutex<State>,
+    flagged_total: AtomicU64,
+    denied: AtomicU64,
+}
+
+impl AnomalyDetector {
+    /// Creates a detector. If `requires_auth` is true, flagged keys require fresh user
+    /// authentication.
+    pub fn new(requires_auth: bool) -> Self {
+        Self { requires_auth, ..Default::default() }
+    }
+
+    /// Creates a detector with the policy configured in `REQUIRES_AUTH_PROPERTY`.
+    pub fn from_property() -> Self {
+        Self::new(rustutils::system_properties::read_bool(REQUIRES_AUTH_PROPERTY, false))
+    }
+
+    /// Records that `uid` began a signing operation with the key `key_id`.
+    pub fn on_sign(&self, uid: u32, key_id: i64) {
+        self.record(uid, key_id, Anomaly::MassSigning);
+    }
+
+    /// Records that a decryption of `uid` with the key `key_id` failed.
+    pub fn on_decrypt_failed(&self, uid: u32, key_id: i64) {
+        self.record(uid, key_id, Anomaly::DecryptFailures);
+    }
+
+    fn record(&self, uid: u32, key_id: i64, anomaly: Anomaly) {
+        if !feature_flags::is_enabled(Feature::AnomalyDetector) {
+            return;
+        }
+        if self.record_at(uid, key_id, anomaly, Instant::now()) {
+            log::warn!("Flagged key {} of uid {}: {}.", key_id, uid, anomaly.name());
+            log_key_usage_anomaly(key_id, uid, anomaly.name());
+        }
+    }
+
+    /// Records an event and returns true if it flagged the key.
+    fn record_at(&self, uid: u32, key_id: i64, anomaly: Anomaly, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let tracked = (uid, key_id, anomaly);
+        if !state.recent.contains_key(&tracked) && state.recent.len() >= MAX_TRACKED {
+            // Forget the uids and keys whose events have all left the window.
+            state.recent.retain(|_, events| {
+                events.back().map_or(false, |t| now.duration_since(*t) < WINDOW)
+            });
+            if state.recent.len() >= MAX_TRACKED {
+                return false;
+            }
+        }
+        let events = state.recent.entry(tracked).or_default();
+        while events.front().map_or(false, |t| now.duration_since(*t) >= WINDOW) {
+            events.pop_front();
+        }
+        events.push_back(now);
+        if events.len() < anomaly.threshold() {
+            return false;
+        }
+        // Start counting anew, so that an ongoing anomaly is not reported on every event.
+        events.clear();
+
+        let newly_flagged = state
+            .flagged
+            .get(&key_id)
+            .map_or(true, |flag| now.duration_since(flag.since) >= FLAG_DURATION);
+        if newly_flagged && state.flagged.len() >= MAX_TRACKED {
+            state.flagged.retain(|_, flag| now.duration_since(flag.since) < FLAG_DURATION);
+            if state.flagged.len() >= MAX_TRACKED {
+                // Make room for the new flag at the expense of the oldest one.
+                if let Some(oldest) =
+                    state.flagged.iter().min_by_key(|(_, flag)| flag.since).map(|(id, _)| *id)
+                {
+                    state.flagged.remove(&oldest);
+                }
+            }
+        }
+        state.flagged.insert(key_id, Flag { uid, anomaly, since: now });
+        if newly_flagged {
+            self.flagged_total.fetch_add(1, Ordering::Relaxed);
+        }
+        newly_flagged
+    }
+
+    fn is_flagged_at(&self, key_id: i64, now: Instant) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .flagged
+            .get(&key_id)
+            .map_or(false, |flag| now.duration_since(flag.since) < FLAG_DURATION)
+    }
+
+    /// Checks that `uid` may begin an operation with the key `key_id` on a KeyMint instance of
+    /// `security_level`. If the policy requires it, a flagged key may only be used if the user of
+    /// `uid` authenticated recently.
+    pub fn check_use(&self, uid: u32, key_id: i64, security_level: SecurityLevel) -> Result<()> {
+        if !self.requires_auth
+            || !feature_flags::is_enabled(Feature::AnomalyDetector)
+            || !self.is_flagged_at(key_id, Instant::now())
+        {
+            return Ok(());
+        }
+        ENFORCEMENTS
+            .authorize_recent_user_auth(uid_to_android_user(uid) as i32, security_level)
+            .map_err(|e| {
+                self.denied.fetch_add(1, Ordering::Relaxed);
+                e
+            })
+            .context(ks_err!("Key {key_id} was flagged and requires user authentication."))
+    }
+
+    /// Returns the number of flagged anomalies and of denied uses of flagged keys.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.flagged_total.load(Ordering::Relaxed), self.denied.load(Ordering::Relaxed))
+    }
+
+    /// Writes the policy, the statistics, and the flagged keys to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let (flagged_total, denied) = self.stats();
+        ANOMALIES_SECTION.write_header(writer)?;
+        ANOMALIES_SECTION.write_line(writer, &[&self.requires_auth, &flagged_total, &denied])?;
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        let mut flagged: Vec<(&i64, &Flag)> = state
+            .flagged
+            .iter()
+            .filter(|(_, flag)| now.duration_since(flag.since) < FLAG_DURATION)
+            .collect();
+        flagged.sort_by_key(|(key_id, _)| **key_id);
+        FLAGGED_KEYS_SECTION.write_header(writer)?;
+        for (key_id, flag) in flagged {
+            FLAGGED_KEYS_SECTION.write_line(
+                writer,
+                &[
+                    key_id,
+                    &flag.uid,
+                    &flag.anomaly.name(),
+                    &now.duration_since(flag.since).as_secs(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_n(detector: &AnomalyDetector, n: usize, anomaly: Anomaly, now: Instant) -> usize {
+        (0..n).filter(|_| detector.record_at(10001, 1, anomaly, now)).count()
+    }
+
+    #[test]
+    fn flags_bursts_within_the_window() {
+        let detector = AnomalyDetector::default();
+        let start = Instant::now();
+        let threshold = Anomaly::DecryptFailures.threshold();
+        assert_eq!(0, record_n(&detector, threshold - 1, Anomaly::DecryptFailures, start));
+        // Failures of another uid or with another key are counted separately.
+        assert!(!detector.record_at(10002, 1, Anomaly::DecryptFailures, start));
+        assert!(!detector.record_at(10001, 2, Anomaly::DecryptFailures, start));
+        assert!(!detector.is_flagged_at(1, start));
+
+        // The earlier failures have left the window.
+        let later = start + WINDOW;
+        assert_eq!(0, record_n(&detector, threshold - 1, Anomaly::DecryptFailures, later));
+        assert_eq!(1, record_n(&detector, 1, Anomaly::DecryptFailures, later));
+        assert!(detector.is_flagged_at(1, later));
+        assert!(!detector.is_flagged_at(2, later));
+        assert_eq!((1, 0), detector.stats());
+    }
+
+    #[test]
+    fn ongoing_anomaly_is_reported_once() {
+        let detector = AnomalyDetector::default();
+        let now = Instant::now();
+        let threshold = Anomaly::MassSigning.threshold();
+        assert_eq!(1, record_n(&detector, 3 * threshold, Anomaly::MassSigning, now));
+        assert_eq!((1, 0), detector.stats());
+
+        // The flag expires after its last anomaly.
+        let later = now + FLAG_DURATION / 2;
+        assert_eq!(0, record_n(&detector, threshold, Anomaly::MassSigning, later));
+        assert!(detector.is_flagged_at(1, now + FLAG_DURATION));
+        assert!(!detector.is_flagged_at(1, later + FLAG_DURATION));
+        assert_eq!(1, record_n(&detector, threshold, Anomaly::MassSigning, later + FLAG_DURATION));
+    }
+
+    #[test]
+    fn check_use_without_policy() {
+        let detector = AnomalyDetector::new(false);
+        let now = Instant::now();
+        record_n(&detector, Anomaly::DecryptFailures.threshold(), Anomaly::DecryptFailures, now);
+        assert!(detector.check_use(10001, 1, SecurityLevel::TRUSTED_ENVIRONMENT).is_ok());
+        assert_eq!((1, 0), detector.stats());
+    }
+
+    #[test]
+    fn dump_lists_flagged_keys() {
+        let detector = AnomalyDetector::default();
+        let now = Instant::now();
+        record_n(&detector, Anomaly::DecryptFailures.threshold(), Anomaly::DecryptFailures, now);
+        let mut out = Vec::new();
+        detector.dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\n  false, 1, 0\n"));
+        assert!(out.ends_with(", seconds since last anomaly):\n  1, 10001, decrypt_failures, 0\n"));
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module constructs the value of `Tag::ATTESTATION_APPLICATION_ID`, which KeyMint places
+//! in the attestation certificate to identify the app that owns the key. The value is the DER
+//! encoding of
+//!
+//! ```text
+//! AttestationApplicationId ::= SEQUENCE {
+//!     packageInfos  SET OF AttestationPackageInfo,
+//!     signatureDigests  SET OF OCTET_STRING,
+//! }
+//!
+//! AttestationPackageInfo ::= SEQUENCE {
+//!     packageName  OCTET_STRING,
+//!     version  INTEGER,
+//! }
+//! ```
+//!
+//! The package infos of a uid are obtained from a `PackageInfoProvider`. In production this is
+//! `PackageManagerProvider`, which asks the package manager through the
+//! `sec_key_att_app_id_provider` service. The signature digests are the SHA-256 digests of the
+//! signing certificates of the first package. Apps can only share a uid if they are signed with
+//! the same certificates, so the digests apply to all packages.
+//!
+//! The encoding must not exceed `MAX_SIZE` bytes. Package infos and signature digests that do
+//! not fit are left out, using the same size estimates as the original C++ implementation, so
+//! that existing attestations stay stable.
+
+use crate::error::{map_binder_status, map_binder_status_code, Error};
+use crate::ks_err;
+use android_security_aaid_aidl::aidl::android::security::keystore::IKeyAttestationApplicationIdProvider::IKeyAttestationApplicationIdProvider;
+use anyhow::{Context, Result};
+use keystore2_crypto::sha256;
+
+/// The maximum size of an encoded attestation application id.
+pub const MAX_SIZE: usize = 1024;
+
+/// The service that provides the package infos of a uid.
+const PROVIDER_SERVICE_NAME: &str = "sec_key_att_app_id_provider";
+
+/// The package name that identifies all callers with `AID_SYSTEM`.
+const SYSTEM_PACKAGE_NAME: &str = "AndroidSystem";
+
+/// The package name that is used if the package manager cannot identify the caller.
+const UNKNOWN_PACKAGE_NAME: &str = "UnknownPackage";
+
+const AID_SYSTEM: u32 = 1000;
+
+// Size estimates of the original C++ implementation. They decide which package infos and
+// signature digests fit into `MAX_SIZE` bytes.
+/// Headers of the outer octet string, the sequence, and both sets.
+const GENERAL_OVERHEAD: usize = 16;
+/// Headers of a package info and its package name, and the version.
+const PACKAGE_INFO_OVERHEAD: usize = 15;
+/// A signature digest with its header.
+const SIGNATURE_DIGEST_SIZE: usize = 34;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+
+/// The information about one package of a uid that goes into the attestation application id.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PackageInfo {
+    /// The name of the package.
+    pub package_name: String,
+    /// The version code of the package.
+    pub version_code: i64,
+    /// The signing certificates of the package.
+    pub signatures: Vec<Vec<u8>>,
+}
+
+impl PackageInfo {
+    fn with_name(package_name: &str) -> Self {
+        Self { package_name: package_name.to_string(), version_code: 1, signatures: vec![] }
+    }
+}
+
+/// Provides the packages that run with a given uid.
+pub trait PackageInfoProvider {
+    /// Returns the package infos of all packages of `uid`.
+    fn package_infos(&self, uid: u32) -> Result<Vec<PackageInfo>>;
+}
+
+/// Obtains the package infos from the package manager.
+pub struct PackageManagerProvider;
+
+impl PackageInfoProvider for PackageManagerProvider {
+    fn package_infos(&self, uid: u32) -> Result<Vec<PackageInfo>> {
+        let provider: binder::Strong<dyn IKeyAttestationApplicationIdProvider> =
+            map_binder_status_code(binder::wait_for_interface(PROVIDER_SERVICE_NAME))
+                .context(ks_err!("Failed to connect to {}.", PROVIDER_SERVICE_NAME))?;
+        let id = map_binder_status(provider.getKeyAttestationApplicationId(uid as i32))
+            .context(ks_err!("Failed to get the package infos of uid {}.", uid))?;
+        Ok(id
+            .packageInfos
+            .into_iter()
+            .map(|info| PackageInfo {
+                package_name: info.packageName,
+                version_code: info.versionCode,
+                signatures: info.signatures.into_iter().map(|s| s.data).collect(),
+            })
+            .collect())
+    }
+}
+
+/// Returns the attestation application id of `uid`, as obtained from the package manager.
+pub fn get(uid: u32) -> Result<Vec<u8>> {
+    get_with(&PackageManagerProvider, uid)
+}
+
+/// Returns the attestation application id of `uid`, as obtained from `provider`. Callers with
+/// `AID_SYSTEM` get a fixed id. If `provider` fails, the id names an unknown package, so that
+/// attestation still succeeds.
+pub fn get_with(provider: &dyn PackageInfoProvider, uid: u32) -> Result<Vec<u8>> {
+    let package_infos = if uid == AID_SYSTEM {
+        vec![PackageInfo::with_name(SYSTEM_PACKAGE_NAME)]
+    } else {
+        match provider.package_infos(uid) {
+            Ok(package_infos) => package_infos,
+            Err(e) => {
+                log::warn!("Failed to identify uid {}, attesting an unknown package: {:?}", uid, e);
+                vec![PackageInfo::with_name(UNKNOWN_PACKAGE_NAME)]
+            }
+        }
+    };
+    encode(&package_infos)
+}
+
+/// Encodes `package_infos` as attestation application id, leaving out what does not fit into
+/// `MAX_SIZE` bytes. Fails if `package_infos` is empty.
+pub fn encode(package_infos: &[PackageInfo]) -> Result<Vec<u8>> {
+    let first = package_infos
+        .first()
+        .ok_or_else(Error::sys)
+        .context(ks_err!("The uid has no packages."))?;
+
+    let mut estimated_size = GENERAL_OVERHEAD;
+    let mut encoded_infos = Vec::new();
+    for info in package_infos {
+        estimated_size += PACKAGE_INFO_OVERHEAD + info.package_name.len();
+        if estimated_size > MAX_SIZE {
+            break;
+        }
+        encoded_infos.push(der_tlv(
+            TAG_SEQUENCE,
+            &[
+                der_tlv(TAG_OCTET_STRING, info.package_name.as_bytes()),
+                der_integer(info.version_code as u64),
+            ]
+            .concat(),
+        ));
+    }
+
+    let mut encoded_digests = Vec::new();
+    for signature in &first.signatures {
+        estimated_size += SIGNATURE_DIGEST_SIZE;
+        if estimated_size > MAX_SIZE {
+            break;
+        }
+        let digest = sha256(signature).context(ks_err!("Failed to digest signature."))?;
+        encoded_digests.push(der_tlv(TAG_OCTET_STRING, &digest));
+    }
+
+    Ok(der_tlv(TAG_SEQUENCE, &[der_set_of(encoded_infos), der_set_of(encoded_digests)].concat()))
+}
+
+/// Encodes a DER length.
+fn der_length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes = len.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    let mut encoded = vec![0x80 | (bytes.len() - skip) as u8];
+    encoded.extend_from_slice(&bytes[skip..]);
+    encoded
+}
+
+fn der_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    encoded.extend(der_length(content.len()));
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+/// Encodes a non-negative integer with the minimal number of bytes. A leading zero byte keeps
+/// values with the top bit set from being read as negative.
+fn der_integer(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(bytes.len() - 1);
+    let mut content = Vec::with_capacity(bytes.len() + 1);
+    if bytes[skip] & 0x80 != 0 {
+        content.push(0);
+    }
+    content.extend_from_slice(&bytes[skip..]);
+    der_tlv(TAG_INTEGER, &content)
+}
+
+/// DER requires the elements of a SET OF to be sorted by their encoding.
+fn der_set_of(mut elements: Vec<Vec<u8>>) -> Vec<u8> {
+    elements.sort();
+    der_tlv(TAG_SET, &elements.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedProvider(Option<Vec<PackageInfo>>);
+
+    impl PackageInfoProvider for FixedProvider {
+        fn package_infos(&self, _uid: u32) -> Result<Vec<PackageInfo>> {
+            self.0.clone().ok_or_else(|| Error::sys().into())
+        }
+    }
+
+    fn package(name: &str, signatures: &[&[u8]]) -> PackageInfo {
+        PackageInfo {
+            package_name: name.to_string(),
+            version_code: 1,
+            signatures: signatures.iter().map(|s| s.to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn encodes_package_and_signature() {
+        let info = PackageInfo { version_code: 0x80, ..package("a.b", &[b"cert"]) };
+        let digest = sha256(b"cert").unwrap();
+        let expected = [
+            &[0x30, 0x31][..],
+            // packageInfos
+            &[0x31, 0x0b, 0x30, 0x09, 0x04, 0x03, b'a', b'.', b'b', 0x02, 0x02, 0x00, 0x80],
+            // signatureDigests
+            &[0x31, 0x22, 0x04, 0x20],
+            &digest,
+        ]
+        .concat();
+        assert_eq!(expected, encode(&[info]).unwrap());
+    }
+
+    #[test]
+    fn encodes_integers_minimally() {
+        assert_eq!(vec![0x02, 0x01, 0x00], der_integer(0));
+        assert_eq!(vec![0x02, 0x01, 0x7f], der_integer(0x7f));
+        assert_eq!(vec![0x02, 0x02, 0x00, 0x80], der_integer(0x80));
+        assert_eq!(vec![0x02, 0x02, 0x01, 0x00], der_integer(0x100));
+        // Negative version codes are encoded as their unsigned 64 bit value.
+        assert_eq!([&[0x02, 0x09, 0x00][..], &[0xff; 8]].concat(), der_integer(-1i64 as u64));
+    }
+
+    #[test]
+    fn encodes_long_lengths() {
+        assert_eq!(vec![0x7f], der_length(0x7f));
+        assert_eq!(vec![0x81, 0x80], der_length(0x80));
+        assert_eq!(vec![0x82, 0x01, 0x00], der_length(0x100));
+    }
+
+    #[test]
+    fn sorts_set_elements() {
+        let sorted = encode(&[package("b", &[]), package("a.c", &[]), package("a", &[])]).unwrap();
+        let infos = [
+            &[0x30, 0x06, 0x04, 0x01, b'a', 0x02, 0x01, 0x01][..],
+            &[0x30, 0x06, 0x04, 0x01, b'b', 0x02, 0x01, 0x01],
+            &[0x30, 0x08, 0x04, 0x03, b'a', b'.', b'c', 0x02, 0x01, 0x01],
+        ]
+        .concat();
+        let expected = [&[0x30, 0x1e, 0x31, 0x1a][..], &infos, &[0x31, 0x00]].concat();
+        assert_eq!(expected, sorted);
+    }
+
+    #[test]
+    fn leaves_out_what_does_not_fit() {
+        let long_name = "a".repeat(MAX_SIZE);
+        let encoded = encode(&[package(&long_name, &[])]).unwrap();
+        assert_eq!(vec![0x30, 0x04, 0x31, 0x00, 0x31, 0x00], encoded);
+
+        let signatures = vec![&b"cert"[..]; 35];
+        let encoded = encode(&[package("a", &signatures)]).unwrap();
+        assert!(encoded.len() <= MAX_SIZE);
+        // 29 signature digests fit the estimate. The set of the single package info takes 10
+        // bytes, and the headers of the outer sequence and the digest set take 4 bytes each.
+        assert_eq!(4 + 10 + 4 + 29 * SIGNATURE_DIGEST_SIZE, encoded.len());
+
+        let name = format!("a.reasonable.length.package.name{}", "a".repeat(360));
+        let packages: Vec<_> = (0..4).map(|_| package(&name, &[&b"cert"[..]; 3])).collect();
+        let encoded = encode(&packages).unwrap();
+        assert!(encoded.len() <= MAX_SIZE);
+    }
+
+    #[test]
+    fn rejects_uid_without_packages() {
+        assert!(encode(&[]).is_err());
+        assert!(get_with(&FixedProvider(Some(vec![])), 10001).is_err());
+    }
+
+    #[test]
+    fn system_and_unknown_callers_get_fixed_ids() {
+        let app = FixedProvider(Some(vec![package("com.example", &[b"cert"])]));
+        let failing = FixedProvider(None);
+
+        let system = encode(&[PackageInfo::with_name(SYSTEM_PACKAGE_NAME)]).unwrap();
+        assert_eq!(system, get_with(&app, AID_SYSTEM).unwrap());
+        assert_eq!(system, get_with(&failing, AID_SYSTEM).unwrap());
+
+        let unknown = encode(&[PackageInfo::with_name(UNKNOWN_PACKAGE_NAME)]).unwrap();
+        assert_eq!(unknown, get_with(&failing, 10001).unwrap());
+
+        let expected = encode(&app.0.clone().unwrap()).unwrap();
+        assert_eq!(expected, get_with(&app, 10001).unwrap());
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "attestation_extension.hpp"
+
+#include <android-base/logging.h>
+#include <keymint_support/attestation_record.h>
+#include <openssl/obj.h>
+#include <openssl/x509.h>
+
+#include <algorithm>
+#include <iterator>
+#include <memory>
+#include <vector>
+
+using aidl::android::hardware::security::keymint::AuthorizationSet;
+using aidl::android::hardware::security::keymint::ErrorCode;
+using aidl::android::hardware::security::keymint::kAttestionRecordOid;
+using aidl::android::hardware::security::keymint::KeyParameter;
+using aidl::android::hardware::security::keymint::KeyParameterValue;
+using aidl::android::hardware::security::keymint::parse_attestation_record;
+using aidl::android::hardware::security::keymint::parse_root_of_trust;
+using aidl::android::hardware::security::keymint::SecurityLevel;
+using aidl::android::hardware::security::keymint::VerifiedBoot;
+
+namespace {
+
+struct X509_Delete {
+    void operator()(X509* p) { X509_free(p); }
+};
+
+struct ASN1_OBJECT_Delete {
+    void operator()(ASN1_OBJECT* p) { ASN1_OBJECT_free(p); }
+};
+
+using X509_Ptr = std::unique_ptr<X509, X509_Delete>;
+using ASN1_OBJECT_Ptr = std::unique_ptr<ASN1_OBJECT, ASN1_OBJECT_Delete>;
+
+rust::Vec<uint8_t> toRustVec(const std::vector<uint8_t>& v) {
+    rust::Vec<uint8_t> result;
+    std::copy(v.begin(), v.end(), std::back_inserter(result));
+    return result;
+}
+
+// Returns the integer value of an enumerated, integer, long, or date parameter, and 0 for
+// boolean and blob parameters.
+int64_t integerValue(const KeyParameterValue& value) {
+    switch (value.getTag()) {
+    case KeyParameterValue::algorithm:
+        return static_cast<int32_t>(value.get<KeyParameterValue::algorithm>());
+    case KeyParameterValue::blockMode:
+        return static_cast<int32_t>(value.get<KeyParameterValue::blockMode>());
+    case KeyParameterValue::paddingMode:
+        return static_cast<int32_t>(value.get<KeyParameterValue::paddingMode>());
+    case KeyParameterValue::digest:
+        return static_cast<int32_t>(value.get<KeyParameterValue::digest>());
+    case KeyParameterValue::ecCurve:
+        return static_cast<int32_t>(value.get<KeyParameterValue::ecCurve>());
+    case KeyParameterValue::origin:
+        return static_cast<int32_t>(value.get<KeyParameterValue::origin>());
+    case KeyParameterValue::keyPurpose:
+        return static_cast<int32_t>(value.get<KeyParameterValue::keyPurpose>());
+    case KeyParameterValue::hardwareAuthenticatorType:
+        return static_cast<int32_t>(value.get<KeyParameterValue::hardwareAuthenticatorType>());
+    case KeyParameterValue::securityLevel:
+        return static_cast<int32_t>(value.get<KeyParameterValue::securityLevel>());
+    case KeyParameterValue::integer:
+        return value.get<KeyParameterValue::integer>();
+    case KeyParameterValue::longInteger:
+        return value.get<KeyParameterValue::longInteger>();
+    case KeyParameterValue::dateTime:
+        return value.get<KeyParameterValue::dateTime>();
+    default:
+        return 0;
+    }
+}
+
+rust::Vec<Authorization> convert(const AuthorizationSet& authorizations) {
+    rust::Vec<Authorization> result;
+    for (const KeyParameter& param : authorizations) {
+        Authorization authorization{};
+        authorization.tag = static_cast<int32_t>(param.tag);
+        authorization.integer = integerValue(param.value);
+        if (param.value.getTag() == KeyParameterValue::blob) {
+            authorization.blob = toRustVec(param.value.get<KeyParameterValue::blob>());
+        }
+        result.push_back(std::move(authorization));
+    }
+    return result;
+}
+
+// Returns the value of the attestation extension of certificate, or nullptr if the certificate
+// has none or more than one. The returned object is still part of certificate; don't free it
+// separately.
+const ASN1_OCTET_STRING* getAttestationRecord(const X509* certificate) {
+    ASN1_OBJECT_Ptr oid(OBJ_txt2obj(kAttestionRecordOid, 1 /* dotted string format */));
+    if (!oid) return nullptr;
+
+    int location = X509_get_ext_by_OBJ(certificate, oid.get(), -1 /* search from beginning */);
+    if (location == -1) return nullptr;
+    if (X509_get_ext_by_OBJ(certificate, oid.get(), location) != -1) {
+        LOG(ERROR) << "Duplicate attestation extension.";
+        return nullptr;
+    }
+
+    const X509_EXTENSION* extension = X509_get_ext(certificate, location);
+    if (!extension) return nullptr;
+    return X509_EXTENSION_get_data(extension);
+}
+
+}  // namespace
+
+KeyDescription parse_attestation_extension(rust::Slice<const uint8_t> certificate) {
+    KeyDescription result{};
+    result.error = true;
+
+    const uint8_t* p = certificate.data();
+    X509_Ptr cert(d2i_X509(nullptr /* allocate new */, &p, certificate.size()));
+    if (!cert || p != certificate.data() + certificate.size()) {
+        LOG(ERROR) << "Failed to parse the certificate.";
+        return result;
+    }
+
+    const ASN1_OCTET_STRING* record = getAttestationRecord(cert.get());
+    if (!record) {
+        LOG(ERROR) << "Certificate has no attestation extension.";
+        return result;
+    }
+    const uint8_t* record_data = ASN1_STRING_get0_data(record);
+    size_t record_len = ASN1_STRING_length(record);
+
+    uint32_t attestation_version;
+    SecurityLevel attestation_security_level;
+    uint32_t keymint_version;
+    SecurityLevel keymint_security_level;
+    std::vector<uint8_t> challenge;
+    AuthorizationSet software_enforced;
+    AuthorizationSet hardware_enforced;
+    std::vector<uint8_t> unique_id;
+    ErrorCode error = parse_attestation_record(
+        record_data, record_len, &attestation_version, &attestation_security_level,
+        &keymint_version, &keymint_security_level, &challenge, &software_enforced,
+        &hardware_enforced, &unique_id);
+    if (error != ErrorCode::OK) {
+        LOG(ERROR) << "Failed to parse the attestation record: " << static_cast<int32_t>(error);
+        return result;
+    }
+
+    std::vector<uint8_t> verified_boot_key;
+    VerifiedBoot verified_boot_state;
+    bool device_locked;
+    std::vector<uint8_t> verified_boot_hash;
+    error = parse_root_of_trust(record_data, record_len, &verified_boot_key, &verified_boot_state,
+                                &device_locked, &verified_boot_hash);
+    if (error != ErrorCode::OK) {
+        LOG(ERROR) << "Failed to parse the root of trust: " << static_cast<int32_t>(error);
+        return result;
+    }
+
+    result.attestation_version = static_cast<int32_t>(attestation_version);
+    result.attestation_security_level = static_cast<int32_t>(attestation_security_level);
+    result.keymint_version = static_cast<int32_t>(keymint_version);
+    result.keymint_security_level = static_cast<int32_t>(keymint_security_level);
+    result.attestation_challenge = toRustVec(challenge);
+    result.unique_id = toRustVec(unique_id);
+    result.software_enforced = convert(software_enforced);
+    result.hardware_enforced = convert(hardware_enforced);
+    result.verified_boot_key = toRustVec(verified_boot_key);
+    result.device_locked = device_locked;
+    result.verified_boot_state = static_cast<int32_t>(verified_boot_state);
+    result.verified_boot_hash = toRustVec(verified_boot_hash);
+    result.error = false;
+    return result;
+}
@@ -0,0 +1,55 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bindings for parsing the attestation extension of KeyMint attestation certificates with
+//! libkeymint_support.
+
+#[cxx::bridge]
+mod ffi {
+    /// A parameter of an authorization list. Depending on the type of the tag, the value is
+    /// either in `integer` or in `blob`. Boolean parameters carry no value.
+    struct Authorization {
+        tag: i32,
+        integer: i64,
+        blob: Vec<u8>,
+    }
+
+    /// The contents of the attestation extension. If `error` is set, the certificate or its
+    /// attestation extension could not be parsed and the other fields are not set.
+    struct KeyDescription {
+        error: bool,
+        attestation_version: i32,
+        attestation_security_level: i32,
+        keymint_version: i32,
+        keymint_security_level: i32,
+        attestation_challenge: Vec<u8>,
+        unique_id: Vec<u8>,
+        software_enforced: Vec<Authorization>,
+        hardware_enforced: Vec<Authorization>,
+        verified_boot_key: Vec<u8>,
+        device_locked: bool,
+        verified_boot_state: i32,
+        verified_boot_hash: Vec<u8>,
+    }
+
+    unsafe extern "C++" {
+        include!("attestation_extension.hpp");
+
+        /// Parses the attestation extension of the DER encoded X.509 certificate. The signature
+        /// of the certificate is not verified.
+        fn parse_attestation_extension(certificate: &[u8]) -> KeyDescription;
+    }
+}
+
+pub use ffi::*;
@@ -0,0 +1,143 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements `IKeystoreMaintenance::parseAttestationExtension`, which returns the
+//! attestation extension of a KeyMint attestation certificate, so that system components do
+//! not have to maintain parsers of their own. The certificate and the extension are parsed by
+//! libkeymint_support, the parser that the KeyMint VTS tests use, and this module converts
+//! the result into the AIDL `KeyDescription`.
+
+use crate::error::{Error, ResponseCode};
+use crate::key_parameter::{KeyParameterValue, Primitive};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, SecurityLevel::SecurityLevel, Tag::Tag, TagType::TagType,
+};
+use android_security_maintenance::aidl::android::security::maintenance::KeyDescription::KeyDescription;
+use anyhow::{Context, Result};
+use keystore2_attestation_extension::Authorization;
+
+fn tag_type(tag: Tag) -> TagType {
+    TagType((tag.0 as u32 & 0xf0000000) as i32)
+}
+
+/// Converts the authorizations of an authorization list into KeyParameters. Tags that keystore
+/// does not know are skipped, and so is the root of trust, which is returned separately.
+fn convert_authorizations(authorizations: Vec<Authorization>) -> Vec<KeyParameter> {
+    authorizations
+        .into_iter()
+        .filter_map(|authorization| {
+            let tag = Tag(authorization.tag);
+            if tag == Tag::ROOT_OF_TRUST {
+                return None;
+            }
+            let primitive = match tag_type(tag) {
+                TagType::ULONG | TagType::ULONG_REP | TagType::DATE => {
+                    Primitive::I64(authorization.integer)
+                }
+                TagType::BYTES | TagType::BIGNUM => Primitive::Vec(authorization.blob),
+                _ => Primitive::I32(authorization.integer as i32),
+            };
+            KeyParameterValue::new_from_tag_primitive_pair(tag, primitive).ok().map(Into::into)
+        })
+        .collect()
+}
+
+/// Parses the attestation extension of the DER encoded X.509 certificate `certificate`.
+/// Fails with `INVALID_ARGUMENT` if the certificate or the extension is malformed, or if the
+/// certificate has no attestation extension. The signature of the certificate is not
+/// verified.
+pub fn parse_certificate(certificate: &[u8]) -> Result<KeyDescription> {
+    let parsed = keystore2_attestation_extension::parse_attestation_extension(certificate);
+    if parsed.error {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Malformed certificate or attestation extension."));
+    }
+    Ok(KeyDescription {
+        attestationVersion: parsed.attestation_version,
+        attestationSecurityLevel: SecurityLevel(parsed.attestation_security_level),
+        keyMintVersion: parsed.keymint_version,
+        keyMintSecurityLevel: SecurityLevel(parsed.keymint_security_level),
+        attestationChallenge: parsed.attestation_challenge,
+        uniqueId: parsed.unique_id,
+        softwareEnforced: convert_authorizations(parsed.software_enforced),
+        hardwareEnforced: convert_authorizations(parsed.hardware_enforced),
+        verifiedBootKey: parsed.verified_boot_key,
+        deviceLocked: parsed.device_locked,
+        verifiedBootState: parsed.verified_boot_state,
+        verifiedBootHash: parsed.verified_boot_hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        Algorithm::Algorithm, KeyParameterValue::KeyParameterValue as KmKeyParameterValue,
+        KeyPurpose::KeyPurpose,
+    };
+
+    fn authorization(tag: Tag, integer: i64, blob: &[u8]) -> Authorization {
+        Authorization { tag: tag.0, integer, blob: blob.to_vec() }
+    }
+
+    #[test]
+    fn converts_authorizations() {
+        let converted = convert_authorizations(vec![
+            authorization(Tag::PURPOSE, KeyPurpose::SIGN.0 as i64, &[]),
+            authorization(Tag::ALGORITHM, Algorithm::EC.0 as i64, &[]),
+            authorization(Tag::KEY_SIZE, 256, &[]),
+            authorization(Tag::NO_AUTH_REQUIRED, 0, &[]),
+            authorization(Tag::CREATION_DATETIME, 0x018f00000000, &[]),
+            authorization(Tag::ATTESTATION_APPLICATION_ID, 0, b"app id"),
+            authorization(Tag::ROOT_OF_TRUST, 0, b"root of trust"),
+            // Unknown tags are skipped.
+            authorization(Tag(0x7000270f), 0, b"future"),
+        ]);
+
+        assert_eq!(
+            vec![
+                KeyParameter {
+                    tag: Tag::PURPOSE,
+                    value: KmKeyParameterValue::KeyPurpose(KeyPurpose::SIGN)
+                },
+                KeyParameter {
+                    tag: Tag::ALGORITHM,
+                    value: KmKeyParameterValue::Algorithm(Algorithm::EC)
+                },
+                KeyParameter { tag: Tag::KEY_SIZE, value: KmKeyParameterValue::Integer(256) },
+                KeyParameter {
+                    tag: Tag::NO_AUTH_REQUIRED,
+                    value: KmKeyParameterValue::BoolValue(true)
+                },
+                KeyParameter {
+                    tag: Tag::CREATION_DATETIME,
+                    value: KmKeyParameterValue::DateTime(0x018f00000000)
+                },
+                KeyParameter {
+                    tag: Tag::ATTESTATION_APPLICATION_ID,
+                    value: KmKeyParameterValue::Blob(b"app id".to_vec())
+                },
+            ],
+            converted
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_certificates() {
+        assert!(parse_certificate(&[]).is_err());
+        assert!(parse_certificate(&[0x30, 0x80, 0x00, 0x00]).is_err());
+        assert!(parse_certificate(b"not a certificate").is_err());
+    }
+}
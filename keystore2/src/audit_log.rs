@@ -26,6 +26,8 @@ const TAG_KEY_GENERATED: u32 = 210024;
 const TAG_KEY_IMPORTED: u32 = 210025;
 const TAG_KEY_DESTROYED: u32 = 210026;
 const TAG_KEY_INTEGRITY_VIOLATION: u32 = 210032;
+const TAG_KEY_USAGE_ANOMALY: u32 = 210046;
+const TAG_KEY_PATCH_LEVEL_ROLLBACK_ALLOWED: u32 = 210047;
 
 const FLAG_NAMESPACE: i64 = 0x80000000;
 
@@ -64,6 +66,26 @@ pub fn log_key_integrity_violation(key: &KeyDescriptor) {
     })
 }
 
+pub fn log_key_usage_anomaly(key_id: i64, calling_app: uid_t, anomaly: &str) {
+    with_log_context(TAG_KEY_USAGE_ANOMALY, |ctx| {
+        ctx.append_str(anomaly)?.append_i64(key_id)?.append_i32(calling_app as i32)
+    })
+}
+
+pub fn log_key_patch_level_rollback_allowed(
+    key_id: i64,
+    calling_app: uid_t,
+    key_patch_level: i32,
+    current_patch_level: i32,
+) {
+    with_log_context(TAG_KEY_PATCH_LEVEL_ROLLBACK_ALLOWED, |ctx| {
+        ctx.append_i64(key_id)?
+            .append_i32(calling_app as i32)?
+            .append_i32(key_patch_level)?
+            .append_i32(current_patch_level)
+    })
+}
+
 fn log_key_event(tag: u32, key: &KeyDescriptor, calling_app: uid_t, success: bool) {
     with_log_context(tag, |ctx| {
         let owner = key_owner(key.domain, key.nspace, calling_app as i32);
@@ -17,11 +17,15 @@
 use crate::ks_err;
 use crate::error::Error as KeystoreError;
 use crate::error::anyhow_error_to_cstring;
+use crate::feature_flags::{self, Feature};
 use crate::globals::{ENFORCEMENTS, SUPER_KEY, DB, LEGACY_IMPORTER};
 use crate::permission::KeystorePerm;
+use crate::reboot_escrow::KeyMintSealer;
+use crate::super_key::SuperKeyManager;
+use crate::synthetic_password::SyntheticPasswordToken;
 use crate::utils::{check_keystore_permission, watchdog as wd};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    HardwareAuthToken::HardwareAuthToken,
+    HardwareAuthToken::HardwareAuthToken, HardwareAuthenticatorType::HardwareAuthenticatorType,
 };
 use android_security_authorization::binder::{BinderFeatures, ExceptionCode, Interface, Result as BinderResult,
     Strong, Status as BinderStatus};
@@ -138,6 +142,22 @@ impl AuthorizationManager {
         );
 
         ENFORCEMENTS.add_auth_token(auth_token.clone());
+        if auth_token.authenticatorType.0 & HardwareAuthenticatorType::FINGERPRINT.0 != 0 {
+            // The super keys are decrypted by KeyMint without holding the super key lock.
+            let (users, epoch) = SUPER_KEY.read().unwrap().users_without_biometric_bound_key();
+            if !users.is_empty() {
+                let keys = DB.with(|db| {
+                    SuperKeyManager::decrypt_biometric_bound_keys(
+                        &mut db.borrow_mut(),
+                        &users,
+                        auth_token,
+                    )
+                });
+                if !keys.is_empty() {
+                    SUPER_KEY.write().unwrap().add_biometric_bound_keys(keys, epoch);
+                }
+            }
+        }
         Ok(())
     }
 
@@ -206,6 +226,51 @@ impl AuthorizationManager {
         }
     }
 
+    fn on_device_unlocked_with_token(&self, user_id: i32, token: &[u8]) -> Result<()> {
+        log::info!("on_device_unlocked_with_token(user_id={:?})", user_id);
+        check_keystore_permission(KeystorePerm::Unlock).context(ks_err!("Unlock with token."))?;
+        let token = SyntheticPasswordToken::parse(token).context(ks_err!("Unlock with token."))?;
+        ENFORCEMENTS.set_device_locked(user_id, false);
+
+        let mut skm = SUPER_KEY.write().unwrap();
+        DB.with(|db| {
+            skm.unlock_user_with_token(
+                &mut db.borrow_mut(),
+                &LEGACY_IMPORTER,
+                user_id as u32,
+                &token,
+            )
+        })
+        .context(ks_err!("Unlock with token."))
+    }
+
+    fn on_device_unlocked_with_reboot_escrow(
+        &self,
+        user_id: i32,
+        escrow_secret: &[u8],
+    ) -> Result<()> {
+        log::info!("on_device_unlocked_with_reboot_escrow(user_id={:?})", user_id);
+        check_keystore_permission(KeystorePerm::Unlock)
+            .context(ks_err!("Unlock with reboot escrow."))?;
+        // Without the feature there is no copy, and the user unlocks with the LSKF.
+        if !feature_flags::is_enabled(Feature::RebootEscrow) {
+            return Err(KeystoreError::Rc(KsResponseCode::KEY_NOT_FOUND))
+                .context(ks_err!("Reboot escrow is not enabled."));
+        }
+        // The user did not unlock the device, so it stays locked for UnlockedDeviceRequired keys.
+        let mut skm = SUPER_KEY.write().unwrap();
+        DB.with(|db| {
+            skm.unlock_user_with_reboot_escrow(
+                &mut db.borrow_mut(),
+                &LEGACY_IMPORTER,
+                user_id as u32,
+                escrow_secret,
+                &KeyMintSealer,
+            )
+        })
+        .context(ks_err!("Unlock with reboot escrow."))
+    }
+
     fn get_auth_tokens_for_credstore(
         &self,
         challenge: i64,
@@ -258,6 +323,20 @@ impl IKeystoreAuthorization for AuthorizationManager {
         )
     }
 
+    fn onDeviceUnlockedWithToken(&self, user_id: i32, token: &[u8]) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreAuthorization::onDeviceUnlockedWithToken", 500);
+        map_or_log_err(self.on_device_unlocked_with_token(user_id, token), Ok)
+    }
+
+    fn onDeviceUnlockedWithRebootEscrow(
+        &self,
+        user_id: i32,
+        escrow_secret: &[u8],
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreAuthorization::onDeviceUnlockedWithRebootEscrow", 500);
+        map_or_log_err(self.on_device_unlocked_with_reboot_escrow(user_id, escrow_secret), Ok)
+    }
+
     fn getAuthTokensForCredStore(
         &self,
         challenge: i64,
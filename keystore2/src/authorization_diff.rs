@@ -0,0 +1,305 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module compares the parameters that a client requested for a new key with the
+//! authorizations that are actually enforced for it. KeyMint may silently drop parameters it
+//! does not support or replace them with different values, which otherwise only shows up
+//! much later when the key does not behave as expected.
+//!
+//! The diff groups parameters by tag. A requested tag is "dropped" if it is not enforced at
+//! any security level, and "modified" if it is enforced with a different set of values.
+//! Enforced tags that were not requested are "added". Each enforced parameter carries its
+//! security level, which tells apart what KeyMint enforces from what keystore enforces.
+//!
+//! Clients get the diff of a new key from
+//! `IKeystoreSecurityLevelExtension::generateKeyWithAuthorizationDiff`.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyCharacteristics::KeyCharacteristics, KeyParameter::KeyParameter,
+    SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use android_security_keystoreextension::aidl::android::security::keystoreextension::AuthorizationDiff::AuthorizationDiff as AidlAuthorizationDiff;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Authorization::Authorization, KeyDescriptor::KeyDescriptor,
+};
+use std::collections::BTreeMap;
+
+/// Tags that are consumed during key creation and are never returned as authorizations.
+/// Requesting them without seeing them enforced is not a mismatch.
+const CREATION_ONLY_TAGS: &[Tag] = &[
+    Tag::APPLICATION_DATA,
+    Tag::APPLICATION_ID,
+    Tag::ATTESTATION_APPLICATION_ID,
+    Tag::ATTESTATION_CHALLENGE,
+    Tag::ATTESTATION_ID_BRAND,
+    Tag::ATTESTATION_ID_DEVICE,
+    Tag::ATTESTATION_ID_IMEI,
+    Tag::ATTESTATION_ID_MANUFACTURER,
+    Tag::ATTESTATION_ID_MEID,
+    Tag::ATTESTATION_ID_MODEL,
+    Tag::ATTESTATION_ID_PRODUCT,
+    Tag::ATTESTATION_ID_SECOND_IMEI,
+    Tag::ATTESTATION_ID_SERIAL,
+    Tag::CERTIFICATE_NOT_AFTER,
+    Tag::CERTIFICATE_NOT_BEFORE,
+    Tag::CERTIFICATE_SERIAL,
+    Tag::CERTIFICATE_SUBJECT,
+    Tag::DEVICE_UNIQUE_ATTESTATION,
+    Tag::INCLUDE_UNIQUE_ID,
+    Tag::RESET_SINCE_ID_ROTATION,
+];
+
+/// An authorization together with the security level that enforces it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnforcedParameter {
+    /// The enforced parameter.
+    pub param: KeyParameter,
+    /// The security level that enforces the parameter.
+    pub security_level: SecurityLevel,
+}
+
+impl EnforcedParameter {
+    /// True if the parameter is enforced by KeyMint, i.e., by the TEE or StrongBox, rather
+    /// than by keystore.
+    pub fn is_keymint_enforced(&self) -> bool {
+        matches!(self.security_level, SecurityLevel::TRUSTED_ENVIRONMENT | SecurityLevel::STRONGBOX)
+    }
+
+    fn to_authorization(&self) -> Authorization {
+        Authorization { securityLevel: self.security_level, keyParameter: self.param.clone() }
+    }
+}
+
+/// A tag that was requested with values other than those that are enforced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModifiedTag {
+    /// The tag.
+    pub tag: Tag,
+    /// All requested parameters with this tag.
+    pub requested: Vec<KeyParameter>,
+    /// All enforced parameters with this tag.
+    pub enforced: Vec<EnforcedParameter>,
+}
+
+/// The difference between the requested parameters and the enforced authorizations of a key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthorizationDiff {
+    /// Requested parameters whose tag is not enforced at any security level.
+    pub dropped: Vec<KeyParameter>,
+    /// Tags that are enforced with values other than the requested ones.
+    pub modified: Vec<ModifiedTag>,
+    /// Enforced parameters whose tag was not requested, e.g., OS_VERSION or CREATION_DATETIME.
+    pub added: Vec<EnforcedParameter>,
+}
+
+impl AuthorizationDiff {
+    /// Computes the diff between `requested` and the authorizations in `characteristics`.
+    pub fn new(requested: &[KeyParameter], characteristics: &[KeyCharacteristics]) -> Self {
+        let mut requested_by_tag: BTreeMap<Tag, Vec<KeyParameter>> = BTreeMap::new();
+        for param in requested.iter().filter(|p| !CREATION_ONLY_TAGS.contains(&p.tag)) {
+            requested_by_tag.entry(param.tag).or_default().push(param.clone());
+        }
+        let mut enforced_by_tag: BTreeMap<Tag, Vec<EnforcedParameter>> = BTreeMap::new();
+        for c in characteristics {
+            for param in &c.authorizations {
+                enforced_by_tag.entry(param.tag).or_default().push(EnforcedParameter {
+                    param: param.clone(),
+                    security_level: c.securityLevel,
+                });
+            }
+        }
+
+        let mut diff = Self::default();
+        for (tag, mut requested) in requested_by_tag {
+            match enforced_by_tag.remove(&tag) {
+                None => diff.dropped.append(&mut requested),
+                Some(enforced) => {
+                    // Compare as sets, the order and multiplicity of values do not matter.
+                    let same_values =
+                        requested.iter().all(|r| enforced.iter().any(|e| e.param.value == r.value))
+                            && enforced
+                                .iter()
+                                .all(|e| requested.iter().any(|r| r.value == e.param.value));
+                    if !same_values {
+                        diff.modified.push(ModifiedTag { tag, requested, enforced });
+                    }
+                }
+            }
+        }
+        diff.added = enforced_by_tag.into_values().flatten().collect();
+        diff
+    }
+
+    /// True if every requested parameter is enforced with the requested values.
+    pub fn is_faithful(&self) -> bool {
+        self.dropped.is_empty() && self.modified.is_empty()
+    }
+
+    /// The added parameters that are enforced by KeyMint.
+    pub fn keymint_added(&self) -> impl Iterator<Item = &EnforcedParameter> {
+        self.added.iter().filter(|e| e.is_keymint_enforced())
+    }
+
+    /// The added parameters that are enforced by keystore.
+    pub fn keystore_added(&self) -> impl Iterator<Item = &EnforcedParameter> {
+        self.added.iter().filter(|e| !e.is_keymint_enforced())
+    }
+
+    /// Converts the diff into the report that is returned to the client.
+    pub fn to_aidl(&self) -> AidlAuthorizationDiff {
+        AidlAuthorizationDiff {
+            dropped: self.dropped.clone(),
+            modifiedRequested: self.modified.iter().flat_map(|m| m.requested.clone()).collect(),
+            modifiedEnforced: self
+                .modified
+                .iter()
+                .flat_map(|m| m.enforced.iter().map(EnforcedParameter::to_authorization))
+                .collect(),
+            added: self.added.iter().map(EnforcedParameter::to_authorization).collect(),
+        }
+    }
+}
+
+/// Computes the authorization diff of a newly created key and logs a warning if KeyMint
+/// dropped or modified any of the requested parameters. Returns the diff.
+pub fn check_new_key(
+    key: &KeyDescriptor,
+    requested: &[KeyParameter],
+    characteristics: &[KeyCharacteristics],
+) -> AuthorizationDiff {
+    let diff = AuthorizationDiff::new(requested, characteristics);
+    if !diff.is_faithful() {
+        log::warn!(
+            "Authorizations of new key {:?} differ from the request. Dropped: {:?} Modified: {:?}",
+            key.alias,
+            diff.dropped,
+            diff.modified
+        );
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        Algorithm::Algorithm, Digest::Digest, KeyParameterValue::KeyParameterValue,
+        KeyPurpose::KeyPurpose,
+    };
+
+    fn kp(tag: Tag, value: KeyParameterValue) -> KeyParameter {
+        KeyParameter { tag, value }
+    }
+
+    fn characteristics(
+        security_level: SecurityLevel,
+        authorizations: Vec<KeyParameter>,
+    ) -> KeyCharacteristics {
+        KeyCharacteristics { securityLevel: security_level, authorizations }
+    }
+
+    #[test]
+    fn faithful_key_has_only_additions() {
+        let requested = vec![
+            kp(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+            kp(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+            kp(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::VERIFY)),
+            kp(Tag::ATTESTATION_CHALLENGE, KeyParameterValue::Blob(vec![1, 2, 3])),
+        ];
+        let diff = AuthorizationDiff::new(
+            &requested,
+            &[
+                characteristics(
+                    SecurityLevel::TRUSTED_ENVIRONMENT,
+                    vec![
+                        kp(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::VERIFY)),
+                        kp(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+                        kp(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+                        kp(Tag::OS_VERSION, KeyParameterValue::Integer(140000)),
+                    ],
+                ),
+                characteristics(
+                    SecurityLevel::KEYSTORE,
+                    vec![kp(Tag::CREATION_DATETIME, KeyParameterValue::DateTime(1000))],
+                ),
+            ],
+        );
+        assert!(diff.is_faithful());
+        assert_eq!(
+            vec![Tag::OS_VERSION],
+            diff.keymint_added().map(|e| e.param.tag).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Tag::CREATION_DATETIME],
+            diff.keystore_added().map(|e| e.param.tag).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn dropped_and_modified_parameters() {
+        let requested = vec![
+            kp(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+            kp(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+            kp(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_512)),
+            kp(Tag::ROLLBACK_RESISTANCE, KeyParameterValue::BoolValue(true)),
+        ];
+        let enforced_digest = EnforcedParameter {
+            param: kp(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+            security_level: SecurityLevel::STRONGBOX,
+        };
+        let diff = AuthorizationDiff::new(
+            &requested,
+            &[characteristics(
+                SecurityLevel::STRONGBOX,
+                vec![
+                    kp(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+                    enforced_digest.param.clone(),
+                ],
+            )],
+        );
+        assert!(!diff.is_faithful());
+        assert_eq!(vec![requested[3].clone()], diff.dropped);
+        assert_eq!(
+            vec![ModifiedTag {
+                tag: Tag::DIGEST,
+                requested: requested[1..3].to_vec(),
+                enforced: vec![enforced_digest],
+            }],
+            diff.modified
+        );
+        assert!(diff.added.is_empty());
+
+        let report = diff.to_aidl();
+        assert_eq!(vec![requested[3].clone()], report.dropped);
+        assert_eq!(requested[1..3].to_vec(), report.modifiedRequested);
+        assert_eq!(
+            vec![Authorization {
+                securityLevel: SecurityLevel::STRONGBOX,
+                keyParameter: enforced_digest.param.clone(),
+            }],
+            report.modifiedEnforced
+        );
+        assert!(report.added.is_empty());
+    }
+
+    #[test]
+    fn tag_enforced_at_other_security_level_is_not_dropped() {
+        let requested = vec![kp(Tag::USAGE_COUNT_LIMIT, KeyParameterValue::Integer(1))];
+        let diff = AuthorizationDiff::new(
+            &requested,
+            &[characteristics(SecurityLevel::KEYSTORE, requested.clone())],
+        );
+        assert_eq!(AuthorizationDiff::default(), diff);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module defines where the keys of protected VM instances live. virtualizationservice
+//! stores them in its `keystore2_key` namespace `AVF_VM_KEY_NAMESPACE`. Within it, the keys of
+//! each VM instance have aliases that start with `vm_instance:<hex encoded instance id>:`, so
+//! that all keys of an instance can be deleted together when virtualizationservice deletes the
+//! instance, see `IKeystoreMaintenance::deleteVmInstanceKeys`. Other aliases in the namespace
+//! predate VM instance keys and are left alone.
+
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+
+/// The `keystore2_key` namespace of the keys of protected VM instances. This is the
+/// `vm_payload_key` namespace. Keep in sync with system/sepolicy/private/keystore2_key_contexts.
+pub const AVF_VM_KEY_NAMESPACE: i64 = 140;
+
+/// The length of a VM instance id as assigned by virtualizationservice.
+pub const VM_INSTANCE_ID_LEN: usize = 64;
+
+/// Prefix of the aliases of all VM instance keys.
+const VM_INSTANCE_ALIAS_PREFIX: &str = "vm_instance:";
+
+/// Returns the alias prefix of the keys of the VM instance `instance_id`. Fails with
+/// `INVALID_ARGUMENT` if `instance_id` does not have the length of a VM instance id.
+pub fn instance_alias_prefix(instance_id: &[u8]) -> Result<String> {
+    if instance_id.len() != VM_INSTANCE_ID_LEN {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+            "A VM instance id has {} bytes, but {} were given.",
+            VM_INSTANCE_ID_LEN,
+            instance_id.len()
+        ));
+    }
+    let hex: String = instance_id.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("{}{}:", VM_INSTANCE_ALIAS_PREFIX, hex))
+}
+
+/// Checks that a key created under `key` with an alias in the VM instance alias range of the
+/// AVF namespace names a well formed instance id, so that it is deleted with the instance. Fails
+/// with `INVALID_ARGUMENT` otherwise. Other aliases and keys in other namespaces are not
+/// affected.
+pub fn check_vm_key_alias(key: &KeyDescriptor) -> Result<()> {
+    if key.domain != Domain::SELINUX || key.nspace != AVF_VM_KEY_NAMESPACE {
+        return Ok(());
+    }
+    let instance_alias =
+        match key.alias.as_deref().and_then(|alias| alias.strip_prefix(VM_INSTANCE_ALIAS_PREFIX)) {
+            Some(instance_alias) => instance_alias,
+            None => return Ok(()),
+        };
+    let well_formed = instance_alias.split_once(':').map_or(false, |(hex, _)| {
+        // Upper case digits would not match the prefix that the instance is deleted with.
+        hex.len() == 2 * VM_INSTANCE_ID_LEN
+            && hex.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+    });
+    if well_formed {
+        Ok(())
+    } else {
+        Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Malformed VM instance key alias."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn avf_key(alias: Option<String>) -> KeyDescriptor {
+        KeyDescriptor { domain: Domain::SELINUX, nspace: AVF_VM_KEY_NAMESPACE, alias, blob: None }
+    }
+
+    #[test]
+    fn instance_prefix_encodes_id() {
+        let prefix = instance_alias_prefix(&[0xab; VM_INSTANCE_ID_LEN]).unwrap();
+        assert_eq!(format!("vm_instance:{}:", "ab".repeat(VM_INSTANCE_ID_LEN)), prefix);
+        assert!(instance_alias_prefix(&[0; 32]).is_err());
+    }
+
+    #[test]
+    fn vm_instance_aliases_must_name_an_instance() {
+        let prefix = instance_alias_prefix(&[7; VM_INSTANCE_ID_LEN]).unwrap();
+        assert!(check_vm_key_alias(&avf_key(Some(format!("{prefix}attestation")))).is_ok());
+
+        for alias in [
+            Some("vm_instance:0707:attestation".to_string()),
+            Some(format!("vm_instance:{}", "07".repeat(VM_INSTANCE_ID_LEN))),
+            Some(format!("vm_instance:{}:x", "zz".repeat(VM_INSTANCE_ID_LEN))),
+            Some(format!("vm_instance:{}:x", "AB".repeat(VM_INSTANCE_ID_LEN))),
+        ] {
+            assert!(matches!(
+                check_vm_key_alias(&avf_key(alias))
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            ));
+        }
+
+        // Aliases outside of the VM instance range and other namespaces are not affected.
+        assert!(check_vm_key_alias(&avf_key(None)).is_ok());
+        assert!(check_vm_key_alias(&avf_key(Some("attestation".to_string()))).is_ok());
+        let key = KeyDescriptor { nspace: 100, ..avf_key(Some("vm_instance:0707:x".to_string())) };
+        assert!(check_vm_key_alias(&key).is_ok());
+    }
+}
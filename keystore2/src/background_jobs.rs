@@ -0,0 +1,230 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module tracks the long running background jobs of keystore that modify the database,
+//! e.g., the garbage collector. Listeners registered through `IKeystoreMaintenance` receive the
+//! progress of each job and are told when the last job has ended, so that, e.g., recovery tools
+//! know when the database can be snapshotted.
+//!
+//! A job is running for as long as its `JobGuard` is alive.
+
+use android_security_maintenance::aidl::android::security::maintenance::{
+    BackgroundJob::BackgroundJob, IMaintenanceListener::IMaintenanceListener,
+};
+use android_security_maintenance::binder::{Interface, Strong};
+use lazy_static::lazy_static;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The longest time that `wait_for_quiescence` blocks, so that binder threads are not tied up.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref JOBS: Jobs = Default::default();
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    listeners: Vec<Strong<dyn IMaintenanceListener>>,
+}
+
+/// The running jobs and the listeners that observe them.
+#[derive(Default)]
+struct Jobs {
+    state: Mutex<State>,
+    quiescent: Condvar,
+}
+
+impl Jobs {
+    fn start(&self, job: BackgroundJob, total: Option<i64>) -> JobGuard<'_> {
+        self.state.lock().unwrap().running += 1;
+        let guard = JobGuard { jobs: self, job, done: 0, total: total.unwrap_or(-1) };
+        self.notify(|l| l.onJobProgress(guard.job, guard.done, guard.total));
+        guard
+    }
+
+    fn end(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+        if state.running == 0 {
+            drop(state);
+            self.quiescent.notify_all();
+            self.notify(|l| l.onQuiescent());
+        }
+    }
+
+    /// Calls `f` on all listeners without holding the lock, and drops the listeners for which
+    /// it fails, i.e., whose process died.
+    fn notify(&self, f: impl Fn(&Strong<dyn IMaintenanceListener>) -> binder::Result<()>) {
+        let listeners = self.state.lock().unwrap().listeners.clone();
+        let dead: Vec<_> =
+            listeners.iter().filter(|l| f(l).is_err()).map(|l| l.as_binder()).collect();
+        if !dead.is_empty() {
+            self.state.lock().unwrap().listeners.retain(|l| !dead.contains(&l.as_binder()));
+        }
+    }
+
+    fn register_listener(&self, listener: &Strong<dyn IMaintenanceListener>) {
+        let mut state = self.state.lock().unwrap();
+        state.listeners.push(listener.clone());
+        if state.running == 0 {
+            drop(state);
+            if let Err(e) = listener.onQuiescent() {
+                log::warn!("Failed to notify new maintenance listener: {:?}", e);
+            }
+        }
+    }
+
+    fn unregister_listener(&self, listener: &Strong<dyn IMaintenanceListener>) {
+        let binder = listener.as_binder();
+        self.state.lock().unwrap().listeners.retain(|l| l.as_binder() != binder);
+    }
+
+    fn wait_for_quiescence(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout.min(MAX_WAIT);
+        let mut state = self.state.lock().unwrap();
+        while state.running != 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            state = self.quiescent.wait_timeout(state, remaining).unwrap().0;
+        }
+        true
+    }
+}
+
+/// Marks a background job as running until it is dropped.
+pub struct JobGuard<'a> {
+    jobs: &'a Jobs,
+    job: BackgroundJob,
+    done: i64,
+    total: i64,
+}
+
+impl JobGuard<'_> {
+    /// Records that the job has processed `n` more items and reports the progress.
+    pub fn advance(&mut self, n: usize) {
+        self.done += n as i64;
+        let (job, done, total) = (self.job, self.done, self.total);
+        self.jobs.notify(|l| l.onJobProgress(job, done, total));
+    }
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.jobs.end();
+    }
+}
+
+/// Starts tracking `job`, which is going to process `total` items if that is known. The job
+/// runs until the returned guard is dropped.
+pub fn start(job: BackgroundJob, total: Option<i64>) -> JobGuard<'static> {
+    JOBS.start(job, total)
+}
+
+/// Registers `listener` for the progress of all jobs. If no job is running, the listener is
+/// notified that keystore is quiescent right away.
+pub fn register_listener(listener: &Strong<dyn IMaintenanceListener>) {
+    JOBS.register_listener(listener)
+}
+
+/// Unregisters a listener that was registered with `register_listener`.
+pub fn unregister_listener(listener: &Strong<dyn IMaintenanceListener>) {
+    JOBS.unregister_listener(listener)
+}
+
+/// Blocks until no job is running or `timeout` has passed, but at most one minute. Returns true
+/// if no job is running.
+pub fn wait_for_quiescence(timeout: Duration) -> bool {
+    JOBS.wait_for_quiescence(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_security_maintenance::aidl::android::security::maintenance::IMaintenanceListener::BnMaintenanceListener;
+    use android_security_maintenance::binder::BinderFeatures;
+    use std::sync::Arc;
+
+    type Events = Arc<Mutex<Vec<(Option<BackgroundJob>, i64, i64)>>>;
+
+    struct TestListener(Events);
+
+    impl Interface for TestListener {}
+
+    impl IMaintenanceListener for TestListener {
+        fn onJobProgress(&self, job: BackgroundJob, done: i64, total: i64) -> binder::Result<()> {
+            self.0.lock().unwrap().push((Some(job), done, total));
+            Ok(())
+        }
+
+        fn onQuiescent(&self) -> binder::Result<()> {
+            self.0.lock().unwrap().push((None, 0, 0));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn listener_observes_jobs() {
+        let jobs = Jobs::default();
+        let events = Events::default();
+        let binder = BnMaintenanceListener::new_binder(
+            TestListener(events.clone()),
+            BinderFeatures::default(),
+        );
+        jobs.register_listener(&binder);
+        {
+            let mut gc = jobs.start(BackgroundJob::GARBAGE_COLLECTION, None);
+            let mut audit = jobs.start(BackgroundJob::BLOB_CHECKSUM_AUDIT, Some(10));
+            gc.advance(3);
+            audit.advance(10);
+        }
+        jobs.unregister_listener(&binder);
+        let _ignored = jobs.start(BackgroundJob::STARTUP_CLEANUP, None);
+        assert_eq!(
+            vec![
+                (None, 0, 0),
+                (Some(BackgroundJob::GARBAGE_COLLECTION), 0, -1),
+                (Some(BackgroundJob::BLOB_CHECKSUM_AUDIT), 0, 10),
+                (Some(BackgroundJob::GARBAGE_COLLECTION), 3, -1),
+                (Some(BackgroundJob::BLOB_CHECKSUM_AUDIT), 10, 10),
+                // Only the end of the last job is reported.
+                (None, 0, 0),
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn wait_for_quiescence_times_out() {
+        let jobs = Arc::new(Jobs::default());
+        assert!(jobs.wait_for_quiescence(Duration::ZERO));
+
+        let (started, wait) = std::sync::mpsc::channel();
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        let jobs_clone = jobs.clone();
+        let job = std::thread::spawn(move || {
+            let _guard = jobs_clone.start(BackgroundJob::GARBAGE_COLLECTION, None);
+            started.send(()).unwrap();
+            stopped.recv().unwrap();
+        });
+        wait.recv().unwrap();
+        assert!(!jobs.wait_for_quiescence(Duration::from_millis(10)));
+        stop.send(()).unwrap();
+        assert!(jobs.wait_for_quiescence(Duration::from_secs(10)));
+        job.join().unwrap();
+    }
+}
@@ -13,19 +13,26 @@
 // limitations under the License.
 
 //! Offer keys based on the "boot level" for superencryption.
+//!
+//! The keys are derived from a key that is created once per boot. By default they are held in
+//! keystore's memory as raw key material. With a [`KeyWrapper`], the cache holds them only in
+//! wrapped form, and each one is unwrapped transiently when a key for an AES-GCM operation is
+//! derived from it. A memory disclosure in keystore then reveals wrapped keys only.
 
 use crate::ks_err;
 use crate::{
     database::{KeyType, KeystoreDB},
+    error::Error,
     key_parameter::KeyParameterValue,
     raw_device::KeyMintDevice,
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    Algorithm::Algorithm, Digest::Digest, KeyParameter::KeyParameter as KmKeyParameter,
-    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest,
+    KeyParameter::KeyParameter as KmKeyParameter, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
+    SecurityLevel::SecurityLevel,
 };
 use anyhow::{Context, Result};
-use keystore2_crypto::{hkdf_expand, ZVec, AES_256_KEY_LENGTH};
+use keystore2_crypto::{generate_random_data, hkdf_expand, ZVec, AES_256_KEY_LENGTH};
 use std::{collections::VecDeque, convert::TryFrom};
 
 /// Strategies used to prevent later boot stages from using the KM key that protects the level 0
@@ -179,6 +186,99 @@ pub fn get_level_zero_key(db: &mut KeystoreDB) -> Result<ZVec> {
     Ok(level_zero_key)
 }
 
+/// Wraps the keys of a [`BootLevelKeyCache`] while they are not in use.
+pub trait KeyWrapper: Send {
+    /// Returns `key` in wrapped form.
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>>;
+    /// Returns the key that `wrapped` holds.
+    fn unwrap(&self, wrapped: &[u8]) -> Result<ZVec>;
+}
+
+/// A [`KeyWrapper`] that encrypts keys with an AES-GCM key of KeyMint. The KeyMint key is not
+/// stored anywhere but in memory, so it is gone, and the wrapped keys with it, when keystore
+/// restarts.
+pub struct KeyMintKeyWrapper {
+    km_dev: KeyMintDevice,
+    key_blob: Vec<u8>,
+}
+
+impl KeyMintKeyWrapper {
+    const NONCE_LENGTH: usize = 12;
+    const MAC_LENGTH: i32 = 128;
+
+    /// Creates the wrapping key in the TEE KeyMint instance.
+    pub fn new() -> Result<Self> {
+        let km_dev = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context(ks_err!("Get TEE instance failed."))?;
+        let params: Vec<KmKeyParameter> = vec![
+            KeyParameterValue::Algorithm(Algorithm::AES).into(),
+            KeyParameterValue::KeySize(256).into(),
+            KeyParameterValue::BlockMode(BlockMode::GCM).into(),
+            KeyParameterValue::PaddingMode(PaddingMode::NONE).into(),
+            KeyParameterValue::CallerNonce.into(),
+            KeyParameterValue::MinMacLength(Self::MAC_LENGTH).into(),
+            KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT).into(),
+            KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT).into(),
+            KeyParameterValue::NoAuthRequired.into(),
+        ];
+        let key_blob = km_dev
+            .generate_transient_key(&params)
+            .context(ks_err!("Failed to generate wrapping key."))?;
+        Ok(Self { km_dev, key_blob })
+    }
+
+    fn operation_params(nonce: &[u8]) -> Vec<KmKeyParameter> {
+        vec![
+            KeyParameterValue::BlockMode(BlockMode::GCM).into(),
+            KeyParameterValue::PaddingMode(PaddingMode::NONE).into(),
+            KeyParameterValue::Nonce(nonce.to_vec()).into(),
+            KeyParameterValue::MacLength(Self::MAC_LENGTH).into(),
+        ]
+    }
+}
+
+impl KeyWrapper for KeyMintKeyWrapper {
+    /// The wrapped key is the nonce followed by the ciphertext and the tag.
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let mut wrapped =
+            generate_random_data(Self::NONCE_LENGTH).context(ks_err!("Failed to get nonce."))?;
+        let ciphertext = self
+            .km_dev
+            .use_transient_key_in_one_step(
+                &self.key_blob,
+                KeyPurpose::ENCRYPT,
+                &Self::operation_params(&wrapped),
+                key,
+            )
+            .context(ks_err!("Failed to wrap key."))?;
+        wrapped.extend_from_slice(&ciphertext);
+        Ok(wrapped)
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> Result<ZVec> {
+        if wrapped.len() < Self::NONCE_LENGTH {
+            return Err(Error::sys()).context(ks_err!("Wrapped key is too short."));
+        }
+        let (nonce, ciphertext) = wrapped.split_at(Self::NONCE_LENGTH);
+        let key = self
+            .km_dev
+            .use_transient_key_in_one_step(
+                &self.key_blob,
+                KeyPurpose::DECRYPT,
+                &Self::operation_params(nonce),
+                ciphertext,
+            )
+            .context(ks_err!("Failed to unwrap key."))?;
+        ZVec::try_from(key).context(ks_err!("conversion to ZVec failed"))
+    }
+}
+
+/// A key in a [`BootLevelKeyCache`].
+enum CachedKey {
+    Plain(ZVec),
+    Wrapped(Vec<u8>),
+}
+
 /// Holds the key for the current boot level, and a cache of future keys generated as required.
 /// When the boot level advances, keys prior to the current boot level are securely dropped.
 pub struct BootLevelKeyCache {
@@ -188,7 +288,9 @@ pub struct BootLevelKeyCache {
     /// *i* + `current`. If the cache is non-empty it can be grown forwards, but it cannot be
     /// grown backwards, so keys below `current` are inaccessible.
     /// `cache.clear()` makes all keys inaccessible.
-    cache: VecDeque<ZVec>,
+    cache: VecDeque<CachedKey>,
+    /// If present, the keys in the cache are wrapped by it.
+    wrapper: Option<Box<dyn KeyWrapper>>,
 }
 
 impl BootLevelKeyCache {
@@ -198,9 +300,38 @@ impl BootLevelKeyCache {
 
     /// Initialize the cache with the level zero key.
     pub fn new(level_zero_key: ZVec) -> Self {
-        let mut cache: VecDeque<ZVec> = VecDeque::new();
-        cache.push_back(level_zero_key);
-        Self { current: 0, cache }
+        let mut cache: VecDeque<CachedKey> = VecDeque::new();
+        cache.push_back(CachedKey::Plain(level_zero_key));
+        Self { current: 0, cache, wrapper: None }
+    }
+
+    /// Initialize the cache with the level zero key, which is held only in wrapped form from
+    /// now on, like all keys that are derived from it.
+    pub fn new_wrapped(level_zero_key: ZVec, wrapper: Box<dyn KeyWrapper>) -> Result<Self> {
+        let mut cache: VecDeque<CachedKey> = VecDeque::new();
+        cache.push_back(CachedKey::Wrapped(
+            wrapper.wrap(&level_zero_key).context(ks_err!("Failed to wrap level zero key."))?,
+        ));
+        Ok(Self { current: 0, cache, wrapper: Some(wrapper) })
+    }
+
+    fn wrap_for_cache(&self, key: ZVec) -> Result<CachedKey> {
+        match &self.wrapper {
+            Some(wrapper) => Ok(CachedKey::Wrapped(wrapper.wrap(&key).context(ks_err!())?)),
+            None => Ok(CachedKey::Plain(key)),
+        }
+    }
+
+    fn load_cached(&self, key: &CachedKey) -> Result<ZVec> {
+        match (key, &self.wrapper) {
+            (CachedKey::Plain(key), _) => key.try_clone().context(ks_err!("try_clone failed")),
+            (CachedKey::Wrapped(wrapped), Some(wrapper)) => {
+                wrapper.unwrap(wrapped).context(ks_err!())
+            }
+            (CachedKey::Wrapped(_), None) => {
+                Err(Error::sys()).context(ks_err!("Wrapped key without wrapper."))
+            }
+        }
     }
 
     /// Report whether the key for the given level can be inferred.
@@ -213,7 +344,7 @@ impl BootLevelKeyCache {
 
     /// Get the HKDF key for boot level `boot_level`. The key for level *i*+1
     /// is calculated from the level *i* key using `hkdf_expand`.
-    fn get_hkdf_key(&mut self, boot_level: usize) -> Result<Option<&ZVec>> {
+    fn get_hkdf_key(&mut self, boot_level: usize) -> Result<Option<ZVec>> {
         if !self.level_accessible(boot_level) {
             return Ok(None);
         }
@@ -225,14 +356,15 @@ impl BootLevelKeyCache {
         for _level in first_not_cached..=boot_level {
             // We check at the start that cache is non-empty and future iterations only push,
             // so this must unwrap.
-            let highest_key = self.cache.back().unwrap();
-            let next_key = hkdf_expand(Self::HKDF_KEY_SIZE, highest_key, Self::HKDF_ADVANCE)
+            let highest_key = self.load_cached(self.cache.back().unwrap())?;
+            let next_key = hkdf_expand(Self::HKDF_KEY_SIZE, &highest_key, Self::HKDF_ADVANCE)
                 .context(ks_err!("Advancing key one step"))?;
+            let next_key = self.wrap_for_cache(next_key)?;
             self.cache.push_back(next_key);
         }
 
         // If we reach this point, we should have a key at index boot_level - current.
-        Ok(Some(self.cache.get(boot_level - self.current).unwrap()))
+        Ok(Some(self.load_cached(self.cache.get(boot_level - self.current).unwrap())?))
     }
 
     /// Drop keys prior to the given boot level, while retaining the ability to generate keys for
@@ -277,7 +409,7 @@ impl BootLevelKeyCache {
     ) -> Result<Option<ZVec>> {
         self.get_hkdf_key(boot_level)
             .context(ks_err!("Looking up HKDF key"))?
-            .map(|k| hkdf_expand(out_len, k, info))
+            .map(|k| hkdf_expand(out_len, &k, info))
             .transpose()
             .context(ks_err!("Calling hkdf_expand"))
     }
@@ -335,4 +467,32 @@ mod test {
         assert_eq!(None, blkc.aes_key(10)?);
         Ok(())
     }
+    /// Stands in for KeyMint in tests.
+    struct XorWrapper;
+
+    impl KeyWrapper for XorWrapper {
+        fn wrap(&self, key: &[u8]) -> Result<Vec<u8>> {
+            Ok(key.iter().map(|b| b ^ 0x5a).collect())
+        }
+
+        fn unwrap(&self, wrapped: &[u8]) -> Result<ZVec> {
+            Ok(ZVec::try_from(wrapped.iter().map(|b| b ^ 0x5a).collect::<Vec<u8>>())?)
+        }
+    }
+
+    #[test]
+    fn test_wrapped_keys_match_plain_keys() -> Result<()> {
+        let initial_key = b"initial key";
+        let mut plain = BootLevelKeyCache::new(ZVec::try_from(initial_key as &[u8])?);
+        let mut wrapped = BootLevelKeyCache::new_wrapped(
+            ZVec::try_from(initial_key as &[u8])?,
+            Box::new(XorWrapper),
+        )?;
+        assert_eq!(plain.aes_key(10)?, wrapped.aes_key(10)?);
+        wrapped.advance_boot_level(5)?;
+        assert_eq!(None, wrapped.aes_key(0)?);
+        assert_eq!(plain.aes_key(7)?, wrapped.aes_key(7)?);
+        assert!(wrapped.cache.iter().all(|key| matches!(key, CachedKey::Wrapped(_))));
+        Ok(())
+    }
 }
@@ -0,0 +1,243 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the strict mode of certificate updates. By default,
+//! `updateSubcomponent` stores whatever bytes it is given as certificate and chain. In strict
+//! mode, the certificates are parsed, converted from PEM to DER, checked to be ordered from leaf
+//! to root with each certificate issued by the next one, and the leaf certificate must carry the
+//! public key of the key that it is stored with. Garbage is rejected with `INVALID_ARGUMENT` and
+//! an error that names the offending certificate.
+
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use anyhow::{Context, Result};
+use keystore2_crypto::{
+    certificate_issued_by, certificates_share_public_key, normalize_certificate,
+};
+
+/// Splits `buf` into its certificates and returns their DER encodings. `buf` holds one or more
+/// concatenated certificates, each of which may be DER or PEM encoded. `what` names the
+/// buffer in errors.
+fn parse_certificates(buf: &[u8], what: &str) -> Result<Vec<Vec<u8>>> {
+    let mut certs = Vec::new();
+    let mut rest = buf;
+    loop {
+        // PEM encoded certificates may be separated by white space.
+        let start = rest.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(rest.len());
+        rest = &rest[start..];
+        if rest.is_empty() {
+            break;
+        }
+        let (cert, consumed) = normalize_certificate(rest)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .with_context(|| {
+                ks_err!("Certificate {} of the {} is malformed.", certs.len(), what)
+            })?;
+        certs.push(cert);
+        rest = &rest[consumed..];
+    }
+    if certs.is_empty() {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("The {} holds no certificate.", what));
+    }
+    Ok(certs)
+}
+
+/// Checks that each certificate in `certs` was issued by the next one.
+fn check_order(certs: &[Vec<u8>]) -> Result<()> {
+    for (i, pair) in certs.windows(2).enumerate() {
+        let issued = certificate_issued_by(&pair[0], &pair[1])
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Failed to check the issuer of certificate {}.", i))?;
+        if !issued {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                "Certificate {} is not issued by certificate {}. \
+                 The chain must be ordered from leaf to root.",
+                i,
+                i + 1
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parses and validates a certificate update. `public_cert` must hold exactly one certificate,
+/// which is the leaf of the chain. `certificate_chain` continues the chain of `public_cert`,
+/// or holds the complete chain if there is no `public_cert`. Returns the normalized
+/// certificate and chain, the latter as concatenation of DER encodings.
+pub fn normalize(
+    public_cert: Option<&[u8]>,
+    certificate_chain: Option<&[u8]>,
+) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+    let mut certs = Vec::new();
+    let public_cert = match public_cert {
+        Some(buf) => {
+            let mut leaf = parse_certificates(buf, "public certificate")?;
+            if leaf.len() != 1 {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                    "The public certificate holds {} certificates instead of one.",
+                    leaf.len()
+                ));
+            }
+            certs.push(leaf.remove(0));
+            Some(certs[0].clone())
+        }
+        None => None,
+    };
+    let chain_start = certs.len();
+    if let Some(buf) = certificate_chain {
+        certs.extend(parse_certificates(buf, "certificate chain")?);
+    }
+    check_order(&certs).context(ks_err!())?;
+    let certificate_chain = certificate_chain.map(|_| certs[chain_start..].concat());
+    Ok((public_cert, certificate_chain))
+}
+
+/// Checks that the normalized `leaf` certificate carries the public key of a key whose current
+/// certificate is `key_cert`. Keys without certificate, e.g., symmetric keys, cannot be
+/// checked, so a leaf certificate cannot be stored with them in strict mode.
+pub fn check_leaf_matches_key(leaf: &[u8], key_cert: Option<&[u8]>) -> Result<()> {
+    let key_cert = key_cert
+        .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        .context(ks_err!("The key has no public key to check the certificate against."))?;
+    let matches = certificates_share_public_key(leaf, key_cert)
+        .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        .context(ks_err!("Failed to compare the public keys."))?;
+    if !matches {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("The certificate does not carry the public key of the key."));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // A self-signed EC P-256 certificate with the subject "CN=Test Root".
+    pub const ROOT_CERT: &[u8] = &[
+        0x30, 0x82, 0x01, 0x80, 0x30, 0x82, 0x01, 0x25, 0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x14,
+        0x0c, 0x88, 0x9f, 0x87, 0x8f, 0xf6, 0xd7, 0x62, 0x1c, 0xda, 0xd5, 0x68, 0xd1, 0x10, 0xf9,
+        0x90, 0xa2, 0x21, 0xda, 0xed, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04,
+        0x03, 0x02, 0x30, 0x14, 0x31, 0x12, 0x30, 0x10, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x09,
+        0x54, 0x65, 0x73, 0x74, 0x20, 0x52, 0x6f, 0x6f, 0x74, 0x30, 0x20, 0x17, 0x0d, 0x32, 0x36,
+        0x31, 0x30, 0x31, 0x36, 0x31, 0x30, 0x33, 0x32, 0x35, 0x33, 0x5a, 0x18, 0x0f, 0x32, 0x31,
+        0x32, 0x36, 0x30, 0x39, 0x32, 0x32, 0x31, 0x30, 0x33, 0x32, 0x35, 0x33, 0x5a, 0x30, 0x14,
+        0x31, 0x12, 0x30, 0x10, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x09, 0x54, 0x65, 0x73, 0x74,
+        0x20, 0x52, 0x6f, 0x6f, 0x74, 0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce,
+        0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42,
+        0x00, 0x04, 0x2a, 0x06, 0x55, 0x73, 0x65, 0xc0, 0xe5, 0xc7, 0x66, 0xe0, 0xf1, 0x9c, 0x39,
+        0xa6, 0xb7, 0x2d, 0xaa, 0x22, 0x7f, 0x56, 0x20, 0x97, 0x98, 0xbc, 0x6d, 0xbe, 0x60, 0xb9,
+        0xfb, 0x18, 0x95, 0x17, 0xaf, 0xd2, 0x03, 0x6b, 0x6f, 0xba, 0x3e, 0x88, 0x7a, 0x81, 0xd2,
+        0xe8, 0xfa, 0x72, 0x77, 0xb5, 0x0b, 0x9c, 0xf5, 0x1d, 0xa9, 0x04, 0xf0, 0x89, 0xa6, 0x67,
+        0x8d, 0x2e, 0xbb, 0x1e, 0xd0, 0x26, 0xa3, 0x53, 0x30, 0x51, 0x30, 0x1d, 0x06, 0x03, 0x55,
+        0x1d, 0x0e, 0x04, 0x16, 0x04, 0x14, 0x8f, 0x4e, 0xa8, 0x77, 0xef, 0x42, 0xe3, 0x86, 0x4b,
+        0xb8, 0x7f, 0xc8, 0x07, 0xb5, 0xd1, 0x57, 0xc8, 0x2d, 0xc7, 0xd6, 0x30, 0x1f, 0x06, 0x03,
+        0x55, 0x1d, 0x23, 0x04, 0x18, 0x30, 0x16, 0x80, 0x14, 0x8f, 0x4e, 0xa8, 0x77, 0xef, 0x42,
+        0xe3, 0x86, 0x4b, 0xb8, 0x7f, 0xc8, 0x07, 0xb5, 0xd1, 0x57, 0xc8, 0x2d, 0xc7, 0xd6, 0x30,
+        0x0f, 0x06, 0x03, 0x55, 0x1d, 0x13, 0x01, 0x01, 0xff, 0x04, 0x05, 0x30, 0x03, 0x01, 0x01,
+        0xff, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02, 0x03, 0x49,
+        0x00, 0x30, 0x46, 0x02, 0x21, 0x00, 0x98, 0x5e, 0x25, 0xe9, 0x4a, 0xb3, 0x63, 0x54, 0xba,
+        0x35, 0x63, 0x2a, 0x96, 0x9a, 0x80, 0x4b, 0x93, 0x1c, 0x0c, 0xfb, 0xf4, 0xf8, 0x78, 0x12,
+        0x2b, 0xff, 0x70, 0xea, 0x68, 0x53, 0xf8, 0x41, 0x02, 0x21, 0x00, 0xe4, 0x43, 0xe5, 0x41,
+        0xc2, 0xd2, 0x04, 0x18, 0xad, 0x60, 0x7a, 0x8f, 0x40, 0x67, 0x50, 0x2e, 0x27, 0xa5, 0xfd,
+        0xa9, 0xc8, 0xa4, 0x03, 0xad, 0xa2, 0x48, 0x7b, 0x48, 0x83, 0x51, 0x4e, 0x27,
+    ];
+    // An EC P-256 certificate with the subject "CN=Test Leaf", issued by ROOT_CERT.
+    pub const LEAF_CERT: &[u8] = &[
+        0x30, 0x82, 0x01, 0x6d, 0x30, 0x82, 0x01, 0x14, 0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x14,
+        0x6d, 0x24, 0x22, 0x94, 0x3b, 0x2c, 0x22, 0xff, 0x5e, 0xca, 0xf8, 0xd3, 0xf5, 0xc1, 0x37,
+        0x79, 0x71, 0x5e, 0x67, 0x74, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04,
+        0x03, 0x02, 0x30, 0x14, 0x31, 0x12, 0x30, 0x10, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x09,
+        0x54, 0x65, 0x73, 0x74, 0x20, 0x52, 0x6f, 0x6f, 0x74, 0x30, 0x20, 0x17, 0x0d, 0x32, 0x36,
+        0x31, 0x30, 0x31, 0x36, 0x31, 0x30, 0x33, 0x32, 0x35, 0x33, 0x5a, 0x18, 0x0f, 0x32, 0x31,
+        0x32, 0x36, 0x30, 0x39, 0x32, 0x32, 0x31, 0x30, 0x33, 0x32, 0x35, 0x33, 0x5a, 0x30, 0x14,
+        0x31, 0x12, 0x30, 0x10, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x09, 0x54, 0x65, 0x73, 0x74,
+        0x20, 0x4c, 0x65, 0x61, 0x66, 0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce,
+        0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42,
+        0x00, 0x04, 0xcf, 0x69, 0x2b, 0x35, 0xbf, 0x25, 0x37, 0x48, 0xa9, 0x7e, 0xb6, 0x33, 0x9c,
+        0x74, 0x78, 0x11, 0x67, 0x8d, 0xc3, 0x1d, 0xc2, 0x83, 0x11, 0x5b, 0x96, 0xe2, 0x6f, 0x73,
+        0xcc, 0xdf, 0x64, 0x09, 0x5a, 0xe4, 0x7b, 0xb3, 0xb3, 0x51, 0x92, 0x4b, 0x7d, 0x6f, 0x1a,
+        0xbc, 0xea, 0xc2, 0xe2, 0xb6, 0xd0, 0x72, 0xc1, 0x58, 0xb7, 0x37, 0xce, 0xf2, 0xc9, 0x41,
+        0x7e, 0xc8, 0xc0, 0x46, 0x56, 0x9f, 0xa3, 0x42, 0x30, 0x40, 0x30, 0x1d, 0x06, 0x03, 0x55,
+        0x1d, 0x0e, 0x04, 0x16, 0x04, 0x14, 0x25, 0x73, 0x2f, 0x22, 0x00, 0xf0, 0xed, 0xd4, 0x5a,
+        0x0d, 0x9d, 0x1d, 0x1a, 0x68, 0x58, 0x68, 0xab, 0x65, 0x20, 0x1b, 0x30, 0x1f, 0x06, 0x03,
+        0x55, 0x1d, 0x23, 0x04, 0x18, 0x30, 0x16, 0x80, 0x14, 0x8f, 0x4e, 0xa8, 0x77, 0xef, 0x42,
+        0xe3, 0x86, 0x4b, 0xb8, 0x7f, 0xc8, 0x07, 0xb5, 0xd1, 0x57, 0xc8, 0x2d, 0xc7, 0xd6, 0x30,
+        0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02, 0x03, 0x47, 0x00, 0x30,
+        0x44, 0x02, 0x20, 0x67, 0x81, 0x9a, 0x78, 0x1f, 0x00, 0xe0, 0xb9, 0xb5, 0x4a, 0x54, 0xea,
+        0x59, 0x56, 0x85, 0x66, 0xd9, 0x58, 0x5b, 0x21, 0x21, 0x6a, 0xef, 0x7a, 0xf1, 0xb2, 0x7a,
+        0x4e, 0x26, 0x72, 0xab, 0xf3, 0x02, 0x20, 0x41, 0x01, 0x76, 0xc8, 0xc8, 0x98, 0x6e, 0xb2,
+        0x17, 0x64, 0x97, 0x97, 0x12, 0xa8, 0xc0, 0x78, 0x1b, 0x02, 0x0a, 0xad, 0x75, 0xc6, 0x81,
+        0xcc, 0xd8, 0xd8, 0xe7, 0x7c, 0xc8, 0xd9, 0x44, 0x1d,
+    ];
+
+    // LEAF_CERT in PEM encoding.
+    const LEAF_PEM: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBbTCCARSgAwIBAgIUbSQilDssIv9eyvjT9cE3eXFeZ3QwCgYIKoZIzj0EAwIw\n\
+FDESMBAGA1UEAwwJVGVzdCBSb290MCAXDTI2MTAxNjEwMzI1M1oYDzIxMjYwOTIy\n\
+MTAzMjUzWjAUMRIwEAYDVQQDDAlUZXN0IExlYWYwWTATBgcqhkjOPQIBBggqhkjO\n\
+PQMBBwNCAATPaSs1vyU3SKl+tjOcdHgRZ43DHcKDEVuW4m9zzN9kCVrke7OzUZJL\n\
+fW8avOrC4rbQcsFYtzfO8slBfsjARlafo0IwQDAdBgNVHQ4EFgQUJXMvIgDw7dRa\n\
+DZ0dGmhYaKtlIBswHwYDVR0jBBgwFoAUj06od+9C44ZLuH/IB7XRV8gtx9YwCgYI\n\
+KoZIzj0EAwIDRwAwRAIgZ4GaeB8A4Lm1SlTqWVaFZtlYWyEhau968bJ6TiZyq/MC\n\
+IEEBdsjImG6yF2SXlxKowHgbAgqtdcaBzNjY53zI2UQd\n\
+-----END CERTIFICATE-----\n";
+
+    fn is_invalid_argument<T: std::fmt::Debug>(result: Result<T>) -> bool {
+        matches!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        )
+    }
+
+    #[test]
+    fn chain_is_normalized_to_der() -> Result<()> {
+        let chain = format!("{}\n", LEAF_PEM).into_bytes();
+        let (cert, chain) = normalize(None, Some(&[chain.as_slice(), ROOT_CERT].concat()[..]))?;
+        assert_eq!(None, cert);
+        assert_eq!(Some([LEAF_CERT, ROOT_CERT].concat()), chain);
+
+        let (cert, chain) = normalize(Some(LEAF_PEM.as_bytes()), Some(ROOT_CERT))?;
+        assert_eq!(Some(LEAF_CERT.to_vec()), cert);
+        assert_eq!(Some(ROOT_CERT.to_vec()), chain);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_chains_are_rejected() {
+        let truncated = &LEAF_CERT[..LEAF_CERT.len() - 10];
+        assert!(is_invalid_argument(normalize(None, Some(&b"garbage"[..]))));
+        assert!(is_invalid_argument(normalize(None, Some(&b" \n"[..]))));
+        assert!(is_invalid_argument(normalize(None, Some(truncated))));
+        assert!(is_invalid_argument(normalize(
+            None,
+            Some(&[ROOT_CERT, &b"garbage"[..]].concat()[..])
+        )));
+        // Wrong order.
+        assert!(is_invalid_argument(normalize(None, Some(&[ROOT_CERT, LEAF_CERT].concat()[..]))));
+        assert!(is_invalid_argument(normalize(Some(ROOT_CERT), Some(LEAF_CERT))));
+        // The public certificate must be a single certificate.
+        assert!(is_invalid_argument(normalize(Some(&[LEAF_CERT, ROOT_CERT].concat()[..]), None)));
+    }
+
+    #[test]
+    fn leaf_must_match_key() -> Result<()> {
+        check_leaf_matches_key(LEAF_CERT, Some(LEAF_CERT))?;
+        assert!(is_invalid_argument(check_leaf_matches_key(LEAF_CERT, Some(ROOT_CERT))));
+        assert!(is_invalid_argument(check_leaf_matches_key(LEAF_CERT, None)));
+        Ok(())
+    }
+}
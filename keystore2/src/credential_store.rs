@@ -0,0 +1,136 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the ICredentialStore AIDL interface. It stores opaque credential
+//! blobs of platform components, e.g., VPN profiles and Wi-Fi credentials, in the namespace of
+//! the calling uid. Blobs are encrypted with the AfterFirstUnlock super key of the caller's
+//! user, so they can only be written and read after the user unlocked once since boot.
+
+use crate::database::{CredentialEntry, CredentialType};
+use crate::error::{map_or_log_err, Error};
+use crate::globals::{DB, SUPER_KEY};
+use crate::ks_err;
+use crate::permission::KeystorePerm;
+use crate::utils::{check_keystore_permission, uid_to_android_user, watchdog as wd, AesGcm};
+use android_security_credentialstore::aidl::android::security::credentialstore::{
+    CredentialType::CredentialType as AidlCredentialType,
+    ICredentialStore::{BnCredentialStore, ICredentialStore},
+};
+use android_security_credentialstore::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong, ThreadState,
+};
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+
+/// This struct is defined to implement the aforementioned AIDL interface.
+pub struct CredentialStore;
+
+impl CredentialStore {
+    /// Create a new instance of the credential store service.
+    pub fn new_native_binder() -> Result<Strong<dyn ICredentialStore>> {
+        Ok(BnCredentialStore::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    /// Checks the permission of the caller and returns the caller's uid, which owns the
+    /// entries, together with the database representation of `cred_type`.
+    fn check_args(cred_type: AidlCredentialType) -> Result<(u32, CredentialType)> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ManageCredentials).context(ks_err!())?;
+
+        let cred_type = match cred_type {
+            AidlCredentialType::VPN_PROFILE => CredentialType::VpnProfile,
+            AidlCredentialType::WIFI_CREDENTIAL => CredentialType::WifiCredential,
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Unknown credential type {cred_type:?}."));
+            }
+        };
+        Ok((ThreadState::get_calling_uid(), cred_type))
+    }
+
+    /// Returns the AfterFirstUnlock super key of the user of `owner`. Fails with `LOCKED` if the
+    /// user has not unlocked since boot.
+    fn super_key(owner: u32) -> Result<Arc<dyn AesGcm + Send + Sync>> {
+        SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(uid_to_android_user(owner))
+            .ok_or(Error::Rc(ResponseCode::LOCKED))
+            .context(ks_err!("Super key of the owner is not available."))
+    }
+
+    fn put(cred_type: AidlCredentialType, alias: &str, blob: &[u8]) -> Result<()> {
+        let (owner, cred_type) = Self::check_args(cred_type)?;
+        if alias.is_empty() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!("Empty alias."));
+        }
+        let super_key = Self::super_key(owner)?;
+        let (blob, iv, aead_tag) =
+            super_key.encrypt(blob).context(ks_err!("Failed to encrypt credential."))?;
+        let entry = CredentialEntry { blob, iv, aead_tag };
+        DB.with(|db| db.borrow_mut().put_credential(owner, cred_type, alias, &entry))
+            .context(ks_err!("Failed to store credential."))
+    }
+
+    fn get(cred_type: AidlCredentialType, alias: &str) -> Result<Vec<u8>> {
+        let (owner, cred_type) = Self::check_args(cred_type)?;
+        let entry = DB
+            .with(|db| db.borrow_mut().get_credential(owner, cred_type, alias))
+            .context(ks_err!("Failed to load credential."))?;
+        Self::super_key(owner)?
+            .decrypt(&entry.blob, &entry.iv, &entry.aead_tag)
+            .map(|plaintext| plaintext.to_vec())
+            .context(ks_err!("Failed to decrypt credential."))
+    }
+
+    fn remove(cred_type: AidlCredentialType, alias: &str) -> Result<()> {
+        let (owner, cred_type) = Self::check_args(cred_type)?;
+        DB.with(|db| db.borrow_mut().remove_credential(owner, cred_type, alias))
+            .context(ks_err!("Failed to remove credential."))
+    }
+
+    fn list(cred_type: AidlCredentialType, prefix: &str) -> Result<Vec<String>> {
+        let (owner, cred_type) = Self::check_args(cred_type)?;
+        DB.with(|db| db.borrow_mut().list_credentials(owner, cred_type, prefix))
+            .context(ks_err!("Failed to list credentials."))
+    }
+}
+
+impl Interface for CredentialStore {}
+
+impl ICredentialStore for CredentialStore {
+    fn put(&self, cred_type: AidlCredentialType, alias: &str, blob: &[u8]) -> BinderResult<()> {
+        let _wp = wd::watch_millis("ICredentialStore::put", 500);
+        map_or_log_err(Self::put(cred_type, alias, blob), Ok)
+    }
+
+    fn get(&self, cred_type: AidlCredentialType, alias: &str) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("ICredentialStore::get", 500);
+        map_or_log_err(Self::get(cred_type, alias), Ok)
+    }
+
+    fn remove(&self, cred_type: AidlCredentialType, alias: &str) -> BinderResult<()> {
+        let _wp = wd::watch_millis("ICredentialStore::remove", 500);
+        map_or_log_err(Self::remove(cred_type, alias), Ok)
+    }
+
+    fn list(&self, cred_type: AidlCredentialType, prefix: &str) -> BinderResult<Vec<String>> {
+        let _wp = wd::watch_millis("ICredentialStore::list", 500);
+        map_or_log_err(Self::list(cred_type, prefix), Ok)
+    }
+}
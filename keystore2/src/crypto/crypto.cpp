@@ -18,16 +18,24 @@
 
 #include "crypto.hpp"
 
+#include <argon2.h>
 #include <assert.h>
 #include <log/log.h>
 #include <openssl/aes.h>
+#include <openssl/bio.h>
 #include <openssl/ec.h>
 #include <openssl/ec_key.h>
 #include <openssl/ecdh.h>
+#include <openssl/err.h>
 #include <openssl/evp.h>
 #include <openssl/hkdf.h>
 #include <openssl/hmac.h>
+#include <openssl/md5.h>
+#include <openssl/mem.h>
+#include <openssl/pem.h>
 #include <openssl/rand.h>
+#include <openssl/rsa.h>
+#include <openssl/sha.h>
 #include <openssl/x509.h>
 
 #include <vector>
@@ -76,6 +84,14 @@ bool hmacSha256(const uint8_t* key, size_t key_size, const uint8_t* msg, size_t
     return (p != nullptr);
 }
 
+bool sha256Digest(const uint8_t* data, size_t data_size, uint8_t* out, size_t out_size) {
+    if (out_size != SHA256_DIGEST_LENGTH) {
+        return false;
+    }
+    SHA256(data, data_size, out);
+    return true;
+}
+
 bool randomBytes(uint8_t* out, size_t len) {
     return RAND_bytes(out, len);
 }
@@ -203,6 +219,49 @@ void generateKeyFromPassword(uint8_t* key, size_t key_len, const char* pw, size_
     PKCS5_PBKDF2_HMAC(pw, pw_len, salt, SALT_SIZE, 8192, digest, key_len, key);
 }
 
+bool generateKeyFromPasswordArgon2id(uint8_t* key, size_t key_len, const char* pw, size_t pw_len,
+                                     const uint8_t* salt, uint32_t iterations, uint32_t memory_kib,
+                                     uint32_t parallelism) {
+    return argon2id_hash_raw(iterations, memory_kib, parallelism, pw, pw_len, salt, SALT_SIZE, key,
+                             key_len) == ARGON2_OK;
+}
+
+// Keymaster-era keystore (blob version 2 and earlier) derived the master key with an MD5
+// based PBKDF2 and protected the blob with AES-128-CBC plus an MD5 digest of the plaintext.
+// These are only used to read such blobs so they can be re-wrapped; never to write them.
+
+void generateKeyFromPasswordLegacyMd5(uint8_t* key, size_t key_len, const char* pw, size_t pw_len,
+                                      const uint8_t* salt) {
+    PKCS5_PBKDF2_HMAC(pw, pw_len, salt, SALT_SIZE, 8192, EVP_md5(), key_len, key);
+}
+
+/*
+ * Decrypt 'len' data at 'in' with AES-128-CBC, using the 128-bit key at 'key' and 128-bit IV at
+ * 'iv', and write the output to 'out'. 'len' must be a multiple of the AES block size and at
+ * least MD5_DIGEST_LENGTH. The first MD5_DIGEST_LENGTH bytes of the plaintext must be the MD5
+ * digest of the remainder, otherwise false is returned.
+ */
+bool AES_cbc_md5_decrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t* key,
+                         const uint8_t* iv) {
+    if (len < MD5_DIGEST_LENGTH || len % AES_BLOCK_SIZE != 0) {
+        return false;
+    }
+
+    AES_KEY aes_key;
+    if (AES_set_decrypt_key(key, kAes128KeySizeBytes * 8, &aes_key) != 0) {
+        return false;
+    }
+    ArrayEraser keyEraser(reinterpret_cast<uint8_t*>(&aes_key), sizeof(aes_key));
+
+    uint8_t iv_copy[AES_BLOCK_SIZE];
+    std::copy(iv, iv + AES_BLOCK_SIZE, iv_copy);
+    AES_cbc_encrypt(in, out, len, &aes_key, iv_copy, AES_DECRYPT);
+
+    uint8_t digest[MD5_DIGEST_LENGTH];
+    MD5(out + MD5_DIGEST_LENGTH, len - MD5_DIGEST_LENGTH, digest);
+    return CRYPTO_memcmp(digest, out, MD5_DIGEST_LENGTH) == 0;
+}
+
 // New code.
 
 bool HKDFExtract(uint8_t* out_key, size_t* out_len, const uint8_t* secret, size_t secret_len,
@@ -319,3 +378,219 @@ int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint
     uint8_t* tmp = subject_buf;
     return i2d_X509_NAME(subject, &tmp);
 }
+
+static const EVP_MD* verifyDigestToMd(int digest) {
+    switch (digest) {
+    case VERIFY_DIGEST_SHA1:
+        return EVP_sha1();
+    case VERIFY_DIGEST_SHA_2_224:
+        return EVP_sha224();
+    case VERIFY_DIGEST_SHA_2_256:
+        return EVP_sha256();
+    case VERIFY_DIGEST_SHA_2_384:
+        return EVP_sha384();
+    case VERIFY_DIGEST_SHA_2_512:
+        return EVP_sha512();
+    default:
+        return nullptr;
+    }
+}
+
+int verifySignatureWithCertificate(const uint8_t* cert_buf, size_t cert_len, int digest,
+                                   int padding, const uint8_t* msg, size_t msg_len,
+                                   const uint8_t* sig, size_t sig_len) {
+    if (!cert_buf || !msg || !sig) {
+        ALOGE("verifySignatureWithCertificate: received null pointer");
+        return -1;
+    }
+
+    const EVP_MD* md = verifyDigestToMd(digest);
+    if (!md) {
+        ALOGE("verifySignatureWithCertificate: unsupported digest %d", digest);
+        return -1;
+    }
+
+    const uint8_t* p = cert_buf;
+    bssl::UniquePtr<X509> cert(d2i_X509(nullptr /* Allocate X509 struct */, &p, cert_len));
+    if (!cert) {
+        ALOGE("verifySignatureWithCertificate: failed to parse certificate");
+        return -1;
+    }
+
+    bssl::UniquePtr<EVP_PKEY> pkey(X509_get_pubkey(cert.get()));
+    if (!pkey) {
+        ALOGE("verifySignatureWithCertificate: failed to retrieve public key");
+        return -1;
+    }
+
+    int rsa_padding;
+    switch (EVP_PKEY_id(pkey.get())) {
+    case EVP_PKEY_EC:
+        if (padding != VERIFY_PADDING_NONE) {
+            ALOGE("verifySignatureWithCertificate: EC keys take no padding");
+            return -1;
+        }
+        rsa_padding = 0;
+        break;
+    case EVP_PKEY_RSA:
+        if (padding == VERIFY_PADDING_RSA_PKCS1_1_5) {
+            rsa_padding = RSA_PKCS1_PADDING;
+        } else if (padding == VERIFY_PADDING_RSA_PSS) {
+            rsa_padding = RSA_PKCS1_PSS_PADDING;
+        } else {
+            ALOGE("verifySignatureWithCertificate: unsupported RSA padding %d", padding);
+            return -1;
+        }
+        break;
+    default:
+        ALOGE("verifySignatureWithCertificate: unsupported key type");
+        return -1;
+    }
+
+    bssl::ScopedEVP_MD_CTX ctx;
+    EVP_PKEY_CTX* pctx;
+    if (!EVP_DigestVerifyInit(ctx.get(), &pctx, md, nullptr /* engine */, pkey.get())) {
+        ALOGE("verifySignatureWithCertificate: failed to initialize verification");
+        return -1;
+    }
+    if (rsa_padding != 0 && !EVP_PKEY_CTX_set_rsa_padding(pctx, rsa_padding)) {
+        ALOGE("verifySignatureWithCertificate: failed to set RSA padding");
+        return -1;
+    }
+    // KeyMint uses a salt of the length of the digest for PSS signatures.
+    if (rsa_padding == RSA_PKCS1_PSS_PADDING &&
+        !EVP_PKEY_CTX_set_rsa_pss_saltlen(pctx, -1 /* digest length */)) {
+        ALOGE("verifySignatureWithCertificate: failed to set PSS salt length");
+        return -1;
+    }
+
+    int result = EVP_DigestVerify(ctx.get(), sig, sig_len, msg, msg_len);
+    // A bad signature leaves an error on the queue, which is not an error of this function.
+    ERR_clear_error();
+    return result == 1 ? 1 : 0;
+}
+
+int normalizeCertificate(const uint8_t* in, size_t in_len, size_t* consumed, uint8_t* out,
+                         size_t out_len) {
+    if (!in || !consumed || !out) {
+        ALOGE("normalizeCertificate: received null pointer");
+        return 0;
+    }
+
+    bssl::UniquePtr<X509> cert;
+    if (in_len > 0 && in[0] == '-') {
+        bssl::UniquePtr<BIO> bio(BIO_new_mem_buf(in, in_len));
+        if (!bio) {
+            ALOGE("normalizeCertificate: failed to allocate BIO");
+            return 0;
+        }
+        cert.reset(PEM_read_bio_X509(bio.get(), nullptr /* x */, nullptr /* cb */,
+                                     nullptr /* u */));
+        *consumed = in_len - BIO_pending(bio.get());
+    } else {
+        const uint8_t* p = in;
+        cert.reset(d2i_X509(nullptr /* Allocate X509 struct */, &p, in_len));
+        *consumed = p - in;
+    }
+    if (!cert) {
+        ALOGE("normalizeCertificate: failed to parse certificate");
+        ERR_clear_error();
+        return 0;
+    }
+
+    int der_len = i2d_X509(cert.get(), nullptr /* Don't copy the data */);
+    if (der_len <= 0) {
+        ALOGE("normalizeCertificate: error obtaining encoded certificate length");
+        return 0;
+    }
+    if (static_cast<size_t>(der_len) > out_len) {
+        return -der_len;
+    }
+    uint8_t* tmp = out;
+    return i2d_X509(cert.get(), &tmp);
+}
+
+static bssl::UniquePtr<X509> parseDerCertificate(const uint8_t* buf, size_t len) {
+    const uint8_t* p = buf;
+    bssl::UniquePtr<X509> cert(d2i_X509(nullptr /* Allocate X509 struct */, &p, len));
+    if (!cert || p != buf + len) {
+        ERR_clear_error();
+        return nullptr;
+    }
+    return cert;
+}
+
+int checkCertificateIssuer(const uint8_t* cert_buf, size_t cert_len, const uint8_t* issuer_buf,
+                           size_t issuer_len) {
+    if (!cert_buf || !issuer_buf) {
+        ALOGE("checkCertificateIssuer: received null pointer");
+        return -1;
+    }
+    bssl::UniquePtr<X509> cert = parseDerCertificate(cert_buf, cert_len);
+    bssl::UniquePtr<X509> issuer = parseDerCertificate(issuer_buf, issuer_len);
+    if (!cert || !issuer) {
+        ALOGE("checkCertificateIssuer: failed to parse certificate");
+        return -1;
+    }
+
+    // Checks the names and, if present, the key identifiers and the key usage of the issuer.
+    if (X509_check_issued(issuer.get(), cert.get()) != X509_V_OK) {
+        return 0;
+    }
+    bssl::UniquePtr<EVP_PKEY> pkey(X509_get_pubkey(issuer.get()));
+    if (!pkey) {
+        ALOGE("checkCertificateIssuer: failed to retrieve public key");
+        ERR_clear_error();
+        return -1;
+    }
+    int result = X509_verify(cert.get(), pkey.get());
+    // A bad signature leaves an error on the queue, which is not an error of this function.
+    ERR_clear_error();
+    return result == 1 ? 1 : 0;
+}
+
+int compareCertificatePublicKeys(const uint8_t* cert1_buf, size_t cert1_len,
+                                 const uint8_t* cert2_buf, size_t cert2_len) {
+    if (!cert1_buf || !cert2_buf) {
+        ALOGE("compareCertificatePublicKeys: received null pointer");
+        return -1;
+    }
+    bssl::UniquePtr<X509> cert1 = parseDerCertificate(cert1_buf, cert1_len);
+    bssl::UniquePtr<X509> cert2 = parseDerCertificate(cert2_buf, cert2_len);
+    if (!cert1 || !cert2) {
+        ALOGE("compareCertificatePublicKeys: failed to parse certificate");
+        return -1;
+    }
+    bssl::UniquePtr<EVP_PKEY> pkey1(X509_get_pubkey(cert1.get()));
+    bssl::UniquePtr<EVP_PKEY> pkey2(X509_get_pubkey(cert2.get()));
+    if (!pkey1 || !pkey2) {
+        ALOGE("compareCertificatePublicKeys: failed to retrieve public key");
+        ERR_clear_error();
+        return -1;
+    }
+    return EVP_PKEY_cmp(pkey1.get(), pkey2.get()) == 1 ? 1 : 0;
+}
+
+int extractCertificateSignatureAlgorithm(const uint8_t* cert_buf, size_t cert_len, char* oid_buf,
+                                         size_t oid_buf_len) {
+    if (!cert_buf || !oid_buf) {
+        ALOGE("extractCertificateSignatureAlgorithm: received null pointer");
+        return 0;
+    }
+    bssl::UniquePtr<X509> cert = parseDerCertificate(cert_buf, cert_len);
+    if (!cert) {
+        ALOGE("extractCertificateSignatureAlgorithm: failed to parse certificate");
+        return 0;
+    }
+    const X509_ALGOR* alg = nullptr;
+    X509_get0_signature(nullptr /* signature */, &alg, cert.get());
+    const ASN1_OBJECT* obj = nullptr;
+    X509_ALGOR_get0(&obj, nullptr /* parameter type */, nullptr /* parameter */, alg);
+    // Always use the numerical form, so that callers need not know the short names.
+    int len = OBJ_obj2txt(oid_buf, oid_buf_len, obj, 1 /* no_name */);
+    if (len <= 0 || static_cast<size_t>(len) >= oid_buf_len) {
+        ALOGE("extractCertificateSignatureAlgorithm: failed to encode algorithm");
+        return 0;
+    }
+    return len;
+}
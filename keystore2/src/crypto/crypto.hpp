@@ -24,6 +24,7 @@
 extern "C" {
   bool hmacSha256(const uint8_t* key, size_t key_size, const uint8_t* msg, size_t msg_size,
                   uint8_t* out, size_t out_size);
+  bool sha256Digest(const uint8_t* data, size_t data_size, uint8_t* out, size_t out_size);
   bool randomBytes(uint8_t* out, size_t len);
   bool AES_gcm_encrypt(const uint8_t* in, uint8_t* out, size_t len,
                        const uint8_t* key, size_t key_size, const uint8_t* iv, uint8_t* tag);
@@ -40,6 +41,21 @@ extern "C" {
   void generateKeyFromPassword(uint8_t* key, size_t key_len, const char* pw,
                                size_t pw_len, const uint8_t* salt);
 
+  // Derives the key with Argon2id. The salt parameter must be non-nullptr and point to 16 bytes
+  // of data. Returns false if the cost parameters are out of range or the derivation failed.
+  bool generateKeyFromPasswordArgon2id(uint8_t* key, size_t key_len, const char* pw,
+                                       size_t pw_len, const uint8_t* salt, uint32_t iterations,
+                                       uint32_t memory_kib, uint32_t parallelism);
+
+  // Only used to read Keymaster-era legacy blobs. The salt parameter must be non-nullptr and
+  // point to 16 bytes of data.
+  void generateKeyFromPasswordLegacyMd5(uint8_t* key, size_t key_len, const char* pw,
+                                        size_t pw_len, const uint8_t* salt);
+
+  // Only used to read Keymaster-era legacy blobs. The key and iv must point to 16 bytes of data.
+  bool AES_cbc_md5_decrypt(const uint8_t* in, uint8_t* out, size_t len,
+                           const uint8_t* key, const uint8_t* iv);
+
   #include "openssl/digest.h"
   #include "openssl/ec_key.h"
 
@@ -66,6 +82,24 @@ extern "C" {
 
   EC_POINT* ECPOINTOct2Point(const uint8_t *buf, size_t len);
 
+  // Digests and paddings understood by verifySignatureWithCertificate.
+  static const int VERIFY_DIGEST_SHA1 = 1;
+  static const int VERIFY_DIGEST_SHA_2_224 = 2;
+  static const int VERIFY_DIGEST_SHA_2_256 = 3;
+  static const int VERIFY_DIGEST_SHA_2_384 = 4;
+  static const int VERIFY_DIGEST_SHA_2_512 = 5;
+  static const int VERIFY_PADDING_NONE = 0;
+  static const int VERIFY_PADDING_RSA_PKCS1_1_5 = 1;
+  static const int VERIFY_PADDING_RSA_PSS = 2;
+
+  // Verifies the RSA or EC signature sig over msg with the public key of the DER-encoded X.509
+  // certificate in cert_buf. EC keys take VERIFY_PADDING_NONE, RSA keys one of the RSA paddings.
+  // Returns 1 if the signature is valid, 0 if it is not, and -1 if the certificate cannot be
+  // parsed or the digest or padding is not supported for the key.
+  int verifySignatureWithCertificate(const uint8_t* cert_buf, size_t cert_len, int digest,
+                                     int padding, const uint8_t* msg, size_t msg_len,
+                                     const uint8_t* sig, size_t sig_len);
+
 }
 
 // Parse a DER-encoded X.509 certificate contained in cert_buf, with length
@@ -90,4 +124,30 @@ extern "C" {
 int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len,
                                   uint8_t* subject_buf, size_t subject_buf_len);
 
+// Parses the first X.509 certificate in the buffer in, which may be DER or PEM encoded, and
+// writes its DER encoding to out. The number of bytes of in that make up the certificate is
+// written to consumed. The return value is overloaded like the one of
+// extractSubjectFromCertificate: the number of bytes written on success, 0 if the certificate
+// cannot be parsed, or -(size of the DER encoding) if out_len is too small.
+int normalizeCertificate(const uint8_t* in, size_t in_len, size_t* consumed, uint8_t* out,
+                         size_t out_len);
+
+// Checks that the DER-encoded certificate cert_buf was issued by the DER-encoded certificate
+// issuer_buf, i.e., that the names match and that the signature of cert_buf verifies with the
+// public key of issuer_buf. Returns 1 if it was, 0 if it was not, and -1 if either
+// certificate cannot be parsed.
+int checkCertificateIssuer(const uint8_t* cert_buf, size_t cert_len, const uint8_t* issuer_buf,
+                           size_t issuer_len);
+
+// Returns 1 if the DER-encoded certificates cert1_buf and cert2_buf carry the same public key,
+// 0 if they do not, and -1 if either certificate cannot be parsed.
+int compareCertificatePublicKeys(const uint8_t* cert1_buf, size_t cert1_len,
+                                 const uint8_t* cert2_buf, size_t cert2_len);
+
+// Writes the OID of the signature algorithm of the DER-encoded certificate cert_buf in dotted
+// decimal form to oid_buf, NUL terminated. Returns the length of the OID without the
+// terminator, or 0 if the certificate cannot be parsed or the OID does not fit.
+int extractCertificateSignatureAlgorithm(const uint8_t* cert_buf, size_t cert_len, char* oid_buf,
+                                         size_t oid_buf_len);
+
 #endif  //  __CRYPTO_H__
@@ -95,10 +95,41 @@ pub enum Error {
     #[error("Failed to extract certificate subject.")]
     ExtractSubjectFailed,
 
+    /// This is returned if the C implementation of verifySignatureWithCertificate could not
+    /// perform the verification. It is not returned for invalid signatures.
+    #[error("Failed to verify signature.")]
+    VerifySignatureFailed,
+
+    /// This is returned if the C implementation of normalizeCertificate failed.
+    #[error("Failed to normalize certificate.")]
+    NormalizeCertificateFailed,
+
+    /// This is returned if the C implementation of checkCertificateIssuer could not parse the
+    /// certificates. It is not returned if the issuer does not match.
+    #[error("Failed to check certificate issuer.")]
+    CheckCertificateIssuerFailed,
+
+    /// This is returned if the C implementation of compareCertificatePublicKeys could not parse
+    /// the certificates.
+    #[error("Failed to compare certificate public keys.")]
+    CompareCertificatePublicKeysFailed,
+
+    /// This is returned if the C implementation of extractCertificateSignatureAlgorithm failed.
+    #[error("Failed to extract the certificate signature algorithm.")]
+    ExtractCertificateSignatureAlgorithmFailed,
+
     /// This is returned if the C implementation of hmacSha256 failed.
     #[error("Failed to calculate HMAC-SHA256.")]
     HmacSha256Failed,
 
+    /// This is returned if the C implementation of generateKeyFromPasswordArgon2id failed.
+    #[error("Failed to derive key with Argon2id.")]
+    Argon2idFailed,
+
+    /// This is returned if the C implementation of sha256Digest failed.
+    #[error("Failed to calculate SHA-256.")]
+    Sha256Failed,
+
     /// Zvec error.
     #[error(transparent)]
     ZVec(#[from] zvec::Error),
@@ -19,10 +19,16 @@ mod error;
 pub mod zvec;
 pub use error::Error;
 use keystore2_crypto_bindgen::{
-    extractSubjectFromCertificate, generateKeyFromPassword, hmacSha256, randomBytes,
-    AES_gcm_decrypt, AES_gcm_encrypt, ECDHComputeKey, ECKEYGenerateKey, ECKEYMarshalPrivateKey,
-    ECKEYParsePrivateKey, ECPOINTOct2Point, ECPOINTPoint2Oct, EC_KEY_free, EC_KEY_get0_public_key,
-    EC_POINT_free, HKDFExpand, HKDFExtract, EC_KEY, EC_MAX_BYTES, EC_POINT, EVP_MAX_MD_SIZE,
+    checkCertificateIssuer, compareCertificatePublicKeys, extractCertificateSignatureAlgorithm,
+    extractSubjectFromCertificate, generateKeyFromPassword, generateKeyFromPasswordArgon2id,
+    generateKeyFromPasswordLegacyMd5, hmacSha256, normalizeCertificate, randomBytes, sha256Digest,
+    verifySignatureWithCertificate, AES_cbc_md5_decrypt, AES_gcm_decrypt, AES_gcm_encrypt,
+    ECDHComputeKey, ECKEYGenerateKey, ECKEYMarshalPrivateKey, ECKEYParsePrivateKey,
+    ECPOINTOct2Point, ECPOINTPoint2Oct, EC_KEY_free, EC_KEY_get0_public_key, EC_POINT_free,
+    HKDFExpand, HKDFExtract, EC_KEY, EC_MAX_BYTES, EC_POINT, EVP_MAX_MD_SIZE, VERIFY_DIGEST_SHA1,
+    VERIFY_DIGEST_SHA_2_224, VERIFY_DIGEST_SHA_2_256, VERIFY_DIGEST_SHA_2_384,
+    VERIFY_DIGEST_SHA_2_512, VERIFY_PADDING_NONE, VERIFY_PADDING_RSA_PKCS1_1_5,
+    VERIFY_PADDING_RSA_PSS,
 };
 use std::convert::TryFrom;
 use std::convert::TryInto;
@@ -41,6 +47,13 @@ pub const AES_128_KEY_LENGTH: usize = 16;
 pub const SALT_LENGTH: usize = 16;
 /// Length of an HMAC-SHA256 tag in bytes.
 pub const HMAC_SHA256_LEN: usize = 32;
+/// Length of a SHA-256 digest in bytes.
+pub const SHA256_LEN: usize = 32;
+
+/// Length of the MD5 digest that prefixes the plaintext of Keymaster-era legacy blobs.
+pub const LEGACY_MD5_DIGEST_LENGTH: usize = 16;
+/// Block size of the AES-CBC mode used by Keymaster-era legacy blobs.
+const LEGACY_CBC_BLOCK_SIZE: usize = 16;
 
 /// Older versions of keystore produced IVs with four extra
 /// ignored zero bytes at the end; recognise and trim those.
@@ -89,6 +102,18 @@ pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> Result<Vec<u8>, Error> {
     }
 }
 
+/// Computes the SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut digest = vec![0; SHA256_LEN];
+    // Safety: The first pair of arguments must point to a const buffer with the size given by
+    // the second argument. The second pair of arguments must point to an output buffer with the
+    // size given by the second argument of the pair.
+    match unsafe { sha256Digest(data.as_ptr(), data.len(), digest.as_mut_ptr(), digest.len()) } {
+        true => Ok(digest),
+        false => Err(Error::Sha256Failed),
+    }
+}
+
 /// Uses AES GCM to decipher a message given an initialization vector, aead tag, and key.
 /// This function accepts 128 and 256-bit keys and uses AES128 and AES256 respectively based
 /// on the key length.
@@ -172,6 +197,56 @@ pub fn aes_gcm_encrypt(plaintext: &[u8], key: &[u8]) -> Result<(Vec<u8>, Vec<u8>
     }
 }
 
+/// Uses AES-128-CBC to decipher a Keymaster-era legacy blob given an initialization vector and
+/// key. The plaintext of such blobs starts with an MD5 digest of the remainder of the plaintext,
+/// which is checked before the plaintext is returned. The digest is included in the result.
+/// This must only be used for reading legacy blobs; there is no corresponding encrypt function.
+pub fn aes_cbc_md5_decrypt_legacy(data: &[u8], iv: &[u8], key: &[u8]) -> Result<ZVec, Error> {
+    if iv.len() != LEGACY_IV_LENGTH {
+        return Err(Error::InvalidIvLength);
+    }
+    if key.len() != AES_128_KEY_LENGTH {
+        return Err(Error::InvalidKeyLength);
+    }
+    if data.len() < LEGACY_MD5_DIGEST_LENGTH || data.len() % LEGACY_CBC_BLOCK_SIZE != 0 {
+        return Err(Error::InvalidDataLength);
+    }
+
+    let mut result = ZVec::new(data.len())?;
+
+    // Safety: The first two arguments must point to buffers with a size given by the third
+    // argument. The `key` and `iv` buffers must be 16 bytes, which we check above.
+    match unsafe {
+        AES_cbc_md5_decrypt(
+            data.as_ptr(),
+            result.as_mut_ptr(),
+            data.len(),
+            key.as_ptr(),
+            iv.as_ptr(),
+        )
+    } {
+        true => Ok(result),
+        false => Err(Error::DecryptionFailed),
+    }
+}
+
+/// Cost parameters of the Argon2id key derivation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Argon2idParams {
+    /// The number of passes over the memory.
+    pub iterations: u32,
+    /// The amount of memory in KiB.
+    pub memory_kib: u32,
+    /// The number of lanes.
+    pub parallelism: u32,
+}
+
+impl Argon2idParams {
+    /// The parameters for new derivations. These follow the second recommended option of
+    /// RFC 9106, but with 32 MiB of memory to fit into the memory budget of the service.
+    pub const DEFAULT: Self = Self { iterations: 3, memory_kib: 32 * 1024, parallelism: 4 };
+}
+
 /// Represents a "password" that can be used to key the PBKDF2 algorithm.
 pub enum Password<'a> {
     /// Borrow an existing byte array
@@ -224,6 +299,74 @@ impl<'a> Password<'a> {
         Ok(result)
     }
 
+    /// Generate a key from the given password and salt using Argon2id with the cost parameters
+    /// `params`. The salt must be exactly 16 bytes long.
+    /// Two key sizes are accepted: 16 and 32 bytes.
+    pub fn derive_key_argon2id(
+        &self,
+        salt: &[u8],
+        key_length: usize,
+        params: &Argon2idParams,
+    ) -> Result<ZVec, Error> {
+        if salt.len() != SALT_LENGTH {
+            return Err(Error::InvalidSaltLength);
+        }
+        match key_length {
+            AES_128_KEY_LENGTH | AES_256_KEY_LENGTH => {}
+            _ => return Err(Error::InvalidKeyLength),
+        }
+
+        let pw = self.get_key();
+        let mut result = ZVec::new(key_length)?;
+
+        // Safety: We checked that the salt is exactly 16 bytes long. The other pointers are valid,
+        // and have matching lengths.
+        let ok = unsafe {
+            generateKeyFromPasswordArgon2id(
+                result.as_mut_ptr(),
+                result.len(),
+                pw.as_ptr() as *const std::os::raw::c_char,
+                pw.len(),
+                salt.as_ptr(),
+                params.iterations,
+                params.memory_kib,
+                params.parallelism,
+            )
+        };
+
+        if ok {
+            Ok(result)
+        } else {
+            Err(Error::Argon2idFailed)
+        }
+    }
+
+    /// Generate a key from the given password and salt using the MD5 based PBKDF2 of
+    /// Keymaster-era keystore. The salt must be exactly 16 bytes long, and the resulting key
+    /// is always 16 bytes long. This must only be used for reading legacy blobs.
+    pub fn derive_key_legacy_md5(&self, salt: &[u8]) -> Result<ZVec, Error> {
+        if salt.len() != SALT_LENGTH {
+            return Err(Error::InvalidSaltLength);
+        }
+
+        let pw = self.get_key();
+        let mut result = ZVec::new(AES_128_KEY_LENGTH)?;
+
+        // Safety: We checked that the salt is exactly 16 bytes long. The other pointers are valid,
+        // and have matching lengths.
+        unsafe {
+            generateKeyFromPasswordLegacyMd5(
+                result.as_mut_ptr(),
+                result.len(),
+                pw.as_ptr() as *const std::os::raw::c_char,
+                pw.len(),
+                salt.as_ptr(),
+            )
+        };
+
+        Ok(result)
+    }
+
     /// Try to make another Password object with the same data.
     pub fn try_clone(&self) -> Result<Password<'static>, Error> {
         Ok(Password::Owned(ZVec::try_from(self.get_key())?))
@@ -467,6 +610,181 @@ pub fn parse_subject_from_certificate(cert_buf: &[u8]) -> Result<Vec<u8>, Error>
     Ok(retval)
 }
 
+/// Digests of signatures that can be verified with `verify_signature_with_certificate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureDigest {
+    /// SHA-1.
+    Sha1,
+    /// SHA-2 224.
+    Sha224,
+    /// SHA-2 256.
+    Sha256,
+    /// SHA-2 384.
+    Sha384,
+    /// SHA-2 512.
+    Sha512,
+}
+
+/// Paddings of signatures that can be verified with `verify_signature_with_certificate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignaturePadding {
+    /// No padding, for EC signatures.
+    None,
+    /// RSASSA-PKCS1-v1_5.
+    RsaPkcs1_1_5,
+    /// RSASSA-PSS with a salt of the length of the digest.
+    RsaPss,
+}
+
+/// Uses BoringSSL to verify an RSA or EC `signature` over `message` with the public key of the
+/// DER-encoded X.509 certificate `cert_buf`. Returns Ok(false) if the signature is invalid.
+pub fn verify_signature_with_certificate(
+    cert_buf: &[u8],
+    digest: SignatureDigest,
+    padding: SignaturePadding,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<bool, Error> {
+    let digest = match digest {
+        SignatureDigest::Sha1 => VERIFY_DIGEST_SHA1,
+        SignatureDigest::Sha224 => VERIFY_DIGEST_SHA_2_224,
+        SignatureDigest::Sha256 => VERIFY_DIGEST_SHA_2_256,
+        SignatureDigest::Sha384 => VERIFY_DIGEST_SHA_2_384,
+        SignatureDigest::Sha512 => VERIFY_DIGEST_SHA_2_512,
+    };
+    let padding = match padding {
+        SignaturePadding::None => VERIFY_PADDING_NONE,
+        SignaturePadding::RsaPkcs1_1_5 => VERIFY_PADDING_RSA_PKCS1_1_5,
+        SignaturePadding::RsaPss => VERIFY_PADDING_RSA_PSS,
+    };
+    // Safety: verifySignatureWithCertificate reads at most cert_buf.len() bytes from cert_buf,
+    // message.len() bytes from message, and signature.len() bytes from signature.
+    match unsafe {
+        verifySignatureWithCertificate(
+            cert_buf.as_ptr(),
+            cert_buf.len(),
+            digest,
+            padding,
+            message.as_ptr(),
+            message.len(),
+            signature.as_ptr(),
+            signature.len(),
+        )
+    } {
+        1 => Ok(true),
+        0 => Ok(false),
+        _ => Err(Error::VerifySignatureFailed),
+    }
+}
+
+/// Uses BoringSSL to parse the first X.509 certificate in `buf`, which may be DER or PEM
+/// encoded. Returns the DER encoding of the certificate and the number of bytes of `buf` that
+/// it took up.
+pub fn normalize_certificate(buf: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    // Most certificates fit, so that the certificate rarely needs to be parsed twice.
+    let mut retval = vec![0; 2048];
+    let mut consumed: usize = 0;
+
+    // Safety: normalizeCertificate reads at most buf.len() bytes from buf, writes at most
+    // retval.len() bytes to retval, and writes a usize to consumed.
+    let mut size = unsafe {
+        normalizeCertificate(
+            buf.as_ptr(),
+            buf.len(),
+            &mut consumed,
+            retval.as_mut_ptr(),
+            retval.len(),
+        )
+    };
+
+    if size < 0 {
+        let negated_size =
+            usize::try_from(-size).map_err(|_e| Error::NormalizeCertificateFailed)?;
+        retval = vec![0; negated_size];
+
+        // Safety: normalizeCertificate reads at most buf.len() bytes from buf, writes at most
+        // retval.len() bytes to retval, and writes a usize to consumed.
+        size = unsafe {
+            normalizeCertificate(
+                buf.as_ptr(),
+                buf.len(),
+                &mut consumed,
+                retval.as_mut_ptr(),
+                retval.len(),
+            )
+        };
+    }
+
+    if size <= 0 || consumed > buf.len() {
+        return Err(Error::NormalizeCertificateFailed);
+    }
+    let safe_size = usize::try_from(size).map_err(|_e| Error::NormalizeCertificateFailed)?;
+    retval.truncate(safe_size);
+    Ok((retval, consumed))
+}
+
+/// Uses BoringSSL to check that the DER-encoded certificate `cert_buf` was issued by the
+/// DER-encoded certificate `issuer_buf`, i.e., that the names match and that the signature of
+/// `cert_buf` verifies with the public key of `issuer_buf`.
+pub fn certificate_issued_by(cert_buf: &[u8], issuer_buf: &[u8]) -> Result<bool, Error> {
+    // Safety: checkCertificateIssuer reads at most cert_buf.len() bytes from cert_buf and
+    // issuer_buf.len() bytes from issuer_buf.
+    match unsafe {
+        checkCertificateIssuer(
+            cert_buf.as_ptr(),
+            cert_buf.len(),
+            issuer_buf.as_ptr(),
+            issuer_buf.len(),
+        )
+    } {
+        1 => Ok(true),
+        0 => Ok(false),
+        _ => Err(Error::CheckCertificateIssuerFailed),
+    }
+}
+
+/// Uses BoringSSL to check if the DER-encoded certificates `cert1_buf` and `cert2_buf` carry the
+/// same public key.
+pub fn certificates_share_public_key(cert1_buf: &[u8], cert2_buf: &[u8]) -> Result<bool, Error> {
+    // Safety: compareCertificatePublicKeys reads at most cert1_buf.len() bytes from cert1_buf
+    // and cert2_buf.len() bytes from cert2_buf.
+    match unsafe {
+        compareCertificatePublicKeys(
+            cert1_buf.as_ptr(),
+            cert1_buf.len(),
+            cert2_buf.as_ptr(),
+            cert2_buf.len(),
+        )
+    } {
+        1 => Ok(true),
+        0 => Ok(false),
+        _ => Err(Error::CompareCertificatePublicKeysFailed),
+    }
+}
+
+/// Uses BoringSSL to extract the signature algorithm of the DER-encoded certificate `cert_buf`.
+/// Returns its OID in dotted decimal form, e.g., "1.2.840.10045.4.3.2" for ECDSA with SHA-256.
+pub fn certificate_signature_algorithm(cert_buf: &[u8]) -> Result<String, Error> {
+    // Registered signature algorithm OIDs are much shorter than this.
+    let mut oid = vec![0u8; 128];
+    // Safety: extractCertificateSignatureAlgorithm reads at most cert_buf.len() bytes from
+    // cert_buf and writes at most oid.len() bytes to oid.
+    let len = unsafe {
+        extractCertificateSignatureAlgorithm(
+            cert_buf.as_ptr(),
+            cert_buf.len(),
+            oid.as_mut_ptr() as *mut std::os::raw::c_char,
+            oid.len(),
+        )
+    };
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len > 0)
+        .ok_or(Error::ExtractCertificateSignatureAlgorithmFailed)?;
+    oid.truncate(len);
+    String::from_utf8(oid).map_err(|_| Error::ExtractCertificateSignatureAlgorithmFailed)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -554,6 +872,28 @@ mod tests {
         assert_ne!(key, vec![0; 16]);
     }
 
+    #[test]
+    fn test_derive_key_argon2id() -> Result<(), Error> {
+        let pw: Password = b"password"[..].into();
+        let salt = [1; SALT_LENGTH];
+        let params = Argon2idParams { iterations: 1, memory_kib: 64, parallelism: 1 };
+        let key = pw.derive_key_argon2id(&salt, AES_256_KEY_LENGTH, &params)?;
+        assert_eq!(key.len(), AES_256_KEY_LENGTH);
+        assert_eq!(key[..], pw.derive_key_argon2id(&salt, AES_256_KEY_LENGTH, &params)?[..]);
+        assert_ne!(key[..], pw.derive_key(&salt, AES_256_KEY_LENGTH)?[..]);
+
+        let costlier = Argon2idParams { iterations: 2, ..params };
+        assert_ne!(key[..], pw.derive_key_argon2id(&salt, AES_256_KEY_LENGTH, &costlier)?[..]);
+
+        // Argon2 needs at least 8 KiB of memory per lane.
+        let too_little_memory = Argon2idParams { memory_kib: 1, ..params };
+        assert_eq!(
+            Err(Error::Argon2idFailed),
+            pw.derive_key_argon2id(&salt, AES_256_KEY_LENGTH, &too_little_memory).map(|_| ())
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_hkdf() {
         let result = hkdf_extract(&[0; 16], &[0; 16]);
@@ -602,4 +942,135 @@ mod tests {
         assert_eq!(tag2.len(), HMAC_SHA256_LEN);
         assert_ne!(tag1a, tag2);
     }
+
+    #[test]
+    fn test_sha256() {
+        // The SHA-256 test vector "abc" from FIPS 180-2, appendix B.1.
+        assert_eq!(
+            sha256(b"abc").unwrap(),
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad,
+            ]
+        );
+    }
+
+    // A self-signed EC P-256 certificate and an ECDSA SHA-256 signature over VERIFY_MESSAGE.
+    const VERIFY_CERT: &[u8] = &[
+        0x30, 0x82, 0x01, 0x83, 0x30, 0x82, 0x01, 0x29, 0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x14,
+        0x2e, 0xc0, 0xec, 0x56, 0xbb, 0x43, 0xe3, 0xfe, 0xf9, 0x4e, 0xfd, 0x84, 0x4c, 0xea, 0x72,
+        0xcc, 0x04, 0x20, 0x42, 0xab, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04,
+        0x03, 0x02, 0x30, 0x16, 0x31, 0x14, 0x30, 0x12, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x0b,
+        0x76, 0x65, 0x72, 0x69, 0x66, 0x79, 0x20, 0x74, 0x65, 0x73, 0x74, 0x30, 0x20, 0x17, 0x0d,
+        0x32, 0x36, 0x31, 0x30, 0x31, 0x36, 0x31, 0x30, 0x30, 0x33, 0x32, 0x35, 0x5a, 0x18, 0x0f,
+        0x32, 0x31, 0x32, 0x36, 0x30, 0x39, 0x32, 0x32, 0x31, 0x30, 0x30, 0x33, 0x32, 0x35, 0x5a,
+        0x30, 0x16, 0x31, 0x14, 0x30, 0x12, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x0b, 0x76, 0x65,
+        0x72, 0x69, 0x66, 0x79, 0x20, 0x74, 0x65, 0x73, 0x74, 0x30, 0x59, 0x30, 0x13, 0x06, 0x07,
+        0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03,
+        0x01, 0x07, 0x03, 0x42, 0x00, 0x04, 0x19, 0xa6, 0x7f, 0xc1, 0x8d, 0x6a, 0xbd, 0x68, 0x90,
+        0x0a, 0x76, 0x0e, 0xf1, 0x83, 0x80, 0x8d, 0xba, 0xda, 0x06, 0x6d, 0x49, 0xc8, 0x79, 0xa3,
+        0x23, 0x0e, 0xa3, 0x18, 0x5a, 0x74, 0x9b, 0x46, 0xe4, 0xcc, 0x0f, 0xa9, 0xad, 0x3c, 0xfa,
+        0x51, 0x91, 0xc0, 0x58, 0xad, 0x39, 0xdb, 0xa8, 0xdf, 0xb4, 0xe7, 0xc1, 0xd3, 0x91, 0x68,
+        0xfc, 0xf8, 0x4a, 0xe0, 0x89, 0x3c, 0x77, 0xfe, 0x34, 0x02, 0xa3, 0x53, 0x30, 0x51, 0x30,
+        0x1d, 0x06, 0x03, 0x55, 0x1d, 0x0e, 0x04, 0x16, 0x04, 0x14, 0xb6, 0x51, 0x1e, 0xe0, 0x45,
+        0xa1, 0xc1, 0xf9, 0xa1, 0xdd, 0x26, 0x22, 0x96, 0x69, 0xba, 0x5c, 0xa8, 0x52, 0x7a, 0x68,
+        0x30, 0x1f, 0x06, 0x03, 0x55, 0x1d, 0x23, 0x04, 0x18, 0x30, 0x16, 0x80, 0x14, 0xb6, 0x51,
+        0x1e, 0xe0, 0x45, 0xa1, 0xc1, 0xf9, 0xa1, 0xdd, 0x26, 0x22, 0x96, 0x69, 0xba, 0x5c, 0xa8,
+        0x52, 0x7a, 0x68, 0x30, 0x0f, 0x06, 0x03, 0x55, 0x1d, 0x13, 0x01, 0x01, 0xff, 0x04, 0x05,
+        0x30, 0x03, 0x01, 0x01, 0xff, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04,
+        0x03, 0x02, 0x03, 0x48, 0x00, 0x30, 0x45, 0x02, 0x21, 0x00, 0xcc, 0x03, 0xdb, 0xd2, 0x2c,
+        0x43, 0x6a, 0xe7, 0xe5, 0x01, 0x7a, 0x20, 0x03, 0x87, 0x27, 0x0b, 0xd3, 0xb9, 0xc2, 0xf4,
+        0x4d, 0x86, 0x70, 0x63, 0x58, 0x1b, 0x0f, 0xb4, 0x96, 0x44, 0x34, 0x34, 0x02, 0x20, 0x66,
+        0x91, 0x90, 0x5a, 0xec, 0x8b, 0x8c, 0x94, 0x07, 0x7c, 0x92, 0xe5, 0x33, 0xa6, 0x6e, 0x17,
+        0xdd, 0x60, 0x03, 0xfa, 0x9f, 0xd8, 0x2c, 0x82, 0x93, 0x8d, 0xde, 0x91, 0x75, 0x0c, 0x47,
+        0x4c,
+    ];
+    const VERIFY_SIGNATURE: &[u8] = &[
+        0x30, 0x46, 0x02, 0x21, 0x00, 0xee, 0x4a, 0xaf, 0x43, 0xf7, 0x77, 0xad, 0x36, 0xcb, 0x66,
+        0x74, 0x46, 0xe8, 0xda, 0x6a, 0xd4, 0x83, 0xd4, 0x2d, 0x26, 0x90, 0xda, 0xfb, 0x13, 0xbd,
+        0x3f, 0xd6, 0xa2, 0x90, 0xd2, 0xba, 0xe5, 0x02, 0x21, 0x00, 0xec, 0x01, 0xff, 0x03, 0x40,
+        0xb4, 0xe9, 0xe5, 0x73, 0xd3, 0xca, 0x24, 0xc2, 0xe5, 0x38, 0xb0, 0xc7, 0xd6, 0x90, 0xdd,
+        0xe8, 0xc0, 0x53, 0xa3, 0xde, 0xcd, 0x4b, 0xbd, 0xb9, 0xca, 0xe9, 0xc6,
+    ];
+    const VERIFY_MESSAGE: &[u8] = b"message to sign";
+
+    #[test]
+    fn test_verify_signature_with_certificate() -> Result<(), Error> {
+        let verify = |message: &[u8], signature: &[u8]| {
+            verify_signature_with_certificate(
+                VERIFY_CERT,
+                SignatureDigest::Sha256,
+                SignaturePadding::None,
+                message,
+                signature,
+            )
+        };
+        assert!(verify(VERIFY_MESSAGE, VERIFY_SIGNATURE)?);
+        assert!(!verify(b"another message", VERIFY_SIGNATURE)?);
+        assert!(!verify(VERIFY_MESSAGE, &VERIFY_SIGNATURE[..VERIFY_SIGNATURE.len() - 1])?);
+
+        // RSA paddings do not apply to EC keys, and the certificate must be well formed.
+        assert_eq!(
+            Err(Error::VerifySignatureFailed),
+            verify_signature_with_certificate(
+                VERIFY_CERT,
+                SignatureDigest::Sha256,
+                SignaturePadding::RsaPss,
+                VERIFY_MESSAGE,
+                VERIFY_SIGNATURE,
+            )
+        );
+        assert_eq!(
+            Err(Error::VerifySignatureFailed),
+            verify_signature_with_certificate(
+                &VERIFY_CERT[1..],
+                SignatureDigest::Sha256,
+                SignaturePadding::None,
+                VERIFY_MESSAGE,
+                VERIFY_SIGNATURE,
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_certificate() -> Result<(), Error> {
+        let mut buf = VERIFY_CERT.to_vec();
+        buf.extend_from_slice(b"trailing data");
+        assert_eq!((VERIFY_CERT.to_vec(), VERIFY_CERT.len()), normalize_certificate(&buf)?);
+        assert_eq!(
+            Err(Error::NormalizeCertificateFailed),
+            normalize_certificate(&VERIFY_CERT[..VERIFY_CERT.len() - 1])
+        );
+        assert_eq!(Err(Error::NormalizeCertificateFailed), normalize_certificate(b""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_certificate_signature_algorithm() {
+        assert_eq!(
+            Ok("1.2.840.10045.4.3.2".to_string()),
+            certificate_signature_algorithm(VERIFY_CERT)
+        );
+        assert_eq!(
+            Err(Error::ExtractCertificateSignatureAlgorithmFailed),
+            certificate_signature_algorithm(&VERIFY_CERT[1..])
+        );
+    }
+
+    #[test]
+    fn test_self_signed_certificate_issuer() -> Result<(), Error> {
+        assert!(certificate_issued_by(VERIFY_CERT, VERIFY_CERT)?);
+        assert!(certificates_share_public_key(VERIFY_CERT, VERIFY_CERT)?);
+        assert_eq!(
+            Err(Error::CheckCertificateIssuerFailed),
+            certificate_issued_by(VERIFY_CERT, &VERIFY_CERT[1..])
+        );
+        assert_eq!(
+            Err(Error::CompareCertificatePublicKeysFailed),
+            certificates_share_public_key(&VERIFY_CERT[1..], VERIFY_CERT)
+        );
+        Ok(())
+    }
 }
@@ -14,7 +14,14 @@
 
 //! Implements ZVec, a vector that is mlocked during its lifetime and zeroed
 //! when dropped.
+//!
+//! A ZVec normally lives on the heap, where it may share its pages with other
+//! allocations. Guarded ZVecs, which hold long lived secrets such as super keys,
+//! get pages of their own instead. These pages are locked, excluded from core
+//! dumps, and surrounded by inaccessible guard pages. The data ends right at the
+//! trailing guard page, so that reading past its end faults immediately.
 
+use nix::errno::Errno;
 use nix::sys::mman::{mlock, munlock};
 use std::convert::TryFrom;
 use std::fmt;
@@ -25,12 +32,124 @@ use std::ptr::write_volatile;
 /// size but cannot grow larger than the original size (and if it shrinks it
 /// still owns the entire buffer).  Also the data is pinned in memory with
 /// mlock.
-#[derive(Default, Eq, PartialEq)]
+#[derive(Default)]
 pub struct ZVec {
-    elems: Box<[u8]>,
+    elems: Storage,
     len: usize,
 }
 
+enum Storage {
+    Heap(Box<[u8]>),
+    Guarded(GuardedPages),
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::Heap(Default::default())
+    }
+}
+
+impl Storage {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Heap(b) => b,
+            Self::Guarded(g) => g.as_slice(),
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            Self::Heap(b) => b,
+            Self::Guarded(g) => g.as_mut_slice(),
+        }
+    }
+}
+
+/// A private anonymous mapping that holds `size` bytes between two guard pages.
+struct GuardedPages {
+    mapping: *mut u8,
+    mapping_len: usize,
+    data: *mut u8,
+    size: usize,
+}
+
+// SAFETY: GuardedPages owns its mapping exclusively, like a Box owns its allocation.
+unsafe impl Send for GuardedPages {}
+// SAFETY: Shared references only allow reads of the mapping.
+unsafe impl Sync for GuardedPages {}
+
+impl GuardedPages {
+    fn new(size: usize) -> Result<Self, Error> {
+        // SAFETY: sysconf has no preconditions.
+        let page = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            page if page > 0 => page as usize,
+            _ => return Err(Error::NixError(Errno::last())),
+        };
+        let data_len = size.div_ceil(page) * page;
+        let mapping_len = data_len + 2 * page;
+        // SAFETY: An anonymous mapping at an address of the kernel's choosing does not affect
+        // any existing memory.
+        let mapping = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapping_len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if mapping == libc::MAP_FAILED {
+            return Err(Error::NixError(Errno::last()));
+        }
+        // From here on, dropping `pages` unmaps the mapping.
+        let pages = Self {
+            mapping: mapping as *mut u8,
+            mapping_len,
+            // SAFETY: The offset is within the mapping.
+            data: unsafe { (mapping as *mut u8).add(page + data_len - size) },
+            size,
+        };
+        // SAFETY: The data pages are part of the mapping.
+        let data_pages = unsafe { pages.mapping.add(page) } as *mut libc::c_void;
+        // SAFETY: The address range is part of the mapping, which nothing else refers to.
+        if unsafe { libc::mprotect(data_pages, data_len, libc::PROT_READ | libc::PROT_WRITE) } != 0
+        {
+            return Err(Error::NixError(Errno::last()));
+        }
+        // SAFETY: The address range is part of the mapping.
+        unsafe { mlock(data_pages, data_len) }?;
+        // Excluding the pages from core dumps is best effort.
+        // SAFETY: The address range is part of the mapping.
+        if unsafe { libc::madvise(data_pages, data_len, libc::MADV_DONTDUMP) } != 0 {
+            log::warn!("In GuardedPages::new: `madvise` failed: {:?}.", Errno::last());
+        }
+        Ok(pages)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: `data` points to `size` readable bytes that live as long as `self`.
+        unsafe { std::slice::from_raw_parts(self.data, self.size) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: `data` points to `size` writable bytes that live as long as `self`, and the
+        // mutable reference to `self` makes the access exclusive.
+        unsafe { std::slice::from_raw_parts_mut(self.data, self.size) }
+    }
+}
+
+impl Drop for GuardedPages {
+    fn drop(&mut self) {
+        // Unmapping also unlocks the pages.
+        // SAFETY: The mapping was created in `GuardedPages::new` and nothing refers to it
+        // any more.
+        if unsafe { libc::munmap(self.mapping as *mut libc::c_void, self.mapping_len) } != 0 {
+            log::error!("In GuardedPages::drop: `munmap` failed: {:?}.", Errno::last());
+        }
+    }
+}
+
 /// ZVec specific error codes.
 #[derive(Debug, thiserror::Error, Eq, PartialEq)]
 pub enum Error {
@@ -48,21 +167,44 @@ impl ZVec {
             // SAFETY: The address range is part of our address space.
             unsafe { mlock(b.as_ptr() as *const std::ffi::c_void, b.len()) }?;
         }
-        Ok(Self { elems: b, len: size })
+        Ok(Self { elems: Storage::Heap(b), len: size })
+    }
+
+    /// Create a guarded ZVec with the given size. See the module documentation.
+    pub fn new_guarded(size: usize) -> Result<Self, Error> {
+        if size == 0 {
+            return Ok(Self::default());
+        }
+        Ok(Self { elems: Storage::Guarded(GuardedPages::new(size)?), len: size })
+    }
+
+    /// Copies the contents of this ZVec into a new guarded ZVec.
+    pub fn try_clone_guarded(&self) -> Result<Self, Error> {
+        let mut result = Self::new_guarded(self.len())?;
+        result[..].copy_from_slice(&self[..]);
+        Ok(result)
+    }
+
+    /// Returns true if this ZVec has pages of its own with guard pages around them.
+    pub fn is_guarded(&self) -> bool {
+        matches!(self.elems, Storage::Guarded(_))
     }
 
     /// Reduce the length to the given value.  Does nothing if that length is
     /// greater than the length of the vector.  Note that it still owns the
     /// original allocation even if the length is reduced.
     pub fn reduce_len(&mut self, len: usize) {
-        if len <= self.elems.len() {
+        if len <= self.elems.as_slice().len() {
             self.len = len;
         }
     }
 
     /// Attempts to make a clone of the Zvec. This may fail due trying to mlock
-    /// the new memory region.
+    /// the new memory region. The clone of a guarded ZVec is guarded as well.
     pub fn try_clone(&self) -> Result<Self, Error> {
+        if self.is_guarded() {
+            return self.try_clone_guarded();
+        }
         let mut result = Self::new(self.len())?;
         result[..].copy_from_slice(&self[..]);
         Ok(result)
@@ -71,41 +213,53 @@ impl ZVec {
 
 impl Drop for ZVec {
     fn drop(&mut self) {
-        for i in 0..self.elems.len() {
+        let elems = self.elems.as_mut_slice();
+        for i in 0..elems.len() {
             // SAFETY: The pointer is valid and properly aligned because it came from a reference.
-            unsafe { write_volatile(&mut self.elems[i], 0) };
-        }
-        if !self.elems.is_empty() {
-            if let Err(e) =
-                // SAFETY: The address range is part of our address space, and was previously locked
-                // by `mlock` in `ZVec::new` or the `TryFrom<Vec<u8>>` implementation.
-                unsafe {
-                    munlock(self.elems.as_ptr() as *const std::ffi::c_void, self.elems.len())
+            unsafe { write_volatile(&mut elems[i], 0) };
+        }
+        // Guarded pages are unlocked when they are unmapped.
+        if let Storage::Heap(elems) = &self.elems {
+            if !elems.is_empty() {
+                if let Err(e) =
+                    // SAFETY: The address range is part of our address space, and was previously
+                    // locked by `mlock` in `ZVec::new` or the `TryFrom<Vec<u8>>` implementation.
+                    unsafe {
+                        munlock(elems.as_ptr() as *const std::ffi::c_void, elems.len())
+                    }
+                {
+                    log::error!("In ZVec::drop: `munlock` failed: {:?}.", e);
                 }
-            {
-                log::error!("In ZVec::drop: `munlock` failed: {:?}.", e);
             }
         }
     }
 }
 
+impl PartialEq for ZVec {
+    fn eq(&self, other: &Self) -> bool {
+        self.elems.as_slice() == other.elems.as_slice() && self.len == other.len
+    }
+}
+
+impl Eq for ZVec {}
+
 impl Deref for ZVec {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.elems[0..self.len]
+        &self.elems.as_slice()[0..self.len]
     }
 }
 
 impl DerefMut for ZVec {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.elems[0..self.len]
+        &mut self.elems.as_mut_slice()[0..self.len]
     }
 }
 
 impl fmt::Debug for ZVec {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.elems.is_empty() {
+        if self.elems.as_slice().is_empty() {
             write!(f, "Zvec empty")
         } else {
             write!(f, "Zvec size: {} [ Sensitive information redacted ]", self.len)
@@ -139,6 +293,42 @@ impl TryFrom<Vec<u8>> for ZVec {
             // SAFETY: The address range is part of our address space.
             unsafe { mlock(b.as_ptr() as *const std::ffi::c_void, b.len()) }?;
         }
-        Ok(Self { elems: b, len })
+        Ok(Self { elems: Storage::Heap(b), len })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guarded_zvec_round_trip() -> Result<(), Error> {
+        let mut z = ZVec::new_guarded(32)?;
+        assert!(z.is_guarded());
+        assert_eq!(&[0u8; 32][..], &z[..]);
+        z.copy_from_slice(&[7u8; 32]);
+        let clone = z.try_clone()?;
+        assert!(clone.is_guarded());
+        assert_eq!(z, clone);
+        z.reduce_len(16);
+        assert_eq!(&[7u8; 16][..], &z[..]);
+
+        let heap = ZVec::try_from(&[7u8; 32][..])?;
+        assert!(!heap.is_guarded());
+        assert_eq!(heap, clone);
+        assert!(heap.try_clone_guarded()?.is_guarded());
+        Ok(())
+    }
+
+    #[test]
+    fn guarded_data_ends_at_page_boundary() -> Result<(), Error> {
+        // SAFETY: sysconf has no preconditions.
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        for size in [1, 32, page, page + 1] {
+            let z = ZVec::new_guarded(size)?;
+            assert_eq!(0, (z.as_ptr() as usize + size) % page, "size {}", size);
+        }
+        assert!(!ZVec::new_guarded(0)?.is_guarded());
+        Ok(())
     }
 }
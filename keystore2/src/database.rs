@@ -42,21 +42,31 @@
 //! callbacks.
 
 mod perboot;
+pub mod shadow;
+mod skeleton;
 pub(crate) mod utils;
 mod versioning;
 
+use crate::feature_flags::{self, Feature};
 use crate::gc::Gc;
+use crate::grant_cache::{self, GRANT_CACHE};
 use crate::impl_metadata; // This is in db_utils.rs
 use crate::key_parameter::{KeyParameter, Tag};
 use crate::ks_err;
+use crate::memory_trim;
+use crate::namespace::Namespace;
 use crate::permission::KeyPermSet;
-use crate::utils::{get_current_time_in_milliseconds, watchdog as wd, AID_USER_OFFSET};
+use crate::post_mortem::{self, FatalEvent};
+use crate::utils::{
+    get_current_time_in_milliseconds, watchdog as wd, AID_APP_START, AID_USER_OFFSET,
+};
 use crate::{
-    error::{Error as KsError, ErrorCode, ResponseCode},
+    error::{DatabaseErrorKind, Error as KsError, ErrorCode, ResponseCode, KEY_CHANGED},
     super_key::SuperKeyType,
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    HardwareAuthToken::HardwareAuthToken, HardwareAuthenticatorType::HardwareAuthenticatorType,
+    Algorithm::Algorithm, HardwareAuthToken::HardwareAuthToken,
+    HardwareAuthenticatorType::HardwareAuthenticatorType, KeyPurpose::KeyPurpose,
     SecurityLevel::SecurityLevel,
 };
 use android_security_metrics::aidl::android::security::metrics::{
@@ -71,7 +81,7 @@ use std::{convert::TryFrom, convert::TryInto, ops::Deref, time::SystemTimeError}
 use utils as db_utils;
 use utils::SqlField;
 
-use keystore2_crypto::ZVec;
+use keystore2_crypto::{hmac_sha256, sha256, ZVec};
 use lazy_static::lazy_static;
 use log::error;
 #[cfg(not(test))]
@@ -86,8 +96,9 @@ use rusqlite::{
 };
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     path::Path,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
     sync::{Arc, Condvar, Mutex},
     time::{Duration, SystemTime},
 };
@@ -114,6 +125,23 @@ impl_metadata!(
         AttestationRawPubKey(Vec<u8>) with accessor attestation_raw_pub_key,
         /// SEC1 public key for ECDH encryption
         Sec1PublicKey(Vec<u8>) with accessor sec1_public_key,
+        /// Imported key material that the owner marked as eligible for backup, sealed with
+        /// the owner's AfterFirstUnlock super key. See `key_backup`.
+        BackupMaterial(Vec<u8>) with accessor backup_material,
+        /// OID of the signature algorithm of the leaf certificate, in dotted decimal form.
+        /// Only recorded for keys that KeyMint issued a self-signed certificate for, i.e.,
+        /// keys without attestation. See `IKeystoreServiceExtension::getCertSignatureAlgorithm`.
+        CertSignatureAlgorithm(String) with accessor cert_signature_algorithm,
+        /// Encoded window of hours of the day in which the key may be used. See
+        /// `access_window`.
+        AccessWindow(i32) with accessor access_window,
+        /// If the nonces of the key are picked by keystore, this is the prefix of its nonces.
+        /// See `managed_nonce`.
+        ManagedNoncePrefix(Vec<u8>) with accessor managed_nonce_prefix,
+        /// The first value of the nonce counter of the key that was not reserved yet.
+        ManagedNonceCounter(i64) with accessor managed_nonce_counter,
+        /// Fingerprint of the imported key material. See `import_fingerprint`.
+        ImportFingerprint(Vec<u8>) with accessor import_fingerprint,
         //  --- ADD NEW META DATA FIELDS HERE ---
         // For backwards compatibility add new entries only to
         // end of this list and above this comment.
@@ -190,6 +218,16 @@ impl_metadata!(
         /// If the key is encrypted with a MaxBootLevel key, this is the boot level
         /// of that key
         MaxBootLevel(i32) with accessor max_boot_level,
+        /// If the blob is a password encrypted super key that is bound to a secret in a rollback
+        /// protected store, this is the handle of the secret.
+        SecretHandle(Vec<u8>) with accessor secret_handle,
+        /// If the blob is encrypted with a key derived from a password using Argon2id, this is
+        /// the number of iterations of the derivation. Otherwise PBKDF2 was used.
+        Argon2idIterations(i32) with accessor argon2id_iterations,
+        /// The memory cost of the Argon2id derivation in KiB.
+        Argon2idMemoryKib(i32) with accessor argon2id_memory_kib,
+        /// The parallelism of the Argon2id derivation.
+        Argon2idParallelism(i32) with accessor argon2id_parallelism,
         //  --- ADD NEW META DATA FIELDS HERE ---
         // For backwards compatibility add new entries only to
         // end of this list and above this comment.
@@ -270,6 +308,37 @@ impl FromSql for KeyType {
     }
 }
 
+/// Selects the keys of a user that `KeystoreDB::unbind_keys_for_user` unbinds. The keys of a
+/// user in `Domain::APP` are its super keys and the client keys in the namespaces of its apps.
+/// Its keys in `Domain::SELINUX` are the client keys that are encrypted with one of its super
+/// keys.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UserKeyFilter {
+    /// Only keys of this type, or client and super keys if None.
+    pub key_type: Option<KeyType>,
+    /// Only keys in this domain, or keys in any domain if None.
+    pub domain: Option<Domain>,
+    /// Keeps the keys whose blobs are not encrypted with a super key or, for super keys, with a
+    /// password.
+    pub keep_non_super_encrypted_keys: bool,
+}
+
+impl UserKeyFilter {
+    /// The super keys of the user and the client keys of its apps.
+    pub const APP_AND_SUPER_KEYS: Self =
+        Self { key_type: None, domain: Some(Domain::APP), keep_non_super_encrypted_keys: false };
+
+    /// Returns this filter, but keeping the keys that are not super-encrypted.
+    pub const fn super_encrypted_only(self) -> Self {
+        Self { keep_non_super_encrypted_keys: true, ..self }
+    }
+
+    /// Returns true if keys of type `key_type` in `domain` pass the filter.
+    pub fn includes(&self, key_type: KeyType, domain: Domain) -> bool {
+        self.key_type.map_or(true, |t| t == key_type) && self.domain.map_or(true, |d| d == domain)
+    }
+}
+
 /// Uuid representation that can be stored in the database.
 /// Right now it can only be initialized from SecurityLevel.
 /// Once KeyMint provides a UUID type a corresponding From impl shall be added.
@@ -324,6 +393,14 @@ pub enum EncryptedBy {
     /// In the database this variant is represented as non NULL value
     /// that is convertible to i64, typically NUMERIC.
     KeyId(i64),
+    /// The keyblob is the resume-on-reboot copy of a super key, encrypted by a key derived from
+    /// the secret that RebootEscrow keeps across the reboot.
+    /// In the database this variant is represented as the TEXT `REBOOT_ESCROW_TAG`.
+    RebootEscrow,
+}
+
+impl EncryptedBy {
+    const REBOOT_ESCROW_TAG: &'static str = "reboot_escrow";
 }
 
 impl ToSql for EncryptedBy {
@@ -331,6 +408,7 @@ impl ToSql for EncryptedBy {
         match self {
             Self::Password => Ok(ToSqlOutput::Owned(Value::Null)),
             Self::KeyId(id) => id.to_sql(),
+            Self::RebootEscrow => Self::REBOOT_ESCROW_TAG.to_sql(),
         }
     }
 }
@@ -339,6 +417,9 @@ impl FromSql for EncryptedBy {
     fn column_result(value: ValueRef) -> FromSqlResult<Self> {
         match value {
             ValueRef::Null => Ok(Self::Password),
+            ValueRef::Text(tag) if tag == Self::REBOOT_ESCROW_TAG.as_bytes() => {
+                Ok(Self::RebootEscrow)
+            }
             _ => Ok(Self::KeyId(i64::column_result(value)?)),
         }
     }
@@ -472,6 +553,171 @@ impl FromSql for KeyLifeCycle {
     }
 }
 
+/// Lifecycle events of keys that are recorded in the key history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyHistoryEvent {
+    /// The key was generated or imported.
+    Created = 1,
+    /// The key was moved to a different alias or namespace.
+    Rebound = 2,
+    /// The key was deleted, replaced by a new key with the same alias, or used up.
+    Deleted = 3,
+    /// The key was invalidated because the user removed their lock screen knowledge factor.
+    Invalidated = 4,
+}
+
+impl ToSql for KeyHistoryEvent {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(*self as i64)))
+    }
+}
+
+impl FromSql for KeyHistoryEvent {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match i64::column_result(value)? {
+            1 => Ok(Self::Created),
+            2 => Ok(Self::Rebound),
+            3 => Ok(Self::Deleted),
+            4 => Ok(Self::Invalidated),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+/// An entry of the key history. The domain and namespace are those of the key at the time of
+/// the event. Neither aliases nor key ids are recorded, keys are identified by the hash of
+/// their key id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyHistoryEntry {
+    /// The hash of the id of the key entry, see `KeystoreDB::key_id_hash`.
+    pub key_id_hash: i64,
+    /// The recorded event.
+    pub event: KeyHistoryEvent,
+    /// The domain of the key.
+    pub domain: Domain,
+    /// The namespace of the key.
+    pub namespace: i64,
+    /// The wall clock time of the event.
+    pub time: DateTime,
+}
+
+/// The non-sensitive characteristics of a key, as exported for provisioning audits. Key blobs,
+/// certificates, and metadata are not part of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyCharacteristicsEntry {
+    /// The id of the key entry.
+    pub key_id: i64,
+    /// The namespace of the key.
+    pub namespace: i64,
+    /// The alias of the key.
+    pub alias: Option<String>,
+    /// The authorizations of the key.
+    pub parameters: Vec<KeyParameter>,
+}
+
+/// Classes of file based encryption keys that vold stores in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKeyClass {
+    /// The key of the device encrypted storage of a user.
+    DeviceEncrypted = 1,
+    /// The key of the credential encrypted storage of a user.
+    CredentialEncrypted = 2,
+}
+
+impl ToSql for StorageKeyClass {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(*self as i64)))
+    }
+}
+
+/// A storage key blob together with its metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageKeyEntry {
+    /// The key blob as registered by vold.
+    pub key_blob: Vec<u8>,
+    /// Starts at 1 when the key is registered and is incremented on every rotation.
+    pub generation: i64,
+    /// The wall clock time at which the blob was registered or rotated in.
+    pub created: DateTime,
+}
+
+/// Types of the credential blobs that platform components keep in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialType {
+    /// A VPN profile.
+    VpnProfile = 1,
+    /// A Wi-Fi credential.
+    WifiCredential = 2,
+}
+
+impl ToSql for CredentialType {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(*self as i64)))
+    }
+}
+
+/// A credential blob as stored in the database, encrypted with the AfterFirstUnlock super key
+/// of the owner's user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CredentialEntry {
+    /// The encrypted blob.
+    pub blob: Vec<u8>,
+    /// The IV of the encryption.
+    pub iv: Vec<u8>,
+    /// The AEAD tag of the encryption.
+    pub aead_tag: Vec<u8>,
+}
+
+/// Rows that `KeystoreDB::check_consistency` found to reference key entries that no longer
+/// exist, and deleted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// Grants of deleted keys.
+    pub dangling_grants: usize,
+    /// Metadata rows of deleted keys.
+    pub orphaned_key_metadata: usize,
+    /// Key parameters of deleted keys.
+    pub orphaned_key_parameters: usize,
+}
+
+impl ConsistencyReport {
+    /// Returns the total number of anomalies that were repaired.
+    pub fn anomalies(&self) -> usize {
+        self.dangling_grants + self.orphaned_key_metadata + self.orphaned_key_parameters
+    }
+}
+
+/// Reasons for which a key needs the attention of a background maintenance job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyMaintenanceReason {
+    /// The key was created at an older OS patch level. KeyMint rejects such keys until they
+    /// are upgraded.
+    RequiresUpgrade = 1,
+    /// The key blob is encrypted with the public UnlockedDeviceRequired super key, because it
+    /// was created while the device was locked. It must be re-wrapped with the symmetric super
+    /// key.
+    RequiresRewrap = 2,
+    /// The key uses a deprecated algorithm, i.e., 3DES, or RSA with fewer than 2048 bits.
+    DeprecatedAlgorithm = 3,
+}
+
+impl ToSql for KeyMaintenanceReason {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(*self as i64)))
+    }
+}
+
+impl FromSql for KeyMaintenanceReason {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match i64::column_result(value)? {
+            1 => Ok(Self::RequiresUpgrade),
+            2 => Ok(Self::RequiresRewrap),
+            3 => Ok(Self::DeprecatedAlgorithm),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
 /// Keys have a KeyMint blob component and optional public certificate and
 /// certificate chain components.
 /// KeyEntryLoadBits is a bitmap that indicates to `KeystoreDB::load_key_entry`
@@ -752,12 +998,37 @@ impl<T> DoGc<T> for Result<T> {
     }
 }
 
+/// The category of a database transaction. It is included in watchdog reports and in the slow
+/// query log, so that a stalled binder thread can be attributed without logging key material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionCategory {
+    /// Database initialization and schema upgrades.
+    Init,
+    /// Lookups of key entries and their components.
+    KeyRead,
+    /// Creation, update, and deletion of key entries.
+    KeyWrite,
+    /// Creation and removal of grants.
+    Grant,
+    /// Storage and retrieval of super keys.
+    SuperKey,
+    /// Garbage collection of unreferenced keys and superseded blobs.
+    Gc,
+    /// Metrics and maintenance queries.
+    Maintenance,
+}
+
+/// Incremented by the memory trimmer to ask all connections to release their page cache.
+static SHRINK_GENERATION: AtomicU64 = AtomicU64::new(0);
+
 /// KeystoreDB wraps a connection to an SQLite database and tracks its
 /// ownership. It also implements all of Keystore 2.0's database functionality.
 pub struct KeystoreDB {
     conn: Connection,
     gc: Option<Arc<Gc>>,
     perboot: Arc<perboot::PerbootDB>,
+    /// The value of `SHRINK_GENERATION` when this connection last released its page cache.
+    shrink_generation: u64,
 }
 
 /// Database representation of the monotonic time retrieved from the system call clock_gettime with
@@ -771,6 +1042,11 @@ impl MonotonicRawTime {
         Self(get_current_time_in_milliseconds())
     }
 
+    /// Constructs a MonotonicRawTime from milliseconds.
+    pub fn from_millis(millis: i64) -> Self {
+        Self(millis)
+    }
+
     /// Returns the value of MonotonicRawTime in milliseconds as i64
     pub fn milliseconds(&self) -> i64 {
         self.0
@@ -809,7 +1085,8 @@ pub struct AuthTokenEntry {
 }
 
 impl AuthTokenEntry {
-    fn new(auth_token: HardwareAuthToken, time_received: MonotonicRawTime) -> Self {
+    /// Creates an entry for `auth_token`, received at `time_received`.
+    pub fn new(auth_token: HardwareAuthToken, time_received: MonotonicRawTime) -> Self {
         AuthTokenEntry { auth_token, time_received }
     }
 
@@ -842,6 +1119,51 @@ impl AuthTokenEntry {
     }
 }
 
+/// Constraints of a grant that apply in addition to the permissions of its access vector.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GrantConstraints {
+    /// If set, operations that the grantee creates through the grant require that one of these
+    /// secure user ids, typically the key owner's, authenticated recently.
+    pub auth_sids: Option<Vec<i64>>,
+    /// If set, the grantee can only create operations with one of these purposes.
+    pub purposes: Option<Vec<KeyPurpose>>,
+}
+
+impl GrantConstraints {
+    fn auth_sids_to_sql(&self) -> Option<Vec<u8>> {
+        self.auth_sids.as_ref().map(|sids| sids.iter().flat_map(|sid| sid.to_be_bytes()).collect())
+    }
+
+    fn auth_sids_from_sql(blob: Option<Vec<u8>>) -> Result<Option<Vec<i64>>> {
+        blob.map(|blob| {
+            if blob.len() % 8 != 0 {
+                return Err(KsError::sys()).context(ks_err!("Malformed secure user ids."));
+            }
+            Ok(blob.chunks_exact(8).map(|c| i64::from_be_bytes(c.try_into().unwrap())).collect())
+        })
+        .transpose()
+    }
+
+    fn purposes_to_sql(&self) -> Option<Vec<u8>> {
+        self.purposes
+            .as_ref()
+            .map(|purposes| purposes.iter().flat_map(|p| p.0.to_be_bytes()).collect())
+    }
+
+    fn purposes_from_sql(blob: Option<Vec<u8>>) -> Result<Option<Vec<KeyPurpose>>> {
+        blob.map(|blob| {
+            if blob.len() % 4 != 0 {
+                return Err(KsError::sys()).context(ks_err!("Malformed purposes."));
+            }
+            Ok(blob
+                .chunks_exact(4)
+                .map(|c| KeyPurpose(i32::from_be_bytes(c.try_into().unwrap())))
+                .collect())
+        })
+        .transpose()
+    }
+}
+
 /// Shared in-memory databases get destroyed as soon as the last connection to them gets closed.
 /// This object does not allow access to the database connection. But it keeps a database
 /// connection alive in order to keep the in memory per boot database alive.
@@ -849,12 +1171,36 @@ pub struct PerBootDbKeepAlive(Connection);
 
 impl KeystoreDB {
     const UNASSIGNED_KEY_ID: i64 = -1i64;
-    const CURRENT_DB_VERSION: u32 = 1;
-    const UPGRADERS: &'static [fn(&Transaction) -> Result<u32>] = &[Self::from_0_to_1];
+    const CURRENT_DB_VERSION: u32 = 4;
+    const UPGRADERS: &'static [fn(&Transaction) -> Result<u32>] =
+        &[Self::from_0_to_1, Self::from_1_to_2, Self::from_2_to_3, Self::from_3_to_4];
+
+    /// Key of the HMAC that serves as blob checksum. The checksum only detects corruption of
+    /// the database file, so the key does not need to be secret.
+    const BLOB_CHECKSUM_KEY: &'static [u8] = b"keystore2 blob checksum";
+    /// Number of blobs that the blob checksum audit verifies per transaction.
+    const BLOB_CHECKSUM_AUDIT_BATCH_SIZE: i64 = 100;
+
+    /// The key history retains at most this many entries. The oldest entries are dropped first.
+    pub const KEY_HISTORY_MAX_ENTRIES: i64 = 1000;
+    /// Entries of the key history are dropped when they are older than this.
+    pub const KEY_HISTORY_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    /// Delay before a rollback resistant key blob is handed to the garbage collector again
+    /// after its deletion failed. The delay doubles with every failed attempt.
+    const BLOB_DELETION_RETRY_DELAY: Duration = Duration::from_secs(60);
+    /// Upper bound of the delay between two deletion attempts of the same key blob.
+    const BLOB_DELETION_MAX_RETRY_DELAY: Duration = Duration::from_secs(6 * 60 * 60);
 
     /// Name of the file that holds the cross-boot persistent database.
     pub const PERSISTENT_DB_FILENAME: &'static str = "persistent.sqlite";
 
+    /// Transactions and statements that take longer than this are logged as slow.
+    const SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(100);
+
+    /// Name of the connections' page caches in the memory trimmer's eviction counts.
+    pub const TRIMMER_NAME: &'static str = "database_page_cache";
+
     /// This will create a new database connection connecting the two
     /// files persistent.sqlite and perboot.sqlite in the given directory.
     /// It also attempts to initialize all of the tables.
@@ -866,8 +1212,13 @@ impl KeystoreDB {
         let persistent_path = Self::make_persistent_path(db_root)?;
         let conn = Self::make_connection(&persistent_path)?;
 
-        let mut db = Self { conn, gc, perboot: perboot::PERBOOT_DB.clone() };
-        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+        let mut db = Self {
+            conn,
+            gc,
+            perboot: perboot::PERBOOT_DB.clone(),
+            shrink_generation: SHRINK_GENERATION.load(Ordering::Relaxed),
+        };
+        db.with_transaction(TransactionCategory::Init, TransactionBehavior::Immediate, |tx| {
             versioning::upgrade_database(tx, Self::CURRENT_DB_VERSION, Self::UPGRADERS)
                 .context(ks_err!("KeystoreDB::new: trying to upgrade database."))?;
             Self::init_tables(tx).context("Trying to initialize tables.").no_gc()
@@ -895,6 +1246,45 @@ impl KeystoreDB {
         Ok(1)
     }
 
+    // This upgrade function adds the column that stores the secure user ids that grants
+    // constrained to use with user authentication are bound to.
+    fn from_1_to_2(tx: &Transaction) -> Result<u32> {
+        tx.execute("ALTER TABLE persistent.grant ADD COLUMN auth_sids BLOB;", [])
+            .context(ks_err!("Failed to add auth_sids column."))?;
+        Ok(2)
+    }
+
+    // This upgrade function adds a checksum to every blob, so that corruption of the database
+    // file is detected when a blob is loaded.
+    fn from_2_to_3(tx: &Transaction) -> Result<u32> {
+        tx.execute("ALTER TABLE persistent.blobentry ADD COLUMN checksum BLOB;", [])
+            .context(ks_err!("Failed to add checksum column."))?;
+        let blobs: Vec<(i64, Vec<u8>)> = {
+            let mut stmt = tx
+                .prepare("SELECT id, blob FROM persistent.blobentry;")
+                .context(ks_err!("Failed to prepare statement."))?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .context(ks_err!("Failed to query blobs."))?;
+            rows.collect::<rusqlite::Result<_>>().context(ks_err!("Failed to extract blobs."))?
+        };
+        for (blob_id, blob) in blobs {
+            tx.execute(
+                "UPDATE persistent.blobentry SET checksum = ? WHERE id = ?;",
+                params![Self::blob_checksum(&blob)?, blob_id],
+            )
+            .context(ks_err!("Failed to store checksum of blob {}.", blob_id))?;
+        }
+        Ok(3)
+    }
+
+    // This upgrade function adds the column that stores the purposes that grants are limited to.
+    fn from_3_to_4(tx: &Transaction) -> Result<u32> {
+        tx.execute("ALTER TABLE persistent.grant ADD COLUMN purposes BLOB;", [])
+            .context(ks_err!("Failed to add purposes column."))?;
+        Ok(4)
+    }
+
     fn init_tables(tx: &Transaction) -> Result<()> {
         tx.execute(
             "CREATE TABLE IF NOT EXISTS persistent.keyentry (
@@ -928,7 +1318,8 @@ impl KeystoreDB {
                     id INTEGER PRIMARY KEY,
                     subcomponent_type INTEGER,
                     keyentryid INTEGER,
-                    blob BLOB);",
+                    blob BLOB,
+                    checksum BLOB);",
             [],
         )
         .context("Failed to initialize \"blobentry\" table.")?;
@@ -997,11 +1388,112 @@ impl KeystoreDB {
                     id INTEGER UNIQUE,
                     grantee INTEGER,
                     keyentryid INTEGER,
-                    access_vector INTEGER);",
+                    access_vector INTEGER,
+                    auth_sids BLOB,
+                    purposes BLOB);",
             [],
         )
         .context("Failed to initialize \"grant\" table.")?;
 
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.keyhistory (
+                    id INTEGER PRIMARY KEY,
+                    keyidhash INTEGER,
+                    event INTEGER,
+                    domain INTEGER,
+                    namespace INTEGER,
+                    time INTEGER);",
+            [],
+        )
+        .context("Failed to initialize \"keyhistory\" table.")?;
+
+        tx.execute(
+            "CREATE INDEX IF NOT EXISTS persistent.keyhistory_domain_namespace_index
+            ON keyhistory(domain, namespace);",
+            [],
+        )
+        .context("Failed to create index keyhistory_domain_namespace_index.")?;
+
+        // Key blobs of rollback resistant keys whose deletion KeyMint has not confirmed yet.
+        // `retry_after` is the `MonotonicRawTime` in milliseconds before which the blob is not
+        // handed to the garbage collector again. The monotonic clock restarts with the device,
+        // so `reset_blob_deletion_delays` clears it when keystore starts.
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.blobdeletion (
+                    blobentryid INTEGER PRIMARY KEY,
+                    attempts INTEGER,
+                    retry_after INTEGER);",
+            [],
+        )
+        .context("Failed to initialize \"blobdeletion\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.blobshadow (
+                    blobentryid INTEGER PRIMARY KEY,
+                    encoding TEXT,
+                    blob BLOB);",
+            [],
+        )
+        .context("Failed to initialize \"blobshadow\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.storagekey (
+                    userid INTEGER,
+                    keyclass INTEGER,
+                    blob BLOB,
+                    generation INTEGER,
+                    time INTEGER,
+                    PRIMARY KEY (userid, keyclass));",
+            [],
+        )
+        .context("Failed to initialize \"storagekey\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.credential (
+                    owner INTEGER,
+                    type INTEGER,
+                    alias TEXT,
+                    blob BLOB,
+                    iv BLOB,
+                    aead_tag BLOB,
+                    PRIMARY KEY (owner, type, alias));",
+            [],
+        )
+        .context("Failed to initialize \"credential\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.keyvaliditypolicy (
+                    userid INTEGER PRIMARY KEY,
+                    maxvalidity INTEGER);",
+            [],
+        )
+        .context("Failed to initialize \"keyvaliditypolicy\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.databasebinding (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    nonce BLOB NOT NULL,
+                    mac BLOB NOT NULL);",
+            [],
+        )
+        .context("Failed to initialize \"databasebinding\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.patchlevelpolicy (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    policy TEXT NOT NULL);",
+            [],
+        )
+        .context("Failed to initialize \"patchlevelpolicy\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.fingerprintkey (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    key BLOB NOT NULL);",
+            [],
+        )
+        .context("Failed to initialize \"fingerprintkey\" table.")?;
+
         Ok(())
     }
 
@@ -1018,8 +1510,9 @@ impl KeystoreDB {
     }
 
     fn make_connection(persistent_file: &str) -> Result<Connection> {
-        let conn =
+        let mut conn =
             Connection::open_in_memory().context("Failed to initialize SQLite connection.")?;
+        conn.profile(Some(Self::log_slow_statement));
 
         loop {
             if let Err(e) = conn
@@ -1054,13 +1547,17 @@ impl KeystoreDB {
         query: &str,
         params: &[&str],
     ) -> Result<StorageStats> {
-        let (total, unused) = self.with_transaction(TransactionBehavior::Deferred, |tx| {
-            tx.query_row(query, params_from_iter(params), |row| Ok((row.get(0)?, row.get(1)?)))
-                .with_context(|| {
-                    ks_err!("get_storage_stat: Error size of storage type {}", storage_type.0)
-                })
-                .no_gc()
-        })?;
+        let (total, unused) = self.with_transaction(
+            TransactionCategory::Maintenance,
+            TransactionBehavior::Deferred,
+            |tx| {
+                tx.query_row(query, params_from_iter(params), |row| Ok((row.get(0)?, row.get(1)?)))
+                    .with_context(|| {
+                        ks_err!("get_storage_stat: Error size of storage type {}", storage_type.0)
+                    })
+                    .no_gc()
+            },
+        )?;
         Ok(StorageStats { storage_type, size: total, unused_size: unused })
     }
 
@@ -1151,25 +1648,60 @@ impl KeystoreDB {
     /// superseded key blobs that might need special handling by the garbage collector.
     /// If no further superseded blobs can be found it deletes all other superseded blobs that don't
     /// need special handling and returns None.
+    /// Along with the superseded blobs it returns the number of database rows purged by the call.
     pub fn handle_next_superseded_blobs(
         &mut self,
         blob_ids_to_delete: &[i64],
         max_blobs: usize,
-    ) -> Result<Vec<(i64, Vec<u8>, BlobMetaData)>> {
+    ) -> Result<(Vec<(i64, Vec<u8>, BlobMetaData)>, usize)> {
         let _wp = wd::watch_millis("KeystoreDB::handle_next_superseded_blob", 500);
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::Gc, TransactionBehavior::Immediate, |tx| {
+            let mut rows_purged = 0;
             // Delete the given blobs.
             for blob_id in blob_ids_to_delete {
+                rows_purged += tx
+                    .execute(
+                        "DELETE FROM persistent.blobmetadata WHERE blobentryid = ?;",
+                        params![blob_id],
+                    )
+                    .context("Trying to delete blob metadata.")?;
+                rows_purged += tx
+                    .execute("DELETE FROM persistent.blobentry WHERE id = ?;", params![blob_id])
+                    .context("Trying to blob.")?;
                 tx.execute(
-                    "DELETE FROM persistent.blobmetadata WHERE blobentryid = ?;",
+                    "DELETE FROM persistent.blobdeletion WHERE blobentryid = ?;",
                     params![blob_id],
                 )
-                .context("Trying to delete blob metadata.")?;
-                tx.execute("DELETE FROM persistent.blobentry WHERE id = ?;", params![blob_id])
-                    .context("Trying to blob.")?;
+                .context("Trying to delete blob deletion record.")?;
             }
 
-            Self::cleanup_unreferenced(tx).context("Trying to cleanup unreferenced.")?;
+            // Rollback resistant keys occupy a slot in KeyMint until they are deleted. Their
+            // blobs must be kept until KeyMint confirms the deletion, so they are recorded before
+            // the key parameters of unreferenced keys are purged.
+            tx.execute(
+                "INSERT OR IGNORE INTO persistent.blobdeletion (blobentryid, attempts, retry_after)
+                 SELECT id, 0, 0 FROM persistent.blobentry
+                 WHERE subcomponent_type = ?
+                 AND keyentryid IN (SELECT keyentryid FROM persistent.keyparameter WHERE tag = ?)
+                 AND (
+                     keyentryid IN (SELECT id FROM persistent.keyentry WHERE state = ?)
+                     OR id NOT IN (
+                         SELECT MAX(id) FROM persistent.blobentry
+                         WHERE subcomponent_type = ?
+                         GROUP BY keyentryid
+                     )
+                 );",
+                params![
+                    SubComponentType::KEY_BLOB,
+                    Tag::ROLLBACK_RESISTANCE.0,
+                    KeyLifeCycle::Unreferenced,
+                    SubComponentType::KEY_BLOB
+                ],
+            )
+            .context("Trying to record rollback resistant blobs.")?;
+
+            rows_purged +=
+                Self::cleanup_unreferenced(tx).context("Trying to cleanup unreferenced.")?;
 
             // Find up to max_blobx more superseded key blobs, load their metadata and return it.
             let result: Vec<(i64, Vec<u8>)> = {
@@ -1184,6 +1716,9 @@ impl KeystoreDB {
                                 GROUP BY keyentryid, subcomponent_type
                             )
                         OR keyentryid NOT IN (SELECT id FROM persistent.keyentry)
+                    )
+                    AND id NOT IN (
+                        SELECT blobentryid FROM persistent.blobdeletion WHERE retry_after > ?
                     ) LIMIT ?;",
                     )
                     .context("Trying to prepare query for superseded blobs.")?;
@@ -1193,6 +1728,7 @@ impl KeystoreDB {
                         params![
                             SubComponentType::KEY_BLOB,
                             SubComponentType::KEY_BLOB,
+                            MonotonicRawTime::now(),
                             max_blobs as i64,
                         ],
                         |row| Ok((row.get(0)?, row.get(1)?)),
@@ -1211,13 +1747,14 @@ impl KeystoreDB {
                 .collect::<Result<Vec<(i64, Vec<u8>, BlobMetaData)>>>()
                 .context("Trying to load blob metadata.")?;
             if !result.is_empty() {
-                return Ok(result).no_gc();
+                return Ok((result, rows_purged)).no_gc();
             }
 
             // We did not find any superseded key blob, so let's remove other superseded blob in
             // one transaction.
-            tx.execute(
-                "DELETE FROM persistent.blobentry
+            rows_purged += tx
+                .execute(
+                    "DELETE FROM persistent.blobentry
                  WHERE NOT subcomponent_type = ?
                  AND (
                      id NOT IN (
@@ -1226,11 +1763,88 @@ impl KeystoreDB {
                         GROUP BY keyentryid, subcomponent_type
                      ) OR keyentryid NOT IN (SELECT id FROM persistent.keyentry)
                  );",
-                params![SubComponentType::KEY_BLOB, SubComponentType::KEY_BLOB],
+                    params![SubComponentType::KEY_BLOB, SubComponentType::KEY_BLOB],
+                )
+                .context("Trying to purge superseded blobs.")?;
+            tx.execute(
+                "DELETE FROM persistent.blobshadow
+                 WHERE blobentryid NOT IN (SELECT id FROM persistent.blobentry);",
+                [],
+            )
+            .context("Trying to purge shadow blobs.")?;
+
+            Ok((vec![], rows_purged)).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Records a failed attempt to delete the key blob `blob_id` with KeyMint. If the blob
+    /// belongs to a rollback resistant key, it is kept and handed to the garbage collector again
+    /// after a delay that grows with every failed attempt. Returns true in this case, and false if
+    /// the blob does not require a deletion confirmation and can be deleted anyway.
+    pub fn postpone_blob_deletion(&mut self, blob_id: i64) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::postpone_blob_deletion", 500);
+
+        self.with_transaction(TransactionCategory::Gc, TransactionBehavior::Immediate, |tx| {
+            let attempts: Option<u32> = tx
+                .query_row(
+                    "SELECT attempts FROM persistent.blobdeletion WHERE blobentryid = ?;",
+                    params![blob_id],
+                    |row| row.get(0),
+                )
+                .optional()
+                .context("Failed to query blob deletion record.")?;
+            let attempts = match attempts {
+                Some(attempts) => attempts + 1,
+                None => return Ok(false).no_gc(),
+            };
+            let delay = (Self::BLOB_DELETION_RETRY_DELAY * (1 << (attempts - 1).min(16)))
+                .min(Self::BLOB_DELETION_MAX_RETRY_DELAY);
+            let retry_after = MonotonicRawTime::now().milliseconds() + delay.as_millis() as i64;
+            tx.execute(
+                "UPDATE persistent.blobdeletion SET attempts = ?, retry_after = ?
+                 WHERE blobentryid = ?;",
+                params![attempts, retry_after, blob_id],
             )
-            .context("Trying to purge superseded blobs.")?;
+            .context("Failed to update blob deletion record.")?;
+            Ok(true).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Makes all key blobs whose deletion was postponed by `postpone_blob_deletion` available to
+    /// the garbage collector again. This must be called when keystore starts, because the retry
+    /// times refer to the monotonic clock, which may have restarted since they were recorded.
+    /// The number of failed attempts is kept, so the delays keep growing after the next failure.
+    pub fn reset_blob_deletion_delays(&mut self) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::reset_blob_deletion_delays", 500);
 
-            Ok(vec![]).no_gc()
+        self.with_transaction(TransactionCategory::Gc, TransactionBehavior::Immediate, |tx| {
+            tx.execute("UPDATE persistent.blobdeletion SET retry_after = 0;", [])
+                .context("Failed to reset blob deletion delays.")?;
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Returns the id and the number of failed deletion attempts of every key blob of a
+    /// rollback resistant key whose deletion KeyMint has not confirmed yet.
+    pub fn get_pending_blob_deletions(&mut self) -> Result<Vec<(i64, u32)>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_pending_blob_deletions", 500);
+
+        self.with_transaction(TransactionCategory::Gc, TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT blobentryid, attempts FROM persistent.blobdeletion
+                     WHERE attempts > 0 ORDER BY blobentryid;",
+                )
+                .context("Failed to prepare statement.")?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .context("Failed to query blob deletion records.")?;
+            rows.collect::<Result<Vec<(i64, u32)>, rusqlite::Error>>()
+                .context("Failed to extract blob deletion records.")
+                .no_gc()
         })
         .context(ks_err!())
     }
@@ -1246,7 +1860,7 @@ impl KeystoreDB {
     pub fn cleanup_leftovers(&mut self) -> Result<usize> {
         let _wp = wd::watch_millis("KeystoreDB::cleanup_leftovers", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::Gc, TransactionBehavior::Immediate, |tx| {
             tx.execute(
                 "UPDATE persistent.keyentry SET state = ? WHERE state = ?;",
                 params![KeyLifeCycle::Unreferenced, KeyLifeCycle::Existing],
@@ -1257,6 +1871,49 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
+    /// This maintenance function is intended to be used by the garbage collector. It deletes
+    /// grants, key metadata, and key parameters that reference key entries that no longer exist.
+    /// Such rows are never created by keystore itself, because all of them are deleted together
+    /// with their key entry, but they may be left behind by crashes of older keystore versions
+    /// or by database corruption. Returns what was deleted.
+    pub fn check_consistency(&mut self) -> Result<ConsistencyReport> {
+        let _wp = wd::watch_millis("KeystoreDB::check_consistency", 500);
+
+        self.with_transaction(TransactionCategory::Gc, TransactionBehavior::Immediate, |tx| {
+            let dangling_grants = tx
+                .execute(
+                    "DELETE FROM persistent.grant
+                     WHERE keyentryid NOT IN (SELECT id FROM persistent.keyentry);",
+                    [],
+                )
+                .context("Trying to delete dangling grants.")?;
+            if dangling_grants != 0 {
+                grant_cache::note_change();
+            }
+            let orphaned_key_metadata = tx
+                .execute(
+                    "DELETE FROM persistent.keymetadata
+                     WHERE keyentryid NOT IN (SELECT id FROM persistent.keyentry);",
+                    [],
+                )
+                .context("Trying to delete orphaned key metadata.")?;
+            let orphaned_key_parameters = tx
+                .execute(
+                    "DELETE FROM persistent.keyparameter
+                     WHERE keyentryid NOT IN (SELECT id FROM persistent.keyentry);",
+                    [],
+                )
+                .context("Trying to delete orphaned key parameters.")?;
+            Ok(ConsistencyReport {
+                dangling_grants,
+                orphaned_key_metadata,
+                orphaned_key_parameters,
+            })
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
+
     /// Checks if a key exists with given key type and key descriptor properties.
     pub fn key_exists(
         &mut self,
@@ -1267,7 +1924,7 @@ impl KeystoreDB {
     ) -> Result<bool> {
         let _wp = wd::watch_millis("KeystoreDB::key_exists", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Immediate, |tx| {
             let key_descriptor =
                 KeyDescriptor { domain, nspace, alias: Some(alias.to_string()), blob: None };
             let result = Self::load_key_entry_id(tx, &key_descriptor, key_type);
@@ -1294,7 +1951,7 @@ impl KeystoreDB {
     ) -> Result<KeyEntry> {
         let _wp = wd::watch_millis("KeystoreDB::store_super_key", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::SuperKey, TransactionBehavior::Immediate, |tx| {
             let key_id = Self::insert_with_retry(|id| {
                 tx.execute(
                     "INSERT into persistent.keyentry
@@ -1331,47 +1988,288 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
-    /// Loads super key of a given user, if exists
-    pub fn load_super_key(
+    /// Replaces the key blobs of super keys of the given user in a single transaction. Each entry
+    /// of `blobs` holds the key id of a super key, its new blob, and the new blob metadata. The
+    /// key ids and key metadata do not change, so keys encrypted with the super keys are not
+    /// affected. The superseded blobs are left to the garbage collector.
+    pub fn rewrap_super_keys(
         &mut self,
-        key_type: &SuperKeyType,
         user_id: u32,
-    ) -> Result<Option<(KeyIdGuard, KeyEntry)>> {
-        let _wp = wd::watch_millis("KeystoreDB::load_super_key", 500);
+        blobs: &[(i64, Vec<u8>, BlobMetaData)],
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::rewrap_super_keys", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let key_descriptor = KeyDescriptor {
-                domain: Domain::APP,
-                nspace: user_id as i64,
-                alias: Some(key_type.alias.into()),
-                blob: None,
-            };
-            let id = Self::load_key_entry_id(tx, &key_descriptor, KeyType::Super);
-            match id {
-                Ok(id) => {
-                    let key_entry = Self::load_key_components(tx, KeyEntryLoadBits::KM, id)
-                        .context(ks_err!("Failed to load key entry."))?;
-                    Ok(Some((KEY_ID_LOCK.get(id), key_entry)))
-                }
-                Err(error) => match error.root_cause().downcast_ref::<KsError>() {
-                    Some(KsError::Rc(ResponseCode::KEY_NOT_FOUND)) => Ok(None),
-                    _ => Err(error).context(ks_err!()),
-                },
+        self.with_transaction(TransactionCategory::SuperKey, TransactionBehavior::Immediate, |tx| {
+            for (key_id, blob, blob_metadata) in blobs {
+                tx.query_row(
+                    "SELECT id FROM persistent.keyentry
+                     WHERE id = ? AND key_type = ? AND domain = ? AND namespace = ? AND state = ?;",
+                    params![
+                        key_id,
+                        KeyType::Super,
+                        Domain::APP.0,
+                        user_id as i64,
+                        KeyLifeCycle::Live
+                    ],
+                    |_| Ok(()),
+                )
+                .optional()
+                .context("Failed to query super key.")?
+                .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context(format!("Super key {} of user {} not found.", key_id, user_id))?;
+
+                Self::set_blob_internal(
+                    tx,
+                    *key_id,
+                    SubComponentType::KEY_BLOB,
+                    Some(blob),
+                    Some(blob_metadata),
+                )
+                .context("Failed to store key blob.")?;
             }
-            .no_gc()
+            Ok(()).need_gc()
         })
         .context(ks_err!())
     }
 
-    /// Atomically loads a key entry and associated metadata or creates it using the
-    /// callback create_new_key callback. The callback is called during a database
-    /// transaction. This means that implementers should be mindful about using
-    /// blocking operations such as IPC or grabbing mutexes.
-    pub fn get_or_create_key_with<F>(
+    /// Replaces the key blob of the live super key `key_id` with `blob` and `blob_metadata`, if
+    /// its current key blob is still `old_blob`. Returns whether the blob was replaced. The
+    /// superseded blob is left to the garbage collector.
+    pub fn replace_super_key_blob(
         &mut self,
-        domain: Domain,
-        namespace: i64,
-        alias: &str,
+        key_id: i64,
+        old_blob: &[u8],
+        blob: &[u8],
+        blob_metadata: &BlobMetaData,
+    ) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::replace_super_key_blob", 500);
+
+        self.with_transaction(TransactionCategory::SuperKey, TransactionBehavior::Immediate, |tx| {
+            let current: Option<Vec<u8>> = tx
+                .query_row(
+                    "SELECT blob FROM persistent.blobentry
+                     WHERE keyentryid = ? AND subcomponent_type = ?
+                     AND keyentryid IN (
+                         SELECT id FROM persistent.keyentry WHERE key_type = ? AND state = ?
+                     )
+                     ORDER BY id DESC LIMIT 1;",
+                    params![key_id, SubComponentType::KEY_BLOB, KeyType::Super, KeyLifeCycle::Live],
+                    |row| row.get(0),
+                )
+                .optional()
+                .context("Failed to query the key blob of the super key.")?;
+            if current.as_deref() != Some(old_blob) {
+                return Ok(false).no_gc();
+            }
+            Self::set_blob_internal(
+                tx,
+                key_id,
+                SubComponentType::KEY_BLOB,
+                Some(blob),
+                Some(blob_metadata),
+            )
+            .context("Failed to store key blob.")?;
+            Ok(true).need_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Loads up to `limit` current key blobs of live client keys that are encrypted with the super
+    /// key `super_key_id`, in the order of their blob ids, starting after `after_blob_id`. Each
+    /// entry holds the key id, the blob id, the blob, and its metadata.
+    pub fn load_key_blobs_encrypted_by(
+        &mut self,
+        super_key_id: i64,
+        after_blob_id: i64,
+        limit: usize,
+    ) -> Result<Vec<(i64, i64, Vec<u8>, BlobMetaData)>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_blobs_encrypted_by", 500);
+
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keyentryid, id, blob, checksum FROM persistent.blobentry
+                     WHERE id > ? AND id IN (
+                         SELECT MAX(id) FROM persistent.blobentry
+                         WHERE subcomponent_type = ?
+                         GROUP BY keyentryid
+                     )
+                     AND keyentryid IN (
+                         SELECT id FROM persistent.keyentry WHERE key_type = ? AND state = ?
+                     )
+                     AND id IN (
+                         SELECT blobentryid FROM persistent.blobmetadata
+                         WHERE tag = ? AND data = ?
+                     )
+                     ORDER BY id LIMIT ?;",
+                )
+                .context("Failed to prepare statement.")?;
+            let mut rows = stmt
+                .query(params![
+                    after_blob_id,
+                    SubComponentType::KEY_BLOB,
+                    KeyType::Client,
+                    KeyLifeCycle::Live,
+                    BlobMetaData::EncryptedBy,
+                    super_key_id,
+                    limit as i64,
+                ])
+                .context("Failed to query blobs.")?;
+            let mut blobs = vec![];
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let blob_id: i64 = row.get(1).context("Failed to extract blob id.")?;
+                let blob: Vec<u8> = row.get(2).context("Failed to extract blob.")?;
+                let checksum: Option<Vec<u8>> =
+                    row.get(3).context("Failed to extract checksum.")?;
+                Self::verify_blob_checksum(blob_id, &blob, checksum.as_deref())?;
+                shadow::verify(tx, blob_id, &blob)?;
+                let blob_metadata = BlobMetaData::load_from_db(blob_id, tx)
+                    .context("Failed to load blob metadata.")?;
+                blobs.push((
+                    row.get(0).context("Failed to extract key id.")?,
+                    blob_id,
+                    blob,
+                    blob_metadata,
+                ));
+                Ok(())
+            })
+            .context("Failed to extract blobs.")?;
+            Ok(blobs).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Counts the live client keys whose current key blob is encrypted with one of the super keys
+    /// of the user `user_id`. Returns the count for each super key alias that encrypts at least
+    /// one key, in the order of the aliases.
+    pub fn count_super_encrypted_keys(&mut self, user_id: u32) -> Result<Vec<(String, i64)>> {
+        let _wp = wd::watch_millis("KeystoreDB::count_super_encrypted_keys", 500);
+
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT superkey.alias, COUNT(*) FROM persistent.blobentry AS blob
+                     JOIN persistent.blobmetadata AS metadata
+                         ON metadata.blobentryid = blob.id AND metadata.tag = ?
+                     JOIN persistent.keyentry AS superkey
+                         ON superkey.id = metadata.data
+                     JOIN persistent.keyentry AS key
+                         ON key.id = blob.keyentryid
+                     WHERE blob.id IN (
+                         SELECT MAX(id) FROM persistent.blobentry
+                         WHERE subcomponent_type = ?
+                         GROUP BY keyentryid
+                     )
+                     AND superkey.key_type = ? AND superkey.domain = ? AND superkey.namespace = ?
+                     AND key.key_type = ? AND key.state = ?
+                     GROUP BY superkey.alias ORDER BY superkey.alias;",
+                )
+                .context("Failed to prepare statement.")?;
+            let mut rows = stmt
+                .query(params![
+                    BlobMetaData::EncryptedBy,
+                    SubComponentType::KEY_BLOB,
+                    KeyType::Super,
+                    Domain::APP.0 as u32,
+                    user_id,
+                    KeyType::Client,
+                    KeyLifeCycle::Live,
+                ])
+                .context("Failed to query super-encrypted keys.")?;
+            let mut counts = vec![];
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                counts.push((
+                    row.get(0).context("Failed to extract alias.")?,
+                    row.get(1).context("Failed to extract count.")?,
+                ));
+                Ok(())
+            })
+            .context("Failed to extract counts.")?;
+            Ok(counts).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Replaces the key blobs of client keys in a single transaction. Each entry of `blobs` holds
+    /// the key id, the id of the blob that is replaced, the new blob, and the new blob metadata.
+    /// Keys whose current blob is no longer the one to be replaced, e.g., because the key was
+    /// upgraded in the meantime, are skipped. Returns the number of replaced blobs.
+    pub fn rewrap_key_blobs(
+        &mut self,
+        blobs: &[(i64, i64, Vec<u8>, BlobMetaData)],
+    ) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::rewrap_key_blobs", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let mut rewrapped = 0;
+            for (key_id, blob_id, blob, blob_metadata) in blobs {
+                let current: Option<i64> = tx
+                    .query_row(
+                        "SELECT MAX(id) FROM persistent.blobentry
+                         WHERE keyentryid = ? AND subcomponent_type = ?;",
+                        params![key_id, SubComponentType::KEY_BLOB],
+                        |row| row.get(0),
+                    )
+                    .context("Failed to query current blob.")?;
+                if current != Some(*blob_id) {
+                    continue;
+                }
+                Self::set_blob_internal(
+                    tx,
+                    *key_id,
+                    SubComponentType::KEY_BLOB,
+                    Some(blob),
+                    Some(blob_metadata),
+                )
+                .context("Failed to store key blob.")?;
+                rewrapped += 1;
+            }
+            Ok(rewrapped).need_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Loads super key of a given user, if exists
+    pub fn load_super_key(
+        &mut self,
+        key_type: &SuperKeyType,
+        user_id: u32,
+    ) -> Result<Option<(KeyIdGuard, KeyEntry)>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_super_key", 500);
+
+        self.with_transaction(TransactionCategory::SuperKey, TransactionBehavior::Immediate, |tx| {
+            let key_descriptor = KeyDescriptor {
+                domain: Domain::APP,
+                nspace: user_id as i64,
+                alias: Some(key_type.alias.into()),
+                blob: None,
+            };
+            let id = Self::load_key_entry_id(tx, &key_descriptor, KeyType::Super);
+            match id {
+                Ok(id) => {
+                    let key_entry = Self::load_key_components(tx, KeyEntryLoadBits::KM, id)
+                        .context(ks_err!("Failed to load key entry."))?;
+                    Ok(Some((KEY_ID_LOCK.get(id), key_entry)))
+                }
+                Err(error) => match error.root_cause().downcast_ref::<KsError>() {
+                    Some(KsError::Rc(ResponseCode::KEY_NOT_FOUND)) => Ok(None),
+                    _ => Err(error).context(ks_err!()),
+                },
+            }
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Atomically loads a key entry and associated metadata or creates it using the
+    /// callback create_new_key callback. The callback is called during a database
+    /// transaction. This means that implementers should be mindful about using
+    /// blocking operations such as IPC or grabbing mutexes.
+    pub fn get_or_create_key_with<F>(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        alias: &str,
         km_uuid: Uuid,
         create_new_key: F,
     ) -> Result<(KeyIdGuard, KeyEntry)>
@@ -1380,7 +2278,7 @@ impl KeystoreDB {
     {
         let _wp = wd::watch_millis("KeystoreDB::get_or_create_key_with", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::SuperKey, TransactionBehavior::Immediate, |tx| {
             let id = {
                 let mut stmt = tx
                     .prepare(
@@ -1456,14 +2354,39 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
+    /// Profiling callback of the connection. Logs statements that take longer than
+    /// `SLOW_QUERY_THRESHOLD`. Bound parameters are never part of `sql`, and literals are
+    /// redacted, so the log does not reveal key material or aliases.
+    fn log_slow_statement(sql: &str, duration: Duration) {
+        if duration > Self::SLOW_QUERY_THRESHOLD {
+            log::warn!("Slow statement ({duration:?}): {}", db_utils::redact_sql(sql));
+        }
+    }
+
     /// Creates a transaction with the given behavior and executes f with the new transaction.
     /// The transaction is committed only if f returns Ok and retried if DatabaseBusy
-    /// or DatabaseLocked is encountered.
-    fn with_transaction<T, F>(&mut self, behavior: TransactionBehavior, f: F) -> Result<T>
+    /// or DatabaseLocked is encountered. A watch point reports the `category` and the number of
+    /// attempts if the transaction stalls, and slow transactions are logged.
+    fn with_transaction<T, F>(
+        &mut self,
+        category: TransactionCategory,
+        behavior: TransactionBehavior,
+        f: F,
+    ) -> Result<T>
     where
         F: Fn(&Transaction) -> Result<(bool, T)>,
     {
-        loop {
+        self.shrink_memory_if_requested();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let _wp = {
+            let attempts = attempts.clone();
+            wd::watch_millis_with("KeystoreDB::with_transaction", 500, move || {
+                format!("{category:?} transaction, attempt {}", attempts.load(Ordering::Relaxed))
+            })
+        };
+        let start = std::time::Instant::now();
+        let result = loop {
+            attempts.fetch_add(1, Ordering::Relaxed);
             match self
                 .conn
                 .transaction_with_behavior(behavior)
@@ -1479,12 +2402,30 @@ impl KeystoreDB {
                         std::thread::sleep(std::time::Duration::from_micros(500));
                         continue;
                     } else {
-                        return Err(e).context(ks_err!());
+                        if DatabaseErrorKind::from_anyhow(&e) == Some(DatabaseErrorKind::Corrupted)
+                        {
+                            post_mortem::report(
+                                FatalEvent::DatabaseCorrupted,
+                                &format!("{category:?} transaction"),
+                                &[],
+                            );
+                        }
+                        break Err(e).context(ks_err!());
                     }
                 }
             }
+        };
+        // Cached grant lookups are dropped only after the transaction ended, so that a lookup
+        // cannot cache the state from before the change again.
+        grant_cache::apply_changes();
+        let elapsed = start.elapsed();
+        if elapsed > Self::SLOW_QUERY_THRESHOLD {
+            log::warn!(
+                "Slow {category:?} transaction ({elapsed:?}, {} attempts).",
+                attempts.load(Ordering::Relaxed)
+            );
         }
-        .map(|(need_gc, result)| {
+        result.map(|(need_gc, result)| {
             if need_gc {
                 if let Some(ref gc) = self.gc {
                     gc.notify_gc();
@@ -1494,12 +2435,29 @@ impl KeystoreDB {
         })
     }
 
+    /// Registers the page caches of all connections with the memory trimmer. Each connection
+    /// is owned by its thread, so it releases its page cache when it is next used.
+    pub fn register_trimmer() {
+        memory_trim::register_trimmer(Self::TRIMMER_NAME, |_| {
+            SHRINK_GENERATION.fetch_add(1, Ordering::Relaxed);
+            0
+        });
+    }
+
+    fn shrink_memory_if_requested(&mut self) {
+        let generation = SHRINK_GENERATION.load(Ordering::Relaxed);
+        if generation == self.shrink_generation {
+            return;
+        }
+        self.shrink_generation = generation;
+        match self.conn.execute_batch("PRAGMA shrink_memory;") {
+            Ok(()) => memory_trim::record_evictions(Self::TRIMMER_NAME, 1),
+            Err(e) => log::error!("Failed to release database page cache: {:?}", e),
+        }
+    }
+
     fn is_locked_error(e: &anyhow::Error) -> bool {
-        matches!(
-            e.root_cause().downcast_ref::<rusqlite::ffi::Error>(),
-            Some(rusqlite::ffi::Error { code: rusqlite::ErrorCode::DatabaseBusy, .. })
-                | Some(rusqlite::ffi::Error { code: rusqlite::ErrorCode::DatabaseLocked, .. })
-        )
+        DatabaseErrorKind::from_anyhow(e) == Some(DatabaseErrorKind::Busy)
     }
 
     /// Creates a new key entry and allocates a new randomized id for the new key.
@@ -1517,7 +2475,7 @@ impl KeystoreDB {
     ) -> Result<KeyIdGuard> {
         let _wp = wd::watch_millis("KeystoreDB::create_key_entry", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
             Self::create_key_entry_internal(tx, domain, namespace, key_type, km_uuid).no_gc()
         })
         .context(ks_err!())
@@ -1573,7 +2531,7 @@ impl KeystoreDB {
     ) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::set_blob", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
             Self::set_blob_internal(tx, key_id.0, sc_type, blob, blob_metadata).need_gc()
         })
         .context(ks_err!())
@@ -1586,7 +2544,7 @@ impl KeystoreDB {
     pub fn set_deleted_blob(&mut self, blob: &[u8], blob_metadata: &BlobMetaData) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::set_deleted_blob", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::Gc, TransactionBehavior::Immediate, |tx| {
             Self::set_blob_internal(
                 tx,
                 Self::UNASSIGNED_KEY_ID,
@@ -1610,20 +2568,19 @@ impl KeystoreDB {
             (Some(blob), _) => {
                 tx.execute(
                     "INSERT INTO persistent.blobentry
-                     (subcomponent_type, keyentryid, blob) VALUES (?, ?, ?);",
-                    params![sc_type, key_id, blob],
+                     (subcomponent_type, keyentryid, blob, checksum) VALUES (?, ?, ?, ?);",
+                    params![sc_type, key_id, blob, Self::blob_checksum(blob)?],
                 )
                 .context(ks_err!("Failed to insert blob."))?;
+                let blob_id = tx
+                    .query_row("SELECT MAX(id) FROM persistent.blobentry;", [], |row| row.get(0))
+                    .context(ks_err!("Failed to get new blob id."))?;
                 if let Some(blob_metadata) = blob_metadata {
-                    let blob_id = tx
-                        .query_row("SELECT MAX(id) FROM persistent.blobentry;", [], |row| {
-                            row.get(0)
-                        })
-                        .context(ks_err!("Failed to get new blob id."))?;
                     blob_metadata
                         .store_in_db(blob_id, tx)
                         .context(ks_err!("Trying to store blob metadata."))?;
                 }
+                shadow::write(tx, blob_id, blob).context(ks_err!())?;
             }
             (None, SubComponentType::CERT) | (None, SubComponentType::CERT_CHAIN) => {
                 tx.execute(
@@ -1645,7 +2602,7 @@ impl KeystoreDB {
     /// and associates them with the given `key_id`.
     #[cfg(test)]
     fn insert_keyparameter(&mut self, key_id: &KeyIdGuard, params: &[KeyParameter]) -> Result<()> {
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
             Self::insert_keyparameter_internal(tx, key_id, params).no_gc()
         })
         .context(ks_err!())
@@ -1678,7 +2635,7 @@ impl KeystoreDB {
     /// Insert a set of key entry specific metadata into the database.
     #[cfg(test)]
     fn insert_key_metadata(&mut self, key_id: &KeyIdGuard, metadata: &KeyMetaData) -> Result<()> {
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
             metadata.store_in_db(key_id.0, tx).no_gc()
         })
         .context(ks_err!())
@@ -1704,6 +2661,13 @@ impl KeystoreDB {
                     .context(ks_err!("Domain {:?} must be either App or SELinux.", domain));
             }
         }
+        Self::record_key_history(
+            tx,
+            KeyHistoryEvent::Deleted,
+            "alias = ? AND domain = ? AND namespace = ? AND key_type = ?",
+            params![alias, domain.0 as u32, namespace, key_type],
+        )
+        .context(ks_err!("Failed to record replaced entry."))?;
         let updated = tx
             .execute(
                 "UPDATE persistent.keyentry
@@ -1712,6 +2676,9 @@ impl KeystoreDB {
                 params![KeyLifeCycle::Unreferenced, alias, domain.0 as u32, namespace, key_type],
             )
             .context(ks_err!("Failed to rebind existing entry."))?;
+        if updated != 0 {
+            grant_cache::note_change();
+        }
         let result = tx
             .execute(
                 "UPDATE persistent.keyentry
@@ -1734,6 +2701,8 @@ impl KeystoreDB {
                 result
             ));
         }
+        Self::record_key_history(tx, KeyHistoryEvent::Created, "id = ?", params![newid.0])
+            .context(ks_err!("Failed to record new entry."))?;
         Ok(updated != 0)
     }
 
@@ -1766,41 +2735,237 @@ impl KeystoreDB {
             .ok_or(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
             .context(ks_err!("Alias must be specified."))?;
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            // Query the destination location. If there is a key, the migration request fails.
-            if tx
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            Self::rebind_key_entry(tx, key_id_guard.id(), alias, &destination).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Moves the `Domain::APP` key entry guarded by `key_id_guard` into the `Domain::SELINUX`
+    /// namespace given by `destination`, so that it becomes owned by a system component.
+    /// In addition to what `migrate_key_namespace` does, all grants of the key are revoked in
+    /// the same transaction, because they were issued by the previous owner. The key material,
+    /// parameters, and metadata are left untouched.
+    /// Super-encrypted keys are refused with `INVALID_ARGUMENT`. They are bound to the super keys
+    /// of the app's user, which do not protect SELinux namespaces, so moving them would either
+    /// strand them or strip the binding.
+    /// The function calls `check_permission` with the destination descriptor which must
+    /// return Ok if the caller may rebind keys in the destination namespace.
+    pub fn adopt_app_key(
+        &mut self,
+        key_id_guard: KeyIdGuard,
+        destination: &KeyDescriptor,
+        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::adopt_app_key", 500);
+
+        if destination.domain != Domain::SELINUX {
+            return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Destination domain must be SELINUX."));
+        }
+
+        // Security critical: Must return immediately on failure. Do not remove the '?';
+        check_permission(destination).context(ks_err!("Trying to check permission."))?;
+
+        let alias = destination
+            .alias
+            .as_ref()
+            .ok_or(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Alias must be specified."))?;
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let domain: Domain = tx
                 .query_row(
-                    "SELECT id FROM persistent.keyentry
-                     WHERE alias = ? AND domain = ? AND namespace = ?;",
-                    params![alias, destination.domain.0, destination.nspace],
-                    |_| Ok(()),
+                    "SELECT domain FROM persistent.keyentry WHERE id = ?;",
+                    params![key_id_guard.id()],
+                    |row| row.get(0).map(Domain),
                 )
-                .optional()
-                .context("Failed to query destination.")?
-                .is_some()
-            {
+                .context("Failed to query source domain.")?;
+            if domain != Domain::APP {
                 return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
-                    .context("Target already exists.");
+                    .context(format!("Source domain {:?} must be APP.", domain));
             }
 
-            let updated = tx
-                .execute(
-                    "UPDATE persistent.keyentry
-                 SET alias = ?, domain = ?, namespace = ?
-                 WHERE id = ?;",
-                    params![alias, destination.domain.0, destination.nspace, key_id_guard.id()],
+            let super_encrypted: bool = tx
+                .query_row(
+                    "SELECT EXISTS (
+                         SELECT 1 FROM persistent.blobmetadata
+                         WHERE tag = ? AND blobentryid = (
+                             SELECT MAX(id) FROM persistent.blobentry
+                             WHERE keyentryid = ? AND subcomponent_type = ?
+                         )
+                     );",
+                    params![
+                        BlobMetaData::EncryptedBy,
+                        key_id_guard.id(),
+                        SubComponentType::KEY_BLOB
+                    ],
+                    |row| row.get(0),
                 )
-                .context("Failed to update key entry.")?;
-
-            if updated != 1 {
-                return Err(KsError::sys())
-                    .context(format!("Update succeeded, but {} rows were updated.", updated));
+                .context("Failed to query the encryption of the key blob.")?;
+            if super_encrypted {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context("Cannot adopt a super-encrypted key.");
             }
+
+            Self::rebind_key_entry(tx, key_id_guard.id(), alias, destination)?;
+            tx.execute(
+                "DELETE FROM persistent.grant WHERE keyentryid = ?;",
+                params![key_id_guard.id()],
+            )
+            .context("Failed to revoke grants.")?;
+            grant_cache::note_change();
             Ok(()).no_gc()
         })
         .context(ks_err!())
     }
 
+    /// Renames the key given by `key` to `new_alias` within its namespace. The key keeps its id,
+    /// so its grants and metadata stay with it. Fails with `INVALID_ARGUMENT` if there is
+    /// already a key with `new_alias` in the namespace. Keys can only be renamed by their owner,
+    /// not through a grant.
+    /// The function calls `check_permission` with the renamed descriptor, i.e., with the
+    /// namespace of the key and `new_alias`, which must return Ok if the caller may rebind keys
+    /// in that namespace.
+    pub fn rename_key(
+        &mut self,
+        key: &KeyDescriptor,
+        new_alias: &str,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::rename_key", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let (key_id, access_key_descriptor, _) =
+                Self::load_access_tuple(tx, key, KeyType::Client, caller_uid)
+                    .context("Trying to get access tuple.")?;
+
+            if !matches!(access_key_descriptor.domain, Domain::APP | Domain::SELINUX) {
+                return Err(KsError::perm()).context("Granted keys cannot be renamed.");
+            }
+            let destination = KeyDescriptor {
+                alias: Some(new_alias.to_string()),
+                blob: None,
+                ..access_key_descriptor
+            };
+
+            // Security critical: Must return immediately on failure. Do not remove the '?';
+            check_permission(&destination).context("While checking permission.")?;
+            Self::check_alias_binding(tx, key, key_id, KeyType::Client)?;
+
+            Self::rebind_key_entry(tx, key_id, new_alias, &destination).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Assigns `alias` and the domain and namespace of `destination` to the key entry with
+    /// the given id. Fails if the destination is already occupied.
+    fn rebind_key_entry(
+        tx: &Transaction,
+        key_id: i64,
+        alias: &str,
+        destination: &KeyDescriptor,
+    ) -> Result<()> {
+        // Query the destination location. If there is a key, the migration request fails.
+        if tx
+            .query_row(
+                "SELECT id FROM persistent.keyentry
+                 WHERE alias = ? AND domain = ? AND namespace = ?;",
+                params![alias, destination.domain.0, destination.nspace],
+                |_| Ok(()),
+            )
+            .optional()
+            .context("Failed to query destination.")?
+            .is_some()
+        {
+            return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("Target already exists.");
+        }
+
+        let updated = tx
+            .execute(
+                "UPDATE persistent.keyentry
+                 SET alias = ?, domain = ?, namespace = ?
+                 WHERE id = ?;",
+                params![alias, destination.domain.0, destination.nspace, key_id],
+            )
+            .context("Failed to update key entry.")?;
+
+        if updated != 1 {
+            return Err(KsError::sys())
+                .context(format!("Update succeeded, but {} rows were updated.", updated));
+        }
+        Self::record_key_history(tx, KeyHistoryEvent::Rebound, "id = ?", params![key_id])
+            .context("Failed to record rebound entry.")
+    }
+
+    /// Moves live app keys whose alias starts with one of `prefixes` to a different alias in
+    /// the same namespace. `new_alias` is called with the namespace, key id, and alias of each
+    /// such key and returns the alias to move the key to, or None if the key stays where it is.
+    /// Returns the number of keys that were moved.
+    pub fn rename_app_aliases_with_prefix(
+        &mut self,
+        prefixes: &[String],
+        new_alias: impl Fn(i64, i64, &str) -> Option<String>,
+    ) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::rename_app_aliases_with_prefix", 500);
+
+        self.with_transaction(
+            TransactionCategory::Maintenance,
+            TransactionBehavior::Immediate,
+            |tx| {
+                let mut keys: Vec<(i64, i64, String)> = Vec::new();
+                {
+                    let mut stmt = tx
+                        .prepare(
+                            "SELECT id, namespace, alias FROM persistent.keyentry
+                             WHERE domain = ? AND state = ? AND key_type = ?
+                             AND substr(alias, 1, length(?)) = ?;",
+                        )
+                        .context("Failed to prepare statement.")?;
+                    for prefix in prefixes {
+                        let rows = stmt
+                            .query_map(
+                                params![
+                                    Domain::APP.0 as u32,
+                                    KeyLifeCycle::Live,
+                                    KeyType::Client,
+                                    prefix,
+                                    prefix
+                                ],
+                                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                            )
+                            .context("Failed to query keys with prefix.")?;
+                        for row in rows {
+                            keys.push(row.context("Failed to read key entry.")?);
+                        }
+                    }
+                }
+                // A key may match more than one prefix.
+                keys.sort_unstable();
+                keys.dedup();
+
+                let mut renamed = 0;
+                for (key_id, namespace, alias) in keys {
+                    if let Some(new_alias) = new_alias(namespace, key_id, &alias) {
+                        let destination = KeyDescriptor {
+                            domain: Domain::APP,
+                            nspace: namespace,
+                            alias: Some(new_alias.clone()),
+                            blob: None,
+                        };
+                        Self::rebind_key_entry(tx, key_id, &new_alias, &destination)
+                            .context(format!("Failed to rename key {}.", key_id))?;
+                        renamed += 1;
+                    }
+                }
+                Ok(renamed).no_gc()
+            },
+        )
+        .context(ks_err!())
+    }
+
     /// Store a new key in a single transaction.
     /// The function creates a new key entry, populates the blob, key parameter, and metadata
     /// fields, and rebinds the given alias to the new key.
@@ -1829,7 +2994,7 @@ impl KeystoreDB {
                     .context(ks_err!("Need alias and domain must be APP or SELINUX."));
             }
         };
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
             let key_id = Self::create_key_entry_internal(tx, &domain, namespace, key_type, km_uuid)
                 .context("Trying to create new key entry.")?;
             let BlobInfo { blob, metadata: blob_metadata, superseded_blob } = *blob_info;
@@ -1908,7 +3073,7 @@ impl KeystoreDB {
                     .context(ks_err!("Need alias and domain must be APP or SELINUX."));
             }
         };
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
             let key_id = Self::create_key_entry_internal(tx, &domain, namespace, key_type, km_uuid)
                 .context("Trying to create new key entry.")?;
 
@@ -1966,37 +3131,124 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
-    /// This helper function completes the access tuple of a key, which is required
-    /// to perform access control. The strategy depends on the `domain` field in the
-    /// key descriptor.
-    /// * Domain::SELINUX: The access tuple is complete and this function only loads
-    ///       the key_id for further processing.
-    /// * Domain::APP: Like Domain::SELINUX, but the tuple is completed by `caller_uid`
-    ///       which serves as the namespace.
-    /// * Domain::GRANT: The grant table is queried for the `key_id` and the
-    ///       `access_vector`.
-    /// * Domain::KEY_ID: The keyentry table is queried for the owning `domain` and
-    ///       `namespace`.
-    /// In each case the information returned is sufficient to perform the access
-    /// check and the key id can be used to load further key artifacts.
-    fn load_access_tuple(
+    /// Checks that the alias of `key`, if it has the domain `Domain::KEY_ID`, is bound to the
+    /// key with `key_id`. Callers name the alias together with the key id to make sure that they
+    /// still use the key they looked up, even if the alias was rebound in the meantime. Returns
+    /// `KEY_CHANGED` if the alias is now bound to a different key in the namespace of `key_id`,
+    /// and `KEY_NOT_FOUND` otherwise. The result reveals whether the
+    /// alias was rebound, so it must only be checked after the caller passed the permission check.
+    fn check_alias_binding(
         tx: &Transaction,
         key: &KeyDescriptor,
+        key_id: i64,
         key_type: KeyType,
-        caller_uid: u32,
-    ) -> Result<(i64, KeyDescriptor, Option<KeyPermSet>)> {
-        match key.domain {
-            // Domain App or SELinux. In this case we load the key_id from
-            // the keyentry database for further loading of key components.
-            // We already have the full access tuple to perform access control.
-            // The only distinction is that we use the caller_uid instead
-            // of the caller supplied namespace if the domain field is
-            // Domain::APP.
-            Domain::APP | Domain::SELINUX => {
-                let mut access_key = key.clone();
-                if access_key.domain == Domain::APP {
-                    access_key.nspace = caller_uid as i64;
-                }
+    ) -> Result<()> {
+        let alias = match (key.domain, &key.alias) {
+            (Domain::KEY_ID, Some(alias)) => alias,
+            _ => return Ok(()),
+        };
+        let entry: Option<(Option<i64>, Option<i64>, Option<String>, KeyLifeCycle)> = tx
+            .query_row(
+                "SELECT domain, namespace, alias, state FROM persistent.keyentry WHERE id = ?;",
+                params![key_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()
+            .context("Failed to load key entry.")?;
+        let owner = match entry {
+            Some((_, _, Some(current_alias), KeyLifeCycle::Live)) if &current_alias == alias => {
+                return Ok(());
+            }
+            Some((Some(domain), Some(namespace), _, _)) => Some((domain, namespace)),
+            _ => Self::load_replaced_key_owner(tx, key_id)?,
+        };
+        let rebound = match owner {
+            Some((domain, namespace)) => tx
+                .query_row(
+                    "SELECT id FROM persistent.keyentry
+                     WHERE key_type = ? AND domain = ? AND namespace = ? AND alias = ?
+                     AND state = ?;",
+                    params![key_type, domain, namespace, alias, KeyLifeCycle::Live],
+                    |_| Ok(()),
+                )
+                .optional()
+                .context("Failed to look up alias.")?
+                .is_some(),
+            None => false,
+        };
+        if rebound {
+            Err(KsError::Rc(KEY_CHANGED)).context("Alias is bound to a different key.")
+        } else {
+            Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND)).context("Alias is not bound to the key.")
+        }
+    }
+
+    /// A replaced key loses its namespace, but the key history remembers it.
+    fn load_replaced_key_owner(tx: &Transaction, key_id: i64) -> Result<Option<(i64, i64)>> {
+        tx.query_row(
+            "SELECT domain, namespace FROM persistent.keyhistory
+             WHERE keyidhash = ? ORDER BY id DESC LIMIT 1;",
+            params![Self::key_id_hash(key_id)?],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .context("Failed to load key history.")
+    }
+
+    /// Like `load_access_tuple`, but serves grant lookups from `GRANT_CACHE` if
+    /// `grant_cache_generation` is given. It must have been read before `tx` started.
+    fn load_access_tuple_cached(
+        tx: &Transaction,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        grant_cache_generation: Option<u64>,
+    ) -> Result<(i64, KeyDescriptor, Option<KeyPermSet>)> {
+        let generation = match (key.domain, grant_cache_generation) {
+            (Domain::GRANT, Some(generation)) => generation,
+            _ => return Self::load_access_tuple(tx, key, key_type, caller_uid),
+        };
+        if let Some((key_id, access_vector)) = GRANT_CACHE.get(caller_uid, key.nspace) {
+            return Ok((key_id, key.clone(), Some(access_vector)));
+        }
+        let (key_id, access_key, access_vector) =
+            Self::load_access_tuple(tx, key, key_type, caller_uid)?;
+        if let Some(access_vector) = access_vector {
+            GRANT_CACHE.insert(caller_uid, key.nspace, key_id, access_vector, generation);
+        }
+        Ok((key_id, access_key, access_vector))
+    }
+
+    /// This helper function completes the access tuple of a key, which is required
+    /// to perform access control. The strategy depends on the `domain` field in the
+    /// key descriptor.
+    /// * Domain::SELINUX: The access tuple is complete and this function only loads
+    ///       the key_id for further processing.
+    /// * Domain::APP: Like Domain::SELINUX, but the tuple is completed by `caller_uid`
+    ///       which serves as the namespace.
+    /// * Domain::GRANT: The grant table is queried for the `key_id` and the
+    ///       `access_vector`.
+    /// * Domain::KEY_ID: The keyentry table is queried for the owning `domain` and
+    ///       `namespace`. If the key descriptor has an alias, the owner of a replaced key is
+    ///       taken from the key history, so that callers can check the permission before
+    ///       they check the alias binding with `check_alias_binding`.
+    /// In each case the information returned is sufficient to perform the access
+    /// check and the key id can be used to load further key artifacts.
+    fn load_access_tuple(
+        tx: &Transaction,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+    ) -> Result<(i64, KeyDescriptor, Option<KeyPermSet>)> {
+        match Namespace::for_caller(key, caller_uid).context("Invalid key descriptor.")? {
+            // Domain App or SELinux. In this case we load the key_id from
+            // the keyentry database for further loading of key components.
+            // We already have the full access tuple to perform access control.
+            // The only distinction is that `Namespace::for_caller` uses the caller_uid
+            // instead of the caller supplied namespace if the domain field is
+            // Domain::APP.
+            namespace @ (Namespace::App(_) | Namespace::SeLinux(_)) => {
+                let access_key = namespace.apply_to(key);
                 let key_id = Self::load_key_entry_id(tx, &access_key, key_type)
                     .with_context(|| format!("With key.domain = {:?}.", access_key.domain))?;
 
@@ -2005,7 +3257,7 @@ impl KeystoreDB {
 
             // Domain::GRANT. In this case we load the key_id and the access_vector
             // from the grant table.
-            Domain::GRANT => {
+            Namespace::Grant(grant_id) => {
                 let mut stmt = tx
                     .prepare(
                         "SELECT keyentryid, access_vector FROM persistent.grant
@@ -2014,7 +3266,7 @@ impl KeystoreDB {
                     )
                     .context("Domain::GRANT prepare statement failed")?;
                 let mut rows = stmt
-                    .query(params![caller_uid as i64, key.nspace, KeyLifeCycle::Live])
+                    .query(params![caller_uid as i64, grant_id, KeyLifeCycle::Live])
                     .context("Domain:Grant: query failed.")?;
                 let (key_id, access_vector): (i64, i32) =
                     db_utils::with_rows_extract_one(&mut rows, |row| {
@@ -2031,29 +3283,33 @@ impl KeystoreDB {
 
             // Domain::KEY_ID. In this case we load the domain and namespace from the
             // keyentry database because we need them for access control.
-            Domain::KEY_ID => {
-                let (domain, namespace): (Domain, i64) = {
-                    let mut stmt = tx
-                        .prepare(
-                            "SELECT domain, namespace FROM persistent.keyentry
-                                WHERE
-                                id = ?
-                                AND state = ?;",
-                        )
-                        .context("Domain::KEY_ID: prepare statement failed")?;
-                    let mut rows = stmt
-                        .query(params![key.nspace, KeyLifeCycle::Live])
-                        .context("Domain::KEY_ID: query failed.")?;
-                    db_utils::with_rows_extract_one(&mut rows, |row| {
-                        let r =
-                            row.map_or_else(|| Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND)), Ok)?;
-                        Ok((
-                            Domain(r.get(0).context("Failed to unpack domain.")?),
-                            r.get(1).context("Failed to unpack namespace.")?,
-                        ))
-                    })
-                    .context("Domain::KEY_ID.")?
+            Namespace::KeyId(key_id) => {
+                let live_owner: Option<(Domain, i64)> = tx
+                    .query_row(
+                        "SELECT domain, namespace FROM persistent.keyentry
+                            WHERE
+                            id = ?
+                            AND state = ?;",
+                        params![key_id, KeyLifeCycle::Live],
+                        |row| Ok((Domain(row.get(0)?), row.get(1)?)),
+                    )
+                    .optional()
+                    .context("Domain::KEY_ID: query failed.")?;
+                let (domain, namespace) = match (live_owner, &key.alias) {
+                    (Some(owner), _) => owner,
+                    // If the alias was rebound, the caller learns it from `check_alias_binding`,
+                    // which requires the owner of the replaced key for the permission check.
+                    (None, Some(_)) => Self::load_replaced_key_owner(tx, key_id)?
+                        .map(|(domain, namespace)| (Domain(domain as i32), namespace))
+                        .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                        .context("Domain::KEY_ID.")?,
+                    (None, None) => {
+                        return Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                            .context("Domain::KEY_ID.")
+                    }
                 };
+                let owner = Namespace::new(domain, namespace)
+                    .context("Domain::KEY_ID: invalid owner namespace.")?;
 
                 // We may use a key by id after loading it by grant.
                 // In this case we have to check if the caller has a grant for this particular
@@ -2061,31 +3317,118 @@ impl KeystoreDB {
                 // But we cannot know this if domain is anything but App. E.g. in the case
                 // of Domain::SELINUX we have to speculatively check for grants because we have to
                 // consult the SEPolicy before we know if the caller is the owner.
-                let access_vector: Option<KeyPermSet> =
-                    if domain != Domain::APP || namespace != caller_uid as i64 {
-                        let access_vector: Option<i32> = tx
-                            .query_row(
-                                "SELECT access_vector FROM persistent.grant
+                let access_vector: Option<KeyPermSet> = if owner != Namespace::App(caller_uid) {
+                    let access_vector: Option<i32> = tx
+                        .query_row(
+                            "SELECT access_vector FROM persistent.grant
                                 WHERE grantee = ? AND keyentryid = ?;",
-                                params![caller_uid as i64, key.nspace],
-                                |row| row.get(0),
-                            )
-                            .optional()
-                            .context("Domain::KEY_ID: query grant failed.")?;
-                        access_vector.map(|p| p.into())
-                    } else {
-                        None
-                    };
+                            params![caller_uid as i64, key_id],
+                            |row| row.get(0),
+                        )
+                        .optional()
+                        .context("Domain::KEY_ID: query grant failed.")?;
+                    access_vector.map(|p| p.into())
+                } else {
+                    None
+                };
+
+                Ok((key_id, owner.apply_to(key), access_vector))
+            }
+            Namespace::Blob(_) => Err(anyhow!(KsError::Rc(ResponseCode::INVALID_ARGUMENT))),
+        }
+    }
+
+    fn blob_checksum(blob: &[u8]) -> Result<Vec<u8>> {
+        hmac_sha256(Self::BLOB_CHECKSUM_KEY, blob).context(ks_err!("Failed to compute checksum."))
+    }
 
-                let key_id = key.nspace;
-                let mut access_key: KeyDescriptor = key.clone();
-                access_key.domain = domain;
-                access_key.nspace = namespace;
+    /// Fails with `VALUE_CORRUPTED` if `blob` does not match `checksum`. Blobs without a checksum
+    /// are accepted.
+    fn verify_blob_checksum(blob_id: i64, blob: &[u8], checksum: Option<&[u8]>) -> Result<()> {
+        match checksum {
+            Some(checksum) if Self::blob_checksum(blob)? != checksum => {
+                log::error!("Checksum mismatch for blob {}. The database is corrupted.", blob_id);
+                post_mortem::report(
+                    FatalEvent::BlobChecksumMismatch,
+                    "blobentry",
+                    &[("blob_id", blob_id)],
+                );
+                Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED))
+                    .context(ks_err!("Blob checksum mismatch for blob {}.", blob_id))
+            }
+            _ => Ok(()),
+        }
+    }
 
-                Ok((key_id, access_key, access_vector))
+    /// Verifies the checksums of all blobs in the database. Returns the ids of the live key
+    /// entries that own a corrupted blob, ordered by key id. Blobs of deleted keys are checked as
+    /// well, but only counted in the log.
+    /// The blobs are read in batches of `BLOB_CHECKSUM_AUDIT_BATCH_SIZE`, each in its own
+    /// transaction, so that the audit does not hold up other database users for long.
+    pub fn audit_blob_checksums(&mut self) -> Result<Vec<i64>> {
+        let mut after_blob_id = i64::MIN;
+        let mut corrupted_blobs = 0;
+        let mut key_ids = BTreeSet::new();
+        loop {
+            let (last_blob_id, corrupted) =
+                self.audit_blob_checksum_batch(after_blob_id).context(ks_err!())?;
+            corrupted_blobs += corrupted.len();
+            key_ids.extend(corrupted.into_iter().filter(|(_, is_live)| *is_live).map(|(id, _)| id));
+            match last_blob_id {
+                Some(blob_id) => after_blob_id = blob_id,
+                None => break,
             }
-            _ => Err(anyhow!(KsError::Rc(ResponseCode::INVALID_ARGUMENT))),
         }
+        log::info!("Blob checksum audit found {} corrupted blobs.", corrupted_blobs);
+        Ok(key_ids.into_iter().collect())
+    }
+
+    /// Verifies the checksums of the next batch of blobs whose id is greater than
+    /// `after_blob_id`. Returns the id of the last blob of the batch, or None if there were no
+    /// more blobs, and the key id of each corrupted blob along with whether the key is live.
+    fn audit_blob_checksum_batch(
+        &mut self,
+        after_blob_id: i64,
+    ) -> Result<(Option<i64>, Vec<(i64, bool)>)> {
+        let _wp = wd::watch_millis("KeystoreDB::audit_blob_checksum_batch", 500);
+
+        self.with_transaction(
+            TransactionCategory::Maintenance,
+            TransactionBehavior::Deferred,
+            |tx| {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT id, keyentryid, blob, checksum,
+                             keyentryid IN (SELECT id FROM persistent.keyentry WHERE state = ?)
+                         FROM persistent.blobentry WHERE id > ? ORDER BY id LIMIT ?;",
+                    )
+                    .context("Failed to prepare statement.")?;
+                let mut rows = stmt
+                    .query(params![
+                        KeyLifeCycle::Live,
+                        after_blob_id,
+                        Self::BLOB_CHECKSUM_AUDIT_BATCH_SIZE
+                    ])
+                    .context("Failed to query blobs.")?;
+                let mut last_blob_id = None;
+                let mut corrupted = Vec::new();
+                db_utils::with_rows_extract_all(&mut rows, |row| {
+                    let blob_id: i64 = row.get(0).context("Failed to read blob id.")?;
+                    let key_id: i64 = row.get(1).context("Failed to read key id.")?;
+                    let blob: Vec<u8> = row.get(2).context("Failed to read blob.")?;
+                    let checksum: Option<Vec<u8>> =
+                        row.get(3).context("Failed to read checksum.")?;
+                    let is_live: bool = row.get(4).context("Failed to read key state.")?;
+                    if Self::verify_blob_checksum(blob_id, &blob, checksum.as_deref()).is_err() {
+                        corrupted.push((key_id, is_live));
+                    }
+                    last_blob_id = Some(blob_id);
+                    Ok(())
+                })
+                .context("Failed to extract rows.")?;
+                Ok((last_blob_id, corrupted)).no_gc()
+            },
+        )
     }
 
     fn load_blob_components(
@@ -2095,7 +3438,7 @@ impl KeystoreDB {
     ) -> Result<(bool, Option<(Vec<u8>, BlobMetaData)>, Option<Vec<u8>>, Option<Vec<u8>>)> {
         let mut stmt = tx
             .prepare(
-                "SELECT MAX(id), subcomponent_type, blob FROM persistent.blobentry
+                "SELECT MAX(id), subcomponent_type, blob, checksum FROM persistent.blobentry
                     WHERE keyentryid = ? GROUP BY subcomponent_type;",
             )
             .context(ks_err!("prepare statement failed."))?;
@@ -2110,20 +3453,30 @@ impl KeystoreDB {
             let sub_type: SubComponentType =
                 row.get(1).context("Failed to extract subcomponent_type.")?;
             has_km_blob = has_km_blob || sub_type == SubComponentType::KEY_BLOB;
+            let verified_blob = || -> Result<Vec<u8>> {
+                let blob_id: i64 = row.get(0).context("Failed to extract blob id.")?;
+                let blob: Vec<u8> = row.get(2).context("Failed to extract blob.")?;
+                let checksum: Option<Vec<u8>> =
+                    row.get(3).context("Failed to extract checksum.")?;
+                Self::verify_blob_checksum(blob_id, &blob, checksum.as_deref())?;
+                shadow::verify(tx, blob_id, &blob)?;
+                Ok(blob)
+            };
             match (sub_type, load_bits.load_public(), load_bits.load_km()) {
                 (SubComponentType::KEY_BLOB, _, true) => {
                     key_blob = Some((
                         row.get(0).context("Failed to extract key blob id.")?,
-                        row.get(2).context("Failed to extract key blob.")?,
+                        verified_blob().context("Failed to extract key blob.")?,
                     ));
                 }
                 (SubComponentType::CERT, true, _) => {
-                    cert_blob =
-                        Some(row.get(2).context("Failed to extract public certificate blob.")?);
+                    cert_blob = Some(
+                        verified_blob().context("Failed to extract public certificate blob.")?,
+                    );
                 }
                 (SubComponentType::CERT_CHAIN, true, _) => {
                     cert_chain_blob =
-                        Some(row.get(2).context("Failed to extract certificate chain blob.")?);
+                        Some(verified_blob().context("Failed to extract certificate chain blob.")?);
                 }
                 (SubComponentType::CERT, _, _)
                 | (SubComponentType::CERT_CHAIN, _, _)
@@ -2178,7 +3531,7 @@ impl KeystoreDB {
     pub fn check_and_update_key_usage_count(&mut self, key_id: i64) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::check_and_update_key_usage_count", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
             let limit: Option<i32> = tx
                 .query_row(
                     "SELECT data FROM persistent.keyparameter WHERE keyentryid = ? AND tag = ?;",
@@ -2201,7 +3554,7 @@ impl KeystoreDB {
             .context("Failed to update key usage count.")?;
 
             match limit {
-                1 => Self::mark_unreferenced(tx, key_id)
+                1 => Self::mark_unreferenced(tx, key_id, KeyHistoryEvent::Deleted)
                     .map(|need_gc| (need_gc, ()))
                     .context("Trying to mark limited use key for deletion."),
                 0 => Err(KsError::Km(ErrorCode::INVALID_KEY_BLOB)).context("Key is exhausted."),
@@ -2211,6 +3564,31 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
+    /// Reserves `count` values of the nonce counter of a key with managed nonces, and returns the
+    /// first of them. The counter is persisted before the values are handed out, so they are not
+    /// reserved again.
+    pub fn reserve_nonce_counters(&mut self, key_id: i64, count: i64) -> Result<i64> {
+        let _wp = wd::watch_millis("KeystoreDB::reserve_nonce_counters", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let metadata =
+                KeyMetaData::load_from_db(key_id, tx).context("Trying to load key metadata.")?;
+            let first = *metadata
+                .managed_nonce_counter()
+                .ok_or(KsError::Km(ErrorCode::INVALID_KEY_BLOB))
+                .context("The key does not have managed nonces.")?;
+            let end = first
+                .checked_add(count)
+                .ok_or(KsError::Km(ErrorCode::KEY_MAX_OPS_EXCEEDED))
+                .context("The nonce counter is exhausted.")?;
+            let mut update = KeyMetaData::new();
+            update.add(KeyMetaEntry::ManagedNonceCounter(end));
+            update.store_in_db(key_id, tx).context("Trying to store the nonce counter.")?;
+            Ok(first).no_gc()
+        })
+        .context(ks_err!())
+    }
+
     /// Load a key entry by the given key descriptor.
     /// It uses the `check_permission` callback to verify if the access is allowed
     /// given the key access tuple read from the database using `load_access_tuple`.
@@ -2247,6 +3625,35 @@ impl KeystoreDB {
         }
     }
 
+    /// Looks up the key id and the KeyMint instance of a key without loading any of its
+    /// artifacts. The key is resolved and access control is performed as in `load_key_entry`,
+    /// i.e., `check_permission` is called with the access tuple of the key.
+    pub fn load_key_id(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<(i64, Uuid)> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_id", 500);
+
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            let (key_id, access_key_descriptor, access_vector) =
+                Self::load_access_tuple(tx, key, key_type, caller_uid)
+                    .context("Trying to get access tuple.")?;
+
+            // Perform access control. It is vital that we return here if the permission is denied.
+            // So do not touch that '?' at the end.
+            check_permission(&access_key_descriptor, access_vector)
+                .context("While checking permission.")?;
+            Self::check_alias_binding(tx, key, key_id, key_type)?;
+
+            let km_uuid = Self::get_key_km_uuid(tx, key_id)?;
+            Ok((key_id, km_uuid)).no_gc()
+        })
+        .context(ks_err!())
+    }
+
     fn load_key_entry_internal(
         &mut self,
         key: &KeyDescriptor,
@@ -2263,6 +3670,12 @@ impl KeystoreDB {
             _ => None,
         };
 
+        // The generation must be read before the transaction sees the grant table, see
+        // `grant_cache`.
+        let grant_cache_generation = (key.domain == Domain::GRANT
+            && feature_flags::is_enabled(Feature::GrantCache))
+        .then(|| GRANT_CACHE.generation());
+
         let tx = self
             .conn
             .unchecked_transaction()
@@ -2270,11 +3683,13 @@ impl KeystoreDB {
 
         // Load the key_id and complete the access control tuple.
         let (key_id, access_key_descriptor, access_vector) =
-            Self::load_access_tuple(&tx, key, key_type, caller_uid).context(ks_err!())?;
+            Self::load_access_tuple_cached(&tx, key, key_type, caller_uid, grant_cache_generation)
+                .context(ks_err!())?;
 
         // Perform access control. It is vital that we return here if the permission is denied.
         // So do not touch that '?' at the end.
         check_permission(&access_key_descriptor, access_vector).context(ks_err!())?;
+        Self::check_alias_binding(&tx, key, key_id, key_type).context(ks_err!())?;
 
         // KEY ID LOCK 2/2
         // If we did not get a key id lock by now, it was because we got a key descriptor
@@ -2330,576 +3745,1664 @@ impl KeystoreDB {
         Ok((key_id_guard, key_entry))
     }
 
-    fn mark_unreferenced(tx: &Transaction, key_id: i64) -> Result<bool> {
-        let updated = tx
-            .execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id])
-            .context("Trying to delete keyentry.")?;
-        tx.execute("DELETE FROM persistent.keymetadata WHERE keyentryid = ?;", params![key_id])
-            .context("Trying to delete keymetadata.")?;
-        tx.execute("DELETE FROM persistent.keyparameter WHERE keyentryid = ?;", params![key_id])
-            .context("Trying to delete keyparameters.")?;
-        tx.execute("DELETE FROM persistent.grant WHERE keyentryid = ?;", params![key_id])
-            .context("Trying to delete grants.")?;
-        Ok(updated != 0)
+    /// Returns the hash under which the key history records the key with `key_id`. The history
+    /// outlives the keys and is handed out to diagnostic tools, so it identifies keys by the
+    /// first 64 bits of the SHA-256 digest of their id rather than by the id itself.
+    fn key_id_hash(key_id: i64) -> Result<i64> {
+        let digest = sha256(&key_id.to_be_bytes()).context("Failed to hash key id.")?;
+        let mut hash = [0u8; 8];
+        hash.copy_from_slice(&digest[..8]);
+        Ok(i64::from_be_bytes(hash))
     }
 
-    /// Marks the given key as unreferenced and removes all of the grants to this key.
-    /// Returns Ok(true) if a key was marked unreferenced as a hint for the garbage collector.
-    pub fn unbind_key(
-        &mut self,
-        key: &KeyDescriptor,
-        key_type: KeyType,
-        caller_uid: u32,
-        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    /// Records `event` in the key history for all key entries that match `condition`, an SQL
+    /// expression over the columns of `persistent.keyentry` with `params` as its parameters.
+    /// The entries are recorded with their current domain and namespace. Afterwards, entries
+    /// that exceed the retention limits are dropped, so the history behaves like a ring buffer.
+    fn record_key_history(
+        tx: &Transaction,
+        event: KeyHistoryEvent,
+        condition: &str,
+        params: impl rusqlite::Params,
     ) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::unbind_key", 500);
-
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let (key_id, access_key_descriptor, access_vector) =
-                Self::load_access_tuple(tx, key, key_type, caller_uid)
-                    .context("Trying to get access tuple.")?;
+        let now = DateTime::now().context("Failed to get the current time.")?;
+        let mut keys: Vec<(i64, i64, i64)> = Vec::new();
+        {
+            let mut stmt = tx
+                .prepare(&format!(
+                    "SELECT id, domain, namespace FROM persistent.keyentry
+                     WHERE domain IS NOT NULL AND ({condition});"
+                ))
+                .context("Failed to prepare statement.")?;
+            let mut rows = stmt.query(params).context("Failed to query keyentry table.")?;
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                keys.push((row.get(0)?, row.get(1)?, row.get(2)?));
+                Ok(())
+            })
+            .context("Failed to extract rows.")?;
+        }
+        for (key_id, domain, namespace) in keys {
+            tx.execute(
+                "INSERT INTO persistent.keyhistory (keyidhash, event, domain, namespace, time)
+                 VALUES (?, ?, ?, ?, ?);",
+                params![Self::key_id_hash(key_id)?, event, domain, namespace, now],
+            )
+            .context("Failed to insert into keyhistory table.")?;
+        }
+        tx.execute(
+            "DELETE FROM persistent.keyhistory
+             WHERE id <= (SELECT MAX(id) FROM persistent.keyhistory) - ? OR time < ?;",
+            params![
+                Self::KEY_HISTORY_MAX_ENTRIES,
+                now.to_millis_epoch() - Self::KEY_HISTORY_MAX_AGE.as_millis() as i64
+            ],
+        )
+        .context("Failed to drop expired keyhistory entries.")?;
+        Ok(())
+    }
 
-            // Perform access control. It is vital that we return here if the permission is denied.
-            // So do not touch that '?' at the end.
-            check_permission(&access_key_descriptor, access_vector)
-                .context("While checking permission.")?;
+    /// Returns the key history, oldest entry first. If `namespace` is given, only the entries
+    /// of keys in that namespace are returned.
+    pub fn get_key_history(
+        &mut self,
+        namespace: Option<Namespace>,
+    ) -> Result<Vec<KeyHistoryEntry>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_key_history", 500);
+
+        self.with_transaction(
+            TransactionCategory::Maintenance,
+            TransactionBehavior::Deferred,
+            |tx| {
+                let mut stmt = tx
+                .prepare(
+                    "SELECT keyidhash, event, domain, namespace, time FROM persistent.keyhistory
+                     WHERE ?1 IS NULL OR (domain = ?1 AND namespace = ?2)
+                     ORDER BY id ASC;",
+                )
+                .context("Failed to prepare statement.")?;
+                let mut rows = stmt
+                    .query(params![namespace.map(|n| n.domain().0), namespace.map(|n| n.nspace())])
+                    .context("Failed to query keyhistory table.")?;
+                let mut entries = Vec::new();
+                db_utils::with_rows_extract_all(&mut rows, |row| {
+                    entries.push(KeyHistoryEntry {
+                        key_id_hash: row.get(0).context("Failed to read key id hash.")?,
+                        event: row.get(1).context("Failed to read event.")?,
+                        domain: Domain(row.get(2).context("Failed to read domain.")?),
+                        namespace: row.get(3).context("Failed to read namespace.")?,
+                        time: row.get(4).context("Failed to read time.")?,
+                    });
+                    Ok(())
+                })
+                .context("Failed to extract rows.")?;
+                Ok(entries).no_gc()
+            },
+        )
+        .context(ks_err!())
+    }
 
-            Self::mark_unreferenced(tx, key_id)
-                .map(|need_gc| (need_gc, ()))
-                .context("Trying to mark the key unreferenced.")
-        })
+    /// Returns the characteristics of up to `limit` live client keys in `domain` whose key id is
+    /// greater than `after_key_id`, ordered by key id. Callers page through all keys by passing
+    /// the last returned key id. The keys and their parameters are read in a single query.
+    pub fn load_key_characteristics(
+        &mut self,
+        domain: Domain,
+        after_key_id: i64,
+        limit: usize,
+    ) -> Result<Vec<KeyCharacteristicsEntry>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_characteristics", 500);
+
+        self.with_transaction(
+            TransactionCategory::Maintenance,
+            TransactionBehavior::Deferred,
+            |tx| {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT k.id, k.namespace, k.alias, p.tag, p.data, p.security_level
+                         FROM (SELECT id, namespace, alias FROM persistent.keyentry
+                               WHERE domain = ? AND key_type = ? AND state = ? AND id > ?
+                               ORDER BY id LIMIT ?) AS k
+                         LEFT JOIN persistent.keyparameter AS p ON p.keyentryid = k.id
+                         ORDER BY k.id;",
+                    )
+                    .context("Failed to prepare statement.")?;
+                let mut rows = stmt
+                    .query(params![
+                        domain.0,
+                        KeyType::Client,
+                        KeyLifeCycle::Live,
+                        after_key_id,
+                        limit as i64
+                    ])
+                    .context("Failed to query keys.")?;
+                let mut entries: Vec<KeyCharacteristicsEntry> = Vec::new();
+                db_utils::with_rows_extract_all(&mut rows, |row| {
+                    let key_id: i64 = row.get(0).context("Failed to read key id.")?;
+                    if entries.last().map_or(true, |e| e.key_id != key_id) {
+                        entries.push(KeyCharacteristicsEntry {
+                            key_id,
+                            namespace: row.get(1).context("Failed to read namespace.")?,
+                            alias: row.get(2).context("Failed to read alias.")?,
+                            parameters: Vec::new(),
+                        });
+                    }
+                    let tag: Option<i32> = row.get(3).context("Failed to read tag.")?;
+                    if let Some(tag) = tag {
+                        let sec_level =
+                            SecurityLevel(row.get(5).context("Failed to read sec_level.")?);
+                        let param =
+                            KeyParameter::new_from_sql(Tag(tag), &SqlField::new(4, row), sec_level)
+                                .context("Failed to read KeyParameter.")?;
+                        // The entry was pushed above, if it was not there yet.
+                        entries.last_mut().unwrap().parameters.push(param);
+                    }
+                    Ok(())
+                })
+                .context("Failed to extract rows.")?;
+                Ok(entries).no_gc()
+            },
+        )
         .context(ks_err!())
     }
 
-    fn get_key_km_uuid(tx: &Transaction, key_id: i64) -> Result<Uuid> {
-        tx.query_row(
-            "SELECT km_uuid FROM persistent.keyentry WHERE id = ?",
-            params![key_id],
-            |row| row.get(0),
+    /// Returns the ids and descriptors of the live client keys of the KeyMint instance `km_uuid`
+    /// that have no authorizations enforced by secure hardware, ordered by key id. These keys
+    /// were created by a software implementation that stood in for the instance. Only keys with
+    /// an id greater than `after_key_id` are listed, and at most `limit` of them.
+    pub fn list_keys_without_hardware_enforcement(
+        &mut self,
+        km_uuid: &Uuid,
+        after_key_id: i64,
+        limit: usize,
+    ) -> Result<Vec<(i64, KeyDescriptor)>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_keys_without_hardware_enforcement", 500);
+
+        self.with_transaction(
+            TransactionCategory::Maintenance,
+            TransactionBehavior::Deferred,
+            |tx| {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT id, domain, namespace, alias FROM persistent.keyentry
+                         WHERE key_type = ? AND state = ? AND km_uuid = ? AND id > ?
+                         AND id NOT IN (
+                             SELECT keyentryid FROM persistent.keyparameter
+                             WHERE security_level IN (?, ?)
+                         )
+                         ORDER BY id LIMIT ?;",
+                    )
+                    .context("Failed to prepare statement.")?;
+                let mut rows = stmt
+                    .query(params![
+                        KeyType::Client,
+                        KeyLifeCycle::Live,
+                        km_uuid,
+                        after_key_id,
+                        SecurityLevel::TRUSTED_ENVIRONMENT.0,
+                        SecurityLevel::STRONGBOX.0,
+                        limit as i64
+                    ])
+                    .context("Failed to query keys.")?;
+                let mut keys = Vec::new();
+                db_utils::with_rows_extract_all(&mut rows, |row| {
+                    keys.push((
+                        row.get(0).context("Failed to read key id.")?,
+                        KeyDescriptor {
+                            domain: Domain(row.get(1).context("Failed to read domain.")?),
+                            nspace: row.get(2).context("Failed to read namespace.")?,
+                            alias: row.get(3).context("Failed to read alias.")?,
+                            blob: None,
+                        },
+                    ));
+                    Ok(())
+                })
+                .context("Failed to extract rows.")?;
+                Ok(keys).no_gc()
+            },
         )
         .context(ks_err!())
     }
 
-    /// Delete all artifacts belonging to the namespace given by the domain-namespace tuple.
-    /// This leaves all of the blob entries orphaned for subsequent garbage collection.
-    pub fn unbind_keys_for_namespace(&mut self, domain: Domain, namespace: i64) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::unbind_keys_for_namespace", 500);
+    /// Replaces the key blob and the key parameters of a key in one transaction, for a key whose
+    /// material was imported into another KeyMint implementation. The superseded blob is
+    /// garbage collected.
+    pub fn replace_key_material(
+        &mut self,
+        key_id: &KeyIdGuard,
+        blob: &[u8],
+        blob_metadata: &BlobMetaData,
+        params: &[KeyParameter],
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::replace_key_material", 500);
 
-        if !(domain == Domain::APP || domain == Domain::SELINUX) {
-            return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!());
-        }
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            tx.execute(
-                "DELETE FROM persistent.keymetadata
-                WHERE keyentryid IN (
-                    SELECT id FROM persistent.keyentry
-                    WHERE domain = ? AND namespace = ? AND key_type = ?
-                );",
-                params![domain.0, namespace, KeyType::Client],
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            Self::set_blob_internal(
+                tx,
+                key_id.0,
+                SubComponentType::KEY_BLOB,
+                Some(blob),
+                Some(blob_metadata),
             )
-            .context("Trying to delete keymetadata.")?;
+            .context("Trying to insert the key blob.")?;
             tx.execute(
-                "DELETE FROM persistent.keyparameter
-                WHERE keyentryid IN (
-                    SELECT id FROM persistent.keyentry
-                    WHERE domain = ? AND namespace = ? AND key_type = ?
-                );",
-                params![domain.0, namespace, KeyType::Client],
+                "DELETE FROM persistent.keyparameter WHERE keyentryid = ?;",
+                params![key_id.0],
             )
-            .context("Trying to delete keyparameters.")?;
-            tx.execute(
-                "DELETE FROM persistent.grant
-                WHERE keyentryid IN (
-                    SELECT id FROM persistent.keyentry
-                    WHERE domain = ? AND namespace = ? AND key_type = ?
-                );",
-                params![domain.0, namespace, KeyType::Client],
-            )
-            .context("Trying to delete grants.")?;
-            tx.execute(
-                "DELETE FROM persistent.keyentry
-                 WHERE domain = ? AND namespace = ? AND key_type = ?;",
-                params![domain.0, namespace, KeyType::Client],
-            )
-            .context("Trying to delete keyentry.")?;
-            Ok(()).need_gc()
+            .context("Trying to delete key parameters.")?;
+            Self::insert_keyparameter_internal(tx, key_id, params)
+                .context("Trying to insert key parameters.")
+                .need_gc()
         })
         .context(ks_err!())
     }
 
-    fn cleanup_unreferenced(tx: &Transaction) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::cleanup_unreferenced", 500);
-        {
-            tx.execute(
-                "DELETE FROM persistent.keymetadata
-            WHERE keyentryid IN (
-                SELECT id FROM persistent.keyentry
-                WHERE state = ?
-            );",
-                params![KeyLifeCycle::Unreferenced],
-            )
-            .context("Trying to delete keymetadata.")?;
-            tx.execute(
-                "DELETE FROM persistent.keyparameter
-            WHERE keyentryid IN (
-                SELECT id FROM persistent.keyentry
-                WHERE state = ?
-            );",
-                params![KeyLifeCycle::Unreferenced],
-            )
-            .context("Trying to delete keyparameters.")?;
-            tx.execute(
-                "DELETE FROM persistent.grant
-            WHERE keyentryid IN (
-                SELECT id FROM persistent.keyentry
-                WHERE state = ?
-            );",
-                params![KeyLifeCycle::Unreferenced],
-            )
-            .context("Trying to delete grants.")?;
-            tx.execute(
-                "DELETE FROM persistent.keyentry
-                WHERE state = ?;",
-                params![KeyLifeCycle::Unreferenced],
-            )
-            .context("Trying to delete keyentry.")?;
-            Result::<()>::Ok(())
-        }
+    /// Returns an SQL script that recreates the structure of the persistent database with
+    /// redacted contents. See `skeleton` for what is redacted.
+    pub fn export_skeleton(&mut self) -> Result<String> {
+        let _wp = wd::watch_millis("KeystoreDB::export_skeleton", 500);
+
+        self.with_transaction(
+            TransactionCategory::Maintenance,
+            TransactionBehavior::Deferred,
+            |tx| skeleton::export(tx).no_gc(),
+        )
         .context(ks_err!())
     }
 
-    /// Delete the keys created on behalf of the user, denoted by the user id.
-    /// Delete all the keys unless 'keep_non_super_encrypted_keys' set to true.
-    /// Returned boolean is to hint the garbage collector to delete the unbound keys.
-    /// The caller of this function should notify the gc if the returned value is true.
-    pub fn unbind_keys_for_user(
+    /// Stores the storage key blob of class `key_class` for `user_id` as generation 1. Fails
+    /// with `INVALID_ARGUMENT` if the user already has a key of this class.
+    pub fn register_storage_key(
         &mut self,
         user_id: u32,
-        keep_non_super_encrypted_keys: bool,
+        key_class: StorageKeyClass,
+        key_blob: &[u8],
     ) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::unbind_keys_for_user", 500);
+        let _wp = wd::watch_millis("KeystoreDB::register_storage_key", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let mut stmt = tx
-                .prepare(&format!(
-                    "SELECT id from persistent.keyentry
-                     WHERE (
-                         key_type = ?
-                         AND domain = ?
-                         AND cast ( (namespace/{aid_user_offset}) as int) = ?
-                         AND state = ?
-                     ) OR (
-                         key_type = ?
-                         AND namespace = ?
-                         AND state = ?
-                     );",
-                    aid_user_offset = AID_USER_OFFSET
-                ))
-                .context(concat!(
-                    "In unbind_keys_for_user. ",
-                    "Failed to prepare the query to find the keys created by apps."
-                ))?;
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            if Self::load_storage_key(tx, user_id, key_class)?.is_some() {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context("Storage key is already registered.");
+            }
+            let now = DateTime::now().context("Failed to get the current time.")?;
+            tx.execute(
+                "INSERT INTO persistent.storagekey (userid, keyclass, blob, generation, time)
+                 VALUES (?, ?, ?, 1, ?);",
+                params![user_id, key_class, key_blob, now],
+            )
+            .context("Failed to insert into storagekey table.")
+            .map(|_| ())
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
 
-            let mut rows = stmt
-                .query(params![
-                    // WHERE client key:
-                    KeyType::Client,
-                    Domain::APP.0 as u32,
-                    user_id,
-                    KeyLifeCycle::Live,
-                    // OR super key:
-                    KeyType::Super,
-                    user_id,
-                    KeyLifeCycle::Live
-                ])
-                .context(ks_err!("Failed to query the keys created by apps."))?;
+    /// Returns the storage key blob of class `key_class` for `user_id`. Fails with
+    /// `KEY_NOT_FOUND` if there is none.
+    pub fn get_storage_key(
+        &mut self,
+        user_id: u32,
+        key_class: StorageKeyClass,
+    ) -> Result<StorageKeyEntry> {
+        let _wp = wd::watch_millis("KeystoreDB::get_storage_key", 500);
+
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            Self::load_storage_key(tx, user_id, key_class)?
+                .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context("No storage key registered.")
+                .no_gc()
+        })
+        .context(ks_err!())
+    }
 
-            let mut key_ids: Vec<i64> = Vec::new();
-            db_utils::with_rows_extract_all(&mut rows, |row| {
-                key_ids
-                    .push(row.get(0).context("Failed to read key id of a key created by an app.")?);
-                Ok(())
-            })
-            .context(ks_err!())?;
+    /// Replaces the storage key blob of class `key_class` for `user_id` with `key_blob` and
+    /// increments its generation. Returns the replaced entry. Fails with `KEY_NOT_FOUND` if
+    /// there is none.
+    pub fn rotate_storage_key(
+        &mut self,
+        user_id: u32,
+        key_class: StorageKeyClass,
+        key_blob: &[u8],
+    ) -> Result<StorageKeyEntry> {
+        let _wp = wd::watch_millis("KeystoreDB::rotate_storage_key", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let previous = Self::load_storage_key(tx, user_id, key_class)?
+                .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context("No storage key registered.")?;
+            let now = DateTime::now().context("Failed to get the current time.")?;
+            tx.execute(
+                "UPDATE persistent.storagekey SET blob = ?, generation = ?, time = ?
+                 WHERE userid = ? AND keyclass = ?;",
+                params![key_blob, previous.generation + 1, now, user_id, key_class],
+            )
+            .context("Failed to update storagekey table.")?;
+            Ok(previous).no_gc()
+        })
+        .context(ks_err!())
+    }
 
-            let mut notify_gc = false;
-            for key_id in key_ids {
-                if keep_non_super_encrypted_keys {
-                    // Load metadata and filter out non-super-encrypted keys.
-                    if let (_, Some((_, blob_metadata)), _, _) =
-                        Self::load_blob_components(key_id, KeyEntryLoadBits::KM, tx)
-                            .context(ks_err!("Trying to load blob info."))?
-                    {
-                        if blob_metadata.encrypted_by().is_none() {
-                            continue;
-                        }
-                    }
-                }
-                notify_gc = Self::mark_unreferenced(tx, key_id)
-                    .context("In unbind_keys_for_user.")?
-                    || notify_gc;
+    /// Removes all storage key blobs of `user_id`. Like `set_deleted_blob`, the blobs are handed
+    /// to the garbage collector, which deletes them with the KeyMint instance `km_uuid`. Returns
+    /// the number of removed blobs.
+    pub fn unbind_storage_keys_for_user(&mut self, user_id: u32, km_uuid: &Uuid) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_storage_keys_for_user", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let blobs: Vec<Vec<u8>> = {
+                let mut stmt = tx
+                    .prepare("SELECT blob FROM persistent.storagekey WHERE userid = ?;")
+                    .context("Failed to prepare statement.")?;
+                let rows = stmt
+                    .query_map(params![user_id], |row| row.get(0))
+                    .context("Failed to query storagekey table.")?;
+                rows.collect::<rusqlite::Result<_>>().context("Failed to extract blobs.")?
+            };
+            let mut blob_metadata = BlobMetaData::new();
+            blob_metadata.add(BlobMetaEntry::KmUuid(*km_uuid));
+            for blob in &blobs {
+                Self::set_blob_internal(
+                    tx,
+                    Self::UNASSIGNED_KEY_ID,
+                    SubComponentType::KEY_BLOB,
+                    Some(blob),
+                    Some(&blob_metadata),
+                )
+                .context("Trying to hand storage key blob to the garbage collector.")?;
             }
-            Ok(()).do_gc(notify_gc)
+            tx.execute("DELETE FROM persistent.storagekey WHERE userid = ?;", params![user_id])
+                .context("Failed to delete from storagekey table.")?;
+            Ok((!blobs.is_empty(), blobs.len()))
         })
         .context(ks_err!())
     }
 
-    fn load_key_components(
+    fn load_storage_key(
         tx: &Transaction,
-        load_bits: KeyEntryLoadBits,
-        key_id: i64,
-    ) -> Result<KeyEntry> {
-        let metadata = KeyMetaData::load_from_db(key_id, tx).context("In load_key_components.")?;
+        user_id: u32,
+        key_class: StorageKeyClass,
+    ) -> Result<Option<StorageKeyEntry>> {
+        tx.query_row(
+            "SELECT blob, generation, time FROM persistent.storagekey
+             WHERE userid = ? AND keyclass = ?;",
+            params![user_id, key_class],
+            |row| {
+                Ok(StorageKeyEntry {
+                    key_blob: row.get(0)?,
+                    generation: row.get(1)?,
+                    created: row.get(2)?,
+                })
+            },
+        )
+        .optional()
+        .context("Failed to query storagekey table.")
+    }
 
-        let (has_km_blob, key_blob_info, cert_blob, cert_chain_blob) =
-            Self::load_blob_components(key_id, load_bits, tx).context("In load_key_components.")?;
+    /// Stores `entry` as the credential of type `cred_type` under `alias` in the namespace of
+    /// `owner`, replacing an existing entry.
+    pub fn put_credential(
+        &mut self,
+        owner: u32,
+        cred_type: CredentialType,
+        alias: &str,
+        entry: &CredentialEntry,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::put_credential", 500);
 
-        let parameters = Self::load_key_parameters(key_id, tx)
-            .context("In load_key_components: Trying to load key parameters.")?;
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO persistent.credential
+                    (owner, type, alias, blob, iv, aead_tag)
+                 VALUES (?, ?, ?, ?, ?, ?);",
+                params![owner, cred_type, alias, entry.blob, entry.iv, entry.aead_tag],
+            )
+            .context("Failed to insert into credential table.")
+            .map(|_| ())
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
 
-        let km_uuid = Self::get_key_km_uuid(tx, key_id)
-            .context("In load_key_components: Trying to get KM uuid.")?;
+    /// Returns the credential of type `cred_type` stored under `alias` in the namespace of
+    /// `owner`. Fails with `KEY_NOT_FOUND` if there is none.
+    pub fn get_credential(
+        &mut self,
+        owner: u32,
+        cred_type: CredentialType,
+        alias: &str,
+    ) -> Result<CredentialEntry> {
+        let _wp = wd::watch_millis("KeystoreDB::get_credential", 500);
 
-        Ok(KeyEntry {
-            id: key_id,
-            key_blob_info,
-            cert: cert_blob,
-            cert_chain: cert_chain_blob,
-            km_uuid,
-            parameters,
-            metadata,
-            pure_cert: !has_km_blob,
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT blob, iv, aead_tag FROM persistent.credential
+                 WHERE owner = ? AND type = ? AND alias = ?;",
+                params![owner, cred_type, alias],
+                |row| {
+                    Ok(CredentialEntry {
+                        blob: row.get(0)?,
+                        iv: row.get(1)?,
+                        aead_tag: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .context("Failed to query credential table.")?
+            .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context("No such credential.")
+            .no_gc()
         })
+        .context(ks_err!())
     }
 
-    /// Returns a list of KeyDescriptors in the selected domain/namespace whose
-    /// aliases are greater than the specified 'start_past_alias'. If no value
-    /// is provided, returns all KeyDescriptors.
-    /// The key descriptors will have the domain, nspace, and alias field set.
-    /// The returned list will be sorted by alias.
-    /// Domain must be APP or SELINUX, the caller must make sure of that.
-    pub fn list_past_alias(
+    /// Deletes the credential of type `cred_type` stored under `alias` in the namespace of
+    /// `owner`. Fails with `KEY_NOT_FOUND` if there is none.
+    pub fn remove_credential(
         &mut self,
-        domain: Domain,
-        namespace: i64,
-        key_type: KeyType,
-        start_past_alias: Option<&str>,
-    ) -> Result<Vec<KeyDescriptor>> {
-        let _wp = wd::watch_millis("KeystoreDB::list_past_alias", 500);
+        owner: u32,
+        cred_type: CredentialType,
+        alias: &str,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::remove_credential", 500);
 
-        let query = format!(
-            "SELECT DISTINCT alias FROM persistent.keyentry
-                     WHERE domain = ?
-                     AND namespace = ?
-                     AND alias IS NOT NULL
-                     AND state = ?
-                     AND key_type = ?
-                     {}
-                     ORDER BY alias ASC;",
-            if start_past_alias.is_some() { " AND alias > ?" } else { "" }
-        );
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let deleted = tx
+                .execute(
+                    "DELETE FROM persistent.credential WHERE owner = ? AND type = ? AND alias = ?;",
+                    params![owner, cred_type, alias],
+                )
+                .context("Failed to delete from credential table.")?;
+            if deleted == 0 {
+                return Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                    .context("No such credential.");
+            }
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
 
-        self.with_transaction(TransactionBehavior::Deferred, |tx| {
-            let mut stmt = tx.prepare(&query).context(ks_err!("Failed to prepare."))?;
+    /// Returns the sorted aliases of the credentials of type `cred_type` in the namespace of
+    /// `owner` that start with `prefix`.
+    pub fn list_credentials(
+        &mut self,
+        owner: u32,
+        cred_type: CredentialType,
+        prefix: &str,
+    ) -> Result<Vec<String>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_credentials", 500);
 
-            let mut rows = match start_past_alias {
-                Some(past_alias) => stmt
-                    .query(params![
-                        domain.0 as u32,
-                        namespace,
-                        KeyLifeCycle::Live,
-                        key_type,
-                        past_alias
-                    ])
-                    .context(ks_err!("Failed to query."))?,
-                None => stmt
-                    .query(params![domain.0 as u32, namespace, KeyLifeCycle::Live, key_type,])
-                    .context(ks_err!("Failed to query."))?,
-            };
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT alias FROM persistent.credential WHERE owner = ? AND type = ?
+                     ORDER BY alias ASC;",
+                )
+                .context("Failed to prepare statement.")?;
+            let aliases = stmt
+                .query_map(params![owner, cred_type], |row| row.get::<_, String>(0))
+                .context("Failed to query credential table.")?
+                .collect::<rusqlite::Result<Vec<String>>>()
+                .context("Failed to read aliases.")?;
+            // Filtering here rather than with LIKE avoids escaping the wildcards in `prefix`.
+            Ok(aliases.into_iter().filter(|alias| alias.starts_with(prefix)).collect()).no_gc()
+        })
+        .context(ks_err!())
+    }
 
-            let mut descriptors: Vec<KeyDescriptor> = Vec::new();
-            db_utils::with_rows_extract_all(&mut rows, |row| {
-                descriptors.push(KeyDescriptor {
-                    domain,
-                    nspace: namespace,
-                    alias: Some(row.get(0).context("Trying to extract alias.")?),
-                    blob: None,
-                });
-                Ok(())
-            })
-            .context(ks_err!("Failed to extract rows."))?;
-            Ok(descriptors).no_gc()
+    /// Deletes all credentials in the namespace of `owner`. Returns the number of deleted
+    /// credentials.
+    pub fn delete_credentials_for_owner(&mut self, owner: u32) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_credentials_for_owner", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            tx.execute("DELETE FROM persistent.credential WHERE owner = ?;", params![owner])
+                .context("Failed to delete from credential table.")
+                .no_gc()
         })
+        .context(ks_err!())
     }
 
-    /// Returns a number of KeyDescriptors in the selected domain/namespace.
-    /// Domain must be APP or SELINUX, the caller must make sure of that.
-    pub fn count_keys(
-        &mut self,
-        domain: Domain,
-        namespace: i64,
-        key_type: KeyType,
-    ) -> Result<usize> {
-        let _wp = wd::watch_millis("KeystoreDB::countKeys", 500);
+    /// Deletes the credentials of all namespaces of `user_id`. Returns the number of deleted
+    /// credentials.
+    pub fn delete_credentials_for_user(&mut self, user_id: u32) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_credentials_for_user", 500);
 
-        let num_keys = self.with_transaction(TransactionBehavior::Deferred, |tx| {
-            tx.query_row(
-                "SELECT COUNT(alias) FROM persistent.keyentry
-                     WHERE domain = ?
-                     AND namespace = ?
-                     AND alias IS NOT NULL
-                     AND state = ?
-                     AND key_type = ?;",
-                params![domain.0 as u32, namespace, KeyLifeCycle::Live, key_type],
-                |row| row.get(0),
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                &format!(
+                    "DELETE FROM persistent.credential
+                     WHERE cast ( (owner/{aid_user_offset}) as int) = ?;",
+                    aid_user_offset = AID_USER_OFFSET
+                ),
+                params![user_id],
             )
-            .context(ks_err!("Failed to count number of keys."))
+            .context("Failed to delete from credential table.")
             .no_gc()
-        })?;
-        Ok(num_keys)
+        })
+        .context(ks_err!())
     }
 
-    /// Adds a grant to the grant table.
-    /// Like `load_key_entry` this function loads the access tuple before
-    /// it uses the callback for a permission check. Upon success,
-    /// it inserts the `grantee_uid`, `key_id`, and `access_vector` into the
-    /// grant table. The new row will have a randomized id, which is used as
-    /// grant id in the namespace field of the resulting KeyDescriptor.
-    pub fn grant(
-        &mut self,
-        key: &KeyDescriptor,
-        caller_uid: u32,
-        grantee_uid: u32,
-        access_vector: KeyPermSet,
-        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
-    ) -> Result<KeyDescriptor> {
-        let _wp = wd::watch_millis("KeystoreDB::grant", 500);
-
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            // Load the key_id and complete the access control tuple.
-            // We ignore the access vector here because grants cannot be granted.
-            // The access vector returned here expresses the permissions the
-            // grantee has if key.domain == Domain::GRANT. But this vector
-            // cannot include the grant permission by design, so there is no way the
-            // subsequent permission check can pass.
-            // We could check key.domain == Domain::GRANT and fail early.
-            // But even if we load the access tuple by grant here, the permission
-            // check denies the attempt to create a grant by grant descriptor.
-            let (key_id, access_key_descriptor, _) =
-                Self::load_access_tuple(tx, key, KeyType::Client, caller_uid).context(ks_err!())?;
-
-            // Perform access control. It is vital that we return here if the permission
-            // was denied. So do not touch that '?' at the end of the line.
-            // This permission check checks if the caller has the grant permission
-            // for the given key and in addition to all of the permissions
-            // expressed in `access_vector`.
-            check_permission(&access_key_descriptor, &access_vector)
-                .context(ks_err!("check_permission failed"))?;
-
-            let grant_id = if let Some(grant_id) = tx
-                .query_row(
-                    "SELECT id FROM persistent.grant
-                WHERE keyentryid = ? AND grantee = ?;",
-                    params![key_id, grantee_uid],
-                    |row| row.get(0),
-                )
-                .optional()
-                .context(ks_err!("Failed get optional existing grant id."))?
-            {
-                tx.execute(
-                    "UPDATE persistent.grant
-                    SET access_vector = ?
-                    WHERE id = ?;",
-                    params![i32::from(access_vector), grant_id],
-                )
-                .context(ks_err!("Failed to update existing grant."))?;
-                grant_id
-            } else {
-                Self::insert_with_retry(|id| {
-                    tx.execute(
-                        "INSERT INTO persistent.grant (id, grantee, keyentryid, access_vector)
-                        VALUES (?, ?, ?, ?);",
-                        params![id, grantee_uid, key_id, i32::from(access_vector)],
-                    )
-                })
-                .context(ks_err!())?
-            };
+    /// Sets the longest validity in milliseconds of keys generated or imported by apps of
+    /// `user_id`. `None` removes the limit.
+    pub fn set_max_key_validity(&mut self, user_id: u32, max_validity: Option<i64>) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_max_key_validity", 500);
 
-            Ok(KeyDescriptor { domain: Domain::GRANT, nspace: grant_id, alias: None, blob: None })
-                .no_gc()
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            match max_validity {
+                Some(millis) => tx.execute(
+                    "INSERT OR REPLACE INTO persistent.keyvaliditypolicy (userid, maxvalidity)
+                     VALUES (?, ?);",
+                    params![user_id, millis],
+                ),
+                None => tx.execute(
+                    "DELETE FROM persistent.keyvaliditypolicy WHERE userid = ?;",
+                    params![user_id],
+                ),
+            }
+            .context("Failed to update keyvaliditypolicy table.")
+            .map(|_| ())
+            .no_gc()
         })
+        .context(ks_err!())
     }
 
-    /// This function checks permissions like `grant` and `load_key_entry`
-    /// before removing a grant from the grant table.
-    pub fn ungrant(
-        &mut self,
-        key: &KeyDescriptor,
-        caller_uid: u32,
-        grantee_uid: u32,
-        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
-    ) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::ungrant", 500);
-
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            // Load the key_id and complete the access control tuple.
-            // We ignore the access vector here because grants cannot be granted.
-            let (key_id, access_key_descriptor, _) =
-                Self::load_access_tuple(tx, key, KeyType::Client, caller_uid).context(ks_err!())?;
-
-            // Perform access control. We must return here if the permission
-            // was denied. So do not touch the '?' at the end of this line.
-            check_permission(&access_key_descriptor)
-                .context(ks_err!("check_permission failed."))?;
+    /// Returns the longest validity in milliseconds of keys of `user_id`, if it is limited.
+    pub fn get_max_key_validity(&mut self, user_id: u32) -> Result<Option<i64>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_max_key_validity", 500);
 
-            tx.execute(
-                "DELETE FROM persistent.grant
-                WHERE keyentryid = ? AND grantee = ?;",
-                params![key_id, grantee_uid],
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT maxvalidity FROM persistent.keyvaliditypolicy WHERE userid = ?;",
+                params![user_id],
+                |row| row.get(0),
             )
-            .context("Failed to delete grant.")?;
-
-            Ok(()).no_gc()
+            .optional()
+            .context("Failed to query keyvaliditypolicy table.")
+            .no_gc()
         })
+        .context(ks_err!())
     }
 
-    // Generates a random id and passes it to the given function, which will
-    // try to insert it into a database.  If that insertion fails, retry;
-    // otherwise return the id.
-    fn insert_with_retry(inserter: impl Fn(i64) -> rusqlite::Result<usize>) -> Result<i64> {
-        loop {
-            let newid: i64 = match random() {
-                Self::UNASSIGNED_KEY_ID => continue, // UNASSIGNED_KEY_ID cannot be assigned.
-                i => i,
-            };
-            match inserter(newid) {
-                // If the id already existed, try again.
-                Err(rusqlite::Error::SqliteFailure(
-                    libsqlite3_sys::Error {
-                        code: libsqlite3_sys::ErrorCode::ConstraintViolation,
-                        extended_code: libsqlite3_sys::SQLITE_CONSTRAINT_UNIQUE,
-                    },
-                    _,
-                )) => (),
-                Err(e) => {
-                    return Err(e).context(ks_err!("failed to insert into database."));
-                }
-                _ => return Ok(newid),
-            }
-        }
-    }
+    /// Stores the name of the patch level policy that overrides the configured one. `None`
+    /// removes the override. See `patch_level_policy`.
+    pub fn set_patch_level_policy(&mut self, policy: Option<&str>) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_patch_level_policy", 500);
 
-    /// Insert or replace the auth token based on (user_id, auth_id, auth_type)
-    pub fn insert_auth_token(&mut self, auth_token: &HardwareAuthToken) {
-        self.perboot.insert_auth_token_entry(AuthTokenEntry::new(
-            auth_token.clone(),
-            MonotonicRawTime::now(),
-        ))
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            match policy {
+                Some(policy) => tx.execute(
+                    "INSERT OR REPLACE INTO persistent.patchlevelpolicy (id, policy)
+                     VALUES (0, ?);",
+                    params![policy],
+                ),
+                None => tx.execute("DELETE FROM persistent.patchlevelpolicy WHERE id = 0;", []),
+            }
+            .context("Failed to update patchlevelpolicy table.")
+            .map(|_| ())
+            .no_gc()
+        })
+        .context(ks_err!())
     }
 
-    /// Find the newest auth token matching the given predicate.
-    pub fn find_auth_token_entry<F>(&self, p: F) -> Option<(AuthTokenEntry, MonotonicRawTime)>
-    where
-        F: Fn(&AuthTokenEntry) -> bool,
-    {
-        self.perboot.find_auth_token_entry(p).map(|entry| (entry, self.get_last_off_body()))
-    }
+    /// Returns the name of the patch level policy that overrides the configured one, if any.
+    pub fn get_patch_level_policy(&mut self) -> Result<Option<String>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_patch_level_policy", 500);
 
-    /// Insert last_off_body into the metadata table at the initialization of auth token table
-    pub fn insert_last_off_body(&self, last_off_body: MonotonicRawTime) {
-        self.perboot.set_last_off_body(last_off_body)
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT policy FROM persistent.patchlevelpolicy WHERE id = 0;",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to query patchlevelpolicy table.")
+            .no_gc()
+        })
+        .context(ks_err!())
     }
 
-    /// Update last_off_body when on_device_off_body is called
-    pub fn update_last_off_body(&self, last_off_body: MonotonicRawTime) {
-        self.perboot.set_last_off_body(last_off_body)
-    }
+    /// Returns the nonce and the MAC that bind the database to the device, or None if the
+    /// database is not bound yet. See `database_binding`.
+    pub fn load_database_binding(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_database_binding", 500);
 
-    /// Get last_off_body time when finding auth tokens
-    fn get_last_off_body(&self) -> MonotonicRawTime {
-        self.perboot.get_last_off_body()
+        self.with_transaction(TransactionCategory::Init, TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT nonce, mac FROM persistent.databasebinding WHERE id = 0;",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .context("Failed to query databasebinding table.")
+            .no_gc()
+        })
+        .context(ks_err!())
     }
 
-    /// Load descriptor of a key by key id
-    pub fn load_key_descriptor(&mut self, key_id: i64) -> Result<Option<KeyDescriptor>> {
-        let _wp = wd::watch_millis("KeystoreDB::load_key_descriptor", 500);
+    /// Binds the database to the device with the given nonce and MAC. An existing binding is
+    /// replaced.
+    pub fn store_database_binding(&mut self, nonce: &[u8], mac: &[u8]) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::store_database_binding", 500);
 
-        self.with_transaction(TransactionBehavior::Deferred, |tx| {
-            tx.query_row(
-                "SELECT domain, namespace, alias FROM persistent.keyentry WHERE id = ?;",
-                params![key_id],
-                |row| {
-                    Ok(KeyDescriptor {
-                        domain: Domain(row.get(0)?),
-                        nspace: row.get(1)?,
-                        alias: row.get(2)?,
-                        blob: None,
-                    })
-                },
+        self.with_transaction(TransactionCategory::Init, TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO persistent.databasebinding (id, nonce, mac)
+                 VALUES (0, ?, ?);",
+                params![nonce, mac],
             )
-            .optional()
-            .context("Trying to load key descriptor")
+            .context("Failed to insert into databasebinding table.")
+            .map(|_| ())
             .no_gc()
         })
         .context(ks_err!())
     }
-}
 
-#[cfg(test)]
-pub mod tests {
+    /// Returns the key of the fingerprints of imported key material. If there is none yet,
+    /// `new_key` is stored and returned. See `import_fingerprint`.
+    pub fn get_or_insert_fingerprint_key(&mut self, new_key: &[u8]) -> Result<Vec<u8>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_or_insert_fingerprint_key", 500);
 
-    use super::*;
-    use crate::key_parameter::{
-        Algorithm, BlockMode, Digest, EcCurve, HardwareAuthenticatorType, KeyOrigin, KeyParameter,
-        KeyParameterValue, KeyPurpose, PaddingMode, SecurityLevel,
-    };
-    use crate::key_perm_set;
-    use crate::permission::{KeyPerm, KeyPermSet};
-    use crate::super_key::{SuperKeyManager, USER_AFTER_FIRST_UNLOCK_SUPER_KEY, SuperEncryptionAlgorithm, SuperKeyType};
-    use keystore2_test_utils::TempDir;
-    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-        HardwareAuthToken::HardwareAuthToken,
-        HardwareAuthenticatorType::HardwareAuthenticatorType as kmhw_authenticator_type,
-    };
-    use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::{
-        Timestamp::Timestamp,
-    };
-    use rusqlite::TransactionBehavior;
-    use std::cell::RefCell;
-    use std::collections::BTreeMap;
-    use std::fmt::Write;
-    use std::sync::atomic::{AtomicU8, Ordering};
-    use std::sync::Arc;
-    use std::thread;
-    use std::time::{Duration, SystemTime};
-    use crate::utils::AesGcm;
-    #[cfg(disabled)]
-    use std::time::Instant;
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "INSERT OR IGNORE INTO persistent.fingerprintkey (id, key) VALUES (0, ?);",
+                params![new_key],
+            )
+            .context("Failed to insert into fingerprintkey table.")?;
+            tx.query_row("SELECT key FROM persistent.fingerprintkey WHERE id = 0;", [], |row| {
+                row.get(0)
+            })
+            .context("Failed to query fingerprintkey table.")
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
 
-    pub fn new_test_db() -> Result<KeystoreDB> {
-        let conn = KeystoreDB::make_connection("file::memory:")?;
+    /// Returns the aliases of the live client keys in the given namespace whose imported key
+    /// material has the fingerprint `fingerprint`, sorted by alias.
+    /// Domain must be APP or SELINUX, the caller must make sure of that.
+    pub fn find_imports_with_fingerprint(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        fingerprint: &[u8],
+    ) -> Result<Vec<String>> {
+        let _wp = wd::watch_millis("KeystoreDB::find_imports_with_fingerprint", 500);
 
-        let mut db = KeystoreDB { conn, gc: None, perboot: Arc::new(perboot::PerbootDB::new()) };
-        db.with_transaction(TransactionBehavior::Immediate, |tx| {
-            KeystoreDB::init_tables(tx).context("Failed to initialize tables.").no_gc()
-        })?;
-        Ok(db)
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keyentry.alias FROM persistent.keyentry
+                     INNER JOIN persistent.keymetadata
+                        ON keymetadata.keyentryid = keyentry.id
+                     WHERE keyentry.domain = ?
+                     AND keyentry.namespace = ?
+                     AND keyentry.alias IS NOT NULL
+                     AND keyentry.state = ?
+                     AND keyentry.key_type = ?
+                     AND keymetadata.tag = ?
+                     AND keymetadata.data = ?
+                     ORDER BY keyentry.alias ASC;",
+                )
+                .context("Failed to prepare statement.")?;
+            let aliases = stmt
+                .query_map(
+                    params![
+                        domain.0 as u32,
+                        namespace,
+                        KeyLifeCycle::Live,
+                        KeyType::Client,
+                        KeyMetaData::ImportFingerprint,
+                        fingerprint,
+                    ],
+                    |row| row.get(0),
+                )
+                .context("Failed to query keys.")?
+                .collect::<rusqlite::Result<Vec<String>>>()
+                .context("Failed to extract aliases.")?;
+            Ok(aliases).no_gc()
+        })
+        .context(ks_err!())
     }
 
-    fn rebind_alias(
-        db: &mut KeystoreDB,
-        newid: &KeyIdGuard,
-        alias: &str,
+    /// Returns the groups of aliases of the live client keys in the given namespace that were
+    /// imported with the same key material, i.e., that have the same import fingerprint. Each
+    /// group has at least two aliases and is sorted by alias.
+    /// Domain must be APP or SELINUX, the caller must make sure of that.
+    pub fn list_duplicate_imports(
+        &mut self,
         domain: Domain,
         namespace: i64,
-    ) -> Result<bool> {
-        db.with_transaction(TransactionBehavior::Immediate, |tx| {
-            KeystoreDB::rebind_alias(tx, newid, alias, &domain, &namespace, KeyType::Client).no_gc()
+    ) -> Result<Vec<Vec<String>>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_duplicate_imports", 500);
+
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keymetadata.data, keyentry.alias FROM persistent.keyentry
+                     INNER JOIN persistent.keymetadata
+                        ON keymetadata.keyentryid = keyentry.id
+                     WHERE keyentry.domain = ?
+                     AND keyentry.namespace = ?
+                     AND keyentry.alias IS NOT NULL
+                     AND keyentry.state = ?
+                     AND keyentry.key_type = ?
+                     AND keymetadata.tag = ?
+                     ORDER BY keymetadata.data, keyentry.alias ASC;",
+                )
+                .context("Failed to prepare statement.")?;
+            let mut rows = stmt
+                .query(params![
+                    domain.0 as u32,
+                    namespace,
+                    KeyLifeCycle::Live,
+                    KeyType::Client,
+                    KeyMetaData::ImportFingerprint,
+                ])
+                .context("Failed to query keys.")?;
+            let mut groups: Vec<(Vec<u8>, Vec<String>)> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let fingerprint: Vec<u8> = row.get(0).context("Failed to extract fingerprint.")?;
+                let alias: String = row.get(1).context("Failed to extract alias.")?;
+                match groups.last_mut() {
+                    Some((last, aliases)) if *last == fingerprint => aliases.push(alias),
+                    _ => groups.push((fingerprint, vec![alias])),
+                }
+                Ok(())
+            })
+            .context("Failed to extract rows.")?;
+            Ok(groups
+                .into_iter()
+                .filter_map(|(_, aliases)| (aliases.len() > 1).then_some(aliases))
+                .collect::<Vec<_>>())
+            .no_gc()
         })
         .context(ks_err!())
     }
 
-    #[test]
+    /// Returns the number of live keys that apps of `user_id` created in `Domain::APP` and the
+    /// total size of their blobs in bytes. Keys of system uids are not counted.
+    pub fn get_user_key_usage(&mut self, user_id: u32) -> Result<(u64, u64)> {
+        let _wp = wd::watch_millis("KeystoreDB::get_user_key_usage", 500);
+
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                &format!(
+                    "SELECT COUNT(DISTINCT k.id), COALESCE(SUM(LENGTH(b.blob)), 0)
+                     FROM persistent.keyentry k
+                     LEFT JOIN persistent.blobentry b ON b.keyentryid = k.id
+                     WHERE k.key_type = ? AND k.domain = ? AND k.state = ?
+                     AND cast ( (k.namespace/{aid_user_offset}) as int) = ?
+                     AND k.namespace % {aid_user_offset} >= {aid_app_start};",
+                    aid_user_offset = AID_USER_OFFSET,
+                    aid_app_start = AID_APP_START
+                ),
+                params![KeyType::Client, Domain::APP.0 as u32, KeyLifeCycle::Live, user_id],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+            )
+            .context("Failed to query the key usage.")
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Returns the ids of the live client keys that need the attention of a background
+    /// maintenance job, ordered by key id. A key is listed once for every reason that applies.
+    /// Keys with an OS patch level below `os_patch_level` require an upgrade. If
+    /// `os_patch_level` is None, upgrades are not considered.
+    pub fn list_keys_requiring_maintenance(
+        &mut self,
+        os_patch_level: Option<i32>,
+    ) -> Result<Vec<(i64, KeyMaintenanceReason)>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_keys_requiring_maintenance", 500);
+
+        self.with_transaction(
+            TransactionCategory::Maintenance,
+            TransactionBehavior::Deferred,
+            |tx| {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT id, ?1 FROM persistent.keyentry
+                     WHERE key_type = ?4 AND state = ?5 AND id IN (
+                         SELECT keyentryid FROM persistent.keyparameter
+                         WHERE tag = ?8 AND data < ?9
+                     )
+                     UNION
+                     SELECT id, ?2 FROM persistent.keyentry
+                     WHERE key_type = ?4 AND state = ?5 AND id IN (
+                         SELECT keyentryid FROM persistent.blobentry
+                         WHERE id IN (
+                             SELECT MAX(id) FROM persistent.blobentry
+                             WHERE subcomponent_type = ?6
+                             GROUP BY keyentryid
+                         )
+                         AND id IN (
+                             SELECT blobentryid FROM persistent.blobmetadata WHERE tag = ?7
+                         )
+                     )
+                     UNION
+                     SELECT id, ?3 FROM persistent.keyentry
+                     WHERE key_type = ?4 AND state = ?5 AND (
+                         id IN (
+                             SELECT keyentryid FROM persistent.keyparameter
+                             WHERE tag = ?10 AND data = ?11
+                         )
+                         OR (
+                             id IN (
+                                 SELECT keyentryid FROM persistent.keyparameter
+                                 WHERE tag = ?10 AND data = ?12
+                             )
+                             AND id IN (
+                                 SELECT keyentryid FROM persistent.keyparameter
+                                 WHERE tag = ?13 AND data < 2048
+                             )
+                         )
+                     )
+                     ORDER BY 1, 2;",
+                    )
+                    .context("Failed to prepare statement.")?;
+                let mut rows = stmt
+                    .query(params![
+                        KeyMaintenanceReason::RequiresUpgrade,
+                        KeyMaintenanceReason::RequiresRewrap,
+                        KeyMaintenanceReason::DeprecatedAlgorithm,
+                        KeyType::Client,
+                        KeyLifeCycle::Live,
+                        SubComponentType::KEY_BLOB,
+                        BlobMetaData::PublicKey,
+                        Tag::OS_PATCHLEVEL.0,
+                        os_patch_level,
+                        Tag::ALGORITHM.0,
+                        Algorithm::TRIPLE_DES.0,
+                        Algorithm::RSA.0,
+                        Tag::KEY_SIZE.0,
+                    ])
+                    .context("Failed to query keys requiring maintenance.")?;
+                let mut keys = Vec::new();
+                db_utils::with_rows_extract_all(&mut rows, |row| {
+                    keys.push((
+                        row.get(0).context("Failed to read key id.")?,
+                        row.get(1).context("Failed to read reason.")?,
+                    ));
+                    Ok(())
+                })
+                .context("Failed to extract rows.")?;
+                Ok(keys).no_gc()
+            },
+        )
+        .context(ks_err!())
+    }
+
+    fn mark_unreferenced(tx: &Transaction, key_id: i64, event: KeyHistoryEvent) -> Result<bool> {
+        Self::record_key_history(tx, event, "id = ?", params![key_id])
+            .context("Trying to record key history.")?;
+        let updated = tx
+            .execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id])
+            .context("Trying to delete keyentry.")?;
+        tx.execute("DELETE FROM persistent.keymetadata WHERE keyentryid = ?;", params![key_id])
+            .context("Trying to delete keymetadata.")?;
+        tx.execute("DELETE FROM persistent.keyparameter WHERE keyentryid = ?;", params![key_id])
+            .context("Trying to delete keyparameters.")?;
+        tx.execute("DELETE FROM persistent.grant WHERE keyentryid = ?;", params![key_id])
+            .context("Trying to delete grants.")?;
+        grant_cache::note_change();
+        Ok(updated != 0)
+    }
+
+    /// Deletes all client keys in `domain` and `namespace` whose alias starts with `prefix`.
+    /// Returns the number of deleted keys.
+    pub fn unbind_keys_with_alias_prefix(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        prefix: &str,
+    ) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_keys_with_alias_prefix", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let key_ids = {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT id FROM persistent.keyentry
+                         WHERE domain = ? AND namespace = ? AND key_type = ?
+                         AND substr(alias, 1, length(?)) = ?;",
+                    )
+                    .context("Failed to prepare statement.")?;
+                let rows = stmt
+                    .query_map(
+                        params![domain.0, namespace, KeyType::Client, prefix, prefix],
+                        |row| row.get::<_, i64>(0),
+                    )
+                    .context("Failed to query keys with prefix.")?;
+                rows.collect::<rusqlite::Result<Vec<i64>>>().context("Failed to read key ids.")?
+            };
+            for key_id in &key_ids {
+                Self::mark_unreferenced(tx, *key_id, KeyHistoryEvent::Deleted)
+                    .context("Trying to mark the key unreferenced.")?;
+            }
+            Ok((!key_ids.is_empty(), key_ids.len()))
+        })
+        .context(ks_err!())
+    }
+
+    /// Deletes all live client keys whose current key blob is encrypted with the super key
+    /// `super_key_id`. Returns the number of deleted keys.
+    pub fn unbind_keys_encrypted_by(&mut self, super_key_id: i64) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_keys_encrypted_by", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let key_ids = Self::select_key_ids(
+                tx,
+                "SELECT keyentryid FROM persistent.blobentry
+                 WHERE id IN (
+                     SELECT MAX(id) FROM persistent.blobentry
+                     WHERE subcomponent_type = ?
+                     GROUP BY keyentryid
+                 )
+                 AND keyentryid IN (
+                     SELECT id FROM persistent.keyentry WHERE key_type = ? AND state = ?
+                 )
+                 AND id IN (
+                     SELECT blobentryid FROM persistent.blobmetadata
+                     WHERE tag = ? AND data = ?
+                 );",
+                params![
+                    SubComponentType::KEY_BLOB,
+                    KeyType::Client,
+                    KeyLifeCycle::Live,
+                    BlobMetaData::EncryptedBy,
+                    super_key_id,
+                ],
+            )
+            .context("Failed to find the keys encrypted with the super key.")?;
+            for key_id in &key_ids {
+                Self::mark_unreferenced(tx, *key_id, KeyHistoryEvent::Deleted)
+                    .context("Trying to mark the key unreferenced.")?;
+            }
+            Ok((!key_ids.is_empty(), key_ids.len()))
+        })
+        .context(ks_err!())
+    }
+
+    /// Marks the given key as unreferenced and removes all of the grants to this key.
+    /// Returns Ok(true) if a key was marked unreferenced as a hint for the garbage collector.
+    pub fn unbind_key(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_key", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let (key_id, access_key_descriptor, access_vector) =
+                Self::load_access_tuple(tx, key, key_type, caller_uid)
+                    .context("Trying to get access tuple.")?;
+
+            // Perform access control. It is vital that we return here if the permission is denied.
+            // So do not touch that '?' at the end.
+            check_permission(&access_key_descriptor, access_vector)
+                .context("While checking permission.")?;
+            Self::check_alias_binding(tx, key, key_id, key_type)?;
+
+            Self::mark_unreferenced(tx, key_id, KeyHistoryEvent::Deleted)
+                .map(|need_gc| (need_gc, ()))
+                .context("Trying to mark the key unreferenced.")
+        })
+        .context(ks_err!())
+    }
+
+    fn get_key_km_uuid(tx: &Transaction, key_id: i64) -> Result<Uuid> {
+        tx.query_row(
+            "SELECT km_uuid FROM persistent.keyentry WHERE id = ?",
+            params![key_id],
+            |row| row.get(0),
+        )
+        .context(ks_err!())
+    }
+
+    /// Delete all artifacts belonging to the namespace given by the domain-namespace tuple.
+    /// This leaves all of the blob entries orphaned for subsequent garbage collection.
+    pub fn unbind_keys_for_namespace(&mut self, domain: Domain, namespace: i64) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_keys_for_namespace", 500);
+
+        if !(domain == Domain::APP || domain == Domain::SELINUX) {
+            return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!());
+        }
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            Self::record_key_history(
+                tx,
+                KeyHistoryEvent::Deleted,
+                "domain = ? AND namespace = ? AND key_type = ?",
+                params![domain.0, namespace, KeyType::Client],
+            )
+            .context("Trying to record key history.")?;
+            tx.execute(
+                "DELETE FROM persistent.keymetadata
+                WHERE keyentryid IN (
+                    SELECT id FROM persistent.keyentry
+                    WHERE domain = ? AND namespace = ? AND key_type = ?
+                );",
+                params![domain.0, namespace, KeyType::Client],
+            )
+            .context("Trying to delete keymetadata.")?;
+            tx.execute(
+                "DELETE FROM persistent.keyparameter
+                WHERE keyentryid IN (
+                    SELECT id FROM persistent.keyentry
+                    WHERE domain = ? AND namespace = ? AND key_type = ?
+                );",
+                params![domain.0, namespace, KeyType::Client],
+            )
+            .context("Trying to delete keyparameters.")?;
+            tx.execute(
+                "DELETE FROM persistent.grant
+                WHERE keyentryid IN (
+                    SELECT id FROM persistent.keyentry
+                    WHERE domain = ? AND namespace = ? AND key_type = ?
+                );",
+                params![domain.0, namespace, KeyType::Client],
+            )
+            .context("Trying to delete grants.")?;
+            grant_cache::note_change();
+            tx.execute(
+                "DELETE FROM persistent.keyentry
+                 WHERE domain = ? AND namespace = ? AND key_type = ?;",
+                params![domain.0, namespace, KeyType::Client],
+            )
+            .context("Trying to delete keyentry.")?;
+            Ok(()).need_gc()
+        })
+        .context(ks_err!())
+    }
+
+    fn cleanup_unreferenced(tx: &Transaction) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::cleanup_unreferenced", 500);
+        {
+            let mut rows_purged = tx
+                .execute(
+                    "DELETE FROM persistent.keymetadata
+            WHERE keyentryid IN (
+                SELECT id FROM persistent.keyentry
+                WHERE state = ?
+            );",
+                    params![KeyLifeCycle::Unreferenced],
+                )
+                .context("Trying to delete keymetadata.")?;
+            rows_purged += tx
+                .execute(
+                    "DELETE FROM persistent.keyparameter
+            WHERE keyentryid IN (
+                SELECT id FROM persistent.keyentry
+                WHERE state = ?
+            );",
+                    params![KeyLifeCycle::Unreferenced],
+                )
+                .context("Trying to delete keyparameters.")?;
+            rows_purged += tx
+                .execute(
+                    "DELETE FROM persistent.grant
+            WHERE keyentryid IN (
+                SELECT id FROM persistent.keyentry
+                WHERE state = ?
+            );",
+                    params![KeyLifeCycle::Unreferenced],
+                )
+                .context("Trying to delete grants.")?;
+            rows_purged += tx
+                .execute(
+                    "DELETE FROM persistent.keyentry
+                WHERE state = ?;",
+                    params![KeyLifeCycle::Unreferenced],
+                )
+                .context("Trying to delete keyentry.")?;
+            Result::<usize>::Ok(rows_purged)
+        }
+        .context(ks_err!())
+    }
+
+    /// Returns the key ids in the first column of the rows that `sql` selects with `params`.
+    fn select_key_ids(
+        tx: &Transaction,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<i64>> {
+        let mut stmt = tx.prepare(sql).context(ks_err!("Failed to prepare the query."))?;
+        let mut rows = stmt.query(params).context(ks_err!("Failed to query the keys."))?;
+        let mut key_ids: Vec<i64> = Vec::new();
+        db_utils::with_rows_extract_all(&mut rows, |row| {
+            key_ids.push(row.get(0).context("Failed to read key id.")?);
+            Ok(())
+        })
+        .context(ks_err!())?;
+        Ok(key_ids)
+    }
+
+    /// Delete the keys created on behalf of the user, denoted by the user id, that pass
+    /// `filter`. See `UserKeyFilter`.
+    pub fn unbind_keys_for_user(&mut self, user_id: u32, filter: UserKeyFilter) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_keys_for_user", 500);
+
+        self.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            let mut key_ids: Vec<i64> = Vec::new();
+            if filter.includes(KeyType::Client, Domain::APP) {
+                key_ids.extend(
+                    Self::select_key_ids(
+                        tx,
+                        &format!(
+                            "SELECT id FROM persistent.keyentry
+                             WHERE key_type = ? AND domain = ?
+                             AND cast ( (namespace/{aid_user_offset}) as int) = ? AND state = ?;",
+                            aid_user_offset = AID_USER_OFFSET
+                        ),
+                        params![KeyType::Client, Domain::APP.0 as u32, user_id, KeyLifeCycle::Live],
+                    )
+                    .context(ks_err!("Failed to find the keys created by apps."))?,
+                );
+            }
+            if filter.includes(KeyType::Super, Domain::APP) {
+                key_ids.extend(
+                    Self::select_key_ids(
+                        tx,
+                        "SELECT id FROM persistent.keyentry
+                         WHERE key_type = ? AND namespace = ? AND state = ?;",
+                        params![KeyType::Super, user_id, KeyLifeCycle::Live],
+                    )
+                    .context(ks_err!("Failed to find the super keys."))?,
+                );
+            }
+            if filter.includes(KeyType::Client, Domain::SELINUX) {
+                key_ids.extend(
+                    Self::select_key_ids(
+                        tx,
+                        "SELECT key.id FROM persistent.keyentry AS key
+                         JOIN persistent.blobentry AS blob ON blob.keyentryid = key.id
+                         JOIN persistent.blobmetadata AS metadata
+                             ON metadata.blobentryid = blob.id AND metadata.tag = ?
+                         JOIN persistent.keyentry AS superkey ON superkey.id = metadata.data
+                         WHERE blob.id IN (
+                             SELECT MAX(id) FROM persistent.blobentry
+                             WHERE subcomponent_type = ?
+                             GROUP BY keyentryid
+                         )
+                         AND key.key_type = ? AND key.domain = ? AND key.state = ?
+                         AND superkey.key_type = ? AND superkey.domain = ?
+                         AND superkey.namespace = ?;",
+                        params![
+                            BlobMetaData::EncryptedBy,
+                            SubComponentType::KEY_BLOB,
+                            KeyType::Client,
+                            Domain::SELINUX.0 as u32,
+                            KeyLifeCycle::Live,
+                            KeyType::Super,
+                            Domain::APP.0 as u32,
+                            user_id,
+                        ],
+                    )
+                    .context(ks_err!("Failed to find the super-encrypted SELinux keys."))?,
+                );
+            }
+
+            let mut notify_gc = false;
+            for key_id in key_ids {
+                if filter.keep_non_super_encrypted_keys {
+                    // Load metadata and filter out non-super-encrypted keys.
+                    if let (_, Some((_, blob_metadata)), _, _) =
+                        Self::load_blob_components(key_id, KeyEntryLoadBits::KM, tx)
+                            .context(ks_err!("Trying to load blob info."))?
+                    {
+                        if blob_metadata.encrypted_by().is_none() {
+                            continue;
+                        }
+                    }
+                }
+                let event = if filter.keep_non_super_encrypted_keys {
+                    KeyHistoryEvent::Invalidated
+                } else {
+                    KeyHistoryEvent::Deleted
+                };
+                notify_gc = Self::mark_unreferenced(tx, key_id, event)
+                    .context("In unbind_keys_for_user.")?
+                    || notify_gc;
+            }
+            Ok(()).do_gc(notify_gc)
+        })
+        .context(ks_err!())
+    }
+
+    fn load_key_components(
+        tx: &Transaction,
+        load_bits: KeyEntryLoadBits,
+        key_id: i64,
+    ) -> Result<KeyEntry> {
+        let metadata = KeyMetaData::load_from_db(key_id, tx).context("In load_key_components.")?;
+
+        let (has_km_blob, key_blob_info, cert_blob, cert_chain_blob) =
+            Self::load_blob_components(key_id, load_bits, tx).context("In load_key_components.")?;
+
+        let parameters = Self::load_key_parameters(key_id, tx)
+            .context("In load_key_components: Trying to load key parameters.")?;
+
+        let km_uuid = Self::get_key_km_uuid(tx, key_id)
+            .context("In load_key_components: Trying to get KM uuid.")?;
+
+        Ok(KeyEntry {
+            id: key_id,
+            key_blob_info,
+            cert: cert_blob,
+            cert_chain: cert_chain_blob,
+            km_uuid,
+            parameters,
+            metadata,
+            pure_cert: !has_km_blob,
+        })
+    }
+
+    /// Returns a list of KeyDescriptors in the selected domain/namespace whose
+    /// aliases are greater than the specified 'start_past_alias'. If no value
+    /// is provided, returns all KeyDescriptors.
+    /// The key descriptors will have the domain, nspace, and alias field set.
+    /// The returned list will be sorted by alias.
+    /// Domain must be APP or SELINUX, the caller must make sure of that.
+    pub fn list_past_alias(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        key_type: KeyType,
+        start_past_alias: Option<&str>,
+    ) -> Result<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_past_alias", 500);
+
+        let query = format!(
+            "SELECT DISTINCT alias FROM persistent.keyentry
+                     WHERE domain = ?
+                     AND namespace = ?
+                     AND alias IS NOT NULL
+                     AND state = ?
+                     AND key_type = ?
+                     {}
+                     ORDER BY alias ASC;",
+            if start_past_alias.is_some() { " AND alias > ?" } else { "" }
+        );
+
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx.prepare(&query).context(ks_err!("Failed to prepare."))?;
+
+            let mut rows = match start_past_alias {
+                Some(past_alias) => stmt
+                    .query(params![
+                        domain.0 as u32,
+                        namespace,
+                        KeyLifeCycle::Live,
+                        key_type,
+                        past_alias
+                    ])
+                    .context(ks_err!("Failed to query."))?,
+                None => stmt
+                    .query(params![domain.0 as u32, namespace, KeyLifeCycle::Live, key_type,])
+                    .context(ks_err!("Failed to query."))?,
+            };
+
+            let mut descriptors: Vec<KeyDescriptor> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                descriptors.push(KeyDescriptor {
+                    domain,
+                    nspace: namespace,
+                    alias: Some(row.get(0).context("Trying to extract alias.")?),
+                    blob: None,
+                });
+                Ok(())
+            })
+            .context(ks_err!("Failed to extract rows."))?;
+            Ok(descriptors).no_gc()
+        })
+    }
+
+    /// Returns a number of KeyDescriptors in the selected domain/namespace.
+    /// Domain must be APP or SELINUX, the caller must make sure of that.
+    pub fn count_keys(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        key_type: KeyType,
+    ) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::countKeys", 500);
+
+        let num_keys = self.with_transaction(
+            TransactionCategory::KeyRead,
+            TransactionBehavior::Deferred,
+            |tx| {
+                tx.query_row(
+                    "SELECT COUNT(alias) FROM persistent.keyentry
+                     WHERE domain = ?
+                     AND namespace = ?
+                     AND alias IS NOT NULL
+                     AND state = ?
+                     AND key_type = ?;",
+                    params![domain.0 as u32, namespace, KeyLifeCycle::Live, key_type],
+                    |row| row.get(0),
+                )
+                .context(ks_err!("Failed to count number of keys."))
+                .no_gc()
+            },
+        )?;
+        Ok(num_keys)
+    }
+
+    /// Adds a grant to the grant table.
+    /// Like `load_key_entry` this function loads the access tuple before
+    /// it uses the callback for a permission check. Upon success,
+    /// it inserts the `grantee_uid`, `key_id`, and `access_vector` into the
+    /// grant table. The new row will have a randomized id, which is used as
+    /// grant id in the namespace field of the resulting KeyDescriptor.
+    pub fn grant(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        grantee_uid: u32,
+        access_vector: KeyPermSet,
+        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
+    ) -> Result<KeyDescriptor> {
+        self.grant_with_constraints(
+            key,
+            caller_uid,
+            grantee_uid,
+            access_vector,
+            &GrantConstraints::default(),
+            check_permission,
+        )
+    }
+
+    /// Like `grant` but also stores `constraints` with the grant. Granting a key again replaces
+    /// the access vector as well as the constraints of the existing grant.
+    pub fn grant_with_constraints(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        grantee_uid: u32,
+        access_vector: KeyPermSet,
+        constraints: &GrantConstraints,
+        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
+    ) -> Result<KeyDescriptor> {
+        let _wp = wd::watch_millis("KeystoreDB::grant_with_constraints", 500);
+
+        self.with_transaction(TransactionCategory::Grant, TransactionBehavior::Immediate, |tx| {
+            // Load the key_id and complete the access control tuple.
+            // We ignore the access vector here because grants cannot be granted.
+            // The access vector returned here expresses the permissions the
+            // grantee has if key.domain == Domain::GRANT. But this vector
+            // cannot include the grant permission by design, so there is no way the
+            // subsequent permission check can pass.
+            // We could check key.domain == Domain::GRANT and fail early.
+            // But even if we load the access tuple by grant here, the permission
+            // check denies the attempt to create a grant by grant descriptor.
+            let (key_id, access_key_descriptor, _) =
+                Self::load_access_tuple(tx, key, KeyType::Client, caller_uid).context(ks_err!())?;
+
+            // Perform access control. It is vital that we return here if the permission
+            // was denied. So do not touch that '?' at the end of the line.
+            // This permission check checks if the caller has the grant permission
+            // for the given key and in addition to all of the permissions
+            // expressed in `access_vector`.
+            check_permission(&access_key_descriptor, &access_vector)
+                .context(ks_err!("check_permission failed"))?;
+            Self::check_alias_binding(tx, key, key_id, KeyType::Client).context(ks_err!())?;
+
+            let grant_id = if let Some(grant_id) = tx
+                .query_row(
+                    "SELECT id FROM persistent.grant
+                WHERE keyentryid = ? AND grantee = ?;",
+                    params![key_id, grantee_uid],
+                    |row| row.get(0),
+                )
+                .optional()
+                .context(ks_err!("Failed get optional existing grant id."))?
+            {
+                tx.execute(
+                    "UPDATE persistent.grant
+                    SET access_vector = ?, auth_sids = ?, purposes = ?
+                    WHERE id = ?;",
+                    params![
+                        i32::from(access_vector),
+                        constraints.auth_sids_to_sql(),
+                        constraints.purposes_to_sql(),
+                        grant_id
+                    ],
+                )
+                .context(ks_err!("Failed to update existing grant."))?;
+                grant_cache::note_change();
+                grant_id
+            } else {
+                Self::insert_with_retry(|id| {
+                    tx.execute(
+                        "INSERT INTO persistent.grant
+                            (id, grantee, keyentryid, access_vector, auth_sids, purposes)
+                        VALUES (?, ?, ?, ?, ?, ?);",
+                        params![
+                            id,
+                            grantee_uid,
+                            key_id,
+                            i32::from(access_vector),
+                            constraints.auth_sids_to_sql(),
+                            constraints.purposes_to_sql()
+                        ],
+                    )
+                })
+                .context(ks_err!())?
+            };
+
+            Ok(KeyDescriptor { domain: Domain::GRANT, nspace: grant_id, alias: None, blob: None })
+                .no_gc()
+        })
+    }
+
+    /// Loads the constraints of the grant of the key `key_id` to `grantee_uid`. Callers use
+    /// this after they accessed the key through the grant, so a missing grant, e.g., because it
+    /// was revoked in the meantime, is reported as KEY_NOT_FOUND.
+    pub fn load_grant_constraints(
+        &mut self,
+        grantee_uid: u32,
+        key_id: i64,
+    ) -> Result<GrantConstraints> {
+        let _wp = wd::watch_millis("KeystoreDB::load_grant_constraints", 500);
+
+        self.with_transaction(TransactionCategory::Grant, TransactionBehavior::Deferred, |tx| {
+            let (auth_sids, purposes): (Option<Vec<u8>>, Option<Vec<u8>>) = tx
+                .query_row(
+                    "SELECT auth_sids, purposes FROM persistent.grant
+                    WHERE keyentryid = ? AND grantee = ?;",
+                    params![key_id, grantee_uid],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .context(ks_err!("Failed to query grant."))?
+                .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context(ks_err!("No grant of key {key_id} to {grantee_uid}."))?;
+            Ok(GrantConstraints {
+                auth_sids: GrantConstraints::auth_sids_from_sql(auth_sids)?,
+                purposes: GrantConstraints::purposes_from_sql(purposes)?,
+            })
+            .no_gc()
+        })
+    }
+
+    /// This function checks permissions like `grant` and `load_key_entry`
+    /// before removing a grant from the grant table.
+    pub fn ungrant(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        grantee_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::ungrant", 500);
+
+        self.with_transaction(TransactionCategory::Grant, TransactionBehavior::Immediate, |tx| {
+            // Load the key_id and complete the access control tuple.
+            // We ignore the access vector here because grants cannot be granted.
+            let (key_id, access_key_descriptor, _) =
+                Self::load_access_tuple(tx, key, KeyType::Client, caller_uid).context(ks_err!())?;
+
+            // Perform access control. We must return here if the permission
+            // was denied. So do not touch the '?' at the end of this line.
+            check_permission(&access_key_descriptor)
+                .context(ks_err!("check_permission failed."))?;
+            Self::check_alias_binding(tx, key, key_id, KeyType::Client).context(ks_err!())?;
+
+            tx.execute(
+                "DELETE FROM persistent.grant
+                WHERE keyentryid = ? AND grantee = ?;",
+                params![key_id, grantee_uid],
+            )
+            .context("Failed to delete grant.")?;
+            grant_cache::note_change();
+
+            Ok(()).no_gc()
+        })
+    }
+
+    // Generates a random id and passes it to the given function, which will
+    // try to insert it into a database.  If that insertion fails, retry;
+    // otherwise return the id.
+    fn insert_with_retry(inserter: impl Fn(i64) -> rusqlite::Result<usize>) -> Result<i64> {
+        loop {
+            let newid: i64 = match random() {
+                Self::UNASSIGNED_KEY_ID => continue, // UNASSIGNED_KEY_ID cannot be assigned.
+                i => i,
+            };
+            match inserter(newid) {
+                // If the id already existed, try again.
+                Err(rusqlite::Error::SqliteFailure(
+                    libsqlite3_sys::Error {
+                        code: libsqlite3_sys::ErrorCode::ConstraintViolation,
+                        extended_code: libsqlite3_sys::SQLITE_CONSTRAINT_UNIQUE,
+                    },
+                    _,
+                )) => (),
+                Err(e) => {
+                    return Err(e).context(ks_err!("failed to insert into database."));
+                }
+                _ => return Ok(newid),
+            }
+        }
+    }
+
+    /// Insert or replace the auth token based on (user_id, auth_id, auth_type)
+    pub fn insert_auth_token(&mut self, auth_token: &HardwareAuthToken) {
+        self.perboot.insert_auth_token_entry(AuthTokenEntry::new(
+            auth_token.clone(),
+            MonotonicRawTime::now(),
+        ))
+    }
+
+    /// Find the newest auth token matching the given predicate.
+    pub fn find_auth_token_entry<F>(&self, p: F) -> Option<(AuthTokenEntry, MonotonicRawTime)>
+    where
+        F: Fn(&AuthTokenEntry) -> bool,
+    {
+        self.perboot.find_auth_token_entry(p).map(|entry| (entry, self.get_last_off_body()))
+    }
+
+    /// Insert last_off_body into the metadata table at the initialization of auth token table
+    pub fn insert_last_off_body(&self, last_off_body: MonotonicRawTime) {
+        self.perboot.set_last_off_body(last_off_body)
+    }
+
+    /// Update last_off_body when on_device_off_body is called
+    pub fn update_last_off_body(&self, last_off_body: MonotonicRawTime) {
+        self.perboot.set_last_off_body(last_off_body)
+    }
+
+    /// Get last_off_body time when finding auth tokens
+    fn get_last_off_body(&self) -> MonotonicRawTime {
+        self.perboot.get_last_off_body()
+    }
+
+    /// Load descriptor of a key by key id
+    pub fn load_key_descriptor(&mut self, key_id: i64) -> Result<Option<KeyDescriptor>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_descriptor", 500);
+
+        self.with_transaction(TransactionCategory::KeyRead, TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT domain, namespace, alias FROM persistent.keyentry WHERE id = ?;",
+                params![key_id],
+                |row| {
+                    Ok(KeyDescriptor {
+                        domain: Domain(row.get(0)?),
+                        nspace: row.get(1)?,
+                        alias: row.get(2)?,
+                        blob: None,
+                    })
+                },
+            )
+            .optional()
+            .context("Trying to load key descriptor")
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::key_parameter::{
+        Algorithm, BlockMode, Digest, EcCurve, HardwareAuthenticatorType, KeyOrigin, KeyParameter,
+        KeyParameterValue, KeyPurpose, PaddingMode, SecurityLevel,
+    };
+    use crate::key_perm_set;
+    use crate::permission::{KeyPerm, KeyPermSet};
+    use crate::super_key::{SuperKeyManager, USER_AFTER_FIRST_UNLOCK_SUPER_KEY, USER_UNLOCKED_DEVICE_REQUIRED_SYMMETRIC_SUPER_KEY, SuperEncryptionAlgorithm, SuperKeyType};
+    use keystore2_test_utils::TempDir;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        HardwareAuthToken::HardwareAuthToken,
+        HardwareAuthenticatorType::HardwareAuthenticatorType as kmhw_authenticator_type,
+    };
+    use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::{
+        Timestamp::Timestamp,
+    };
+    use rusqlite::TransactionBehavior;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::fmt::Write;
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+    use crate::utils::AesGcm;
+    #[cfg(disabled)]
+    use std::time::Instant;
+
+    pub fn new_test_db() -> Result<KeystoreDB> {
+        let conn = KeystoreDB::make_connection("file::memory:")?;
+
+        let mut db = KeystoreDB {
+            conn,
+            gc: None,
+            perboot: Arc::new(perboot::PerbootDB::new()),
+            shrink_generation: SHRINK_GENERATION.load(Ordering::Relaxed),
+        };
+        db.with_transaction(TransactionCategory::Init, TransactionBehavior::Immediate, |tx| {
+            KeystoreDB::init_tables(tx).context("Failed to initialize tables.").no_gc()
+        })?;
+        Ok(db)
+    }
+
+    fn rebind_alias(
+        db: &mut KeystoreDB,
+        newid: &KeyIdGuard,
+        alias: &str,
+        domain: Domain,
+        namespace: i64,
+    ) -> Result<bool> {
+        db.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            KeystoreDB::rebind_alias(tx, newid, alias, &domain, &namespace, KeyType::Client).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    #[test]
     fn datetime() -> Result<()> {
         let conn = Connection::open_in_memory()?;
         conn.execute("CREATE TABLE test (ts DATETIME);", [])?;
@@ -2911,976 +5414,2386 @@ pub mod tests {
             "INSERT INTO test (ts) VALUES (?), (?), (?);",
             params![DateTime::try_from(now)?, DateTime::try_from(then)?, DateTime::try_from(soon)?],
         )?;
-        let mut stmt = conn.prepare("SELECT ts FROM test ORDER BY ts ASC;")?;
-        let mut rows = stmt.query([])?;
-        assert_eq!(DateTime::try_from(then)?, rows.next()?.unwrap().get(0)?);
-        assert_eq!(DateTime::try_from(now)?, rows.next()?.unwrap().get(0)?);
-        assert_eq!(DateTime::try_from(soon)?, rows.next()?.unwrap().get(0)?);
-        assert!(rows.next()?.is_none());
-        assert!(DateTime::try_from(then)? < DateTime::try_from(now)?);
-        assert!(DateTime::try_from(then)? < DateTime::try_from(soon)?);
-        assert!(DateTime::try_from(now)? < DateTime::try_from(soon)?);
+        let mut stmt = conn.prepare("SELECT ts FROM test ORDER BY ts ASC;")?;
+        let mut rows = stmt.query([])?;
+        assert_eq!(DateTime::try_from(then)?, rows.next()?.unwrap().get(0)?);
+        assert_eq!(DateTime::try_from(now)?, rows.next()?.unwrap().get(0)?);
+        assert_eq!(DateTime::try_from(soon)?, rows.next()?.unwrap().get(0)?);
+        assert!(rows.next()?.is_none());
+        assert!(DateTime::try_from(then)? < DateTime::try_from(now)?);
+        assert!(DateTime::try_from(then)? < DateTime::try_from(soon)?);
+        assert!(DateTime::try_from(now)? < DateTime::try_from(soon)?);
+        Ok(())
+    }
+
+    // Ensure that we're using the "injected" random function, not the real one.
+    #[test]
+    fn test_mocked_random() {
+        let rand1 = random();
+        let rand2 = random();
+        let rand3 = random();
+        if rand1 == rand2 {
+            assert_eq!(rand2 + 1, rand3);
+        } else {
+            assert_eq!(rand1 + 1, rand2);
+            assert_eq!(rand2, rand3);
+        }
+    }
+
+    // Test that we have the correct tables.
+    #[test]
+    fn test_tables() -> Result<()> {
+        let db = new_test_db()?;
+        let tables = db
+            .conn
+            .prepare("SELECT name from persistent.sqlite_master WHERE type='table' ORDER BY name;")?
+            .query_map(params![], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        assert_eq!(tables.len(), 15);
+        assert_eq!(tables[0], "blobdeletion");
+        assert_eq!(tables[1], "blobentry");
+        assert_eq!(tables[2], "blobmetadata");
+        assert_eq!(tables[3], "blobshadow");
+        assert_eq!(tables[4], "credential");
+        assert_eq!(tables[5], "databasebinding");
+        assert_eq!(tables[6], "fingerprintkey");
+        assert_eq!(tables[7], "grant");
+        assert_eq!(tables[8], "keyentry");
+        assert_eq!(tables[9], "keyhistory");
+        assert_eq!(tables[10], "keymetadata");
+        assert_eq!(tables[11], "keyparameter");
+        assert_eq!(tables[12], "keyvaliditypolicy");
+        assert_eq!(tables[13], "patchlevelpolicy");
+        assert_eq!(tables[14], "storagekey");
+        Ok(())
+    }
+
+    #[test]
+    fn test_auth_token_table_invariant() -> Result<()> {
+        let mut db = new_test_db()?;
+        let auth_token1 = HardwareAuthToken {
+            challenge: i64::MAX,
+            userId: 200,
+            authenticatorId: 200,
+            authenticatorType: kmhw_authenticator_type(kmhw_authenticator_type::PASSWORD.0),
+            timestamp: Timestamp { milliSeconds: 500 },
+            mac: String::from("mac").into_bytes(),
+        };
+        db.insert_auth_token(&auth_token1);
+        let auth_tokens_returned = get_auth_tokens(&db);
+        assert_eq!(auth_tokens_returned.len(), 1);
+
+        // insert another auth token with the same values for the columns in the UNIQUE constraint
+        // of the auth token table and different value for timestamp
+        let auth_token2 = HardwareAuthToken {
+            challenge: i64::MAX,
+            userId: 200,
+            authenticatorId: 200,
+            authenticatorType: kmhw_authenticator_type(kmhw_authenticator_type::PASSWORD.0),
+            timestamp: Timestamp { milliSeconds: 600 },
+            mac: String::from("mac").into_bytes(),
+        };
+
+        db.insert_auth_token(&auth_token2);
+        let mut auth_tokens_returned = get_auth_tokens(&db);
+        assert_eq!(auth_tokens_returned.len(), 1);
+
+        if let Some(auth_token) = auth_tokens_returned.pop() {
+            assert_eq!(auth_token.auth_token.timestamp.milliSeconds, 600);
+        }
+
+        // insert another auth token with the different values for the columns in the UNIQUE
+        // constraint of the auth token table
+        let auth_token3 = HardwareAuthToken {
+            challenge: i64::MAX,
+            userId: 201,
+            authenticatorId: 200,
+            authenticatorType: kmhw_authenticator_type(kmhw_authenticator_type::PASSWORD.0),
+            timestamp: Timestamp { milliSeconds: 600 },
+            mac: String::from("mac").into_bytes(),
+        };
+
+        db.insert_auth_token(&auth_token3);
+        let auth_tokens_returned = get_auth_tokens(&db);
+        assert_eq!(auth_tokens_returned.len(), 2);
+
+        Ok(())
+    }
+
+    // utility function for test_auth_token_table_invariant()
+    fn get_auth_tokens(db: &KeystoreDB) -> Vec<AuthTokenEntry> {
+        db.perboot.get_all_auth_token_entries()
+    }
+
+    #[test]
+    fn test_persistence_for_files() -> Result<()> {
+        let temp_dir = TempDir::new("persistent_db_test")?;
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+
+        db.create_key_entry(&Domain::APP, &100, KeyType::Client, &KEYSTORE_UUID)?;
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 1);
+
+        let db = KeystoreDB::new(temp_dir.path(), None)?;
+
+        let entries_new = get_keyentry(&db)?;
+        assert_eq!(entries, entries_new);
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_key_entry() -> Result<()> {
+        fn extractor(ke: &KeyEntryRow) -> (Domain, i64, Option<&str>, Uuid) {
+            (ke.domain.unwrap(), ke.namespace.unwrap(), ke.alias.as_deref(), ke.km_uuid.unwrap())
+        }
+
+        let mut db = new_test_db()?;
+
+        db.create_key_entry(&Domain::APP, &100, KeyType::Client, &KEYSTORE_UUID)?;
+        db.create_key_entry(&Domain::SELINUX, &101, KeyType::Client, &KEYSTORE_UUID)?;
+
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(extractor(&entries[0]), (Domain::APP, 100, None, KEYSTORE_UUID));
+        assert_eq!(extractor(&entries[1]), (Domain::SELINUX, 101, None, KEYSTORE_UUID));
+
+        // Test that we must pass in a valid Domain.
+        check_result_is_error_containing_string(
+            db.create_key_entry(&Domain::GRANT, &102, KeyType::Client, &KEYSTORE_UUID),
+            &format!("Domain {:?} must be either App or SELinux.", Domain::GRANT),
+        );
+        check_result_is_error_containing_string(
+            db.create_key_entry(&Domain::BLOB, &103, KeyType::Client, &KEYSTORE_UUID),
+            &format!("Domain {:?} must be either App or SELinux.", Domain::BLOB),
+        );
+        check_result_is_error_containing_string(
+            db.create_key_entry(&Domain::KEY_ID, &104, KeyType::Client, &KEYSTORE_UUID),
+            &format!("Domain {:?} must be either App or SELinux.", Domain::KEY_ID),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebind_alias() -> Result<()> {
+        fn extractor(
+            ke: &KeyEntryRow,
+        ) -> (Option<Domain>, Option<i64>, Option<&str>, Option<Uuid>) {
+            (ke.domain, ke.namespace, ke.alias.as_deref(), ke.km_uuid)
+        }
+
+        let mut db = new_test_db()?;
+        db.create_key_entry(&Domain::APP, &42, KeyType::Client, &KEYSTORE_UUID)?;
+        db.create_key_entry(&Domain::APP, &42, KeyType::Client, &KEYSTORE_UUID)?;
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            extractor(&entries[0]),
+            (Some(Domain::APP), Some(42), None, Some(KEYSTORE_UUID))
+        );
+        assert_eq!(
+            extractor(&entries[1]),
+            (Some(Domain::APP), Some(42), None, Some(KEYSTORE_UUID))
+        );
+
+        // Test that the first call to rebind_alias sets the alias.
+        rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[0].id), "foo", Domain::APP, 42)?;
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            extractor(&entries[0]),
+            (Some(Domain::APP), Some(42), Some("foo"), Some(KEYSTORE_UUID))
+        );
+        assert_eq!(
+            extractor(&entries[1]),
+            (Some(Domain::APP), Some(42), None, Some(KEYSTORE_UUID))
+        );
+
+        // Test that the second call to rebind_alias also empties the old one.
+        rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[1].id), "foo", Domain::APP, 42)?;
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(extractor(&entries[0]), (None, None, None, Some(KEYSTORE_UUID)));
+        assert_eq!(
+            extractor(&entries[1]),
+            (Some(Domain::APP), Some(42), Some("foo"), Some(KEYSTORE_UUID))
+        );
+
+        // Test that we must pass in a valid Domain.
+        check_result_is_error_containing_string(
+            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::GRANT, 42),
+            &format!("Domain {:?} must be either App or SELinux.", Domain::GRANT),
+        );
+        check_result_is_error_containing_string(
+            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::BLOB, 42),
+            &format!("Domain {:?} must be either App or SELinux.", Domain::BLOB),
+        );
+        check_result_is_error_containing_string(
+            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::KEY_ID, 42),
+            &format!("Domain {:?} must be either App or SELinux.", Domain::KEY_ID),
+        );
+
+        // Test that we correctly handle setting an alias for something that does not exist.
+        check_result_is_error_containing_string(
+            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::SELINUX, 42),
+            "Expected to update a single entry but instead updated 0",
+        );
+        // Test that we correctly abort the transaction in this case.
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(extractor(&entries[0]), (None, None, None, Some(KEYSTORE_UUID)));
+        assert_eq!(
+            extractor(&entries[1]),
+            (Some(Domain::APP), Some(42), Some("foo"), Some(KEYSTORE_UUID))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_grant_ungrant() -> Result<()> {
+        const CALLER_UID: u32 = 15;
+        const GRANTEE_UID: u32 = 12;
+        const SELINUX_NAMESPACE: i64 = 7;
+
+        let mut db = new_test_db()?;
+        db.conn.execute(
+            "INSERT INTO persistent.keyentry (id, key_type, domain, namespace, alias, state, km_uuid)
+                VALUES (1, 0, 0, 15, 'key', 1, ?), (2, 0, 2, 7, 'yek', 1, ?);",
+            params![KEYSTORE_UUID, KEYSTORE_UUID],
+        )?;
+        let app_key = KeyDescriptor {
+            domain: super::Domain::APP,
+            nspace: 0,
+            alias: Some("key".to_string()),
+            blob: None,
+        };
+        const PVEC1: KeyPermSet = key_perm_set![KeyPerm::Use, KeyPerm::GetInfo];
+        const PVEC2: KeyPermSet = key_perm_set![KeyPerm::Use];
+
+        // Reset totally predictable random number generator in case we
+        // are not the first test running on this thread.
+        reset_random();
+        let next_random = 0i64;
+
+        let app_granted_key = db
+            .grant(&app_key, CALLER_UID, GRANTEE_UID, PVEC1, |k, a| {
+                assert_eq!(*a, PVEC1);
+                assert_eq!(
+                    *k,
+                    KeyDescriptor {
+                        domain: super::Domain::APP,
+                        // namespace must be set to the caller_uid.
+                        nspace: CALLER_UID as i64,
+                        alias: Some("key".to_string()),
+                        blob: None,
+                    }
+                );
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            app_granted_key,
+            KeyDescriptor {
+                domain: super::Domain::GRANT,
+                // The grantid is next_random due to the mock random number generator.
+                nspace: next_random,
+                alias: None,
+                blob: None,
+            }
+        );
+
+        let selinux_key = KeyDescriptor {
+            domain: super::Domain::SELINUX,
+            nspace: SELINUX_NAMESPACE,
+            alias: Some("yek".to_string()),
+            blob: None,
+        };
+
+        let selinux_granted_key = db
+            .grant(&selinux_key, CALLER_UID, 12, PVEC1, |k, a| {
+                assert_eq!(*a, PVEC1);
+                assert_eq!(
+                    *k,
+                    KeyDescriptor {
+                        domain: super::Domain::SELINUX,
+                        // namespace must be the supplied SELinux
+                        // namespace.
+                        nspace: SELINUX_NAMESPACE,
+                        alias: Some("yek".to_string()),
+                        blob: None,
+                    }
+                );
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            selinux_granted_key,
+            KeyDescriptor {
+                domain: super::Domain::GRANT,
+                // The grantid is next_random + 1 due to the mock random number generator.
+                nspace: next_random + 1,
+                alias: None,
+                blob: None,
+            }
+        );
+
+        // This should update the existing grant with PVEC2.
+        let selinux_granted_key = db
+            .grant(&selinux_key, CALLER_UID, 12, PVEC2, |k, a| {
+                assert_eq!(*a, PVEC2);
+                assert_eq!(
+                    *k,
+                    KeyDescriptor {
+                        domain: super::Domain::SELINUX,
+                        // namespace must be the supplied SELinux
+                        // namespace.
+                        nspace: SELINUX_NAMESPACE,
+                        alias: Some("yek".to_string()),
+                        blob: None,
+                    }
+                );
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            selinux_granted_key,
+            KeyDescriptor {
+                domain: super::Domain::GRANT,
+                // Same grant id as before. The entry was only updated.
+                nspace: next_random + 1,
+                alias: None,
+                blob: None,
+            }
+        );
+
+        {
+            // Limiting scope of stmt, because it borrows db.
+            let mut stmt = db
+                .conn
+                .prepare("SELECT id, grantee, keyentryid, access_vector FROM persistent.grant;")?;
+            let mut rows = stmt.query_map::<(i64, u32, i64, KeyPermSet), _, _>([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, KeyPermSet::from(row.get::<_, i32>(3)?)))
+            })?;
+
+            let r = rows.next().unwrap().unwrap();
+            assert_eq!(r, (next_random, GRANTEE_UID, 1, PVEC1));
+            let r = rows.next().unwrap().unwrap();
+            assert_eq!(r, (next_random + 1, GRANTEE_UID, 2, PVEC2));
+            assert!(rows.next().is_none());
+        }
+
+        debug_dump_keyentry_table(&mut db)?;
+        println!("app_key {:?}", app_key);
+        println!("selinux_key {:?}", selinux_key);
+
+        db.ungrant(&app_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
+        db.ungrant(&selinux_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
+
+        Ok(())
+    }
+
+    static TEST_KEY_BLOB: &[u8] = b"my test blob";
+    static TEST_CERT_BLOB: &[u8] = b"my test cert";
+    static TEST_CERT_CHAIN_BLOB: &[u8] = b"my test cert_chain";
+
+    #[test]
+    fn test_set_blob() -> Result<()> {
+        let key_id = KEY_ID_LOCK.get(3000);
+        let mut db = new_test_db()?;
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        db.set_blob(
+            &key_id,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(TEST_CERT_CHAIN_BLOB), None)?;
+        drop(key_id);
+
+        let mut stmt = db.conn.prepare(
+            "SELECT subcomponent_type, keyentryid, blob, id FROM persistent.blobentry
+                ORDER BY subcomponent_type ASC;",
+        )?;
+        let mut rows = stmt
+            .query_map::<((SubComponentType, i64, Vec<u8>), i64), _, _>([], |row| {
+                Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?))
+            })?;
+        let (r, id) = rows.next().unwrap().unwrap();
+        assert_eq!(r, (SubComponentType::KEY_BLOB, 3000, TEST_KEY_BLOB.to_vec()));
+        let (r, _) = rows.next().unwrap().unwrap();
+        assert_eq!(r, (SubComponentType::CERT, 3000, TEST_CERT_BLOB.to_vec()));
+        let (r, _) = rows.next().unwrap().unwrap();
+        assert_eq!(r, (SubComponentType::CERT_CHAIN, 3000, TEST_CERT_CHAIN_BLOB.to_vec()));
+
+        drop(rows);
+        drop(stmt);
+
+        assert_eq!(
+            db.with_transaction(
+                TransactionCategory::KeyWrite,
+                TransactionBehavior::Immediate,
+                |tx| { BlobMetaData::load_from_db(id, tx).no_gc() }
+            )
+            .expect("Should find blob metadata."),
+            blob_metadata
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_next_superseded_blobs_reports_purged_rows() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        // Supersede the key blob and the certificate.
+        db.set_blob(
+            &key_id,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
+        drop(key_id);
+
+        // The superseded key blob is handed out for invalidation, nothing is purged yet.
+        let (blobs, rows_purged) = db.handle_next_superseded_blobs(&[], 20)?;
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(rows_purged, 0);
+        let (blob_id, _, superseded_metadata) = &blobs[0];
+        assert_eq!(superseded_metadata.km_uuid(), Some(&KEYSTORE_UUID));
+        assert_ne!(superseded_metadata, &blob_metadata);
+
+        // Deleting it purges its blob entry and its five metadata entries as well as the
+        // superseded certificate.
+        let (blobs, rows_purged) = db.handle_next_superseded_blobs(&[*blob_id], 20)?;
+        assert!(blobs.is_empty());
+        assert_eq!(rows_purged, 7);
+
+        let (blobs, rows_purged) = db.handle_next_superseded_blobs(&[], 20)?;
+        assert!(blobs.is_empty());
+        assert_eq!(rows_purged, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_resistant_blob_deletion_needs_confirmation() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        db.insert_keyparameter(
+            &key_id,
+            &[KeyParameter::new(
+                KeyParameterValue::RollbackResistance,
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            )],
+        )?;
+        drop(key_id);
+        make_test_key_entry(&mut db, Domain::APP, 2, TEST_ALIAS, None)?;
+        for uid in [1, 2] {
+            let key = KeyDescriptor {
+                domain: Domain::APP,
+                nspace: uid,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            };
+            db.unbind_key(&key, KeyType::Client, uid as u32, |_, _| Ok(()))?;
+        }
+
+        let (blobs, _) = db.handle_next_superseded_blobs(&[], 20)?;
+        let blob_ids: Vec<i64> = blobs.iter().map(|(id, _, _)| *id).collect();
+        assert_eq!(2, blob_ids.len());
+        // Both deletions fail. Only the rollback resistant blob is kept.
+        let mut kept = vec![];
+        let mut deleted = vec![];
+        for blob_id in blob_ids {
+            if db.postpone_blob_deletion(blob_id)? {
+                kept.push(blob_id);
+            } else {
+                deleted.push(blob_id);
+            }
+        }
+        assert_eq!((1, 1), (kept.len(), deleted.len()));
+        let (kept, deleted) = (kept[0], deleted[0]);
+        assert_eq!(vec![(kept, 1)], db.get_pending_blob_deletions()?);
+
+        // The kept blob is not handed out again before the retry delay has passed.
+        let (blobs, _) = db.handle_next_superseded_blobs(&[deleted], 20)?;
+        assert!(blobs.is_empty());
+        assert_eq!(vec![(kept, 1)], db.get_pending_blob_deletions()?);
+
+        // After a restart, the kept blob is handed out again right away.
+        db.reset_blob_deletion_delays()?;
+        let (blobs, _) = db.handle_next_superseded_blobs(&[], 20)?;
+        assert_eq!(vec![kept], blobs.iter().map(|(id, _, _)| *id).collect::<Vec<_>>());
+        assert_eq!(vec![(kept, 1)], db.get_pending_blob_deletions()?);
+
+        // Once the deletion is confirmed, the record is dropped with the blob.
+        db.handle_next_superseded_blobs(&[kept], 20)?;
+        assert!(db.get_pending_blob_deletions()?.is_empty());
+        assert!(!db.postpone_blob_deletion(kept)?);
+        Ok(())
+    }
+
+    static TEST_ALIAS: &str = "my super duper key";
+
+    #[test]
+    fn test_load_key_id() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.0;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+
+        assert_eq!(
+            (key_id, KEYSTORE_UUID),
+            db.load_key_id(&key, KeyType::Client, 1, |k, av| {
+                assert_eq!(Domain::APP, k.domain);
+                assert_eq!(1, k.nspace);
+                assert!(av.is_none());
+                Ok(())
+            })?
+        );
+
+        // The key is resolved by key id just the same.
+        let by_key_id =
+            KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None };
+        assert_eq!(key_id, db.load_key_id(&by_key_id, KeyType::Client, 1, |_k, _av| Ok(()))?.0);
+
+        assert_eq!(
+            Some(&KsError::perm()),
+            db.load_key_id(&key, KeyType::Client, 1, |_k, _av| Err(anyhow!(KsError::perm())))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_id(&key, KeyType::Client, 2, |_k, _av| Ok(()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_and_load_full_keyentry_domain_app() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)
+            .context("test_insert_and_load_full_keyentry_domain_app")?
+            .0;
+        let (_key_guard, key_entry) = db
+            .load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 0,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                1,
+                |_k, _av| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+
+        db.unbind_key(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            1,
+            |_, _| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 0,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                1,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_and_load_certificate_entry_domain_app() -> Result<()> {
+        let mut db = new_test_db()?;
+
+        db.store_new_certificate(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 1,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            TEST_CERT_BLOB,
+            &KEYSTORE_UUID,
+        )
+        .expect("Trying to insert cert.");
+
+        let (_key_guard, mut key_entry) = db
+            .load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 1,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::PUBLIC,
+                1,
+                |_k, _av| Ok(()),
+            )
+            .expect("Trying to read certificate entry.");
+
+        assert!(key_entry.pure_cert());
+        assert!(key_entry.cert().is_none());
+        assert_eq!(key_entry.take_cert_chain(), Some(TEST_CERT_BLOB.to_vec()));
+
+        db.unbind_key(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 1,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            1,
+            |_, _| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 1,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                1,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_and_load_full_keyentry_domain_selinux() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, None)
+            .context("test_insert_and_load_full_keyentry_domain_selinux")?
+            .0;
+        let (_key_guard, key_entry) = db
+            .load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::SELINUX,
+                    nspace: 1,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                1,
+                |_k, _av| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+
+        db.unbind_key(
+            &KeyDescriptor {
+                domain: Domain::SELINUX,
+                nspace: 1,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            1,
+            |_, _| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::SELINUX,
+                    nspace: 1,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                1,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_and_load_full_keyentry_domain_key_id() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, None)
+            .context("test_insert_and_load_full_keyentry_domain_key_id")?
+            .0;
+        let (_, key_entry) = db
+            .load_key_entry(
+                &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                1,
+                |_k, _av| Ok(()),
+            )
+            .unwrap();
+
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+
+        db.unbind_key(
+            &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+            KeyType::Client,
+            1,
+            |_, _| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                1,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_and_update_key_usage_count_with_limited_use_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, Some(123))
+            .context("test_check_and_update_key_usage_count_with_limited_use_key")?
+            .0;
+        // Update the usage count of the limited use key.
+        db.check_and_update_key_usage_count(key_id)?;
+
+        let (_key_guard, key_entry) = db.load_key_entry(
+            &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            1,
+            |_k, _av| Ok(()),
+        )?;
+
+        // The usage count is decremented now.
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, Some(122)));
+
         Ok(())
     }
 
-    // Ensure that we're using the "injected" random function, not the real one.
     #[test]
-    fn test_mocked_random() {
-        let rand1 = random();
-        let rand2 = random();
-        let rand3 = random();
-        if rand1 == rand2 {
-            assert_eq!(rand2 + 1, rand3);
-        } else {
-            assert_eq!(rand1 + 1, rand2);
-            assert_eq!(rand2, rand3);
+    fn test_check_and_update_key_usage_count_with_exhausted_limited_use_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, Some(1))
+            .context("test_check_and_update_key_usage_count_with_exhausted_limited_use_key")?
+            .0;
+        // Update the usage count of the limited use key.
+        db.check_and_update_key_usage_count(key_id).expect(concat!(
+            "In test_check_and_update_key_usage_count_with_exhausted_limited_use_key: ",
+            "This should succeed."
+        ));
+
+        // Try to update the exhausted limited use key.
+        let e = db.check_and_update_key_usage_count(key_id).expect_err(concat!(
+            "In test_check_and_update_key_usage_count_with_exhausted_limited_use_key: ",
+            "This should fail."
+        ));
+        assert_eq!(
+            &KsError::Km(ErrorCode::INVALID_KEY_BLOB),
+            e.root_cause().downcast_ref::<KsError>().unwrap()
+        );
+
+        Ok(())
+    }
+
+    // Removes a granted key entry behind keystore's back and checks that the consistency check
+    // deletes its grant, metadata, and parameters, but leaves the other key alone.
+    #[test]
+    fn test_check_consistency() -> Result<()> {
+        const OWNER_UID: u32 = 10001;
+        const GRANTEE_UID: u32 = 10002;
+        let mut db = new_test_db()?;
+        let lost_id =
+            make_test_key_entry(&mut db, Domain::APP, OWNER_UID as i64, "lost", None)?.id();
+        let kept_id =
+            make_test_key_entry(&mut db, Domain::APP, OWNER_UID as i64, "kept", None)?.id();
+        for alias in ["lost", "kept"] {
+            db.grant(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: -1,
+                    alias: Some(alias.to_string()),
+                    blob: None,
+                },
+                OWNER_UID,
+                GRANTEE_UID,
+                key_perm_set![KeyPerm::Use],
+                |_k, _av| Ok(()),
+            )?;
+        }
+        let count = |db: &KeystoreDB, table: &str, key_id: i64| -> Result<i64> {
+            Ok(db.conn.query_row(
+                &format!("SELECT COUNT(*) FROM persistent.{} WHERE keyentryid = ?;", table),
+                params![key_id],
+                |row| row.get(0),
+            )?)
+        };
+        let expected_parameters = count(&db, "keyparameter", lost_id)? as usize;
+        let expected_metadata = count(&db, "keymetadata", lost_id)? as usize;
+        assert!(expected_parameters > 0);
+        assert!(expected_metadata > 0);
+
+        db.conn.execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![lost_id])?;
+
+        let report = db.check_consistency()?;
+        assert_eq!(
+            ConsistencyReport {
+                dangling_grants: 1,
+                orphaned_key_metadata: expected_metadata,
+                orphaned_key_parameters: expected_parameters,
+            },
+            report
+        );
+        for table in ["grant", "keymetadata", "keyparameter"] {
+            assert_eq!(0, count(&db, table, lost_id)?);
+            assert!(count(&db, table, kept_id)? > 0);
         }
+        // A consistent database is left alone.
+        assert_eq!(0, db.check_consistency()?.anomalies());
+        Ok(())
     }
 
-    // Test that we have the correct tables.
     #[test]
-    fn test_tables() -> Result<()> {
-        let db = new_test_db()?;
-        let tables = db
-            .conn
-            .prepare("SELECT name from persistent.sqlite_master WHERE type='table' ORDER BY name;")?
-            .query_map(params![], |row| row.get(0))?
-            .collect::<rusqlite::Result<Vec<String>>>()?;
-        assert_eq!(tables.len(), 6);
-        assert_eq!(tables[0], "blobentry");
-        assert_eq!(tables[1], "blobmetadata");
-        assert_eq!(tables[2], "grant");
-        assert_eq!(tables[3], "keyentry");
-        assert_eq!(tables[4], "keymetadata");
-        assert_eq!(tables[5], "keyparameter");
+    fn test_insert_and_load_full_keyentry_from_grant() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)
+            .context("test_insert_and_load_full_keyentry_from_grant")?
+            .0;
+
+        let granted_key = db
+            .grant(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 0,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                1,
+                2,
+                key_perm_set![KeyPerm::Use],
+                |_k, _av| Ok(()),
+            )
+            .unwrap();
+
+        debug_dump_grant_table(&mut db)?;
+
+        let (_key_guard, key_entry) = db
+            .load_key_entry(&granted_key, KeyType::Client, KeyEntryLoadBits::BOTH, 2, |k, av| {
+                assert_eq!(Domain::GRANT, k.domain);
+                assert!(av.unwrap().includes(KeyPerm::Use));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+
+        db.unbind_key(&granted_key, KeyType::Client, 2, |_, _| Ok(())).unwrap();
+
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &granted_key,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                2,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+
         Ok(())
     }
 
     #[test]
-    fn test_auth_token_table_invariant() -> Result<()> {
+    fn test_grant_changes_invalidate_grant_cache() -> Result<()> {
         let mut db = new_test_db()?;
-        let auth_token1 = HardwareAuthToken {
-            challenge: i64::MAX,
-            userId: 200,
-            authenticatorId: 200,
-            authenticatorType: kmhw_authenticator_type(kmhw_authenticator_type::PASSWORD.0),
-            timestamp: Timestamp { milliSeconds: 500 },
-            mac: String::from("mac").into_bytes(),
+        make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let app_key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
         };
-        db.insert_auth_token(&auth_token1);
-        let auth_tokens_returned = get_auth_tokens(&db);
-        assert_eq!(auth_tokens_returned.len(), 1);
-
-        // insert another auth token with the same values for the columns in the UNIQUE constraint
-        // of the auth token table and different value for timestamp
-        let auth_token2 = HardwareAuthToken {
-            challenge: i64::MAX,
-            userId: 200,
-            authenticatorId: 200,
-            authenticatorType: kmhw_authenticator_type(kmhw_authenticator_type::PASSWORD.0),
-            timestamp: Timestamp { milliSeconds: 600 },
-            mac: String::from("mac").into_bytes(),
+        let granted_key =
+            db.grant(&app_key, 1, 2, key_perm_set![KeyPerm::Use], |_k, _av| Ok(()))?;
+        let load = |db: &mut KeystoreDB, key: &KeyDescriptor| -> Result<Option<KeyPermSet>> {
+            let generation = GRANT_CACHE.generation();
+            let tx = db.conn.unchecked_transaction()?;
+            let (_, _, access_vector) = KeystoreDB::load_access_tuple_cached(
+                &tx,
+                key,
+                KeyType::Client,
+                2,
+                Some(generation),
+            )?;
+            Ok(access_vector)
+        };
+        let response_code = |r: Result<Option<KeyPermSet>>| match r
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        {
+            Some(KsError::Rc(rc)) => Some(*rc),
+            _ => None,
         };
 
-        db.insert_auth_token(&auth_token2);
-        let mut auth_tokens_returned = get_auth_tokens(&db);
-        assert_eq!(auth_tokens_returned.len(), 1);
-
-        if let Some(auth_token) = auth_tokens_returned.pop() {
-            assert_eq!(auth_token.auth_token.timestamp.milliSeconds, 600);
-        }
+        assert_eq!(Some(key_perm_set![KeyPerm::Use]), load(&mut db, &granted_key)?);
+        assert_eq!(Some(key_perm_set![KeyPerm::Use]), load(&mut db, &granted_key)?);
 
-        // insert another auth token with the different values for the columns in the UNIQUE
-        // constraint of the auth token table
-        let auth_token3 = HardwareAuthToken {
-            challenge: i64::MAX,
-            userId: 201,
-            authenticatorId: 200,
-            authenticatorType: kmhw_authenticator_type(kmhw_authenticator_type::PASSWORD.0),
-            timestamp: Timestamp { milliSeconds: 600 },
-            mac: String::from("mac").into_bytes(),
-        };
+        // Granting again changes the access vector of the existing grant.
+        db.grant(&app_key, 1, 2, key_perm_set![KeyPerm::GetInfo], |_k, _av| Ok(()))?;
+        assert_eq!(Some(key_perm_set![KeyPerm::GetInfo]), load(&mut db, &granted_key)?);
 
-        db.insert_auth_token(&auth_token3);
-        let auth_tokens_returned = get_auth_tokens(&db);
-        assert_eq!(auth_tokens_returned.len(), 2);
+        db.ungrant(&app_key, 1, 2, |_k| Ok(()))?;
+        assert_eq!(Some(ResponseCode::KEY_NOT_FOUND), response_code(load(&mut db, &granted_key)));
 
+        // Deleting the key revokes its grants as well.
+        let granted_key =
+            db.grant(&app_key, 1, 2, key_perm_set![KeyPerm::Use], |_k, _av| Ok(()))?;
+        assert_eq!(Some(key_perm_set![KeyPerm::Use]), load(&mut db, &granted_key)?);
+        db.unbind_key(&app_key, KeyType::Client, 1, |_, _| Ok(()))?;
+        assert_eq!(Some(ResponseCode::KEY_NOT_FOUND), response_code(load(&mut db, &granted_key)));
         Ok(())
     }
 
-    // utility function for test_auth_token_table_invariant()
-    fn get_auth_tokens(db: &KeystoreDB) -> Vec<AuthTokenEntry> {
-        db.perboot.get_all_auth_token_entries()
+    #[test]
+    fn test_grant_constraints() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        let app_key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let constraints = GrantConstraints {
+            auth_sids: Some(vec![1, -2]),
+            purposes: Some(vec![KeyPurpose::SIGN, KeyPurpose::VERIFY]),
+        };
+        let response_code = |r: Result<GrantConstraints>| match r
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        {
+            Some(KsError::Rc(rc)) => Some(*rc),
+            _ => None,
+        };
+
+        db.grant_with_constraints(
+            &app_key,
+            1,
+            2,
+            key_perm_set![KeyPerm::Use],
+            &constraints,
+            |_k, _av| Ok(()),
+        )?;
+        assert_eq!(constraints, db.load_grant_constraints(2, key_id)?);
+        assert_eq!(
+            Some(ResponseCode::KEY_NOT_FOUND),
+            response_code(db.load_grant_constraints(3, key_id))
+        );
+
+        // Granting again replaces the constraints of the existing grant.
+        db.grant(&app_key, 1, 2, key_perm_set![KeyPerm::Use], |_k, _av| Ok(()))?;
+        assert_eq!(GrantConstraints::default(), db.load_grant_constraints(2, key_id)?);
+        Ok(())
     }
 
     #[test]
-    fn test_persistence_for_files() -> Result<()> {
-        let temp_dir = TempDir::new("persistent_db_test")?;
-        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
-
-        db.create_key_entry(&Domain::APP, &100, KeyType::Client, &KEYSTORE_UUID)?;
-        let entries = get_keyentry(&db)?;
-        assert_eq!(entries.len(), 1);
+    fn test_load_key_entry_by_key_id_with_alias() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, None)?.id();
+        let load_checked = |db: &mut KeystoreDB, alias: &str, permitted: bool| {
+            db.load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::KEY_ID,
+                    nspace: key_id,
+                    alias: Some(alias.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                1,
+                |_k, _av| if permitted { Ok(()) } else { Err(KsError::perm().into()) },
+            )
+        };
+        let load = |db: &mut KeystoreDB, alias: &str| load_checked(db, alias, true);
+        let response_code = |r: Result<(KeyIdGuard, KeyEntry)>| match r
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        {
+            Some(KsError::Rc(rc)) => Some(*rc),
+            _ => None,
+        };
 
-        let db = KeystoreDB::new(temp_dir.path(), None)?;
+        assert_eq!(key_id, load(&mut db, TEST_ALIAS)?.0.id());
+        assert_eq!(Some(ResponseCode::KEY_NOT_FOUND), response_code(load(&mut db, "other_alias")));
 
-        let entries_new = get_keyentry(&db)?;
-        assert_eq!(entries, entries_new);
+        // Rebinding the alias replaces the key.
+        make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, None)?;
+        assert_eq!(Some(KEY_CHANGED), response_code(load(&mut db, TEST_ALIAS)));
+        // Callers without permission do not learn that the alias was rebound.
+        assert_eq!(
+            Some(ResponseCode::PERMISSION_DENIED),
+            response_code(load_checked(&mut db, TEST_ALIAS, false))
+        );
+        assert_eq!(
+            Some(ResponseCode::PERMISSION_DENIED),
+            response_code(load_checked(&mut db, "other_alias", false))
+        );
         Ok(())
     }
 
+    // This test attempts to load a key by key id while the caller is not the owner
+    // but a grant exists for the given key and the caller.
     #[test]
-    fn test_create_key_entry() -> Result<()> {
-        fn extractor(ke: &KeyEntryRow) -> (Domain, i64, Option<&str>, Uuid) {
-            (ke.domain.unwrap(), ke.namespace.unwrap(), ke.alias.as_deref(), ke.km_uuid.unwrap())
-        }
-
+    fn test_insert_and_load_full_keyentry_from_grant_by_key_id() -> Result<()> {
         let mut db = new_test_db()?;
+        const OWNER_UID: u32 = 1u32;
+        const GRANTEE_UID: u32 = 2u32;
+        const SOMEONE_ELSE_UID: u32 = 3u32;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, OWNER_UID as i64, TEST_ALIAS, None)
+            .context("test_insert_and_load_full_keyentry_from_grant_by_key_id")?
+            .0;
 
-        db.create_key_entry(&Domain::APP, &100, KeyType::Client, &KEYSTORE_UUID)?;
-        db.create_key_entry(&Domain::SELINUX, &101, KeyType::Client, &KEYSTORE_UUID)?;
+        db.grant(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            OWNER_UID,
+            GRANTEE_UID,
+            key_perm_set![KeyPerm::Use],
+            |_k, _av| Ok(()),
+        )
+        .unwrap();
 
-        let entries = get_keyentry(&db)?;
-        assert_eq!(entries.len(), 2);
-        assert_eq!(extractor(&entries[0]), (Domain::APP, 100, None, KEYSTORE_UUID));
-        assert_eq!(extractor(&entries[1]), (Domain::SELINUX, 101, None, KEYSTORE_UUID));
+        debug_dump_grant_table(&mut db)?;
 
-        // Test that we must pass in a valid Domain.
-        check_result_is_error_containing_string(
-            db.create_key_entry(&Domain::GRANT, &102, KeyType::Client, &KEYSTORE_UUID),
-            &format!("Domain {:?} must be either App or SELinux.", Domain::GRANT),
-        );
-        check_result_is_error_containing_string(
-            db.create_key_entry(&Domain::BLOB, &103, KeyType::Client, &KEYSTORE_UUID),
-            &format!("Domain {:?} must be either App or SELinux.", Domain::BLOB),
-        );
-        check_result_is_error_containing_string(
-            db.create_key_entry(&Domain::KEY_ID, &104, KeyType::Client, &KEYSTORE_UUID),
-            &format!("Domain {:?} must be either App or SELinux.", Domain::KEY_ID),
+        let id_descriptor =
+            KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, ..Default::default() };
+
+        let (_, key_entry) = db
+            .load_key_entry(
+                &id_descriptor,
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                GRANTEE_UID,
+                |k, av| {
+                    assert_eq!(Domain::APP, k.domain);
+                    assert_eq!(OWNER_UID as i64, k.nspace);
+                    assert!(av.unwrap().includes(KeyPerm::Use));
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+
+        let (_, key_entry) = db
+            .load_key_entry(
+                &id_descriptor,
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                SOMEONE_ELSE_UID,
+                |k, av| {
+                    assert_eq!(Domain::APP, k.domain);
+                    assert_eq!(OWNER_UID as i64, k.nspace);
+                    assert!(av.is_none());
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+
+        db.unbind_key(&id_descriptor, KeyType::Client, OWNER_UID, |_, _| Ok(())).unwrap();
+
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &id_descriptor,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                GRANTEE_UID,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_rebind_alias() -> Result<()> {
-        fn extractor(
-            ke: &KeyEntryRow,
-        ) -> (Option<Domain>, Option<i64>, Option<&str>, Option<Uuid>) {
-            (ke.domain, ke.namespace, ke.alias.as_deref(), ke.km_uuid)
-        }
-
+    fn test_rename_app_aliases_with_prefix() -> Result<()> {
         let mut db = new_test_db()?;
-        db.create_key_entry(&Domain::APP, &42, KeyType::Client, &KEYSTORE_UUID)?;
-        db.create_key_entry(&Domain::APP, &42, KeyType::Client, &KEYSTORE_UUID)?;
-        let entries = get_keyentry(&db)?;
-        assert_eq!(entries.len(), 2);
-        assert_eq!(
-            extractor(&entries[0]),
-            (Some(Domain::APP), Some(42), None, Some(KEYSTORE_UUID))
-        );
-        assert_eq!(
-            extractor(&entries[1]),
-            (Some(Domain::APP), Some(42), None, Some(KEYSTORE_UUID))
-        );
+        let squatted = make_test_key_entry(&mut db, Domain::APP, 10001, "sys:key", None)?.id();
+        make_test_key_entry(&mut db, Domain::APP, 1000, "sys:system_key", None)?;
+        make_test_key_entry(&mut db, Domain::APP, 10001, "app_key", None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 10001, "sys:selinux_key", None)?;
+
+        let prefixes = vec!["sys:".to_string(), "sys:k".to_string()];
+        let renamed = db.rename_app_aliases_with_prefix(&prefixes, |nspace, key_id, alias| {
+            (nspace == 10001).then(|| format!("moved:{key_id}:{alias}"))
+        })?;
+        assert_eq!(1, renamed);
 
-        // Test that the first call to rebind_alias sets the alias.
-        rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[0].id), "foo", Domain::APP, 42)?;
-        let entries = get_keyentry(&db)?;
-        assert_eq!(entries.len(), 2);
-        assert_eq!(
-            extractor(&entries[0]),
-            (Some(Domain::APP), Some(42), Some("foo"), Some(KEYSTORE_UUID))
-        );
+        let mut aliases = |domain, nspace| -> Result<Vec<String>> {
+            Ok(db
+                .list_past_alias(domain, nspace, KeyType::Client, None)?
+                .into_iter()
+                .filter_map(|k| k.alias)
+                .collect())
+        };
         assert_eq!(
-            extractor(&entries[1]),
-            (Some(Domain::APP), Some(42), None, Some(KEYSTORE_UUID))
+            vec!["app_key".to_string(), format!("moved:{squatted}:sys:key")],
+            aliases(Domain::APP, 10001)?
         );
+        assert_eq!(vec!["sys:system_key".to_string()], aliases(Domain::APP, 1000)?);
+        assert_eq!(vec!["sys:selinux_key".to_string()], aliases(Domain::SELINUX, 10001)?);
+        Ok(())
+    }
 
-        // Test that the second call to rebind_alias also empties the old one.
-        rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[1].id), "foo", Domain::APP, 42)?;
-        let entries = get_keyentry(&db)?;
-        assert_eq!(entries.len(), 2);
-        assert_eq!(extractor(&entries[0]), (None, None, None, Some(KEYSTORE_UUID)));
-        assert_eq!(
-            extractor(&entries[1]),
-            (Some(Domain::APP), Some(42), Some("foo"), Some(KEYSTORE_UUID))
-        );
+    // Creates a key migrates it to a different location and then tries to access it by the old
+    // and new location.
+    #[test]
+    fn test_migrate_key_app_to_app() -> Result<()> {
+        let mut db = new_test_db()?;
+        const SOURCE_UID: u32 = 1u32;
+        const DESTINATION_UID: u32 = 2u32;
+        static SOURCE_ALIAS: &str = "SOURCE_ALIAS";
+        static DESTINATION_ALIAS: &str = "DESTINATION_ALIAS";
+        let key_id_guard =
+            make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, SOURCE_ALIAS, None)
+                .context("test_insert_and_load_full_keyentry_from_grant_by_key_id")?;
+
+        let source_descriptor: KeyDescriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(SOURCE_ALIAS.to_string()),
+            blob: None,
+        };
+
+        let destination_descriptor: KeyDescriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(DESTINATION_ALIAS.to_string()),
+            blob: None,
+        };
+
+        let key_id = key_id_guard.id();
+
+        db.migrate_key_namespace(key_id_guard, &destination_descriptor, DESTINATION_UID, |_k| {
+            Ok(())
+        })
+        .unwrap();
+
+        let (_, key_entry) = db
+            .load_key_entry(
+                &destination_descriptor,
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                DESTINATION_UID,
+                |k, av| {
+                    assert_eq!(Domain::APP, k.domain);
+                    assert_eq!(DESTINATION_UID as i64, k.nspace);
+                    assert!(av.is_none());
+                    Ok(())
+                },
+            )
+            .unwrap();
 
-        // Test that we must pass in a valid Domain.
-        check_result_is_error_containing_string(
-            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::GRANT, 42),
-            &format!("Domain {:?} must be either App or SELinux.", Domain::GRANT),
-        );
-        check_result_is_error_containing_string(
-            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::BLOB, 42),
-            &format!("Domain {:?} must be either App or SELinux.", Domain::BLOB),
-        );
-        check_result_is_error_containing_string(
-            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::KEY_ID, 42),
-            &format!("Domain {:?} must be either App or SELinux.", Domain::KEY_ID),
-        );
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
 
-        // Test that we correctly handle setting an alias for something that does not exist.
-        check_result_is_error_containing_string(
-            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::SELINUX, 42),
-            "Expected to update a single entry but instead updated 0",
-        );
-        // Test that we correctly abort the transaction in this case.
-        let entries = get_keyentry(&db)?;
-        assert_eq!(entries.len(), 2);
-        assert_eq!(extractor(&entries[0]), (None, None, None, Some(KEYSTORE_UUID)));
         assert_eq!(
-            extractor(&entries[1]),
-            (Some(Domain::APP), Some(42), Some("foo"), Some(KEYSTORE_UUID))
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &source_descriptor,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                SOURCE_UID,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
         );
 
         Ok(())
     }
 
+    // Creates a key migrates it to a different location and then tries to access it by the old
+    // and new location.
     #[test]
-    fn test_grant_ungrant() -> Result<()> {
-        const CALLER_UID: u32 = 15;
-        const GRANTEE_UID: u32 = 12;
-        const SELINUX_NAMESPACE: i64 = 7;
-
+    fn test_migrate_key_app_to_selinux() -> Result<()> {
         let mut db = new_test_db()?;
-        db.conn.execute(
-            "INSERT INTO persistent.keyentry (id, key_type, domain, namespace, alias, state, km_uuid)
-                VALUES (1, 0, 0, 15, 'key', 1, ?), (2, 0, 2, 7, 'yek', 1, ?);",
-            params![KEYSTORE_UUID, KEYSTORE_UUID],
-        )?;
-        let app_key = KeyDescriptor {
-            domain: super::Domain::APP,
-            nspace: 0,
-            alias: Some("key".to_string()),
+        const SOURCE_UID: u32 = 1u32;
+        const DESTINATION_UID: u32 = 2u32;
+        const DESTINATION_NAMESPACE: i64 = 1000i64;
+        static SOURCE_ALIAS: &str = "SOURCE_ALIAS";
+        static DESTINATION_ALIAS: &str = "DESTINATION_ALIAS";
+        let key_id_guard =
+            make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, SOURCE_ALIAS, None)
+                .context("test_insert_and_load_full_keyentry_from_grant_by_key_id")?;
+
+        let source_descriptor: KeyDescriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(SOURCE_ALIAS.to_string()),
             blob: None,
         };
-        const PVEC1: KeyPermSet = key_perm_set![KeyPerm::Use, KeyPerm::GetInfo];
-        const PVEC2: KeyPermSet = key_perm_set![KeyPerm::Use];
 
-        // Reset totally predictable random number generator in case we
-        // are not the first test running on this thread.
-        reset_random();
-        let next_random = 0i64;
+        let destination_descriptor: KeyDescriptor = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: DESTINATION_NAMESPACE,
+            alias: Some(DESTINATION_ALIAS.to_string()),
+            blob: None,
+        };
 
-        let app_granted_key = db
-            .grant(&app_key, CALLER_UID, GRANTEE_UID, PVEC1, |k, a| {
-                assert_eq!(*a, PVEC1);
-                assert_eq!(
-                    *k,
-                    KeyDescriptor {
-                        domain: super::Domain::APP,
-                        // namespace must be set to the caller_uid.
-                        nspace: CALLER_UID as i64,
-                        alias: Some("key".to_string()),
-                        blob: None,
-                    }
-                );
-                Ok(())
-            })
+        let key_id = key_id_guard.id();
+
+        db.migrate_key_namespace(key_id_guard, &destination_descriptor, DESTINATION_UID, |_k| {
+            Ok(())
+        })
+        .unwrap();
+
+        let (_, key_entry) = db
+            .load_key_entry(
+                &destination_descriptor,
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                DESTINATION_UID,
+                |k, av| {
+                    assert_eq!(Domain::SELINUX, k.domain);
+                    assert_eq!(DESTINATION_NAMESPACE, k.nspace);
+                    assert!(av.is_none());
+                    Ok(())
+                },
+            )
             .unwrap();
 
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+
         assert_eq!(
-            app_granted_key,
-            KeyDescriptor {
-                domain: super::Domain::GRANT,
-                // The grantid is next_random due to the mock random number generator.
-                nspace: next_random,
-                alias: None,
-                blob: None,
-            }
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &source_descriptor,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                SOURCE_UID,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
         );
 
-        let selinux_key = KeyDescriptor {
-            domain: super::Domain::SELINUX,
-            nspace: SELINUX_NAMESPACE,
-            alias: Some("yek".to_string()),
+        Ok(())
+    }
+
+    // Creates an app key with a grant, adopts it into an SELINUX namespace, and checks that
+    // the key moved, the grant was revoked, and that the key cannot be adopted a second time.
+    #[test]
+    fn test_adopt_app_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        const SOURCE_UID: u32 = 10001u32;
+        const GRANTEE_UID: u32 = 10002u32;
+        const DESTINATION_NAMESPACE: i64 = 1000i64;
+        static SOURCE_ALIAS: &str = "SOURCE_ALIAS";
+        static DESTINATION_ALIAS: &str = "DESTINATION_ALIAS";
+        let key_id_guard =
+            make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, SOURCE_ALIAS, None)
+                .context("test_adopt_app_key")?;
+        let key_id = key_id_guard.id();
+
+        let source_descriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: SOURCE_UID as i64,
+            alias: Some(SOURCE_ALIAS.to_string()),
+            blob: None,
+        };
+        let destination_descriptor = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: DESTINATION_NAMESPACE,
+            alias: Some(DESTINATION_ALIAS.to_string()),
             blob: None,
         };
 
-        let selinux_granted_key = db
-            .grant(&selinux_key, CALLER_UID, 12, PVEC1, |k, a| {
-                assert_eq!(*a, PVEC1);
-                assert_eq!(
-                    *k,
-                    KeyDescriptor {
-                        domain: super::Domain::SELINUX,
-                        // namespace must be the supplied SELinux
-                        // namespace.
-                        nspace: SELINUX_NAMESPACE,
-                        alias: Some("yek".to_string()),
-                        blob: None,
-                    }
-                );
-                Ok(())
-            })
-            .unwrap();
+        let granted_descriptor = db.grant(
+            &source_descriptor,
+            SOURCE_UID,
+            GRANTEE_UID,
+            key_perm_set![KeyPerm::Use],
+            |_k, _av| Ok(()),
+        )?;
 
-        assert_eq!(
-            selinux_granted_key,
-            KeyDescriptor {
-                domain: super::Domain::GRANT,
-                // The grantid is next_random + 1 due to the mock random number generator.
-                nspace: next_random + 1,
-                alias: None,
-                blob: None,
-            }
-        );
+        db.adopt_app_key(key_id_guard, &destination_descriptor, |k| {
+            assert_eq!(k, &destination_descriptor);
+            Ok(())
+        })?;
 
-        // This should update the existing grant with PVEC2.
-        let selinux_granted_key = db
-            .grant(&selinux_key, CALLER_UID, 12, PVEC2, |k, a| {
-                assert_eq!(*a, PVEC2);
-                assert_eq!(
-                    *k,
-                    KeyDescriptor {
-                        domain: super::Domain::SELINUX,
-                        // namespace must be the supplied SELinux
-                        // namespace.
-                        nspace: SELINUX_NAMESPACE,
-                        alias: Some("yek".to_string()),
-                        blob: None,
-                    }
-                );
+        let (key_id_guard, key_entry) = db.load_key_entry(
+            &destination_descriptor,
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            SOURCE_UID,
+            |k, av| {
+                assert_eq!(Domain::SELINUX, k.domain);
+                assert_eq!(DESTINATION_NAMESPACE, k.nspace);
+                assert!(av.is_none());
                 Ok(())
-            })
-            .unwrap();
-
-        assert_eq!(
-            selinux_granted_key,
-            KeyDescriptor {
-                domain: super::Domain::GRANT,
-                // Same grant id as before. The entry was only updated.
-                nspace: next_random + 1,
-                alias: None,
-                blob: None,
-            }
-        );
+            },
+        )?;
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
 
+        for (descriptor, uid) in
+            [(&source_descriptor, SOURCE_UID), (&granted_descriptor, GRANTEE_UID)]
         {
-            // Limiting scope of stmt, because it borrows db.
-            let mut stmt = db
-                .conn
-                .prepare("SELECT id, grantee, keyentryid, access_vector FROM persistent.grant;")?;
-            let mut rows = stmt.query_map::<(i64, u32, i64, KeyPermSet), _, _>([], |row| {
-                Ok((row.get(0)?, row.get(1)?, row.get(2)?, KeyPermSet::from(row.get::<_, i32>(3)?)))
-            })?;
-
-            let r = rows.next().unwrap().unwrap();
-            assert_eq!(r, (next_random, GRANTEE_UID, 1, PVEC1));
-            let r = rows.next().unwrap().unwrap();
-            assert_eq!(r, (next_random + 1, GRANTEE_UID, 2, PVEC2));
-            assert!(rows.next().is_none());
+            assert_eq!(
+                Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+                db.load_key_entry(
+                    descriptor,
+                    KeyType::Client,
+                    KeyEntryLoadBits::NONE,
+                    uid,
+                    |_k, _av| Ok(()),
+                )
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+            );
         }
 
-        debug_dump_keyentry_table(&mut db)?;
-        println!("app_key {:?}", app_key);
-        println!("selinux_key {:?}", selinux_key);
-
-        db.ungrant(&app_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
-        db.ungrant(&selinux_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
+        // The key now lives in the SELINUX domain and cannot be adopted again.
+        let other_destination = KeyDescriptor {
+            alias: Some("OTHER_ALIAS".to_string()),
+            ..destination_descriptor.clone()
+        };
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+            db.adopt_app_key(key_id_guard, &other_destination, |_k| Ok(()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
 
         Ok(())
     }
 
-    static TEST_KEY_BLOB: &[u8] = b"my test blob";
-    static TEST_CERT_BLOB: &[u8] = b"my test cert";
-    static TEST_CERT_CHAIN_BLOB: &[u8] = b"my test cert_chain";
-
+    // Checks that a super-encrypted app key is not adopted and stays where it was.
     #[test]
-    fn test_set_blob() -> Result<()> {
-        let key_id = KEY_ID_LOCK.get(3000);
+    fn test_adopt_app_key_refuses_super_encrypted_key() -> Result<()> {
         let mut db = new_test_db()?;
+        const SOURCE_UID: u32 = 10001u32;
+        let super_key_id = db
+            .store_super_key(
+                0,
+                &USER_AFTER_FIRST_UNLOCK_SUPER_KEY,
+                TEST_KEY_BLOB,
+                &BlobMetaData::new(),
+                &KeyMetaData::new(),
+            )?
+            .id();
         let mut blob_metadata = BlobMetaData::new();
-        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(super_key_id)));
+        let key_id_guard =
+            make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, TEST_ALIAS, None)?;
         db.set_blob(
-            &key_id,
+            &key_id_guard,
             SubComponentType::KEY_BLOB,
             Some(TEST_KEY_BLOB),
             Some(&blob_metadata),
         )?;
-        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
-        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(TEST_CERT_CHAIN_BLOB), None)?;
-        drop(key_id);
-
-        let mut stmt = db.conn.prepare(
-            "SELECT subcomponent_type, keyentryid, blob, id FROM persistent.blobentry
-                ORDER BY subcomponent_type ASC;",
-        )?;
-        let mut rows = stmt
-            .query_map::<((SubComponentType, i64, Vec<u8>), i64), _, _>([], |row| {
-                Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?))
-            })?;
-        let (r, id) = rows.next().unwrap().unwrap();
-        assert_eq!(r, (SubComponentType::KEY_BLOB, 3000, TEST_KEY_BLOB.to_vec()));
-        let (r, _) = rows.next().unwrap().unwrap();
-        assert_eq!(r, (SubComponentType::CERT, 3000, TEST_CERT_BLOB.to_vec()));
-        let (r, _) = rows.next().unwrap().unwrap();
-        assert_eq!(r, (SubComponentType::CERT_CHAIN, 3000, TEST_CERT_CHAIN_BLOB.to_vec()));
-
-        drop(rows);
-        drop(stmt);
 
+        let destination_descriptor = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 1000,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
         assert_eq!(
-            db.with_transaction(TransactionBehavior::Immediate, |tx| {
-                BlobMetaData::load_from_db(id, tx).no_gc()
-            })
-            .expect("Should find blob metadata."),
-            blob_metadata
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+            db.adopt_app_key(key_id_guard, &destination_descriptor, |_k| Ok(()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        assert_eq!(
+            1,
+            db.list_past_alias(Domain::APP, SOURCE_UID as i64, KeyType::Client, None)?.len()
         );
+        assert!(db.list_past_alias(Domain::SELINUX, 1000, KeyType::Client, None)?.is_empty());
         Ok(())
     }
 
-    static TEST_ALIAS: &str = "my super duper key";
-
+    // Renames an app key with a grant and checks that the key id, metadata, and grant survive,
+    // and that renaming onto an existing alias or through the grant fails.
     #[test]
-    fn test_insert_and_load_full_keyentry_domain_app() -> Result<()> {
+    fn test_rename_key() -> Result<()> {
         let mut db = new_test_db()?;
-        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)
-            .context("test_insert_and_load_full_keyentry_domain_app")?
-            .0;
-        let (_key_guard, key_entry) = db
-            .load_key_entry(
-                &KeyDescriptor {
-                    domain: Domain::APP,
-                    nspace: 0,
-                    alias: Some(TEST_ALIAS.to_string()),
-                    blob: None,
-                },
-                KeyType::Client,
-                KeyEntryLoadBits::BOTH,
-                1,
-                |_k, _av| Ok(()),
-            )
-            .unwrap();
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        const OWNER_UID: u32 = 10001u32;
+        const GRANTEE_UID: u32 = 10002u32;
+        static NEW_ALIAS: &str = "NEW_ALIAS";
+        static OTHER_ALIAS: &str = "OTHER_ALIAS";
+        let key_id =
+            make_test_key_entry(&mut db, Domain::APP, OWNER_UID as i64, TEST_ALIAS, None)?.id();
+        make_test_key_entry(&mut db, Domain::APP, OWNER_UID as i64, OTHER_ALIAS, None)?;
+        let app_key = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
 
-        db.unbind_key(
-            &KeyDescriptor {
-                domain: Domain::APP,
-                nspace: 0,
-                alias: Some(TEST_ALIAS.to_string()),
-                blob: None,
-            },
-            KeyType::Client,
-            1,
-            |_, _| Ok(()),
-        )
-        .unwrap();
+        let granted_descriptor = db.grant(
+            &app_key(TEST_ALIAS),
+            OWNER_UID,
+            GRANTEE_UID,
+            key_perm_set![KeyPerm::Use],
+            |_k, _av| Ok(()),
+        )?;
 
+        db.rename_key(&app_key(TEST_ALIAS), NEW_ALIAS, OWNER_UID, |k| {
+            assert_eq!(Domain::APP, k.domain);
+            assert_eq!(OWNER_UID as i64, k.nspace);
+            assert_eq!(Some(NEW_ALIAS), k.alias.as_deref());
+            Ok(())
+        })?;
+
+        let (_, key_entry) = db.load_key_entry(
+            &app_key(NEW_ALIAS),
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            OWNER_UID,
+            |_k, _av| Ok(()),
+        )?;
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
         assert_eq!(
             Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
             db.load_key_entry(
-                &KeyDescriptor {
-                    domain: Domain::APP,
-                    nspace: 0,
-                    alias: Some(TEST_ALIAS.to_string()),
-                    blob: None,
-                },
+                &app_key(TEST_ALIAS),
                 KeyType::Client,
                 KeyEntryLoadBits::NONE,
-                1,
+                OWNER_UID,
                 |_k, _av| Ok(()),
             )
             .unwrap_err()
             .root_cause()
             .downcast_ref::<KsError>()
         );
+        // The grant refers to the key id and follows the key.
+        let (key_id_guard, _) = db.load_key_entry(
+            &granted_descriptor,
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            GRANTEE_UID,
+            |_k, _av| Ok(()),
+        )?;
+        assert_eq!(key_id, key_id_guard.id());
+        drop(key_id_guard);
+
+        // Renaming onto an existing key fails and leaves both keys in place.
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+            db.rename_key(&app_key(NEW_ALIAS), OTHER_ALIAS, OWNER_UID, |_k| Ok(()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        // The grantee cannot rename the key.
+        assert_eq!(
+            Some(&KsError::perm()),
+            db.rename_key(&granted_descriptor, TEST_ALIAS, GRANTEE_UID, |_k| Ok(()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        // A denied permission check leaves the key where it is.
+        assert!(db
+            .rename_key(
+                &app_key(NEW_ALIAS),
+                TEST_ALIAS,
+                OWNER_UID,
+                |_k| Err(KsError::perm().into())
+            )
+            .is_err());
+        db.load_key_entry(
+            &app_key(NEW_ALIAS),
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            OWNER_UID,
+            |_k, _av| Ok(()),
+        )?;
 
         Ok(())
     }
 
     #[test]
-    fn test_insert_and_load_certificate_entry_domain_app() -> Result<()> {
+    fn test_key_history() -> Result<()> {
         let mut db = new_test_db()?;
+        const SOURCE_UID: u32 = 10001u32;
+        const OTHER_UID: u32 = 10002u32;
+        let key_id_guard =
+            make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, TEST_ALIAS, None)?;
+        let key_id = key_id_guard.id();
+        let replaced_id =
+            make_test_key_entry(&mut db, Domain::APP, OTHER_UID as i64, TEST_ALIAS, None)?.id();
+        let new_id =
+            make_test_key_entry(&mut db, Domain::APP, OTHER_UID as i64, TEST_ALIAS, None)?.id();
 
-        db.store_new_certificate(
-            &KeyDescriptor {
-                domain: Domain::APP,
-                nspace: 1,
-                alias: Some(TEST_ALIAS.to_string()),
-                blob: None,
-            },
-            KeyType::Client,
-            TEST_CERT_BLOB,
-            &KEYSTORE_UUID,
-        )
-        .expect("Trying to insert cert.");
-
-        let (_key_guard, mut key_entry) = db
-            .load_key_entry(
-                &KeyDescriptor {
-                    domain: Domain::APP,
-                    nspace: 1,
-                    alias: Some(TEST_ALIAS.to_string()),
-                    blob: None,
-                },
-                KeyType::Client,
-                KeyEntryLoadBits::PUBLIC,
-                1,
-                |_k, _av| Ok(()),
-            )
-            .expect("Trying to read certificate entry.");
-
-        assert!(key_entry.pure_cert());
-        assert!(key_entry.cert().is_none());
-        assert_eq!(key_entry.take_cert_chain(), Some(TEST_CERT_BLOB.to_vec()));
-
-        db.unbind_key(
-            &KeyDescriptor {
-                domain: Domain::APP,
-                nspace: 1,
-                alias: Some(TEST_ALIAS.to_string()),
-                blob: None,
-            },
-            KeyType::Client,
-            1,
-            |_, _| Ok(()),
-        )
-        .unwrap();
+        let destination = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 1000,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        db.adopt_app_key(key_id_guard, &destination, |_| Ok(()))?;
+        db.unbind_keys_for_namespace(Domain::SELINUX, 1000)?;
 
+        let history: Vec<(i64, KeyHistoryEvent, Domain, i64)> = db
+            .get_key_history(None)?
+            .into_iter()
+            .map(|e| (e.key_id_hash, e.event, e.domain, e.namespace))
+            .collect();
+        let key_id = KeystoreDB::key_id_hash(key_id)?;
+        let replaced_id = KeystoreDB::key_id_hash(replaced_id)?;
+        let new_id = KeystoreDB::key_id_hash(new_id)?;
         assert_eq!(
-            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
-            db.load_key_entry(
-                &KeyDescriptor {
-                    domain: Domain::APP,
-                    nspace: 1,
-                    alias: Some(TEST_ALIAS.to_string()),
-                    blob: None,
-                },
-                KeyType::Client,
-                KeyEntryLoadBits::NONE,
-                1,
-                |_k, _av| Ok(()),
-            )
-            .unwrap_err()
-            .root_cause()
-            .downcast_ref::<KsError>()
+            history,
+            vec![
+                (key_id, KeyHistoryEvent::Created, Domain::APP, SOURCE_UID as i64),
+                (replaced_id, KeyHistoryEvent::Created, Domain::APP, OTHER_UID as i64),
+                (replaced_id, KeyHistoryEvent::Deleted, Domain::APP, OTHER_UID as i64),
+                (new_id, KeyHistoryEvent::Created, Domain::APP, OTHER_UID as i64),
+                (key_id, KeyHistoryEvent::Rebound, Domain::SELINUX, 1000),
+                (key_id, KeyHistoryEvent::Deleted, Domain::SELINUX, 1000),
+            ]
         );
+        assert_eq!(
+            3,
+            db.get_key_history(Some(Namespace::App(OTHER_UID)))?.len(),
+            "Only the history of the given namespace must be returned."
+        );
+        Ok(())
+    }
 
+    #[test]
+    fn test_key_history_retention() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        db.with_transaction(TransactionCategory::KeyWrite, TransactionBehavior::Immediate, |tx| {
+            for _ in 0..KeystoreDB::KEY_HISTORY_MAX_ENTRIES {
+                KeystoreDB::record_key_history(
+                    tx,
+                    KeyHistoryEvent::Rebound,
+                    "id = ?",
+                    params![key_id],
+                )?;
+            }
+            tx.execute(
+                "UPDATE persistent.keyhistory SET time = 0
+                 WHERE id = (SELECT MIN(id) FROM persistent.keyhistory) + 1;",
+                [],
+            )?;
+            KeystoreDB::record_key_history(tx, KeyHistoryEvent::Deleted, "id = ?", params![key_id])
+                .no_gc()
+        })?;
+
+        let history = db.get_key_history(None)?;
+        // The oldest entry exceeds the size limit and the second oldest is aged out.
+        assert_eq!(KeystoreDB::KEY_HISTORY_MAX_ENTRIES as usize - 1, history.len());
+        assert!(history[..history.len() - 1].iter().all(|e| e.event == KeyHistoryEvent::Rebound));
+        assert_eq!(KeyHistoryEvent::Deleted, history.last().unwrap().event);
         Ok(())
     }
 
     #[test]
-    fn test_insert_and_load_full_keyentry_domain_selinux() -> Result<()> {
+    fn test_storage_keys() -> Result<()> {
         let mut db = new_test_db()?;
-        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, None)
-            .context("test_insert_and_load_full_keyentry_domain_selinux")?
-            .0;
-        let (_key_guard, key_entry) = db
-            .load_key_entry(
-                &KeyDescriptor {
-                    domain: Domain::SELINUX,
-                    nspace: 1,
-                    alias: Some(TEST_ALIAS.to_string()),
-                    blob: None,
-                },
-                KeyType::Client,
-                KeyEntryLoadBits::BOTH,
-                1,
-                |_k, _av| Ok(()),
-            )
-            .unwrap();
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        fn response_code<T>(result: Result<T>) -> Option<ResponseCode> {
+            match result.unwrap_err().root_cause().downcast_ref::<KsError>() {
+                Some(KsError::Rc(rc)) => Some(*rc),
+                _ => None,
+            }
+        }
 
-        db.unbind_key(
-            &KeyDescriptor {
-                domain: Domain::SELINUX,
-                nspace: 1,
-                alias: Some(TEST_ALIAS.to_string()),
-                blob: None,
-            },
-            KeyType::Client,
-            1,
-            |_, _| Ok(()),
-        )
-        .unwrap();
+        assert_eq!(
+            Some(ResponseCode::KEY_NOT_FOUND),
+            response_code(db.get_storage_key(10, StorageKeyClass::DeviceEncrypted))
+        );
+        db.register_storage_key(10, StorageKeyClass::DeviceEncrypted, b"de_v1")?;
+        db.register_storage_key(10, StorageKeyClass::CredentialEncrypted, b"ce_v1")?;
+        db.register_storage_key(11, StorageKeyClass::DeviceEncrypted, b"other_user")?;
+        assert_eq!(
+            Some(ResponseCode::INVALID_ARGUMENT),
+            response_code(db.register_storage_key(10, StorageKeyClass::DeviceEncrypted, b"again"))
+        );
+
+        let entry = db.get_storage_key(10, StorageKeyClass::DeviceEncrypted)?;
+        assert_eq!((b"de_v1".to_vec(), 1), (entry.key_blob, entry.generation));
 
+        let previous = db.rotate_storage_key(10, StorageKeyClass::DeviceEncrypted, b"de_v2")?;
+        assert_eq!((b"de_v1".to_vec(), 1), (previous.key_blob, previous.generation));
+        let entry = db.get_storage_key(10, StorageKeyClass::DeviceEncrypted)?;
+        assert_eq!((b"de_v2".to_vec(), 2), (entry.key_blob, entry.generation));
         assert_eq!(
-            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
-            db.load_key_entry(
-                &KeyDescriptor {
-                    domain: Domain::SELINUX,
-                    nspace: 1,
-                    alias: Some(TEST_ALIAS.to_string()),
-                    blob: None,
-                },
-                KeyType::Client,
-                KeyEntryLoadBits::NONE,
-                1,
-                |_k, _av| Ok(()),
-            )
-            .unwrap_err()
-            .root_cause()
-            .downcast_ref::<KsError>()
+            b"ce_v1".to_vec(),
+            db.get_storage_key(10, StorageKeyClass::CredentialEncrypted)?.key_blob
+        );
+        assert_eq!(
+            Some(ResponseCode::KEY_NOT_FOUND),
+            response_code(db.rotate_storage_key(12, StorageKeyClass::DeviceEncrypted, b"x"))
         );
 
+        assert_eq!(2, db.unbind_storage_keys_for_user(10, &KEYSTORE_UUID)?);
+        assert!(db.get_storage_key(10, StorageKeyClass::CredentialEncrypted).is_err());
+        assert!(db.get_storage_key(11, StorageKeyClass::DeviceEncrypted).is_ok());
+
+        // The removed blobs are left to the garbage collector, which deletes them with KeyMint.
+        let (superseded, _) = db.handle_next_superseded_blobs(&[], 10)?;
+        let mut blobs: Vec<Vec<u8>> = superseded.iter().map(|(_, blob, _)| blob.clone()).collect();
+        blobs.sort();
+        assert_eq!(vec![b"ce_v1".to_vec(), b"de_v2".to_vec()], blobs);
+        assert!(superseded
+            .iter()
+            .all(|(_, _, metadata)| metadata.km_uuid() == Some(&KEYSTORE_UUID)));
         Ok(())
     }
 
-    #[test]
-    fn test_insert_and_load_full_keyentry_domain_key_id() -> Result<()> {
-        let mut db = new_test_db()?;
-        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, None)
-            .context("test_insert_and_load_full_keyentry_domain_key_id")?
-            .0;
-        let (_, key_entry) = db
-            .load_key_entry(
-                &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
-                KeyType::Client,
-                KeyEntryLoadBits::BOTH,
-                1,
-                |_k, _av| Ok(()),
-            )
-            .unwrap();
+    struct ReversedEncoding;
 
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+    impl shadow::BlobEncoding for ReversedEncoding {
+        fn id(&self) -> &'static str {
+            "test_reversed"
+        }
+        fn encode(&self, blob: &[u8]) -> Result<Vec<u8>> {
+            Ok(blob.iter().rev().copied().collect())
+        }
+        fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>> {
+            self.encode(encoded)
+        }
+    }
 
+    #[test]
+    fn test_load_key_characteristics() -> Result<()> {
+        let mut db = new_test_db()?;
+        let first = make_test_key_entry(&mut db, Domain::SELINUX, 100, "first", None)?.id();
+        make_test_key_entry(&mut db, Domain::APP, 10001, "app", None)?;
+        let second = make_test_key_entry(&mut db, Domain::SELINUX, 101, "second", Some(2))?.id();
+        make_test_key_entry(&mut db, Domain::SELINUX, 100, "third", None)?;
+        // Keys that are no longer live must not be exported.
         db.unbind_key(
-            &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+            &KeyDescriptor {
+                domain: Domain::SELINUX,
+                nspace: 100,
+                alias: Some("third".to_string()),
+                blob: None,
+            },
             KeyType::Client,
-            1,
+            0,
             |_, _| Ok(()),
-        )
-        .unwrap();
+        )?;
 
-        assert_eq!(
-            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
-            db.load_key_entry(
-                &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
-                KeyType::Client,
-                KeyEntryLoadBits::NONE,
-                1,
-                |_k, _av| Ok(()),
-            )
-            .unwrap_err()
-            .root_cause()
-            .downcast_ref::<KsError>()
-        );
+        let page = db.load_key_characteristics(Domain::SELINUX, 0, 1)?;
+        assert_eq!(1, page.len());
+        assert_eq!(first, page[0].key_id);
+        assert_eq!(100, page[0].namespace);
+        assert_eq!(Some("first".to_string()), page[0].alias);
+        let mut expected = make_test_params(None);
+        let mut actual = page[0].parameters.clone();
+        expected.sort();
+        actual.sort();
+        assert_eq!(expected, actual);
+
+        let page = db.load_key_characteristics(Domain::SELINUX, first, 10)?;
+        assert_eq!(vec![second], page.iter().map(|e| e.key_id).collect::<Vec<_>>());
+        assert_eq!(make_test_params(Some(2)).len(), page[0].parameters.len());
+        assert!(db.load_key_characteristics(Domain::SELINUX, second, 10)?.is_empty());
+        Ok(())
+    }
 
+    #[test]
+    fn test_export_skeleton() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        make_test_key_entry(&mut db, Domain::APP, 2, TEST_ALIAS, None)?;
+        let script = db.export_skeleton()?;
+        assert!(!script.contains(TEST_ALIAS));
+        assert!(script.contains(&format!("randomblob({})", TEST_KEY_BLOB.len())));
+
+        // The script recreates the tables with the same number of rows.
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(&script)?;
+        let count = |conn: &Connection, table: &str| -> Result<i64> {
+            Ok(conn.query_row(&format!("SELECT COUNT(*) FROM {};", table), [], |row| row.get(0))?)
+        };
+        for table in ["keyentry", "blobentry", "keyparameter", "keymetadata"] {
+            let expected = count(&db.conn, &format!("persistent.{}", table))?;
+            assert!(expected > 0, "{}", table);
+            assert_eq!(expected, count(&conn, table)?, "{}", table);
+        }
         Ok(())
     }
 
     #[test]
-    fn test_check_and_update_key_usage_count_with_limited_use_key() -> Result<()> {
+    fn test_get_user_key_usage() -> Result<()> {
         let mut db = new_test_db()?;
-        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, Some(123))
-            .context("test_check_and_update_key_usage_count_with_limited_use_key")?
-            .0;
-        // Update the usage count of the limited use key.
-        db.check_and_update_key_usage_count(key_id)?;
+        let app_uid = 10 * AID_USER_OFFSET as i64 + 10001;
+        make_test_key_entry(&mut db, Domain::APP, app_uid, TEST_ALIAS, None)?;
+        make_test_key_entry(&mut db, Domain::APP, app_uid, "second", None)?;
+        // Keys of system uids, of other users, and in SELinux namespaces are not counted.
+        make_test_key_entry(&mut db, Domain::APP, 10 * AID_USER_OFFSET as i64 + 1000, "s", None)?;
+        make_test_key_entry(&mut db, Domain::APP, 11 * AID_USER_OFFSET as i64 + 10001, "o", None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 10, TEST_ALIAS, None)?;
+
+        let key_bytes =
+            (TEST_KEY_BLOB.len() + TEST_CERT_BLOB.len() + TEST_CERT_CHAIN_BLOB.len()) as u64;
+        assert_eq!((2, 2 * key_bytes), db.get_user_key_usage(10)?);
+        assert_eq!((0, 0), db.get_user_key_usage(12)?);
+        Ok(())
+    }
 
-        let (_key_guard, key_entry) = db.load_key_entry(
-            &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
-            KeyType::Client,
-            KeyEntryLoadBits::BOTH,
-            1,
-            |_k, _av| Ok(()),
-        )?;
+    #[test]
+    fn test_blob_shadow_writes() -> Result<()> {
+        let mut db = new_test_db()?;
+        shadow::set_active(Some(std::sync::Arc::new(ReversedEncoding)));
+        let result = (|| -> Result<()> {
+            let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, "shadow", None)?.0;
+            let shadow_key_blob: Vec<u8> = db.conn.query_row(
+                "SELECT s.blob FROM persistent.blobshadow s
+                 JOIN persistent.blobentry b ON s.blobentryid = b.id
+                 WHERE b.keyentryid = ? AND b.subcomponent_type = ?;",
+                params![key_id, SubComponentType::KEY_BLOB],
+                |row| row.get(0),
+            )?;
+            assert_eq!(TEST_KEY_BLOB.iter().rev().copied().collect::<Vec<u8>>(), shadow_key_blob);
 
-        // The usage count is decremented now.
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, Some(122)));
+            let load = |db: &mut KeystoreDB| -> Result<KeyEntry> {
+                db.load_key_entry(
+                    &KeyDescriptor {
+                        domain: Domain::SELINUX,
+                        nspace: 1,
+                        alias: Some("shadow".to_string()),
+                        blob: None,
+                    },
+                    KeyType::Client,
+                    KeyEntryLoadBits::BOTH,
+                    1,
+                    |_k, _av| Ok(()),
+                )
+                .map(|(_, entry)| entry)
+            };
+            let (_, verified, mismatched, _) = shadow::stats();
+            assert_eq!(make_test_key_entry_test_vector(key_id, None), load(&mut db)?);
+            assert!(shadow::stats().1 >= verified + 3);
+
+            // Shadows that disagree are reported, but the reads still succeed.
+            db.conn.execute(
+                "UPDATE persistent.blobshadow SET blob = x'00' WHERE blobentryid IN
+                 (SELECT id FROM persistent.blobentry WHERE keyentryid = ?);",
+                params![key_id],
+            )?;
+            assert_eq!(make_test_key_entry_test_vector(key_id, None), load(&mut db)?);
+            assert!(shadow::stats().2 >= mismatched + 3);
+            Ok(())
+        })();
+        shadow::set_active(None);
+        result
+    }
 
+    #[test]
+    fn test_max_key_validity() -> Result<()> {
+        let mut db = new_test_db()?;
+        assert_eq!(None, db.get_max_key_validity(10)?);
+        db.set_max_key_validity(10, Some(1000))?;
+        db.set_max_key_validity(11, Some(2000))?;
+        assert_eq!(Some(1000), db.get_max_key_validity(10)?);
+        db.set_max_key_validity(10, Some(500))?;
+        assert_eq!(Some(500), db.get_max_key_validity(10)?);
+        db.set_max_key_validity(10, None)?;
+        assert_eq!(None, db.get_max_key_validity(10)?);
+        assert_eq!(Some(2000), db.get_max_key_validity(11)?);
         Ok(())
     }
 
     #[test]
-    fn test_check_and_update_key_usage_count_with_exhausted_limited_use_key() -> Result<()> {
+    fn test_patch_level_policy() -> Result<()> {
         let mut db = new_test_db()?;
-        let key_id = make_test_key_entry(&mut db, Domain::SELINUX, 1, TEST_ALIAS, Some(1))
-            .context("test_check_and_update_key_usage_count_with_exhausted_limited_use_key")?
-            .0;
-        // Update the usage count of the limited use key.
-        db.check_and_update_key_usage_count(key_id).expect(concat!(
-            "In test_check_and_update_key_usage_count_with_exhausted_limited_use_key: ",
-            "This should succeed."
-        ));
+        assert_eq!(None, db.get_patch_level_policy()?);
+        db.set_patch_level_policy(Some("allow"))?;
+        assert_eq!(Some("allow".to_string()), db.get_patch_level_policy()?);
+        db.set_patch_level_policy(Some("refuse"))?;
+        assert_eq!(Some("refuse".to_string()), db.get_patch_level_policy()?);
+        db.set_patch_level_policy(None)?;
+        assert_eq!(None, db.get_patch_level_policy()?);
+        Ok(())
+    }
 
-        // Try to update the exhausted limited use key.
-        let e = db.check_and_update_key_usage_count(key_id).expect_err(concat!(
-            "In test_check_and_update_key_usage_count_with_exhausted_limited_use_key: ",
-            "This should fail."
-        ));
+    #[test]
+    fn test_database_binding() -> Result<()> {
+        let mut db = new_test_db()?;
+        assert_eq!(None, db.load_database_binding()?);
+        db.store_database_binding(b"nonce", b"mac")?;
+        assert_eq!(Some((b"nonce".to_vec(), b"mac".to_vec())), db.load_database_binding()?);
+        db.store_database_binding(b"other nonce", b"other mac")?;
         assert_eq!(
-            &KsError::Km(ErrorCode::INVALID_KEY_BLOB),
-            e.root_cause().downcast_ref::<KsError>().unwrap()
+            Some((b"other nonce".to_vec(), b"other mac".to_vec())),
+            db.load_database_binding()?
         );
+        Ok(())
+    }
 
+    #[test]
+    fn test_fingerprint_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        assert_eq!(b"first".to_vec(), db.get_or_insert_fingerprint_key(b"first")?);
+        assert_eq!(b"first".to_vec(), db.get_or_insert_fingerprint_key(b"second")?);
         Ok(())
     }
 
     #[test]
-    fn test_insert_and_load_full_keyentry_from_grant() -> Result<()> {
+    fn test_duplicate_imports() -> Result<()> {
         let mut db = new_test_db()?;
-        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)
-            .context("test_insert_and_load_full_keyentry_from_grant")?
-            .0;
+        let mut import = |namespace: i64, alias: &str, fingerprint: &[u8]| -> Result<KeyIdGuard> {
+            let key_id = make_test_key_entry(&mut db, Domain::APP, namespace, alias, None)?;
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::ImportFingerprint(fingerprint.to_vec()));
+            db.insert_key_metadata(&key_id, &metadata)?;
+            Ok(key_id)
+        };
+        import(1, "b", b"same")?;
+        import(1, "a", b"same")?;
+        import(1, "c", b"other")?;
+        import(2, "d", b"same")?;
+        make_test_key_entry(&mut db, Domain::APP, 1, "generated", None)?;
 
-        let granted_key = db
-            .grant(
-                &KeyDescriptor {
-                    domain: Domain::APP,
-                    nspace: 0,
-                    alias: Some(TEST_ALIAS.to_string()),
-                    blob: None,
-                },
-                1,
-                2,
-                key_perm_set![KeyPerm::Use],
-                |_k, _av| Ok(()),
-            )
-            .unwrap();
+        assert_eq!(
+            vec!["a".to_string(), "b".to_string()],
+            db.find_imports_with_fingerprint(Domain::APP, 1, b"same")?
+        );
+        assert_eq!(
+            vec!["d".to_string()],
+            db.find_imports_with_fingerprint(Domain::APP, 2, b"same")?
+        );
+        assert!(db.find_imports_with_fingerprint(Domain::APP, 1, b"unknown")?.is_empty());
+        assert_eq!(
+            vec![vec!["a".to_string(), "b".to_string()]],
+            db.list_duplicate_imports(Domain::APP, 1)?
+        );
+        assert!(db.list_duplicate_imports(Domain::APP, 2)?.is_empty());
 
-        debug_dump_grant_table(&mut db)?;
+        // Replacing an alias with other key material ends the duplicate.
+        make_test_key_entry(&mut db, Domain::APP, 1, "a", None)?;
+        assert_eq!(
+            vec!["b".to_string()],
+            db.find_imports_with_fingerprint(Domain::APP, 1, b"same")?
+        );
+        assert!(db.list_duplicate_imports(Domain::APP, 1)?.is_empty());
+        Ok(())
+    }
 
-        let (_key_guard, key_entry) = db
-            .load_key_entry(&granted_key, KeyType::Client, KeyEntryLoadBits::BOTH, 2, |k, av| {
-                assert_eq!(Domain::GRANT, k.domain);
-                assert!(av.unwrap().includes(KeyPerm::Use));
-                Ok(())
-            })
-            .unwrap();
+    #[test]
+    fn test_reserve_nonce_counters() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, "gcm", None)?;
+        assert!(db.reserve_nonce_counters(key_id.id(), 256).is_err());
 
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::ManagedNoncePrefix(vec![1, 2, 3, 4]));
+        metadata.add(KeyMetaEntry::ManagedNonceCounter(0));
+        db.insert_key_metadata(&key_id, &metadata)?;
+        assert_eq!(0, db.reserve_nonce_counters(key_id.id(), 256)?);
+        assert_eq!(256, db.reserve_nonce_counters(key_id.id(), 256)?);
 
-        db.unbind_key(&granted_key, KeyType::Client, 2, |_, _| Ok(())).unwrap();
+        let key =
+            KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id.id(), ..Default::default() };
+        drop(key_id);
+        let (_, key_entry) =
+            db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::NONE, 1, |_k, _av| Ok(()))?;
+        assert_eq!(Some(&512), key_entry.metadata().managed_nonce_counter());
+        assert_eq!(Some(&vec![1, 2, 3, 4]), key_entry.metadata().managed_nonce_prefix());
+        Ok(())
+    }
 
+    #[test]
+    fn test_replace_software_key_material() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, "hardware", None)?;
+        let key_id = db.create_key_entry(&Domain::APP, &1, KeyType::Client, &KEYSTORE_UUID)?;
+        db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(b"software blob"), None)?;
+        db.insert_keyparameter(
+            &key_id,
+            &[
+                KeyParameter::new(
+                    KeyParameterValue::Algorithm(Algorithm::AES),
+                    SecurityLevel::SOFTWARE,
+                ),
+                KeyParameter::new(KeyParameterValue::UserID(0), SecurityLevel::SOFTWARE),
+            ],
+        )?;
+        rebind_alias(&mut db, &key_id, "software", Domain::APP, 1)?;
         assert_eq!(
-            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
-            db.load_key_entry(
-                &granted_key,
-                KeyType::Client,
-                KeyEntryLoadBits::NONE,
-                2,
-                |_k, _av| Ok(()),
-            )
-            .unwrap_err()
-            .root_cause()
-            .downcast_ref::<KsError>()
+            vec![(
+                key_id.id(),
+                KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 1,
+                    alias: Some("software".to_string()),
+                    blob: None
+                }
+            )],
+            db.list_keys_without_hardware_enforcement(&KEYSTORE_UUID, 0, 10)?
         );
+        assert!(db
+            .list_keys_without_hardware_enforcement(&KEYSTORE_UUID, key_id.id(), 10)?
+            .is_empty());
+
+        let params = vec![
+            KeyParameter::new(
+                KeyParameterValue::Algorithm(Algorithm::AES),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            ),
+            KeyParameter::new(KeyParameterValue::UserID(0), SecurityLevel::SOFTWARE),
+        ];
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        db.replace_key_material(&key_id, b"hardware blob", &blob_metadata, &params)?;
+        assert!(db.list_keys_without_hardware_enforcement(&KEYSTORE_UUID, 0, 10)?.is_empty());
 
+        let key =
+            KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id.id(), ..Default::default() };
+        drop(key_id);
+        let (_, key_entry) =
+            db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, 1, |_k, _av| Ok(()))?;
+        let (blob, stored_metadata) = key_entry.key_blob_info().as_ref().unwrap();
+        assert_eq!(b"hardware blob", &blob[..]);
+        assert_eq!(&blob_metadata, stored_metadata);
+        assert_eq!(&params, key_entry.key_parameters());
         Ok(())
     }
 
-    // This test attempts to load a key by key id while the caller is not the owner
-    // but a grant exists for the given key and the caller.
     #[test]
-    fn test_insert_and_load_full_keyentry_from_grant_by_key_id() -> Result<()> {
+    fn test_credentials() -> Result<()> {
         let mut db = new_test_db()?;
-        const OWNER_UID: u32 = 1u32;
-        const GRANTEE_UID: u32 = 2u32;
-        const SOMEONE_ELSE_UID: u32 = 3u32;
-        let key_id = make_test_key_entry(&mut db, Domain::APP, OWNER_UID as i64, TEST_ALIAS, None)
-            .context("test_insert_and_load_full_keyentry_from_grant_by_key_id")?
-            .0;
+        fn response_code<T>(result: Result<T>) -> Option<ResponseCode> {
+            match result.unwrap_err().root_cause().downcast_ref::<KsError>() {
+                Some(KsError::Rc(rc)) => Some(*rc),
+                _ => None,
+            }
+        }
 
-        db.grant(
-            &KeyDescriptor {
-                domain: Domain::APP,
-                nspace: 0,
-                alias: Some(TEST_ALIAS.to_string()),
-                blob: None,
-            },
-            OWNER_UID,
-            GRANTEE_UID,
-            key_perm_set![KeyPerm::Use],
-            |_k, _av| Ok(()),
-        )
-        .unwrap();
+        let profile = CredentialEntry {
+            blob: b"profile".to_vec(),
+            iv: b"iv".to_vec(),
+            aead_tag: b"tag".to_vec(),
+        };
+        let other_profile = CredentialEntry {
+            blob: b"other profile".to_vec(),
+            iv: b"other iv".to_vec(),
+            aead_tag: b"other tag".to_vec(),
+        };
+        db.put_credential(10001, CredentialType::VpnProfile, "vpn_b", &other_profile)?;
+        db.put_credential(10001, CredentialType::VpnProfile, "vpn_a", &profile)?;
+        db.put_credential(10001, CredentialType::WifiCredential, "vpn_c", &other_profile)?;
+        db.put_credential(1_010_001, CredentialType::VpnProfile, "vpn_d", &other_profile)?;
 
-        debug_dump_grant_table(&mut db)?;
+        assert_eq!(profile, db.get_credential(10001, CredentialType::VpnProfile, "vpn_a")?);
+        assert_eq!(
+            Some(ResponseCode::KEY_NOT_FOUND),
+            response_code(db.get_credential(10001, CredentialType::WifiCredential, "vpn_a"))
+        );
+        assert_eq!(
+            vec!["vpn_a".to_string(), "vpn_b".to_string()],
+            db.list_credentials(10001, CredentialType::VpnProfile, "vpn_")?
+        );
+        assert!(db.list_credentials(10001, CredentialType::VpnProfile, "vpn%")?.is_empty());
 
-        let id_descriptor =
-            KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, ..Default::default() };
+        // Putting an existing alias replaces the entry.
+        db.put_credential(10001, CredentialType::VpnProfile, "vpn_a", &other_profile)?;
+        assert_eq!(other_profile, db.get_credential(10001, CredentialType::VpnProfile, "vpn_a")?);
 
-        let (_, key_entry) = db
-            .load_key_entry(
-                &id_descriptor,
-                KeyType::Client,
-                KeyEntryLoadBits::BOTH,
-                GRANTEE_UID,
-                |k, av| {
-                    assert_eq!(Domain::APP, k.domain);
-                    assert_eq!(OWNER_UID as i64, k.nspace);
-                    assert!(av.unwrap().includes(KeyPerm::Use));
-                    Ok(())
-                },
-            )
-            .unwrap();
+        db.remove_credential(10001, CredentialType::VpnProfile, "vpn_a")?;
+        assert_eq!(
+            Some(ResponseCode::KEY_NOT_FOUND),
+            response_code(db.remove_credential(10001, CredentialType::VpnProfile, "vpn_a"))
+        );
 
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        assert_eq!(1, db.delete_credentials_for_user(10)?);
+        assert_eq!(2, db.delete_credentials_for_owner(10001)?);
+        assert!(db.list_credentials(10001, CredentialType::WifiCredential, "")?.is_empty());
+        Ok(())
+    }
 
-        let (_, key_entry) = db
-            .load_key_entry(
-                &id_descriptor,
-                KeyType::Client,
-                KeyEntryLoadBits::BOTH,
-                SOMEONE_ELSE_UID,
-                |k, av| {
-                    assert_eq!(Domain::APP, k.domain);
-                    assert_eq!(OWNER_UID as i64, k.nspace);
-                    assert!(av.is_none());
-                    Ok(())
+    #[test]
+    fn test_blob_checksums() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, "intact", None)?;
+        let corrupted_id = make_test_key_entry(&mut db, Domain::APP, 1, "corrupted", None)?.id();
+        let load = |db: &mut KeystoreDB, alias: &str, load_bits| {
+            db.load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 1,
+                    alias: Some(alias.to_string()),
+                    blob: None,
                 },
+                KeyType::Client,
+                load_bits,
+                1,
+                |_, _| Ok(()),
             )
-            .unwrap();
-
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        };
+        assert!(load(&mut db, "corrupted", KeyEntryLoadBits::BOTH).is_ok());
+        assert!(db.audit_blob_checksums()?.is_empty());
 
-        db.unbind_key(&id_descriptor, KeyType::Client, OWNER_UID, |_, _| Ok(())).unwrap();
+        // Flip a bit in the certificate of one key.
+        let mut corrupted_cert = TEST_CERT_BLOB.to_vec();
+        corrupted_cert[0] ^= 1;
+        db.conn.execute(
+            "UPDATE persistent.blobentry SET blob = ? WHERE keyentryid = ? AND subcomponent_type = ?;",
+            params![corrupted_cert, corrupted_id, SubComponentType::CERT],
+        )?;
 
+        let result = load(&mut db, "corrupted", KeyEntryLoadBits::PUBLIC);
         assert_eq!(
-            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
-            db.load_key_entry(
-                &id_descriptor,
-                KeyType::Client,
-                KeyEntryLoadBits::NONE,
-                GRANTEE_UID,
-                |_k, _av| Ok(()),
-            )
-            .unwrap_err()
-            .root_cause()
-            .downcast_ref::<KsError>()
+            Some(&KsError::Rc(ResponseCode::VALUE_CORRUPTED)),
+            result.unwrap_err().root_cause().downcast_ref::<KsError>()
         );
-
+        // Blobs that are not loaded are not verified.
+        assert!(load(&mut db, "corrupted", KeyEntryLoadBits::KM).is_ok());
+        assert!(load(&mut db, "intact", KeyEntryLoadBits::BOTH).is_ok());
+        assert_eq!(vec![corrupted_id], db.audit_blob_checksums()?);
+
+        // Corrupted blobs of deleted keys are not reported.
+        db.unbind_keys_for_namespace(Domain::APP, 1)?;
+        assert!(db.audit_blob_checksums()?.is_empty());
         Ok(())
     }
 
-    // Creates a key migrates it to a different location and then tries to access it by the old
-    // and new location.
+    // Checks that the audit pages through all blobs and reports corrupted blobs of any batch
+    // once per key, in key id order.
     #[test]
-    fn test_migrate_key_app_to_app() -> Result<()> {
+    fn test_audit_blob_checksums_spans_batches() -> Result<()> {
         let mut db = new_test_db()?;
-        const SOURCE_UID: u32 = 1u32;
-        const DESTINATION_UID: u32 = 2u32;
-        static SOURCE_ALIAS: &str = "SOURCE_ALIAS";
-        static DESTINATION_ALIAS: &str = "DESTINATION_ALIAS";
-        let key_id_guard =
-            make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, SOURCE_ALIAS, None)
-                .context("test_insert_and_load_full_keyentry_from_grant_by_key_id")?;
-
-        let source_descriptor: KeyDescriptor = KeyDescriptor {
-            domain: Domain::APP,
-            nspace: -1,
-            alias: Some(SOURCE_ALIAS.to_string()),
-            blob: None,
-        };
-
-        let destination_descriptor: KeyDescriptor = KeyDescriptor {
-            domain: Domain::APP,
-            nspace: -1,
-            alias: Some(DESTINATION_ALIAS.to_string()),
-            blob: None,
-        };
-
-        let key_id = key_id_guard.id();
+        let key_ids = (0..KeystoreDB::BLOB_CHECKSUM_AUDIT_BATCH_SIZE)
+            .map(|i| {
+                Ok(make_test_key_entry(&mut db, Domain::APP, 1, &format!("key_{i}"), None)?.id())
+            })
+            .collect::<Result<Vec<i64>>>()?;
+        let mut corrupted_ids = vec![key_ids[0], *key_ids.last().unwrap()];
+        corrupted_ids.sort();
+        for key_id in &corrupted_ids {
+            db.conn.execute(
+                "UPDATE persistent.blobentry SET blob = x'00' WHERE keyentryid = ?;",
+                params![key_id],
+            )?;
+        }
+        assert_eq!(corrupted_ids, db.audit_blob_checksums()?);
+        Ok(())
+    }
 
-        db.migrate_key_namespace(key_id_guard, &destination_descriptor, DESTINATION_UID, |_k| {
-            Ok(())
-        })
-        .unwrap();
+    #[test]
+    fn test_upgrade_from_2_to_3_adds_checksums() -> Result<()> {
+        let mut db = new_test_db()?;
+        db.conn.execute("ALTER TABLE persistent.blobentry DROP COLUMN checksum;", [])?;
+        db.conn.execute(
+            "INSERT INTO persistent.blobentry (subcomponent_type, keyentryid, blob)
+             VALUES (?, ?, ?);",
+            params![SubComponentType::KEY_BLOB, 1, TEST_KEY_BLOB],
+        )?;
 
-        let (_, key_entry) = db
-            .load_key_entry(
-                &destination_descriptor,
-                KeyType::Client,
-                KeyEntryLoadBits::BOTH,
-                DESTINATION_UID,
-                |k, av| {
-                    assert_eq!(Domain::APP, k.domain);
-                    assert_eq!(DESTINATION_UID as i64, k.nspace);
-                    assert!(av.is_none());
-                    Ok(())
-                },
-            )
-            .unwrap();
+        db.with_transaction(TransactionCategory::Init, TransactionBehavior::Immediate, |tx| {
+            KeystoreDB::from_2_to_3(tx).no_gc()
+        })?;
 
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        let checksum: Vec<u8> =
+            db.conn
+                .query_row("SELECT checksum FROM persistent.blobentry;", [], |row| row.get(0))?;
+        assert_eq!(KeystoreDB::blob_checksum(TEST_KEY_BLOB)?, checksum);
+        Ok(())
+    }
 
+    #[test]
+    fn test_redact_sql() {
         assert_eq!(
-            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
-            db.load_key_entry(
-                &source_descriptor,
-                KeyType::Client,
-                KeyEntryLoadBits::NONE,
-                SOURCE_UID,
-                |_k, _av| Ok(()),
+            "SELECT id FROM persistent.keyentry WHERE alias = '?' AND data = X'?' AND id = ?;",
+            db_utils::redact_sql(
+                "SELECT id FROM persistent.keyentry
+                 WHERE alias = 'it''s secret' AND data = X'0102' AND id = ?;"
             )
-            .unwrap_err()
-            .root_cause()
-            .downcast_ref::<KsError>()
         );
+    }
 
+    #[test]
+    fn test_shrink_memory_on_next_transaction() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, "key", None)?;
+        let generation = SHRINK_GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+        assert!(db.shrink_generation < generation);
+        assert!(db.key_exists(Domain::APP, 1, "key", KeyType::Client)?);
+        assert!(db.shrink_generation >= generation);
         Ok(())
     }
 
-    // Creates a key migrates it to a different location and then tries to access it by the old
-    // and new location.
     #[test]
-    fn test_migrate_key_app_to_selinux() -> Result<()> {
+    fn test_list_keys_requiring_maintenance() -> Result<()> {
+        use KeyMaintenanceReason::*;
         let mut db = new_test_db()?;
-        const SOURCE_UID: u32 = 1u32;
-        const DESTINATION_UID: u32 = 2u32;
-        const DESTINATION_NAMESPACE: i64 = 1000i64;
-        static SOURCE_ALIAS: &str = "SOURCE_ALIAS";
-        static DESTINATION_ALIAS: &str = "DESTINATION_ALIAS";
-        let key_id_guard =
-            make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, SOURCE_ALIAS, None)
-                .context("test_insert_and_load_full_keyentry_from_grant_by_key_id")?;
-
-        let source_descriptor: KeyDescriptor = KeyDescriptor {
-            domain: Domain::APP,
-            nspace: -1,
-            alias: Some(SOURCE_ALIAS.to_string()),
-            blob: None,
-        };
-
-        let destination_descriptor: KeyDescriptor = KeyDescriptor {
-            domain: Domain::SELINUX,
-            nspace: DESTINATION_NAMESPACE,
-            alias: Some(DESTINATION_ALIAS.to_string()),
-            blob: None,
-        };
+        // The test parameters describe an RSA 1024 bit key at OS patch level 2.
+        let stale_id = make_test_key_entry(&mut db, Domain::APP, 1, "stale", None)?.id();
+        let rewrap_key = make_test_key_entry(&mut db, Domain::APP, 1, "rewrap", None)?;
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::PublicKey(vec![1, 2, 3]));
+        db.set_blob(
+            &rewrap_key,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        let rewrap_id = rewrap_key.id();
+        make_test_key_entry(&mut db, Domain::APP, 2, "deleted", None)?;
+        db.unbind_keys_for_namespace(Domain::APP, 2)?;
+
+        let mut expected = vec![
+            (stale_id, RequiresUpgrade),
+            (stale_id, DeprecatedAlgorithm),
+            (rewrap_id, RequiresUpgrade),
+            (rewrap_id, RequiresRewrap),
+            (rewrap_id, DeprecatedAlgorithm),
+        ];
+        expected.sort();
+        assert_eq!(expected, db.list_keys_requiring_maintenance(Some(3))?);
 
-        let key_id = key_id_guard.id();
+        expected.retain(|(_, reason)| *reason != RequiresUpgrade);
+        assert_eq!(expected, db.list_keys_requiring_maintenance(Some(2))?);
+        assert_eq!(expected, db.list_keys_requiring_maintenance(None)?);
 
-        db.migrate_key_namespace(key_id_guard, &destination_descriptor, DESTINATION_UID, |_k| {
-            Ok(())
-        })
-        .unwrap();
+        // Only the current blob of a key matters.
+        db.set_blob(&rewrap_key, SubComponentType::KEY_BLOB, Some(TEST_KEY_BLOB), None)?;
+        expected.retain(|(_, reason)| *reason != RequiresRewrap);
+        assert_eq!(expected, db.list_keys_requiring_maintenance(None)?);
+        Ok(())
+    }
 
-        let (_, key_entry) = db
-            .load_key_entry(
-                &destination_descriptor,
-                KeyType::Client,
-                KeyEntryLoadBits::BOTH,
-                DESTINATION_UID,
-                |k, av| {
-                    assert_eq!(Domain::SELINUX, k.domain);
-                    assert_eq!(DESTINATION_NAMESPACE, k.nspace);
-                    assert!(av.is_none());
-                    Ok(())
-                },
+    #[test]
+    fn test_count_super_encrypted_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        let store_super_key = |db: &mut KeystoreDB, user_id: u32, key_type: &SuperKeyType| {
+            db.store_super_key(
+                user_id,
+                key_type,
+                TEST_KEY_BLOB,
+                &BlobMetaData::new(),
+                &KeyMetaData::new(),
             )
-            .unwrap();
+            .map(|entry| entry.id())
+        };
+        let after_first_unlock = store_super_key(&mut db, 1, &USER_AFTER_FIRST_UNLOCK_SUPER_KEY)?;
+        let unlocked_device_required =
+            store_super_key(&mut db, 1, &USER_UNLOCKED_DEVICE_REQUIRED_SYMMETRIC_SUPER_KEY)?;
+        let other_user = store_super_key(&mut db, 2, &USER_AFTER_FIRST_UNLOCK_SUPER_KEY)?;
+
+        let uid = AID_USER_OFFSET as i64 + 10001;
+        for (alias, super_key_id) in [
+            ("a", Some(after_first_unlock)),
+            ("b", Some(after_first_unlock)),
+            ("c", Some(unlocked_device_required)),
+            ("d", Some(other_user)),
+            ("e", None),
+        ] {
+            let key = make_test_key_entry(&mut db, Domain::APP, uid, alias, None)?;
+            if let Some(super_key_id) = super_key_id {
+                let mut blob_metadata = BlobMetaData::new();
+                blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(super_key_id)));
+                db.set_blob(
+                    &key,
+                    SubComponentType::KEY_BLOB,
+                    Some(TEST_KEY_BLOB),
+                    Some(&blob_metadata),
+                )?;
+            }
+        }
+        let expected = vec![
+            (USER_AFTER_FIRST_UNLOCK_SUPER_KEY.alias.to_string(), 2),
+            (USER_UNLOCKED_DEVICE_REQUIRED_SYMMETRIC_SUPER_KEY.alias.to_string(), 1),
+        ];
+        assert_eq!(expected, db.count_super_encrypted_keys(1)?);
 
-        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        // Deleted keys and keys whose current blob is not super-encrypted do not count.
+        db.unbind_key(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: uid,
+                alias: Some("a".to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            uid as u32,
+            |_, _| Ok(()),
+        )?;
+        let c = db.load_key_entry(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: uid,
+                alias: Some("c".to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            uid as u32,
+            |_, _| Ok(()),
+        )?;
+        db.set_blob(&c.0, SubComponentType::KEY_BLOB, Some(TEST_KEY_BLOB), None)?;
+        assert_eq!(
+            vec![(USER_AFTER_FIRST_UNLOCK_SUPER_KEY.alias.to_string(), 1)],
+            db.count_super_encrypted_keys(1)?
+        );
+        assert_eq!(
+            vec![(USER_AFTER_FIRST_UNLOCK_SUPER_KEY.alias.to_string(), 1)],
+            db.count_super_encrypted_keys(2)?
+        );
+        assert!(db.count_super_encrypted_keys(3)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrap_key_blobs() -> Result<()> {
+        let mut db = new_test_db()?;
+        let stale = make_test_key_entry(&mut db, Domain::APP, 1, "stale", None)?;
+        let upgraded = make_test_key_entry(&mut db, Domain::APP, 1, "upgraded", None)?;
+        let other = make_test_key_entry(&mut db, Domain::APP, 1, "other", None)?;
+        for (key, super_key_id) in [(&stale, 42), (&upgraded, 42), (&other, 43)] {
+            let mut blob_metadata = BlobMetaData::new();
+            blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(super_key_id)));
+            db.set_blob(
+                key,
+                SubComponentType::KEY_BLOB,
+                Some(TEST_KEY_BLOB),
+                Some(&blob_metadata),
+            )?;
+        }
 
+        let blobs = db.load_key_blobs_encrypted_by(42, 0, 10)?;
         assert_eq!(
-            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
-            db.load_key_entry(
-                &source_descriptor,
-                KeyType::Client,
-                KeyEntryLoadBits::NONE,
-                SOURCE_UID,
-                |_k, _av| Ok(()),
-            )
-            .unwrap_err()
-            .root_cause()
-            .downcast_ref::<KsError>()
+            vec![stale.id(), upgraded.id()],
+            blobs.iter().map(|(key_id, ..)| *key_id).collect::<Vec<_>>()
         );
+        assert_eq!(1, db.load_key_blobs_encrypted_by(42, 0, 1)?.len());
+        assert_eq!(1, db.load_key_blobs_encrypted_by(42, blobs[0].1, 10)?.len());
+
+        // The second key gets a new blob before its stale one is replaced.
+        db.set_blob(&upgraded, SubComponentType::KEY_BLOB, Some(TEST_KEY_BLOB), None)?;
+        let rewrapped: Vec<_> = blobs
+            .into_iter()
+            .map(|(key_id, blob_id, _, _)| (key_id, blob_id, vec![1, 2, 3], BlobMetaData::new()))
+            .collect();
+        assert_eq!(1, db.rewrap_key_blobs(&rewrapped)?);
+        assert!(db.load_key_blobs_encrypted_by(42, 0, 10)?.is_empty());
+        assert_eq!(1, db.load_key_blobs_encrypted_by(43, 0, 10)?.len());
+
+        let (_, entry) = db.load_key_entry(
+            &KeyDescriptor { domain: Domain::KEY_ID, nspace: stale.id(), ..Default::default() },
+            KeyType::Client,
+            KeyEntryLoadBits::KM,
+            1,
+            |_, _| Ok(()),
+        )?;
+        assert_eq!(Some(&vec![1, 2, 3]), entry.key_blob_info().as_ref().map(|(b, _)| b));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_encrypted_by() -> Result<()> {
+        let mut db = new_test_db()?;
+        let encrypted = make_test_key_entry(&mut db, Domain::APP, 1, "encrypted", None)?;
+        let other = make_test_key_entry(&mut db, Domain::APP, 1, "other", None)?;
+        for (key, super_key_id) in [(&encrypted, 42), (&other, 43)] {
+            let mut blob_metadata = BlobMetaData::new();
+            blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(super_key_id)));
+            db.set_blob(
+                key,
+                SubComponentType::KEY_BLOB,
+                Some(TEST_KEY_BLOB),
+                Some(&blob_metadata),
+            )?;
+        }
 
+        assert_eq!(1, db.unbind_keys_encrypted_by(42)?);
+        assert_eq!(0, db.unbind_keys_encrypted_by(42)?);
+        assert!(db.load_key_blobs_encrypted_by(42, 0, 10)?.is_empty());
+        assert_eq!(1, db.load_key_blobs_encrypted_by(43, 0, 10)?.len());
         Ok(())
     }
 
@@ -3998,7 +7911,7 @@ pub mod tests {
             .unwrap();
         assert_eq!(key_entry, make_bootlevel_test_key_entry_test_vector(key_id_deleted, true));
 
-        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+        db.with_transaction(TransactionCategory::Init, TransactionBehavior::Immediate, |tx| {
             KeystoreDB::from_0_to_1(tx).no_gc()
         })
         .unwrap();
@@ -4864,18 +8777,97 @@ pub mod tests {
     #[test]
     fn test_unbind_keys_for_user() -> Result<()> {
         let mut db = new_test_db()?;
-        db.unbind_keys_for_user(1, false)?;
+        db.unbind_keys_for_user(1, UserKeyFilter::APP_AND_SUPER_KEYS)?;
 
         make_test_key_entry(&mut db, Domain::APP, 210000, TEST_ALIAS, None)?;
         make_test_key_entry(&mut db, Domain::APP, 110000, TEST_ALIAS, None)?;
-        db.unbind_keys_for_user(2, false)?;
+        db.unbind_keys_for_user(2, UserKeyFilter::APP_AND_SUPER_KEYS)?;
 
         assert_eq!(1, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
         assert_eq!(0, db.list_past_alias(Domain::APP, 210000, KeyType::Client, None)?.len());
 
-        db.unbind_keys_for_user(1, true)?;
+        db.unbind_keys_for_user(1, UserKeyFilter::APP_AND_SUPER_KEYS.super_encrypted_only())?;
+        assert_eq!(0, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_for_user_with_filter() -> Result<()> {
+        let mut db = new_test_db()?;
+        let super_key_id = db
+            .store_super_key(
+                1,
+                &USER_AFTER_FIRST_UNLOCK_SUPER_KEY,
+                TEST_KEY_BLOB,
+                &BlobMetaData::new(),
+                &KeyMetaData::new(),
+            )?
+            .id();
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(super_key_id)));
+        let app_key = make_test_key_entry(&mut db, Domain::APP, 110000, TEST_ALIAS, None)?;
+        db.set_blob(
+            &app_key,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        let selinux_key = make_test_key_entry(&mut db, Domain::SELINUX, 100, "encrypted", None)?;
+        db.set_blob(
+            &selinux_key,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        // Not encrypted with a super key of the user, so it does not belong to the user.
+        make_test_key_entry(&mut db, Domain::SELINUX, 100, "plain", None)?;
+        let selinux_aliases = |db: &mut KeystoreDB| -> Result<Vec<Option<String>>> {
+            Ok(db
+                .list_past_alias(Domain::SELINUX, 100, KeyType::Client, None)?
+                .into_iter()
+                .map(|k| k.alias)
+                .collect())
+        };
+
+        let only = |key_type: Option<KeyType>, domain: Option<Domain>| UserKeyFilter {
+            key_type,
+            domain,
+            keep_non_super_encrypted_keys: false,
+        };
+        db.unbind_keys_for_user(1, only(None, Some(Domain::SELINUX)))?;
+        assert_eq!(vec![Some("plain".to_string())], selinux_aliases(&mut db)?);
+        assert_eq!(1, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
+        assert!(db.load_super_key(&USER_AFTER_FIRST_UNLOCK_SUPER_KEY, 1)?.is_some());
+
+        db.unbind_keys_for_user(1, only(Some(KeyType::Super), None))?;
+        assert!(db.load_super_key(&USER_AFTER_FIRST_UNLOCK_SUPER_KEY, 1)?.is_none());
+        assert_eq!(1, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
+
+        db.unbind_keys_for_user(1, only(Some(KeyType::Client), None))?;
         assert_eq!(0, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
+        assert_eq!(vec![Some("plain".to_string())], selinux_aliases(&mut db)?);
+        Ok(())
+    }
 
+    #[test]
+    fn test_unbind_keys_with_alias_prefix() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 140, "vm_instance:aa:key1", None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 140, "vm_instance:aa:key2", None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 140, "vm_instance:ab:key1", None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 141, "vm_instance:aa:key1", None)?;
+        // Wildcards in the prefix are taken literally.
+        assert_eq!(0, db.unbind_keys_with_alias_prefix(Domain::SELINUX, 140, "vm_instance:a_:")?);
+
+        assert_eq!(2, db.unbind_keys_with_alias_prefix(Domain::SELINUX, 140, "vm_instance:aa:")?);
+        let remaining: Vec<Option<String>> = db
+            .list_past_alias(Domain::SELINUX, 140, KeyType::Client, None)?
+            .into_iter()
+            .map(|k| k.alias)
+            .collect();
+        assert_eq!(vec![Some("vm_instance:ab:key1".to_string())], remaining);
+        assert_eq!(1, db.list_past_alias(Domain::SELINUX, 141, KeyType::Client, None)?.len());
         Ok(())
     }
 
@@ -4926,7 +8918,7 @@ pub mod tests {
         assert!(db.load_super_key(&key_name_nonenc, 2)?.is_some());
 
         // Delete only encrypted keys.
-        db.unbind_keys_for_user(1, true)?;
+        db.unbind_keys_for_user(1, UserKeyFilter::APP_AND_SUPER_KEYS.super_encrypted_only())?;
 
         // The encrypted superkey should be gone now.
         assert!(db.load_super_key(&key_name_enc, 1)?.is_none());
@@ -4940,7 +8932,7 @@ pub mod tests {
         assert!(db.load_super_key(&key_name_nonenc, 1)?.is_some());
 
         // Delete all even unencrypted keys.
-        db.unbind_keys_for_user(1, false)?;
+        db.unbind_keys_for_user(1, UserKeyFilter::APP_AND_SUPER_KEYS)?;
 
         // Both should be gone now.
         assert!(db.load_super_key(&key_name_enc, 1)?.is_none());
@@ -4982,6 +8974,7 @@ pub mod tests {
 
         let (_, key_entry) = db.load_super_key(&USER_AFTER_FIRST_UNLOCK_SUPER_KEY, 1)?.unwrap();
         let loaded_super_key = SuperKeyManager::extract_super_key_from_key_entry(
+            &mut db,
             USER_AFTER_FIRST_UNLOCK_SUPER_KEY.algorithm,
             key_entry,
             &pw,
@@ -4994,6 +8987,43 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_encrypted_by_round_trip() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_types = [
+            (
+                SuperKeyType { alias: "password", algorithm: SuperEncryptionAlgorithm::Aes256Gcm },
+                EncryptedBy::Password,
+            ),
+            (
+                SuperKeyType { alias: "key_id", algorithm: SuperEncryptionAlgorithm::Aes256Gcm },
+                EncryptedBy::KeyId(42),
+            ),
+            (
+                SuperKeyType {
+                    alias: "reboot_escrow",
+                    algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+                },
+                EncryptedBy::RebootEscrow,
+            ),
+        ];
+        for (key_type, encrypted_by) in &key_types {
+            let mut metadata = BlobMetaData::new();
+            metadata.add(BlobMetaEntry::EncryptedBy(match encrypted_by {
+                EncryptedBy::Password => EncryptedBy::Password,
+                EncryptedBy::KeyId(id) => EncryptedBy::KeyId(*id),
+                EncryptedBy::RebootEscrow => EncryptedBy::RebootEscrow,
+            }));
+            db.store_super_key(1, key_type, TEST_KEY_BLOB, &metadata, &KeyMetaData::new())?;
+        }
+        for (key_type, encrypted_by) in &key_types {
+            let (_, entry) = db.load_super_key(key_type, 1)?.unwrap();
+            let (_, metadata) = entry.key_blob_info().as_ref().unwrap();
+            assert_eq!(Some(encrypted_by), metadata.encrypted_by());
+        }
+        Ok(())
+    }
+
     fn get_valid_statsd_storage_types() -> Vec<MetricsStorage> {
         vec![
             MetricsStorage::KEY_ENTRY,
@@ -0,0 +1,157 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements shadow writes, the safety net for changes of the stored blob format,
+//! e.g., compression or column encryption. While a new `BlobEncoding` soaks, every blob that is
+//! written to `blobentry` is also written in the new encoding to `blobshadow`, and every blob
+//! that is read from `blobentry` is compared with its decoded shadow. The old representation
+//! stays authoritative: a mismatch is logged, counted, and reported to DropBox, but it never
+//! fails the read or the write. Once the mismatch count stays at zero for the soak period, the
+//! old path can be retired.
+//!
+//! Each shadow blob records the id of the encoding that wrote it, so that blobs written by an
+//! earlier candidate are skipped rather than reported.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::ks_err;
+use crate::post_mortem::{self, FatalEvent};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use rusqlite::{params, OptionalExtension, Transaction};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// A candidate representation of stored blobs.
+pub trait BlobEncoding: Send + Sync {
+    /// Identifies the encoding. It is stored with every shadow blob.
+    fn id(&self) -> &'static str;
+    /// Encodes `blob` for storage.
+    fn encode(&self, blob: &[u8]) -> Result<Vec<u8>>;
+    /// Decodes a stored blob that was encoded with `encode`.
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>>;
+}
+
+lazy_static! {
+    /// The encoding that is soaking, if any.
+    static ref ACTIVE: RwLock<Option<Arc<dyn BlobEncoding>>> = RwLock::new(None);
+}
+
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+static VERIFIED: AtomicU64 = AtomicU64::new(0);
+static MISMATCHED: AtomicU64 = AtomicU64::new(0);
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Starts shadow writes with `encoding`, or stops them if it is None. Shadow blobs that were
+/// already written are kept, so that a soak can be resumed.
+pub fn set_active(encoding: Option<Arc<dyn BlobEncoding>>) {
+    if let Some(encoding) = &encoding {
+        log::info!("Starting shadow writes with blob encoding {}.", encoding.id());
+    }
+    *ACTIVE.write().unwrap() = encoding;
+}
+
+fn active() -> Option<Arc<dyn BlobEncoding>> {
+    ACTIVE.read().unwrap().clone()
+}
+
+/// Returns the number of shadow blobs written, verified, mismatched, and skipped since the
+/// service started.
+pub fn stats() -> (u64, u64, u64, u64) {
+    (
+        WRITTEN.load(Ordering::Relaxed),
+        VERIFIED.load(Ordering::Relaxed),
+        MISMATCHED.load(Ordering::Relaxed),
+        SKIPPED.load(Ordering::Relaxed),
+    )
+}
+
+fn report_mismatch(blob_id: i64, encoding: &dyn BlobEncoding, reason: &str) {
+    log::error!("Shadow of blob {} in encoding {} {}.", blob_id, encoding.id(), reason);
+    MISMATCHED.fetch_add(1, Ordering::Relaxed);
+    post_mortem::report(FatalEvent::ShadowBlobMismatch, "blobshadow", &[("blob_id", blob_id)]);
+}
+
+/// Writes the shadow of `blob`, which was just stored as blob `blob_id`, if an encoding is
+/// soaking.
+pub(super) fn write(tx: &Transaction, blob_id: i64, blob: &[u8]) -> Result<()> {
+    if let Some(encoding) = active() {
+        match encoding.encode(blob) {
+            Ok(encoded) => {
+                tx.execute(
+                    "INSERT OR REPLACE INTO persistent.blobshadow (blobentryid, encoding, blob)
+                     VALUES (?, ?, ?);",
+                    params![blob_id, encoding.id(), encoded],
+                )
+                .context(ks_err!("Failed to insert shadow blob."))?;
+                WRITTEN.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => report_mismatch(blob_id, encoding.as_ref(), &format!("failed: {:?}", e)),
+        }
+    }
+    Ok(())
+}
+
+/// Compares `blob`, which was just read as blob `blob_id`, with its shadow, if an encoding is
+/// soaking and the blob has a shadow in that encoding.
+pub(super) fn verify(tx: &Transaction, blob_id: i64, blob: &[u8]) -> Result<()> {
+    if let Some(encoding) = active() {
+        let shadow: Option<(String, Vec<u8>)> = tx
+            .query_row(
+                "SELECT encoding, blob FROM persistent.blobshadow WHERE blobentryid = ?;",
+                params![blob_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .context(ks_err!("Failed to query shadow blob."))?;
+        match shadow {
+            Some((id, encoded)) if id == encoding.id() => match encoding.decode(&encoded) {
+                Ok(decoded) if decoded == blob => {
+                    VERIFIED.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(_) => report_mismatch(blob_id, encoding.as_ref(), "differs"),
+                Err(e) => report_mismatch(blob_id, encoding.as_ref(), &format!("failed: {:?}", e)),
+            },
+            _ => {
+                SKIPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The dump section of the shadow writes.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "blob_shadow_writes",
+    header: "Blob shadow writes (encoding, written, verified, mismatched, skipped):",
+    line: "  <encoding>, <written>, <verified>, <mismatched>, <skipped>",
+    fields: dump_fields!(
+        encoding: "string",
+        written: "uint64",
+        verified: "uint64",
+        mismatched: "uint64",
+        skipped: "uint64",
+    ),
+};
+
+/// Writes the soaking encoding and the statistics of shadow writes to `writer`.
+pub fn dump(writer: &mut dyn Write) -> std::io::Result<()> {
+    let (written, verified, mismatched, skipped) = stats();
+    DUMP_SECTION.write_header(writer)?;
+    DUMP_SECTION.write_line(
+        writer,
+        &[&active().map_or("none", |e| e.id()), &written, &verified, &mismatched, &skipped],
+    )
+}
@@ -0,0 +1,87 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module exports the structure of the persistent database as an SQL script, which serves
+//! as fuzzer seed and as regression fixture. The script creates the tables and indices of the
+//! database and inserts up to `MAX_ROWS_PER_TABLE` rows of each table, redacted as follows:
+//!
+//! * Integers are kept, because they are ids, enum values, flags, and timestamps that tie the
+//!   rows together.
+//! * Reals become 0.0.
+//! * Texts, e.g., aliases, are replaced by unique placeholders.
+//! * Blobs, e.g., key blobs and certificates, become `randomblob()`s of the same length.
+//!
+//! The script loads into an empty database without an attached schema.
+
+use crate::ks_err;
+use anyhow::{Context, Result};
+use rusqlite::types::ValueRef;
+use rusqlite::Transaction;
+use std::fmt::Write;
+
+/// The maximum number of rows that are exported per table.
+const MAX_ROWS_PER_TABLE: usize = 256;
+
+fn redact(value: ValueRef, texts: &mut usize) -> String {
+    match value {
+        ValueRef::Null => "NULL".to_string(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(_) => "0.0".to_string(),
+        ValueRef::Text(_) => {
+            *texts += 1;
+            format!("'redacted{}'", texts)
+        }
+        // randomblob(0) would return one byte.
+        ValueRef::Blob([]) => "X''".to_string(),
+        ValueRef::Blob(b) => format!("randomblob({})", b.len()),
+    }
+}
+
+/// Returns the redacted SQL script of the persistent database.
+pub(super) fn export(tx: &Transaction) -> Result<String> {
+    let mut script = String::new();
+    let mut texts = 0;
+    let objects: Vec<(String, String, String)> = tx
+        .prepare(
+            "SELECT type, name, sql FROM persistent.sqlite_master
+             WHERE sql NOT NULL AND name NOT LIKE 'sqlite_%'
+             ORDER BY type != 'table', name;",
+        )
+        .context(ks_err!("Failed to prepare schema query."))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .context(ks_err!("Failed to query schema."))?
+        .collect::<rusqlite::Result<_>>()
+        .context(ks_err!("Failed to read schema."))?;
+    for (_, _, sql) in &objects {
+        writeln!(script, "{};", sql).unwrap();
+    }
+    for (_, table, _) in objects.iter().filter(|(kind, _, _)| kind == "table") {
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT * FROM persistent.\"{}\" LIMIT {};",
+                table, MAX_ROWS_PER_TABLE
+            ))
+            .context(ks_err!("Failed to prepare query of {}.", table))?;
+        let columns = stmt.column_count();
+        let mut rows = stmt.query([]).context(ks_err!("Failed to query {}.", table))?;
+        while let Some(row) = rows.next().context(ks_err!("Failed to read {}.", table))? {
+            let values = (0..columns)
+                .map(|i| row.get_ref(i).map(|v| redact(v, &mut texts)))
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context(ks_err!("Failed to read a row of {}.", table))?;
+            writeln!(script, "INSERT INTO \"{}\" VALUES ({});", table, values.join(", ")).unwrap();
+        }
+    }
+    Ok(script)
+}
@@ -51,6 +51,39 @@ where
     }
 }
 
+/// Prepares SQL statement text for logging. String and blob literals are replaced with `'?'`,
+/// so that values spelled out in the statement are not logged, and runs of whitespace are
+/// collapsed into a single space.
+pub fn redact_sql(sql: &str) -> String {
+    let mut redacted = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // Skip to the closing quote. Quotes inside a literal are escaped by doubling.
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                        }
+                        Some('\'') | None => break,
+                        Some(_) => {}
+                    }
+                }
+                redacted.push_str("'?'");
+            }
+            c if c.is_whitespace() => {
+                while chars.peek().map_or(false, |c| c.is_whitespace()) {
+                    chars.next();
+                }
+                redacted.push(' ');
+            }
+            c => redacted.push(c),
+        }
+    }
+    redacted.trim().to_string()
+}
+
 /// This struct is defined to postpone converting rusqlite column value to the
 /// appropriate key parameter value until we know the corresponding tag value.
 /// Wraps the column index and a rusqlite row.
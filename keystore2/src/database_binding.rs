@@ -0,0 +1,370 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module binds the persistent database to the device, so that a database file that was
+//! copied from another device is not accepted.
+//!
+//! The binding is a MAC over a random nonce, both of which are stored in the `databasebinding`
+//! table. The MAC is computed with an HMAC key of the TEE KeyMint instance. Its key blob is
+//! stored as internal key in the database itself. Key blobs are bound to the device that
+//! created them, so on another device KeyMint rejects the key blob.
+//!
+//! A database without binding is bound when keystore starts. This includes databases that were
+//! created before the binding was introduced. Only a MAC that differs from the stored one,
+//! although KeyMint accepted the binding key, makes keystore enter recovery: the database files
+//! are set aside with the suffix `UNBOUND_SUFFIX`, keystore starts over with an empty database,
+//! and the event is logged, reported post-mortem, and dumped.
+//!
+//! KeyMint also rejects the key blobs of the same device, e.g., after a rollback of the patch
+//! level, or after KeyMint was reset. So if KeyMint rejects the binding key, or if the binding
+//! cannot be checked at all, e.g., because KeyMint is not reachable, the database is used and
+//! checked again on the next start. If the binding key had to be replaced, e.g., because it was
+//! created by a software fallback while the TEE was not available, the database is bound to the
+//! new key.
+
+use crate::database::{KeyType, KeystoreDB};
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::error::{Error, ErrorCode};
+use crate::feature_flags::{self, Feature};
+use crate::key_parameter::KeyParameterValue;
+use crate::ks_err;
+use crate::post_mortem::{self, FatalEvent};
+use crate::raw_device::KeyMintDevice;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, KeyParameter::KeyParameter as KmKeyParameter,
+    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::generate_salt;
+use lazy_static::lazy_static;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Alias of the internal HMAC key that computes the binding.
+const BINDING_KEY_ALIAS: &str = "database_binding_key";
+
+/// Prefix of the input of the binding MAC, followed by the nonce.
+const MAC_LABEL: &[u8] = b"keystore2 database binding";
+
+/// Suffix of the names of database files that were set aside, because they were not bound to
+/// this device.
+pub const UNBOUND_SUFFIX: &str = ".unbound";
+
+/// The outcome of the binding check when keystore started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingState {
+    /// The binding was not checked, because the feature is disabled.
+    Unchecked,
+    /// The database was bound to this device.
+    Verified,
+    /// The database was not bound yet, and was bound to this device now.
+    Bound,
+    /// The binding key was replaced, and the database was bound to the new key.
+    Rebound,
+    /// The binding could not be checked, or KeyMint rejected the binding key. The database is
+    /// in use.
+    Unverified,
+    /// The MAC of the binding key differed from the stored one. The database was set aside.
+    Recovered,
+}
+
+/// The outcome of computing the binding MAC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MacOutcome {
+    /// The binding key in the database computed the MAC.
+    Computed(Vec<u8>),
+    /// The binding key was missing or outdated, and a new binding key computed the MAC.
+    Regenerated(Vec<u8>),
+    /// KeyMint rejected the key blob of the binding key.
+    KeyRejected,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<BindingState> = Mutex::new(BindingState::Unchecked);
+}
+
+/// Computes the binding MAC over `nonce` with the binding key of the TEE KeyMint instance.
+fn tee_mac(db: &mut KeystoreDB, nonce: &[u8]) -> Result<MacOutcome> {
+    let km_dev = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+        .context(ks_err!("Get TEE instance failed."))?;
+    let params: Vec<KmKeyParameter> = vec![
+        KeyParameterValue::Algorithm(Algorithm::HMAC).into(),
+        KeyParameterValue::Digest(Digest::SHA_2_256).into(),
+        KeyParameterValue::KeySize(256).into(),
+        KeyParameterValue::MinMacLength(256).into(),
+        KeyParameterValue::KeyPurpose(KeyPurpose::SIGN).into(),
+        KeyParameterValue::NoAuthRequired.into(),
+    ];
+    let key_desc = KeyMintDevice::internal_descriptor(BINDING_KEY_ALIAS.to_string());
+    let security_level = km_dev.security_level();
+    let previous_key_id = KeyMintDevice::lookup_key_id(db, &key_desc, KeyType::Client)
+        .context(ks_err!("Failed to look up the binding key."))?;
+    let mac = km_dev
+        .lookup_or_generate_key(db, &key_desc, KeyType::Client, &params, |characteristics| {
+            characteristics.iter().any(|c| c.securityLevel == security_level)
+        })
+        .context(ks_err!("lookup_or_generate_key failed."))
+        .and_then(|(key_id_guard, key_blob)| {
+            let mut input = MAC_LABEL.to_vec();
+            input.extend_from_slice(nonce);
+            let params = [
+                KeyParameterValue::MacLength(256).into(),
+                KeyParameterValue::Digest(Digest::SHA_2_256).into(),
+            ];
+            let mac = km_dev
+                .use_key_in_one_step(
+                    db,
+                    &key_id_guard,
+                    &key_blob,
+                    KeyPurpose::SIGN,
+                    &params,
+                    None,
+                    &input,
+                )
+                .context(ks_err!("use_key_in_one_step failed."))?;
+            Ok((key_id_guard.id(), mac))
+        });
+    match mac {
+        Ok((key_id, mac)) if Some(key_id) == previous_key_id => Ok(MacOutcome::Computed(mac)),
+        Ok((_, mac)) => Ok(MacOutcome::Regenerated(mac)),
+        Err(e) => match e.root_cause().downcast_ref::<Error>() {
+            Some(Error::Km(ErrorCode::INVALID_KEY_BLOB)) => Ok(MacOutcome::KeyRejected),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Verifies the binding of `db`, or binds it if it is not bound yet. `compute_mac` computes the
+/// binding MAC over a nonce.
+fn check<F>(db: &mut KeystoreDB, compute_mac: F) -> Result<BindingState>
+where
+    F: FnOnce(&mut KeystoreDB, &[u8]) -> Result<MacOutcome>,
+{
+    let (nonce, stored_mac) = match db.load_database_binding().context(ks_err!())? {
+        Some((nonce, stored_mac)) => (nonce, Some(stored_mac)),
+        None => (generate_salt().context(ks_err!("Failed to generate nonce."))?, None),
+    };
+    let (mac, state) = match (compute_mac(db, &nonce).context(ks_err!())?, stored_mac) {
+        (MacOutcome::KeyRejected, _) => return Ok(BindingState::Unverified),
+        (MacOutcome::Computed(mac), Some(stored_mac)) => {
+            return Ok(if mac == stored_mac {
+                BindingState::Verified
+            } else {
+                BindingState::Recovered
+            });
+        }
+        (MacOutcome::Computed(mac), None) | (MacOutcome::Regenerated(mac), None) => {
+            (mac, BindingState::Bound)
+        }
+        (MacOutcome::Regenerated(mac), Some(_)) => (mac, BindingState::Rebound),
+    };
+    db.store_database_binding(&nonce, &mac).context(ks_err!())?;
+    Ok(state)
+}
+
+/// Renames the files of the persistent database in `db_root`, so that the next connection
+/// creates a new database. The write-ahead log and the shared memory index keep their suffix,
+/// so that the database that was set aside can still be opened.
+fn set_aside(db_root: &Path) -> Result<()> {
+    for suffix in ["-wal", "-shm", ""] {
+        let name = format!("{}{}", KeystoreDB::PERSISTENT_DB_FILENAME, suffix);
+        let path = db_root.join(&name);
+        if !path.exists() {
+            continue;
+        }
+        let unbound = db_root.join(format!(
+            "{}{}{}",
+            KeystoreDB::PERSISTENT_DB_FILENAME,
+            UNBOUND_SUFFIX,
+            suffix
+        ));
+        std::fs::rename(&path, &unbound).context(ks_err!("Failed to set aside {name}."))?;
+    }
+    Ok(())
+}
+
+/// Checks that the persistent database in `db_root` is bound to this device, and sets it aside
+/// if it is not. This must be called when keystore starts, before any other connection to the
+/// database is opened.
+pub fn verify_or_recover(db_root: &Path) {
+    if !feature_flags::is_enabled(Feature::DatabaseBinding) {
+        return;
+    }
+    *STATE.lock().unwrap() = verify_or_recover_with(db_root, tee_mac);
+}
+
+fn verify_or_recover_with<F>(db_root: &Path, compute_mac: F) -> BindingState
+where
+    F: FnOnce(&mut KeystoreDB, &[u8]) -> Result<MacOutcome>,
+{
+    let result = KeystoreDB::new(db_root, None)
+        .context(ks_err!("Failed to open database."))
+        .and_then(|mut db| check(&mut db, compute_mac));
+    match result {
+        Ok(BindingState::Recovered) => {
+            log::error!(
+                "The database is not bound to this device. Starting over with a new database, \
+                 the old one is kept with the suffix {}.",
+                UNBOUND_SUFFIX
+            );
+            post_mortem::report(FatalEvent::DatabaseBindingMismatch, "databasebinding", &[]);
+            match set_aside(db_root) {
+                Ok(()) => BindingState::Recovered,
+                Err(e) => {
+                    // Keys in the database cannot be used on this device in any case.
+                    log::error!("Failed to set aside the unbound database: {:?}", e);
+                    BindingState::Unverified
+                }
+            }
+        }
+        Ok(BindingState::Unverified) => {
+            log::warn!("KeyMint rejected the database binding key, keeping the database.");
+            BindingState::Unverified
+        }
+        Ok(state) => {
+            log::info!("Database binding: {:?}", state);
+            state
+        }
+        Err(e) => {
+            log::error!("Failed to check the database binding: {:?}", e);
+            BindingState::Unverified
+        }
+    }
+}
+
+/// Returns the outcome of the binding check when keystore started.
+pub fn state() -> BindingState {
+    *STATE.lock().unwrap()
+}
+
+/// The dump section of the binding check.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "database_binding",
+    header: "Database binding:",
+    line: "  <state>",
+    fields: dump_fields!(state: "string"),
+};
+
+/// Writes the outcome of the binding check to `writer`.
+pub fn dump(writer: &mut dyn Write) -> std::io::Result<()> {
+    DUMP_SECTION.write_header(writer)?;
+    DUMP_SECTION.write_line(writer, &[&format!("{:?}", state())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_test_utils::TempDir;
+
+    fn boot(db_root: &Path, outcome: MacOutcome) -> BindingState {
+        verify_or_recover_with(db_root, |_, _| Ok(outcome))
+    }
+
+    fn database_exists(db_root: &Path) -> bool {
+        db_root.join(KeystoreDB::PERSISTENT_DB_FILENAME).exists()
+    }
+
+    #[test]
+    fn patch_level_rollback_keeps_database() -> Result<()> {
+        let temp_dir = TempDir::new("database_binding_rollback_test")?;
+        let db_root = temp_dir.path();
+        assert_eq!(BindingState::Bound, boot(db_root, MacOutcome::Regenerated(b"mac".to_vec())));
+
+        // The binding key was upgraded to the newer patch level, so KeyMint rejects it after
+        // the rollback.
+        assert_eq!(BindingState::Unverified, boot(db_root, MacOutcome::KeyRejected));
+        assert!(database_exists(db_root));
+
+        // The binding is unchanged, so it verifies once the patch level is restored.
+        assert_eq!(BindingState::Verified, boot(db_root, MacOutcome::Computed(b"mac".to_vec())));
+        Ok(())
+    }
+
+    #[test]
+    fn tee_restored_after_software_fallback_rebinds_database() -> Result<()> {
+        let temp_dir = TempDir::new("database_binding_fallback_test")?;
+        let db_root = temp_dir.path();
+        // km_compat fell back to software, which created the binding key.
+        assert_eq!(
+            BindingState::Bound,
+            boot(db_root, MacOutcome::Regenerated(b"software mac".to_vec()))
+        );
+
+        // The key of the software fallback is not a TEE key, so it is replaced once the TEE is
+        // back. The MAC of the new key differs, but the database is not set aside.
+        assert_eq!(
+            BindingState::Rebound,
+            boot(db_root, MacOutcome::Regenerated(b"tee mac".to_vec()))
+        );
+        assert!(database_exists(db_root));
+        assert_eq!(
+            BindingState::Verified,
+            boot(db_root, MacOutcome::Computed(b"tee mac".to_vec()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keymint_reset_keeps_database() -> Result<()> {
+        let temp_dir = TempDir::new("database_binding_reset_test")?;
+        let db_root = temp_dir.path();
+        assert_eq!(BindingState::Bound, boot(db_root, MacOutcome::Regenerated(b"mac".to_vec())));
+
+        // After the reset, KeyMint rejects all key blobs that it created before.
+        for _ in 0..2 {
+            assert_eq!(BindingState::Unverified, boot(db_root, MacOutcome::KeyRejected));
+            assert!(database_exists(db_root));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mac_mismatch_sets_database_aside() -> Result<()> {
+        let temp_dir = TempDir::new("database_binding_mismatch_test")?;
+        let db_root = temp_dir.path();
+        assert_eq!(BindingState::Bound, boot(db_root, MacOutcome::Regenerated(b"mac".to_vec())));
+
+        assert_eq!(
+            BindingState::Recovered,
+            boot(db_root, MacOutcome::Computed(b"other mac".to_vec()))
+        );
+        assert!(!database_exists(db_root));
+        assert!(db_root.join("persistent.sqlite.unbound").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn set_aside_keeps_database_files_together() -> Result<()> {
+        let temp_dir = TempDir::new("database_binding_test")?;
+        let db_path = temp_dir.path().join(KeystoreDB::PERSISTENT_DB_FILENAME);
+        std::fs::write(&db_path, b"database")?;
+        std::fs::write(temp_dir.path().join("persistent.sqlite-wal"), b"log")?;
+
+        set_aside(temp_dir.path())?;
+        assert!(!db_path.exists());
+        assert!(!temp_dir.path().join("persistent.sqlite-wal").exists());
+        assert_eq!(
+            b"database".to_vec(),
+            std::fs::read(temp_dir.path().join("persistent.sqlite.unbound"))?
+        );
+        assert_eq!(
+            b"log".to_vec(),
+            std::fs::read(temp_dir.path().join("persistent.sqlite.unbound-wal"))?
+        );
+        assert!(!temp_dir.path().join("persistent.sqlite.unbound-shm").exists());
+        Ok(())
+    }
+}
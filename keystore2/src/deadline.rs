@@ -0,0 +1,102 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements deadlines that clients pass along with a call. A client that gives
+//! up on a call after its own timeout has no use for the result, so keystore checks the deadline
+//! before each expensive stage of the call and fails with `DEADLINE_EXCEEDED` instead of
+//! finishing the work. This is not `BACKEND_BUSY`, because clients and retry policies retry
+//! calls that fail with `BACKEND_BUSY`, while the client has already given up on this one.
+//!
+//! Clients pass a timeout relative to the time of the call rather than an absolute point in
+//! time, so that client and keystore do not need to agree on a clock.
+
+use crate::error::{Error, DEADLINE_EXCEEDED};
+use crate::ks_err;
+use anyhow::{Context, Result};
+use std::time::{Duration, Instant};
+
+/// The point in time after which the client no longer waits for the result of a call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// A deadline that is never exceeded. Used for calls without a client deadline.
+    pub fn none() -> Self {
+        Self(None)
+    }
+
+    /// Creates a deadline `timeout_millis` from now. A timeout of zero or less means that the
+    /// client did not set a deadline.
+    pub fn from_timeout_millis(timeout_millis: i64) -> Self {
+        match u64::try_from(timeout_millis) {
+            Ok(millis) if millis > 0 => {
+                Self(Instant::now().checked_add(Duration::from_millis(millis)))
+            }
+            _ => Self::none(),
+        }
+    }
+
+    /// True if the deadline has passed.
+    pub fn is_exceeded(&self) -> bool {
+        self.0.map_or(false, |deadline| Instant::now() >= deadline)
+    }
+
+    /// Fails with `DEADLINE_EXCEEDED` if the deadline has passed. This is for closures that
+    /// return a plain `Error`, e.g., KeyMint calls that are retried after a key upgrade.
+    pub fn error_if_exceeded(&self) -> Result<(), Error> {
+        if self.is_exceeded() {
+            Err(Error::Rc(DEADLINE_EXCEEDED))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails with `DEADLINE_EXCEEDED` if the deadline has passed. `stage` names the work that is
+    /// skipped because of it.
+    pub fn check(&self, stage: &str) -> Result<()> {
+        self.error_if_exceeded().context(ks_err!("Deadline exceeded before {}.", stage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_deadline_is_never_exceeded() {
+        for deadline in
+            [Deadline::none(), Deadline::from_timeout_millis(0), Deadline::from_timeout_millis(-5)]
+        {
+            assert_eq!(Deadline::none(), deadline);
+            assert!(!deadline.is_exceeded());
+            assert!(deadline.check("test").is_ok());
+            assert_eq!(Ok(()), deadline.error_if_exceeded());
+        }
+    }
+
+    #[test]
+    fn deadline_is_exceeded_after_timeout() {
+        let deadline = Deadline::from_timeout_millis(200);
+        assert!(!deadline.is_exceeded());
+        assert!(deadline.check("test").is_ok());
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(deadline.is_exceeded());
+        assert_eq!(Err(Error::Rc(DEADLINE_EXCEEDED)), deadline.error_if_exceeded());
+        assert_eq!(
+            Some(&Error::Rc(DEADLINE_EXCEEDED)),
+            deadline.check("test").unwrap_err().root_cause().downcast_ref::<Error>()
+        );
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module warns app developers about weak cryptography before it stops working. The
+//! `DEPRECATIONS` table lists the deprecated uses of key parameters, e.g., MD5 digests, along
+//! with an identifier of the timeline of their removal. Whenever an app generates or imports a
+//! key or begins an operation with a deprecated parameter, keystore records an event, which the
+//! app can retrieve with `IKeystoreServiceExtension::getDeprecationWarnings` and which shows in
+//! the service dump.
+//!
+//! The events are kept in memory for the `MAX_EVENTS` most recent pairs of uid and deprecation,
+//! so they start over when keystore restarts.
+
+use crate::database::DateTime;
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::key_parameter::{KeyParameter as KsKeyParam, KeyParameterValue};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, KeyParameter::KeyParameter,
+};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// The number of pairs of uid and deprecation whose events are kept.
+const MAX_EVENTS: usize = 256;
+
+/// A deprecated use of key parameters.
+#[derive(Debug)]
+pub struct Deprecation {
+    /// Identifies the deprecation.
+    pub id: &'static str,
+    /// Identifies the timeline after which the use stops working.
+    pub removal: &'static str,
+    /// Returns true if the given parameters of a key or an operation make the deprecated use.
+    uses: fn(&[KeyParameterValue]) -> bool,
+}
+
+/// The deprecated uses of key parameters.
+pub const DEPRECATIONS: &[Deprecation] = &[
+    Deprecation {
+        id: "digest_md5",
+        removal: "2027Q1",
+        uses: |params| params.contains(&KeyParameterValue::Digest(Digest::MD5)),
+    },
+    Deprecation {
+        id: "algorithm_3des",
+        removal: "2027Q1",
+        uses: |params| params.contains(&KeyParameterValue::Algorithm(Algorithm::TRIPLE_DES)),
+    },
+    Deprecation {
+        id: "rsa_1024",
+        removal: "2027Q3",
+        uses: |params| {
+            params.contains(&KeyParameterValue::Algorithm(Algorithm::RSA))
+                && params.iter().any(|p| matches!(p, KeyParameterValue::KeySize(s) if *s <= 1024))
+        },
+    },
+];
+
+/// The uses of a deprecation by an app.
+#[derive(Clone, Debug)]
+pub struct DeprecationEvent {
+    /// The uid of the app.
+    pub uid: u32,
+    /// The deprecation that the app used.
+    pub deprecation: &'static Deprecation,
+    /// The number of uses.
+    pub count: u64,
+    /// The time of the last use in milliseconds since the epoch.
+    pub last_used: i64,
+}
+
+/// The dump section of the uses.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "deprecated_parameter_uses",
+    header: "Deprecated parameter uses (uid, deprecation, removal, count, last used):",
+    line: "  <uid>, <deprecation>, <removal>, <count>, <last_used>",
+    fields: dump_fields!(
+        uid: "uint64",
+        deprecation: "string",
+        removal: "string",
+        count: "uint64",
+        last_used: "int64",
+    ),
+};
+
+/// Bounded table of the uses of deprecated key parameters.
+#[derive(Default)]
+pub struct DeprecationEvents {
+    /// The events ordered from least to most recently updated.
+    events: Mutex<Vec<DeprecationEvent>>,
+}
+
+impl DeprecationEvents {
+    fn record(&self, uid: u32, params: &[KeyParameterValue]) {
+        let now = DateTime::now().map_or(0, |t| t.to_millis_epoch());
+        self.record_at(uid, params, now);
+    }
+
+    fn record_at(&self, uid: u32, params: &[KeyParameterValue], now: i64) {
+        let mut events = self.events.lock().unwrap();
+        for deprecation in DEPRECATIONS.iter().filter(|d| (d.uses)(params)) {
+            let mut event = match events
+                .iter()
+                .position(|e| e.uid == uid && e.deprecation.id == deprecation.id)
+            {
+                Some(pos) => events.remove(pos),
+                None => {
+                    log::warn!(
+                        "Uid {} uses {}, which stops working after {}.",
+                        uid,
+                        deprecation.id,
+                        deprecation.removal
+                    );
+                    DeprecationEvent { uid, deprecation, count: 0, last_used: now }
+                }
+            };
+            event.count += 1;
+            event.last_used = now;
+            if events.len() >= MAX_EVENTS {
+                events.remove(0);
+            }
+            events.push(event);
+        }
+    }
+
+    /// Records the deprecated uses of `uid` in the parameters of a key that it generates or
+    /// imports.
+    pub fn on_key_creation(&self, uid: u32, params: &[KeyParameter]) {
+        let params: Vec<KeyParameterValue> = params.iter().map(KeyParameterValue::from).collect();
+        self.record(uid, &params);
+    }
+
+    /// Records the deprecated uses of `uid` in an operation with the operation parameters
+    /// `op_params` and the key parameters `key_params`. Only the algorithm and the size of the
+    /// key are considered, because the other key parameters are only authorized, not used.
+    pub fn on_begin(
+        &self,
+        uid: u32,
+        key_params: Option<&[KsKeyParam]>,
+        op_params: &[KeyParameter],
+    ) {
+        let mut params: Vec<KeyParameterValue> = key_params
+            .unwrap_or_default()
+            .iter()
+            .map(|p| p.key_parameter_value())
+            .filter(|v| {
+                matches!(v, KeyParameterValue::Algorithm(_) | KeyParameterValue::KeySize(_))
+            })
+            .cloned()
+            .collect();
+        params.extend(op_params.iter().map(KeyParameterValue::from));
+        self.record(uid, &params);
+    }
+
+    /// Returns the events of `uid`, most recently updated first.
+    pub fn get(&self, uid: u32) -> Vec<DeprecationEvent> {
+        self.events.lock().unwrap().iter().rev().filter(|e| e.uid == uid).cloned().collect()
+    }
+
+    /// Writes the events of all apps to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        DUMP_SECTION.write_header(writer)?;
+        let mut events = self.events.lock().unwrap().clone();
+        events.sort_by_key(|e| (e.uid, e.deprecation.id));
+        for e in events {
+            DUMP_SECTION.write_line(
+                writer,
+                &[&e.uid, &e.deprecation.id, &e.deprecation.removal, &e.count, &e.last_used],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UID: u32 = 10001;
+
+    #[test]
+    fn records_deprecated_uses() {
+        let events = DeprecationEvents::default();
+        let rsa =
+            |size| [KeyParameterValue::Algorithm(Algorithm::RSA), KeyParameterValue::KeySize(size)];
+        events.record_at(UID, &rsa(2048), 1);
+        assert!(events.get(UID).is_empty());
+
+        events.record_at(UID, &rsa(1024), 2);
+        events.record_at(UID, &rsa(1024), 3);
+        events.record_at(UID, &[KeyParameterValue::Digest(Digest::MD5)], 4);
+        events.record_at(UID + 1, &[KeyParameterValue::Algorithm(Algorithm::TRIPLE_DES)], 5);
+
+        let mine = events.get(UID);
+        assert_eq!(2, mine.len());
+        assert_eq!(
+            ("digest_md5", 1, 4),
+            (mine[0].deprecation.id, mine[0].count, mine[0].last_used)
+        );
+        assert_eq!(("rsa_1024", 2, 3), (mine[1].deprecation.id, mine[1].count, mine[1].last_used));
+
+        let mut out = Vec::new();
+        events.dump(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with(
+            "\n  10001, rsa_1024, 2027Q3, 2, 3\n  10002, algorithm_3des, 2027Q1, 1, 5\n"
+        ));
+    }
+
+    #[test]
+    fn deprecation_ids_are_unique() {
+        for (i, d) in DEPRECATIONS.iter().enumerate() {
+            assert!(DEPRECATIONS[i + 1..].iter().all(|other| other.id != d.id), "{}", d.id);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module encodes a message hash as the DER `DigestInfo` structure of an RSASSA-PKCS1-v1_5
+//! signature, see RFC 8017, section 9.2. KeyMint signs whatever it is given with
+//! `Digest::NONE` and `PaddingMode::RSA_PKCS1_1_5_SIGN`, so clients that sign a precomputed
+//! hash this way have to do the encoding themselves, which is easy to get wrong.
+
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::Digest::Digest;
+use anyhow::{Context, Result};
+
+/// Returns the DER encoding of the `DigestInfo` sequence up to the hash value, and the length
+/// of the hash, for the given digest. The prefixes are taken from RFC 8017, section 9.2,
+/// note 1.
+fn prefix_and_hash_len(digest: Digest) -> Option<(&'static [u8], usize)> {
+    match digest {
+        Digest::MD5 => Some((
+            &[
+                0x30, 0x20, 0x30, 0x0c, 0x06, 0x08, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x05,
+                0x05, 0x00, 0x04, 0x10,
+            ],
+            16,
+        )),
+        Digest::SHA1 => Some((
+            &[
+                0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04,
+                0x14,
+            ],
+            20,
+        )),
+        Digest::SHA_2_224 => Some((
+            &[
+                0x30, 0x2d, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x04, 0x05, 0x00, 0x04, 0x1c,
+            ],
+            28,
+        )),
+        Digest::SHA_2_256 => Some((
+            &[
+                0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x01, 0x05, 0x00, 0x04, 0x20,
+            ],
+            32,
+        )),
+        Digest::SHA_2_384 => Some((
+            &[
+                0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x02, 0x05, 0x00, 0x04, 0x30,
+            ],
+            48,
+        )),
+        Digest::SHA_2_512 => Some((
+            &[
+                0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x03, 0x05, 0x00, 0x04, 0x40,
+            ],
+            64,
+        )),
+        _ => None,
+    }
+}
+
+/// Encodes `hash`, which was computed with `digest`, as `DigestInfo`. Fails with
+/// `INVALID_ARGUMENT` if `digest` is `Digest::NONE` or unknown, or if `hash` does not have
+/// the length of a `digest` hash.
+pub fn encode(digest: Digest, hash: &[u8]) -> Result<Vec<u8>> {
+    let (prefix, hash_len) = prefix_and_hash_len(digest)
+        .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        .context(ks_err!("Unsupported digest {:?}.", digest))?;
+    if hash.len() != hash_len {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+            "A {:?} hash has {} bytes, but {} were given.",
+            digest,
+            hash_len,
+            hash.len()
+        ));
+    }
+    Ok([prefix, hash].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_sha256_digest_info() {
+        let hash = [0xab; 32];
+        let encoded = encode(Digest::SHA_2_256, &hash).unwrap();
+        assert_eq!(51, encoded.len());
+        // The outer sequence covers the rest of the encoding.
+        assert_eq!([0x30, encoded.len() as u8 - 2], encoded[..2]);
+        assert_eq!(hash, encoded[19..]);
+    }
+
+    #[test]
+    fn prefixes_match_hash_lengths() {
+        for digest in [
+            Digest::MD5,
+            Digest::SHA1,
+            Digest::SHA_2_224,
+            Digest::SHA_2_256,
+            Digest::SHA_2_384,
+            Digest::SHA_2_512,
+        ] {
+            let (prefix, hash_len) = prefix_and_hash_len(digest).unwrap();
+            assert_eq!(prefix.len() + hash_len - 2, prefix[1] as usize, "{:?}", digest);
+            assert_eq!(hash_len, *prefix.last().unwrap() as usize, "{:?}", digest);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let is_invalid_argument = |r: Result<Vec<u8>>| {
+            matches!(
+                r.unwrap_err().root_cause().downcast_ref::<Error>(),
+                Some(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            )
+        };
+        assert!(is_invalid_argument(encode(Digest::NONE, &[0; 32])));
+        assert!(is_invalid_argument(encode(Digest::SHA_2_256, &[0; 20])));
+    }
+}
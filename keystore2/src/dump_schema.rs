@@ -0,0 +1,502 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module describes the layout of the Keystore 2.0 dump and of the metrics atoms that
+//! keystore reports, so that tools can parse them without guessing. `dumpsys
+//! android.system.keystore2.IKeystoreService/default --schema` writes the description as JSON
+//! instead of the dump.
+//!
+//! Every dump section starts with its `header` line and is followed by one line per entry in
+//! the format given by `line`. Each section is defined next to the code that dumps it, which
+//! writes the header and the entry lines with `DumpSection::write_header` and
+//! `DumpSection::write_line`, so the dump cannot drift from its description. `SCHEMA_VERSION`
+//! must be incremented whenever a section, atom, or field changes in a way that existing parsers
+//! would misread.
+
+use crate::anomaly_detector;
+use crate::database::shadow;
+use crate::database_binding;
+use crate::deprecation;
+use crate::feature_flags;
+use crate::grant_cache;
+use crate::hal_health;
+use crate::key_material_cache;
+use crate::key_operation_stats;
+use crate::lock_stats;
+use crate::patch_level_policy;
+use crate::service;
+use crate::shared_secret_negotiation;
+use crate::strongbox_budget;
+use crate::super_key;
+use crate::user_limits;
+use android_security_metrics::aidl::android::security::metrics::AtomID::AtomID;
+use std::fmt::Display;
+use std::io::Write;
+
+/// The version of the dump and metrics layout described by this module.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// A named value within a dump line or a metrics atom.
+pub struct Field {
+    /// The name of the field.
+    pub name: &'static str,
+    /// The type of the field, e.g., `int64`, `string`, or `enum:Domain`.
+    pub kind: &'static str,
+}
+
+/// A section of the dump.
+pub struct DumpSection {
+    /// The machine readable name of the section.
+    pub name: &'static str,
+    /// The line that starts the section, up to the first value if it has one.
+    pub header: &'static str,
+    /// The format of the entry lines of the section. Fields are given as `<field name>`.
+    pub line: &'static str,
+    /// The fields of the entry lines.
+    pub fields: &'static [Field],
+}
+
+/// A metrics atom that keystore reports through `IKeystoreMetrics::pullMetrics`.
+pub struct MetricsAtom {
+    /// The id of the atom.
+    pub atom_id: AtomID,
+    /// The variant of `KeystoreAtomPayload` that the atom is reported with.
+    pub payload: &'static str,
+    /// The fields of the payload.
+    pub fields: &'static [Field],
+}
+
+impl DumpSection {
+    /// Writes the header line of the section.
+    pub fn write_header(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(writer, "{}", self.header)
+    }
+
+    /// Writes an entry line of the section. `values` are the values of the fields in the order
+    /// of `fields`, and replace the placeholders of `line`.
+    pub fn write_line(
+        &self,
+        writer: &mut dyn Write,
+        values: &[&dyn Display],
+    ) -> std::io::Result<()> {
+        debug_assert_eq!(self.fields.len(), values.len(), "{}", self.name);
+        let mut rest = self.line;
+        for (field, value) in self.fields.iter().zip(values) {
+            let placeholder = format!("<{}>", field.name);
+            let (before, after) = rest.split_once(&placeholder).unwrap_or(("", rest));
+            write!(writer, "{}{}", before, value)?;
+            rest = after;
+        }
+        writeln!(writer, "{}", rest)
+    }
+}
+
+/// Lists the fields of a dump section or a metrics atom with their types.
+#[macro_export]
+macro_rules! dump_fields {
+    ($($name:ident: $kind:literal),* $(,)?) => {
+        &[$($crate::dump_schema::Field { name: stringify!($name), kind: $kind }),*]
+    };
+}
+
+/// The sections of the dump in the order in which they are written. Each section is defined
+/// next to the code that writes it.
+pub const DUMP_SECTIONS: &[&DumpSection] = &[
+    &shared_secret_negotiation::PARTICIPANTS_SECTION,
+    &shared_secret_negotiation::NEGOTIATION_SECTION,
+    &database_binding::DUMP_SECTION,
+    &feature_flags::DUMP_SECTION,
+    &lock_stats::DUMP_SECTION,
+    &key_material_cache::DUMP_SECTION,
+    &super_key::DUMP_SECTION,
+    &grant_cache::DUMP_SECTION,
+    &service::KEY_HISTORY_SECTION,
+    &service::PENDING_BLOB_DELETIONS_SECTION,
+    &shadow::DUMP_SECTION,
+    &strongbox_budget::DUMP_SECTION,
+    &key_operation_stats::DUMP_SECTION,
+    &user_limits::DUMP_SECTION,
+    &deprecation::DUMP_SECTION,
+    &anomaly_detector::ANOMALIES_SECTION,
+    &anomaly_detector::FLAGGED_KEYS_SECTION,
+    &patch_level_policy::DUMP_SECTION,
+    &hal_health::DUMP_SECTION,
+];
+
+/// The metrics atoms that keystore reports.
+pub const METRICS_ATOMS: &[MetricsAtom] = &[
+    MetricsAtom {
+        atom_id: AtomID::STORAGE_STATS,
+        payload: "StorageStats",
+        fields: dump_fields!(storage_type: "enum:Storage", size: "int32", unused_size: "int32"),
+    },
+    MetricsAtom {
+        atom_id: AtomID::KEY_CREATION_WITH_GENERAL_INFO,
+        payload: "KeyCreationWithGeneralInfo",
+        fields: dump_fields!(
+            algorithm: "enum:Algorithm",
+            key_size: "int32",
+            ec_curve: "enum:EcCurve",
+            key_origin: "enum:KeyOrigin",
+            error_code: "int32",
+            attestation_requested: "bool",
+        ),
+    },
+    MetricsAtom {
+        atom_id: AtomID::KEY_CREATION_WITH_AUTH_INFO,
+        payload: "KeyCreationWithAuthInfo",
+        fields: dump_fields!(
+            user_auth_type: "enum:HardwareAuthenticatorType",
+            log10_auth_key_timeout_seconds: "int32",
+            security_level: "enum:SecurityLevel",
+        ),
+    },
+    MetricsAtom {
+        atom_id: AtomID::KEY_CREATION_WITH_PURPOSE_AND_MODES_INFO,
+        payload: "KeyCreationWithPurposeAndModesInfo",
+        fields: dump_fields!(
+            algorithm: "enum:Algorithm",
+            purpose_bitmap: "int32",
+            padding_mode_bitmap: "int32",
+            digest_bitmap: "int32",
+            block_mode_bitmap: "int32",
+        ),
+    },
+    MetricsAtom {
+        atom_id: AtomID::KEYSTORE2_ATOM_WITH_OVERFLOW,
+        payload: "Keystore2AtomWithOverflow",
+        fields: dump_fields!(atom_id: "enum:AtomID"),
+    },
+    MetricsAtom {
+        atom_id: AtomID::KEY_OPERATION_WITH_PURPOSE_AND_MODES_INFO,
+        payload: "KeyOperationWithPurposeAndModesInfo",
+        fields: dump_fields!(
+            purpose: "enum:Purpose",
+            padding_mode_bitmap: "int32",
+            digest_bitmap: "int32",
+            block_mode_bitmap: "int32",
+        ),
+    },
+    MetricsAtom {
+        atom_id: AtomID::KEY_OPERATION_WITH_GENERAL_INFO,
+        payload: "KeyOperationWithGeneralInfo",
+        fields: dump_fields!(
+            outcome: "enum:Outcome",
+            error_code: "int32",
+            key_upgraded: "bool",
+            security_level: "enum:SecurityLevel",
+        ),
+    },
+    MetricsAtom {
+        atom_id: AtomID::RKP_ERROR_STATS,
+        payload: "RkpErrorStats",
+        fields: dump_fields!(rkpError: "enum:RkpError", security_level: "enum:SecurityLevel"),
+    },
+    MetricsAtom {
+        atom_id: AtomID::CRASH_STATS,
+        payload: "CrashStats",
+        fields: dump_fields!(count_of_crash_events: "int32"),
+    },
+    MetricsAtom {
+        atom_id: AtomID::USER_UNLOCK_STATS,
+        payload: "UserUnlockStats",
+        fields: dump_fields!(
+            outcome: "enum:UnlockOutcome",
+            log2_kdf_millis: "int32",
+            log2_db_load_millis: "int32",
+        ),
+    },
+    MetricsAtom {
+        atom_id: AtomID::DATABASE_CONSISTENCY_STATS,
+        payload: "DatabaseConsistencyStats",
+        fields: dump_fields!(anomaly: "enum:ConsistencyAnomaly", log2_count: "int32"),
+    },
+];
+
+// All names and types of the sections and atoms are plain ASCII without quotes or backslashes,
+// so they can be written as JSON strings verbatim.
+fn write_fields(writer: &mut dyn Write, fields: &[Field]) -> std::io::Result<()> {
+    write!(writer, "[")?;
+    for (i, field) in fields.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        write!(writer, "{}{{\"name\":\"{}\",\"type\":\"{}\"}}", separator, field.name, field.kind)?;
+    }
+    write!(writer, "]")
+}
+
+/// Writes the description of the dump and of the metrics atoms to `writer` as a JSON object.
+pub fn write_json(writer: &mut dyn Write) -> std::io::Result<()> {
+    write!(writer, "{{\"version\":{},\"dump\":[", SCHEMA_VERSION)?;
+    for (i, section) in DUMP_SECTIONS.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        write!(
+            writer,
+            "{}{{\"name\":\"{}\",\"header\":\"{}\",\"line\":\"{}\",\"fields\":",
+            separator, section.name, section.header, section.line
+        )?;
+        write_fields(writer, section.fields)?;
+        write!(writer, "}}")?;
+    }
+    write!(writer, "],\"metrics\":[")?;
+    for (i, atom) in METRICS_ATOMS.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        write!(
+            writer,
+            "{}{{\"atom_id\":{},\"payload\":\"{}\",\"fields\":",
+            separator, atom.atom_id.0, atom.payload
+        )?;
+        write_fields(writer, atom.fields)?;
+        write!(writer, "}}")?;
+    }
+    writeln!(writer, "]}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anomaly_detector::AnomalyDetector;
+    use crate::deprecation::DeprecationEvents;
+    use crate::grant_cache::GrantCache;
+    use crate::hal_health::{HalHealth, InitRestarter};
+    use crate::key_material_cache::KeyMaterialCache;
+    use crate::key_operation_stats::KeyOperationStats;
+    use crate::patch_level_policy::PatchLevelPolicy;
+    use crate::strongbox_budget::GenerationBudget;
+    use crate::super_key::SuperKeyManager;
+    use crate::user_limits::UserLimits;
+    use android_security_metrics::aidl::android::security::metrics::{
+        Algorithm::Algorithm, ConsistencyAnomaly::ConsistencyAnomaly, CrashStats::CrashStats,
+        DatabaseConsistencyStats::DatabaseConsistencyStats, EcCurve::EcCurve,
+        HardwareAuthenticatorType::HardwareAuthenticatorType,
+        KeyCreationWithAuthInfo::KeyCreationWithAuthInfo,
+        KeyCreationWithGeneralInfo::KeyCreationWithGeneralInfo,
+        KeyCreationWithPurposeAndModesInfo::KeyCreationWithPurposeAndModesInfo,
+        KeyOperationWithGeneralInfo::KeyOperationWithGeneralInfo,
+        KeyOperationWithPurposeAndModesInfo::KeyOperationWithPurposeAndModesInfo,
+        KeyOrigin::KeyOrigin, Keystore2AtomWithOverflow::Keystore2AtomWithOverflow,
+        KeystoreAtomPayload::KeystoreAtomPayload, Outcome::Outcome, Purpose::Purpose,
+        RkpError::RkpError, RkpErrorStats::RkpErrorStats, SecurityLevel::SecurityLevel,
+        Storage::Storage, StorageStats::StorageStats, UnlockOutcome::UnlockOutcome,
+        UserUnlockStats::UserUnlockStats,
+    };
+    use std::collections::HashSet;
+
+    fn dump_header(dump: fn(&mut dyn Write) -> std::io::Result<()>) -> String {
+        let mut out = Vec::new();
+        dump(&mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().next().unwrap().to_string()
+    }
+
+    fn section(name: &str) -> &'static DumpSection {
+        DUMP_SECTIONS.iter().find(|s| s.name == name).copied().unwrap()
+    }
+
+    #[test]
+    fn headers_match_dump() {
+        assert_eq!(
+            section("shared_secret_participants").header,
+            dump_header(shared_secret_negotiation::dump_state)
+        );
+        let mut out = Vec::new();
+        shared_secret_negotiation::dump_state(&mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains(&format!("\n{}\n", section("shared_secret_negotiation").header)));
+        assert_eq!(section("database_binding").header, dump_header(database_binding::dump));
+        assert_eq!(section("feature_flags").header, dump_header(feature_flags::dump));
+        assert_eq!(section("lock_contention").header, dump_header(lock_stats::dump));
+        assert_eq!(section("blob_shadow_writes").header, dump_header(shadow::dump));
+        let mut out = Vec::new();
+        KeyMaterialCache::default().dump(&mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with(&format!("{}\n", section("key_material_cache").header)));
+        let mut out = Vec::new();
+        SuperKeyManager::default().dump(&mut out).unwrap();
+        assert_eq!(
+            format!("{}\n  0, 0, 0\n", section("super_key_cache").header),
+            String::from_utf8(out).unwrap()
+        );
+        let mut out = Vec::new();
+        GrantCache::default().dump(&mut out).unwrap();
+        assert_eq!(
+            format!("{}\n  0, 0, 0, 0\n", section("grant_cache").header),
+            String::from_utf8(out).unwrap()
+        );
+        let mut out = Vec::new();
+        GenerationBudget::default().dump(&mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with(&format!("{}\n", section("strongbox_generation_budget").header)));
+        let mut out = Vec::new();
+        KeyOperationStats::default().dump(&mut out).unwrap();
+        assert_eq!(
+            format!("{}\n", section("key_operation_stats").header),
+            String::from_utf8(out).unwrap()
+        );
+        let mut out = Vec::new();
+        UserLimits::default().dump(&mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with(&format!("{}\n", section("user_key_limits").header)));
+        let mut out = Vec::new();
+        DeprecationEvents::default().dump(&mut out).unwrap();
+        assert_eq!(
+            format!("{}\n", section("deprecated_parameter_uses").header),
+            String::from_utf8(out).unwrap()
+        );
+        let mut out = Vec::new();
+        AnomalyDetector::default().dump(&mut out).unwrap();
+        assert_eq!(
+            format!(
+                "{}\n  false, 0, 0\n{}\n",
+                section("key_usage_anomalies").header,
+                section("flagged_keys").header
+            ),
+            String::from_utf8(out).unwrap()
+        );
+        let mut out = Vec::new();
+        PatchLevelPolicy::default().dump(&mut out).unwrap();
+        assert_eq!(
+            format!("{}\n  refuse, not loaded, 0, 0\n", section("patch_level_policy").header),
+            String::from_utf8(out).unwrap()
+        );
+        let mut out = Vec::new();
+        HalHealth::new(
+            hal_health::HARD_LIMIT,
+            hal_health::MIN_RESTART_INTERVAL,
+            Box::new(InitRestarter),
+        )
+        .dump(&mut out)
+        .unwrap();
+        assert_eq!(
+            format!("{}\n", section("keymint_health").header),
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn line_formats_name_all_fields_in_order() {
+        for section in DUMP_SECTIONS {
+            let mut rest = section.line;
+            for field in section.fields {
+                let placeholder = format!("<{}>", field.name);
+                let (_, after) = rest.split_once(&placeholder).unwrap_or_else(|| {
+                    panic!("{}: <{}> is missing or out of order", section.name, field.name)
+                });
+                rest = after;
+            }
+            assert!(!rest.contains('<'), "{}: unknown placeholder in {}", section.name, rest);
+        }
+    }
+
+    #[test]
+    fn write_line_fills_placeholders() {
+        let mut out = Vec::new();
+        section("lock_contention").write_line(&mut out, &[&"db", &1, &2, &3]).unwrap();
+        assert_eq!("  db: 1, 2, 3us\n", String::from_utf8(out).unwrap());
+        let mut out = Vec::new();
+        section("pending_blob_deletions").write_line(&mut out, &[&7, &2]).unwrap();
+        assert_eq!("  blob id 7, 2 failed attempts\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn atoms_are_unique() {
+        let ids: HashSet<i32> = METRICS_ATOMS.iter().map(|a| a.atom_id.0).collect();
+        assert_eq!(METRICS_ATOMS.len(), ids.len());
+    }
+
+    // This test does not compile if a field is added to or removed from one of the payloads,
+    // which is the cue to update `METRICS_ATOMS` and `SCHEMA_VERSION`.
+    #[test]
+    fn atoms_list_all_payload_fields() {
+        let payloads = [
+            KeystoreAtomPayload::StorageStats(StorageStats {
+                storage_type: Storage::KEY_ENTRY,
+                size: 0,
+                unused_size: 0,
+            }),
+            KeystoreAtomPayload::KeyCreationWithGeneralInfo(KeyCreationWithGeneralInfo {
+                algorithm: Algorithm::EC,
+                key_size: 0,
+                ec_curve: EcCurve::P_256,
+                key_origin: KeyOrigin::GENERATED,
+                error_code: 0,
+                attestation_requested: false,
+            }),
+            KeystoreAtomPayload::KeyCreationWithAuthInfo(KeyCreationWithAuthInfo {
+                user_auth_type: HardwareAuthenticatorType::NONE,
+                log10_auth_key_timeout_seconds: 0,
+                security_level: SecurityLevel::SECURITY_LEVEL_TRUSTED_ENVIRONMENT,
+            }),
+            KeystoreAtomPayload::KeyCreationWithPurposeAndModesInfo(
+                KeyCreationWithPurposeAndModesInfo {
+                    algorithm: Algorithm::EC,
+                    purpose_bitmap: 0,
+                    padding_mode_bitmap: 0,
+                    digest_bitmap: 0,
+                    block_mode_bitmap: 0,
+                },
+            ),
+            KeystoreAtomPayload::Keystore2AtomWithOverflow(Keystore2AtomWithOverflow {
+                atom_id: AtomID::STORAGE_STATS,
+            }),
+            KeystoreAtomPayload::KeyOperationWithPurposeAndModesInfo(
+                KeyOperationWithPurposeAndModesInfo {
+                    purpose: Purpose::SIGN,
+                    padding_mode_bitmap: 0,
+                    digest_bitmap: 0,
+                    block_mode_bitmap: 0,
+                },
+            ),
+            KeystoreAtomPayload::KeyOperationWithGeneralInfo(KeyOperationWithGeneralInfo {
+                outcome: Outcome::SUCCESS,
+                error_code: 0,
+                key_upgraded: false,
+                security_level: SecurityLevel::SECURITY_LEVEL_TRUSTED_ENVIRONMENT,
+            }),
+            KeystoreAtomPayload::RkpErrorStats(RkpErrorStats {
+                rkpError: RkpError::RKP_ERROR_UNSPECIFIED,
+                security_level: SecurityLevel::SECURITY_LEVEL_TRUSTED_ENVIRONMENT,
+            }),
+            KeystoreAtomPayload::CrashStats(CrashStats { count_of_crash_events: 0 }),
+            KeystoreAtomPayload::UserUnlockStats(UserUnlockStats {
+                outcome: UnlockOutcome::SUCCESS,
+                log2_kdf_millis: 0,
+                log2_db_load_millis: 0,
+            }),
+            KeystoreAtomPayload::DatabaseConsistencyStats(DatabaseConsistencyStats {
+                anomaly: ConsistencyAnomaly::DANGLING_GRANT,
+                log2_count: 0,
+            }),
+        ];
+        assert_eq!(METRICS_ATOMS.len(), payloads.len());
+    }
+
+    #[test]
+    fn json_lists_all_sections_and_atoms() {
+        let mut out = Vec::new();
+        write_json(&mut out).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.starts_with(&format!("{{\"version\":{},", SCHEMA_VERSION)));
+        for section in DUMP_SECTIONS {
+            assert!(json.contains(&format!("\"name\":\"{}\"", section.name)));
+        }
+        for atom in METRICS_ATOMS {
+            assert!(json.contains(&format!("\"atom_id\":{},", atom.atom_id.0)));
+        }
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+        assert_eq!(json.matches('[').count(), json.matches(']').count());
+    }
+}
@@ -16,8 +16,10 @@
 // TODO: more description to follow.
 use crate::ks_err;
 use crate::error::{map_binder_status, Error, ErrorCode};
+use crate::feature_flags::{self, Feature};
 use crate::globals::{get_timestamp_service, ASYNC_TASK, DB, ENFORCEMENTS};
 use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::shared_secret_negotiation::authenticator_shares_hmac_domain;
 use crate::{authorization::Error as AuthzError, super_key::SuperEncryptionType};
 use crate::{
     database::{AuthTokenEntry, MonotonicRawTime},
@@ -26,7 +28,8 @@ use crate::{
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, ErrorCode::ErrorCode as Ec, HardwareAuthToken::HardwareAuthToken,
     HardwareAuthenticatorType::HardwareAuthenticatorType,
-    KeyParameter::KeyParameter as KmKeyParameter, KeyPurpose::KeyPurpose, Tag::Tag,
+    KeyParameter::KeyParameter as KmKeyParameter, KeyPurpose::KeyPurpose,
+    SecurityLevel::SecurityLevel, Tag::Tag,
 };
 use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::{
     TimeStampToken::TimeStampToken,
@@ -365,8 +368,47 @@ impl AuthInfo {
     }
 }
 
+/// Maximum age of the auth token that authorizes an operation through a grant with the
+/// use-with-auth-only modifier.
+const GRANT_AUTH_TIMEOUT_SECONDS: i64 = 30;
+
+/// The store of received auth tokens together with the clock that time stamps them.
+/// `Enforcements` uses the database and CLOCK_MONOTONIC_RAW. Unit tests substitute both, so
+/// that they can age auth tokens without sleeping.
+pub trait AuthTokenStore: Send + Sync {
+    /// Stores `hat` with the current time as its time of receipt.
+    fn insert_auth_token(&self, hat: &HardwareAuthToken);
+    /// Returns the newest auth token that satisfies `p` together with the last time the device
+    /// was taken off body.
+    fn find_auth_token(
+        &self,
+        p: &dyn Fn(&AuthTokenEntry) -> bool,
+    ) -> Option<(AuthTokenEntry, MonotonicRawTime)>;
+    /// Returns the current time of the clock that time stamps received auth tokens.
+    fn now(&self) -> MonotonicRawTime;
+}
+
+/// Keeps auth tokens in the per boot database.
+struct DbAuthTokenStore;
+
+impl AuthTokenStore for DbAuthTokenStore {
+    fn insert_auth_token(&self, hat: &HardwareAuthToken) {
+        DB.with(|db| db.borrow_mut().insert_auth_token(hat));
+    }
+
+    fn find_auth_token(
+        &self,
+        p: &dyn Fn(&AuthTokenEntry) -> bool,
+    ) -> Option<(AuthTokenEntry, MonotonicRawTime)> {
+        DB.with(|db| db.borrow().find_auth_token_entry(p))
+    }
+
+    fn now(&self) -> MonotonicRawTime {
+        MonotonicRawTime::now()
+    }
+}
+
 /// Enforcements data structure
-#[derive(Default)]
 pub struct Enforcements {
     /// This hash set contains the user ids for whom the device is currently unlocked. If a user id
     /// is not in the set, it implies that the device is locked for the user.
@@ -380,9 +422,27 @@ pub struct Enforcements {
     /// The enforcement module will try to get a confirmation token from this channel whenever
     /// an operation that requires confirmation finishes.
     confirmation_token_receiver: Arc<Mutex<Option<Receiver<Vec<u8>>>>>,
+    /// Received auth tokens.
+    auth_tokens: Box<dyn AuthTokenStore>,
+}
+
+impl Default for Enforcements {
+    fn default() -> Self {
+        Self::with_auth_token_store(Box::new(DbAuthTokenStore))
+    }
 }
 
 impl Enforcements {
+    /// Creates an instance that keeps auth tokens in `auth_tokens`.
+    pub fn with_auth_token_store(auth_tokens: Box<dyn AuthTokenStore>) -> Self {
+        Self {
+            device_unlocked_set: Default::default(),
+            op_auth_map: Default::default(),
+            confirmation_token_receiver: Default::default(),
+            auth_tokens,
+        }
+    }
+
     /// Install the confirmation token receiver. The enforcement module will try to get a
     /// confirmation token from this channel whenever an operation that requires confirmation
     /// finishes.
@@ -401,7 +461,9 @@ impl Enforcements {
     ///
     /// If no key parameters are given (typically when the client is self managed
     /// (see Domain.Blob)) nothing is enforced.
-    /// If the key is time-bound, find a matching auth token from the database.
+    /// If the key is time-bound, find a matching auth token from the database. Auth tokens issued
+    /// in an HMAC domain that the KeyMint instance at `security_level` is not part of are skipped,
+    /// because that instance could not verify them.
     /// If the above step is successful, and if requires_timestamp is given, the returned
     /// AuthInfo will provide a Timestamp token as appropriate.
     pub fn authorize_create(
@@ -410,6 +472,7 @@ impl Enforcements {
         key_properties: Option<&(i64, Vec<KeyParameter>)>,
         op_params: &[KmKeyParameter],
         requires_timestamp: bool,
+        security_level: SecurityLevel,
     ) -> Result<(Option<HardwareAuthToken>, AuthInfo)> {
         let (key_id, key_params) = match key_properties {
             Some((key_id, key_params)) => (*key_id, key_params),
@@ -623,9 +686,13 @@ impl Enforcements {
         let need_auth_token = timeout_bound || unlocked_device_required;
 
         let hat_and_last_off_body = if need_auth_token {
-            let hat_and_last_off_body = Self::find_auth_token(|hat: &AuthTokenEntry| {
+            let hat_and_last_off_body = self.find_auth_token(|hat: &AuthTokenEntry| {
                 if let (Some(auth_type), true) = (user_auth_type, timeout_bound) {
                     hat.satisfies(&user_secure_ids, auth_type)
+                        && authenticator_shares_hmac_domain(
+                            hat.auth_token().authenticatorType,
+                            security_level,
+                        )
                 } else {
                     unlocked_device_required
                 }
@@ -642,7 +709,7 @@ impl Enforcements {
         // Now check the validity of the auth token if the key is timeout bound.
         let hat = match (hat_and_last_off_body, key_time_out) {
             (Some((hat, last_off_body)), Some(key_time_out)) => {
-                let now = MonotonicRawTime::now();
+                let now = self.auth_tokens.now();
                 let token_age = now
                     .checked_sub(&hat.time_received())
                     .ok_or_else(Error::sys)
@@ -690,11 +757,72 @@ impl Enforcements {
         })
     }
 
-    fn find_auth_token<F>(p: F) -> Option<(AuthTokenEntry, MonotonicRawTime)>
+    /// Checks the constraint of a grant that may only be used with user authentication, which
+    /// applies in addition to the authorizations of the key itself. The device must be unlocked
+    /// for the grantee's user, and an auth token of one of `owner_sids`, the secure user ids of
+    /// the key owner, issued in the HMAC domain of `security_level` must have been received
+    /// within `GRANT_AUTH_TIMEOUT_SECONDS`.
+    pub fn authorize_use_with_auth_only_grant(
+        &self,
+        user_id: i32,
+        security_level: SecurityLevel,
+        owner_sids: &[i64],
+    ) -> Result<()> {
+        self.authorize_recent_auth(user_id, security_level, |hat: &AuthTokenEntry| {
+            hat.satisfies(owner_sids, HardwareAuthenticatorType::ANY)
+        })
+    }
+
+    /// Checks that the device is unlocked for `user_id` and that an auth token issued in the
+    /// HMAC domain of `security_level` was received within `GRANT_AUTH_TIMEOUT_SECONDS`.
+    pub fn authorize_recent_user_auth(
+        &self,
+        user_id: i32,
+        security_level: SecurityLevel,
+    ) -> Result<()> {
+        self.authorize_recent_auth(user_id, security_level, |_| true)
+    }
+
+    fn authorize_recent_auth<F>(
+        &self,
+        user_id: i32,
+        security_level: SecurityLevel,
+        p: F,
+    ) -> Result<()>
     where
         F: Fn(&AuthTokenEntry) -> bool,
     {
-        DB.with(|db| db.borrow().find_auth_token_entry(p))
+        if self.is_device_locked(user_id) {
+            return Err(Error::Km(Ec::DEVICE_LOCKED)).context(ks_err!("device is locked."));
+        }
+        let (hat, _) = self
+            .find_auth_token(|hat: &AuthTokenEntry| {
+                authenticator_shares_hmac_domain(hat.auth_token().authenticatorType, security_level)
+                    && p(hat)
+            })
+            .ok_or(Error::Km(Ec::KEY_USER_NOT_AUTHENTICATED))
+            .context(ks_err!("No suitable auth token found."))?;
+        let token_age = self
+            .auth_tokens
+            .now()
+            .checked_sub(&hat.time_received())
+            .ok_or_else(Error::sys)
+            .context(ks_err!(
+                "Overflow while computing Auth token validity. \
+                Validity cannot be established."
+            ))?;
+        if token_age.seconds() > GRANT_AUTH_TIMEOUT_SECONDS {
+            return Err(Error::Km(Ec::KEY_USER_NOT_AUTHENTICATED))
+                .context(ks_err!("matching auth token is expired."));
+        }
+        Ok(())
+    }
+
+    fn find_auth_token<F>(&self, p: F) -> Option<(AuthTokenEntry, MonotonicRawTime)>
+    where
+        F: Fn(&AuthTokenEntry) -> bool,
+    {
+        self.auth_tokens.find_auth_token(&p)
     }
 
     /// Checks if the time now since epoch is greater than (or equal, if is_given_time_inclusive is
@@ -739,7 +867,7 @@ impl Enforcements {
     /// Then present the auth token to the op auth map. If an operation is waiting for this
     /// auth token this fulfills the request and removes the receiver from the map.
     pub fn add_auth_token(&self, hat: HardwareAuthToken) {
-        DB.with(|db| db.borrow_mut().insert_auth_token(&hat));
+        self.auth_tokens.insert_auth_token(&hat);
         self.op_auth_map.add_auth_token(hat);
     }
 
@@ -767,6 +895,15 @@ impl Enforcements {
             priority: u32,
             enc_type: SuperEncryptionType,
         }
+        // Keys that only a biometric can authorize use the biometric-bound super key, unless the
+        // tier is turned off.
+        let biometric_only = feature_flags::is_enabled(Feature::BiometricBoundSuperKeys)
+            && key_parameters.iter().any(|kp| {
+                kp.key_parameter_value()
+                    == &KeyParameterValue::HardwareAuthenticatorType(
+                        HardwareAuthenticatorType::FINGERPRINT,
+                    )
+            });
         let mut result = Candidate { priority: 0, enc_type: SuperEncryptionType::None };
         for kp in key_parameters {
             let t = match kp.key_parameter_value() {
@@ -776,9 +913,14 @@ impl Enforcements {
                 KeyParameterValue::UnlockedDeviceRequired if *domain == Domain::APP => {
                     Candidate { priority: 2, enc_type: SuperEncryptionType::UnlockedDeviceRequired }
                 }
-                KeyParameterValue::UserSecureID(_) if *domain == Domain::APP => {
-                    Candidate { priority: 1, enc_type: SuperEncryptionType::AfterFirstUnlock }
-                }
+                KeyParameterValue::UserSecureID(_) if *domain == Domain::APP => Candidate {
+                    priority: 1,
+                    enc_type: if biometric_only {
+                        SuperEncryptionType::BiometricBound
+                    } else {
+                        SuperEncryptionType::AfterFirstUnlock
+                    },
+                },
                 _ => Candidate { priority: 0, enc_type: SuperEncryptionType::None },
             };
             if t.priority > result.priority {
@@ -806,7 +948,7 @@ impl Enforcements {
         let auth_type = HardwareAuthenticatorType::ANY;
         let sids: Vec<i64> = vec![secure_user_id];
         // Filter the matching auth tokens by challenge
-        let result = Self::find_auth_token(|hat: &AuthTokenEntry| {
+        let result = self.find_auth_token(|hat: &AuthTokenEntry| {
             (challenge == hat.challenge()) && hat.satisfies(&sids, auth_type)
         });
 
@@ -815,8 +957,8 @@ impl Enforcements {
         } else {
             // Filter the matching auth tokens by age.
             if auth_token_max_age_millis != 0 {
-                let now_in_millis = MonotonicRawTime::now();
-                let result = Self::find_auth_token(|auth_token_entry: &AuthTokenEntry| {
+                let now_in_millis = self.auth_tokens.now();
+                let result = self.find_auth_token(|auth_token_entry: &AuthTokenEntry| {
                     let token_valid = now_in_millis
                         .checked_sub(&auth_token_entry.time_received())
                         .map_or(false, |token_age_in_millis| {
@@ -847,4 +989,297 @@ impl Enforcements {
     }
 }
 
-// TODO: Add tests to enforcement module (b/175578618).
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::Timestamp::Timestamp;
+
+    const USER_ID: i32 = 10;
+    const SID: i64 = 1000;
+    const OTHER_SID: i64 = 2000;
+    const KEY_ID: i64 = 1;
+
+    /// An in-memory auth token store with a clock that only moves when told to.
+    #[derive(Default)]
+    struct FakeAuthTokenStore {
+        tokens: Mutex<Vec<AuthTokenEntry>>,
+        now: Arc<Mutex<i64>>,
+    }
+
+    impl AuthTokenStore for FakeAuthTokenStore {
+        fn insert_auth_token(&self, hat: &HardwareAuthToken) {
+            let entry = AuthTokenEntry::new(hat.clone(), self.now());
+            self.tokens.lock().unwrap().push(entry);
+        }
+
+        fn find_auth_token(
+            &self,
+            p: &dyn Fn(&AuthTokenEntry) -> bool,
+        ) -> Option<(AuthTokenEntry, MonotonicRawTime)> {
+            let tokens = self.tokens.lock().unwrap();
+            tokens.iter().rev().find(|entry| p(entry)).map(|e| (e.clone(), Default::default()))
+        }
+
+        fn now(&self) -> MonotonicRawTime {
+            MonotonicRawTime::from_millis(*self.now.lock().unwrap())
+        }
+    }
+
+    /// Returns enforcements backed by a fake auth token store and a handle to the fake clock,
+    /// in milliseconds. The device is unlocked for `USER_ID`.
+    fn new_enforcements() -> (Enforcements, Arc<Mutex<i64>>) {
+        let store = FakeAuthTokenStore::default();
+        let clock = store.now.clone();
+        *clock.lock().unwrap() = 1_000_000;
+        let enforcements = Enforcements::with_auth_token_store(Box::new(store));
+        enforcements.set_device_locked(USER_ID, false);
+        (enforcements, clock)
+    }
+
+    fn advance(clock: &Mutex<i64>, seconds: i64) {
+        *clock.lock().unwrap() += seconds * 1000;
+    }
+
+    fn hat(sid: i64, auth_type: HardwareAuthenticatorType, challenge: i64) -> HardwareAuthToken {
+        HardwareAuthToken {
+            challenge,
+            userId: sid,
+            authenticatorId: 0,
+            authenticatorType: auth_type,
+            timestamp: Timestamp { milliSeconds: 0 },
+            mac: vec![1, 2, 3],
+        }
+    }
+
+    /// Key parameters of an HMAC signing key bound to `sids` with the given authenticator type
+    /// and, optionally, auth timeout.
+    fn auth_bound_key(
+        sids: &[i64],
+        auth_type: HardwareAuthenticatorType,
+        timeout: Option<i32>,
+    ) -> (i64, Vec<KeyParameter>) {
+        let mut params = vec![
+            KeyParameterValue::Algorithm(Algorithm::HMAC),
+            KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+            KeyParameterValue::HardwareAuthenticatorType(auth_type),
+        ];
+        params.extend(sids.iter().map(|sid| KeyParameterValue::UserSecureID(*sid)));
+        params.extend(timeout.map(KeyParameterValue::AuthTimeout));
+        (
+            KEY_ID,
+            params
+                .into_iter()
+                .map(|v| KeyParameter::new(v, SecurityLevel::TRUSTED_ENVIRONMENT))
+                .collect(),
+        )
+    }
+
+    fn authorize(
+        enforcements: &Enforcements,
+        key: &(i64, Vec<KeyParameter>),
+    ) -> Result<(Option<HardwareAuthToken>, AuthInfo)> {
+        enforcements.authorize_create(
+            KeyPurpose::SIGN,
+            Some(key),
+            &[],
+            false,
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        )
+    }
+
+    fn km_error<T>(result: Result<T>) -> Option<Ec> {
+        match result.err()?.root_cause().downcast_ref::<Error>() {
+            Some(Error::Km(ec)) => Some(*ec),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn timeout_bound_key_needs_fresh_auth_token() {
+        let (enforcements, clock) = new_enforcements();
+        let key = auth_bound_key(&[SID], HardwareAuthenticatorType::PASSWORD, Some(10));
+
+        assert_eq!(Some(Ec::KEY_USER_NOT_AUTHENTICATED), km_error(authorize(&enforcements, &key)));
+
+        enforcements.add_auth_token(hat(SID, HardwareAuthenticatorType::PASSWORD, 0));
+        advance(&clock, 10);
+        let (presented, info) = authorize(&enforcements, &key).unwrap();
+        assert_eq!(Some(SID), presented.map(|hat| hat.userId));
+        assert!(matches!(info.state, DeferredAuthState::NoAuthRequired));
+
+        advance(&clock, 1);
+        assert_eq!(Some(Ec::KEY_USER_NOT_AUTHENTICATED), km_error(authorize(&enforcements, &key)));
+
+        // A new token restarts the timeout.
+        enforcements.add_auth_token(hat(SID, HardwareAuthenticatorType::PASSWORD, 0));
+        assert!(authorize(&enforcements, &key).is_ok());
+    }
+
+    #[test]
+    fn per_operation_key_defers_to_operation_token() {
+        let (enforcements, _clock) = new_enforcements();
+        let key = auth_bound_key(&[SID], HardwareAuthenticatorType::FINGERPRINT, None);
+
+        // No auth token is needed, or even looked at, to create the operation.
+        let (presented, info) = authorize(&enforcements, &key).unwrap();
+        assert!(presented.is_none());
+        assert!(matches!(info.state, DeferredAuthState::OpAuthRequired));
+
+        enforcements.add_auth_token(hat(SID, HardwareAuthenticatorType::FINGERPRINT, 0));
+        let (presented, info) = authorize(&enforcements, &key).unwrap();
+        assert!(presented.is_none());
+        assert!(matches!(info.state, DeferredAuthState::OpAuthRequired));
+    }
+
+    #[test]
+    fn any_of_multiple_sids_authenticates() {
+        let (enforcements, _clock) = new_enforcements();
+        let key = auth_bound_key(&[SID, OTHER_SID], HardwareAuthenticatorType::PASSWORD, Some(60));
+
+        enforcements.add_auth_token(hat(3000, HardwareAuthenticatorType::PASSWORD, 0));
+        assert_eq!(Some(Ec::KEY_USER_NOT_AUTHENTICATED), km_error(authorize(&enforcements, &key)));
+
+        enforcements.add_auth_token(hat(OTHER_SID, HardwareAuthenticatorType::PASSWORD, 0));
+        assert!(authorize(&enforcements, &key).is_ok());
+
+        // The authenticator id is matched against the sids, too.
+        let (enforcements, _clock) = new_enforcements();
+        enforcements.add_auth_token(HardwareAuthToken {
+            authenticatorId: SID,
+            ..hat(3000, HardwareAuthenticatorType::PASSWORD, 0)
+        });
+        let (presented, _) = authorize(&enforcements, &key).unwrap();
+        assert_eq!(Some(SID), presented.map(|hat| hat.authenticatorId));
+    }
+
+    #[test]
+    fn authenticator_type_must_match() {
+        let (enforcements, _clock) = new_enforcements();
+        let key = auth_bound_key(&[SID], HardwareAuthenticatorType::FINGERPRINT, Some(60));
+
+        enforcements.add_auth_token(hat(SID, HardwareAuthenticatorType::PASSWORD, 0));
+        assert_eq!(Some(Ec::KEY_USER_NOT_AUTHENTICATED), km_error(authorize(&enforcements, &key)));
+
+        enforcements.add_auth_token(hat(SID, HardwareAuthenticatorType::FINGERPRINT, 0));
+        assert!(authorize(&enforcements, &key).is_ok());
+
+        // A key that accepts either authenticator type is satisfied as well.
+        let key = auth_bound_key(
+            &[SID],
+            HardwareAuthenticatorType(
+                HardwareAuthenticatorType::PASSWORD.0 | HardwareAuthenticatorType::FINGERPRINT.0,
+            ),
+            Some(60),
+        );
+        assert!(authorize(&enforcements, &key).is_ok());
+    }
+
+    #[test]
+    fn use_with_auth_only_grant_times_out() {
+        let (enforcements, clock) = new_enforcements();
+        let authorize_grant = || {
+            enforcements.authorize_use_with_auth_only_grant(
+                USER_ID,
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+                &[SID],
+            )
+        };
+
+        assert_eq!(Some(Ec::KEY_USER_NOT_AUTHENTICATED), km_error(authorize_grant()));
+        // Only an authentication of the key owner satisfies the grant.
+        enforcements.add_auth_token(hat(SID + 1, HardwareAuthenticatorType::PASSWORD, 0));
+        assert_eq!(Some(Ec::KEY_USER_NOT_AUTHENTICATED), km_error(authorize_grant()));
+        assert!(enforcements
+            .authorize_recent_user_auth(USER_ID, SecurityLevel::TRUSTED_ENVIRONMENT)
+            .is_ok());
+
+        enforcements.add_auth_token(hat(SID, HardwareAuthenticatorType::PASSWORD, 0));
+        advance(&clock, GRANT_AUTH_TIMEOUT_SECONDS);
+        assert!(authorize_grant().is_ok());
+        advance(&clock, 1);
+        assert_eq!(Some(Ec::KEY_USER_NOT_AUTHENTICATED), km_error(authorize_grant()));
+
+        enforcements.add_auth_token(hat(SID, HardwareAuthenticatorType::PASSWORD, 0));
+        enforcements.set_device_locked(USER_ID, true);
+        assert_eq!(Some(Ec::DEVICE_LOCKED), km_error(authorize_grant()));
+    }
+
+    #[test]
+    fn get_auth_tokens_honors_max_age() {
+        let (enforcements, clock) = new_enforcements();
+        enforcements.add_auth_token(hat(SID, HardwareAuthenticatorType::PASSWORD, 0));
+        advance(&clock, 5);
+
+        // The token is older than the maximum age and has a different challenge. The fresh
+        // token path needs the secure clock service, so only the rejections are covered here.
+        for max_age_millis in [0, 5000] {
+            let result = enforcements.get_auth_tokens(42, SID, max_age_millis);
+            assert_eq!(
+                Some(&AuthzError::Rc(AuthzResponseCode::NO_AUTH_TOKEN_FOUND)),
+                result.unwrap_err().root_cause().downcast_ref::<AuthzError>()
+            );
+        }
+    }
+
+    #[test]
+    fn biometric_only_keys_use_biometric_bound_super_key() {
+        let required = |auth_type, domain| {
+            let (_, params) = auth_bound_key(&[SID], auth_type, None);
+            Enforcements::super_encryption_required(&domain, &params, None)
+        };
+        if feature_flags::is_enabled(Feature::BiometricBoundSuperKeys) {
+            assert!(matches!(
+                required(HardwareAuthenticatorType::FINGERPRINT, Domain::APP),
+                SuperEncryptionType::BiometricBound
+            ));
+        } else {
+            assert!(matches!(
+                required(HardwareAuthenticatorType::FINGERPRINT, Domain::APP),
+                SuperEncryptionType::AfterFirstUnlock
+            ));
+        }
+        assert!(matches!(
+            required(HardwareAuthenticatorType::PASSWORD, Domain::APP),
+            SuperEncryptionType::AfterFirstUnlock
+        ));
+        assert!(matches!(
+            required(HardwareAuthenticatorType::FINGERPRINT, Domain::SELINUX),
+            SuperEncryptionType::None
+        ));
+    }
+
+    #[test]
+    fn unlocked_device_required_keys_use_their_own_tier() {
+        let (_, mut params) = auth_bound_key(&[SID], HardwareAuthenticatorType::PASSWORD, None);
+        params.push(KeyParameter::new(
+            KeyParameterValue::UnlockedDeviceRequired,
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        ));
+        assert!(matches!(
+            Enforcements::super_encryption_required(&Domain::APP, &params, None),
+            SuperEncryptionType::UnlockedDeviceRequired
+        ));
+        assert!(matches!(
+            Enforcements::super_encryption_required(&Domain::SELINUX, &params, None),
+            SuperEncryptionType::None
+        ));
+        assert!(matches!(
+            Enforcements::super_encryption_required(
+                &Domain::APP,
+                &params,
+                Some(KEY_FLAG_AUTH_BOUND_WITHOUT_CRYPTOGRAPHIC_LSKF_BINDING)
+            ),
+            SuperEncryptionType::None
+        ));
+
+        // Boot level keys take precedence.
+        params.push(KeyParameter::new(
+            KeyParameterValue::MaxBootLevel(3),
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        ));
+        assert!(matches!(
+            Enforcements::super_encryption_required(&Domain::APP, &params, None),
+            SuperEncryptionType::BootLevel(3)
+        ));
+    }
+}
@@ -24,6 +24,12 @@
 //! `map_or_log_err` is a convenience method used to convert `anyhow::Error` into `SerializedError`
 //! wire type.
 //!
+//! `RetryAfter` is attached as context to `BACKEND_BUSY` errors to tell the client when a retry
+//! is likely to succeed.
+//!
+//! `DatabaseErrorKind` classifies SQLite failures of the Keystore database, so that transient
+//! conditions can be told apart from fatal ones.
+//!
 //! Keystore functions should use `anyhow::Result` to return error conditions, and context should
 //! be added every time an error is forwarded.
 
@@ -35,6 +41,7 @@ use android_system_keystore2::binder::{
 use keystore2_selinux as selinux;
 use std::cmp::PartialEq;
 use std::ffi::CString;
+use std::time::Duration;
 
 /// This is the main Keystore error type. It wraps the Keystore `ResponseCode` generated
 /// from AIDL in the `Rc` variant and Keymint `ErrorCode` in the Km variant.
@@ -66,6 +73,89 @@ impl Error {
     }
 }
 
+/// Response code that extends the `ResponseCode`s of the Keystore AIDL interface. It is returned
+/// if a key descriptor of domain KEY_ID also names an alias, and the alias has since been bound
+/// to a different key.
+pub const KEY_CHANGED: ResponseCode = ResponseCode(100);
+
+/// Response code that extends the `ResponseCode`s of the Keystore AIDL interface. It is returned
+/// if the deadline that the client passed with a call has passed before keystore completed it.
+/// Unlike `BACKEND_BUSY`, retrying the same call does not help.
+pub const DEADLINE_EXCEEDED: ResponseCode = ResponseCode(101);
+
+/// Precedes the retry hint in the message of a service specific error, so that clients can find
+/// it in an otherwise free-form message.
+pub const RETRY_AFTER_MS_PREFIX: &str = "retry_after_ms=";
+
+/// A hint when retrying a call that failed with `BACKEND_BUSY` is likely to succeed. It is
+/// attached to the error as context, and reaches the client as `retry_after_ms=<millis>` in the
+/// message of the service specific error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryAfter(pub Duration);
+
+impl std::fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", RETRY_AFTER_MS_PREFIX, self.0.as_millis())
+    }
+}
+
+/// Attaches the `RetryAfter` hint computed by `hint` to `e` if `e` is caused by
+/// `Error::Rc(ResponseCode::BACKEND_BUSY)`. Other errors are returned unchanged.
+pub fn add_retry_hint<F>(e: anyhow::Error, hint: F) -> anyhow::Error
+where
+    F: FnOnce() -> Duration,
+{
+    match e.root_cause().downcast_ref::<Error>() {
+        Some(Error::Rc(ResponseCode::BACKEND_BUSY)) => e.context(RetryAfter(hint())),
+        _ => e,
+    }
+}
+
+/// Classification of SQLite failures that surface from the Keystore database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatabaseErrorKind {
+    /// The database was busy or locked by another connection.
+    Busy,
+    /// The file system holding the database is full.
+    DiskFull,
+    /// The database file is malformed or is not a database.
+    Corrupted,
+    /// A constraint of the database schema was violated.
+    ConstraintViolation,
+    /// Any other SQLite failure.
+    Other,
+}
+
+impl DatabaseErrorKind {
+    /// Classifies the root cause of `e` if it is an SQLite failure and returns None otherwise.
+    pub fn from_anyhow(e: &anyhow::Error) -> Option<Self> {
+        e.root_cause().downcast_ref::<rusqlite::ffi::Error>().map(|e| match e.code {
+            rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked => Self::Busy,
+            rusqlite::ErrorCode::DiskFull => Self::DiskFull,
+            rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase => {
+                Self::Corrupted
+            }
+            rusqlite::ErrorCode::ConstraintViolation => Self::ConstraintViolation,
+            _ => Self::Other,
+        })
+    }
+
+    /// Returns true if the failure may resolve by itself, so that retrying the request later
+    /// may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Busy | Self::DiskFull)
+    }
+
+    /// The response code reported to clients. Only a busy database has a dedicated response
+    /// code; all other failures are reported as system error.
+    pub fn response_code(&self) -> ResponseCode {
+        match self {
+            Self::Busy => ResponseCode::BACKEND_BUSY,
+            _ => ResponseCode::SYSTEM_ERROR,
+        }
+    }
+}
+
 /// Helper function to map the binder status we get from calls into KeyMint
 /// to a Keystore Error. We don't create an anyhow error here to make
 /// it easier to evaluate KeyMint errors, which we must do in some cases, e.g.,
@@ -147,6 +237,16 @@ where
     map_err_with(
         result,
         |e| {
+            // Tag database failures with their kind, so that the log and the message sent
+            // to the client tell transient from fatal failures.
+            let e = match DatabaseErrorKind::from_anyhow(&e) {
+                Some(kind) => e.context(format!(
+                    "Database failure: {:?} (transient: {}).",
+                    kind,
+                    kind.is_transient()
+                )),
+                None => e,
+            };
             // Make the key not found errors silent.
             if !matches!(
                 e.root_cause().downcast_ref::<Error>(),
@@ -206,6 +306,7 @@ where
 ///   convention Keystore `ResponseCode` errors are positive, and Keymint `ErrorCode` errors are
 ///   negative.
 /// - `selinux::Error::PermissionDenied` is mapped to `ResponseCode::PERMISSION_DENIED`.
+/// - SQLite failures are mapped according to `DatabaseErrorKind::response_code`.
 /// - All other error conditions, e.g. Binder errors, are mapped to `ResponseCode::SYSTEM_ERROR`.
 ///
 /// The type should be used to forward all error codes to clients of Keystore AIDL interface and to
@@ -234,7 +335,11 @@ pub fn anyhow_error_to_serialized_error(e: &anyhow::Error) -> SerializedError {
             Some(selinux::Error::PermissionDenied) => {
                 SerializedError(ResponseCode::PERMISSION_DENIED.0)
             }
-            _ => SerializedError(ResponseCode::SYSTEM_ERROR.0),
+            _ => SerializedError(
+                DatabaseErrorKind::from_anyhow(e)
+                    .map_or(ResponseCode::SYSTEM_ERROR, |kind| kind.response_code())
+                    .0,
+            ),
         },
     }
 }
@@ -393,6 +498,64 @@ pub mod tests {
         Ok(())
     }
 
+    fn sqlite_failure(code: std::os::raw::c_int) -> anyhow::Result<()> {
+        Err(rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(code), None))
+            .context("nested sqlite failure")
+    }
+
+    #[test]
+    fn database_error_kind_test() {
+        let cases = [
+            (rusqlite::ffi::SQLITE_BUSY, DatabaseErrorKind::Busy, ResponseCode::BACKEND_BUSY),
+            (rusqlite::ffi::SQLITE_LOCKED, DatabaseErrorKind::Busy, ResponseCode::BACKEND_BUSY),
+            (rusqlite::ffi::SQLITE_FULL, DatabaseErrorKind::DiskFull, ResponseCode::SYSTEM_ERROR),
+            (
+                rusqlite::ffi::SQLITE_CORRUPT,
+                DatabaseErrorKind::Corrupted,
+                ResponseCode::SYSTEM_ERROR,
+            ),
+            (
+                rusqlite::ffi::SQLITE_NOTADB,
+                DatabaseErrorKind::Corrupted,
+                ResponseCode::SYSTEM_ERROR,
+            ),
+            (
+                rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE,
+                DatabaseErrorKind::ConstraintViolation,
+                ResponseCode::SYSTEM_ERROR,
+            ),
+            (rusqlite::ffi::SQLITE_IOERR, DatabaseErrorKind::Other, ResponseCode::SYSTEM_ERROR),
+        ];
+        for (code, kind, rc) in cases {
+            let e = sqlite_failure(code).unwrap_err();
+            assert_eq!(Some(kind), DatabaseErrorKind::from_anyhow(&e));
+            assert_eq!(SerializedError(rc.0), anyhow_error_to_serialized_error(&e));
+        }
+
+        assert!(DatabaseErrorKind::Busy.is_transient());
+        assert!(!DatabaseErrorKind::Corrupted.is_transient());
+        assert_eq!(None, DatabaseErrorKind::from_anyhow(&nested_other_error().unwrap_err()));
+    }
+
+    #[test]
+    fn retry_hint_reaches_client() {
+        let busy = add_retry_hint(nested_rc(ResponseCode::BACKEND_BUSY).unwrap_err(), || {
+            Duration::from_millis(250)
+        });
+        assert_eq!(
+            SerializedError(ResponseCode::BACKEND_BUSY.0),
+            anyhow_error_to_serialized_error(&busy)
+        );
+        let status = map_or_log_err::<(), _, _>(Err(busy), Ok).unwrap_err();
+        assert!(status.get_description().contains("retry_after_ms=250"), "{:?}", status);
+
+        // Only BACKEND_BUSY errors get a hint.
+        let other = add_retry_hint(nested_rc(ResponseCode::SYSTEM_ERROR).unwrap_err(), || {
+            panic!("No hint must be computed for other errors.")
+        });
+        assert!(other.downcast_ref::<RetryAfter>().is_none());
+    }
+
     //Helper function to test whether error cases are handled as expected.
     pub fn check_result_contains_error_string<T>(
         result: anyhow::Result<T>,
@@ -0,0 +1,461 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module injects KeyMint errors for resilience testing. Real KeyMint devices cannot be
+//! made to fail a particular call, e.g., an update in the middle of an operation, so there is
+//! no other way to exercise keystore2's handling of such errors.
+//!
+//! It is only compiled with the `keystore2_fault_injection` feature, which must not be enabled
+//! in production builds. With the feature, every KeyMint device is wrapped in a
+//! `FaultInjectingKeyMint`, which also wraps the operations that it begins. Tests call
+//! `inject_fault` to make a call at a given security level fail with a given error code,
+//! `inject_stall` to make it hang for a while, and `clear_faults` to restore normal behavior.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    AttestationKey::AttestationKey, BeginResult::BeginResult, ErrorCode::ErrorCode,
+    HardwareAuthToken::HardwareAuthToken, IKeyMintDevice::BnKeyMintDevice,
+    IKeyMintDevice::IKeyMintDevice, IKeyMintOperation::BnKeyMintOperation,
+    IKeyMintOperation::IKeyMintOperation, KeyCharacteristics::KeyCharacteristics,
+    KeyCreationResult::KeyCreationResult, KeyFormat::KeyFormat,
+    KeyMintHardwareInfo::KeyMintHardwareInfo, KeyParameter::KeyParameter,
+    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+};
+use android_hardware_security_keymint::binder::{BinderFeatures, Strong};
+use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::TimeStampToken::TimeStampToken;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The KeyMint calls that can be made to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KmCall {
+    /// IKeyMintDevice::generateKey
+    GenerateKey,
+    /// IKeyMintDevice::importKey
+    ImportKey,
+    /// IKeyMintDevice::importWrappedKey
+    ImportWrappedKey,
+    /// IKeyMintDevice::upgradeKey
+    UpgradeKey,
+    /// IKeyMintDevice::deleteKey
+    DeleteKey,
+    /// IKeyMintDevice::begin
+    Begin,
+    /// IKeyMintDevice::getKeyCharacteristics
+    GetKeyCharacteristics,
+    /// IKeyMintDevice::convertStorageKeyToEphemeral
+    ConvertStorageKeyToEphemeral,
+    /// IKeyMintOperation::updateAad
+    UpdateAad,
+    /// IKeyMintOperation::update
+    Update,
+    /// IKeyMintOperation::finish
+    Finish,
+    /// IKeyMintOperation::abort
+    Abort,
+}
+
+#[derive(Debug)]
+struct Fault {
+    security_level: SecurityLevel,
+    call: KmCall,
+    /// The error that the call fails with, or None if it only stalls.
+    error_code: Option<ErrorCode>,
+    /// How long the call is delayed before it fails or is passed on.
+    stall: Duration,
+    /// Number of matching calls that still pass before the fault fires.
+    skip: u32,
+    /// Number of matching calls that still fail.
+    remaining: u32,
+}
+
+lazy_static! {
+    static ref FAULTS: Mutex<Vec<Fault>> = Default::default();
+}
+
+/// Makes `call` on the KeyMint device of `security_level` fail with `error_code`. The first
+/// `skip` calls pass, the following `times` calls fail, and the ones after that pass again.
+/// E.g., `skip = 2, times = 1` fails the third update of an operation. Faults that are
+/// injected for the same call fire in the order in which they were injected, i.e., a fault only
+/// starts counting calls when the ones before it are used up.
+pub fn inject_fault(
+    security_level: SecurityLevel,
+    call: KmCall,
+    error_code: ErrorCode,
+    skip: u32,
+    times: u32,
+) {
+    log::warn!(
+        "Injecting {:?} into {:?} at {:?} after {} calls, {} times.",
+        error_code,
+        call,
+        security_level,
+        skip,
+        times
+    );
+    FAULTS.lock().unwrap().push(Fault {
+        security_level,
+        call,
+        error_code: Some(error_code),
+        stall: Duration::ZERO,
+        skip,
+        remaining: times,
+    });
+}
+
+/// Makes `call` on the KeyMint device of `security_level` hang for `stall` before it is passed
+/// on to the device, like a wedged HAL. `skip` and `times` count calls as for `inject_fault`.
+pub fn inject_stall(
+    security_level: SecurityLevel,
+    call: KmCall,
+    stall: Duration,
+    skip: u32,
+    times: u32,
+) {
+    log::warn!(
+        "Injecting a stall of {:?} into {:?} at {:?} after {} calls, {} times.",
+        stall,
+        call,
+        security_level,
+        skip,
+        times
+    );
+    FAULTS.lock().unwrap().push(Fault {
+        security_level,
+        call,
+        error_code: None,
+        stall,
+        skip,
+        remaining: times,
+    });
+}
+
+/// Removes all injected faults.
+pub fn clear_faults() {
+    FAULTS.lock().unwrap().clear();
+}
+
+/// Consumes one `call` from the first pending fault for it. Stalls the call and returns the
+/// error to inject, if the fault fires.
+fn next_fault(security_level: SecurityLevel, call: KmCall) -> binder::Result<()> {
+    let mut faults = FAULTS.lock().unwrap();
+    let fault = match faults
+        .iter_mut()
+        .find(|f| f.security_level == security_level && f.call == call && f.remaining > 0)
+    {
+        Some(fault) => fault,
+        None => return Ok(()),
+    };
+    if fault.skip > 0 {
+        fault.skip -= 1;
+        return Ok(());
+    }
+    fault.remaining -= 1;
+    let (error_code, stall) = (fault.error_code, fault.stall);
+    // Other calls must not wait for the stall.
+    drop(faults);
+    log::warn!(
+        "Injected {:?} after {:?} into {:?} at {:?}.",
+        error_code,
+        stall,
+        call,
+        security_level
+    );
+    std::thread::sleep(stall);
+    match error_code {
+        Some(error_code) => Err(binder::Status::new_service_specific_error(error_code.0, None)),
+        None => Ok(()),
+    }
+}
+
+/// Wrapper around a KeyMint device that fails calls on request of `inject_fault`.
+pub struct FaultInjectingKeyMint {
+    security_level: SecurityLevel,
+    inner: Strong<dyn IKeyMintDevice>,
+}
+
+impl FaultInjectingKeyMint {
+    /// Wraps the KeyMint device of `security_level`.
+    pub fn wrap(
+        security_level: SecurityLevel,
+        inner: Strong<dyn IKeyMintDevice>,
+    ) -> Strong<dyn IKeyMintDevice> {
+        log::warn!("KeyMint fault injection is enabled for {:?}.", security_level);
+        BnKeyMintDevice::new_binder(
+            Self { security_level, inner },
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        )
+    }
+
+    fn fault(&self, call: KmCall) -> binder::Result<()> {
+        next_fault(self.security_level, call)
+    }
+}
+
+impl binder::Interface for FaultInjectingKeyMint {}
+
+impl IKeyMintDevice for FaultInjectingKeyMint {
+    fn getHardwareInfo(&self) -> binder::Result<KeyMintHardwareInfo> {
+        self.inner.getHardwareInfo()
+    }
+    fn addRngEntropy(&self, data: &[u8]) -> binder::Result<()> {
+        self.inner.addRngEntropy(data)
+    }
+    fn generateKey(
+        &self,
+        key_params: &[KeyParameter],
+        attestation_key: Option<&AttestationKey>,
+    ) -> binder::Result<KeyCreationResult> {
+        self.fault(KmCall::GenerateKey)?;
+        self.inner.generateKey(key_params, attestation_key)
+    }
+    fn importKey(
+        &self,
+        key_params: &[KeyParameter],
+        key_format: KeyFormat,
+        key_data: &[u8],
+        attestation_key: Option<&AttestationKey>,
+    ) -> binder::Result<KeyCreationResult> {
+        self.fault(KmCall::ImportKey)?;
+        self.inner.importKey(key_params, key_format, key_data, attestation_key)
+    }
+    fn importWrappedKey(
+        &self,
+        wrapped_key_data: &[u8],
+        wrapping_key_blob: &[u8],
+        masking_key: &[u8],
+        unwrapping_params: &[KeyParameter],
+        password_sid: i64,
+        biometric_sid: i64,
+    ) -> binder::Result<KeyCreationResult> {
+        self.fault(KmCall::ImportWrappedKey)?;
+        self.inner.importWrappedKey(
+            wrapped_key_data,
+            wrapping_key_blob,
+            masking_key,
+            unwrapping_params,
+            password_sid,
+            biometric_sid,
+        )
+    }
+    fn upgradeKey(
+        &self,
+        keyblob_to_upgrade: &[u8],
+        upgrade_params: &[KeyParameter],
+    ) -> binder::Result<Vec<u8>> {
+        self.fault(KmCall::UpgradeKey)?;
+        self.inner.upgradeKey(keyblob_to_upgrade, upgrade_params)
+    }
+    fn deleteKey(&self, keyblob: &[u8]) -> binder::Result<()> {
+        self.fault(KmCall::DeleteKey)?;
+        self.inner.deleteKey(keyblob)
+    }
+    fn deleteAllKeys(&self) -> binder::Result<()> {
+        self.inner.deleteAllKeys()
+    }
+    fn destroyAttestationIds(&self) -> binder::Result<()> {
+        self.inner.destroyAttestationIds()
+    }
+    fn begin(
+        &self,
+        purpose: KeyPurpose,
+        keyblob: &[u8],
+        params: &[KeyParameter],
+        auth_token: Option<&HardwareAuthToken>,
+    ) -> binder::Result<BeginResult> {
+        self.fault(KmCall::Begin)?;
+        let mut result = self.inner.begin(purpose, keyblob, params, auth_token)?;
+        result.operation =
+            result.operation.map(|op| FaultInjectingOperation::wrap(self.security_level, op));
+        Ok(result)
+    }
+    fn deviceLocked(
+        &self,
+        password_only: bool,
+        timestamp_token: Option<&TimeStampToken>,
+    ) -> binder::Result<()> {
+        self.inner.deviceLocked(password_only, timestamp_token)
+    }
+    fn earlyBootEnded(&self) -> binder::Result<()> {
+        self.inner.earlyBootEnded()
+    }
+    fn convertStorageKeyToEphemeral(&self, storage_keyblob: &[u8]) -> binder::Result<Vec<u8>> {
+        self.fault(KmCall::ConvertStorageKeyToEphemeral)?;
+        self.inner.convertStorageKeyToEphemeral(storage_keyblob)
+    }
+    fn getKeyCharacteristics(
+        &self,
+        keyblob: &[u8],
+        app_id: &[u8],
+        app_data: &[u8],
+    ) -> binder::Result<Vec<KeyCharacteristics>> {
+        self.fault(KmCall::GetKeyCharacteristics)?;
+        self.inner.getKeyCharacteristics(keyblob, app_id, app_data)
+    }
+    fn getRootOfTrustChallenge(&self) -> binder::Result<[u8; 16]> {
+        self.inner.getRootOfTrustChallenge()
+    }
+    fn getRootOfTrust(&self, challenge: &[u8; 16]) -> binder::Result<Vec<u8>> {
+        self.inner.getRootOfTrust(challenge)
+    }
+    fn sendRootOfTrust(&self, root_of_trust: &[u8]) -> binder::Result<()> {
+        self.inner.sendRootOfTrust(root_of_trust)
+    }
+}
+
+/// Wrapper around a KeyMint operation that fails calls on request of `inject_fault`.
+pub struct FaultInjectingOperation {
+    security_level: SecurityLevel,
+    inner: Strong<dyn IKeyMintOperation>,
+}
+
+impl FaultInjectingOperation {
+    /// Wraps an operation of the KeyMint device of `security_level`.
+    pub fn wrap(
+        security_level: SecurityLevel,
+        inner: Strong<dyn IKeyMintOperation>,
+    ) -> Strong<dyn IKeyMintOperation> {
+        BnKeyMintOperation::new_binder(Self { security_level, inner }, BinderFeatures::default())
+    }
+
+    fn fault(&self, call: KmCall) -> binder::Result<()> {
+        next_fault(self.security_level, call)
+    }
+}
+
+impl binder::Interface for FaultInjectingOperation {}
+
+impl IKeyMintOperation for FaultInjectingOperation {
+    fn updateAad(
+        &self,
+        input: &[u8],
+        auth_token: Option<&HardwareAuthToken>,
+        timestamp_token: Option<&TimeStampToken>,
+    ) -> binder::Result<()> {
+        self.fault(KmCall::UpdateAad)?;
+        self.inner.updateAad(input, auth_token, timestamp_token)
+    }
+    fn update(
+        &self,
+        input: &[u8],
+        auth_token: Option<&HardwareAuthToken>,
+        timestamp_token: Option<&TimeStampToken>,
+    ) -> binder::Result<Vec<u8>> {
+        self.fault(KmCall::Update)?;
+        self.inner.update(input, auth_token, timestamp_token)
+    }
+    fn finish(
+        &self,
+        input: Option<&[u8]>,
+        signature: Option<&[u8]>,
+        auth_token: Option<&HardwareAuthToken>,
+        timestamp_token: Option<&TimeStampToken>,
+        confirmation_token: Option<&[u8]>,
+    ) -> binder::Result<Vec<u8>> {
+        self.fault(KmCall::Finish)?;
+        self.inner.finish(input, signature, auth_token, timestamp_token, confirmation_token)
+    }
+    fn abort(&self) -> binder::Result<()> {
+        self.fault(KmCall::Abort)?;
+        self.inner.abort()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::error::{map_km_error, Error};
+
+    /// Fake operation that echoes its input.
+    pub struct EchoOperation;
+
+    impl binder::Interface for EchoOperation {}
+
+    impl IKeyMintOperation for EchoOperation {
+        fn updateAad(
+            &self,
+            _: &[u8],
+            _: Option<&HardwareAuthToken>,
+            _: Option<&TimeStampToken>,
+        ) -> binder::Result<()> {
+            Ok(())
+        }
+        fn update(
+            &self,
+            input: &[u8],
+            _: Option<&HardwareAuthToken>,
+            _: Option<&TimeStampToken>,
+        ) -> binder::Result<Vec<u8>> {
+            Ok(input.to_vec())
+        }
+        fn finish(
+            &self,
+            input: Option<&[u8]>,
+            _: Option<&[u8]>,
+            _: Option<&HardwareAuthToken>,
+            _: Option<&TimeStampToken>,
+            _: Option<&[u8]>,
+        ) -> binder::Result<Vec<u8>> {
+            Ok(input.unwrap_or_default().to_vec())
+        }
+        fn abort(&self) -> binder::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Tests run concurrently and share the injected faults, so each test uses its own,
+    // made up, security level.
+
+    #[test]
+    fn fault_fires_after_skipped_calls() {
+        let sec_level = SecurityLevel(1001);
+        inject_fault(sec_level, KmCall::Begin, ErrorCode::UNKNOWN_ERROR, 1, 2);
+        inject_fault(sec_level, KmCall::Begin, ErrorCode::TOO_MANY_OPERATIONS, 0, 1);
+
+        let results: Vec<_> =
+            (0..5).map(|_| map_km_error(next_fault(sec_level, KmCall::Begin))).collect();
+        assert_eq!(
+            vec![
+                Ok(()),
+                Err(Error::Km(ErrorCode::UNKNOWN_ERROR)),
+                Err(Error::Km(ErrorCode::UNKNOWN_ERROR)),
+                Err(Error::Km(ErrorCode::TOO_MANY_OPERATIONS)),
+                Ok(()),
+            ],
+            results
+        );
+        // Other calls and security levels are not affected.
+        inject_fault(sec_level, KmCall::Begin, ErrorCode::UNKNOWN_ERROR, 0, 1);
+        assert_eq!(Ok(()), map_km_error(next_fault(sec_level, KmCall::GenerateKey)));
+        assert_eq!(Ok(()), map_km_error(next_fault(SecurityLevel(1002), KmCall::Begin)));
+    }
+
+    #[test]
+    fn fault_in_the_middle_of_an_operation() {
+        let sec_level = SecurityLevel(1003);
+        let op = FaultInjectingOperation::wrap(
+            sec_level,
+            BnKeyMintOperation::new_binder(EchoOperation, BinderFeatures::default()),
+        );
+        inject_fault(sec_level, KmCall::Update, ErrorCode::UNKNOWN_ERROR, 1, 1);
+
+        assert_eq!(Ok(vec![1]), map_km_error(op.update(&[1], None, None)));
+        assert_eq!(
+            Err(Error::Km(ErrorCode::UNKNOWN_ERROR)),
+            map_km_error(op.update(&[2], None, None))
+        );
+        assert_eq!(Ok(vec![3]), map_km_error(op.update(&[3], None, None)));
+        assert_eq!(Ok(vec![4]), map_km_error(op.finish(Some(&[4]), None, None, None, None)));
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module gates features that are rolled out in stages. Each `Feature` is backed by an
+//! aconfig flag in `aconfig/flags.aconfig`. The flags are read once when the service starts, so
+//! that a feature cannot appear in the middle of the lifetime of the service.
+//!
+//! Features that can be turned off safely while keystore is running also have a kill switch.
+//! Listing the name of a feature in the comma separated system property
+//! `persist.keystore.kill_switches` turns the feature off without a reboot, e.g., when a flag
+//! push turned out to be harmful. The property is read when the service starts and whenever it
+//! changes, so that checks do not need to read it.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::ks_err;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use rustutils::system_properties::PropertyWatcher;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The system property that lists the features whose kill switch is engaged.
+const KILL_SWITCH_PROPERTY: &str = "persist.keystore.kill_switches";
+
+/// Features that are gated by a flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// `IKeystoreServiceExtension::renameKey`.
+    RenameKey,
+    /// Throttling of key generations that request a unique ID.
+    UniqueIdThrottling,
+    /// The `android.security.credentialstore` service.
+    CredentialStore,
+    /// Caching of decrypted super-encrypted key blobs.
+    KeyMaterialCache,
+    /// The per app budget of StrongBox key generations.
+    StrongBoxGenerationBudget,
+    /// The per user caps of keys and key storage.
+    UserKeyLimits,
+    /// Argon2id derivation of the keys that encrypt super keys from passwords.
+    Argon2idSuperKeys,
+    /// The binding of the database to the device, which is checked at startup.
+    DatabaseBinding,
+    /// The super key tier of keys that only a biometric can authorize. Its kill switch stops new
+    /// keys from using the tier, but the super keys of the tier are still unlocked, so that the
+    /// keys that use it already stay usable.
+    BiometricBoundSuperKeys,
+    /// Nonces of AES-GCM encryptions that are picked by keystore.
+    ManagedNonces,
+    /// Wrapping of the boot level keys by a KeyMint key while they are not in use.
+    WrappedBootLevelKeys,
+    /// Eviction of the super keys of background users under memory pressure.
+    SuperKeyEviction,
+    /// `IKeystoreServiceExtension::listEntriesStreamed`.
+    StreamedListEntries,
+    /// Caching of the grant lookups of `Domain::GRANT` key descriptors.
+    GrantCache,
+    /// The detector of abnormal key use.
+    AnomalyDetector,
+    /// The policy for keys whose patch level is newer than that of the running image.
+    PatchLevelPolicy,
+    /// Fingerprints of imported key material, to detect duplicate imports.
+    ImportFingerprints,
+    /// Escalation of stuck KeyMint calls to a restart of the KeyMint HAL.
+    HalRestartEscalation,
+    /// The resume-on-reboot copy of the AfterFirstUnlock super key.
+    RebootEscrow,
+    /// The migration of keys of the software Keymaster emulator to the TEE KeyMint.
+    SoftKeyMigration,
+}
+
+impl Feature {
+    /// All features in the order in which they are dumped.
+    pub const ALL: [Feature; 20] = [
+        Feature::RenameKey,
+        Feature::UniqueIdThrottling,
+        Feature::CredentialStore,
+        Feature::KeyMaterialCache,
+        Feature::StrongBoxGenerationBudget,
+        Feature::UserKeyLimits,
+        Feature::Argon2idSuperKeys,
+        Feature::DatabaseBinding,
+        Feature::BiometricBoundSuperKeys,
+        Feature::ManagedNonces,
+        Feature::WrappedBootLevelKeys,
+        Feature::SuperKeyEviction,
+        Feature::StreamedListEntries,
+        Feature::GrantCache,
+        Feature::AnomalyDetector,
+        Feature::PatchLevelPolicy,
+        Feature::ImportFingerprints,
+        Feature::HalRestartEscalation,
+        Feature::RebootEscrow,
+        Feature::SoftKeyMigration,
+    ];
+
+    /// The name of the aconfig flag of the feature.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RenameKey => "rename_key",
+            Self::UniqueIdThrottling => "unique_id_throttling",
+            Self::CredentialStore => "credential_store",
+            Self::KeyMaterialCache => "key_material_cache",
+            Self::StrongBoxGenerationBudget => "strongbox_generation_budget",
+            Self::UserKeyLimits => "user_key_limits",
+            Self::Argon2idSuperKeys => "argon2id_super_keys",
+            Self::DatabaseBinding => "database_binding",
+            Self::BiometricBoundSuperKeys => "biometric_bound_super_keys",
+            Self::ManagedNonces => "managed_nonces",
+            Self::WrappedBootLevelKeys => "wrapped_boot_level_keys",
+            Self::SuperKeyEviction => "super_key_eviction",
+            Self::StreamedListEntries => "streamed_list_entries",
+            Self::GrantCache => "grant_cache",
+            Self::AnomalyDetector => "anomaly_detector",
+            Self::PatchLevelPolicy => "patch_level_policy",
+            Self::ImportFingerprints => "import_fingerprints",
+            Self::HalRestartEscalation => "hal_restart_escalation",
+            Self::RebootEscrow => "reboot_escrow",
+            Self::SoftKeyMigration => "soft_key_migration",
+        }
+    }
+
+    /// Whether the feature can be turned off at runtime by its kill switch. This is not the
+    /// case for features that are set up only once, like a service registration.
+    pub fn has_kill_switch(&self) -> bool {
+        match self {
+            Self::RenameKey
+            | Self::UniqueIdThrottling
+            | Self::KeyMaterialCache
+            | Self::StrongBoxGenerationBudget
+            | Self::UserKeyLimits
+            | Self::Argon2idSuperKeys
+            | Self::BiometricBoundSuperKeys
+            | Self::ManagedNonces
+            | Self::SuperKeyEviction
+            | Self::StreamedListEntries
+            | Self::GrantCache
+            | Self::AnomalyDetector
+            | Self::PatchLevelPolicy
+            | Self::ImportFingerprints
+            | Self::HalRestartEscalation
+            | Self::RebootEscrow
+            | Self::SoftKeyMigration => true,
+            Self::CredentialStore | Self::DatabaseBinding | Self::WrappedBootLevelKeys => false,
+        }
+    }
+
+    fn read_flag(&self) -> bool {
+        match self {
+            Self::RenameKey => keystore2_flags::rename_key(),
+            Self::UniqueIdThrottling => keystore2_flags::unique_id_throttling(),
+            Self::CredentialStore => keystore2_flags::credential_store(),
+            Self::KeyMaterialCache => keystore2_flags::key_material_cache(),
+            Self::StrongBoxGenerationBudget => keystore2_flags::strongbox_generation_budget(),
+            Self::UserKeyLimits => keystore2_flags::user_key_limits(),
+            Self::Argon2idSuperKeys => keystore2_flags::argon2id_super_keys(),
+            Self::DatabaseBinding => keystore2_flags::database_binding(),
+            Self::BiometricBoundSuperKeys => keystore2_flags::biometric_bound_super_keys(),
+            Self::ManagedNonces => keystore2_flags::managed_nonces(),
+            Self::WrappedBootLevelKeys => keystore2_flags::wrapped_boot_level_keys(),
+            Self::SuperKeyEviction => keystore2_flags::super_key_eviction(),
+            Self::StreamedListEntries => keystore2_flags::streamed_list_entries(),
+            Self::GrantCache => keystore2_flags::grant_cache(),
+            Self::AnomalyDetector => keystore2_flags::anomaly_detector(),
+            Self::PatchLevelPolicy => keystore2_flags::patch_level_policy(),
+            Self::ImportFingerprints => keystore2_flags::import_fingerprints(),
+            Self::HalRestartEscalation => keystore2_flags::hal_restart_escalation(),
+            Self::RebootEscrow => keystore2_flags::reboot_escrow(),
+            Self::SoftKeyMigration => keystore2_flags::soft_key_migration(),
+        }
+    }
+
+    fn index(&self) -> usize {
+        Feature::ALL.iter().position(|f| f == self).unwrap()
+    }
+}
+
+/// The state of the kill switches of all features, in the order of `Feature::ALL`.
+#[derive(Default)]
+struct KillSwitches([AtomicBool; Feature::ALL.len()]);
+
+impl KillSwitches {
+    fn engaged(&self, feature: Feature) -> bool {
+        self.0[feature.index()].load(Ordering::Relaxed)
+    }
+
+    /// Engages the kill switches of the features that are named in `value`, a comma separated
+    /// list, and releases all others. Names of features without a kill switch are ignored.
+    fn update(&self, value: &str) {
+        let names: Vec<&str> = value.split(',').map(str::trim).collect();
+        for feature in Feature::ALL {
+            let engaged = feature.has_kill_switch() && names.contains(&feature.name());
+            if self.0[feature.index()].swap(engaged, Ordering::Relaxed) != engaged {
+                log::warn!(
+                    "Kill switch of {} {}.",
+                    feature.name(),
+                    if engaged { "engaged" } else { "released" }
+                );
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// The flag values read when the service started, in the order of `Feature::ALL`.
+    static ref FLAGS: [bool; Feature::ALL.len()] = Feature::ALL.map(|f| f.read_flag());
+    /// The kill switches, kept up to date with `KILL_SWITCH_PROPERTY` by `watch_kill_switches`.
+    static ref KILL_SWITCHES: KillSwitches = Default::default();
+}
+
+fn flag(feature: Feature) -> bool {
+    FLAGS[feature.index()]
+}
+
+/// Reads the flags and the kill switches of all features and logs them, and starts a thread
+/// that updates the kill switches when their property changes. Called once during service
+/// initialization, before any feature is used.
+pub fn init() {
+    match rustutils::system_properties::read(KILL_SWITCH_PROPERTY) {
+        Ok(value) => KILL_SWITCHES.update(value.as_deref().unwrap_or_default()),
+        Err(e) => log::error!("Failed to read {}: {:?}", KILL_SWITCH_PROPERTY, e),
+    }
+    for feature in Feature::ALL {
+        log::info!("Feature {}: flag {}.", feature.name(), flag(feature));
+    }
+    std::thread::spawn(|| {
+        watch_kill_switches()
+            .unwrap_or_else(|e| log::error!("watch_kill_switches failed:\n{:?}", e));
+    });
+}
+
+/// Watches `KILL_SWITCH_PROPERTY` and keeps `KILL_SWITCHES` up to date. Blocks waiting for
+/// system property changes, so must be run in its own thread.
+fn watch_kill_switches() -> Result<()> {
+    let mut w = PropertyWatcher::new(KILL_SWITCH_PROPERTY)
+        .context(ks_err!("PropertyWatcher::new failed"))?;
+    loop {
+        w.wait(None).context(ks_err!("property wait failed"))?;
+        let value =
+            w.read(|_n, v| Ok(v.to_string())).context(ks_err!("read of property failed"))?;
+        KILL_SWITCHES.update(&value);
+    }
+}
+
+/// Returns whether `feature` is enabled, i.e., its flag was set when the service started and
+/// its kill switch, if any, is not engaged.
+pub fn is_enabled(feature: Feature) -> bool {
+    flag(feature) && !KILL_SWITCHES.engaged(feature)
+}
+
+/// Returns whether the flag of `feature` was set when the service started, regardless of its
+/// kill switch. This is for features whose data must stay usable while the kill switch is
+/// engaged.
+pub fn is_flag_set(feature: Feature) -> bool {
+    flag(feature)
+}
+
+/// The dump section of the features.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "feature_flags",
+    header: "Feature flags (flag, kill switch, enabled):",
+    line: "  <feature>: <flag>, <kill_switch>, <enabled>",
+    fields: dump_fields!(feature: "string", flag: "bool", kill_switch: "string", enabled: "bool"),
+};
+
+/// Writes the state of all features to `writer`.
+pub fn dump(writer: &mut dyn Write) -> std::io::Result<()> {
+    DUMP_SECTION.write_header(writer)?;
+    for feature in Feature::ALL {
+        let kill_switch = if feature.has_kill_switch() {
+            if KILL_SWITCHES.engaged(feature) {
+                "engaged"
+            } else {
+                "off"
+            }
+        } else {
+            "none"
+        };
+        DUMP_SECTION.write_line(
+            writer,
+            &[&feature.name(), &flag(feature), &kill_switch, &is_enabled(feature)],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn feature_names_are_unique() {
+        let names: HashSet<&str> = Feature::ALL.iter().map(|f| f.name()).collect();
+        assert_eq!(Feature::ALL.len(), names.len());
+    }
+
+    #[test]
+    fn kill_switches_follow_property_value() {
+        let switches = KillSwitches::default();
+        switches.update("rename_key, grant_cache,credential_store");
+        assert!(switches.engaged(Feature::RenameKey));
+        assert!(switches.engaged(Feature::GrantCache));
+        // The credential store has no kill switch.
+        assert!(!switches.engaged(Feature::CredentialStore));
+        assert!(!switches.engaged(Feature::AnomalyDetector));
+
+        switches.update("grant_cache");
+        assert!(!switches.engaged(Feature::RenameKey));
+        assert!(switches.engaged(Feature::GrantCache));
+
+        switches.update("");
+        assert!(Feature::ALL.iter().all(|f| !switches.engaged(*f)));
+    }
+
+    #[test]
+    fn dump_lists_all_features() {
+        let mut out = Vec::new();
+        dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        for feature in Feature::ALL {
+            assert!(out.contains(&format!("  {}: {}, ", feature.name(), flag(feature))));
+        }
+        // The credential store has no kill switch.
+        assert!(out
+            .lines()
+            .any(|l| l.starts_with("  credential_store: ") && l.contains(", none, ")));
+    }
+}
@@ -0,0 +1,390 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Drives structured transactions against IKeystoreService and IKeystoreSecurityLevel.
+//! Unlike a random parcel fuzzer, every transaction is well-formed, so the fuzzer gets past
+//! unmarshalling and exercises the service layer. Service calls are expected to fail
+//! gracefully; only panics, e.g., due to poisoned locks, are reported as crashes.
+//! The keys created by an input are deleted after it ran, so that the fuzzer does not fill up
+//! the storage of the KeyMint devices over many iterations.
+
+#![allow(missing_docs)]
+#![no_main]
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose,
+    PaddingMode::PaddingMode, SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata,
+};
+use binder::Strong;
+use keystore2::globals::DB_PATH;
+use keystore2::id_rotation::IdRotationState;
+use keystore2::service::KeystoreService;
+use libfuzzer_sys::{arbitrary::Arbitrary, fuzz_target};
+use std::sync::OnceLock;
+
+// Bound the number of commands per input, so that a single input cannot exhaust the
+// operation slots or the storage of the device.
+const MAX_COMMANDS: usize = 16;
+
+#[derive(Arbitrary, Debug)]
+enum FuzzDomain {
+    App,
+    Selinux,
+    Grant,
+    KeyId,
+    Blob,
+}
+
+impl From<FuzzDomain> for Domain {
+    fn from(domain: FuzzDomain) -> Self {
+        match domain {
+            FuzzDomain::App => Domain::APP,
+            FuzzDomain::Selinux => Domain::SELINUX,
+            FuzzDomain::Grant => Domain::GRANT,
+            FuzzDomain::KeyId => Domain::KEY_ID,
+            FuzzDomain::Blob => Domain::BLOB,
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct FuzzKeyDescriptor {
+    domain: FuzzDomain,
+    nspace: i64,
+    alias: Option<String>,
+    blob: Option<Vec<u8>>,
+}
+
+impl From<FuzzKeyDescriptor> for KeyDescriptor {
+    fn from(key: FuzzKeyDescriptor) -> Self {
+        KeyDescriptor {
+            domain: key.domain.into(),
+            nspace: key.nspace,
+            alias: key.alias,
+            blob: key.blob,
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum FuzzKeyParameter {
+    Algorithm(u8),
+    KeySize(i32),
+    Purpose(u8),
+    Digest(u8),
+    Padding(u8),
+    BlockMode(u8),
+    EcCurve(u8),
+    MinMacLength(i32),
+    MacLength(i32),
+    NoAuthRequired,
+    UserSecureId(i64),
+    AuthTimeout(i32),
+    UnlockedDeviceRequired,
+    AttestationChallenge(Vec<u8>),
+}
+
+fn pick<T: Copy>(values: &[T], index: u8) -> T {
+    values[index as usize % values.len()]
+}
+
+impl From<FuzzKeyParameter> for KeyParameter {
+    fn from(param: FuzzKeyParameter) -> Self {
+        let (tag, value) = match param {
+            FuzzKeyParameter::Algorithm(i) => (
+                Tag::ALGORITHM,
+                KeyParameterValue::Algorithm(pick(
+                    &[Algorithm::RSA, Algorithm::EC, Algorithm::AES, Algorithm::HMAC],
+                    i,
+                )),
+            ),
+            FuzzKeyParameter::KeySize(size) => (Tag::KEY_SIZE, KeyParameterValue::Integer(size)),
+            FuzzKeyParameter::Purpose(i) => (
+                Tag::PURPOSE,
+                KeyParameterValue::KeyPurpose(pick(
+                    &[
+                        KeyPurpose::ENCRYPT,
+                        KeyPurpose::DECRYPT,
+                        KeyPurpose::SIGN,
+                        KeyPurpose::VERIFY,
+                        KeyPurpose::AGREE_KEY,
+                        KeyPurpose::ATTEST_KEY,
+                    ],
+                    i,
+                )),
+            ),
+            FuzzKeyParameter::Digest(i) => (
+                Tag::DIGEST,
+                KeyParameterValue::Digest(pick(
+                    &[Digest::NONE, Digest::SHA1, Digest::SHA_2_256, Digest::SHA_2_512],
+                    i,
+                )),
+            ),
+            FuzzKeyParameter::Padding(i) => (
+                Tag::PADDING,
+                KeyParameterValue::PaddingMode(pick(
+                    &[
+                        PaddingMode::NONE,
+                        PaddingMode::RSA_OAEP,
+                        PaddingMode::RSA_PSS,
+                        PaddingMode::RSA_PKCS1_1_5_SIGN,
+                        PaddingMode::PKCS7,
+                    ],
+                    i,
+                )),
+            ),
+            FuzzKeyParameter::BlockMode(i) => (
+                Tag::BLOCK_MODE,
+                KeyParameterValue::BlockMode(pick(
+                    &[BlockMode::ECB, BlockMode::CBC, BlockMode::CTR, BlockMode::GCM],
+                    i,
+                )),
+            ),
+            FuzzKeyParameter::EcCurve(i) => (
+                Tag::EC_CURVE,
+                KeyParameterValue::EcCurve(pick(
+                    &[EcCurve::P_224, EcCurve::P_256, EcCurve::P_384, EcCurve::CURVE_25519],
+                    i,
+                )),
+            ),
+            FuzzKeyParameter::MinMacLength(len) => {
+                (Tag::MIN_MAC_LENGTH, KeyParameterValue::Integer(len))
+            }
+            FuzzKeyParameter::MacLength(len) => (Tag::MAC_LENGTH, KeyParameterValue::Integer(len)),
+            FuzzKeyParameter::NoAuthRequired => {
+                (Tag::NO_AUTH_REQUIRED, KeyParameterValue::BoolValue(true))
+            }
+            FuzzKeyParameter::UserSecureId(sid) => {
+                (Tag::USER_SECURE_ID, KeyParameterValue::LongInteger(sid))
+            }
+            FuzzKeyParameter::AuthTimeout(timeout) => {
+                (Tag::AUTH_TIMEOUT, KeyParameterValue::Integer(timeout))
+            }
+            FuzzKeyParameter::UnlockedDeviceRequired => {
+                (Tag::UNLOCKED_DEVICE_REQUIRED, KeyParameterValue::BoolValue(true))
+            }
+            FuzzKeyParameter::AttestationChallenge(challenge) => {
+                (Tag::ATTESTATION_CHALLENGE, KeyParameterValue::Blob(challenge))
+            }
+        };
+        KeyParameter { tag, value }
+    }
+}
+
+fn to_params(params: Vec<FuzzKeyParameter>) -> Vec<KeyParameter> {
+    params.into_iter().map(KeyParameter::from).collect()
+}
+
+#[derive(Arbitrary, Debug)]
+enum FuzzCommand {
+    GetKeyEntry {
+        key: FuzzKeyDescriptor,
+    },
+    UpdateSubcomponent {
+        key: FuzzKeyDescriptor,
+        public_cert: Option<Vec<u8>>,
+        certificate_chain: Option<Vec<u8>>,
+    },
+    ListEntries {
+        domain: FuzzDomain,
+        nspace: i64,
+    },
+    ListEntriesBatched {
+        domain: FuzzDomain,
+        nspace: i64,
+        start_past_alias: Option<String>,
+    },
+    GetNumberOfEntries {
+        domain: FuzzDomain,
+        nspace: i64,
+    },
+    DeleteKey {
+        key: FuzzKeyDescriptor,
+    },
+    Grant {
+        key: FuzzKeyDescriptor,
+        grantee_uid: i32,
+        access_vector: i32,
+    },
+    Ungrant {
+        key: FuzzKeyDescriptor,
+        grantee_uid: i32,
+    },
+    GenerateKey {
+        strongbox: bool,
+        key: FuzzKeyDescriptor,
+        attestation_key: Option<FuzzKeyDescriptor>,
+        params: Vec<FuzzKeyParameter>,
+        flags: i32,
+        entropy: Vec<u8>,
+    },
+    ImportKey {
+        strongbox: bool,
+        key: FuzzKeyDescriptor,
+        params: Vec<FuzzKeyParameter>,
+        flags: i32,
+        key_data: Vec<u8>,
+    },
+    Operation {
+        strongbox: bool,
+        key: FuzzKeyDescriptor,
+        params: Vec<FuzzKeyParameter>,
+        forced: bool,
+        aad: Option<Vec<u8>>,
+        input: Vec<Vec<u8>>,
+        signature: Option<Vec<u8>>,
+        abort: bool,
+    },
+}
+
+fn keystore_service() -> &'static Strong<dyn IKeystoreService> {
+    static SERVICE: OnceLock<Strong<dyn IKeystoreService>> = OnceLock::new();
+    SERVICE.get_or_init(|| {
+        // Keep the fuzzer's keys out of the database of the running Keystore 2.0 instance.
+        let db_path = std::env::temp_dir().join("keystore2_service_fuzzer");
+        std::fs::create_dir_all(&db_path).expect("Failed to create database directory.");
+        *DB_PATH.write().expect("Could not lock DB_PATH.") = db_path.clone();
+        let (service, _) = KeystoreService::new_native_binder(IdRotationState::new(&db_path))
+            .unwrap_or_else(|e| {
+                panic!("Failed to create android.system.keystore2 service because of {:?}", e);
+            });
+        service
+    })
+}
+
+fn security_level(strongbox: bool) -> Option<Strong<dyn IKeystoreSecurityLevel>> {
+    let level =
+        if strongbox { SecurityLevel::STRONGBOX } else { SecurityLevel::TRUSTED_ENVIRONMENT };
+    keystore_service().getSecurityLevel(level).ok()
+}
+
+/// A key created by the fuzzer, and the security level that created it.
+struct CreatedKey {
+    sec_level: Strong<dyn IKeystoreSecurityLevel>,
+    key: KeyDescriptor,
+}
+
+impl CreatedKey {
+    fn new(sec_level: Strong<dyn IKeystoreSecurityLevel>, metadata: KeyMetadata) -> Self {
+        Self { sec_level, key: metadata.key }
+    }
+
+    /// Deletes the key. Keys with `Domain::BLOB` are not in the database, so they are deleted
+    /// through the security level, which deletes them from KeyMint right away. All other keys
+    /// are deleted through the service, and their blobs are deleted from KeyMint by the garbage
+    /// collector.
+    fn delete(self) {
+        let _ = if self.key.domain == Domain::BLOB {
+            self.sec_level.deleteKey(&self.key)
+        } else {
+            keystore_service().deleteKey(&self.key)
+        };
+    }
+}
+
+// Errors returned by the service are expected and ignored. The results are bound to `_` on
+// purpose, so that the fuzzer only fails on panics. Keys that were created are added to
+// `created`.
+fn run(command: FuzzCommand, created: &mut Vec<CreatedKey>) {
+    let ks = keystore_service();
+    match command {
+        FuzzCommand::GetKeyEntry { key } => {
+            let _ = ks.getKeyEntry(&key.into());
+        }
+        FuzzCommand::UpdateSubcomponent { key, public_cert, certificate_chain } => {
+            let _ = ks.updateSubcomponent(
+                &key.into(),
+                public_cert.as_deref(),
+                certificate_chain.as_deref(),
+            );
+        }
+        FuzzCommand::ListEntries { domain, nspace } => {
+            let _ = ks.listEntries(domain.into(), nspace);
+        }
+        FuzzCommand::ListEntriesBatched { domain, nspace, start_past_alias } => {
+            let _ = ks.listEntriesBatched(domain.into(), nspace, start_past_alias.as_deref());
+        }
+        FuzzCommand::GetNumberOfEntries { domain, nspace } => {
+            let _ = ks.getNumberOfEntries(domain.into(), nspace);
+        }
+        FuzzCommand::DeleteKey { key } => {
+            let _ = ks.deleteKey(&key.into());
+        }
+        FuzzCommand::Grant { key, grantee_uid, access_vector } => {
+            let _ = ks.grant(&key.into(), grantee_uid, access_vector);
+        }
+        FuzzCommand::Ungrant { key, grantee_uid } => {
+            let _ = ks.ungrant(&key.into(), grantee_uid);
+        }
+        FuzzCommand::GenerateKey { strongbox, key, attestation_key, params, flags, entropy } => {
+            if let Some(sec_level) = security_level(strongbox) {
+                let attestation_key = attestation_key.map(KeyDescriptor::from);
+                if let Ok(metadata) = sec_level.generateKey(
+                    &key.into(),
+                    attestation_key.as_ref(),
+                    &to_params(params),
+                    flags,
+                    &entropy,
+                ) {
+                    created.push(CreatedKey::new(sec_level, metadata));
+                }
+            }
+        }
+        FuzzCommand::ImportKey { strongbox, key, params, flags, key_data } => {
+            if let Some(sec_level) = security_level(strongbox) {
+                if let Ok(metadata) =
+                    sec_level.importKey(&key.into(), None, &to_params(params), flags, &key_data)
+                {
+                    created.push(CreatedKey::new(sec_level, metadata));
+                }
+            }
+        }
+        FuzzCommand::Operation { strongbox, key, params, forced, aad, input, signature, abort } => {
+            let Some(sec_level) = security_level(strongbox) else { return };
+            let Ok(response) = sec_level.createOperation(&key.into(), &to_params(params), forced)
+            else {
+                return;
+            };
+            let Some(op) = response.iOperation else { return };
+            if let Some(aad) = aad {
+                let _ = op.updateAad(&aad);
+            }
+            for chunk in input {
+                let _ = op.update(&chunk);
+            }
+            if abort {
+                let _ = op.abort();
+            } else {
+                let _ = op.finish(None, signature.as_deref());
+            }
+        }
+    }
+}
+
+fuzz_target!(|commands: Vec<FuzzCommand>| {
+    let mut created = Vec::new();
+    for command in commands.into_iter().take(MAX_COMMANDS) {
+        run(command, &mut created);
+    }
+    for key in created {
+        key.delete();
+    }
+});
@@ -16,8 +16,7 @@
 
 #![no_main]
 
-use keystore2::{legacy_blob::LegacyBlobLoader, utils::ui_opts_2_compat};
-use keystore2_aaid::get_aaid;
+use keystore2::{attestation_app_id, legacy_blob::LegacyBlobLoader, utils::ui_opts_2_compat};
 use keystore2_apc_compat::ApcHal;
 use keystore2_crypto::{
     aes_gcm_decrypt, aes_gcm_encrypt, ec_key_generate_key, ec_key_get0_public_key,
@@ -185,7 +184,7 @@ fuzz_target!(|commands: Vec<FuzzCommand>| {
                 get_hidl_instances(hidl_package, major_version, minor_version, hidl_interface_name);
             }
             FuzzCommand::GetAaid { aaid_uid } => {
-                let _res = get_aaid(aaid_uid);
+                let _res = attestation_app_id::get(aaid_uid);
             }
             FuzzCommand::Hal { opt, prompt_text, locale, extra_data } => {
                 let hal = ApcHal::try_get_service();
@@ -17,19 +17,49 @@
 //! a thread on demand which will query the database for unreferenced key entries,
 //! optionally dispose of sensitive key material appropriately, and then delete
 //! the key entry from the database.
+//! Additionally, `run_now()` runs a full collection cycle synchronously, which is used where
+//! deterministic cleanup is required.
+//! Key blobs of rollback resistant keys are only deleted from the database once KeyMint
+//! confirmed their deletion. If `deleteKey` fails, the blob is retried by a later collection
+//! cycle after a growing delay.
+//! Once per `CONSISTENCY_CHECK_INTERVAL`, when a background collection runs out of work, and
+//! with every synchronous run, the garbage collector also deletes grants, key metadata, and key
+//! parameters of key entries that no longer exist. Such anomalies are logged and reported as
+//! the DATABASE_CONSISTENCY_STATS atom.
 
 use crate::ks_err;
 use crate::{
     async_task,
+    background_jobs::{self, JobGuard},
     database::{BlobMetaData, KeystoreDB, Uuid},
+    lock_stats::ProfiledRwLock,
+    metrics_store::log_database_consistency_stats,
     super_key::SuperKeyManager,
 };
+use android_security_maintenance::aidl::android::security::maintenance::BackgroundJob::BackgroundJob;
 use anyhow::{Context, Result};
 use async_task::AsyncTask;
 use std::sync::{
     atomic::{AtomicU8, Ordering},
-    Arc, RwLock,
+    mpsc::channel,
+    Arc,
 };
+use std::time::{Duration, Instant};
+
+/// Minimum time between two consistency checks run by background collections.
+const CONSISTENCY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Resources reclaimed by a synchronous garbage collection run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Number of database rows purged.
+    pub rows_purged: usize,
+    /// Number of key blobs invalidated with their KeyMint backend.
+    pub blobs_invalidated: usize,
+    /// Number of rows deleted by the consistency check because they referenced key entries
+    /// that no longer exist.
+    pub anomalies_repaired: usize,
+}
 
 pub struct Gc {
     async_task: Arc<AsyncTask>,
@@ -48,7 +78,7 @@ impl Gc {
         F: FnOnce() -> (
                 Box<dyn Fn(&Uuid, &[u8]) -> Result<()> + Send + 'static>,
                 KeystoreDB,
-                Arc<RwLock<SuperKeyManager>>,
+                Arc<ProfiledRwLock<SuperKeyManager>>,
             ) + Send
             + 'static,
     {
@@ -67,6 +97,8 @@ impl Gc {
                 async_task: weak_at,
                 super_key,
                 notified,
+                job: None,
+                last_consistency_check: None,
             });
         });
         Self { async_task, notified }
@@ -80,6 +112,20 @@ impl Gc {
             self.async_task.queue_lo(|shelf| shelf.get_downcast_mut::<GcInternal>().unwrap().step())
         }
     }
+
+    /// Runs a full garbage collection cycle and blocks until no more orphaned or superseded blobs
+    /// are left. The cycle is queued on the async_task's high priority queue, so it is
+    /// serialized with the background collection triggered by `notify_gc`.
+    /// Returns the resources reclaimed by this cycle.
+    pub fn run_now(&self) -> Result<GcStats> {
+        let (sender, receiver) = channel();
+        self.async_task.queue_hi(move |shelf| {
+            let result = shelf.get_downcast_mut::<GcInternal>().unwrap().run_to_completion();
+            // The receiver may have gone away. There is nothing we can do about that.
+            let _ = sender.send(result);
+        });
+        receiver.recv().context(ks_err!("Garbage collection task did not report back."))?
+    }
 }
 
 struct GcInternal {
@@ -88,8 +134,13 @@ struct GcInternal {
     invalidate_key: Box<dyn Fn(&Uuid, &[u8]) -> Result<()> + Send + 'static>,
     db: KeystoreDB,
     async_task: std::sync::Weak<AsyncTask>,
-    super_key: Arc<RwLock<SuperKeyManager>>,
+    super_key: Arc<ProfiledRwLock<SuperKeyManager>>,
     notified: Arc<AtomicU8>,
+    /// Tracks a background collection from its first step with work to do until it runs out of
+    /// blobs to delete.
+    job: Option<JobGuard<'static>>,
+    /// When the consistency check last ran.
+    last_consistency_check: Option<Instant>,
 }
 
 impl GcInternal {
@@ -101,18 +152,31 @@ impl GcInternal {
     /// with threads on the critical path, deleted blobs are loaded in batches.
     fn process_one_key(&mut self) -> Result<()> {
         if self.superseded_blobs.is_empty() {
-            let blobs = self
-                .db
-                .handle_next_superseded_blobs(&self.deleted_blob_ids, 20)
-                .context(ks_err!("Trying to handle superseded blob."))?;
-            self.deleted_blob_ids = vec![];
-            self.superseded_blobs = blobs;
+            self.load_next_superseded_blobs()?;
         }
+        self.invalidate_next_blob()?;
+        Ok(())
+    }
+
+    /// Deletes the blobs processed so far from the database and loads the next batch of
+    /// superseded blobs. Returns the number of database rows purged.
+    fn load_next_superseded_blobs(&mut self) -> Result<usize> {
+        let (blobs, rows_purged) = self
+            .db
+            .handle_next_superseded_blobs(&self.deleted_blob_ids, 20)
+            .context(ks_err!("Trying to handle superseded blob."))?;
+        self.deleted_blob_ids = vec![];
+        self.superseded_blobs = blobs;
+        Ok(rows_purged)
+    }
 
+    /// Pops the next superseded blob and invalidates it with its KeyMint backend if possible.
+    /// Returns true if a key blob was invalidated.
+    fn invalidate_next_blob(&mut self) -> Result<bool> {
         if let Some((blob_id, blob, blob_metadata)) = self.superseded_blobs.pop() {
             // Add the next blob_id to the deleted blob ids list. So it will be
             // removed from the database regardless of whether the following
-            // succeeds or not.
+            // succeeds or not, unless its deletion must be confirmed.
             self.deleted_blob_ids.push(blob_id);
 
             // If the key has a km_uuid we try to get the corresponding device
@@ -120,16 +184,68 @@ impl GcInternal {
             // (At this time keys may get deleted without having the super encryption
             // key in this case we can only delete the key from the database.)
             if let Some(uuid) = blob_metadata.km_uuid() {
-                let blob = self
+                let result = self
                     .super_key
                     .read()
                     .unwrap()
                     .unwrap_key_if_required(&blob_metadata, &blob)
-                    .context(ks_err!("Trying to unwrap to-be-deleted blob.",))?;
-                (self.invalidate_key)(uuid, &blob).context(ks_err!("Trying to invalidate key."))?;
+                    .context(ks_err!("Trying to unwrap to-be-deleted blob.",))
+                    .and_then(|blob| {
+                        (self.invalidate_key)(uuid, &blob)
+                            .context(ks_err!("Trying to invalidate key."))
+                    });
+                if let Err(e) = result {
+                    // Blobs of rollback resistant keys are kept until KeyMint confirms their
+                    // deletion, so that the key slot they occupy is not leaked.
+                    if self
+                        .db
+                        .postpone_blob_deletion(blob_id)
+                        .context(ks_err!("Trying to postpone blob deletion."))?
+                    {
+                        self.deleted_blob_ids.pop();
+                    }
+                    return Err(e);
+                }
+                return Ok(true);
             }
         }
-        Ok(())
+        Ok(false)
+    }
+
+    /// Deletes rows that reference key entries which no longer exist and reports them.
+    /// Returns the number of rows deleted.
+    fn check_consistency(&mut self) -> Result<usize> {
+        self.last_consistency_check = Some(Instant::now());
+        let report =
+            self.db.check_consistency().context(ks_err!("Trying to check consistency."))?;
+        if report.anomalies() != 0 {
+            log::warn!("Consistency check repaired the database: {:?}", report);
+            log_database_consistency_stats(&report);
+        }
+        Ok(report.anomalies())
+    }
+
+    /// Processes all orphaned and superseded blobs until none are left. Unlike `step`, this
+    /// does not yield to other tasks in between. Failures to invalidate individual blobs are
+    /// logged but do not abort the cycle, because the blob is deleted from the database anyway.
+    fn run_to_completion(&mut self) -> Result<GcStats> {
+        let mut stats = GcStats::default();
+        let mut job = background_jobs::start(BackgroundJob::GARBAGE_COLLECTION, None);
+        loop {
+            if self.superseded_blobs.is_empty() {
+                stats.rows_purged += self.load_next_superseded_blobs()?;
+                if self.superseded_blobs.is_empty() {
+                    stats.anomalies_repaired = self.check_consistency()?;
+                    return Ok(stats);
+                }
+            }
+            match self.invalidate_next_blob() {
+                Ok(true) => stats.blobs_invalidated += 1,
+                Ok(false) => {}
+                Err(e) => log::error!("Error trying to delete blob entry. {:?}", e),
+            }
+            job.advance(1);
+        }
     }
 
     /// Processes one key and then schedules another attempt until it runs out of blobs to delete.
@@ -139,7 +255,12 @@ impl GcInternal {
             log::error!("Error trying to delete blob entry. {:?}", e);
         }
         // Schedule the next step. This gives high priority requests a chance to interleave.
-        if !self.deleted_blob_ids.is_empty() {
+        if !self.deleted_blob_ids.is_empty() || !self.superseded_blobs.is_empty() {
+            self.job
+                .get_or_insert_with(|| {
+                    background_jobs::start(BackgroundJob::GARBAGE_COLLECTION, None)
+                })
+                .advance(1);
             if let Some(at) = self.async_task.upgrade() {
                 if let Ok(0) =
                     self.notified.compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed)
@@ -149,6 +270,16 @@ impl GcInternal {
                     });
                 }
             }
+        } else {
+            self.job = None;
+            if self
+                .last_consistency_check
+                .map_or(true, |t| t.elapsed() >= CONSISTENCY_CHECK_INTERVAL)
+            {
+                if let Err(e) = self.check_consistency() {
+                    log::error!("Error trying to check database consistency. {:?}", e);
+                }
+            }
         }
     }
 }
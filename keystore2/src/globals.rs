@@ -16,12 +16,25 @@
 //! database connections and connections to services that Keystore needs
 //! to talk to.
 
+use crate::anomaly_detector::AnomalyDetector;
+use crate::background_jobs;
+use crate::deprecation::DeprecationEvents;
 use crate::gc::Gc;
+use crate::hal_health::{self, HalHealth, InitRestarter};
+use crate::key_operation_stats::KeyOperationStats;
 use crate::km_compat::{BacklevelKeyMintWrapper, KeyMintV1};
 use crate::ks_err;
 use crate::legacy_blob::LegacyBlobLoader;
 use crate::legacy_importer::LegacyImporter;
+use crate::lock_stats::ProfiledRwLock;
+use crate::managed_nonce::ManagedNonces;
+use crate::operation::UidPriorityTable;
+use crate::patch_level_policy::PatchLevelPolicy;
+use crate::reserved_alias;
+use crate::strongbox_budget::GenerationBudget;
 use crate::super_key::SuperKeyManager;
+use crate::unique_id::UniqueIdTracker;
+use crate::user_limits::UserLimits;
 use crate::utils::watchdog as wd;
 use crate::{async_task::AsyncTask, database::MonotonicRawTime};
 use crate::{
@@ -43,6 +56,7 @@ use android_hardware_security_secureclock::aidl::android::hardware::security::se
     ISecureClock::BpSecureClock, ISecureClock::ISecureClock,
 };
 use android_security_compat::aidl::android::security::compat::IKeystoreCompatService::IKeystoreCompatService;
+use android_security_maintenance::aidl::android::security::maintenance::BackgroundJob::BackgroundJob;
 use anyhow::{Context, Result};
 use binder::get_declared_instances;
 use binder::FromIBinder;
@@ -67,6 +81,7 @@ pub fn create_thread_local_db() -> KeystoreDB {
     let mut db = KeystoreDB::new(&db_path, Some(GC.clone())).expect("Failed to open database.");
 
     DB_INIT.call_once(|| {
+        let _job = background_jobs::start(BackgroundJob::STARTUP_CLEANUP, None);
         log::info!("Touching Keystore 2.0 database for this first time since boot.");
         db.insert_last_off_body(MonotonicRawTime::now());
         log::info!("Calling cleanup leftovers.");
@@ -80,6 +95,12 @@ pub fn create_thread_local_db() -> KeystoreDB {
                 n
             );
         }
+        db.reset_blob_deletion_delays().expect("Failed to reset blob deletion delays on startup.");
+        match reserved_alias::quarantine_squatted_aliases(&mut db) {
+            Ok(0) => {}
+            Ok(n) => log::warn!("Quarantined {} app keys with reserved alias prefixes.", n),
+            Err(e) => log::error!("{:?}", e),
+        }
     });
     db
 }
@@ -142,7 +163,8 @@ lazy_static! {
     pub static ref DB_PATH: RwLock<PathBuf> = RwLock::new(
         Path::new("/data/misc/keystore").to_path_buf());
     /// Runtime database of unwrapped super keys.
-    pub static ref SUPER_KEY: Arc<RwLock<SuperKeyManager>> = Default::default();
+    pub static ref SUPER_KEY: Arc<ProfiledRwLock<SuperKeyManager>> =
+        Arc::new(ProfiledRwLock::new("SuperKeyManager", Default::default()));
     /// Map of KeyMint devices.
     static ref KEY_MINT_DEVICES: Mutex<DevicesMap<dyn IKeyMintDevice>> = Default::default();
     /// Timestamp service.
@@ -161,8 +183,33 @@ lazy_static! {
         Arc::new(LegacyImporter::new(Arc::new(Default::default())));
     /// Background thread which handles logging via statsd and logd
     pub static ref LOGS_HANDLER: Arc<AsyncTask> = Default::default();
-
-    static ref GC: Arc<Gc> = Arc::new(Gc::new_init_with(ASYNC_TASK.clone(), || {
+    /// Priority classes of operation owners as reported by ActivityManager.
+    pub static ref UID_PRIORITIES: Arc<UidPriorityTable> = Default::default();
+    /// Per app state of unique ID attestation requests.
+    pub static ref UNIQUE_ID_REQUESTS: UniqueIdTracker = Default::default();
+    /// Per app budget of StrongBox key generations.
+    pub static ref STRONGBOX_GENERATIONS: GenerationBudget = GenerationBudget::from_property();
+    /// Per key statistics of operation outcomes.
+    pub static ref KEY_OPERATION_STATS: KeyOperationStats = Default::default();
+    /// Per user caps of keys and key storage.
+    pub static ref USER_LIMITS: UserLimits = UserLimits::from_properties();
+    /// Uses of deprecated key parameters per app.
+    pub static ref DEPRECATION_EVENTS: DeprecationEvents = Default::default();
+    /// Reserved nonce counters of keys with managed nonces.
+    pub static ref MANAGED_NONCES: ManagedNonces = Default::default();
+    /// Detector of abnormal key use.
+    pub static ref ANOMALY_DETECTOR: AnomalyDetector = AnomalyDetector::from_property();
+    /// The policy for keys whose patch level is newer than that of the running image.
+    pub static ref PATCH_LEVEL_POLICY: PatchLevelPolicy = PatchLevelPolicy::from_properties();
+    /// Tracks stuck KeyMint calls and escalates them to HAL restarts.
+    pub static ref HAL_HEALTH: Arc<HalHealth> = HalHealth::new(
+        hal_health::HARD_LIMIT,
+        hal_health::MIN_RESTART_INTERVAL,
+        Box::new(InitRestarter),
+    );
+
+    /// The key garbage collector.
+    pub static ref GC: Arc<Gc> = Arc::new(Gc::new_init_with(ASYNC_TASK.clone(), || {
         (
             Box::new(|uuid, blob| {
                 let km_dev = get_keymint_dev_by_uuid(uuid).map(|(dev, _)| dev)?;
@@ -180,7 +227,7 @@ lazy_static! {
 /// Determine the service name for a KeyMint device of the given security level
 /// gotten by binder service from the device and determining what services
 /// are available.
-fn keymint_service_name(security_level: &SecurityLevel) -> Result<Option<String>> {
+pub fn keymint_service_name(security_level: &SecurityLevel) -> Result<Option<String>> {
     let keymint_descriptor: &str = <BpKeyMintDevice as IKeyMintDevice>::get_descriptor();
     let keymint_instances = get_declared_instances(keymint_descriptor).unwrap();
 
@@ -303,6 +350,9 @@ fn connect_keymint(
         }
     };
 
+    #[cfg(feature = "keystore2_fault_injection")]
+    let keymint = crate::fault_injection::FaultInjectingKeyMint::wrap(*security_level, keymint);
+
     let wp = wd::watch_millis("In connect_keymint: calling getHardwareInfo()", 500);
     let mut hw_info =
         map_km_error(keymint.getHardwareInfo()).context(ks_err!("Failed to get hardware info."))?;
@@ -343,6 +393,16 @@ pub fn get_keymint_device(
     }
 }
 
+/// Make a new connection to the keymint device of the given security level and replace the
+/// cached one, e.g., after its HAL was restarted, see `hal_health`. Callers that look the device
+/// up after this use the new connection.
+pub fn reconnect_keymint_device(security_level: &SecurityLevel) -> Result<()> {
+    let (dev, hw_info) =
+        connect_keymint(security_level).context(ks_err!("Cannot reconnect to Keymint"))?;
+    KEY_MINT_DEVICES.lock().unwrap().insert(*security_level, dev, hw_info);
+    Ok(())
+}
+
 /// Get a keymint device for the given uuid. This will only access the cache, but will not
 /// attempt to establish a new connection. It is assumed that the cache is already populated
 /// when this is called. This is a fair assumption, because service.rs iterates through all
@@ -0,0 +1,312 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a small cache of the grant lookups of `Domain::GRANT` key
+//! descriptors. Without it, every access through a grant queries the grant table.
+//!
+//! An entry maps the grantee uid and the grant id to the id of the granted key and the access
+//! vector of the grant. The access vector is all that the permission check of a grant needs,
+//! so the SELinux context of the caller is not part of the entry. Entries expire after `TTL`.
+//!
+//! Every transaction that changes the grant table, or that ends the life of a key, calls
+//! `note_change`. The database drops all entries with `apply_changes` once such a transaction
+//! has ended, whether it was committed or not. A lookup reads the generation of the cache
+//! before it starts its transaction, and its result is only cached if no change was applied
+//! since, so that a lookup that raced an ungrant cannot put the revoked grant back.
+//!
+//! Under memory pressure, `trim` drops the expired entries, or all entries at
+//! `TrimLevel::Critical`.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::memory_trim::TrimLevel;
+use crate::permission::KeyPermSet;
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a grant lookup is cached.
+const TTL: Duration = Duration::from_secs(10);
+
+/// The maximum number of cached grant lookups.
+const MAX_ENTRIES: usize = 256;
+
+struct Entry {
+    key_id: i64,
+    access_vector: KeyPermSet,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Entries by grantee uid and grant id.
+    map: HashMap<(u32, i64), Entry>,
+    /// Incremented whenever the entries are dropped because the grants changed.
+    generation: u64,
+}
+
+/// The dump section of the cache.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "grant_cache",
+    header: "Grant cache (entries, hits, misses, invalidations):",
+    line: "  <entries>, <hits>, <misses>, <invalidations>",
+    fields: dump_fields!(
+        entries: "uint64",
+        hits: "uint64",
+        misses: "uint64",
+        invalidations: "uint64",
+    ),
+};
+
+/// Cache of grant lookups. See the module documentation.
+#[derive(Default)]
+pub struct GrantCache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+lazy_static! {
+    /// The grant cache that is shared by all database connections.
+    pub static ref GRANT_CACHE: GrantCache = Default::default();
+}
+
+thread_local! {
+    /// Set if the transaction of this thread changed the grants.
+    static CHANGED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Records that the current transaction of this thread changes the grants or the liveness of
+/// keys.
+pub fn note_change() {
+    CHANGED.with(|changed| changed.set(true));
+}
+
+/// Drops all cached grant lookups if the transaction of this thread that just ended called
+/// `note_change`.
+pub fn apply_changes() {
+    if CHANGED.with(|changed| changed.replace(false)) {
+        GRANT_CACHE.invalidate();
+    }
+}
+
+impl GrantCache {
+    /// Returns the current generation. Pass it to `insert` with the result of a lookup that
+    /// started after this call.
+    pub fn generation(&self) -> u64 {
+        self.entries.lock().unwrap().generation
+    }
+
+    /// Returns the key id and the access vector of the grant `grant_id` to `grantee`, if they
+    /// are cached.
+    pub fn get(&self, grantee: u32, grant_id: i64) -> Option<(i64, KeyPermSet)> {
+        self.get_at(grantee, grant_id, Instant::now())
+    }
+
+    fn get_at(&self, grantee: u32, grant_id: i64, now: Instant) -> Option<(i64, KeyPermSet)> {
+        let mut entries = self.entries.lock().unwrap();
+        let found = match entries.map.get(&(grantee, grant_id)) {
+            Some(entry) if entry.expires > now => Some((entry.key_id, entry.access_vector)),
+            Some(_) => {
+                entries.map.remove(&(grantee, grant_id));
+                None
+            }
+            None => None,
+        };
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    /// Caches the result of a lookup of the grant `grant_id` to `grantee`, unless the grants
+    /// changed since `generation` was read. If the cache is full, expired entries are dropped,
+    /// and if that is not enough, the lookup is not cached.
+    pub fn insert(
+        &self,
+        grantee: u32,
+        grant_id: i64,
+        key_id: i64,
+        access_vector: KeyPermSet,
+        generation: u64,
+    ) {
+        self.insert_at(grantee, grant_id, key_id, access_vector, generation, Instant::now())
+    }
+
+    fn insert_at(
+        &self,
+        grantee: u32,
+        grant_id: i64,
+        key_id: i64,
+        access_vector: KeyPermSet,
+        generation: u64,
+        now: Instant,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation != generation {
+            return;
+        }
+        if entries.map.len() >= MAX_ENTRIES {
+            entries.map.retain(|_, e| e.expires > now);
+            if entries.map.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        entries
+            .map
+            .insert((grantee, grant_id), Entry { key_id, access_vector, expires: now + TTL });
+    }
+
+    /// Drops all entries, and makes lookups that started before this call uncacheable.
+    pub fn invalidate(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.map.clear();
+        entries.generation += 1;
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Drops the expired entries, or all entries at `TrimLevel::Critical`. Returns the number
+    /// of dropped entries. The generation is not changed, because the remaining entries and
+    /// lookups in flight are still valid.
+    pub fn trim(&self, level: TrimLevel) -> u64 {
+        self.trim_at(level, Instant::now())
+    }
+
+    fn trim_at(&self, level: TrimLevel, now: Instant) -> u64 {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.map.len();
+        match level {
+            TrimLevel::Moderate => entries.map.retain(|_, e| e.expires > now),
+            TrimLevel::Critical => entries.map.clear(),
+        }
+        (len - entries.map.len()) as u64
+    }
+
+    /// Returns the number of hits, misses, and invalidations.
+    pub fn stats(&self) -> (u64, u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.invalidations.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Writes the size and the statistics of the cache to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let entries = self.entries.lock().unwrap().map.len();
+        let (hits, misses, invalidations) = self.stats();
+        DUMP_SECTION.write_header(writer)?;
+        DUMP_SECTION.write_line(writer, &[&entries, &hits, &misses, &invalidations])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AV: KeyPermSet = KeyPermSet(1);
+
+    #[test]
+    fn hit_and_miss() {
+        let cache = GrantCache::default();
+        assert_eq!(None, cache.get(10, 1));
+        cache.insert(10, 1, 100, AV, cache.generation());
+        assert_eq!(Some((100, AV)), cache.get(10, 1));
+        // The grant id is only valid for its grantee.
+        assert_eq!(None, cache.get(11, 1));
+        assert_eq!(None, cache.get(10, 2));
+        assert_eq!((1, 3, 0), cache.stats());
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = GrantCache::default();
+        let now = Instant::now();
+        cache.insert_at(10, 1, 100, AV, cache.generation(), now);
+        assert_eq!(Some((100, AV)), cache.get_at(10, 1, now + TTL / 2));
+        assert_eq!(None, cache.get_at(10, 1, now + TTL));
+        assert!(cache.entries.lock().unwrap().map.is_empty());
+    }
+
+    #[test]
+    fn invalidate_drops_entries() {
+        let cache = GrantCache::default();
+        cache.insert(10, 1, 100, AV, cache.generation());
+        cache.insert(11, 2, 200, AV, cache.generation());
+        cache.invalidate();
+        assert_eq!(None, cache.get(10, 1));
+        assert_eq!(None, cache.get(11, 2));
+        assert_eq!((0, 2, 1), cache.stats());
+    }
+
+    #[test]
+    fn lookup_racing_a_change_is_not_cached() {
+        let cache = GrantCache::default();
+        // The lookup reads the generation, then an ungrant commits, then the lookup ends.
+        let generation = cache.generation();
+        cache.invalidate();
+        cache.insert(10, 1, 100, AV, generation);
+        assert_eq!(None, cache.get(10, 1));
+
+        cache.insert(10, 1, 100, AV, cache.generation());
+        assert_eq!(Some((100, AV)), cache.get(10, 1));
+    }
+
+    #[test]
+    fn full_cache_drops_expired_entries() {
+        let cache = GrantCache::default();
+        let now = Instant::now();
+        for i in 0..MAX_ENTRIES as i64 {
+            cache.insert_at(10, i, i, AV, 0, now);
+        }
+        // Not cached, because no entry expired yet.
+        cache.insert_at(11, 0, 0, AV, 0, now);
+        assert_eq!(None, cache.get_at(11, 0, now));
+
+        let later = now + TTL;
+        cache.insert_at(11, 0, 0, AV, 0, later);
+        assert_eq!(Some((0, AV)), cache.get_at(11, 0, later));
+        assert_eq!(1, cache.entries.lock().unwrap().map.len());
+    }
+
+    #[test]
+    fn trim_drops_expired_or_all_entries() {
+        let cache = GrantCache::default();
+        let now = Instant::now();
+        cache.insert_at(10, 1, 100, AV, cache.generation(), now);
+        cache.insert_at(10, 2, 200, AV, cache.generation(), now + TTL / 2);
+        assert_eq!(1, cache.trim_at(TrimLevel::Moderate, now + TTL));
+        assert_eq!(Some((200, AV)), cache.get_at(10, 2, now + TTL));
+        assert_eq!(1, cache.trim_at(TrimLevel::Critical, now + TTL));
+        assert_eq!(None, cache.get_at(10, 2, now + TTL));
+        // Trimming does not make lookups in flight uncacheable.
+        assert_eq!(0, cache.generation());
+    }
+
+    #[test]
+    fn apply_changes_invalidates_once() {
+        let generation = GRANT_CACHE.generation();
+        note_change();
+        apply_changes();
+        assert!(GRANT_CACHE.generation() > generation);
+        assert!(!CHANGED.with(|changed| changed.get()));
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module keeps track of KeyMint calls that are stuck in the HAL. A wedged KeyMint call
+//! blocks its binder thread forever, along with the locks that the thread holds, e.g., the lock
+//! of the operation, and every caller that queues up behind them.
+//!
+//! Calls into KeyMint are watched with `HalHealth::watch_call`. If a call has not returned after
+//! the hard limit, the watchdog escalates it:
+//!
+//! 1. The device is marked unhealthy, and `HalHealth::watch_call` fails new calls to it with
+//!    `SECURE_HW_COMMUNICATION_FAILED` instead of letting them queue up behind the stuck call.
+//! 2. A restart of the HAL is requested from init through `ctl.interface_restart`, at most once
+//!    per minimum restart interval. The restart fails the stuck call with a dead object error,
+//!    which releases its thread and locks.
+//! 3. The device is healthy again when all of its escalated calls have returned. If its HAL was
+//!    restarted, keystore then drops the cached connection to the old HAL and connects to the
+//!    restarted one, see `globals::reconnect_keymint_device`.
+//!
+//! Escalation is gated by `Feature::HalRestartEscalation` and needs the `watchdog` feature.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::error::{Error, ErrorCode};
+use crate::feature_flags::{self, Feature};
+use crate::globals::{keymint_service_name, reconnect_keymint_device};
+use crate::ks_err;
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a KeyMint call may take before it is escalated. StrongBox takes tens of seconds to
+/// generate large RSA keys, so this is well above the longest legitimate call.
+pub const HARD_LIMIT: Duration = Duration::from_secs(120);
+
+/// The minimum time between two restarts of the same HAL, so that a HAL that wedges again
+/// right away is not restarted in a loop.
+pub const MIN_RESTART_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The system property through which init restarts an AIDL service.
+const RESTART_PROPERTY: &str = "ctl.interface_restart";
+
+/// Restarts the KeyMint HAL of a security level.
+pub trait Restarter: Send + Sync {
+    /// Requests a restart of the HAL of `security_level`.
+    fn restart(&self, security_level: SecurityLevel) -> Result<()>;
+    /// Replaces the connection to the HAL of `security_level` after it was restarted.
+    fn reconnect(&self, security_level: SecurityLevel) -> Result<()>;
+}
+
+/// Requests restarts of KeyMint HALs from init.
+pub struct InitRestarter;
+
+impl Restarter for InitRestarter {
+    fn restart(&self, security_level: SecurityLevel) -> Result<()> {
+        let service_name = keymint_service_name(&security_level)
+            .context(ks_err!())?
+            .ok_or(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+            .context(ks_err!("No AIDL KeyMint instance for {:?}.", security_level))?;
+        rustutils::system_properties::write(RESTART_PROPERTY, &format!("aidl/{service_name}"))
+            .context(ks_err!("Failed to request a restart of {service_name}."))
+    }
+    fn reconnect(&self, security_level: SecurityLevel) -> Result<()> {
+        reconnect_keymint_device(&security_level).context(ks_err!())
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeviceHealth {
+    /// The escalated calls that have not returned yet.
+    stuck: u32,
+    escalations: u64,
+    restarts: u64,
+    fast_failures: u64,
+    last_restart: Option<Instant>,
+    /// A restart was requested, and the connection has to be replaced once the device is
+    /// healthy again.
+    reconnect_pending: bool,
+}
+
+/// The dump section of the health of the devices.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "keymint_health",
+    header: "KeyMint health (security level, stuck calls, escalations, restarts, fast failures):",
+    line: "  <security_level>, <stuck_calls>, <escalations>, <restarts>, <fast_failures>",
+    fields: dump_fields!(
+        security_level: "enum:SecurityLevel",
+        stuck_calls: "uint32",
+        escalations: "uint64",
+        restarts: "uint64",
+        fast_failures: "uint64",
+    ),
+};
+
+/// The health of the KeyMint devices. See the module documentation.
+pub struct HalHealth {
+    hard_limit: Duration,
+    min_restart_interval: Duration,
+    restarter: Box<dyn Restarter>,
+    devices: Mutex<BTreeMap<SecurityLevel, DeviceHealth>>,
+}
+
+impl HalHealth {
+    /// Creates a tracker that escalates calls after `hard_limit`, and restarts each HAL through
+    /// `restarter` at most once per `min_restart_interval`.
+    pub fn new(
+        hard_limit: Duration,
+        min_restart_interval: Duration,
+        restarter: Box<dyn Restarter>,
+    ) -> Arc<Self> {
+        Arc::new(Self { hard_limit, min_restart_interval, restarter, devices: Default::default() })
+    }
+
+    /// Fails with `SECURE_HW_COMMUNICATION_FAILED` while a call to the KeyMint device of
+    /// `security_level` is stuck.
+    fn check(&self, security_level: SecurityLevel) -> Result<()> {
+        let mut devices = self.devices.lock().unwrap();
+        match devices.get_mut(&security_level) {
+            Some(device) if device.stuck > 0 => {
+                device.fast_failures += 1;
+                Err(Error::Km(ErrorCode::SECURE_HW_COMMUNICATION_FAILED)).context(ks_err!(
+                    "KeyMint at {:?} is unhealthy: {} calls are stuck.",
+                    security_level,
+                    device.stuck
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Marks the device of `security_level` unhealthy because the call `id` is stuck, and
+    /// requests a restart of its HAL unless one was requested recently.
+    fn escalate(&self, security_level: SecurityLevel, id: &str) {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.entry(security_level).or_default();
+        device.stuck += 1;
+        device.escalations += 1;
+        log::error!(
+            "KeyMint call \"{}\" at {:?} is stuck for more than {:?}.",
+            id,
+            security_level,
+            self.hard_limit
+        );
+        let restart = !matches!(
+            device.last_restart,
+            Some(last) if last.elapsed() < self.min_restart_interval
+        );
+        if restart {
+            device.restarts += 1;
+            device.last_restart = Some(Instant::now());
+            device.reconnect_pending = true;
+        }
+        drop(devices);
+        if restart {
+            log::error!("Requesting a restart of KeyMint at {:?}.", security_level);
+            if let Err(e) = self.restarter.restart(security_level) {
+                log::error!("Failed to restart KeyMint at {:?}: {:?}", security_level, e);
+            }
+        }
+    }
+
+    /// Records that an escalated call to the device of `security_level` has returned, and
+    /// reconnects to the device once it is healthy again after a restart of its HAL.
+    fn recovered(&self, security_level: SecurityLevel) {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.entry(security_level).or_default();
+        device.stuck = device.stuck.saturating_sub(1);
+        if device.stuck != 0 {
+            return;
+        }
+        log::info!("KeyMint at {:?} is healthy again.", security_level);
+        let reconnect = std::mem::take(&mut device.reconnect_pending);
+        drop(devices);
+        if reconnect {
+            if let Err(e) = self.restarter.reconnect(security_level) {
+                log::error!("Failed to reconnect to KeyMint at {:?}: {:?}", security_level, e);
+            }
+        }
+    }
+
+    /// Watches a call into the KeyMint device of `security_level` like `wd::watch_millis`, and
+    /// escalates it if it is still pending after the hard limit. The call ends when the
+    /// returned guard is dropped. Fails instead of queuing the call behind a stuck call.
+    pub fn watch_call(
+        self: &Arc<Self>,
+        security_level: SecurityLevel,
+        id: &'static str,
+        millis: u64,
+    ) -> Result<HalCall> {
+        if !feature_flags::is_enabled(Feature::HalRestartEscalation) {
+            return Ok(HalCall {
+                _wp: wd::watch_millis_with(id, millis, move || {
+                    format!("SecurityLevel {:?}", security_level)
+                }),
+                escalation: None,
+            });
+        }
+        self.check(security_level).context(ks_err!("Not calling \"{}\".", id))?;
+        Ok(self.watch_escalating(security_level, id, millis))
+    }
+
+    fn watch_escalating(
+        self: &Arc<Self>,
+        security_level: SecurityLevel,
+        id: &'static str,
+        millis: u64,
+    ) -> HalCall {
+        let state = Arc::new(Mutex::new(CallState::Pending));
+        let (health, escalated_state) = (self.clone(), state.clone());
+        let wp = wd::watch_millis_with_escalation(
+            id,
+            millis,
+            move || format!("SecurityLevel {:?}", security_level),
+            self.hard_limit,
+            move || {
+                let mut state = escalated_state.lock().unwrap();
+                if *state == CallState::Pending {
+                    *state = CallState::Escalated;
+                    health.escalate(security_level, id);
+                }
+            },
+        );
+        HalCall { _wp: wp, escalation: Some((self.clone(), security_level, state)) }
+    }
+
+    /// Writes the health of the devices to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        DUMP_SECTION.write_header(writer)?;
+        for (security_level, device) in self.devices.lock().unwrap().iter() {
+            DUMP_SECTION.write_line(
+                writer,
+                &[
+                    &format!("{:?}", security_level),
+                    &device.stuck,
+                    &device.escalations,
+                    &device.restarts,
+                    &device.fast_failures,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CallState {
+    Pending,
+    Escalated,
+    Returned,
+}
+
+/// A KeyMint call watched by `HalHealth::watch_call`. Dropping it ends the call.
+pub struct HalCall {
+    _wp: Option<wd::WatchPoint>,
+    escalation: Option<(Arc<HalHealth>, SecurityLevel, Arc<Mutex<CallState>>)>,
+}
+
+impl Drop for HalCall {
+    fn drop(&mut self) {
+        if let Some((health, security_level, state)) = &self.escalation {
+            let mut state = state.lock().unwrap();
+            if *state == CallState::Escalated {
+                health.recovered(*security_level);
+            }
+            *state = CallState::Returned;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Records the restarts and reconnects instead of performing them.
+    #[derive(Default)]
+    struct Recorded {
+        restarts: Vec<SecurityLevel>,
+        reconnects: Vec<SecurityLevel>,
+    }
+
+    struct RecordingRestarter(Arc<Mutex<Recorded>>);
+
+    impl Restarter for RecordingRestarter {
+        fn restart(&self, security_level: SecurityLevel) -> Result<()> {
+            self.0.lock().unwrap().restarts.push(security_level);
+            Ok(())
+        }
+        fn reconnect(&self, security_level: SecurityLevel) -> Result<()> {
+            self.0.lock().unwrap().reconnects.push(security_level);
+            Ok(())
+        }
+    }
+
+    fn health(
+        hard_limit: Duration,
+        min_restart_interval: Duration,
+    ) -> (Arc<HalHealth>, Arc<Mutex<Recorded>>) {
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+        let health = HalHealth::new(
+            hard_limit,
+            min_restart_interval,
+            Box::new(RecordingRestarter(recorded.clone())),
+        );
+        (health, recorded)
+    }
+
+    fn is_unhealthy(health: &HalHealth, security_level: SecurityLevel) -> bool {
+        matches!(
+            health.check(security_level),
+            Err(e) if matches!(
+                e.root_cause().downcast_ref::<Error>(),
+                Some(Error::Km(ErrorCode::SECURE_HW_COMMUNICATION_FAILED))
+            )
+        )
+    }
+
+    #[test]
+    fn escalation_state_machine() {
+        let (health, restarts) = health(HARD_LIMIT, MIN_RESTART_INTERVAL);
+        let (tee, strongbox) = (SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX);
+        assert!(!is_unhealthy(&health, tee));
+
+        health.escalate(tee, "first");
+        health.escalate(tee, "second");
+        assert!(is_unhealthy(&health, tee));
+        assert!(!is_unhealthy(&health, strongbox));
+        // The second escalation falls within the minimum restart interval.
+        assert_eq!(vec![tee], restarts.lock().unwrap().restarts);
+
+        health.recovered(tee);
+        assert!(is_unhealthy(&health, tee));
+        assert!(restarts.lock().unwrap().reconnects.is_empty());
+        health.recovered(tee);
+        assert!(!is_unhealthy(&health, tee));
+        // The connection is replaced once, when the device is healthy again.
+        assert_eq!(vec![tee], restarts.lock().unwrap().reconnects);
+
+        let mut out = Vec::new();
+        health.dump(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("  TRUSTED_ENVIRONMENT, 0, 2, 1, 2\n"));
+    }
+
+    #[test]
+    fn restarts_again_after_the_interval() {
+        let (health, restarts) = health(HARD_LIMIT, Duration::ZERO);
+        let tee = SecurityLevel::TRUSTED_ENVIRONMENT;
+        health.escalate(tee, "first");
+        health.recovered(tee);
+        health.escalate(tee, "second");
+        assert_eq!(vec![tee, tee], restarts.lock().unwrap().restarts);
+    }
+
+    #[test]
+    fn calls_within_the_hard_limit_are_not_escalated() {
+        let (health, restarts) = health(Duration::from_millis(200), MIN_RESTART_INTERVAL);
+        let sec_level = SecurityLevel(1005);
+        drop(health.watch_escalating(sec_level, "quick call", 50));
+        thread::sleep(Duration::from_millis(400));
+        assert!(!is_unhealthy(&health, sec_level));
+        assert!(restarts.lock().unwrap().restarts.is_empty());
+    }
+
+    #[cfg(feature = "keystore2_fault_injection")]
+    #[test]
+    fn stuck_call_is_escalated_until_it_returns() {
+        use crate::error::map_km_error;
+        use crate::fault_injection::tests::EchoOperation;
+        use crate::fault_injection::{inject_stall, FaultInjectingOperation, KmCall};
+        use android_hardware_security_keymint::aidl::android::hardware::security::keymint::IKeyMintOperation::BnKeyMintOperation;
+        use android_hardware_security_keymint::binder::BinderFeatures;
+
+        let (health, restarts) = health(Duration::from_millis(200), MIN_RESTART_INTERVAL);
+        // Made up security level, see the tests of `fault_injection`.
+        let sec_level = SecurityLevel(1004);
+        let op = FaultInjectingOperation::wrap(
+            sec_level,
+            BnKeyMintOperation::new_binder(EchoOperation, BinderFeatures::default()),
+        );
+        inject_stall(sec_level, KmCall::Update, Duration::from_millis(1000), 0, 1);
+
+        let stuck = {
+            let (health, op) = (health.clone(), op.clone());
+            thread::spawn(move || {
+                let _call = health.watch_escalating(sec_level, "stuck update", 50);
+                map_km_error(op.update(&[1], None, None))
+            })
+        };
+        thread::sleep(Duration::from_millis(500));
+        // The stuck call has been escalated, and other callers fail fast.
+        assert!(is_unhealthy(&health, sec_level));
+        assert_eq!(vec![sec_level], restarts.lock().unwrap().restarts);
+
+        assert_eq!(Ok(vec![1]), stuck.join().unwrap());
+        assert!(!is_unhealthy(&health, sec_level));
+        assert_eq!(vec![sec_level], restarts.lock().unwrap().reconnects);
+        let _call = health.watch_escalating(sec_level, "next update", 50);
+        assert_eq!(Ok(vec![2]), map_km_error(op.update(&[2], None, None)));
+    }
+}
@@ -31,6 +31,19 @@ use std::time::{Duration, SystemTime};
 const ID_ROTATION_PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60); // Thirty days.
 static TIMESTAMP_FILE_NAME: &str = "timestamp";
 
+/// Returns the index of the ID rotation period that `creation_datetime` falls into. For
+/// Tag::UNIQUE_ID, the temporal counter value is defined as Tag::CREATION_DATETIME divided by
+/// 2592000000, dropping any remainder. The temporal counter value is effectively the index of
+/// the ID rotation period that we are currently in, with each ID rotation period being 30 days.
+pub fn id_rotation_period(creation_datetime: &SystemTime) -> Result<u64> {
+    let temporal_counter_value = creation_datetime
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .context(ks_err!("Failed to get epoch time"))?
+        .as_millis()
+        / ID_ROTATION_PERIOD.as_millis();
+    temporal_counter_value.try_into().context(ks_err!("Temporal counter value out of range."))
+}
+
 /// The IdRotationState stores the path to the timestamp file for deferred usage. The data
 /// partition is usually not available when Keystore 2.0 starts up. So this object is created
 /// and passed down to the users of the feature which can then query the timestamp on demand.
@@ -56,15 +69,7 @@ impl IdRotationState {
     ) -> Result<bool> {
         match fs::metadata(&self.timestamp_path) {
             Ok(metadata) => {
-                // For Tag::UNIQUE_ID, temporal counter value is defined as Tag::CREATION_DATETIME
-                // divided by 2592000000, dropping any remainder. Temporal counter value is
-                // effectively the index of the ID rotation period that we are currently in, with
-                // each ID rotation period being 30 days.
-                let temporal_counter_value = creation_datetime
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .context(ks_err!("Failed to get epoch time"))?
-                    .as_millis()
-                    / ID_ROTATION_PERIOD.as_millis();
+                let temporal_counter_value = id_rotation_period(creation_datetime)?;
 
                 // Calculate the beginning of the current ID rotation period, which is also the
                 // last time ID was rotated.
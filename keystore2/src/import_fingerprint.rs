@@ -0,0 +1,163 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module computes fingerprints of imported key material, so that keystore can tell when
+//! an app imports the same key under several aliases of a namespace.
+//!
+//! The fingerprint of plain key material is an HMAC-SHA256 over the algorithm, the key format,
+//! and the material, keyed with a random secret of the device in the `fingerprintkey` table.
+//! The fingerprints therefore do not confirm guesses of the key material without the secret,
+//! and they do not match across devices. PKCS#8 material is taken as is, so differently
+//! encoded copies of the same key are not detected. Wrapped keys are encrypted to their
+//! wrapping key, so their fingerprint is the SHA-256 digest of the wrapped key data.
+//!
+//! The fingerprint is stored as `KeyMetaEntry::ImportFingerprint` of the new key. If the system
+//! property `REJECT_PROPERTY` is true, an import whose fingerprint matches a live key under
+//! another alias of the same namespace is refused with `INVALID_ARGUMENT`. Fingerprints are
+//! only recorded with `Feature::ImportFingerprints`.
+
+use crate::error::{Error, ResponseCode};
+use crate::feature_flags::{self, Feature};
+use crate::globals::DB;
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, KeyFormat::KeyFormat,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{generate_random_data, hmac_sha256, sha256, ZVec};
+use std::sync::Mutex;
+
+/// The system property that makes keystore refuse duplicate imports.
+const REJECT_PROPERTY: &str = "persist.keystore.reject_duplicate_imports";
+
+/// The length of the fingerprint key in bytes.
+const FINGERPRINT_KEY_LENGTH: usize = 32;
+
+/// Separates the fingerprints of imported material from other uses of the HMAC.
+const LABEL: &[u8] = b"keystore2 import fingerprint v1\0";
+
+/// The fingerprint key, once it was loaded from the database.
+static FINGERPRINT_KEY: Mutex<Option<ZVec>> = Mutex::new(None);
+
+/// The key material of an import.
+pub enum ImportedMaterial<'a> {
+    /// Plain key material, as passed to `IKeyMintDevice::importKey`.
+    Plain { algorithm: Algorithm, format: KeyFormat, data: &'a [u8] },
+    /// Wrapped key data, as passed to `IKeyMintDevice::importWrappedKey`.
+    Wrapped(&'a [u8]),
+}
+
+fn plain_fingerprint(
+    key: &[u8],
+    algorithm: Algorithm,
+    format: KeyFormat,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let mut message = ZVec::new(LABEL.len() + 8 + data.len())?;
+    message[..LABEL.len()].copy_from_slice(LABEL);
+    message[LABEL.len()..LABEL.len() + 4].copy_from_slice(&algorithm.0.to_be_bytes());
+    message[LABEL.len() + 4..LABEL.len() + 8].copy_from_slice(&format.0.to_be_bytes());
+    message[LABEL.len() + 8..].copy_from_slice(data);
+    Ok(hmac_sha256(key, &message)?)
+}
+
+fn with_fingerprint_key<T>(f: impl FnOnce(&[u8]) -> Result<T>) -> Result<T> {
+    let mut fingerprint_key = FINGERPRINT_KEY.lock().unwrap();
+    if fingerprint_key.is_none() {
+        let new_key = ZVec::try_from(generate_random_data(FINGERPRINT_KEY_LENGTH)?)?;
+        let key = DB
+            .with(|db| db.borrow_mut().get_or_insert_fingerprint_key(&new_key))
+            .context(ks_err!("Failed to load the fingerprint key."))?;
+        *fingerprint_key = Some(ZVec::try_from(key)?);
+    }
+    f(fingerprint_key.as_ref().unwrap())
+}
+
+impl ImportedMaterial<'_> {
+    /// Computes the fingerprint of the material.
+    fn fingerprint(&self) -> Result<Vec<u8>> {
+        match *self {
+            Self::Plain { algorithm, format, data } => {
+                with_fingerprint_key(|key| plain_fingerprint(key, algorithm, format, data))
+            }
+            Self::Wrapped(data) => Ok(sha256(data)?),
+        }
+    }
+}
+
+fn rejects_duplicates() -> bool {
+    rustutils::system_properties::read_bool(REJECT_PROPERTY, false).unwrap_or_else(|e| {
+        log::warn!("Failed to read {REJECT_PROPERTY}: {e:?}");
+        false
+    })
+}
+
+/// Returns the fingerprint of `material` to be stored with the imported key `key`, or None if
+/// fingerprints are not recorded for the key. Refuses the import if duplicate imports are
+/// rejected and another alias of the namespace of `key` has the same fingerprint.
+pub fn check_import(key: &KeyDescriptor, material: ImportedMaterial) -> Result<Option<Vec<u8>>> {
+    if !feature_flags::is_enabled(Feature::ImportFingerprints)
+        || !matches!(key.domain, Domain::APP | Domain::SELINUX)
+    {
+        return Ok(None);
+    }
+    let fingerprint = material.fingerprint().context(ks_err!())?;
+    if rejects_duplicates() {
+        let duplicates: Vec<String> = DB
+            .with(|db| {
+                db.borrow_mut().find_imports_with_fingerprint(key.domain, key.nspace, &fingerprint)
+            })
+            .context(ks_err!("Failed to look up duplicate imports."))?
+            .into_iter()
+            .filter(|alias| Some(alias) != key.alias.as_ref())
+            .collect();
+        if !duplicates.is_empty() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("The key material is already imported as {:?}.", duplicates));
+        }
+    }
+    Ok(Some(fingerprint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_fingerprints_bind_key_algorithm_and_format() -> Result<()> {
+        let fingerprint = plain_fingerprint(b"key", Algorithm::AES, KeyFormat::RAW, b"material")?;
+        assert_eq!(
+            fingerprint,
+            plain_fingerprint(b"key", Algorithm::AES, KeyFormat::RAW, b"material")?
+        );
+        for other in [
+            plain_fingerprint(b"other key", Algorithm::AES, KeyFormat::RAW, b"material")?,
+            plain_fingerprint(b"key", Algorithm::HMAC, KeyFormat::RAW, b"material")?,
+            plain_fingerprint(b"key", Algorithm::AES, KeyFormat::PKCS8, b"material")?,
+            plain_fingerprint(b"key", Algorithm::AES, KeyFormat::RAW, b"other material")?,
+        ] {
+            assert_ne!(fingerprint, other);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wrapped_fingerprint_is_digest() -> Result<()> {
+        assert_eq!(sha256(b"wrapped")?, ImportedMaterial::Wrapped(b"wrapped").fingerprint()?);
+        Ok(())
+    }
+}
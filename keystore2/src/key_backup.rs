@@ -0,0 +1,160 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the backup of imported key material that is not bound to secure
+//! hardware. Apps opt in per key by importing the key with
+//! `IKeystoreSecurityLevelExtension::importBackupEligibleKey`.
+//! Keystore then keeps a copy of the imported material sealed with the owner's
+//! AfterFirstUnlock super key in the key's metadata. On request of the recovery controller,
+//! the material is unsealed and encrypted to the user's backup public key, so that it is
+//! only ever visible in the clear to Keystore and to the device that restores the backup.
+
+use crate::ec_crypto::ECDHPrivateKey;
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use crate::utils::AesGcm;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyCharacteristics::KeyCharacteristics, KeyFormat::KeyFormat, SecurityLevel::SecurityLevel,
+};
+use android_security_maintenance::aidl::android::security::maintenance::BackupKeyMaterial::BackupKeyMaterial;
+use anyhow::{Context, Result};
+use keystore2_crypto::{ZVec, GCM_IV_LENGTH, TAG_LENGTH};
+
+/// Returns true if any of the key's authorizations are enforced by secure hardware. The
+/// material of such keys must not leave the device.
+pub fn is_hardware_bound(key_characteristics: &[KeyCharacteristics]) -> bool {
+    key_characteristics.iter().any(|c| {
+        matches!(c.securityLevel, SecurityLevel::TRUSTED_ENVIRONMENT | SecurityLevel::STRONGBOX)
+            && !c.authorizations.is_empty()
+    })
+}
+
+/// Seals the imported `key_data` of the given `format` with `super_key`. The result is stored
+/// as key metadata and has the layout `iv || tag || ciphertext`, where the plaintext is the
+/// big endian key format followed by the key data.
+pub fn seal(super_key: &dyn AesGcm, format: KeyFormat, key_data: &[u8]) -> Result<Vec<u8>> {
+    let mut plaintext = ZVec::new(4 + key_data.len()).context(ks_err!("Failed to allocate."))?;
+    plaintext[..4].copy_from_slice(&format.0.to_be_bytes());
+    plaintext[4..].copy_from_slice(key_data);
+    let (ciphertext, iv, tag) =
+        super_key.encrypt(&plaintext).context(ks_err!("Failed to seal key material."))?;
+    Ok([iv, tag, ciphertext].concat())
+}
+
+/// Reverses `seal` and returns the key format and the key data.
+pub fn unseal(super_key: &dyn AesGcm, sealed: &[u8]) -> Result<(KeyFormat, ZVec)> {
+    if sealed.len() < GCM_IV_LENGTH + TAG_LENGTH {
+        return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            .context(ks_err!("Sealed key material is too short."));
+    }
+    let (iv, rest) = sealed.split_at(GCM_IV_LENGTH);
+    let (tag, ciphertext) = rest.split_at(TAG_LENGTH);
+    let plaintext = super_key
+        .decrypt(ciphertext, iv, tag)
+        .context(ks_err!("Failed to unseal key material."))?;
+    if plaintext.len() < 4 {
+        return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            .context(ks_err!("Unsealed key material is too short."));
+    }
+    let format = KeyFormat(i32::from_be_bytes(plaintext[..4].try_into().unwrap()));
+    let key_data = ZVec::try_from(&plaintext[4..]).context(ks_err!("Failed to copy key data."))?;
+    Ok((format, key_data))
+}
+
+/// Encrypts the key data to `backup_public_key` with the same ECDH P-521 hybrid scheme that
+/// is used for UnlockedDeviceRequired keys.
+pub fn encrypt_for_backup(
+    format: KeyFormat,
+    key_data: &[u8],
+    backup_public_key: &[u8],
+) -> Result<BackupKeyMaterial> {
+    let (sender_public_key, salt, iv, ciphertext, aead_tag) =
+        ECDHPrivateKey::encrypt_message(backup_public_key, key_data)
+            .context(ks_err!("Failed to encrypt to the backup public key."))?;
+    Ok(BackupKeyMaterial {
+        keyFormat: format.0,
+        senderPublicKey: sender_public_key,
+        salt,
+        iv,
+        ciphertext,
+        aeadTag: aead_tag,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_crypto::{generate_aes256_key, AES_256_KEY_LENGTH};
+
+    struct TestKey(ZVec);
+
+    impl crate::utils::AesGcmKey for TestKey {
+        fn key(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn seal_unseal_roundtrip() -> Result<()> {
+        let super_key = TestKey(generate_aes256_key()?);
+        let sealed = seal(&super_key, KeyFormat::RAW, b"key material")?;
+        let (format, key_data) = unseal(&super_key, &sealed)?;
+        assert_eq!(KeyFormat::RAW, format);
+        assert_eq!(b"key material", &key_data[..]);
+
+        let other_key = TestKey(ZVec::new(AES_256_KEY_LENGTH)?);
+        assert!(unseal(&other_key, &sealed).is_err());
+        assert_eq!(
+            Some(&Error::Rc(ResponseCode::VALUE_CORRUPTED)),
+            unseal(&super_key, &sealed[..GCM_IV_LENGTH])
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn encrypt_for_backup_roundtrip() -> Result<()> {
+        let backup_key = ECDHPrivateKey::generate()?;
+        let material = encrypt_for_backup(KeyFormat::PKCS8, b"key", &backup_key.public_key()?)?;
+        assert_eq!(KeyFormat::PKCS8.0, material.keyFormat);
+        let plaintext = backup_key.decrypt_message(
+            &material.senderPublicKey,
+            &material.salt,
+            &material.iv,
+            &material.ciphertext,
+            &material.aeadTag,
+        )?;
+        assert_eq!(b"key", &plaintext[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn hardware_bound() {
+        let characteristics = |security_level, authorizations| KeyCharacteristics {
+            securityLevel: security_level,
+            authorizations,
+        };
+        assert!(!is_hardware_bound(&[characteristics(
+            SecurityLevel::SOFTWARE,
+            vec![Default::default()]
+        )]));
+        assert!(!is_hardware_bound(&[characteristics(SecurityLevel::TRUSTED_ENVIRONMENT, vec![])]));
+        assert!(is_hardware_bound(&[
+            characteristics(SecurityLevel::KEYSTORE, vec![Default::default()]),
+            characteristics(SecurityLevel::STRONGBOX, vec![Default::default()]),
+        ]));
+    }
+}
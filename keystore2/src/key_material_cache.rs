@@ -0,0 +1,326 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a small LRU cache of super-encrypted key blobs that keystore has
+//! already decrypted in software. Without it, the blob of such a key is decrypted on every
+//! operation, which is costly for blobs that are encrypted with the ECDH super key.
+//!
+//! An entry is identified by the id of the super key and the IV and AEAD tag of the encrypted
+//! blob. The IV is fresh for every encryption, so an entry never matches a blob that was
+//! re-encrypted or replaced; such entries simply age out. The cached material lives in
+//! `ZVec`s, i.e., it is mlocked and zeroed when it is evicted. The cache is bounded both in
+//! entries and in bytes. When a user's super keys are locked or forgotten, the entries of
+//! these super keys are evicted, while those of other users, e.g., the parent of a work
+//! profile, are kept. Callers must still look up the super key before they consult the cache,
+//! so that a hit never grants access to a key whose super key is not in memory.
+//!
+//! Blobs can also be decrypted ahead of an operation by `IKeystoreServiceExtension::prewarmKey`.
+//! Such entries expire if they are not used within their time to live, and are dropped at the
+//! next access of the cache after that. Once used, they are kept like any other entry.
+//!
+//! Under memory pressure, `trim` drops the prewarmed entries that were not used yet, or all
+//! entries at `TrimLevel::Critical`.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::memory_trim::TrimLevel;
+use keystore2_crypto::ZVec;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The maximum number of cached blobs.
+const MAX_ENTRIES: usize = 32;
+
+/// The maximum number of bytes of cached key material. This stays well below the default
+/// mlock limit.
+const MAX_BYTES: usize = 32 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+struct CacheKey {
+    super_key_id: i64,
+    iv: Vec<u8>,
+    aead_tag: Vec<u8>,
+}
+
+struct Entry {
+    key: CacheKey,
+    material: ZVec,
+    /// When the entry expires if it was not used yet. None for entries that do not expire.
+    expires: Option<Instant>,
+}
+
+/// The dump section of the cache.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "key_material_cache",
+    header: "Key material cache (entries, bytes, hits, misses, evictions):",
+    line: "  <entries>, <bytes>, <hits>, <misses>, <evictions>",
+    fields: dump_fields!(
+        entries: "uint64",
+        bytes: "uint64",
+        hits: "uint64",
+        misses: "uint64",
+        evictions: "uint64",
+    ),
+};
+
+/// LRU cache of decrypted key blobs. See the module documentation.
+#[derive(Default)]
+pub struct KeyMaterialCache {
+    /// Entries ordered from least to most recently used.
+    entries: Mutex<Vec<Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl KeyMaterialCache {
+    /// Drops the entries that expired at `now` and counts them as evictions.
+    fn drop_expired(&self, entries: &mut Vec<Entry>, now: Instant) {
+        let len = entries.len();
+        entries.retain(|e| e.expires.map_or(true, |expires| expires > now));
+        self.evictions.fetch_add((len - entries.len()) as u64, Ordering::Relaxed);
+    }
+
+    /// Returns a copy of the material of the blob with the given IV and AEAD tag that was
+    /// encrypted with the super key `super_key_id`, if it is cached.
+    pub fn get(&self, super_key_id: i64, iv: &[u8], aead_tag: &[u8]) -> Option<ZVec> {
+        let mut entries = self.entries.lock().unwrap();
+        self.drop_expired(&mut entries, Instant::now());
+        let found = entries
+            .iter()
+            .position(|e| {
+                e.key.super_key_id == super_key_id && e.key.iv == iv && e.key.aead_tag == aead_tag
+            })
+            .and_then(|pos| {
+                let mut entry = entries.remove(pos);
+                // A failure to mlock the copy is treated as a miss.
+                let material = entry.material.try_clone().ok();
+                entry.expires = None;
+                entries.push(entry);
+                material
+            });
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    /// Caches a copy of `material`, the decrypted blob with the given IV and AEAD tag, evicting
+    /// the least recently used entries as needed. Material larger than the cache is not
+    /// cached.
+    pub fn insert(&self, super_key_id: i64, iv: &[u8], aead_tag: &[u8], material: &ZVec) {
+        self.insert_entry(super_key_id, iv, aead_tag, material, None)
+    }
+
+    /// Returns true if the blob with the given IV and AEAD tag that was encrypted with the super
+    /// key `super_key_id` is cached. Unlike `get`, this does not count as a use of the entry.
+    pub fn contains(&self, super_key_id: i64, iv: &[u8], aead_tag: &[u8]) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        self.drop_expired(&mut entries, Instant::now());
+        entries.iter().any(|e| {
+            e.key.super_key_id == super_key_id && e.key.iv == iv && e.key.aead_tag == aead_tag
+        })
+    }
+
+    /// Caches a copy of `material` like `insert`, but drops it again if it is not used within
+    /// `ttl`.
+    pub fn insert_prewarmed(
+        &self,
+        super_key_id: i64,
+        iv: &[u8],
+        aead_tag: &[u8],
+        material: &ZVec,
+        ttl: Duration,
+    ) {
+        self.insert_entry(super_key_id, iv, aead_tag, material, Some(Instant::now() + ttl))
+    }
+
+    fn insert_entry(
+        &self,
+        super_key_id: i64,
+        iv: &[u8],
+        aead_tag: &[u8],
+        material: &ZVec,
+        expires: Option<Instant>,
+    ) {
+        if material.len() > MAX_BYTES {
+            return;
+        }
+        let material = match material.try_clone() {
+            Ok(material) => material,
+            Err(e) => {
+                log::warn!("Not caching key material: {:?}", e);
+                return;
+            }
+        };
+        let key = CacheKey { super_key_id, iv: iv.to_vec(), aead_tag: aead_tag.to_vec() };
+        let mut entries = self.entries.lock().unwrap();
+        self.drop_expired(&mut entries, Instant::now());
+        entries.retain(|e| e.key != key);
+        let mut bytes: usize = entries.iter().map(|e| e.material.len()).sum();
+        while !entries.is_empty()
+            && (entries.len() >= MAX_ENTRIES || bytes + material.len() > MAX_BYTES)
+        {
+            bytes -= entries.remove(0).material.len();
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        entries.push(Entry { key, material, expires });
+    }
+
+    /// Drops the cached material of the blobs that were encrypted with any of the super keys
+    /// `super_key_ids`.
+    pub fn evict_super_keys(&self, super_key_ids: &[i64]) {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|e| !super_key_ids.contains(&e.key.super_key_id));
+        self.evictions.fetch_add((len - entries.len()) as u64, Ordering::Relaxed);
+    }
+
+    /// Drops the prewarmed entries that were not used yet, or all entries at
+    /// `TrimLevel::Critical`. Returns the number of dropped entries, which also count as
+    /// evictions.
+    pub fn trim(&self, level: TrimLevel) -> u64 {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        match level {
+            TrimLevel::Moderate => entries.retain(|e| e.expires.is_none()),
+            TrimLevel::Critical => entries.clear(),
+        }
+        let dropped = (len - entries.len()) as u64;
+        self.evictions.fetch_add(dropped, Ordering::Relaxed);
+        dropped
+    }
+
+    /// Returns the number of hits, misses, and evictions.
+    pub fn stats(&self) -> (u64, u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.evictions.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Writes the size and the statistics of the cache to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let (entries, bytes) = {
+            let mut entries = self.entries.lock().unwrap();
+            self.drop_expired(&mut entries, Instant::now());
+            (entries.len(), entries.iter().map(|e| e.material.len()).sum::<usize>())
+        };
+        let (hits, misses, evictions) = self.stats();
+        DUMP_SECTION.write_header(writer)?;
+        DUMP_SECTION.write_line(writer, &[&entries, &bytes, &hits, &misses, &evictions])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(byte: u8, len: usize) -> ZVec {
+        ZVec::try_from(vec![byte; len]).unwrap()
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let cache = KeyMaterialCache::default();
+        assert!(cache.get(1, b"iv", b"tag").is_none());
+        cache.insert(1, b"iv", b"tag", &material(7, 16));
+        assert_eq!(&[7; 16], &cache.get(1, b"iv", b"tag").unwrap()[..]);
+        assert!(cache.get(2, b"iv", b"tag").is_none());
+        assert!(cache.get(1, b"other iv", b"tag").is_none());
+        assert!(cache.get(1, b"iv", b"other tag").is_none());
+        assert_eq!((1, 4, 0), cache.stats());
+
+        cache.evict_super_keys(&[1]);
+        assert!(cache.get(1, b"iv", b"tag").is_none());
+        assert_eq!((1, 5, 1), cache.stats());
+    }
+
+    #[test]
+    fn evicts_only_given_super_keys() {
+        let cache = KeyMaterialCache::default();
+        cache.insert(1, b"iv", b"tag", &material(1, 16));
+        cache.insert(2, b"iv", b"tag", &material(2, 16));
+        cache.insert(3, b"iv", b"tag", &material(3, 16));
+        cache.evict_super_keys(&[1, 3]);
+        assert!(!cache.contains(1, b"iv", b"tag"));
+        assert!(cache.contains(2, b"iv", b"tag"));
+        assert!(!cache.contains(3, b"iv", b"tag"));
+        assert_eq!(2, cache.stats().2);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = KeyMaterialCache::default();
+        for i in 0..MAX_ENTRIES as u8 {
+            cache.insert(1, &[i], b"tag", &material(i, 16));
+        }
+        // Entry 0 becomes the most recently used, so entry 1 is evicted first.
+        assert!(cache.get(1, &[0], b"tag").is_some());
+        cache.insert(1, b"new", b"tag", &material(0xff, 16));
+        assert!(cache.get(1, &[1], b"tag").is_none());
+        assert!(cache.get(1, &[0], b"tag").is_some());
+        assert!(cache.get(1, b"new", b"tag").is_some());
+        assert_eq!(1, cache.stats().2);
+    }
+
+    #[test]
+    fn prewarmed_entries_expire_unless_used() {
+        let cache = KeyMaterialCache::default();
+        cache.insert_prewarmed(1, b"a", b"tag", &material(1, 16), Duration::from_secs(60));
+        cache.insert_prewarmed(1, b"b", b"tag", &material(2, 16), Duration::ZERO);
+        assert!(cache.contains(1, b"a", b"tag"));
+        assert!(!cache.contains(1, b"b", b"tag"));
+        assert_eq!((0, 0, 1), cache.stats());
+
+        // A used entry no longer expires.
+        assert!(cache.get(1, b"a", b"tag").is_some());
+        assert_eq!(None, cache.entries.lock().unwrap()[0].expires);
+        assert_eq!((1, 0, 1), cache.stats());
+    }
+
+    #[test]
+    fn trim_drops_unused_prewarmed_or_all_entries() {
+        let cache = KeyMaterialCache::default();
+        cache.insert(1, b"a", b"tag", &material(1, 16));
+        cache.insert_prewarmed(1, b"b", b"tag", &material(2, 16), Duration::from_secs(60));
+        assert_eq!(1, cache.trim(TrimLevel::Moderate));
+        assert!(cache.contains(1, b"a", b"tag"));
+        assert!(!cache.contains(1, b"b", b"tag"));
+        assert_eq!(1, cache.trim(TrimLevel::Critical));
+        assert!(!cache.contains(1, b"a", b"tag"));
+        assert_eq!((0, 0, 2), cache.stats());
+    }
+
+    #[test]
+    fn respects_byte_limit() {
+        let cache = KeyMaterialCache::default();
+        cache.insert(1, b"a", b"tag", &material(1, MAX_BYTES / 2));
+        cache.insert(1, b"b", b"tag", &material(2, MAX_BYTES / 2));
+        cache.insert(1, b"c", b"tag", &material(3, 1));
+        assert!(cache.get(1, b"a", b"tag").is_none());
+        assert!(cache.get(1, b"b", b"tag").is_some());
+        assert!(cache.get(1, b"c", b"tag").is_some());
+
+        // Material that does not fit at all is not cached and evicts nothing.
+        cache.insert(1, b"d", b"tag", &material(4, MAX_BYTES + 1));
+        assert!(cache.get(1, b"d", b"tag").is_none());
+        assert!(cache.get(1, b"b", b"tag").is_some());
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module counts the outcomes of the operations of each key, so that a key that keeps
+//! failing in the field can be told apart from a generally flaky device. Failed begins are
+//! counted by error code. Operations that began are counted as finished, failed, or aborted,
+//! where pruned and dropped operations count as aborted.
+//!
+//! The statistics are kept in memory for the `MAX_KEYS` most recently used keys, so they start
+//! over when keystore restarts. Keys that are used with `Domain::BLOB` have no key id and are
+//! not counted.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::error::anyhow_error_to_serialized_error;
+use crate::operation::Outcome;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// The number of keys whose statistics are kept.
+const MAX_KEYS: usize = 256;
+
+/// The operation statistics of one key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyStats {
+    /// The id of the key entry.
+    pub key_id: i64,
+    /// The number of operations that began.
+    pub begins: u64,
+    /// The number of failed begins by error code.
+    pub begin_failures: BTreeMap<i32, u64>,
+    /// The number of operations that finished successfully.
+    pub finishes: u64,
+    /// The number of operations that failed after they began.
+    pub failures: u64,
+    /// The number of operations that were aborted, pruned, or dropped.
+    pub aborts: u64,
+    /// The error code of the last failed begin or operation.
+    pub last_error: Option<i32>,
+}
+
+/// The dump section of the statistics.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "key_operation_stats",
+    header: "Key operation stats (key id, begins, begin failures, finishes, failures, aborts, \
+             last error):",
+    line: "  <key_id>, <begins>, [<begin_failures>], <finishes>, <failures>, <aborts>, \
+           <last_error>",
+    fields: dump_fields!(
+        key_id: "int64",
+        begins: "uint64",
+        begin_failures: "string",
+        finishes: "uint64",
+        failures: "uint64",
+        aborts: "uint64",
+        last_error: "string",
+    ),
+};
+
+/// Bounded table of per key operation statistics.
+#[derive(Default)]
+pub struct KeyOperationStats {
+    /// The statistics ordered from least to most recently updated.
+    keys: Mutex<Vec<KeyStats>>,
+}
+
+impl KeyOperationStats {
+    fn update<F: FnOnce(&mut KeyStats)>(&self, key_id: i64, f: F) {
+        let mut keys = self.keys.lock().unwrap();
+        let mut stats = match keys.iter().position(|s| s.key_id == key_id) {
+            Some(pos) => keys.remove(pos),
+            None => KeyStats { key_id, ..Default::default() },
+        };
+        f(&mut stats);
+        if keys.len() >= MAX_KEYS {
+            keys.remove(0);
+        }
+        keys.push(stats);
+    }
+
+    /// Records that an operation with the key `key_id` began.
+    pub fn on_begin(&self, key_id: i64) {
+        self.update(key_id, |s| s.begins += 1);
+    }
+
+    /// Records that an operation with the key `key_id` failed to begin with `error`.
+    pub fn on_begin_failed(&self, key_id: i64, error: &anyhow::Error) {
+        let code = anyhow_error_to_serialized_error(error).0;
+        self.update(key_id, |s| {
+            *s.begin_failures.entry(code).or_default() += 1;
+            s.last_error = Some(code);
+        });
+    }
+
+    /// Records the final `outcome` of an operation with the key `key_id`. An operation whose
+    /// outcome is still unknown is about to be dropped.
+    pub fn on_ended(&self, key_id: i64, outcome: &Outcome) {
+        self.update(key_id, |s| match outcome {
+            Outcome::Success => s.finishes += 1,
+            Outcome::ErrorCode(e) => {
+                s.failures += 1;
+                s.last_error = Some(e.0);
+            }
+            Outcome::Unknown | Outcome::Abort | Outcome::Dropped | Outcome::Pruned => s.aborts += 1,
+        });
+    }
+
+    /// Returns the statistics of all tracked keys, ordered by key id.
+    pub fn get_all(&self) -> Vec<KeyStats> {
+        let mut all = self.keys.lock().unwrap().clone();
+        all.sort_by_key(|s| s.key_id);
+        all
+    }
+
+    /// Writes the statistics of all tracked keys to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        DUMP_SECTION.write_header(writer)?;
+        for s in self.get_all() {
+            let begin_failures: Vec<String> = s
+                .begin_failures
+                .iter()
+                .map(|(code, count)| format!("{}:{}", code, count))
+                .collect();
+            DUMP_SECTION.write_line(
+                writer,
+                &[
+                    &s.key_id,
+                    &s.begins,
+                    &begin_failures.join(" "),
+                    &s.finishes,
+                    &s.failures,
+                    &s.aborts,
+                    &s.last_error.map_or_else(|| "none".to_string(), |e| e.to_string()),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Error, ErrorCode, SerializedError};
+
+    #[test]
+    fn counts_outcomes_per_key() {
+        let stats = KeyOperationStats::default();
+        stats.on_begin(1);
+        stats.on_begin(1);
+        stats.on_begin(1);
+        stats.on_ended(1, &Outcome::Success);
+        stats.on_ended(1, &Outcome::ErrorCode(SerializedError(ErrorCode::VERIFICATION_FAILED.0)));
+        stats.on_ended(1, &Outcome::Pruned);
+        stats.on_begin_failed(1, &anyhow::Error::new(Error::Km(ErrorCode::KEY_EXPIRED)));
+        stats.on_begin_failed(1, &anyhow::Error::new(Error::Km(ErrorCode::KEY_EXPIRED)));
+        stats.on_begin(2);
+
+        let all = stats.get_all();
+        assert_eq!(2, all.len());
+        assert_eq!(
+            KeyStats {
+                key_id: 1,
+                begins: 3,
+                begin_failures: BTreeMap::from([(ErrorCode::KEY_EXPIRED.0, 2)]),
+                finishes: 1,
+                failures: 1,
+                aborts: 1,
+                last_error: Some(ErrorCode::KEY_EXPIRED.0),
+            },
+            all[0]
+        );
+        assert_eq!(KeyStats { key_id: 2, begins: 1, ..Default::default() }, all[1]);
+
+        let mut out = Vec::new();
+        stats.dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains(&format!("\n  1, 3, [{0}:2], 1, 1, 1, {0}\n", ErrorCode::KEY_EXPIRED.0))
+        );
+        assert!(out.ends_with("\n  2, 1, [], 0, 0, 0, none\n"));
+    }
+
+    #[test]
+    fn forgets_least_recently_used_keys() {
+        let stats = KeyOperationStats::default();
+        for key_id in 0..MAX_KEYS as i64 {
+            stats.on_begin(key_id);
+        }
+        // Key 0 becomes the most recently used, so key 1 is forgotten first.
+        stats.on_begin(0);
+        stats.on_begin(MAX_KEYS as i64);
+        let all = stats.get_all();
+        assert_eq!(MAX_KEYS, all.len());
+        assert_eq!(2, all[0].begins);
+        assert_eq!(2, all[1].key_id);
+    }
+}
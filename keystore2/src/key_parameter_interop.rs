@@ -0,0 +1,359 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Golden tests of the key parameters at the boundary between the framework and keystore.
+//!
+//! The KeyStore SPI of the framework translates a `KeyGenParameterSpec` into key parameters, and
+//! translates the authorizations of `KeyMetadata` back into a `KeyInfo`. Each fixture holds the
+//! parameters that the framework generates for a representative spec, and the authorizations
+//! that the framework expects back, with their security levels. The tests feed the parameters
+//! through a model of KeyMint and then through the conversions of keystore, both for the
+//! metadata that `generateKey` returns and for the metadata that `getKeyEntry` loads from the
+//! database, and compare the result with the fixture. A change to the translation of a tag on
+//! either side of the boundary shows up as a mismatch here.
+
+use crate::database::tests::new_test_db;
+use crate::database::{
+    BlobInfo, BlobMetaData, CertificateInfo, KeyEntryLoadBits, KeyMetaData, KeyType, KEYSTORE_UUID,
+};
+use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::utils::{key_characteristics_to_internal, key_parameters_to_authorizations};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
+    HardwareAuthenticatorType::HardwareAuthenticatorType, KeyCharacteristics::KeyCharacteristics,
+    KeyOrigin::KeyOrigin, KeyParameter::KeyParameter as KmKeyParameter,
+    KeyParameterValue::KeyParameterValue as KmKeyParameterValue, KeyPurpose::KeyPurpose,
+    PaddingMode::PaddingMode, SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Authorization::Authorization, Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::Result;
+
+const USER_ID: i32 = 10;
+const UID: u32 = 1010123;
+const SECURE_USER_ID: i64 = 0x1234_5678_9abc_def0;
+const CREATION_DATETIME: i64 = 1_700_000_000_000;
+const OS_VERSION: i32 = 140000;
+const OS_PATCHLEVEL: i32 = 202403;
+const VENDOR_PATCHLEVEL: i32 = 20240305;
+const BOOT_PATCHLEVEL: i32 = 20240305;
+
+/// Tags that KeyMint reports as enforced by keystore, as the KeyMint reference implementation
+/// does.
+const KEYSTORE_ENFORCED: &[Tag] = &[
+    Tag::ACTIVE_DATETIME,
+    Tag::ORIGINATION_EXPIRE_DATETIME,
+    Tag::USAGE_EXPIRE_DATETIME,
+    Tag::USAGE_COUNT_LIMIT,
+    Tag::UNLOCKED_DEVICE_REQUIRED,
+    Tag::CREATION_DATETIME,
+];
+
+/// Tags that KeyMint does not report in the key characteristics.
+const NOT_REPORTED: &[Tag] = &[
+    Tag::APPLICATION_ID,
+    Tag::APPLICATION_DATA,
+    Tag::ATTESTATION_CHALLENGE,
+    Tag::ATTESTATION_APPLICATION_ID,
+    Tag::CERTIFICATE_SERIAL,
+    Tag::CERTIFICATE_SUBJECT,
+    Tag::CERTIFICATE_NOT_BEFORE,
+    Tag::CERTIFICATE_NOT_AFTER,
+];
+
+struct Fixture {
+    name: &'static str,
+    /// The parameters that the framework passes to `generateKey`.
+    framework_params: Vec<KmKeyParameter>,
+    /// The authorizations that the framework expects for the new key.
+    expected: Vec<(SecurityLevel, KmKeyParameter)>,
+}
+
+fn kp(tag: Tag, value: KmKeyParameterValue) -> KmKeyParameter {
+    KmKeyParameter { tag, value }
+}
+
+fn hw(tag: Tag, value: KmKeyParameterValue) -> (SecurityLevel, KmKeyParameter) {
+    (SecurityLevel::TRUSTED_ENVIRONMENT, kp(tag, value))
+}
+
+fn ks(tag: Tag, value: KmKeyParameterValue) -> (SecurityLevel, KmKeyParameter) {
+    (SecurityLevel::KEYSTORE, kp(tag, value))
+}
+
+/// The authorizations that every key gets from KeyMint and keystore.
+fn common_authorizations() -> Vec<(SecurityLevel, KmKeyParameter)> {
+    vec![
+        hw(Tag::ORIGIN, KmKeyParameterValue::Origin(KeyOrigin::GENERATED)),
+        hw(Tag::OS_VERSION, KmKeyParameterValue::Integer(OS_VERSION)),
+        hw(Tag::OS_PATCHLEVEL, KmKeyParameterValue::Integer(OS_PATCHLEVEL)),
+        hw(Tag::VENDOR_PATCHLEVEL, KmKeyParameterValue::Integer(VENDOR_PATCHLEVEL)),
+        hw(Tag::BOOT_PATCHLEVEL, KmKeyParameterValue::Integer(BOOT_PATCHLEVEL)),
+        ks(Tag::CREATION_DATETIME, KmKeyParameterValue::DateTime(CREATION_DATETIME)),
+        (SecurityLevel::SOFTWARE, kp(Tag::USER_ID, KmKeyParameterValue::Integer(USER_ID))),
+    ]
+}
+
+fn fixtures() -> Vec<Fixture> {
+    use KmKeyParameterValue as V;
+    vec![
+        Fixture {
+            // new KeyGenParameterSpec.Builder(alias, PURPOSE_ENCRYPT | PURPOSE_DECRYPT)
+            //     .setBlockModes(BLOCK_MODE_GCM)
+            //     .setEncryptionPaddings(ENCRYPTION_PADDING_NONE)
+            //     .setKeySize(256)
+            //     .setUserAuthenticationRequired(true)
+            //     .setUserAuthenticationParameters(
+            //         30, AUTH_BIOMETRIC_STRONG | AUTH_DEVICE_CREDENTIAL)
+            name: "aes_gcm_auth_bound",
+            framework_params: vec![
+                kp(Tag::ALGORITHM, V::Algorithm(Algorithm::AES)),
+                kp(Tag::KEY_SIZE, V::Integer(256)),
+                kp(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::ENCRYPT)),
+                kp(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::DECRYPT)),
+                kp(Tag::BLOCK_MODE, V::BlockMode(BlockMode::GCM)),
+                kp(Tag::PADDING, V::PaddingMode(PaddingMode::NONE)),
+                kp(Tag::MIN_MAC_LENGTH, V::Integer(128)),
+                kp(Tag::USER_SECURE_ID, V::LongInteger(SECURE_USER_ID)),
+                kp(
+                    Tag::USER_AUTH_TYPE,
+                    V::HardwareAuthenticatorType(HardwareAuthenticatorType(
+                        HardwareAuthenticatorType::PASSWORD.0
+                            | HardwareAuthenticatorType::FINGERPRINT.0,
+                    )),
+                ),
+                kp(Tag::AUTH_TIMEOUT, V::Integer(30)),
+            ],
+            expected: vec![
+                hw(Tag::ALGORITHM, V::Algorithm(Algorithm::AES)),
+                hw(Tag::KEY_SIZE, V::Integer(256)),
+                hw(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::ENCRYPT)),
+                hw(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::DECRYPT)),
+                hw(Tag::BLOCK_MODE, V::BlockMode(BlockMode::GCM)),
+                hw(Tag::PADDING, V::PaddingMode(PaddingMode::NONE)),
+                hw(Tag::MIN_MAC_LENGTH, V::Integer(128)),
+                hw(Tag::USER_SECURE_ID, V::LongInteger(SECURE_USER_ID)),
+                hw(Tag::USER_AUTH_TYPE, V::HardwareAuthenticatorType(HardwareAuthenticatorType(3))),
+                hw(Tag::AUTH_TIMEOUT, V::Integer(30)),
+            ],
+        },
+        Fixture {
+            // new KeyGenParameterSpec.Builder(alias, PURPOSE_SIGN | PURPOSE_VERIFY)
+            //     .setAlgorithmParameterSpec(new ECGenParameterSpec("secp256r1"))
+            //     .setDigests(DIGEST_SHA256)
+            //     .setKeyValidityStart(start).setKeyValidityEnd(end)
+            //     .setAttestationChallenge(challenge)
+            name: "ec_p256_attested_with_validity",
+            framework_params: vec![
+                kp(Tag::ALGORITHM, V::Algorithm(Algorithm::EC)),
+                kp(Tag::EC_CURVE, V::EcCurve(EcCurve::P_256)),
+                kp(Tag::KEY_SIZE, V::Integer(256)),
+                kp(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::SIGN)),
+                kp(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::VERIFY)),
+                kp(Tag::DIGEST, V::Digest(Digest::SHA_2_256)),
+                kp(Tag::NO_AUTH_REQUIRED, V::BoolValue(true)),
+                kp(Tag::ACTIVE_DATETIME, V::DateTime(1_700_000_000_000)),
+                kp(Tag::ORIGINATION_EXPIRE_DATETIME, V::DateTime(1_800_000_000_000)),
+                kp(Tag::USAGE_EXPIRE_DATETIME, V::DateTime(1_800_000_000_000)),
+                kp(Tag::CERTIFICATE_SERIAL, V::Blob(vec![1])),
+                kp(Tag::CERTIFICATE_SUBJECT, V::Blob(b"CN=key".to_vec())),
+                kp(Tag::CERTIFICATE_NOT_BEFORE, V::DateTime(1_700_000_000_000)),
+                kp(Tag::CERTIFICATE_NOT_AFTER, V::DateTime(1_800_000_000_000)),
+                kp(Tag::ATTESTATION_CHALLENGE, V::Blob(b"challenge".to_vec())),
+            ],
+            expected: vec![
+                hw(Tag::ALGORITHM, V::Algorithm(Algorithm::EC)),
+                hw(Tag::EC_CURVE, V::EcCurve(EcCurve::P_256)),
+                hw(Tag::KEY_SIZE, V::Integer(256)),
+                hw(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::SIGN)),
+                hw(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::VERIFY)),
+                hw(Tag::DIGEST, V::Digest(Digest::SHA_2_256)),
+                hw(Tag::NO_AUTH_REQUIRED, V::BoolValue(true)),
+                ks(Tag::ACTIVE_DATETIME, V::DateTime(1_700_000_000_000)),
+                ks(Tag::ORIGINATION_EXPIRE_DATETIME, V::DateTime(1_800_000_000_000)),
+                ks(Tag::USAGE_EXPIRE_DATETIME, V::DateTime(1_800_000_000_000)),
+            ],
+        },
+        Fixture {
+            // new KeyGenParameterSpec.Builder(alias, PURPOSE_DECRYPT | PURPOSE_SIGN)
+            //     .setKeySize(2048)
+            //     .setDigests(DIGEST_SHA256, DIGEST_SHA512)
+            //     .setEncryptionPaddings(ENCRYPTION_PADDING_RSA_OAEP)
+            //     .setSignaturePaddings(SIGNATURE_PADDING_RSA_PSS)
+            //     .setUnlockedDeviceRequired(true)
+            //     .setMaxUsageCount(1)
+            name: "rsa_2048_oaep_pss_single_use",
+            framework_params: vec![
+                kp(Tag::ALGORITHM, V::Algorithm(Algorithm::RSA)),
+                kp(Tag::KEY_SIZE, V::Integer(2048)),
+                kp(Tag::RSA_PUBLIC_EXPONENT, V::LongInteger(65537)),
+                kp(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::DECRYPT)),
+                kp(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::SIGN)),
+                kp(Tag::DIGEST, V::Digest(Digest::SHA_2_256)),
+                kp(Tag::DIGEST, V::Digest(Digest::SHA_2_512)),
+                kp(Tag::PADDING, V::PaddingMode(PaddingMode::RSA_OAEP)),
+                kp(Tag::PADDING, V::PaddingMode(PaddingMode::RSA_PSS)),
+                kp(Tag::RSA_OAEP_MGF_DIGEST, V::Digest(Digest::SHA1)),
+                kp(Tag::NO_AUTH_REQUIRED, V::BoolValue(true)),
+                kp(Tag::UNLOCKED_DEVICE_REQUIRED, V::BoolValue(true)),
+                kp(Tag::USAGE_COUNT_LIMIT, V::Integer(1)),
+            ],
+            expected: vec![
+                hw(Tag::ALGORITHM, V::Algorithm(Algorithm::RSA)),
+                hw(Tag::KEY_SIZE, V::Integer(2048)),
+                hw(Tag::RSA_PUBLIC_EXPONENT, V::LongInteger(65537)),
+                hw(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::DECRYPT)),
+                hw(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::SIGN)),
+                hw(Tag::DIGEST, V::Digest(Digest::SHA_2_256)),
+                hw(Tag::DIGEST, V::Digest(Digest::SHA_2_512)),
+                hw(Tag::PADDING, V::PaddingMode(PaddingMode::RSA_OAEP)),
+                hw(Tag::PADDING, V::PaddingMode(PaddingMode::RSA_PSS)),
+                hw(Tag::RSA_OAEP_MGF_DIGEST, V::Digest(Digest::SHA1)),
+                hw(Tag::NO_AUTH_REQUIRED, V::BoolValue(true)),
+                ks(Tag::UNLOCKED_DEVICE_REQUIRED, V::BoolValue(true)),
+                ks(Tag::USAGE_COUNT_LIMIT, V::Integer(1)),
+            ],
+        },
+        Fixture {
+            // KeyGenerator.getInstance("HmacSHA256", "AndroidKeyStore") with
+            // new KeyGenParameterSpec.Builder(alias, PURPOSE_SIGN | PURPOSE_VERIFY)
+            name: "hmac_sha256",
+            framework_params: vec![
+                kp(Tag::ALGORITHM, V::Algorithm(Algorithm::HMAC)),
+                kp(Tag::KEY_SIZE, V::Integer(256)),
+                kp(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::SIGN)),
+                kp(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::VERIFY)),
+                kp(Tag::DIGEST, V::Digest(Digest::SHA_2_256)),
+                kp(Tag::MIN_MAC_LENGTH, V::Integer(256)),
+                kp(Tag::NO_AUTH_REQUIRED, V::BoolValue(true)),
+            ],
+            expected: vec![
+                hw(Tag::ALGORITHM, V::Algorithm(Algorithm::HMAC)),
+                hw(Tag::KEY_SIZE, V::Integer(256)),
+                hw(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::SIGN)),
+                hw(Tag::PURPOSE, V::KeyPurpose(KeyPurpose::VERIFY)),
+                hw(Tag::DIGEST, V::Digest(Digest::SHA_2_256)),
+                hw(Tag::MIN_MAC_LENGTH, V::Integer(256)),
+                hw(Tag::NO_AUTH_REQUIRED, V::BoolValue(true)),
+            ],
+        },
+    ]
+}
+
+/// Returns the key characteristics that a TEE KeyMint reports for a key generated with
+/// `params`, after keystore added `CREATION_DATETIME`.
+fn keymint_characteristics(params: &[KmKeyParameter]) -> Vec<KeyCharacteristics> {
+    let mut tee = KeyCharacteristics {
+        securityLevel: SecurityLevel::TRUSTED_ENVIRONMENT,
+        authorizations: vec![],
+    };
+    let mut keystore =
+        KeyCharacteristics { securityLevel: SecurityLevel::KEYSTORE, authorizations: vec![] };
+    let creation = kp(Tag::CREATION_DATETIME, KmKeyParameterValue::DateTime(CREATION_DATETIME));
+    for p in params.iter().chain(std::iter::once(&creation)) {
+        if NOT_REPORTED.contains(&p.tag) {
+            continue;
+        }
+        if KEYSTORE_ENFORCED.contains(&p.tag) {
+            keystore.authorizations.push(p.clone());
+        } else {
+            tee.authorizations.push(p.clone());
+        }
+    }
+    tee.authorizations.extend([
+        kp(Tag::ORIGIN, KmKeyParameterValue::Origin(KeyOrigin::GENERATED)),
+        kp(Tag::OS_VERSION, KmKeyParameterValue::Integer(OS_VERSION)),
+        kp(Tag::OS_PATCHLEVEL, KmKeyParameterValue::Integer(OS_PATCHLEVEL)),
+        kp(Tag::VENDOR_PATCHLEVEL, KmKeyParameterValue::Integer(VENDOR_PATCHLEVEL)),
+        kp(Tag::BOOT_PATCHLEVEL, KmKeyParameterValue::Integer(BOOT_PATCHLEVEL)),
+    ]);
+    vec![tee, keystore]
+}
+
+/// Converts authorizations into pairs that can be compared and sorted.
+fn normalize(authorizations: Vec<Authorization>) -> Vec<(SecurityLevel, KmKeyParameter)> {
+    let mut pairs: Vec<_> =
+        authorizations.into_iter().map(|a| (a.securityLevel, a.keyParameter)).collect();
+    pairs.sort_by_key(|(level, p)| (level.0, p.tag.0, format!("{:?}", p.value)));
+    pairs
+}
+
+fn expected_authorizations(fixture: &Fixture) -> Vec<(SecurityLevel, KmKeyParameter)> {
+    let mut expected = fixture.expected.clone();
+    expected.extend(common_authorizations());
+    expected.sort_by_key(|(level, p)| (level.0, p.tag.0, format!("{:?}", p.value)));
+    expected
+}
+
+/// Runs the parameters of `fixture` through keystore the way `generateKey` does, and returns
+/// the authorizations of the returned metadata and of the metadata loaded by `getKeyEntry`.
+fn keystore_authorizations(fixture: &Fixture) -> Result<(Vec<Authorization>, Vec<Authorization>)> {
+    let mut key_parameters =
+        key_characteristics_to_internal(keymint_characteristics(&fixture.framework_params));
+    key_parameters
+        .push(KeyParameter::new(KeyParameterValue::UserID(USER_ID), SecurityLevel::SOFTWARE));
+
+    let mut db = new_test_db()?;
+    let key = KeyDescriptor {
+        domain: Domain::APP,
+        nspace: UID as i64,
+        alias: Some(fixture.name.to_string()),
+        blob: None,
+    };
+    db.store_new_key(
+        &key,
+        KeyType::Client,
+        &key_parameters,
+        &BlobInfo::new(b"blob", &BlobMetaData::new()),
+        &CertificateInfo::new(None, None),
+        &KeyMetaData::new(),
+        &KEYSTORE_UUID,
+    )?;
+    let (_, key_entry) =
+        db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::NONE, UID, |_, _| Ok(()))?;
+
+    Ok((
+        key_parameters_to_authorizations(key_parameters),
+        key_parameters_to_authorizations(key_entry.into_key_parameters()),
+    ))
+}
+
+#[test]
+fn framework_params_round_trip() -> Result<()> {
+    for fixture in fixtures() {
+        let expected = expected_authorizations(&fixture);
+        let (generated, loaded) = keystore_authorizations(&fixture)?;
+        assert_eq!(expected, normalize(generated), "generateKey of {}", fixture.name);
+        assert_eq!(expected, normalize(loaded), "getKeyEntry of {}", fixture.name);
+    }
+    Ok(())
+}
+
+#[test]
+fn fixtures_cover_reported_framework_params() {
+    // Every parameter that KeyMint reports must appear in the expected authorizations, so that
+    // a fixture cannot silently drop a parameter of the framework.
+    for fixture in fixtures() {
+        for p in &fixture.framework_params {
+            assert_eq!(
+                NOT_REPORTED.contains(&p.tag),
+                !fixture.expected.iter().any(|(_, e)| e == p),
+                "{:?} in {}",
+                p,
+                fixture.name
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module enforces the longest key validity that device policy imposes on the keys of an
+//! Android user, e.g., on all keys of a work profile. The limit is set through
+//! `IKeystoreMaintenance::setMaxKeyValidity` and is stored in the database, so that it
+//! survives reboots.
+//!
+//! When a key is generated or imported by a caller of a limited user, keystore adds
+//! `ORIGINATION_EXPIRE_DATETIME` and `USAGE_EXPIRE_DATETIME` at the end of the validity, or
+//! moves them forward if the caller asked for a later expiry. Earlier expiries requested by
+//! the caller are kept. Both tags are needed, because the origination expiry only applies to
+//! signing and encryption, and the usage expiry only to verification and decryption.
+
+use crate::globals::DB;
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, Tag::Tag,
+};
+use anyhow::{Context, Result};
+
+/// The tags that bound the validity of a key.
+const EXPIRY_TAGS: [Tag; 2] = [Tag::ORIGINATION_EXPIRE_DATETIME, Tag::USAGE_EXPIRE_DATETIME];
+
+/// Makes the key described by `params` expire no later than `max_validity` milliseconds after
+/// `now`, which is in milliseconds since the epoch.
+pub fn limit_validity(params: &mut Vec<KeyParameter>, now: i64, max_validity: i64) {
+    let expiry = now.saturating_add(max_validity);
+    for tag in EXPIRY_TAGS {
+        let mut present = false;
+        for param in params.iter_mut().filter(|p| p.tag == tag) {
+            present = true;
+            if let KeyParameterValue::DateTime(requested) = &mut param.value {
+                *requested = (*requested).min(expiry);
+            }
+        }
+        if !present {
+            params.push(KeyParameter { tag, value: KeyParameterValue::DateTime(expiry) });
+        }
+    }
+}
+
+/// Applies the validity limit of `user_id`, if any, to the parameters of a new key.
+pub fn apply_policy(user_id: u32, now: i64, params: &mut Vec<KeyParameter>) -> Result<()> {
+    let max_validity = DB
+        .with(|db| db.borrow_mut().get_max_key_validity(user_id))
+        .context(ks_err!("Failed to read the key validity policy."))?;
+    if let Some(max_validity) = max_validity {
+        limit_validity(params, now, max_validity);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expiry(params: &[KeyParameter], tag: Tag) -> Vec<i64> {
+        params
+            .iter()
+            .filter(|p| p.tag == tag)
+            .map(|p| match p.value {
+                KeyParameterValue::DateTime(t) => t,
+                _ => panic!("Unexpected value {:?}", p.value),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adds_missing_expiry() {
+        let mut params = vec![];
+        limit_validity(&mut params, 1000, 500);
+        assert_eq!(vec![1500], expiry(&params, Tag::ORIGINATION_EXPIRE_DATETIME));
+        assert_eq!(vec![1500], expiry(&params, Tag::USAGE_EXPIRE_DATETIME));
+    }
+
+    #[test]
+    fn tightens_later_expiry_only() {
+        let mut params = vec![
+            KeyParameter {
+                tag: Tag::ORIGINATION_EXPIRE_DATETIME,
+                value: KeyParameterValue::DateTime(1200),
+            },
+            KeyParameter {
+                tag: Tag::USAGE_EXPIRE_DATETIME,
+                value: KeyParameterValue::DateTime(i64::MAX),
+            },
+        ];
+        limit_validity(&mut params, 1000, 500);
+        assert_eq!(vec![1200], expiry(&params, Tag::ORIGINATION_EXPIRE_DATETIME));
+        assert_eq!(vec![1500], expiry(&params, Tag::USAGE_EXPIRE_DATETIME));
+        assert_eq!(2, params.len());
+
+        // A limit that reaches beyond the end of time does not overflow.
+        limit_validity(&mut params, 1000, i64::MAX);
+        assert_eq!(vec![1500], expiry(&params, Tag::USAGE_EXPIRE_DATETIME));
+    }
+}
@@ -14,12 +14,17 @@
 
 //! This crate implements the Keystore 2.0 service entry point.
 
+use keystore2::credential_store::CredentialStore;
+use keystore2::database_binding;
 use keystore2::entropy;
+use keystore2::feature_flags::{self, Feature};
 use keystore2::globals::ENFORCEMENTS;
 use keystore2::maintenance::Maintenance;
+use keystore2::memory_trim;
 use keystore2::metrics::Metrics;
 use keystore2::metrics_store;
 use keystore2::service::KeystoreService;
+use keystore2::soft_key_migration;
 use keystore2::{apc::ApcManager, shared_secret_negotiation};
 use keystore2::{authorization::AuthorizationManager, id_rotation::IdRotationState};
 use legacykeystore::LegacyKeystore;
@@ -33,6 +38,8 @@ static AUTHORIZATION_SERVICE_NAME: &str = "android.security.authorization";
 static METRICS_SERVICE_NAME: &str = "android.security.metrics";
 static USER_MANAGER_SERVICE_NAME: &str = "android.security.maintenance";
 static LEGACY_KEYSTORE_SERVICE_NAME: &str = "android.security.legacykeystore";
+static CREDENTIAL_STORE_SERVICE_NAME: &str = "android.security.credentialstore";
+static KS2_EXTENSION_SERVICE_NAME: &str = "android.security.keystoreextension";
 
 /// Keystore 2.0 takes one argument which is a path indicating its designated working directory.
 fn main() {
@@ -93,15 +100,23 @@ fn main() {
 
     ENFORCEMENTS.install_confirmation_token_receiver(confirmation_token_receiver);
 
+    feature_flags::init();
     entropy::register_feeder();
+    memory_trim::register_keystore_caches();
+    memory_trim::start_monitor();
     shared_secret_negotiation::perform_shared_secret_negotiation();
+    database_binding::verify_or_recover(
+        &keystore2::globals::DB_PATH.read().expect("Could not get DB_PATH."),
+    );
+    soft_key_migration::detect();
 
     info!("Starting thread pool now.");
     binder::ProcessState::start_thread_pool();
 
-    let ks_service = KeystoreService::new_native_binder(id_rotation_state).unwrap_or_else(|e| {
-        panic!("Failed to create service {} because of {:?}.", KS2_SERVICE_NAME, e);
-    });
+    let (ks_service, ks_extension_service) = KeystoreService::new_native_binder(id_rotation_state)
+        .unwrap_or_else(|e| {
+            panic!("Failed to create service {} because of {:?}.", KS2_SERVICE_NAME, e);
+        });
     binder::add_service(KS2_SERVICE_NAME, ks_service.as_binder()).unwrap_or_else(|e| {
         panic!("Failed to register service {} because of {:?}.", KS2_SERVICE_NAME, e);
     });
@@ -151,6 +166,34 @@ fn main() {
         },
     );
 
+    // The credential store is optional, so failing to bring it up must not take keystore down.
+    if feature_flags::is_enabled(Feature::CredentialStore) {
+        match CredentialStore::new_native_binder() {
+            Ok(credential_store_service) => {
+                if let Err(e) = binder::add_service(
+                    CREDENTIAL_STORE_SERVICE_NAME,
+                    credential_store_service.as_binder(),
+                ) {
+                    error!(
+                        "Failed to register service {} because of {:?}.",
+                        CREDENTIAL_STORE_SERVICE_NAME, e
+                    );
+                }
+            }
+            Err(e) => error!(
+                "Failed to create service {} because of {:?}.",
+                CREDENTIAL_STORE_SERVICE_NAME, e
+            ),
+        }
+    }
+
+    // The extension service is optional, so failing to register it must not take keystore down.
+    if let Err(e) =
+        binder::add_service(KS2_EXTENSION_SERVICE_NAME, ks_extension_service.as_binder())
+    {
+        error!("Failed to register service {} because of {:?}.", KS2_EXTENSION_SERVICE_NAME, e);
+    }
+
     info!("Successfully registered Keystore 2.0 service.");
 
     info!("Joining thread pool now.");
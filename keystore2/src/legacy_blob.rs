@@ -35,6 +35,10 @@ use std::{
 };
 
 const SUPPORTED_LEGACY_BLOB_VERSION: u8 = 3;
+/// Keymaster-era blob version. Only super keys of this version can be read, and only if the
+/// `keystore2_legacy_md5_kdf` feature is enabled.
+#[cfg(feature = "keystore2_legacy_md5_kdf")]
+const LEGACY_MD5_BLOB_VERSION: u8 = 2;
 
 mod flags {
     /// This flag is deprecated. It is here to support keys that have been written with this flag
@@ -1306,6 +1310,16 @@ impl LegacyBlobLoader {
     /// Load and decrypt legacy super key blob.
     pub fn load_super_key(&self, user_id: u32, pw: &Password) -> Result<Option<ZVec>> {
         let path = self.make_super_key_filename(user_id);
+
+        #[cfg(feature = "keystore2_legacy_md5_kdf")]
+        {
+            if let Some(key) = Self::load_md5_kdf_super_key(&path, pw)
+                .context(ks_err!("While loading version 2 super key."))?
+            {
+                return Ok(Some(key));
+            }
+        }
+
         let blob = Self::read_generic_blob(&path).context(ks_err!("While loading super key."))?;
 
         let blob = match blob {
@@ -1334,6 +1348,82 @@ impl LegacyBlobLoader {
         Ok(blob)
     }
 
+    /// Keymaster-era keystore wrote super keys as version 2 blobs which are encrypted with
+    /// AES-128-CBC under a key derived from the password with an MD5 based PBKDF2. Such a blob
+    /// has the following structure:
+    /// version (1 Byte)
+    /// blob_type (1 Byte)
+    /// flags (1 Byte)
+    /// info (1 Byte) Size of the salt appended to the blob.
+    /// initialization_vector (16 Bytes)
+    /// ciphertext of MD5 digest (16 Bytes), length (4 Bytes), value and padding
+    /// salt (info Bytes)
+    ///
+    /// Returns Ok(None) if the file does not exist or does not hold a version 2 blob, so that
+    /// the caller can fall back to the current blob format.
+    #[cfg(feature = "keystore2_legacy_md5_kdf")]
+    fn load_md5_kdf_super_key(path: &Path, pw: &Password) -> Result<Option<ZVec>> {
+        let buffer = match Self::with_retry_interrupted(|| fs::read(path)) {
+            Ok(buffer) => buffer,
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => return Ok(None),
+                _ => return Err(e).context(ks_err!()),
+            },
+        };
+
+        if buffer.is_empty() || buffer[Self::VERSION_OFFSET] != LEGACY_MD5_BLOB_VERSION {
+            return Ok(None);
+        }
+
+        let ciphertext_offset = Self::IV_OFFSET + Self::IV_SIZE;
+        if buffer.len() < ciphertext_offset + Self::SALT_SIZE {
+            return Err(Error::BadLen).context(ks_err!());
+        }
+
+        if buffer[Self::TYPE_OFFSET] != blob_types::SUPER_KEY
+            || (buffer[Self::FLAGS_OFFSET] & flags::ENCRYPTED) == 0
+            || buffer[Self::SALT_SIZE_OFFSET] as usize != Self::SALT_SIZE
+        {
+            return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context(ks_err!("Not an encrypted version 2 super key blob."));
+        }
+
+        let salt_offset = buffer.len() - Self::SALT_SIZE;
+        let iv = &buffer[Self::IV_OFFSET..ciphertext_offset];
+        let salt = &buffer[salt_offset..];
+
+        let key = pw
+            .derive_key_legacy_md5(salt)
+            .context(ks_err!("Failed to derive key from password."))?;
+        let plaintext = keystore2_crypto::aes_cbc_md5_decrypt_legacy(
+            &buffer[ciphertext_offset..salt_offset],
+            iv,
+            &key,
+        )
+        .context(ks_err!("while trying to decrypt version 2 super key blob."))?;
+
+        let length_offset = keystore2_crypto::LEGACY_MD5_DIGEST_LENGTH;
+        let value_offset = length_offset + 4;
+        if plaintext.len() < value_offset {
+            return Err(Error::BadLen).context(ks_err!());
+        }
+        let length =
+            u32::from_be_bytes(plaintext[length_offset..value_offset].try_into().unwrap()) as usize;
+        if plaintext.len() < value_offset + length {
+            return Err(Error::BadLen).context(ks_err!(
+                "Expected: {} got: {}.",
+                value_offset + length,
+                plaintext.len()
+            ));
+        }
+
+        Ok(Some(
+            plaintext[value_offset..value_offset + length]
+                .try_into()
+                .context(ks_err!("Trying to convert key into ZVec"))?,
+        ))
+    }
+
     /// Removes the super key for the given user from the legacy database.
     /// If this was the last entry in the user's database, this function removes
     /// the user_<uid> directory as well.
@@ -2242,6 +2332,31 @@ mod test {
         Ok(())
     }
 
+    #[cfg(feature = "keystore2_legacy_md5_kdf")]
+    #[test]
+    fn test_load_md5_kdf_super_key() -> Result<()> {
+        let temp_dir = TempDir::new("test_load_md5_kdf_super_key").unwrap();
+        std::fs::create_dir(&*temp_dir.build().push("user_0")).unwrap();
+        std::fs::write(&*temp_dir.build().push("user_0").push(".masterkey"), SUPERKEY_V2).unwrap();
+
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+        let pw: Password = PASSWORD.into();
+        let super_key = legacy_blob_loader.load_super_key(0, &pw)?.unwrap();
+        assert_eq!(&super_key[..], SUPERKEY_V2_PAYLOAD);
+
+        // A wrong password is caught by the MD5 digest check.
+        let wrong_pw: Password = SUPERKEY_V2_PAYLOAD.into();
+        assert!(legacy_blob_loader.load_super_key(0, &wrong_pw).is_err());
+
+        // Current super key blobs still take the regular path.
+        std::fs::write(&*temp_dir.build().push("user_0").push(".masterkey"), SUPERKEY).unwrap();
+        let pw_key = pw.derive_key(SUPERKEY_SALT, 32)?;
+        let expected = aes_gcm_decrypt(SUPERKEY_PAYLOAD, SUPERKEY_IV, SUPERKEY_TAG, &pw_key)?;
+        assert_eq!(legacy_blob_loader.load_super_key(0, &pw)?.unwrap(), expected);
+
+        Ok(())
+    }
+
     #[test]
     fn list_non_existing_user() -> Result<()> {
         let temp_dir = TempDir::new("list_non_existing_user").unwrap();
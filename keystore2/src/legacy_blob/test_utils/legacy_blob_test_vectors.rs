@@ -304,6 +304,22 @@ pub static SUPERKEY_PAYLOAD: &[u8] = &[
     0xef, 0x68, 0x5e, 0x5b, 0x53, 0xa8, 0xe7, 0xa2, 0x76, 0x04, 0x2a, 0x48, 0xd1, 0xa7, 0x59, 0xd1,
 ];
 
+/// Keymaster-era version 2 super key blob, encrypted with AES-128-CBC under a key derived
+/// from `PASSWORD` with the MD5 based PBKDF2.
+pub static SUPERKEY_V2: &[u8] = &[
+    0x02, 0x02, 0x01, 0x10, 0xe1, 0x7d, 0x4c, 0x08, 0xa9, 0x5b, 0x33, 0x26, 0xf0, 0xc2, 0x8d, 0x1a,
+    0x7e, 0x6b, 0x50, 0x94, 0xf4, 0x77, 0x60, 0xf5, 0x09, 0xb8, 0x21, 0x7a, 0xb3, 0xd8, 0x20, 0xe9,
+    0x71, 0xe0, 0x02, 0x65, 0x35, 0x77, 0x98, 0x42, 0x0d, 0x40, 0xf8, 0x26, 0x7e, 0x5c, 0xeb, 0x5a,
+    0x7b, 0x6b, 0x04, 0xb1, 0xf7, 0x16, 0x7e, 0x40, 0x13, 0xd4, 0x3f, 0x92, 0xb0, 0xa0, 0x02, 0xba,
+    0xdb, 0x09, 0xce, 0x6c, 0x5c, 0x3a, 0x1f, 0x0e, 0x9b, 0x2d, 0x47, 0xa8, 0xc6, 0x1e, 0x33, 0xf0,
+    0xd8, 0x4b, 0x2a, 0x97,
+];
+
+/// Version 2 super key payload.
+pub static SUPERKEY_V2_PAYLOAD: &[u8] = &[
+    0x3f, 0x8a, 0x21, 0xc6, 0xd0, 0x5e, 0x9b, 0x77, 0x40, 0xa1, 0xe2, 0xc3, 0x9d, 0x86, 0xf5, 0x1b,
+];
+
 /// user key blob.
 pub static USRPKEY_AUTHBOUND: &[u8] = &[
     0x03, 0x04, 0x04, 0x00, 0x1c, 0x34, 0x87, 0x6f, 0xc8, 0x35, 0x0d, 0x34, 0x88, 0x59, 0xbc, 0xf5,
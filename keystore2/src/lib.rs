@@ -15,25 +15,35 @@
 //! This crate implements the Android Keystore 2.0 service.
 #![recursion_limit = "256"]
 
+pub mod access_window;
 pub mod apc;
 pub mod async_task;
+pub mod attestation_app_id;
 pub mod authorization;
+pub mod avf;
 pub mod boot_level_keys;
+pub mod credential_store;
 pub mod database;
+pub mod database_binding;
+pub mod deadline;
 pub mod ec_crypto;
 pub mod enforcements;
 pub mod entropy;
 pub mod error;
+pub mod feature_flags;
 pub mod globals;
 pub mod id_rotation;
+pub mod key_backup;
 /// Internal Representation of Key Parameter and convenience functions.
 pub mod key_parameter;
 pub mod ks_err;
 pub mod legacy_blob;
 pub mod legacy_importer;
 pub mod maintenance;
+pub mod memory_trim;
 pub mod metrics;
 pub mod metrics_store;
+pub mod namespace;
 pub mod operation;
 pub mod permission;
 pub mod raw_device;
@@ -42,14 +52,47 @@ pub mod rkpd_client;
 pub mod security_level;
 pub mod service;
 pub mod shared_secret_negotiation;
+pub mod soft_key_migration;
+pub mod super_key_secret;
 pub mod utils;
 
+mod anomaly_detector;
 mod attestation_key_utils;
+mod attestation_record;
 mod audit_log;
+mod authorization_diff;
+mod background_jobs;
+mod cert_chain;
+mod deprecation;
+mod digest_info;
+mod dump_schema;
 mod gc;
+mod grant_cache;
+mod hal_health;
+mod import_fingerprint;
+mod key_material_cache;
+mod key_operation_stats;
+mod key_validity;
 mod km_compat;
+mod lock_stats;
+mod managed_nonce;
+mod operation_slots;
+mod patch_level_policy;
+mod post_mortem;
+mod reboot_escrow;
+mod reserved_alias;
+mod rkp_roots;
+mod strongbox_budget;
 mod super_key;
+mod super_key_escrow;
 mod sw_keyblob;
+mod synthetic_password;
+mod unique_id;
+mod user_limits;
 
+#[cfg(feature = "keystore2_fault_injection")]
+pub mod fault_injection;
+#[cfg(test)]
+mod key_parameter_interop;
 #[cfg(feature = "watchdog")]
 mod watchdog;
@@ -0,0 +1,203 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements lightweight lock contention profiling. Profiled locks count their
+//! acquisitions, how many of these had to wait for another holder, and the total time spent
+//! waiting. An acquisition only incurs the cost of a failed `try_lock` and a clock read if it
+//! is contended. The counters of all profiled locks are exported through the dump of the
+//! Keystore 2.0 service. Poisoned locks are reported to DropBox.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::post_mortem::{self, FatalEvent};
+use lazy_static::lazy_static;
+use std::io::Write;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, LockResult, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    TryLockResult, Weak,
+};
+use std::time::Instant;
+
+lazy_static! {
+    /// All named lock statistics that are still alive.
+    static ref REGISTRY: Mutex<Vec<(String, Weak<LockStats>)>> = Default::default();
+}
+
+/// Contention counters of one lock or of a group of locks, e.g., the shards of a sharded map.
+#[derive(Debug, Default)]
+pub struct LockStats {
+    name: String,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_micros: AtomicU64,
+}
+
+impl LockStats {
+    /// Creates a new set of counters which is included in `dump` under the given name for as
+    /// long as it is alive.
+    pub fn new_registered(name: &str) -> Arc<Self> {
+        let stats = Arc::new(Self { name: name.to_string(), ..Default::default() });
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.retain(|(_, s)| s.strong_count() != 0);
+        registry.push((name.to_string(), Arc::downgrade(&stats)));
+        stats
+    }
+
+    /// Locks `mutex` and records the acquisition.
+    pub fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> LockResult<MutexGuard<'a, T>> {
+        self.acquire(|| mutex.try_lock(), || mutex.lock())
+    }
+
+    fn acquire<G>(
+        &self,
+        try_acquire: impl FnOnce() -> TryLockResult<G>,
+        acquire: impl FnOnce() -> LockResult<G>,
+    ) -> LockResult<G> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        let result = match try_acquire() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(e)) => Err(e),
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                let start = Instant::now();
+                let result = acquire();
+                self.wait_micros.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+                result
+            }
+        };
+        if result.is_err() {
+            post_mortem::report(FatalEvent::LockPoisoned, &self.name, &[]);
+        }
+        result
+    }
+
+    /// Returns the number of acquisitions, the number of contended acquisitions, and the
+    /// total time spent waiting in microseconds.
+    pub fn get(&self) -> (u64, u64, u64) {
+        (
+            self.acquisitions.load(Ordering::Relaxed),
+            self.contended.load(Ordering::Relaxed),
+            self.wait_micros.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A `RwLock` that records its contention in a `LockStats`. It offers the same locking
+/// interface as `RwLock`.
+#[derive(Debug)]
+pub struct ProfiledRwLock<T> {
+    lock: RwLock<T>,
+    stats: Arc<LockStats>,
+}
+
+impl<T> ProfiledRwLock<T> {
+    /// Creates a new profiled lock holding `value`, whose statistics are dumped as `name`.
+    pub fn new(name: &str, value: T) -> Self {
+        Self { lock: RwLock::new(value), stats: LockStats::new_registered(name) }
+    }
+
+    /// Locks this lock with shared read access. See `RwLock::read`.
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.stats.acquire(|| self.lock.try_read(), || self.lock.read())
+    }
+
+    /// Locks this lock with exclusive write access. See `RwLock::write`.
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        self.stats.acquire(|| self.lock.try_write(), || self.lock.write())
+    }
+}
+
+impl<T: Default> Default for ProfiledRwLock<T> {
+    fn default() -> Self {
+        Self::new(std::any::type_name::<T>(), Default::default())
+    }
+}
+
+/// The dump section of the contention counters.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "lock_contention",
+    header: "Lock contention (acquisitions, contended, wait time):",
+    line: "  <name>: <acquisitions>, <contended>, <wait_micros>us",
+    fields: dump_fields!(
+        name: "string",
+        acquisitions: "uint64",
+        contended: "uint64",
+        wait_micros: "uint64",
+    ),
+};
+
+/// Writes the contention counters of all registered locks to `writer`.
+pub fn dump(writer: &mut dyn Write) -> std::io::Result<()> {
+    DUMP_SECTION.write_header(writer)?;
+    for (name, stats) in REGISTRY.lock().unwrap().iter() {
+        if let Some(stats) = stats.upgrade() {
+            let (acquisitions, contended, wait_micros) = stats.get();
+            DUMP_SECTION.write_line(writer, &[name, &acquisitions, &contended, &wait_micros])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn uncontended_acquisitions() {
+        let stats = LockStats::new_registered("uncontended_acquisitions");
+        let mutex = Mutex::new(0);
+        *stats.lock(&mutex).unwrap() += 1;
+        *stats.lock(&mutex).unwrap() += 1;
+        assert_eq!(*mutex.lock().unwrap(), 2);
+        assert_eq!(stats.get(), (2, 0, 0));
+    }
+
+    #[test]
+    fn contended_acquisition() {
+        let lock = Arc::new(ProfiledRwLock::new("contended_acquisition", 0));
+        let (sender, receiver) = channel();
+        let lock_clone = lock.clone();
+        let holder = std::thread::spawn(move || {
+            let mut guard = lock_clone.write().unwrap();
+            sender.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            *guard += 1;
+        });
+        receiver.recv().unwrap();
+        assert_eq!(*lock.read().unwrap(), 1);
+        holder.join().unwrap();
+
+        let (acquisitions, contended, wait_micros) = lock.stats.get();
+        assert_eq!(acquisitions, 2);
+        assert_eq!(contended, 1);
+        assert!(wait_micros > 0);
+    }
+
+    #[test]
+    fn dump_lists_live_locks() {
+        let stats = LockStats::new_registered("dump_lists_live_locks");
+        drop(stats.lock(&Mutex::new(())).unwrap());
+        let dropped = LockStats::new_registered("dump_lists_dropped_locks");
+        drop(dropped);
+
+        let mut out = Vec::new();
+        dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("dump_lists_live_locks: 1, 0, 0us"));
+        assert!(!out.contains("dump_lists_dropped_locks"));
+    }
+}
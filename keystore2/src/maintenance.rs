@@ -14,23 +14,64 @@
 
 //! This module implements IKeystoreMaintenance AIDL interface.
 
-use crate::database::{KeyEntryLoadBits, KeyType, MonotonicRawTime};
+use crate::attestation_app_id;
+use crate::attestation_record;
+use crate::avf;
+use crate::background_jobs;
+use crate::database::{
+    KeyCharacteristicsEntry, KeyEntryLoadBits, KeyHistoryEvent, KeyMaintenanceReason, KeyType,
+    MonotonicRawTime, StorageKeyClass, StorageKeyEntry, UserKeyFilter,
+};
 use crate::error::map_km_error;
 use crate::error::map_or_log_err;
-use crate::error::Error;
+use crate::error::{Error, ErrorCode};
+use crate::feature_flags::{self, Feature};
 use crate::globals::get_keymint_device;
-use crate::globals::{DB, LEGACY_IMPORTER, SUPER_KEY};
+use crate::globals::{
+    DB, GC, KEY_OPERATION_STATS, LEGACY_IMPORTER, PATCH_LEVEL_POLICY, SUPER_KEY, UID_PRIORITIES,
+    USER_LIMITS,
+};
+use crate::key_backup;
+use crate::key_parameter::KeyParameter;
 use crate::ks_err;
+use crate::namespace::Namespace;
+use crate::patch_level_policy::{self, Policy};
 use crate::permission::{KeyPerm, KeystorePerm};
-use crate::super_key::{SuperKeyManager, UserState};
+use crate::reboot_escrow::KeyMintSealer;
+use crate::reserved_alias;
+use crate::soft_key_migration::{self, Failure, MigrationOutcome};
+use crate::super_key::{
+    SuperKeyManager, UserState, USER_AFTER_FIRST_UNLOCK_SUPER_KEY, USER_BIOMETRIC_BOUND_SUPER_KEY,
+    USER_UNLOCKED_DEVICE_REQUIRED_P521_SUPER_KEY,
+    USER_UNLOCKED_DEVICE_REQUIRED_SYMMETRIC_SUPER_KEY,
+};
 use crate::utils::{
-    check_key_permission, check_keystore_permission, uid_to_android_user, watchdog as wd,
+    app_uid_to_sdk_sandbox_uid, check_key_permission, check_keystore_permission,
+    uid_to_android_user, watchdog as wd,
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     IKeyMintDevice::IKeyMintDevice, SecurityLevel::SecurityLevel,
 };
-use android_security_maintenance::aidl::android::security::maintenance::IKeystoreMaintenance::{
-    BnKeystoreMaintenance, IKeystoreMaintenance,
+use android_security_maintenance::aidl::android::security::maintenance::{
+    BackgroundJob::BackgroundJob,
+    BackupKeyMaterial::BackupKeyMaterial,
+    DuplicateImport::DuplicateImport,
+    ErrorCount::ErrorCount,
+    GarbageCollectionStats::GarbageCollectionStats,
+    IKeystoreMaintenance::{BnKeystoreMaintenance, IKeystoreMaintenance},
+    IMaintenanceListener::IMaintenanceListener,
+    KeyDescription::KeyDescription,
+    KeyHistoryEntry::KeyHistoryEntry,
+    KeyHistoryEvent::KeyHistoryEvent as AidlKeyHistoryEvent,
+    KeyMaintenanceEntry::KeyMaintenanceEntry,
+    KeyMaintenanceReason::KeyMaintenanceReason as AidlKeyMaintenanceReason,
+    KeyOperationStats::KeyOperationStats,
+    PatchLevelPolicy::PatchLevelPolicy,
+    StorageKeyBlob::StorageKeyBlob,
+    StorageKeyClass::StorageKeyClass as AidlStorageKeyClass,
+    UserKeyUsage::UserKeyUsage,
+    UserState::UserState as AidlUserState,
+    UserStateInfo::UserStateInfo,
 };
 use android_security_maintenance::binder::{
     BinderFeatures, Interface, Result as BinderResult, Strong, ThreadState,
@@ -39,6 +80,82 @@ use android_system_keystore2::aidl::android::system::keystore2::KeyDescriptor::K
 use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use anyhow::{Context, Result};
 use keystore2_crypto::Password;
+use serde::Serialize;
+use std::time::Duration;
+
+/// System property that is true on debuggable builds.
+const DEBUGGABLE_PROPERTY: &str = "ro.debuggable";
+
+/// The most keys that one page of IKeystoreMaintenance::exportKeyCharacteristics holds, which
+/// keeps the reply well below the binder transaction limit.
+const MAX_EXPORTED_KEYS_PER_PAGE: usize = 256;
+
+/// A page of IKeystoreMaintenance::exportKeyCharacteristics, as it is encoded in CBOR.
+#[derive(Serialize)]
+struct ExportedKeyCharacteristics {
+    keys: Vec<ExportedKey>,
+    next_key_id: Option<i64>,
+}
+
+/// The characteristics of one key in `ExportedKeyCharacteristics`.
+#[derive(Serialize)]
+struct ExportedKey {
+    key_id: i64,
+    namespace: i64,
+    alias: Option<String>,
+    authorizations: Vec<KeyParameter>,
+}
+
+/// The most keys that one call of IKeystoreMaintenance::migrateSoftwareKeys migrates. Each key
+/// takes an import into the TEE KeyMint.
+const MAX_MIGRATED_KEYS_PER_PAGE: usize = 16;
+
+/// A page of IKeystoreMaintenance::migrateSoftwareKeys, as it is encoded in CBOR.
+#[derive(Serialize)]
+struct SoftwareKeyMigrationReport {
+    migrated: Vec<MigratedKey>,
+    not_preserved: Vec<MigratedKey>,
+    next_key_id: Option<i64>,
+}
+
+/// A key in `SoftwareKeyMigrationReport`. The reason is None for keys that were migrated.
+#[derive(Serialize)]
+struct MigratedKey {
+    key_id: i64,
+    domain: i32,
+    namespace: i64,
+    alias: Option<String>,
+    reason: Option<String>,
+}
+
+impl From<MigrationOutcome> for MigratedKey {
+    fn from(outcome: MigrationOutcome) -> Self {
+        let reason = outcome.failure.map(|failure| match failure {
+            Failure::Locked => "locked".to_string(),
+            Failure::UnknownBlobFormat => "unknown_blob_format".to_string(),
+            Failure::BlobUnreadable => "blob_unreadable".to_string(),
+            Failure::ImportRejected(error_code) => format!("import_rejected({})", error_code.0),
+        });
+        Self {
+            key_id: outcome.key_id,
+            domain: outcome.key.domain.0,
+            namespace: outcome.key.nspace,
+            alias: outcome.key.alias,
+            reason,
+        }
+    }
+}
+
+impl From<KeyCharacteristicsEntry> for ExportedKey {
+    fn from(entry: KeyCharacteristicsEntry) -> Self {
+        Self {
+            key_id: entry.key_id,
+            namespace: entry.namespace,
+            alias: entry.alias,
+            authorizations: entry.parameters,
+        }
+    }
+}
 
 /// Reexport Domain for the benefit of DeleteListener
 pub use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
@@ -75,28 +192,52 @@ impl Maintenance {
 
         let mut skm = SUPER_KEY.write().unwrap();
 
-        if let Some(pw) = password.as_ref() {
-            DB.with(|db| {
-                skm.unlock_unlocked_device_required_keys(&mut db.borrow_mut(), user_id as u32, pw)
-            })
-            .context(ks_err!("unlock_unlocked_device_required_keys failed"))?;
-        }
-
-        if let UserState::BeforeFirstUnlock = DB
+        let user_state = DB
             .with(|db| skm.get_user_state(&mut db.borrow_mut(), &LEGACY_IMPORTER, user_id as u32))
-            .context(ks_err!("Could not get user state while changing password!"))?
-        {
+            .context(ks_err!("Could not get user state while changing password!"))?;
+        if let UserState::BeforeFirstUnlock = user_state {
             // Error - password can not be changed when the device is locked
             return Err(Error::Rc(ResponseCode::LOCKED)).context(ks_err!("Device is locked."));
         }
 
-        DB.with(|db| match password {
-            Some(pass) => {
+        DB.with(|db| match (password, user_state) {
+            (Some(pass), UserState::Uninitialized) => {
+                skm.unlock_unlocked_device_required_keys(
+                    &mut db.borrow_mut(),
+                    user_id as u32,
+                    &pass,
+                )
+                .context(ks_err!("unlock_unlocked_device_required_keys failed"))?;
                 skm.init_user(&mut db.borrow_mut(), &LEGACY_IMPORTER, user_id as u32, &pass)
             }
-            None => {
+            (Some(pass), _) => {
+                // The user changed their LSKF while the device is in use. The super keys stay
+                // in memory and are rewrapped with the new password. This must happen before
+                // the UnlockedDeviceRequired super keys are unlocked with the new password,
+                // which they are not encrypted with yet. If they are locked, the change fails
+                // with LOCKED.
+                skm.change_user_password(
+                    &mut db.borrow_mut(),
+                    &LEGACY_IMPORTER,
+                    user_id as u32,
+                    &pass,
+                )?;
+                // Creates the UnlockedDeviceRequired super keys if the user has none yet.
+                skm.unlock_unlocked_device_required_keys(
+                    &mut db.borrow_mut(),
+                    user_id as u32,
+                    &pass,
+                )
+                .context(ks_err!("unlock_unlocked_device_required_keys failed"))
+            }
+            (None, _) => {
                 // User transitioned to swipe.
-                skm.reset_user(&mut db.borrow_mut(), &LEGACY_IMPORTER, user_id as u32)
+                skm.reset_user(
+                    &mut db.borrow_mut(),
+                    &LEGACY_IMPORTER,
+                    user_id as u32,
+                    UserKeyFilter::APP_AND_SUPER_KEYS.super_encrypted_only(),
+                )
             }
         })
         .context(ks_err!("Failed to change user password!"))
@@ -120,6 +261,28 @@ impl Maintenance {
             .context(ks_err!("While invoking the delete listener."))
     }
 
+    fn remove_user(&self, user_id: i32) -> Result<()> {
+        self.add_or_remove_user(user_id).context(ks_err!())?;
+        // vold creates its storage keys with the KeyMint instance of the TEE.
+        let (_, _, km_uuid) = get_keymint_device(&SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context(ks_err!("Trying to get the KeyMint instance of the storage keys."))?;
+        let deleted = DB
+            .with(|db| db.borrow_mut().unbind_storage_keys_for_user(user_id as u32, &km_uuid))
+            .context(ks_err!("Trying to delete storage keys."))?;
+        if deleted != 0 {
+            log::info!("Deleted {deleted} storage keys of removed user {user_id}.");
+        }
+        let deleted = DB
+            .with(|db| db.borrow_mut().delete_credentials_for_user(user_id as u32))
+            .context(ks_err!("Trying to delete credentials."))?;
+        if deleted != 0 {
+            log::info!("Deleted {deleted} credentials of removed user {user_id}.");
+        }
+        DB.with(|db| db.borrow_mut().set_max_key_validity(user_id as u32, None))
+            .context(ks_err!("Trying to delete the key validity policy."))?;
+        Ok(())
+    }
+
     fn clear_namespace(&self, domain: Domain, nspace: i64) -> Result<()> {
         // Permission check. Must return on error. Do not touch the '?'.
         check_keystore_permission(KeystorePerm::ClearUID).context("In clear_namespace.")?;
@@ -131,7 +294,27 @@ impl Maintenance {
             .context(ks_err!("Trying to delete keys from db."))?;
         self.delete_listener
             .delete_namespace(domain, nspace)
-            .context(ks_err!("While invoking the delete listener."))
+            .context(ks_err!("While invoking the delete listener."))?;
+
+        // Credentials are owned by the uid of the component that stored them.
+        if domain == Domain::APP {
+            DB.with(|db| db.borrow_mut().delete_credentials_for_owner(nspace as u32))
+                .context(ks_err!("Trying to delete credentials from db."))?;
+        }
+
+        // The SDK sandbox of an app has its own isolated namespace, which does not outlive
+        // the app.
+        if domain == Domain::APP {
+            if let Some(sandbox_uid) = app_uid_to_sdk_sandbox_uid(nspace as u32) {
+                let sandbox_nspace = sandbox_uid as i64;
+                DB.with(|db| db.borrow_mut().unbind_keys_for_namespace(domain, sandbox_nspace))
+                    .context(ks_err!("Trying to delete SDK sandbox keys from db."))?;
+                self.delete_listener
+                    .delete_namespace(domain, sandbox_nspace)
+                    .context(ks_err!("While invoking the delete listener for SDK sandbox."))?;
+            }
+        }
+        Ok(())
     }
 
     fn call_with_watchdog<F>(sec_level: SecurityLevel, name: &'static str, op: &F) -> Result<()>
@@ -237,12 +420,641 @@ impl Maintenance {
                 .context(ks_err!("Failed to load key blob."))?;
             {
                 db.borrow_mut().migrate_key_namespace(key_id_guard, destination, calling_uid, |k| {
-                    check_key_permission(KeyPerm::Rebind, k, &None)
+                    check_key_permission(KeyPerm::Rebind, k, &None)?;
+                    reserved_alias::check_alias(k, calling_uid)
                 })
             }
         })
     }
 
+    fn adopt_app_key(source: &KeyDescriptor, destination: &KeyDescriptor) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::AdoptAppKey).context(ks_err!())?;
+
+        let app_uid = match (source.alias.as_ref(), Namespace::new(source.domain, source.nspace)) {
+            (Some(_), Ok(Namespace::App(app_uid))) => app_uid,
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Source must be an APP domain key with uid and alias."));
+            }
+        };
+
+        if destination.domain != Domain::SELINUX {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Destination domain must be SELINUX."));
+        }
+
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(uid_to_android_user(app_uid));
+
+        DB.with(|db| {
+            // The key is looked up on behalf of the owning app. Access was already established
+            // by the AdoptAppKey permission check above.
+            let (key_id_guard, _) = LEGACY_IMPORTER
+                .with_try_import(source, app_uid, super_key, || {
+                    db.borrow_mut().load_key_entry(
+                        source,
+                        KeyType::Client,
+                        KeyEntryLoadBits::NONE,
+                        app_uid,
+                        |_, _| Ok(()),
+                    )
+                })
+                .context(ks_err!("Failed to load key blob."))?;
+            db.borrow_mut().adopt_app_key(key_id_guard, destination, |k| {
+                check_key_permission(KeyPerm::Rebind, k, &None)
+            })
+        })
+    }
+
+    fn export_key_for_backup(
+        key: &KeyDescriptor,
+        backup_public_key: &[u8],
+    ) -> Result<BackupKeyMaterial> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ExportForBackup).context(ks_err!())?;
+
+        let app_uid = match (key.alias.as_ref(), Namespace::new(key.domain, key.nspace)) {
+            (Some(_), Ok(Namespace::App(app_uid))) => app_uid,
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Key must be an APP domain key with uid and alias."));
+            }
+        };
+
+        let user_id = uid_to_android_user(app_uid);
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(user_id)
+            .ok_or(Error::Rc(ResponseCode::LOCKED))
+            .context(ks_err!("User {user_id} has not unlocked the device since boot."))?;
+
+        // Keys eligible for backup were imported into Keystore 2.0, so there are no legacy
+        // keys to consider. Access was already established by the permission check above.
+        let (_, key_entry) = DB
+            .with(|db| {
+                db.borrow_mut().load_key_entry(
+                    key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::NONE,
+                    app_uid,
+                    |_, _| Ok(()),
+                )
+            })
+            .context(ks_err!("Failed to load key entry."))?;
+
+        let sealed = key_entry
+            .metadata()
+            .backup_material()
+            .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Key is not eligible for backup."))?;
+        let (format, key_data) = key_backup::unseal(super_key.as_ref(), sealed)
+            .context(ks_err!("Failed to unseal key material."))?;
+        key_backup::encrypt_for_backup(format, &key_data, backup_public_key)
+            .context(ks_err!("Failed to encrypt key material for backup."))
+    }
+
+    fn provision_super_key_escrow(
+        user_id: i32,
+        escrow_public_key: &[u8],
+        password: Password,
+    ) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ManageSuperKeyEscrow).context(ks_err!())?;
+
+        DB.with(|db| {
+            SUPER_KEY.write().unwrap().provision_super_key_escrow(
+                &mut db.borrow_mut(),
+                user_id as u32,
+                escrow_public_key,
+                &password,
+            )
+        })
+        .context(ks_err!("Failed to provision super key escrow."))
+    }
+
+    fn clear_super_key_escrow(user_id: i32) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ManageSuperKeyEscrow).context(ks_err!())?;
+
+        DB.with(|db| {
+            SUPER_KEY.write().unwrap().clear_super_key_escrow(&mut db.borrow_mut(), user_id as u32)
+        })
+        .context(ks_err!("Failed to clear super key escrow."))
+    }
+
+    fn recover_user_from_escrow(
+        user_id: i32,
+        escrow_private_key: &[u8],
+        new_password: Password,
+    ) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ManageSuperKeyEscrow).context(ks_err!())?;
+
+        DB.with(|db| {
+            SUPER_KEY.write().unwrap().recover_user_from_escrow(
+                &mut db.borrow_mut(),
+                &LEGACY_IMPORTER,
+                user_id as u32,
+                escrow_private_key,
+                &new_password,
+            )
+        })
+        .context(ks_err!("Failed to recover user from escrow."))
+    }
+
+    fn arm_reboot_escrow(user_id: i32, escrow_secret: &[u8]) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ChangePassword).context(ks_err!())?;
+        if !feature_flags::is_enabled(Feature::RebootEscrow) {
+            return Err(Error::Km(ErrorCode::UNIMPLEMENTED))
+                .context(ks_err!("Reboot escrow is not enabled."));
+        }
+
+        DB.with(|db| {
+            SUPER_KEY.write().unwrap().arm_reboot_escrow(
+                &mut db.borrow_mut(),
+                user_id as u32,
+                escrow_secret,
+                &KeyMintSealer,
+            )
+        })
+        .context(ks_err!("Failed to arm reboot escrow."))
+    }
+
+    fn clear_reboot_escrow(user_id: i32) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ChangePassword).context(ks_err!())?;
+
+        DB.with(|db| {
+            SUPER_KEY.write().unwrap().clear_reboot_escrow(&mut db.borrow_mut(), user_id as u32)
+        })
+        .context(ks_err!("Failed to clear reboot escrow."))
+    }
+
+    fn get_key_history(domain: Domain, nspace: i64) -> Result<Vec<KeyHistoryEntry>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::GetKeyHistory).context(ks_err!())?;
+
+        let namespace = match Namespace::new(domain, nspace).context(ks_err!())? {
+            namespace @ (Namespace::App(_) | Namespace::SeLinux(_)) => namespace,
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Domain must be APP or SELINUX."));
+            }
+        };
+
+        let history = DB
+            .with(|db| db.borrow_mut().get_key_history(Some(namespace)))
+            .context(ks_err!("Failed to load key history."))?;
+        Ok(history
+            .into_iter()
+            .map(|entry| KeyHistoryEntry {
+                keyIdHash: entry.key_id_hash,
+                event: match entry.event {
+                    KeyHistoryEvent::Created => AidlKeyHistoryEvent::CREATED,
+                    KeyHistoryEvent::Rebound => AidlKeyHistoryEvent::REBOUND,
+                    KeyHistoryEvent::Deleted => AidlKeyHistoryEvent::DELETED,
+                    KeyHistoryEvent::Invalidated => AidlKeyHistoryEvent::INVALIDATED,
+                },
+                domain: entry.domain,
+                nspace: entry.namespace,
+                timestampMillis: entry.time.to_millis_epoch(),
+            })
+            .collect())
+    }
+
+    fn check_storage_key_args(
+        user_id: i32,
+        key_class: AidlStorageKeyClass,
+    ) -> Result<(u32, StorageKeyClass)> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ManageStorageKeys).context(ks_err!())?;
+
+        let user_id = u32::try_from(user_id)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Invalid user id {user_id}."))?;
+        let key_class = match key_class {
+            AidlStorageKeyClass::DEVICE_ENCRYPTED => StorageKeyClass::DeviceEncrypted,
+            AidlStorageKeyClass::CREDENTIAL_ENCRYPTED => StorageKeyClass::CredentialEncrypted,
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Unknown storage key class {key_class:?}."));
+            }
+        };
+        Ok((user_id, key_class))
+    }
+
+    fn storage_key_blob(entry: StorageKeyEntry) -> StorageKeyBlob {
+        StorageKeyBlob {
+            keyBlob: entry.key_blob,
+            generation: entry.generation,
+            creationTimeMillis: entry.created.to_millis_epoch(),
+        }
+    }
+
+    fn register_storage_key(
+        user_id: i32,
+        key_class: AidlStorageKeyClass,
+        key_blob: &[u8],
+    ) -> Result<()> {
+        let (user_id, key_class) = Self::check_storage_key_args(user_id, key_class)?;
+        if key_blob.is_empty() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Empty key blob."));
+        }
+        DB.with(|db| db.borrow_mut().register_storage_key(user_id, key_class, key_blob))
+            .context(ks_err!("Failed to register storage key."))?;
+        log::info!(
+            "Storage key {key_class:?} of user {user_id} registered by uid {}.",
+            ThreadState::get_calling_uid()
+        );
+        Ok(())
+    }
+
+    fn get_storage_key(user_id: i32, key_class: AidlStorageKeyClass) -> Result<StorageKeyBlob> {
+        let (user_id, key_class) = Self::check_storage_key_args(user_id, key_class)?;
+        DB.with(|db| db.borrow_mut().get_storage_key(user_id, key_class))
+            .map(Self::storage_key_blob)
+            .context(ks_err!("Failed to load storage key."))
+    }
+
+    fn rotate_storage_key(
+        user_id: i32,
+        key_class: AidlStorageKeyClass,
+        new_key_blob: &[u8],
+    ) -> Result<StorageKeyBlob> {
+        let (user_id, key_class) = Self::check_storage_key_args(user_id, key_class)?;
+        if new_key_blob.is_empty() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Empty key blob."));
+        }
+        let previous = DB
+            .with(|db| db.borrow_mut().rotate_storage_key(user_id, key_class, new_key_blob))
+            .context(ks_err!("Failed to rotate storage key."))?;
+        log::info!(
+            "Storage key {key_class:?} of user {user_id} rotated to generation {} by uid {}.",
+            previous.generation + 1,
+            ThreadState::get_calling_uid()
+        );
+        Ok(Self::storage_key_blob(previous))
+    }
+
+    fn list_keys_requiring_maintenance() -> Result<Vec<KeyMaintenanceEntry>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ListKeysForMaintenance).context(ks_err!())?;
+
+        let os_patch_level = patch_level_policy::current_os_patch_level();
+        let keys = DB
+            .with(|db| db.borrow_mut().list_keys_requiring_maintenance(os_patch_level))
+            .context(ks_err!("Failed to list keys."))?;
+        Ok(keys
+            .into_iter()
+            .map(|(key_id, reason)| KeyMaintenanceEntry {
+                keyId: key_id,
+                reason: match reason {
+                    KeyMaintenanceReason::RequiresUpgrade => {
+                        AidlKeyMaintenanceReason::REQUIRES_UPGRADE
+                    }
+                    KeyMaintenanceReason::RequiresRewrap => {
+                        AidlKeyMaintenanceReason::REQUIRES_REWRAP
+                    }
+                    KeyMaintenanceReason::DeprecatedAlgorithm => {
+                        AidlKeyMaintenanceReason::DEPRECATED_ALGORITHM
+                    }
+                },
+            })
+            .collect())
+    }
+
+    fn audit_blob_checksums() -> Result<Vec<KeyMaintenanceEntry>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ListKeysForMaintenance).context(ks_err!())?;
+
+        let _job = background_jobs::start(BackgroundJob::BLOB_CHECKSUM_AUDIT, None);
+        let key_ids = DB
+            .with(|db| db.borrow_mut().audit_blob_checksums())
+            .context(ks_err!("Failed to audit blobs."))?;
+        Ok(key_ids
+            .into_iter()
+            .map(|key_id| KeyMaintenanceEntry {
+                keyId: key_id,
+                reason: AidlKeyMaintenanceReason::CORRUPTED_BLOB,
+            })
+            .collect())
+    }
+
+    fn register_maintenance_listener(listener: &Strong<dyn IMaintenanceListener>) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ListKeysForMaintenance).context(ks_err!())?;
+
+        background_jobs::register_listener(listener);
+        Ok(())
+    }
+
+    fn unregister_maintenance_listener(listener: &Strong<dyn IMaintenanceListener>) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ListKeysForMaintenance).context(ks_err!())?;
+
+        background_jobs::unregister_listener(listener);
+        Ok(())
+    }
+
+    fn wait_for_quiescence(timeout_millis: i64) -> Result<bool> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ListKeysForMaintenance).context(ks_err!())?;
+
+        let timeout = u64::try_from(timeout_millis)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Negative timeout {timeout_millis}."))?;
+        Ok(background_jobs::wait_for_quiescence(Duration::from_millis(timeout)))
+    }
+
+    fn get_attestation_application_id(uid: i32) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::Dump).context(ks_err!())?;
+
+        attestation_app_id::get(uid as u32).context(ks_err!())
+    }
+
+    fn delete_vm_instance_keys(instance_id: &[u8]) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::DeleteVmInstanceKeys).context(ks_err!())?;
+
+        let prefix = avf::instance_alias_prefix(instance_id).context(ks_err!())?;
+        let deleted = DB
+            .with(|db| {
+                db.borrow_mut().unbind_keys_with_alias_prefix(
+                    Domain::SELINUX,
+                    avf::AVF_VM_KEY_NAMESPACE,
+                    &prefix,
+                )
+            })
+            .context(ks_err!("Trying to delete VM instance keys."))?;
+        log::info!("Deleted {deleted} keys of VM instance {prefix}.");
+        Ok(())
+    }
+
+    fn set_max_key_validity(user_id: i32, max_validity_millis: i64) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::SetKeyValidityPolicy).context(ks_err!())?;
+
+        let user_id = u32::try_from(user_id)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Invalid user id {user_id}."))?;
+        let max_validity = match max_validity_millis {
+            0 => None,
+            millis if millis > 0 => Some(millis),
+            millis => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Negative key validity {millis}."));
+            }
+        };
+        DB.with(|db| db.borrow_mut().set_max_key_validity(user_id, max_validity))
+            .context(ks_err!("Failed to store the key validity policy."))?;
+        log::info!(
+            "Key validity of user {user_id} limited to {max_validity:?} ms by uid {}.",
+            ThreadState::get_calling_uid()
+        );
+        Ok(())
+    }
+
+    fn parse_attestation_extension(certificate: &[u8]) -> Result<KeyDescription> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ParseAttestation).context(ks_err!())?;
+
+        attestation_record::parse_certificate(certificate).context(ks_err!())
+    }
+
+    fn get_key_operation_stats() -> Result<Vec<KeyOperationStats>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::Dump).context(ks_err!())?;
+
+        Ok(KEY_OPERATION_STATS
+            .get_all()
+            .into_iter()
+            .map(|s| KeyOperationStats {
+                keyId: s.key_id,
+                begins: s.begins as i64,
+                beginFailures: s
+                    .begin_failures
+                    .into_iter()
+                    .map(|(error_code, count)| ErrorCount {
+                        errorCode: error_code,
+                        count: count as i64,
+                    })
+                    .collect(),
+                finishes: s.finishes as i64,
+                failures: s.failures as i64,
+                aborts: s.aborts as i64,
+                lastError: s.last_error.unwrap_or(0),
+            })
+            .collect())
+    }
+
+    fn export_database_skeleton() -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::Dump).context(ks_err!())?;
+
+        if !rustutils::system_properties::read_bool(DEBUGGABLE_PROPERTY, false)
+            .context(ks_err!("Failed to read {DEBUGGABLE_PROPERTY}."))?
+        {
+            return Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                .context(ks_err!("Only available on debuggable builds."));
+        }
+        let script = DB
+            .with(|db| db.borrow_mut().export_skeleton())
+            .context(ks_err!("Failed to export the database skeleton."))?;
+        log::info!(
+            "Exported the database skeleton ({} bytes) to uid {}.",
+            script.len(),
+            ThreadState::get_calling_uid()
+        );
+        Ok(script.into_bytes())
+    }
+
+    fn get_user_key_usage(user_id: i32) -> Result<UserKeyUsage> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::Dump).context(ks_err!())?;
+
+        let user_id = u32::try_from(user_id)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Invalid user id {user_id}."))?;
+        USER_LIMITS.usage(user_id).context(ks_err!())
+    }
+
+    fn export_key_characteristics(after_key_id: i64, max_keys: i32) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ExportKeyCharacteristics).context(ks_err!())?;
+
+        if after_key_id < 0 || max_keys <= 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Invalid page {after_key_id}, {max_keys}."));
+        }
+        let limit = (max_keys as usize).min(MAX_EXPORTED_KEYS_PER_PAGE);
+        let entries = DB
+            .with(|db| {
+                db.borrow_mut().load_key_characteristics(Domain::SELINUX, after_key_id, limit)
+            })
+            .context(ks_err!("Failed to load key characteristics."))?;
+        let next_key_id = match entries.last() {
+            Some(last) if entries.len() == limit => Some(last.key_id),
+            _ => None,
+        };
+        let page = ExportedKeyCharacteristics {
+            keys: entries.into_iter().map(ExportedKey::from).collect(),
+            next_key_id,
+        };
+        serde_cbor::to_vec(&page).context(ks_err!("Failed to encode key characteristics."))
+    }
+
+    fn migrate_software_keys(after_key_id: i64, max_keys: i32) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::MigrateSoftwareKeys).context(ks_err!())?;
+        if !feature_flags::is_enabled(Feature::SoftKeyMigration) {
+            return Err(Error::Km(ErrorCode::UNIMPLEMENTED))
+                .context(ks_err!("Software key migration is not enabled."));
+        }
+
+        if after_key_id < 0 || max_keys <= 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Invalid page {after_key_id}, {max_keys}."));
+        }
+        let limit = (max_keys as usize).min(MAX_MIGRATED_KEYS_PER_PAGE);
+        let outcomes = DB
+            .with(|db| soft_key_migration::migrate_keys(&mut db.borrow_mut(), after_key_id, limit))
+            .context(ks_err!("Failed to migrate software keys."))?;
+        let next_key_id = match outcomes.last() {
+            Some(last) if outcomes.len() == limit => Some(last.key_id),
+            _ => None,
+        };
+        let (migrated, not_preserved): (Vec<_>, Vec<_>) =
+            outcomes.into_iter().partition(|outcome| outcome.failure.is_none());
+        log::info!(
+            "Migrated {} software keys, {} not preserved, for uid {}.",
+            migrated.len(),
+            not_preserved.len(),
+            ThreadState::get_calling_uid()
+        );
+        let report = SoftwareKeyMigrationReport {
+            migrated: migrated.into_iter().map(MigratedKey::from).collect(),
+            not_preserved: not_preserved.into_iter().map(MigratedKey::from).collect(),
+            next_key_id,
+        };
+        serde_cbor::to_vec(&report).context(ks_err!("Failed to encode the migration report."))
+    }
+
+    fn get_user_state(user_id: i32) -> Result<UserStateInfo> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::Dump).context(ks_err!())?;
+
+        let user_id = u32::try_from(user_id)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Invalid user id {user_id}."))?;
+        let state = DB
+            .with(|db| {
+                SUPER_KEY.read().unwrap().get_user_state(
+                    &mut db.borrow_mut(),
+                    &LEGACY_IMPORTER,
+                    user_id,
+                )
+            })
+            .context(ks_err!("Failed to get user state."))?;
+        let counts = DB
+            .with(|db| db.borrow_mut().count_super_encrypted_keys(user_id))
+            .context(ks_err!("Failed to count super-encrypted keys."))?;
+
+        let mut info = UserStateInfo {
+            userId: user_id as i32,
+            state: match state {
+                UserState::Uninitialized => AidlUserState::UNINITIALIZED,
+                UserState::BeforeFirstUnlock => AidlUserState::LSKF_LOCKED,
+                UserState::AfterFirstUnlock(_) => AidlUserState::LSKF_UNLOCKED,
+            },
+            ..Default::default()
+        };
+        for (alias, count) in counts {
+            let tier_count = match alias.as_str() {
+                a if a == USER_AFTER_FIRST_UNLOCK_SUPER_KEY.alias => {
+                    &mut info.afterFirstUnlockKeyCount
+                }
+                a if a == USER_UNLOCKED_DEVICE_REQUIRED_SYMMETRIC_SUPER_KEY.alias
+                    || a == USER_UNLOCKED_DEVICE_REQUIRED_P521_SUPER_KEY.alias =>
+                {
+                    &mut info.unlockedDeviceRequiredKeyCount
+                }
+                a if a == USER_BIOMETRIC_BOUND_SUPER_KEY.alias => &mut info.biometricBoundKeyCount,
+                _ => {
+                    log::warn!("Keys of user {user_id} are encrypted with super key {alias}.");
+                    continue;
+                }
+            };
+            *tier_count += count;
+        }
+        Ok(info)
+    }
+
+    fn set_patch_level_policy(policy: PatchLevelPolicy) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ManagePatchLevelPolicy).context(ks_err!())?;
+
+        if !feature_flags::is_enabled(Feature::PatchLevelPolicy) {
+            return Err(Error::Km(ErrorCode::UNIMPLEMENTED))
+                .context(ks_err!("The patch level policy is not enabled."));
+        }
+        let policy = match policy {
+            PatchLevelPolicy::DEVICE_DEFAULT => None,
+            PatchLevelPolicy::REFUSE_NEWER => Some(Policy::Refuse),
+            PatchLevelPolicy::ALLOW_NEWER => Some(Policy::Allow),
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Unknown patch level policy {policy:?}."));
+            }
+        };
+        PATCH_LEVEL_POLICY.set(policy).context(ks_err!())?;
+        log::info!(
+            "Patch level policy set to {:?} by uid {}.",
+            policy,
+            ThreadState::get_calling_uid()
+        );
+        Ok(())
+    }
+
+    fn get_patch_level_policy() -> Result<PatchLevelPolicy> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ManagePatchLevelPolicy).context(ks_err!())?;
+
+        if !feature_flags::is_enabled(Feature::PatchLevelPolicy) {
+            return Err(Error::Km(ErrorCode::UNIMPLEMENTED))
+                .context(ks_err!("The patch level policy is not enabled."));
+        }
+        Ok(match PATCH_LEVEL_POLICY.get().context(ks_err!())? {
+            Policy::Refuse => PatchLevelPolicy::REFUSE_NEWER,
+            Policy::Allow => PatchLevelPolicy::ALLOW_NEWER,
+        })
+    }
+
+    fn find_duplicate_imports(domain: Domain, nspace: i64) -> Result<Vec<DuplicateImport>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::Dump).context(ks_err!())?;
+
+        if !feature_flags::is_enabled(Feature::ImportFingerprints) {
+            return Err(Error::Km(ErrorCode::UNIMPLEMENTED))
+                .context(ks_err!("Fingerprints of imported key material are not enabled."));
+        }
+        if !matches!(domain, Domain::APP | Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Domain must be APP or SELINUX."));
+        }
+        let duplicates = DB
+            .with(|db| db.borrow_mut().list_duplicate_imports(domain, nspace))
+            .context(ks_err!("Failed to list duplicate imports."))?;
+        Ok(duplicates.into_iter().map(|aliases| DuplicateImport { aliases }).collect())
+    }
+
     fn delete_all_keys() -> Result<()> {
         // Security critical permission check. This statement must return on fail.
         check_keystore_permission(KeystorePerm::DeleteAllKeys)
@@ -251,6 +1063,32 @@ impl Maintenance {
 
         Maintenance::call_on_all_security_levels("deleteAllKeys", |dev| dev.deleteAllKeys())
     }
+
+    fn on_uid_state_changed(uid: i32, proc_state: i32) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ReportUidState).context(ks_err!())?;
+
+        UID_PRIORITIES.on_uid_state_changed(uid as u32, proc_state);
+        Ok(())
+    }
+
+    fn run_garbage_collection() -> Result<GarbageCollectionStats> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::RunGc).context(ks_err!())?;
+
+        let stats = GC.run_now().context(ks_err!("Garbage collection failed."))?;
+        log::info!(
+            "Garbage collection purged {} rows, invalidated {} blobs, and repaired {} anomalies.",
+            stats.rows_purged,
+            stats.blobs_invalidated,
+            stats.anomalies_repaired
+        );
+        Ok(GarbageCollectionStats {
+            rowsPurged: stats.rows_purged as i64,
+            blobsInvalidated: stats.blobs_invalidated as i64,
+            anomaliesRepaired: stats.anomalies_repaired as i64,
+        })
+    }
 }
 
 impl Interface for Maintenance {}
@@ -275,7 +1113,7 @@ impl IKeystoreMaintenance for Maintenance {
     fn onUserRemoved(&self, user_id: i32) -> BinderResult<()> {
         log::info!("onUserRemoved(user={user_id})");
         let _wp = wd::watch_millis("IKeystoreMaintenance::onUserRemoved", 500);
-        map_or_log_err(self.add_or_remove_user(user_id), Ok)
+        map_or_log_err(self.remove_user(user_id), Ok)
     }
 
     fn clearNamespace(&self, domain: Domain, nspace: i64) -> BinderResult<()> {
@@ -311,4 +1149,237 @@ impl IKeystoreMaintenance for Maintenance {
         let _wp = wd::watch_millis("IKeystoreMaintenance::deleteAllKeys", 500);
         map_or_log_err(Self::delete_all_keys(), Ok)
     }
+
+    fn onUidStateChanged(&self, uid: i32, proc_state: i32) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::onUidStateChanged", 500);
+        map_or_log_err(Self::on_uid_state_changed(uid, proc_state), Ok)
+    }
+
+    fn runGarbageCollection(&self) -> BinderResult<GarbageCollectionStats> {
+        log::info!("runGarbageCollection()");
+        // The cycle deletes every superseded blob before returning, so its duration grows with
+        // their number. Each deleteKey call into KeyMint has its own watch point.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::runGarbageCollection", 30000);
+        map_or_log_err(Self::run_garbage_collection(), Ok)
+    }
+
+    fn adoptAppKey(&self, source: &KeyDescriptor, destination: &KeyDescriptor) -> BinderResult<()> {
+        log::info!("adoptAppKey(src={source:?}, dest={destination:?})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::adoptAppKey", 500);
+        map_or_log_err(Self::adopt_app_key(source, destination), Ok)
+    }
+
+    fn exportKeyForBackup(
+        &self,
+        key: &KeyDescriptor,
+        backup_public_key: &[u8],
+    ) -> BinderResult<BackupKeyMaterial> {
+        log::info!("exportKeyForBackup(key={key:?})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::exportKeyForBackup", 500);
+        map_or_log_err(Self::export_key_for_backup(key, backup_public_key), Ok)
+    }
+
+    fn provisionSuperKeyEscrow(
+        &self,
+        user_id: i32,
+        escrow_public_key: &[u8],
+        password: &[u8],
+    ) -> BinderResult<()> {
+        log::info!("provisionSuperKeyEscrow(user={user_id})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::provisionSuperKeyEscrow", 500);
+        map_or_log_err(
+            Self::provision_super_key_escrow(user_id, escrow_public_key, password.into()),
+            Ok,
+        )
+    }
+
+    fn clearSuperKeyEscrow(&self, user_id: i32) -> BinderResult<()> {
+        log::info!("clearSuperKeyEscrow(user={user_id})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::clearSuperKeyEscrow", 500);
+        map_or_log_err(Self::clear_super_key_escrow(user_id), Ok)
+    }
+
+    fn recoverUserFromEscrow(
+        &self,
+        user_id: i32,
+        escrow_private_key: &[u8],
+        new_password: &[u8],
+    ) -> BinderResult<()> {
+        log::info!("recoverUserFromEscrow(user={user_id})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::recoverUserFromEscrow", 500);
+        map_or_log_err(
+            Self::recover_user_from_escrow(user_id, escrow_private_key, new_password.into()),
+            Ok,
+        )
+    }
+
+    fn armRebootEscrow(&self, user_id: i32, escrow_secret: &[u8]) -> BinderResult<()> {
+        log::info!("armRebootEscrow(user={user_id})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::armRebootEscrow", 500);
+        map_or_log_err(Self::arm_reboot_escrow(user_id, escrow_secret), Ok)
+    }
+
+    fn clearRebootEscrow(&self, user_id: i32) -> BinderResult<()> {
+        log::info!("clearRebootEscrow(user={user_id})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::clearRebootEscrow", 500);
+        map_or_log_err(Self::clear_reboot_escrow(user_id), Ok)
+    }
+
+    fn getKeyHistory(&self, domain: Domain, nspace: i64) -> BinderResult<Vec<KeyHistoryEntry>> {
+        log::info!("getKeyHistory(domain={domain:?}, nspace={nspace})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyHistory", 500);
+        map_or_log_err(Self::get_key_history(domain, nspace), Ok)
+    }
+
+    fn listKeysRequiringMaintenance(&self) -> BinderResult<Vec<KeyMaintenanceEntry>> {
+        log::info!("listKeysRequiringMaintenance()");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::listKeysRequiringMaintenance", 500);
+        map_or_log_err(Self::list_keys_requiring_maintenance(), Ok)
+    }
+
+    fn registerStorageKey(
+        &self,
+        user_id: i32,
+        key_class: AidlStorageKeyClass,
+        key_blob: &[u8],
+    ) -> BinderResult<()> {
+        log::info!("registerStorageKey(user={user_id}, class={key_class:?})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::registerStorageKey", 500);
+        map_or_log_err(Self::register_storage_key(user_id, key_class, key_blob), Ok)
+    }
+
+    fn getStorageKey(
+        &self,
+        user_id: i32,
+        key_class: AidlStorageKeyClass,
+    ) -> BinderResult<StorageKeyBlob> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getStorageKey", 500);
+        map_or_log_err(Self::get_storage_key(user_id, key_class), Ok)
+    }
+
+    fn rotateStorageKey(
+        &self,
+        user_id: i32,
+        key_class: AidlStorageKeyClass,
+        new_key_blob: &[u8],
+    ) -> BinderResult<StorageKeyBlob> {
+        log::info!("rotateStorageKey(user={user_id}, class={key_class:?})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::rotateStorageKey", 500);
+        map_or_log_err(Self::rotate_storage_key(user_id, key_class, new_key_blob), Ok)
+    }
+
+    fn auditBlobChecksums(&self) -> BinderResult<Vec<KeyMaintenanceEntry>> {
+        log::info!("auditBlobChecksums()");
+        // The audit reads every blob in the database, so its duration grows with the number of
+        // keys. Each batch of blobs has its own watch point.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::auditBlobChecksums", 30000);
+        map_or_log_err(Self::audit_blob_checksums(), Ok)
+    }
+
+    fn registerMaintenanceListener(
+        &self,
+        listener: &Strong<dyn IMaintenanceListener>,
+    ) -> BinderResult<()> {
+        log::info!("registerMaintenanceListener()");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::registerMaintenanceListener", 500);
+        map_or_log_err(Self::register_maintenance_listener(listener), Ok)
+    }
+
+    fn unregisterMaintenanceListener(
+        &self,
+        listener: &Strong<dyn IMaintenanceListener>,
+    ) -> BinderResult<()> {
+        log::info!("unregisterMaintenanceListener()");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::unregisterMaintenanceListener", 500);
+        map_or_log_err(Self::unregister_maintenance_listener(listener), Ok)
+    }
+
+    fn waitForQuiescence(&self, timeout_millis: i64) -> BinderResult<bool> {
+        log::info!("waitForQuiescence(timeout_millis={timeout_millis})");
+        // No watchdog, because this call blocks by design.
+        map_or_log_err(Self::wait_for_quiescence(timeout_millis), Ok)
+    }
+
+    fn getAttestationApplicationId(&self, uid: i32) -> BinderResult<Vec<u8>> {
+        log::info!("getAttestationApplicationId(uid={uid})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getAttestationApplicationId", 500);
+        map_or_log_err(Self::get_attestation_application_id(uid), Ok)
+    }
+
+    fn deleteVmInstanceKeys(&self, instance_id: &[u8]) -> BinderResult<()> {
+        log::info!("deleteVmInstanceKeys()");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::deleteVmInstanceKeys", 500);
+        map_or_log_err(Self::delete_vm_instance_keys(instance_id), Ok)
+    }
+
+    fn setMaxKeyValidity(&self, user_id: i32, max_validity_millis: i64) -> BinderResult<()> {
+        log::info!("setMaxKeyValidity(user={user_id}, millis={max_validity_millis})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::setMaxKeyValidity", 500);
+        map_or_log_err(Self::set_max_key_validity(user_id, max_validity_millis), Ok)
+    }
+
+    fn parseAttestationExtension(&self, certificate: &[u8]) -> BinderResult<KeyDescription> {
+        log::info!("parseAttestationExtension()");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::parseAttestationExtension", 500);
+        map_or_log_err(Self::parse_attestation_extension(certificate), Ok)
+    }
+
+    fn getKeyOperationStats(&self) -> BinderResult<Vec<KeyOperationStats>> {
+        log::info!("getKeyOperationStats()");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyOperationStats", 500);
+        map_or_log_err(Self::get_key_operation_stats(), Ok)
+    }
+
+    fn exportDatabaseSkeleton(&self) -> BinderResult<Vec<u8>> {
+        log::info!("exportDatabaseSkeleton()");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::exportDatabaseSkeleton", 500);
+        map_or_log_err(Self::export_database_skeleton(), Ok)
+    }
+
+    fn getUserKeyUsage(&self, user_id: i32) -> BinderResult<UserKeyUsage> {
+        log::info!("getUserKeyUsage(user={user_id})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getUserKeyUsage", 500);
+        map_or_log_err(Self::get_user_key_usage(user_id), Ok)
+    }
+
+    fn exportKeyCharacteristics(&self, after_key_id: i64, max_keys: i32) -> BinderResult<Vec<u8>> {
+        log::info!("exportKeyCharacteristics(after={after_key_id}, max={max_keys})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::exportKeyCharacteristics", 500);
+        map_or_log_err(Self::export_key_characteristics(after_key_id, max_keys), Ok)
+    }
+
+    fn migrateSoftwareKeys(&self, after_key_id: i64, max_keys: i32) -> BinderResult<Vec<u8>> {
+        log::info!("migrateSoftwareKeys(after={after_key_id}, max={max_keys})");
+        // Each key takes a call into KeyMint, so the watchdog allows for a full page.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::migrateSoftwareKeys", 5000);
+        map_or_log_err(Self::migrate_software_keys(after_key_id, max_keys), Ok)
+    }
+
+    fn getUserState(&self, user_id: i32) -> BinderResult<UserStateInfo> {
+        log::info!("getUserState(user={user_id})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getUserState", 500);
+        map_or_log_err(Self::get_user_state(user_id), Ok)
+    }
+
+    fn setPatchLevelPolicy(&self, policy: PatchLevelPolicy) -> BinderResult<()> {
+        log::info!("setPatchLevelPolicy(policy={policy:?})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::setPatchLevelPolicy", 500);
+        map_or_log_err(Self::set_patch_level_policy(policy), Ok)
+    }
+
+    fn getPatchLevelPolicy(&self) -> BinderResult<PatchLevelPolicy> {
+        log::info!("getPatchLevelPolicy()");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getPatchLevelPolicy", 500);
+        map_or_log_err(Self::get_patch_level_policy(), Ok)
+    }
+
+    fn findDuplicateImports(
+        &self,
+        domain: Domain,
+        nspace: i64,
+    ) -> BinderResult<Vec<DuplicateImport>> {
+        log::info!("findDuplicateImports(domain={domain:?}, nspace={nspace})");
+        let _wp = wd::watch_millis("IKeystoreMaintenance::findDuplicateImports", 500);
+        map_or_log_err(Self::find_duplicate_imports(domain, nspace), Ok)
+    }
 }
@@ -0,0 +1,210 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module lets keystore pick the nonces of AES-GCM encryptions, so that no two encryptions
+//! with the same key use the same nonce. KeyMint picks random nonces, which may repeat after
+//! many encryptions, and callers that pick their own nonces are known to reuse them.
+//!
+//! A new AES-GCM encryption key that the caller created without `CALLER_NONCE` gets managed
+//! nonces. Keystore asks KeyMint for `CALLER_NONCE` on the caller's behalf, and stores a random
+//! prefix and an invocation counter in the metadata of the key. Each encryption uses the prefix
+//! followed by the next value of the counter as nonce. Keystore reserves counter values in
+//! batches and persists the end of each batch before it uses any value of it, so that values
+//! are not used again after a restart. `createOperation` returns the nonce in the operation
+//! parameters, as KeyMint does for nonces it picks. Callers still cannot pass a nonce of their
+//! own. Keys in `Domain::BLOB` have no metadata and keep the nonces of KeyMint.
+
+use crate::database::{KeyMetaData, KeyMetaEntry, KeystoreDB};
+use crate::error::{Error, ErrorCode};
+use crate::feature_flags::{self, Feature};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::generate_random_data;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The length of the random prefix of the nonces of a key. The counter fills the remaining
+/// bytes of the 96 bit GCM nonce.
+const PREFIX_LENGTH: usize = 4;
+
+/// The number of counter values that are reserved in the database at once.
+const RESERVATION_SIZE: i64 = 256;
+
+/// Returns true if `params` describe an AES-GCM encryption key for which keystore should pick
+/// the nonces.
+fn wants_managed_nonces(params: &[KeyParameter]) -> bool {
+    let has = |value: KeyParameterValue| params.iter().any(|p| p.value == value);
+    has(KeyParameterValue::Algorithm(Algorithm::AES))
+        && has(KeyParameterValue::BlockMode(BlockMode::GCM))
+        && has(KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT))
+        && !params.iter().any(|p| p.tag == Tag::CALLER_NONCE)
+}
+
+/// Prepares the parameters of the new key `key` for managed nonces, if it should have them.
+/// Returns the parameters to pass to KeyMint, along with the nonce prefix of the key, if any.
+pub fn prepare_new_key(
+    key: &KeyDescriptor,
+    mut params: Vec<KeyParameter>,
+) -> Result<(Vec<KeyParameter>, Option<Vec<u8>>)> {
+    if key.domain == Domain::BLOB
+        || !wants_managed_nonces(&params)
+        || !feature_flags::is_enabled(Feature::ManagedNonces)
+    {
+        return Ok((params, None));
+    }
+    let prefix =
+        generate_random_data(PREFIX_LENGTH).context(ks_err!("Failed to generate nonce prefix."))?;
+    params.push(KeyParameter { tag: Tag::CALLER_NONCE, value: KeyParameterValue::BoolValue(true) });
+    Ok((params, Some(prefix)))
+}
+
+/// Records the nonce prefix of a new key in its metadata, and starts its counter.
+pub fn add_to_metadata(prefix: Vec<u8>, metadata: &mut KeyMetaData) {
+    metadata.add(KeyMetaEntry::ManagedNoncePrefix(prefix));
+    metadata.add(KeyMetaEntry::ManagedNonceCounter(0));
+}
+
+/// Returns the nonce prefix that is stored in the metadata of a key with managed nonces.
+pub fn from_metadata(metadata: &KeyMetaData) -> Option<Vec<u8>> {
+    metadata.managed_nonce_prefix().cloned()
+}
+
+/// Rejects operation parameters that contain a nonce. The caller did not ask for
+/// `CALLER_NONCE`, so this is what KeyMint would do for a key without managed nonces.
+pub fn check_operation_params(params: &[KeyParameter]) -> Result<()> {
+    if params.iter().any(|p| p.tag == Tag::NONCE) {
+        return Err(Error::Km(ErrorCode::CALLER_NONCE_PROHIBITED))
+            .context(ks_err!("Keystore picks the nonces of this key."));
+    }
+    Ok(())
+}
+
+/// Returns the operation parameter that passes `nonce` to KeyMint or to the client.
+pub fn nonce_param(nonce: Vec<u8>) -> KeyParameter {
+    KeyParameter { tag: Tag::NONCE, value: KeyParameterValue::Blob(nonce) }
+}
+
+/// The counter values that were reserved in the database for a key and not used yet.
+#[derive(Debug)]
+struct Reservation {
+    next: i64,
+    end: i64,
+}
+
+/// Hands out the nonces of keys with managed nonces.
+#[derive(Debug, Default)]
+pub struct ManagedNonces {
+    reservations: Mutex<HashMap<i64, Reservation>>,
+}
+
+impl ManagedNonces {
+    /// Returns the next nonce of the key with id `key_id` and nonce prefix `prefix`.
+    pub fn next_nonce(&self, db: &mut KeystoreDB, key_id: i64, prefix: &[u8]) -> Result<Vec<u8>> {
+        self.next_nonce_with(key_id, prefix, |count| {
+            db.reserve_nonce_counters(key_id, count).context(ks_err!())
+        })
+    }
+
+    fn next_nonce_with<F>(&self, key_id: i64, prefix: &[u8], reserve: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce(i64) -> Result<i64>,
+    {
+        let mut reservations = self.reservations.lock().unwrap();
+        let counter = match reservations.get_mut(&key_id) {
+            Some(reservation) if reservation.next < reservation.end => {
+                reservation.next += 1;
+                reservation.next - 1
+            }
+            _ => {
+                // The reservation must be persisted before any of its values is used.
+                let first = reserve(RESERVATION_SIZE)?;
+                reservations
+                    .insert(key_id, Reservation { next: first + 1, end: first + RESERVATION_SIZE });
+                first
+            }
+        };
+        let mut nonce = prefix.to_vec();
+        nonce.extend_from_slice(&counter.to_be_bytes());
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kp(tag: Tag, value: KeyParameterValue) -> KeyParameter {
+        KeyParameter { tag, value }
+    }
+
+    #[test]
+    fn only_gcm_encryption_keys_without_caller_nonce() {
+        let gcm = vec![
+            kp(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::AES)),
+            kp(Tag::BLOCK_MODE, KeyParameterValue::BlockMode(BlockMode::GCM)),
+            kp(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT)),
+        ];
+        assert!(wants_managed_nonces(&gcm));
+
+        let mut caller_nonce = gcm.clone();
+        caller_nonce.push(kp(Tag::CALLER_NONCE, KeyParameterValue::BoolValue(true)));
+        assert!(!wants_managed_nonces(&caller_nonce));
+
+        let mut cbc = gcm.clone();
+        cbc[1] = kp(Tag::BLOCK_MODE, KeyParameterValue::BlockMode(BlockMode::CBC));
+        assert!(!wants_managed_nonces(&cbc));
+
+        let mut decrypt_only = gcm;
+        decrypt_only[2] = kp(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT));
+        assert!(!wants_managed_nonces(&decrypt_only));
+    }
+
+    #[test]
+    fn nonces_do_not_repeat_across_restarts() -> Result<()> {
+        let prefix = [7u8; PREFIX_LENGTH];
+        let mut persisted = 0;
+        let mut reserve = |count: i64| -> Result<i64> {
+            let first = persisted;
+            persisted += count;
+            Ok(first)
+        };
+
+        let nonces = ManagedNonces::default();
+        let first = nonces.next_nonce_with(1, &prefix, &mut reserve)?;
+        assert_eq!(12, first.len());
+        assert_eq!(prefix[..], first[..PREFIX_LENGTH]);
+        let second = nonces.next_nonce_with(1, &prefix, |_| panic!("reserved again"))?;
+        assert_ne!(first, second);
+
+        // After a restart, the rest of the reservation is skipped.
+        let restarted = ManagedNonces::default();
+        let third = restarted.next_nonce_with(1, &prefix, &mut reserve)?;
+        assert_eq!(RESERVATION_SIZE.to_be_bytes()[..], third[PREFIX_LENGTH..]);
+        assert_eq!(2 * RESERVATION_SIZE, persisted);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_caller_nonce() {
+        assert!(check_operation_params(&[nonce_param(vec![0; 12])]).is_err());
+        assert!(check_operation_params(&[]).is_ok());
+    }
+}
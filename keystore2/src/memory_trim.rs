@@ -0,0 +1,324 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module releases memory held by keystore2's caches when the system is under memory
+//! pressure. Caches register a trim function with `register_trimmer`. A monitor thread
+//! subscribes to the kernel's memory pressure stall information (PSI) and trims all caches
+//! when tasks stall on memory for too long. Installing PSI triggers requires write access to
+//! the PSI file, which keystore2 may not have. In that case the monitor falls back to reading
+//! the stall averages every `PSI_POLL_INTERVAL`. Under critical pressure, the allocator is
+//! purged after the trim, so that the freed pages are returned to the system.
+//!
+//! `trim` can be called directly to force a trim, e.g., from tests.
+
+use crate::database::KeystoreDB;
+use crate::error::Error;
+use crate::feature_flags::{self, Feature};
+use crate::globals::SUPER_KEY;
+use crate::grant_cache::GRANT_CACHE;
+use crate::ks_err;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// How severe the memory pressure is. Higher levels drop more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrimLevel {
+    /// Some tasks are stalled on memory. Drop caches that are cheap to rebuild.
+    Moderate,
+    /// All non-idle tasks are stalled on memory. Drop everything that is not essential and
+    /// return free memory to the system.
+    Critical,
+}
+
+/// A registered cache. The trim function returns the number of evicted entries.
+struct Trimmer {
+    name: &'static str,
+    trim: Box<dyn Fn(TrimLevel) -> u64 + Send + Sync>,
+    evictions: AtomicU64,
+}
+
+lazy_static! {
+    static ref TRIMMERS: RwLock<Vec<Trimmer>> = Default::default();
+}
+
+/// Number of trims performed since keystore2 started.
+static TRIM_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// PSI file for memory pressure.
+const PSI_MEMORY_PATH: &str = "/proc/pressure/memory";
+/// Moderate pressure: some tasks were stalled on memory for 150ms within one second.
+const PSI_MODERATE_TRIGGER: &[u8] = b"some 150000 1000000\0";
+/// Critical pressure: all non-idle tasks were stalled on memory for 100ms within one second.
+const PSI_CRITICAL_TRIGGER: &[u8] = b"full 100000 1000000\0";
+/// Trims at the same or a lower level are skipped within this interval of the last trim.
+const MIN_TRIM_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval at which the stall averages are read if PSI triggers cannot be installed.
+const PSI_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Moderate pressure when polling: some tasks were stalled on memory for 15% of the last ten
+/// seconds, which matches `PSI_MODERATE_TRIGGER`.
+const PSI_MODERATE_AVG10: f64 = 15.0;
+/// Critical pressure when polling: all non-idle tasks were stalled on memory for 10% of the
+/// last ten seconds, which matches `PSI_CRITICAL_TRIGGER`.
+const PSI_CRITICAL_AVG10: f64 = 10.0;
+
+/// Bionic's `mallopt` parameter that releases all free memory held by the allocator. See
+/// bionic/libc/include/malloc.h.
+const M_PURGE: libc::c_int = -101;
+
+extern "C" {
+    fn mallopt(param: libc::c_int, value: libc::c_int) -> libc::c_int;
+}
+
+/// Registers a cache under `name`. `trim` is called with the trim level on every trim and
+/// returns the number of evicted entries. The name identifies the cache in eviction counts.
+pub fn register_trimmer<F>(name: &'static str, trim: F)
+where
+    F: Fn(TrimLevel) -> u64 + Send + Sync + 'static,
+{
+    TRIMMERS.write().unwrap().push(Trimmer {
+        name,
+        trim: Box::new(trim),
+        evictions: AtomicU64::new(0),
+    });
+}
+
+/// Adds `count` to the evictions of the cache registered under `name`. This is for caches
+/// that are trimmed lazily, i.e., after their trim function has returned.
+pub fn record_evictions(name: &str, count: u64) {
+    if let Some(trimmer) = TRIMMERS.read().unwrap().iter().find(|t| t.name == name) {
+        trimmer.evictions.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+/// Registers keystore2's own caches with the trimmer.
+pub fn register_keystore_caches() {
+    KeystoreDB::register_trimmer();
+    register_trimmer("super_key_index", |_| SUPER_KEY.write().unwrap().prune_key_index());
+    register_trimmer("super_key_user_keys", |level| {
+        let evict_background =
+            level == TrimLevel::Critical && feature_flags::is_enabled(Feature::SuperKeyEviction);
+        SUPER_KEY.write().unwrap().trim_user_keys(evict_background)
+    });
+    register_trimmer("grant_cache", |level| GRANT_CACHE.trim(level));
+    register_trimmer("key_material_cache", |level| {
+        SUPER_KEY.read().unwrap().key_material_cache().trim(level)
+    });
+}
+
+/// Trims all registered caches at the given level. Returns the number of entries evicted by
+/// this trim.
+pub fn trim(level: TrimLevel) -> u64 {
+    TRIM_COUNT.fetch_add(1, Ordering::Relaxed);
+    let evicted = TRIMMERS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|trimmer| {
+            let evicted = (trimmer.trim)(level);
+            trimmer.evictions.fetch_add(evicted, Ordering::Relaxed);
+            evicted
+        })
+        .sum();
+    if level == TrimLevel::Critical {
+        // SAFETY: M_PURGE takes no pointer arguments and only releases memory that is free.
+        unsafe { mallopt(M_PURGE, 0) };
+    }
+    log::info!("Trimmed caches at {:?} level, {} entries evicted.", level, evicted);
+    evicted
+}
+
+/// Returns the number of trims since keystore2 started.
+pub fn trim_count() -> u64 {
+    TRIM_COUNT.load(Ordering::Relaxed)
+}
+
+/// Returns the total number of evictions of each registered cache.
+pub fn eviction_counts() -> Vec<(&'static str, u64)> {
+    TRIMMERS.read().unwrap().iter().map(|t| (t.name, t.evictions.load(Ordering::Relaxed))).collect()
+}
+
+/// Starts a thread that trims caches when the kernel reports memory pressure. If PSI is not
+/// available at all, this is logged, and caches are only trimmed on explicit calls to `trim`.
+pub fn start_monitor() {
+    let result = std::thread::Builder::new().name("keystore2_trim".to_string()).spawn(|| {
+        if let Err(e) = monitor_memory_pressure() {
+            log::error!("Memory pressure monitor stopped: {:?}", e);
+        }
+    });
+    if let Err(e) = result {
+        log::error!("Failed to start memory pressure monitor: {:?}", e);
+    }
+}
+
+/// Opens a PSI file descriptor that signals POLLPRI when `trigger` fires.
+fn open_psi_trigger(trigger: &[u8]) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(PSI_MEMORY_PATH)
+        .context(ks_err!("Failed to open {}.", PSI_MEMORY_PATH))?;
+    file.write_all(trigger).context(ks_err!("Failed to install PSI trigger."))?;
+    Ok(file)
+}
+
+fn open_psi_triggers() -> Result<[(TrimLevel, File); 2]> {
+    Ok([
+        (TrimLevel::Moderate, open_psi_trigger(PSI_MODERATE_TRIGGER)?),
+        (TrimLevel::Critical, open_psi_trigger(PSI_CRITICAL_TRIGGER)?),
+    ])
+}
+
+/// Skips trims at the same or a lower level within `MIN_TRIM_INTERVAL` of the last trim.
+#[derive(Default)]
+struct TrimThrottle {
+    last_trim: Option<(TrimLevel, Instant)>,
+}
+
+impl TrimThrottle {
+    fn on_pressure(&mut self, level: TrimLevel) {
+        let skip = self.last_trim.map_or(false, |(last_level, at)| {
+            level <= last_level && at.elapsed() < MIN_TRIM_INTERVAL
+        });
+        if !skip {
+            trim(level);
+            self.last_trim = Some((level, Instant::now()));
+        }
+    }
+}
+
+fn monitor_memory_pressure() -> Result<()> {
+    let triggers = match open_psi_triggers() {
+        Ok(triggers) => triggers,
+        Err(e) => {
+            log::warn!("PSI triggers are not available, polling instead: {:?}", e);
+            return poll_memory_pressure();
+        }
+    };
+    let mut fds: Vec<libc::pollfd> = triggers
+        .iter()
+        .map(|(_, file)| libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLPRI, revents: 0 })
+        .collect();
+    let mut throttle = TrimThrottle::default();
+    loop {
+        // SAFETY: `fds` is a valid array of `fds.len()` pollfd structures, and the files it
+        // refers to are kept open by `triggers`.
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ready < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e).context(ks_err!("Polling PSI triggers failed."));
+        }
+        if fds.iter().any(|fd| fd.revents & libc::POLLERR != 0) {
+            return Err(Error::sys()).context(ks_err!("PSI trigger became invalid."));
+        }
+        let level = triggers
+            .iter()
+            .zip(fds.iter())
+            .filter(|(_, fd)| fd.revents & libc::POLLPRI != 0)
+            .map(|((level, _), _)| *level)
+            .max();
+        if let Some(level) = level {
+            throttle.on_pressure(level);
+        }
+    }
+}
+
+fn poll_memory_pressure() -> Result<()> {
+    let mut throttle = TrimThrottle::default();
+    loop {
+        let stats = fs::read_to_string(PSI_MEMORY_PATH)
+            .context(ks_err!("Failed to read {}.", PSI_MEMORY_PATH))?;
+        if let Some(level) = pressure_level(&stats) {
+            throttle.on_pressure(level);
+        }
+        std::thread::sleep(PSI_POLL_INTERVAL);
+    }
+}
+
+/// Returns the trim level that the stall averages in `stats`, the content of the PSI file,
+/// call for, if any.
+fn pressure_level(stats: &str) -> Option<TrimLevel> {
+    let avg10 = |kind: &str| {
+        stats
+            .lines()
+            .find_map(|line| line.strip_prefix(kind)?.trim_start().strip_prefix("avg10="))
+            .and_then(|rest| rest.split_whitespace().next()?.parse::<f64>().ok())
+            .unwrap_or(0.0)
+    };
+    if avg10("full") >= PSI_CRITICAL_AVG10 {
+        Some(TrimLevel::Critical)
+    } else if avg10("some") >= PSI_MODERATE_AVG10 {
+        Some(TrimLevel::Moderate)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn evictions_of(name: &str) -> u64 {
+        eviction_counts().into_iter().find(|(n, _)| *n == name).map_or(0, |(_, count)| count)
+    }
+
+    #[test]
+    fn forced_trim_counts_evictions() {
+        let levels: Arc<std::sync::Mutex<Vec<TrimLevel>>> = Default::default();
+        let seen = levels.clone();
+        register_trimmer("memory_trim_test", move |level| {
+            seen.lock().unwrap().push(level);
+            match level {
+                TrimLevel::Moderate => 1,
+                TrimLevel::Critical => 3,
+            }
+        });
+        let trims = trim_count();
+
+        assert!(trim(TrimLevel::Moderate) >= 1);
+        assert!(trim(TrimLevel::Critical) >= 3);
+        assert!(trim_count() >= trims + 2);
+        assert_eq!(vec![TrimLevel::Moderate, TrimLevel::Critical], *levels.lock().unwrap());
+        assert_eq!(4, evictions_of("memory_trim_test"));
+
+        record_evictions("memory_trim_test", 2);
+        assert_eq!(6, evictions_of("memory_trim_test"));
+    }
+
+    #[test]
+    fn pressure_level_from_stall_averages() {
+        let stats = |some: f64, full: f64| {
+            format!(
+                "some avg10={:.2} avg60=0.00 avg300=0.00 total=0\n\
+                 full avg10={:.2} avg60=0.00 avg300=0.00 total=0\n",
+                some, full
+            )
+        };
+        assert_eq!(None, pressure_level(&stats(14.99, 9.99)));
+        assert_eq!(Some(TrimLevel::Moderate), pressure_level(&stats(15.0, 9.99)));
+        assert_eq!(Some(TrimLevel::Critical), pressure_level(&stats(15.0, 10.0)));
+        // Kernels without the "full" line only report moderate pressure.
+        assert_eq!(Some(TrimLevel::Moderate), pressure_level("some avg10=50.00 total=0\n"));
+        assert_eq!(None, pressure_level(""));
+    }
+}
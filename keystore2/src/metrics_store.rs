@@ -17,7 +17,8 @@
 //!    stores them in an in-memory store.
 //! 2. Returns the collected metrics when requested by the statsd proxy.
 
-use crate::error::anyhow_error_to_serialized_error;
+use crate::database::ConsistencyReport;
+use crate::error::{anyhow_error_to_serialized_error, DatabaseErrorKind};
 use crate::globals::DB;
 use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
 use crate::ks_err;
@@ -29,8 +30,9 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
     SecurityLevel::SecurityLevel,
 };
 use android_security_metrics::aidl::android::security::metrics::{
-    Algorithm::Algorithm as MetricsAlgorithm, AtomID::AtomID, CrashStats::CrashStats,
-    EcCurve::EcCurve as MetricsEcCurve,
+    Algorithm::Algorithm as MetricsAlgorithm, AtomID::AtomID,
+    ConsistencyAnomaly::ConsistencyAnomaly, CrashStats::CrashStats,
+    DatabaseConsistencyStats::DatabaseConsistencyStats, EcCurve::EcCurve as MetricsEcCurve,
     HardwareAuthenticatorType::HardwareAuthenticatorType as MetricsHardwareAuthenticatorType,
     KeyCreationWithAuthInfo::KeyCreationWithAuthInfo,
     KeyCreationWithGeneralInfo::KeyCreationWithGeneralInfo,
@@ -42,11 +44,13 @@ use android_security_metrics::aidl::android::security::metrics::{
     Outcome::Outcome as MetricsOutcome, Purpose::Purpose as MetricsPurpose,
     RkpError::RkpError as MetricsRkpError, RkpErrorStats::RkpErrorStats,
     SecurityLevel::SecurityLevel as MetricsSecurityLevel, Storage::Storage as MetricsStorage,
+    UnlockOutcome::UnlockOutcome, UserUnlockStats::UserUnlockStats,
 };
 use anyhow::{anyhow, Context, Result};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::sync::Mutex;
+use std::time::Duration;
 
 // Note: Crash events are recorded at keystore restarts, based on the assumption that keystore only
 // gets restarted after a crash, during a boot cycle.
@@ -561,6 +565,61 @@ pub fn log_rkp_error_stats(rkp_error: MetricsRkpError, sec_level: &SecurityLevel
     METRICS_STORE.insert_atom(AtomID::RKP_ERROR_STATS, rkp_error_stats);
 }
 
+/// Log the outcome and the latency of unlocking the super keys of a user with the password.
+/// `kdf` and `db_load` are the time spent deriving keys from the password and loading super keys
+/// from the database.
+pub fn log_user_unlock_stats(result: &Result<()>, kdf: Duration, db_load: Duration) {
+    let user_unlock_stats = KeystoreAtomPayload::UserUnlockStats(UserUnlockStats {
+        outcome: process_unlock_outcome(result),
+        log2_kdf_millis: log2_millis(kdf),
+        log2_db_load_millis: log2_millis(db_load),
+    });
+    METRICS_STORE.insert_atom(AtomID::USER_UNLOCK_STATS, user_unlock_stats);
+}
+
+fn process_unlock_outcome(result: &Result<()>) -> UnlockOutcome {
+    match result {
+        Ok(()) => UnlockOutcome::SUCCESS,
+        Err(e) => {
+            if let Some(keystore2_crypto::Error::DecryptionFailed) =
+                e.root_cause().downcast_ref::<keystore2_crypto::Error>()
+            {
+                UnlockOutcome::DECRYPTION_FAILED
+            } else if DatabaseErrorKind::from_anyhow(e).is_some() {
+                UnlockOutcome::DATABASE_ERROR
+            } else {
+                UnlockOutcome::ERROR
+            }
+        }
+    }
+}
+
+/// Log the rows that a consistency check of the database deleted, one atom for each kind of
+/// anomaly that was found.
+pub fn log_database_consistency_stats(report: &ConsistencyReport) {
+    for (anomaly, count) in [
+        (ConsistencyAnomaly::DANGLING_GRANT, report.dangling_grants),
+        (ConsistencyAnomaly::ORPHANED_KEY_METADATA, report.orphaned_key_metadata),
+        (ConsistencyAnomaly::ORPHANED_KEY_PARAMETER, report.orphaned_key_parameters),
+    ] {
+        if count != 0 {
+            let stats = KeystoreAtomPayload::DatabaseConsistencyStats(DatabaseConsistencyStats {
+                anomaly,
+                log2_count: count.ilog2() as i32,
+            });
+            METRICS_STORE.insert_atom(AtomID::DATABASE_CONSISTENCY_STATS, stats);
+        }
+    }
+}
+
+// Durations are bucketed by powers of two to keep the cardinality of the atoms low.
+fn log2_millis(duration: Duration) -> i32 {
+    match duration.as_millis() {
+        0 => -1,
+        millis => millis.ilog2() as i32,
+    }
+}
+
 /// This function tries to read and update the system property: keystore.crash_count.
 /// If the property is absent, it sets the property with value 0. If the property is present, it
 /// increments the value. This helps tracking keystore crashes internally.
@@ -0,0 +1,136 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module provides a typed form of the `domain` and `nspace` fields of a `KeyDescriptor`.
+//! The meaning of `nspace` depends on the domain, e.g., it is an app uid for `Domain::APP` but
+//! a key id for `Domain::KEY_ID`. Converting to `Namespace` at the binder boundary makes the
+//! interpretation explicit, so that a grant id cannot accidentally be compared with a uid.
+
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+
+/// The namespace of a key together with the domain that determines its meaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    /// Domain::APP. The uid of the app that owns the key.
+    App(u32),
+    /// Domain::SELINUX. The keystore2_key SELinux namespace.
+    SeLinux(i64),
+    /// Domain::GRANT. The grant id under which the key was granted to the caller.
+    Grant(i64),
+    /// Domain::KEY_ID. The id of the key entry in the database.
+    KeyId(i64),
+    /// Domain::BLOB. The SELinux namespace against which use of the blob is checked.
+    Blob(i64),
+}
+
+impl Namespace {
+    /// Interprets `nspace` according to `domain`. Returns `INVALID_ARGUMENT` for unknown domains
+    /// and for app namespaces that are not a valid uid.
+    pub fn new(domain: Domain, nspace: i64) -> Result<Self> {
+        match domain {
+            Domain::APP => u32::try_from(nspace)
+                .map(Self::App)
+                .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Invalid app namespace: {nspace}.")),
+            Domain::SELINUX => Ok(Self::SeLinux(nspace)),
+            Domain::GRANT => Ok(Self::Grant(nspace)),
+            Domain::KEY_ID => Ok(Self::KeyId(nspace)),
+            Domain::BLOB => Ok(Self::Blob(nspace)),
+            _ => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Unknown domain value: \"{:?}\".", domain)),
+        }
+    }
+
+    /// Interprets the namespace of a key descriptor received from `caller_uid`. Callers cannot
+    /// name the app namespace of another app, so the app namespace is always the caller's uid
+    /// no matter what the descriptor says.
+    pub fn for_caller(key: &KeyDescriptor, caller_uid: u32) -> Result<Self> {
+        match key.domain {
+            Domain::APP => Ok(Self::App(caller_uid)),
+            _ => Self::new(key.domain, key.nspace),
+        }
+    }
+
+    /// Returns the domain of this namespace.
+    pub fn domain(&self) -> Domain {
+        match self {
+            Self::App(_) => Domain::APP,
+            Self::SeLinux(_) => Domain::SELINUX,
+            Self::Grant(_) => Domain::GRANT,
+            Self::KeyId(_) => Domain::KEY_ID,
+            Self::Blob(_) => Domain::BLOB,
+        }
+    }
+
+    /// Returns the namespace in the encoding of `KeyDescriptor::nspace`.
+    pub fn nspace(&self) -> i64 {
+        match *self {
+            Self::App(uid) => uid as i64,
+            Self::SeLinux(n) | Self::Grant(n) | Self::KeyId(n) | Self::Blob(n) => n,
+        }
+    }
+
+    /// Returns a copy of `key` with its domain and namespace replaced by this namespace.
+    pub fn apply_to(&self, key: &KeyDescriptor) -> KeyDescriptor {
+        KeyDescriptor { domain: self.domain(), nspace: self.nspace(), ..key.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_roundtrip() -> Result<()> {
+        for (domain, nspace) in [
+            (Domain::APP, 10001),
+            (Domain::SELINUX, 100),
+            (Domain::GRANT, -5),
+            (Domain::KEY_ID, 1 << 40),
+            (Domain::BLOB, 0),
+        ] {
+            let namespace = Namespace::new(domain, nspace)?;
+            assert_eq!(domain, namespace.domain());
+            assert_eq!(nspace, namespace.nspace());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn namespace_rejects_invalid() {
+        for (domain, nspace) in [(Domain::APP, -1), (Domain::APP, 1 << 40), (Domain(42), 0)] {
+            assert_eq!(
+                Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT)),
+                Namespace::new(domain, nspace).unwrap_err().root_cause().downcast_ref::<Error>()
+            );
+        }
+    }
+
+    #[test]
+    fn namespace_for_caller() -> Result<()> {
+        let key = KeyDescriptor { domain: Domain::APP, nspace: -1, alias: None, blob: None };
+        let namespace = Namespace::for_caller(&key, 10001)?;
+        assert_eq!(Namespace::App(10001), namespace);
+        assert_eq!(10001, namespace.apply_to(&key).nspace);
+
+        let key = KeyDescriptor { domain: Domain::SELINUX, nspace: 100, ..key };
+        assert_eq!(Namespace::SeLinux(100), Namespace::for_caller(&key, 10001)?);
+        Ok(())
+    }
+}
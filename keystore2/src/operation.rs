@@ -84,16 +84,21 @@
 //!
 //! ```
 //! struct OperationDb {
-//!     operations: Mutex<Vec<Weak<Operation>>>
+//!     shards: [Mutex<Vec<Weak<Operation>>>; OPERATION_DB_SHARDS],
+//!     ...
 //! }
 //! ```
 //!
+//! The operations are spread across shards by owner uid, so that the operation
+//! creation of one app does not contend with that of other apps. The contention
+//! of the shard locks is recorded and exported through the service dump.
+//!
 //! This allows us to access the operations for the purpose of pruning.
 //! We do this in three phases.
 //!  1. We gather the pruning information. Besides non mutable information,
 //!     we access `last_usage` which is protected by a mutex.
 //!     We only lock this mutex for single statements at a time. During
-//!     this phase we hold the operation db lock.
+//!     this phase we hold the lock of one operation db shard at a time.
 //!  2. We choose a pruning candidate by computing the pruning resistance
 //!     of each operation. We do this entirely with information we now
 //!     have on the stack without holding any locks.
@@ -124,26 +129,66 @@
 //! the operation is either being touched, which changes its pruning resistance,
 //! or it transitions to its end-of-life, which means we may get a free slot.
 //! Either way, we have to revaluate the pruning scores.
+//!
+//! ## Priority classes
+//! Every owner uid belongs to a `PriorityClass` which is derived from the process state
+//! that ActivityManager reports through `IKeystoreMaintenance::onUidStateChanged`. Operations
+//! of background apps have a lower pruning resistance, and background callers have less
+//! pruning power, than their foreground counterparts. The priority classes are tracked
+//! in the global `UidPriorityTable` `crate::globals::UID_PRIORITIES`.
+//!
+//! ## Handover
+//! An operation may be started by one process, e.g., a broker, and finished by another, e.g.,
+//! an isolated worker. Holders of the `transfer_operation` permission can hand an operation
+//! they own over to another uid with `IKeystoreSecurityLevelExtension::transferOperation`,
+//! after passing the operation binder to the target process. From then on the operation is
+//! bound to the target uid: calls from any other uid, including the previous owner, fail with
+//! `PERMISSION_DENIED`, and the operation counts as an operation of the target for pruning and
+//! operation slots. Operations that were never handed over are not bound to a caller.
+//!
+//! ## Replay
+//! Operations created with `IKeystoreSecurityLevelExtension::createOperationWithReplay` survive
+//! pruning if their results do not depend on the KeyMint operation instance, i.e., signing and
+//! MAC computation with keys that need neither authentication nor usage limits. Such an operation
+//! buffers the data of its updates, up to `MAX_REPLAY_DATA` bytes. When it is called after it
+//! was pruned, it begins a new KeyMint operation, pruning another operation if needed, replays
+//! the buffered data, and carries on as if nothing happened. Operations that submitted more data
+//! than the buffer holds fail with `INVALID_OPERATION_HANDLE` after pruning, as usual.
+//!
+//! ## Slot listeners
+//! Every operation is counted in the `OperationSlots` of its `OperationDb` from creation until it
+//! is dropped. Clients that got `BACKEND_BUSY` can register a listener there, which is notified
+//! when an operation ends, see `crate::operation_slots`. Clients that do not register a listener
+//! get a retry hint with `BACKEND_BUSY`, which grows with the number of operations that are
+//! running or waiting for a slot.
 
 use crate::enforcements::AuthInfo;
 use crate::error::{
     error_to_serialized_error, map_err_with, map_km_error, map_or_log_err, Error, ErrorCode,
     ResponseCode, SerializedError,
 };
+use crate::globals::{ANOMALY_DETECTOR, HAL_HEALTH, KEY_OPERATION_STATS, UID_PRIORITIES};
+use crate::hal_health::HalCall;
+use crate::key_parameter::KeyParameter as KsKeyParameter;
 use crate::ks_err;
+use crate::lock_stats::LockStats;
 use crate::metrics_store::log_key_operation_event_stats;
+use crate::operation_slots::OperationSlots;
 use crate::utils::watchdog as wd;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     IKeyMintOperation::IKeyMintOperation, KeyParameter::KeyParameter, KeyPurpose::KeyPurpose,
-    SecurityLevel::SecurityLevel,
+    SecurityLevel::SecurityLevel, Tag::Tag,
 };
-use android_hardware_security_keymint::binder::{BinderFeatures, Strong};
+use android_hardware_security_keymint::binder::{BinderFeatures, SpIBinder, Strong, WpIBinder};
+use android_security_keystoreextension::aidl::android::security::keystoreextension::IOperationSlotListener::IOperationSlotListener;
 use android_system_keystore2::aidl::android::system::keystore2::{
     IKeystoreOperation::BnKeystoreOperation, IKeystoreOperation::IKeystoreOperation,
 };
+use android_system_keystore2::binder::ThreadState;
 use anyhow::{anyhow, Context, Result};
 use std::{
     collections::HashMap,
+    fmt,
     sync::{Arc, Mutex, MutexGuard, Weak},
     time::Duration,
     time::Instant,
@@ -174,13 +219,81 @@ pub enum Outcome {
 pub struct Operation {
     // The index of this operation in the OperationDb.
     index: usize,
-    km_op: Strong<dyn IKeyMintOperation>,
+    // Replaced only when an operation with replay is recreated after pruning.
+    km_op: Mutex<Strong<dyn IKeyMintOperation>>,
     last_usage: Mutex<Instant>,
     outcome: Mutex<Outcome>,
-    owner: u32, // Uid of the operation's owner.
+    owner: Mutex<Owner>,
     auth_info: Mutex<AuthInfo>,
     forced: bool,
     logging_info: LoggingInfo,
+    slots: Arc<OperationSlots>,
+    // The binder of the KeystoreOperation that wraps this operation. It identifies the operation
+    // when a client passes the binder back to keystore.
+    binder: Mutex<Option<WpIBinder>>,
+    replay: Option<Replay>,
+}
+
+/// Begins a new KeyMint operation with the key and the parameters of an operation with replay.
+pub type Restart = Box<dyn Fn() -> Result<Strong<dyn IKeyMintOperation>> + Send + Sync>;
+
+/// The maximum number of bytes of update data that an operation with replay buffers.
+const MAX_REPLAY_DATA: usize = 0x8000;
+
+/// Returns true if an operation with `purpose` and a key with the authorizations `key_params`
+/// can be recreated after pruning without the client noticing. Signatures and MACs only depend
+/// on the key and the input, but beginning a new operation must neither need authentication nor
+/// count as another use of the key.
+pub fn is_replayable(purpose: KeyPurpose, key_params: &[KsKeyParameter]) -> bool {
+    purpose == KeyPurpose::SIGN
+        && !key_params.iter().any(|p| {
+            matches!(
+                p.get_tag(),
+                Tag::USER_SECURE_ID
+                    | Tag::TRUSTED_USER_PRESENCE_REQUIRED
+                    | Tag::USAGE_COUNT_LIMIT
+                    | Tag::MAX_USES_PER_BOOT
+                    | Tag::MIN_SECONDS_BETWEEN_OPS
+            )
+        })
+}
+
+/// Lets an operation recreate its KeyMint operation after it was pruned. See the module
+/// documentation.
+pub struct Replay {
+    restart: Restart,
+    /// The data of all updates so far, or None once it exceeded `MAX_REPLAY_DATA`.
+    input: Mutex<Option<Vec<u8>>>,
+}
+
+impl Replay {
+    /// Creates the replay state of a new operation, which recreates its KeyMint operation with
+    /// `restart`.
+    pub fn new(restart: Restart) -> Self {
+        Self { restart, input: Mutex::new(Some(Vec::new())) }
+    }
+
+    fn record(&self, data: &[u8]) {
+        let mut input = self.input.lock().unwrap();
+        if input.as_ref().map_or(false, |i| i.len() + data.len() > MAX_REPLAY_DATA) {
+            log::info!("Too much data to replay, the operation no longer survives pruning.");
+            *input = None;
+        }
+        if let Some(input) = input.as_mut() {
+            input.extend_from_slice(data);
+        }
+    }
+
+    fn disable(&self) {
+        *self.input.lock().unwrap() = None;
+    }
+}
+
+impl fmt::Debug for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buffered = self.input.lock().unwrap().as_ref().map(|i| i.len());
+        f.debug_struct("Replay").field("buffered", &buffered).finish()
+    }
 }
 
 /// Keeps track of the information required for logging operations.
@@ -190,20 +303,30 @@ pub struct LoggingInfo {
     purpose: KeyPurpose,
     op_params: Vec<KeyParameter>,
     key_upgraded: bool,
+    key_id: Option<i64>,
 }
 
 impl LoggingInfo {
-    /// Constructor
+    /// Constructor. `key_id` is None for keys that are used with `Domain::BLOB`.
     pub fn new(
         sec_level: SecurityLevel,
         purpose: KeyPurpose,
         op_params: Vec<KeyParameter>,
         key_upgraded: bool,
+        key_id: Option<i64>,
     ) -> LoggingInfo {
-        Self { sec_level, purpose, op_params, key_upgraded }
+        Self { sec_level, purpose, op_params, key_upgraded, key_id }
     }
 }
 
+/// The owner of an operation.
+#[derive(Debug, Copy, Clone)]
+struct Owner {
+    uid: u32,
+    /// True once the operation was handed over. Only `uid` may call a bound operation.
+    bound: bool,
+}
+
 struct PruningInfo {
     last_usage: Instant,
     owner: u32,
@@ -211,6 +334,76 @@ struct PruningInfo {
     forced: bool,
 }
 
+/// The priority class of an operation owner. It is derived from the process state of the
+/// owner as reported by ActivityManager and factors into the pruning malus of the owner's
+/// operations as well as the pruning power of the owner when creating new operations.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PriorityClass {
+    /// The owner is in the foreground or otherwise important to the user. This is also the
+    /// class of all uids for which no process state was reported, e.g., system services.
+    Foreground,
+    /// The owner is a background process. Its operations are pruned first.
+    Background,
+}
+
+impl PriorityClass {
+    /// ActivityManager.PROCESS_STATE_UNKNOWN
+    pub const PROCESS_STATE_UNKNOWN: i32 = -1;
+    /// ActivityManager.PROCESS_STATE_IMPORTANT_FOREGROUND. All process states up to and
+    /// including this one are considered foreground.
+    const PROCESS_STATE_IMPORTANT_FOREGROUND: i32 = 6;
+
+    /// Maps an ActivityManager process state to a priority class.
+    pub fn from_proc_state(proc_state: i32) -> Self {
+        if proc_state <= Self::PROCESS_STATE_IMPORTANT_FOREGROUND {
+            Self::Foreground
+        } else {
+            Self::Background
+        }
+    }
+
+    // The contribution of the priority class to the pruning malus.
+    fn malus(&self) -> u64 {
+        match self {
+            Self::Foreground => 0,
+            Self::Background => 1,
+        }
+    }
+}
+
+/// Keeps track of the priority classes of operation owners. It is updated by the uid
+/// state listener and consulted during pruning.
+#[derive(Debug, Default)]
+pub struct UidPriorityTable {
+    classes: Mutex<HashMap<u32, PriorityClass>>,
+}
+
+impl UidPriorityTable {
+    /// Records the new ActivityManager process state of `uid`. Passing
+    /// `PriorityClass::PROCESS_STATE_UNKNOWN` forgets the uid, which reverts it to the
+    /// default priority class.
+    pub fn on_uid_state_changed(&self, uid: u32, proc_state: i32) {
+        let mut classes = self.classes.lock().unwrap();
+        if proc_state == PriorityClass::PROCESS_STATE_UNKNOWN {
+            classes.remove(&uid);
+        } else {
+            classes.insert(uid, PriorityClass::from_proc_state(proc_state));
+        }
+    }
+
+    /// Returns the priority class of `uid`.
+    pub fn get(&self, uid: u32) -> PriorityClass {
+        self.classes.lock().unwrap().get(&uid).copied().unwrap_or(PriorityClass::Foreground)
+    }
+}
+
+struct CandidateInfo {
+    index: usize,
+    malus: u64,
+    last_usage: Instant,
+    age: Duration,
+}
+
 // We don't except more than 32KiB of data in `update`, `updateAad`, and `finish`.
 const MAX_RECEIVE_DATA: usize = 0x8000;
 
@@ -223,19 +416,36 @@ impl Operation {
         auth_info: AuthInfo,
         forced: bool,
         logging_info: LoggingInfo,
+        slots: Arc<OperationSlots>,
+        replay: Option<Replay>,
     ) -> Self {
+        slots.acquire(owner);
         Self {
             index,
-            km_op,
+            km_op: Mutex::new(km_op),
             last_usage: Mutex::new(Instant::now()),
             outcome: Mutex::new(Outcome::Unknown),
-            owner,
+            owner: Mutex::new(Owner { uid: owner, bound: false }),
             auth_info: Mutex::new(auth_info),
             forced,
             logging_info,
+            slots,
+            binder: Mutex::new(None),
+            replay,
         }
     }
 
+    /// Watches a call into the KeyMint operation, see `HalHealth::watch_call`.
+    fn watch_hal_call(&self, id: &'static str, millis: u64) -> Result<HalCall> {
+        HAL_HEALTH.watch_call(self.logging_info.sec_level, id, millis)
+    }
+
+    fn km_op(&self) -> Strong<dyn IKeyMintOperation> {
+        // Expect safety:
+        // `km_op` is locked only for primitive statements that cannot panic.
+        self.km_op.lock().expect("In km_op.").clone()
+    }
+
     fn get_pruning_info(&self) -> Option<PruningInfo> {
         // An operation may be finalized.
         if let Ok(guard) = self.outcome.try_lock() {
@@ -256,7 +466,7 @@ impl Operation {
             // `last_usage` is locked only for primitive single line statements.
             // There is no chance to panic and poison the mutex.
             last_usage: *self.last_usage.lock().expect("In get_pruning_info."),
-            owner: self.owner,
+            owner: self.owner(),
             index: self.index,
             forced: self.forced,
         })
@@ -286,11 +496,14 @@ impl Operation {
         }
         *locked_outcome = Outcome::Pruned;
 
-        let _wp = wd::watch_millis("In Operation::prune: calling abort()", 500);
-
         // We abort the operation. If there was an error we log it but ignore it.
-        if let Err(e) = map_km_error(self.km_op.abort()) {
-            log::error!("In prune: KeyMint::abort failed with {:?}.", e);
+        match self.watch_hal_call("In Operation::prune: calling abort()", 500) {
+            Ok(_wp) => {
+                if let Err(e) = map_km_error(self.km_op().abort()) {
+                    log::error!("In prune: KeyMint::abort failed with {:?}.", e);
+                }
+            }
+            Err(e) => log::error!("In prune: Not aborting: {:?}.", e),
         }
 
         Ok(())
@@ -328,10 +541,95 @@ impl Operation {
         }
     }
 
+    // Like `check_active`, but first recreates the KeyMint operation of an operation with
+    // replay that was pruned. If that fails, the operation stays pruned.
+    fn check_active_or_restart(&self) -> Result<MutexGuard<Outcome>> {
+        let mut guard = self.outcome.lock().expect("In check_active_or_restart.");
+        if let (Outcome::Pruned, Some(replay)) = (*guard, &self.replay) {
+            match self.restart(replay) {
+                Ok(()) => *guard = Outcome::Unknown,
+                Err(e) => log::warn!("Failed to recreate pruned operation: {:?}", e),
+            }
+        }
+        match *guard {
+            Outcome::Unknown => Ok(guard),
+            _ => Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE))
+                .context(ks_err!("Call on finalized operation with outcome: {:?}.", *guard)),
+        }
+    }
+
+    // Begins a new KeyMint operation in place of the pruned one and replays the buffered
+    // update data. The caller must hold the outcome lock.
+    fn restart(&self, replay: &Replay) -> Result<()> {
+        let input = replay.input.lock().unwrap();
+        let input = input
+            .as_ref()
+            .ok_or(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE))
+            .context(ks_err!("Too much data to replay."))?;
+        // The pruning that makes room for the new KeyMint operation should not pick this one.
+        self.touch();
+        let km_op = (replay.restart)().context(ks_err!("Failed to begin."))?;
+        if !input.is_empty() {
+            let (hat, tst) = self.auth_info.lock().unwrap().before_update().context(ks_err!())?;
+            let _wp = self.watch_hal_call("Operation::restart: calling update", 500)?;
+            if let Err(e) = map_km_error(km_op.update(input, hat.as_ref(), tst.as_ref())) {
+                if let Err(e) = map_km_error(km_op.abort()) {
+                    log::warn!("Failed to abort the recreated operation: {:?}", e);
+                }
+                return Err(e).context(ks_err!("Failed to replay {} bytes.", input.len()));
+            }
+        }
+        *self.km_op.lock().expect("In restart.") = km_op;
+        log::info!("Recreated pruned operation, replayed {} bytes.", input.len());
+        Ok(())
+    }
+
+    fn owner(&self) -> u32 {
+        // Expect safety:
+        // `owner` is locked only for primitive statements that cannot panic.
+        self.owner.lock().expect("In owner.").uid
+    }
+
+    // Checks that `caller` may call the operation, i.e., that the operation was not handed
+    // over to another uid.
+    fn check_caller(&self, caller: u32) -> Result<()> {
+        let Owner { uid, bound } = *self.owner.lock().expect("In check_caller.");
+        if bound && uid != caller {
+            return Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                .context(ks_err!("Bound to uid {uid}, called by uid {caller}."));
+        }
+        Ok(())
+    }
+
+    /// Hands the operation over from `caller`, who must own it, to `target`. From then on only
+    /// `target` may call the operation.
+    fn transfer(&self, caller: u32, target: u32) -> Result<()> {
+        // Holding the outcome lock keeps the operation from ending while it changes owners.
+        let _outcome = self.check_active().context("In transfer")?;
+        let mut owner = self.owner.lock().expect("In transfer.");
+        if owner.uid != caller {
+            return Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                .context(ks_err!("Uid {} does not own the operation.", caller));
+        }
+        self.slots.transfer(owner.uid, target);
+        *owner = Owner { uid: target, bound: true };
+        drop(owner);
+        // The target has yet to get going, so the operation should not look idle to pruning.
+        self.touch();
+        Ok(())
+    }
+
+    fn is_wrapped_by(&self, binder: &SpIBinder) -> bool {
+        // Expect safety:
+        // `binder` is locked only for primitive statements that cannot panic.
+        let wrapper = self.binder.lock().expect("In is_wrapped_by.");
+        wrapper.as_ref().and_then(|wrapper| wrapper.promote()).map_or(false, |b| &b == binder)
+    }
+
     // This function checks the amount of input data sent to us. We reject any buffer
     // exceeding MAX_RECEIVE_DATA bytes as input to `update`, `update_aad`, and `finish`
     // in order to force clients into using reasonable limits.
-    fn check_input_length(data: &[u8]) -> Result<()> {
+    pub fn check_input_length(data: &[u8]) -> Result<()> {
         if data.len() > MAX_RECEIVE_DATA {
             // This error code is unique, no context required here.
             return Err(anyhow!(Error::Rc(ResponseCode::TOO_MUCH_DATA)));
@@ -350,9 +648,13 @@ impl Operation {
     /// Implementation of `IKeystoreOperation::updateAad`.
     /// Refer to the AIDL spec at system/hardware/interfaces/keystore2 for details.
     fn update_aad(&self, aad_input: &[u8]) -> Result<()> {
-        let mut outcome = self.check_active().context("In update_aad")?;
+        let mut outcome = self.check_active_or_restart().context("In update_aad")?;
         Self::check_input_length(aad_input).context("In update_aad")?;
         self.touch();
+        // Replaying updates without their associated data would change the result.
+        if let Some(replay) = &self.replay {
+            replay.disable();
+        }
 
         let (hat, tst) = self
             .auth_info
@@ -362,8 +664,8 @@ impl Operation {
             .context(ks_err!("Trying to get auth tokens."))?;
 
         self.update_outcome(&mut outcome, {
-            let _wp = wd::watch_millis("Operation::update_aad: calling updateAad", 500);
-            map_km_error(self.km_op.updateAad(aad_input, hat.as_ref(), tst.as_ref()))
+            let _wp = self.watch_hal_call("Operation::update_aad: calling updateAad", 500)?;
+            map_km_error(self.km_op().updateAad(aad_input, hat.as_ref(), tst.as_ref()))
         })
         .context(ks_err!("Update failed."))?;
 
@@ -373,7 +675,7 @@ impl Operation {
     /// Implementation of `IKeystoreOperation::update`.
     /// Refer to the AIDL spec at system/hardware/interfaces/keystore2 for details.
     fn update(&self, input: &[u8]) -> Result<Option<Vec<u8>>> {
-        let mut outcome = self.check_active().context("In update")?;
+        let mut outcome = self.check_active_or_restart().context("In update")?;
         Self::check_input_length(input).context("In update")?;
         self.touch();
 
@@ -386,11 +688,15 @@ impl Operation {
 
         let output = self
             .update_outcome(&mut outcome, {
-                let _wp = wd::watch_millis("Operation::update: calling update", 500);
-                map_km_error(self.km_op.update(input, hat.as_ref(), tst.as_ref()))
+                let _wp = self.watch_hal_call("Operation::update: calling update", 500)?;
+                map_km_error(self.km_op().update(input, hat.as_ref(), tst.as_ref()))
             })
             .context(ks_err!("Update failed."))?;
 
+        if let Some(replay) = &self.replay {
+            replay.record(input);
+        }
+
         if output.is_empty() {
             Ok(None)
         } else {
@@ -400,8 +706,12 @@ impl Operation {
 
     /// Implementation of `IKeystoreOperation::finish`.
     /// Refer to the AIDL spec at system/hardware/interfaces/keystore2 for details.
-    fn finish(&self, input: Option<&[u8]>, signature: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
-        let mut outcome = self.check_active().context("In finish")?;
+    pub fn finish(
+        &self,
+        input: Option<&[u8]>,
+        signature: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut outcome = self.check_active_or_restart().context("In finish")?;
         if let Some(input) = input {
             Self::check_input_length(input).context("In finish")?;
         }
@@ -416,8 +726,8 @@ impl Operation {
 
         let output = self
             .update_outcome(&mut outcome, {
-                let _wp = wd::watch_millis("Operation::finish: calling finish", 500);
-                map_km_error(self.km_op.finish(
+                let _wp = self.watch_hal_call("Operation::finish: calling finish", 500)?;
+                map_km_error(self.km_op().finish(
                     input,
                     signature,
                     hat.as_ref(),
@@ -447,8 +757,8 @@ impl Operation {
         *locked_outcome = outcome;
 
         {
-            let _wp = wd::watch_millis("Operation::abort: calling abort", 500);
-            map_km_error(self.km_op.abort()).context(ks_err!("KeyMint::abort failed."))
+            let _wp = self.watch_hal_call("Operation::abort: calling abort", 500)?;
+            map_km_error(self.km_op().abort()).context(ks_err!("KeyMint::abort failed."))
         }
     }
 }
@@ -463,6 +773,14 @@ impl Drop for Operation {
             &guard,
             self.logging_info.key_upgraded,
         );
+        if let Some(key_id) = self.logging_info.key_id {
+            KEY_OPERATION_STATS.on_ended(key_id, &guard);
+            if let (KeyPurpose::DECRYPT, Outcome::ErrorCode(_)) =
+                (self.logging_info.purpose, &*guard)
+            {
+                ANOMALY_DETECTOR.on_decrypt_failed(self.owner(), key_id);
+            }
+        }
         if let Outcome::Unknown = *guard {
             drop(guard);
             // If the operation was still active we call abort, setting
@@ -471,22 +789,62 @@ impl Drop for Operation {
                 log::error!("While dropping Operation: abort failed:\n    {:?}", e);
             }
         }
+        // The KeyMint operation has ended by now, so its slot is free.
+        self.slots.release(self.owner());
     }
 }
 
+/// Number of shards of the OperationDb.
+const OPERATION_DB_SHARDS: usize = 8;
+
 /// The OperationDb holds weak references to all ongoing operations.
 /// Its main purpose is to facilitate operation pruning.
-#[derive(Debug, Default)]
+/// The index of an operation encodes both its shard and its slot within the shard as
+/// `slot * OPERATION_DB_SHARDS + shard`.
+#[derive(Debug)]
 pub struct OperationDb {
     // TODO replace Vec with WeakTable when the weak_table crate becomes
     // available.
-    operations: Mutex<Vec<Weak<Operation>>>,
+    shards: [Mutex<Vec<Weak<Operation>>>; OPERATION_DB_SHARDS],
+    lock_stats: Arc<LockStats>,
+    slots: Arc<OperationSlots>,
+}
+
+impl Default for OperationDb {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl OperationDb {
     /// Creates a new OperationDb.
     pub fn new() -> Self {
-        Self { operations: Mutex::new(Vec::new()) }
+        Self {
+            shards: Default::default(),
+            lock_stats: LockStats::new_registered("OperationDb"),
+            slots: Default::default(),
+        }
+    }
+
+    /// Registers `listener` to be notified when an operation slot is freed while `uid` runs
+    /// fewer than `threshold` operations. See `OperationSlots::register_listener`.
+    pub fn register_slot_listener(
+        &self,
+        uid: u32,
+        threshold: i32,
+        listener: &Strong<dyn IOperationSlotListener>,
+    ) -> Result<()> {
+        self.slots.register_listener(uid, threshold, listener)
+    }
+
+    /// Suggests how long a client that got `BACKEND_BUSY` from `prune` should wait before it
+    /// retries. See `OperationSlots::retry_hint`.
+    pub fn retry_hint(&self) -> Duration {
+        self.slots.retry_hint()
+    }
+
+    fn shard_of_owner(owner: u32) -> usize {
+        owner as usize % OPERATION_DB_SHARDS
     }
 
     /// Creates a new operation.
@@ -499,37 +857,44 @@ impl OperationDb {
         auth_info: AuthInfo,
         forced: bool,
         logging_info: LoggingInfo,
+        replay: Option<Replay>,
     ) -> Arc<Operation> {
+        let shard = Self::shard_of_owner(owner);
         // We use unwrap because we don't allow code that can panic while locked.
-        let mut operations = self.operations.lock().expect("In create_operation.");
+        let mut operations =
+            self.lock_stats.lock(&self.shards[shard]).expect("In create_operation.");
 
-        let mut index: usize = 0;
+        let mut slot: usize = 0;
         // First we iterate through the operation slots to try and find an unused
         // slot. If we don't find one, we append the new entry instead.
         match (*operations).iter_mut().find(|s| {
-            index += 1;
+            slot += 1;
             s.upgrade().is_none()
         }) {
             Some(free_slot) => {
                 let new_op = Arc::new(Operation::new(
-                    index - 1,
+                    (slot - 1) * OPERATION_DB_SHARDS + shard,
                     km_op,
                     owner,
                     auth_info,
                     forced,
                     logging_info,
+                    self.slots.clone(),
+                    replay,
                 ));
                 *free_slot = Arc::downgrade(&new_op);
                 new_op
             }
             None => {
                 let new_op = Arc::new(Operation::new(
-                    operations.len(),
+                    operations.len() * OPERATION_DB_SHARDS + shard,
                     km_op,
                     owner,
                     auth_info,
                     forced,
                     logging_info,
+                    self.slots.clone(),
+                    replay,
                 ));
                 operations.push(Arc::downgrade(&new_op));
                 new_op
@@ -537,8 +902,37 @@ impl OperationDb {
         }
     }
 
+    /// Hands the operation that is wrapped by `operation` over from `caller` to `target`. The
+    /// operation must belong to this OperationDb and `caller` must own it. From then on only
+    /// `target` may call the operation.
+    pub fn transfer_operation(
+        &self,
+        operation: &SpIBinder,
+        caller: u32,
+        target: u32,
+    ) -> Result<()> {
+        let op = self
+            .shards
+            .iter()
+            .find_map(|shard| {
+                self.lock_stats
+                    .lock(shard)
+                    .expect("In OperationDb::transfer_operation.")
+                    .iter()
+                    .filter_map(|op| op.upgrade())
+                    .find(|op| op.is_wrapped_by(operation))
+            })
+            .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("The operation does not belong to this security level."))?;
+        op.transfer(caller, target)
+    }
+
     fn get(&self, index: usize) -> Option<Arc<Operation>> {
-        self.operations.lock().expect("In OperationDb::get.").get(index).and_then(|op| op.upgrade())
+        self.lock_stats
+            .lock(&self.shards[index % OPERATION_DB_SHARDS])
+            .expect("In OperationDb::get.")
+            .get(index / OPERATION_DB_SHARDS)
+            .and_then(|op| op.upgrade())
     }
 
     /// Attempts to prune an operation.
@@ -613,104 +1007,38 @@ impl OperationDb {
     /// ## Update
     /// We also allow callers to cannibalize their own sibling operations if no other
     /// slot can be found. In this case the least recently used sibling is pruned.
+    ///
+    /// ## Priority classes
+    /// The `PriorityClass` of an owner adds to the malus of both the caller and the
+    /// existing operations. Background owners get an additional malus of 1. So a
+    /// foreground caller with no running operations can prune a young, single child
+    /// operation of a background app, but not vice versa.
+    /// ```
+    /// malus = 1 + no_of_siblings + floor(log6(age_in_seconds + 1)) + priority_malus
+    /// ```
     pub fn prune(&self, caller: u32, forced: bool) -> Result<(), Error> {
         loop {
-            // Maps the uid of the owner to the number of operations that owner has
-            // (running_siblings). More operations per owner lowers the pruning
-            // resistance of the operations of that owner. Whereas the number of
-            // ongoing operations of the caller lowers the pruning power of the caller.
-            let mut owners: HashMap<u32, u64> = HashMap::new();
             let mut pruning_info: Vec<PruningInfo> = Vec::new();
 
             let now = Instant::now();
-            self.operations
-                .lock()
-                .expect("In OperationDb::prune: Trying to lock self.operations.")
-                .iter()
-                .for_each(|op| {
-                    if let Some(op) = op.upgrade() {
-                        if let Some(p_info) = op.get_pruning_info() {
-                            let owner = p_info.owner;
-                            pruning_info.push(p_info);
-                            // Count operations per owner.
-                            *owners.entry(owner).or_insert(0) += 1;
-                        }
-                    }
-                });
-
-            // If the operation is forced, the caller has a malus of 0.
-            let caller_malus = if forced { 0 } else { 1u64 + *owners.entry(caller).or_default() };
-
-            // We iterate through all operations computing the malus and finding
-            // the candidate with the highest malus which must also be higher
-            // than the caller_malus.
-            struct CandidateInfo {
-                index: usize,
-                malus: u64,
-                last_usage: Instant,
-                age: Duration,
-            }
-            let mut oldest_caller_op: Option<CandidateInfo> = None;
-            let candidate = pruning_info.iter().fold(
-                None,
-                |acc: Option<CandidateInfo>, &PruningInfo { last_usage, owner, index, forced }| {
-                    // Compute the age of the current operation.
-                    let age = now
-                        .checked_duration_since(last_usage)
-                        .unwrap_or_else(|| Duration::new(0, 0));
-
-                    // Find the least recently used sibling as an alternative pruning candidate.
-                    if owner == caller {
-                        if let Some(CandidateInfo { age: a, .. }) = oldest_caller_op {
-                            if age > a {
-                                oldest_caller_op =
-                                    Some(CandidateInfo { index, malus: 0, last_usage, age });
-                            }
-                        } else {
-                            oldest_caller_op =
-                                Some(CandidateInfo { index, malus: 0, last_usage, age });
-                        }
-                    }
-
-                    // Compute the malus of the current operation.
-                    let malus = if forced {
-                        // Forced operations have a malus of 0. And cannot even be pruned
-                        // by other forced operations.
-                        0
-                    } else {
-                        // Expect safety: Every owner in pruning_info was counted in
-                        // the owners map. So this unwrap cannot panic.
-                        *owners.get(&owner).expect(
-                            "This is odd. We should have counted every owner in pruning_info.",
-                        ) + ((age.as_secs() + 1) as f64).log(6.0).floor() as u64
-                    };
-
-                    // Now check if the current operation is a viable/better candidate
-                    // the one currently stored in the accumulator.
-                    match acc {
-                        // First we have to find any operation that is prunable by the caller.
-                        None => {
-                            if caller_malus < malus {
-                                Some(CandidateInfo { index, malus, last_usage, age })
-                            } else {
-                                None
+            for shard in &self.shards {
+                self.lock_stats
+                    .lock(shard)
+                    .expect("In OperationDb::prune: Trying to lock self.shards.")
+                    .iter()
+                    .for_each(|op| {
+                        if let Some(op) = op.upgrade() {
+                            if let Some(p_info) = op.get_pruning_info() {
+                                pruning_info.push(p_info);
                             }
                         }
-                        // If we have found one we look for the operation with the worst score.
-                        // If there is a tie, the older operation is considered weaker.
-                        Some(CandidateInfo { index: i, malus: m, last_usage: l, age: a }) => {
-                            if malus > m || (malus == m && age > a) {
-                                Some(CandidateInfo { index, malus, last_usage, age })
-                            } else {
-                                Some(CandidateInfo { index: i, malus: m, last_usage: l, age: a })
-                            }
-                        }
-                    }
-                },
-            );
+                    });
+            }
 
-            // If we did not find a suitable candidate we may cannibalize our oldest sibling.
-            let candidate = candidate.or(oldest_caller_op);
+            let candidate =
+                Self::select_pruning_candidate(caller, forced, &pruning_info, now, |uid| {
+                    UID_PRIORITIES.get(uid)
+                });
 
             match candidate {
                 Some(CandidateInfo { index, malus: _, last_usage, age: _ }) => {
@@ -760,6 +1088,102 @@ impl OperationDb {
             }
         }
     }
+
+    /// Selects the operation to be pruned on behalf of `caller` from the gathered pruning
+    /// information. See `OperationDb::prune` for the strategy. `priority_of` yields the
+    /// priority class of an owner uid.
+    fn select_pruning_candidate<P>(
+        caller: u32,
+        forced: bool,
+        pruning_info: &[PruningInfo],
+        now: Instant,
+        priority_of: P,
+    ) -> Option<CandidateInfo>
+    where
+        P: Fn(u32) -> PriorityClass,
+    {
+        // Maps the uid of the owner to the number of operations that owner has
+        // (running_siblings) plus the malus of its priority class. More operations per
+        // owner lowers the pruning resistance of the operations of that owner. Whereas
+        // the number of ongoing operations of the caller lowers the pruning power of the
+        // caller.
+        let mut owners: HashMap<u32, u64> = HashMap::new();
+        for p_info in pruning_info {
+            // Count operations per owner.
+            *owners.entry(p_info.owner).or_insert_with(|| priority_of(p_info.owner).malus()) += 1;
+        }
+
+        // If the operation is forced, the caller has a malus of 0.
+        let caller_malus = if forced {
+            0
+        } else {
+            1u64 + *owners.entry(caller).or_insert_with(|| priority_of(caller).malus())
+        };
+
+        // We iterate through all operations computing the malus and finding
+        // the candidate with the highest malus which must also be higher
+        // than the caller_malus.
+        let mut oldest_caller_op: Option<CandidateInfo> = None;
+        let candidate = pruning_info.iter().fold(
+            None,
+            |acc: Option<CandidateInfo>, &PruningInfo { last_usage, owner, index, forced }| {
+                // Compute the age of the current operation.
+                let age =
+                    now.checked_duration_since(last_usage).unwrap_or_else(|| Duration::new(0, 0));
+
+                // Find the least recently used sibling as an alternative pruning candidate.
+                if owner == caller {
+                    if let Some(CandidateInfo { age: a, .. }) = oldest_caller_op {
+                        if age > a {
+                            oldest_caller_op =
+                                Some(CandidateInfo { index, malus: 0, last_usage, age });
+                        }
+                    } else {
+                        oldest_caller_op = Some(CandidateInfo { index, malus: 0, last_usage, age });
+                    }
+                }
+
+                // Compute the malus of the current operation.
+                let malus = if forced {
+                    // Forced operations have a malus of 0. And cannot even be pruned
+                    // by other forced operations.
+                    0
+                } else {
+                    // Expect safety: Every owner in pruning_info was counted in
+                    // the owners map. So this unwrap cannot panic.
+                    *owners
+                        .get(&owner)
+                        .expect("This is odd. We should have counted every owner in pruning_info.")
+                        + ((age.as_secs() + 1) as f64).log(6.0).floor() as u64
+                };
+
+                // Now check if the current operation is a viable/better candidate
+                // the one currently stored in the accumulator.
+                match acc {
+                    // First we have to find any operation that is prunable by the caller.
+                    None => {
+                        if caller_malus < malus {
+                            Some(CandidateInfo { index, malus, last_usage, age })
+                        } else {
+                            None
+                        }
+                    }
+                    // If we have found one we look for the operation with the worst score.
+                    // If there is a tie, the older operation is considered weaker.
+                    Some(CandidateInfo { index: i, malus: m, last_usage: l, age: a }) => {
+                        if malus > m || (malus == m && age > a) {
+                            Some(CandidateInfo { index, malus, last_usage, age })
+                        } else {
+                            Some(CandidateInfo { index: i, malus: m, last_usage: l, age: a })
+                        }
+                    }
+                }
+            },
+        );
+
+        // If we did not find a suitable candidate we may cannibalize our oldest sibling.
+        candidate.or(oldest_caller_op)
+    }
 }
 
 /// Implementation of IKeystoreOperation.
@@ -771,12 +1195,19 @@ impl KeystoreOperation {
     /// Creates a new operation instance wrapped in a
     /// BnKeystoreOperation proxy object. It also enables
     /// `BinderFeatures::set_requesting_sid` on the new interface, because
-    /// we need it for checking Keystore permissions.
+    /// we need it for checking Keystore permissions. The operation keeps a weak
+    /// reference to the new binder, so that it can be found by its binder later.
     pub fn new_native_binder(operation: Arc<Operation>) -> binder::Strong<dyn IKeystoreOperation> {
-        BnKeystoreOperation::new_binder(
+        let wrapped = operation.clone();
+        let result = BnKeystoreOperation::new_binder(
             Self { operation: Mutex::new(Some(operation)) },
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
-        )
+        );
+        // Expect safety:
+        // `binder` is locked only for primitive statements that cannot panic.
+        *wrapped.binder.lock().expect("In new_native_binder.") =
+            Some(result.as_binder().downgrade());
+        result
     }
 
     /// Grabs the outer operation mutex and calls `f` on the locked operation.
@@ -791,6 +1222,10 @@ impl KeystoreOperation {
             Ok(mut mutex_guard) => {
                 let result = match &*mutex_guard {
                     Some(op) => {
+                        // A caller that may not use the operation must not end it either, so
+                        // this returns without deleting the operation.
+                        op.check_caller(ThreadState::get_calling_uid())
+                            .context(ks_err!("KeystoreOperation::with_locked_operation"))?;
                         let result = f(op);
                         // Any error here means we can discard the operation.
                         if result.is_err() {
@@ -877,3 +1312,143 @@ impl IKeystoreOperation for KeystoreOperation {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ActivityManager.PROCESS_STATE_TOP
+    const PROCESS_STATE_TOP: i32 = 2;
+    // ActivityManager.PROCESS_STATE_CACHED_ACTIVITY
+    const PROCESS_STATE_CACHED_ACTIVITY: i32 = 16;
+
+    const CALLER: u32 = 10001;
+    const OTHER: u32 = 10002;
+
+    fn young_op(owner: u32, index: usize, now: Instant) -> PruningInfo {
+        PruningInfo { last_usage: now, owner, index, forced: false }
+    }
+
+    fn select(
+        caller: u32,
+        pruning_info: &[PruningInfo],
+        now: Instant,
+        table: &UidPriorityTable,
+    ) -> Option<usize> {
+        OperationDb::select_pruning_candidate(caller, false, pruning_info, now, |uid| {
+            table.get(uid)
+        })
+        .map(|c| c.index)
+    }
+
+    #[test]
+    fn priority_class_from_proc_state() {
+        assert_eq!(PriorityClass::from_proc_state(0), PriorityClass::Foreground);
+        assert_eq!(PriorityClass::from_proc_state(PROCESS_STATE_TOP), PriorityClass::Foreground);
+        assert_eq!(PriorityClass::from_proc_state(6), PriorityClass::Foreground);
+        assert_eq!(PriorityClass::from_proc_state(7), PriorityClass::Background);
+        assert_eq!(
+            PriorityClass::from_proc_state(PROCESS_STATE_CACHED_ACTIVITY),
+            PriorityClass::Background
+        );
+    }
+
+    #[test]
+    fn uid_priority_table_defaults_to_foreground() {
+        let table = UidPriorityTable::default();
+        assert_eq!(table.get(OTHER), PriorityClass::Foreground);
+        table.on_uid_state_changed(OTHER, PROCESS_STATE_CACHED_ACTIVITY);
+        assert_eq!(table.get(OTHER), PriorityClass::Background);
+        table.on_uid_state_changed(OTHER, PriorityClass::PROCESS_STATE_UNKNOWN);
+        assert_eq!(table.get(OTHER), PriorityClass::Foreground);
+    }
+
+    #[test]
+    fn foreground_caller_prunes_young_background_operation() {
+        let now = Instant::now();
+        let table = UidPriorityTable::default();
+        let ops = [young_op(OTHER, 0, now)];
+
+        // Both foreground: a young single child operation is protected.
+        assert_eq!(select(CALLER, &ops, now, &table), None);
+
+        // The owner moves to the background, which makes its operation prunable.
+        table.on_uid_state_changed(OTHER, PROCESS_STATE_CACHED_ACTIVITY);
+        assert_eq!(select(CALLER, &ops, now, &table), Some(0));
+
+        // Back to the foreground restores the original pruning resistance.
+        table.on_uid_state_changed(OTHER, PROCESS_STATE_TOP);
+        assert_eq!(select(CALLER, &ops, now, &table), None);
+    }
+
+    #[test]
+    fn background_caller_cannot_prune_foreground_operation() {
+        let now = Instant::now();
+        let table = UidPriorityTable::default();
+        table.on_uid_state_changed(CALLER, PROCESS_STATE_CACHED_ACTIVITY);
+
+        // An aging (5s <= age < 35s) single child would be prunable by a foreground caller
+        // but not by a background caller.
+        let ops = [PruningInfo {
+            last_usage: now - Duration::from_secs(10),
+            owner: OTHER,
+            index: 0,
+            forced: false,
+        }];
+        assert_eq!(select(CALLER, &ops, now, &table), None);
+
+        table.on_uid_state_changed(CALLER, PROCESS_STATE_TOP);
+        assert_eq!(select(CALLER, &ops, now, &table), Some(0));
+    }
+
+    #[test]
+    fn background_operations_are_pruned_first() {
+        let now = Instant::now();
+        let table = UidPriorityTable::default();
+        let third: u32 = 10003;
+        table.on_uid_state_changed(third, PROCESS_STATE_CACHED_ACTIVITY);
+
+        // OTHER (foreground) and third (background) each have two young operations.
+        // Both are prunable, but the background operations have the higher malus.
+        let ops = [
+            young_op(OTHER, 0, now),
+            young_op(OTHER, 1, now),
+            young_op(third, 2, now),
+            young_op(third, 3, now),
+        ];
+        let candidate = select(CALLER, &ops, now, &table);
+        assert!(matches!(candidate, Some(2) | Some(3)), "Unexpected candidate {:?}", candidate);
+    }
+
+    #[test]
+    fn only_unlimited_signing_without_auth_is_replayable() {
+        use crate::key_parameter::KeyParameterValue as KsValue;
+        let kp = |v| KsKeyParameter::new(v, SecurityLevel::TRUSTED_ENVIRONMENT);
+        let params = vec![kp(KsValue::KeyPurpose(KeyPurpose::SIGN)), kp(KsValue::NoAuthRequired)];
+        assert!(is_replayable(KeyPurpose::SIGN, &params));
+        assert!(!is_replayable(KeyPurpose::ENCRYPT, &params));
+        for limit in [
+            KsValue::UserSecureID(1),
+            KsValue::UsageCountLimit(10),
+            KsValue::MaxUsesPerBoot(10),
+            KsValue::MinSecondsBetweenOps(1),
+        ] {
+            let mut limited = params.clone();
+            limited.push(kp(limit));
+            assert!(!is_replayable(KeyPurpose::SIGN, &limited));
+        }
+    }
+
+    #[test]
+    fn replay_gives_up_on_too_much_data() {
+        let replay = Replay::new(Box::new(|| Err(anyhow!("not called"))));
+        replay.record(&[1; MAX_REPLAY_DATA / 2]);
+        replay.record(&[2; MAX_REPLAY_DATA / 2]);
+        assert_eq!(Some(MAX_REPLAY_DATA), replay.input.lock().unwrap().as_ref().map(|i| i.len()));
+        replay.record(&[3]);
+        assert!(replay.input.lock().unwrap().is_none());
+        // Once given up, nothing is buffered any more.
+        replay.record(&[]);
+        assert!(replay.input.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module notifies clients when an operation slot of a security level becomes available,
+//! so that they can start an operation right away instead of retrying after `BACKEND_BUSY`.
+//!
+//! Keystore cannot see the operation slots of KeyMint. It counts the running operations of each
+//! uid instead, and regards the end of an operation as a freed slot. A notification is therefore
+//! only a hint, and creating the operation may still fail with `BACKEND_BUSY`.
+//!
+//! A client registers a listener with a threshold. The listener is notified once, when a slot is
+//! freed while the uid of the client runs fewer operations than the threshold, and is then
+//! dropped. To avoid a thundering herd, every freed slot notifies at most one listener. Waiting
+//! listeners are notified in the order of registration, and each uid can only have
+//! `MAX_LISTENERS_PER_UID` listeners waiting at a time.
+//!
+//! Clients that do not register a listener get a retry hint with `BACKEND_BUSY` instead, see
+//! `OperationSlots::retry_hint`.
+
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use android_security_keystoreextension::aidl::android::security::keystoreextension::IOperationSlotListener::IOperationSlotListener;
+use android_system_keystore2::binder::{IBinder, Strong};
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The maximum number of listeners that one uid can have waiting at a time.
+const MAX_LISTENERS_PER_UID: usize = 4;
+
+/// The retry hint grows by this much for every operation that is running or waiting for a slot.
+const RETRY_HINT_PER_OPERATION: Duration = Duration::from_millis(25);
+
+/// The shortest retry hint. Retrying sooner than this is unlikely to find a free slot.
+const MIN_RETRY_HINT: Duration = Duration::from_millis(100);
+
+/// The longest retry hint, so that clients do not stall when many operations are running.
+const MAX_RETRY_HINT: Duration = Duration::from_secs(5);
+
+struct Waiter {
+    uid: u32,
+    threshold: usize,
+    listener: Strong<dyn IOperationSlotListener>,
+}
+
+#[derive(Default)]
+struct SlotState {
+    running: HashMap<u32, usize>,
+    waiters: VecDeque<Waiter>,
+}
+
+impl SlotState {
+    fn running(&self, uid: u32) -> usize {
+        self.running.get(&uid).copied().unwrap_or(0)
+    }
+
+    fn decrement(&mut self, uid: u32) {
+        if let Some(running) = self.running.get_mut(&uid) {
+            *running -= 1;
+            if *running == 0 {
+                self.running.remove(&uid);
+            }
+        }
+    }
+}
+
+/// Counts the running operations of each uid and notifies waiting listeners when an operation
+/// ends.
+#[derive(Default)]
+pub struct OperationSlots {
+    state: Mutex<SlotState>,
+}
+
+impl std::fmt::Debug for OperationSlots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("OperationSlots")
+            .field("running", &state.running)
+            .field("waiters", &state.waiters.len())
+            .finish()
+    }
+}
+
+impl OperationSlots {
+    /// Records that `uid` started an operation.
+    pub fn acquire(&self, uid: u32) {
+        *self.state.lock().unwrap().running.entry(uid).or_default() += 1;
+    }
+
+    /// Records that an operation was handed over from `from` to `to`. The operation keeps its
+    /// slot, so no listener is notified.
+    pub fn transfer(&self, from: u32, to: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.decrement(from);
+        *state.running.entry(to).or_default() += 1;
+    }
+
+    /// Records that an operation of `uid` ended, and notifies the longest waiting listener that
+    /// is interested in the freed slot.
+    pub fn release(&self, uid: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.decrement(uid);
+        loop {
+            let position = state.waiters.iter().position(|w| state.running(w.uid) < w.threshold);
+            let waiter = match position.and_then(|p| state.waiters.remove(p)) {
+                Some(waiter) => waiter,
+                None => return,
+            };
+            // The listener is notified without holding the lock. The call is oneway, so it
+            // only fails if the client is gone, in which case the slot goes to the next waiter.
+            drop(state);
+            match waiter.listener.onOperationSlotAvailable() {
+                Ok(()) => return,
+                Err(e) => log::warn!("Failed to notify operation slot listener: {:?}", e),
+            }
+            state = self.state.lock().unwrap();
+        }
+    }
+
+    /// Suggests how long a client that was refused an operation slot should wait before it
+    /// retries. The more operations are running, and the more clients are already waiting for a
+    /// slot, the longer it takes until a slot is freed that the client can get.
+    pub fn retry_hint(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        let pressure = state.running.values().sum::<usize>() + state.waiters.len();
+        RETRY_HINT_PER_OPERATION
+            .saturating_mul(u32::try_from(pressure).unwrap_or(u32::MAX))
+            .clamp(MIN_RETRY_HINT, MAX_RETRY_HINT)
+    }
+
+    /// Registers `listener` to be notified once a slot is freed while `uid` runs fewer than
+    /// `threshold` operations. Fails with `INVALID_ARGUMENT` if `threshold` is not positive, and
+    /// with `BACKEND_BUSY` if `uid` already has `MAX_LISTENERS_PER_UID` listeners waiting.
+    pub fn register_listener(
+        &self,
+        uid: u32,
+        threshold: i32,
+        listener: &Strong<dyn IOperationSlotListener>,
+    ) -> Result<()> {
+        let threshold = match usize::try_from(threshold) {
+            Ok(threshold) if threshold > 0 => threshold,
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Invalid threshold {}.", threshold))
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        state.waiters.retain(|w| w.listener.as_binder().is_binder_alive());
+        if state.waiters.iter().filter(|w| w.uid == uid).count() >= MAX_LISTENERS_PER_UID {
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(ks_err!("Too many operation slot listeners for uid {}.", uid));
+        }
+        state.waiters.push_back(Waiter { uid, threshold, listener: listener.clone() });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_security_keystoreextension::aidl::android::security::keystoreextension::IOperationSlotListener::BnOperationSlotListener;
+    use android_system_keystore2::binder::{BinderFeatures, Interface};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct TestListener(Arc<AtomicUsize>);
+
+    impl Interface for TestListener {}
+
+    impl IOperationSlotListener for TestListener {
+        fn onOperationSlotAvailable(&self) -> binder::Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn new_listener() -> (Strong<dyn IOperationSlotListener>, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let listener = BnOperationSlotListener::new_binder(
+            TestListener(count.clone()),
+            BinderFeatures::default(),
+        );
+        (listener, count)
+    }
+
+    #[test]
+    fn freed_slot_notifies_one_listener() -> Result<()> {
+        let slots = OperationSlots::default();
+        let (first, first_count) = new_listener();
+        let (second, second_count) = new_listener();
+        slots.acquire(1);
+        slots.acquire(1);
+        slots.register_listener(2, 1, &first)?;
+        slots.register_listener(3, 1, &second)?;
+
+        slots.release(1);
+        assert_eq!(1, first_count.load(Ordering::Relaxed));
+        assert_eq!(0, second_count.load(Ordering::Relaxed));
+
+        // The first listener was dropped after its notification.
+        slots.release(1);
+        assert_eq!(1, first_count.load(Ordering::Relaxed));
+        assert_eq!(1, second_count.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn listener_waits_for_threshold() -> Result<()> {
+        let slots = OperationSlots::default();
+        let (listener, count) = new_listener();
+        for _ in 0..3 {
+            slots.acquire(1);
+        }
+        slots.register_listener(1, 2, &listener)?;
+
+        // The uid still runs two operations.
+        slots.release(1);
+        assert_eq!(0, count.load(Ordering::Relaxed));
+        slots.release(1);
+        assert_eq!(1, count.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_moves_operation_without_notifying() -> Result<()> {
+        let slots = OperationSlots::default();
+        let (listener, count) = new_listener();
+        slots.acquire(1);
+        slots.register_listener(1, 1, &listener)?;
+
+        slots.transfer(1, 2);
+        assert_eq!(0, count.load(Ordering::Relaxed));
+        // The slot is freed when the new owner ends the operation.
+        slots.release(2);
+        assert_eq!(1, count.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn listeners_per_uid_are_limited() -> Result<()> {
+        let slots = OperationSlots::default();
+        let (listener, _) = new_listener();
+        for _ in 0..MAX_LISTENERS_PER_UID {
+            slots.register_listener(1, 1, &listener)?;
+        }
+        assert!(slots.register_listener(1, 1, &listener).is_err());
+        assert!(slots.register_listener(2, 1, &listener).is_ok());
+        assert!(slots.register_listener(2, 0, &listener).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn retry_hint_follows_slot_pressure() -> Result<()> {
+        let slots = OperationSlots::default();
+        assert_eq!(MIN_RETRY_HINT, slots.retry_hint());
+
+        for _ in 0..8 {
+            slots.acquire(10001);
+        }
+        let (listener, _count) = new_listener();
+        slots.register_listener(10002, 1, &listener)?;
+        slots.register_listener(10002, 1, &listener)?;
+        assert_eq!(RETRY_HINT_PER_OPERATION * 10, slots.retry_hint());
+
+        for _ in 0..1000 {
+            slots.acquire(10003);
+        }
+        assert_eq!(MAX_RETRY_HINT, slots.retry_hint());
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module decides whether keystore uses keys whose OS or vendor patch level is newer than
+//! that of the running image, as happens when a device is rolled back to an older image, e.g.,
+//! in a recovery scenario.
+//!
+//! By default, keystore refuses such keys with `INVALID_KEY_BLOB`, which is what KeyMint
+//! returns for them. OEMs whose devices must keep their keys across rollbacks set the system
+//! property `POLICY_PROPERTY` to "allow", and the policy can be overridden at runtime through
+//! `IKeystoreMaintenance::setPatchLevelPolicy`. The override is stored in the database, so that
+//! it survives the reboot into the older image. The policy is gated by
+//! `Feature::PatchLevelPolicy`.
+//!
+//! Keystore cannot make KeyMint use such a key: KeyMint still refuses it, unless the KeyMint
+//! implementation tolerates patch level rollbacks. So an allowed use is only counted and
+//! written to the security log once KeyMint has begun the operation.
+
+use crate::audit_log::log_key_patch_level_rollback_allowed;
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::error::{Error, ErrorCode};
+use crate::feature_flags::{self, Feature};
+use crate::globals::DB;
+use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::Tag::Tag;
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The system property that holds the configured policy, "refuse" or "allow".
+const POLICY_PROPERTY: &str = "ro.keystore.patch_level_policy";
+
+/// System property that holds the security patch level of the running system.
+const SECURITY_PATCH_PROPERTY: &str = "ro.build.version.security_patch";
+
+/// System property that holds the security patch level of the running vendor image.
+const VENDOR_SECURITY_PATCH_PROPERTY: &str = "ro.vendor.build.security_patch";
+
+/// What keystore does with keys whose patch level is newer than that of the running image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// The keys are refused.
+    #[default]
+    Refuse,
+    /// Keystore does not refuse the keys, and each use that KeyMint begins is audited.
+    Allow,
+}
+
+impl Policy {
+    /// The name of the policy in `POLICY_PROPERTY`, the database, and the dump.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Refuse => "refuse",
+            Self::Allow => "allow",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "refuse" => Some(Self::Refuse),
+            "allow" => Some(Self::Allow),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a security patch property of the format YYYY-MM-DD into YYYYMM, or into YYYYMMDD if
+/// `with_day` is true.
+fn parse_security_patch(patch: &str, with_day: bool) -> Option<i32> {
+    let mut parts = patch.splitn(3, '-').map(|p| p.parse::<i32>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next().flatten());
+    if with_day {
+        Some((year * 100 + month) * 100 + day?)
+    } else {
+        Some(year * 100 + month)
+    }
+}
+
+fn read_patch_level(property: &str, with_day: bool) -> Option<i32> {
+    let patch = match rustutils::system_properties::read(property) {
+        Ok(Some(patch)) => patch,
+        r => {
+            log::warn!("Failed to read {property}: {r:?}");
+            return None;
+        }
+    };
+    let level = parse_security_patch(&patch, with_day);
+    if level.is_none() {
+        log::warn!("Malformed {property}: {patch:?}");
+    }
+    level
+}
+
+/// Returns the OS patch level of the running system in the YYYYMM format of
+/// Tag::OS_PATCHLEVEL, or None if the security patch property cannot be parsed.
+pub fn current_os_patch_level() -> Option<i32> {
+    read_patch_level(SECURITY_PATCH_PROPERTY, false)
+}
+
+/// Returns the first patch level in `params` that is newer than the corresponding patch level
+/// of the running image, along with its tag and the patch level of the image. Patch levels of
+/// the image that are unknown are not compared.
+fn find_newer_patch_level(
+    params: &[KeyParameter],
+    os_patch_level: Option<i32>,
+    vendor_patch_level: Option<i32>,
+) -> Option<(Tag, i32, i32)> {
+    params.iter().find_map(|p| {
+        let (tag, level, current) = match *p.key_parameter_value() {
+            KeyParameterValue::OSPatchLevel(level) => (Tag::OS_PATCHLEVEL, level, os_patch_level?),
+            KeyParameterValue::VendorPatchLevel(level) => {
+                (Tag::VENDOR_PATCHLEVEL, level, vendor_patch_level?)
+            }
+            _ => return None,
+        };
+        (level > current).then_some((tag, level, current))
+    })
+}
+
+/// A use of a key with a newer patch level that the policy allowed. It is audited with
+/// `PatchLevelPolicy::on_allowed_use` once KeyMint has begun the operation.
+#[derive(Debug)]
+pub struct AllowedUse {
+    key_id: i64,
+    uid: u32,
+    tag: Tag,
+    level: i32,
+    current: i32,
+}
+
+/// The dump section of the policy.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "patch_level_policy",
+    header: "Patch level policy (configured, override, allowed, refused):",
+    line: "  <configured>, <override>, <allowed>, <refused>",
+    fields: dump_fields!(
+        configured: "string",
+        override: "string",
+        allowed: "uint64",
+        refused: "uint64",
+    ),
+};
+
+/// The patch level policy. See the module documentation.
+#[derive(Default)]
+pub struct PatchLevelPolicy {
+    /// The policy configured in `POLICY_PROPERTY`.
+    configured: Policy,
+    /// The patch levels of the running image.
+    os_patch_level: Option<i32>,
+    vendor_patch_level: Option<i32>,
+    /// The override from the database. The outer option is None until it has been loaded.
+    overridden: Mutex<Option<Option<Policy>>>,
+    allowed: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl PatchLevelPolicy {
+    /// Creates the policy configured in `POLICY_PROPERTY` for the running image.
+    pub fn from_properties() -> Self {
+        let configured = match rustutils::system_properties::read(POLICY_PROPERTY) {
+            Ok(Some(value)) => Policy::from_name(&value).unwrap_or_else(|| {
+                log::error!("Ignoring invalid {}={:?}.", POLICY_PROPERTY, value);
+                Policy::default()
+            }),
+            Ok(None) => Policy::default(),
+            Err(e) => {
+                log::error!("Failed to read {}: {:?}", POLICY_PROPERTY, e);
+                Policy::default()
+            }
+        };
+        Self {
+            configured,
+            os_patch_level: current_os_patch_level(),
+            vendor_patch_level: read_patch_level(VENDOR_SECURITY_PATCH_PROPERTY, true),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the override of the configured policy, loading it from the database on first
+    /// use.
+    fn overridden(&self) -> Result<Option<Policy>> {
+        let mut overridden = self.overridden.lock().unwrap();
+        if let Some(policy) = *overridden {
+            return Ok(policy);
+        }
+        let name = DB
+            .with(|db| db.borrow_mut().get_patch_level_policy())
+            .context(ks_err!("Failed to load the patch level policy."))?;
+        let policy = name.as_deref().and_then(|name| {
+            Policy::from_name(name).or_else(|| {
+                log::error!("Ignoring invalid stored patch level policy {:?}.", name);
+                None
+            })
+        });
+        *overridden = Some(policy);
+        Ok(policy)
+    }
+
+    /// Returns the policy in effect.
+    pub fn get(&self) -> Result<Policy> {
+        Ok(self.overridden().context(ks_err!())?.unwrap_or(self.configured))
+    }
+
+    /// Overrides the configured policy with `policy`, or restores it if `policy` is None.
+    pub fn set(&self, policy: Option<Policy>) -> Result<()> {
+        let mut overridden = self.overridden.lock().unwrap();
+        DB.with(|db| db.borrow_mut().set_patch_level_policy(policy.map(|p| p.name())))
+            .context(ks_err!("Failed to store the patch level policy."))?;
+        *overridden = Some(policy);
+        Ok(())
+    }
+
+    /// Checks that the key `key_id` with the authorizations `params` may be used by `uid`.
+    /// Returns the use that must be audited if the key has a newer patch level, but the policy
+    /// allows it.
+    pub fn check(
+        &self,
+        key_id: i64,
+        uid: u32,
+        params: &[KeyParameter],
+    ) -> Result<Option<AllowedUse>> {
+        if !feature_flags::is_enabled(Feature::PatchLevelPolicy) {
+            return Ok(None);
+        }
+        let (tag, level, current) =
+            match find_newer_patch_level(params, self.os_patch_level, self.vendor_patch_level) {
+                Some(newer) => newer,
+                None => return Ok(None),
+            };
+        match self.get().context(ks_err!())? {
+            Policy::Refuse => {
+                self.refused.fetch_add(1, Ordering::Relaxed);
+                Err(Error::Km(ErrorCode::INVALID_KEY_BLOB)).context(ks_err!(
+                    "Key {key_id} has {tag:?} {level}, which is newer than {current}."
+                ))
+            }
+            Policy::Allow => Ok(Some(AllowedUse { key_id, uid, tag, level, current })),
+        }
+    }
+
+    /// Counts and audits `allowed_use` after KeyMint has begun the operation.
+    pub fn on_allowed_use(&self, allowed_use: AllowedUse) {
+        let AllowedUse { key_id, uid, tag, level, current } = allowed_use;
+        self.allowed.fetch_add(1, Ordering::Relaxed);
+        log::warn!(
+            "Allowed uid {uid} to use key {key_id} with {tag:?} {level}, which is newer than \
+             {current}."
+        );
+        log_key_patch_level_rollback_allowed(key_id, uid, level, current);
+    }
+
+    /// Returns the number of allowed and refused uses of keys with newer patch levels.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.allowed.load(Ordering::Relaxed), self.refused.load(Ordering::Relaxed))
+    }
+
+    /// Writes the policy and its statistics to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let overridden = match *self.overridden.lock().unwrap() {
+            None => "not loaded",
+            Some(None) => "none",
+            Some(Some(policy)) => policy.name(),
+        };
+        let (allowed, refused) = self.stats();
+        DUMP_SECTION.write_header(writer)?;
+        DUMP_SECTION.write_line(writer, &[&self.configured.name(), &overridden, &allowed, &refused])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+
+    fn kp(value: KeyParameterValue) -> KeyParameter {
+        KeyParameter::new(value, SecurityLevel::TRUSTED_ENVIRONMENT)
+    }
+
+    #[test]
+    fn parses_security_patch() {
+        assert_eq!(Some(202403), parse_security_patch("2024-03-05", false));
+        assert_eq!(Some(20240305), parse_security_patch("2024-03-05", true));
+        assert_eq!(Some(202403), parse_security_patch("2024-03", false));
+        assert_eq!(None, parse_security_patch("2024-03", true));
+        assert_eq!(None, parse_security_patch("March 2024", false));
+    }
+
+    #[test]
+    fn finds_newer_patch_levels() {
+        let params = [
+            kp(KeyParameterValue::OSPatchLevel(202403)),
+            kp(KeyParameterValue::VendorPatchLevel(20240305)),
+        ];
+        assert_eq!(None, find_newer_patch_level(&params, Some(202403), Some(20240305)));
+        assert_eq!(
+            Some((Tag::OS_PATCHLEVEL, 202403, 202402)),
+            find_newer_patch_level(&params, Some(202402), Some(20240305))
+        );
+        assert_eq!(
+            Some((Tag::VENDOR_PATCHLEVEL, 20240305, 20240301)),
+            find_newer_patch_level(&params, Some(202404), Some(20240301))
+        );
+        // Unknown patch levels of the image are not compared.
+        assert_eq!(None, find_newer_patch_level(&params, None, None));
+    }
+
+    #[test]
+    fn policy_names_round_trip() {
+        for policy in [Policy::Refuse, Policy::Allow] {
+            assert_eq!(Some(policy), Policy::from_name(policy.name()));
+        }
+        assert_eq!(None, Policy::from_name("ignore"));
+    }
+}
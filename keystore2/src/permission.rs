@@ -19,18 +19,21 @@
 //! defined by keystore2 and keystore2_key respectively.
 
 use crate::error::Error as KsError;
-use crate::error::ResponseCode;
 use crate::ks_err;
+use crate::namespace::Namespace;
+use crate::utils::is_sdk_sandbox_pair;
 use android_system_keystore2::aidl::android::system::keystore2::{
-    Domain::Domain, KeyDescriptor::KeyDescriptor, KeyPermission::KeyPermission,
+    KeyDescriptor::KeyDescriptor, KeyPermission::KeyPermission,
 };
 use anyhow::Context as AnyhowContext;
 use keystore2_selinux as selinux;
 use lazy_static::lazy_static;
 use selinux::{implement_class, Backend, ClassPermission};
 use std::cmp::PartialEq;
+use std::collections::HashMap;
 use std::convert::From;
 use std::ffi::CStr;
+use std::sync::{Arc, RwLock};
 
 // Replace getcon with a mock in the test situation
 #[cfg(not(test))]
@@ -49,6 +52,86 @@ fn lookup_keystore2_key_context(namespace: i64) -> anyhow::Result<selinux::Conte
     KEYSTORE2_KEY_LABEL_BACKEND.lookup(&namespace.to_string())
 }
 
+lazy_static! {
+    static ref TARGET_CONTEXTS: TargetContextCache = Default::default();
+}
+
+/// An immutable snapshot of the target contexts that permission checks have needed so far.
+#[derive(Default)]
+struct TargetContexts {
+    /// The context of keystore itself, i.e., the target context of `Domain::APP` keys.
+    keystore: Option<Arc<CStr>>,
+    /// The `keystore2_key` contexts by `Domain::SELINUX` namespace.
+    namespaces: HashMap<i64, Arc<CStr>>,
+}
+
+/// Caches the target contexts of permission checks, so that checks do not call into libselinux
+/// and take its global lock more than once for each of them. Neither changes during the lifetime
+/// of keystore, because the key context backend is opened only once.
+///
+/// Readers only clone the `Arc` of the current snapshot under a shared lock and never wait for
+/// a libselinux call. A miss looks up the context without holding any lock and then publishes
+/// a new snapshot that includes it. Snapshots that readers still hold stay valid.
+#[derive(Default)]
+struct TargetContextCache {
+    current: RwLock<Arc<TargetContexts>>,
+}
+
+impl TargetContextCache {
+    fn snapshot(&self) -> Arc<TargetContexts> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Returns the context of keystore itself, calling `get_context` on the first call.
+    fn keystore<F>(&self, get_context: F) -> anyhow::Result<Arc<CStr>>
+    where
+        F: FnOnce() -> anyhow::Result<selinux::Context>,
+    {
+        if let Some(context) = &self.snapshot().keystore {
+            return Ok(context.clone());
+        }
+        let context: Arc<CStr> = Arc::from(&*get_context()?);
+        Ok(self.publish(|contexts| contexts.keystore.get_or_insert(context).clone()))
+    }
+
+    /// Returns the `keystore2_key` context of `namespace`, calling `lookup` on the first call
+    /// for `namespace`.
+    fn namespace<F>(&self, namespace: i64, lookup: F) -> anyhow::Result<Arc<CStr>>
+    where
+        F: FnOnce(i64) -> anyhow::Result<selinux::Context>,
+    {
+        if let Some(context) = self.snapshot().namespaces.get(&namespace) {
+            return Ok(context.clone());
+        }
+        let context: Arc<CStr> = Arc::from(&*lookup(namespace)?);
+        Ok(self.publish(|contexts| contexts.namespaces.entry(namespace).or_insert(context).clone()))
+    }
+
+    /// Replaces the current snapshot with a copy that `update` was applied to. If another
+    /// thread published the same context in the meantime, `update` keeps that one.
+    fn publish<F>(&self, update: F) -> Arc<CStr>
+    where
+        F: FnOnce(&mut TargetContexts) -> Arc<CStr>,
+    {
+        let mut current = self.current.write().unwrap();
+        let mut contexts = TargetContexts {
+            keystore: current.keystore.clone(),
+            namespaces: current.namespaces.clone(),
+        };
+        let context = update(&mut contexts);
+        *current = Arc::new(contexts);
+        context
+    }
+}
+
+fn keystore_context() -> anyhow::Result<Arc<CStr>> {
+    TARGET_CONTEXTS.keystore(getcon)
+}
+
+fn keystore2_key_context(namespace: i64) -> anyhow::Result<Arc<CStr>> {
+    TARGET_CONTEXTS.namespace(namespace, lookup_keystore2_key_context)
+}
+
 implement_class!(
     /// KeyPerm provides a convenient abstraction from the SELinux class `keystore2_key`.
     /// At the same time it maps `KeyPermissions` from the Keystore 2.0 AIDL Grant interface to
@@ -149,6 +232,71 @@ implement_class!(
         /// Checked on calls to IRemotelyProvisionedKeyPool::getAttestationKey
         #[selinux(name = get_attestation_key)]
         GetAttestationKey,
+        /// Checked when IKeystoreMaintenance::onUidStateChanged is called.
+        #[selinux(name = report_uid_state)]
+        ReportUidState,
+        /// Checked when IKeystoreMaintenance::runGarbageCollection is called.
+        #[selinux(name = run_gc)]
+        RunGc,
+        /// Checked when the Keystore 2.0 service is asked to dump its state, or when
+        /// IKeystoreMaintenance::getAttestationApplicationId is called.
+        #[selinux(name = dump)]
+        Dump,
+        /// Checked when IKeystoreMaintenance::adoptAppKey is called.
+        #[selinux(name = adopt_app_key)]
+        AdoptAppKey,
+        /// Checked when IKeystoreMaintenance::exportKeyForBackup is called.
+        #[selinux(name = export_for_backup)]
+        ExportForBackup,
+        /// Checked when the super key escrow of a user is provisioned, cleared, or used for
+        /// recovery through IKeystoreMaintenance.
+        #[selinux(name = manage_super_key_escrow)]
+        ManageSuperKeyEscrow,
+        /// Checked when IKeystoreMaintenance::getKeyHistory is called.
+        #[selinux(name = get_key_history)]
+        GetKeyHistory,
+        /// Checked when IKeystoreMaintenance::listKeysRequiringMaintenance,
+        /// IKeystoreMaintenance::auditBlobChecksums, or one of the maintenance listener calls,
+        /// i.e., registerMaintenanceListener, unregisterMaintenanceListener, and
+        /// waitForQuiescence, is called.
+        #[selinux(name = list_keys_for_maintenance)]
+        ListKeysForMaintenance,
+        /// Checked when vold registers, fetches, or rotates its storage key blobs through
+        /// IKeystoreMaintenance.
+        #[selinux(name = manage_storage_keys)]
+        ManageStorageKeys,
+        /// Checked when a platform component stores, loads, removes, or lists its credential
+        /// blobs through ICredentialStore.
+        #[selinux(name = manage_credentials)]
+        ManageCredentials,
+        /// Checked when virtualizationservice deletes the keys of a protected VM instance
+        /// through IKeystoreMaintenance::deleteVmInstanceKeys.
+        #[selinux(name = delete_vm_instance_keys)]
+        DeleteVmInstanceKeys,
+        /// Checked when device policy limits the validity of new keys of a user through
+        /// IKeystoreMaintenance::setMaxKeyValidity.
+        #[selinux(name = set_key_validity_policy)]
+        SetKeyValidityPolicy,
+        /// Checked when a system component parses an attestation certificate through
+        /// IKeystoreMaintenance::parseAttestationExtension.
+        #[selinux(name = parse_attestation)]
+        ParseAttestation,
+        /// Checked when an operation is handed over to another uid through
+        /// IKeystoreSecurityLevelExtension::transferOperation.
+        #[selinux(name = transfer_operation)]
+        TransferOperation,
+        /// Checked when a factory audit tool exports the characteristics of the keys in SELinux
+        /// namespaces through IKeystoreMaintenance::exportKeyCharacteristics.
+        #[selinux(name = export_key_characteristics)]
+        ExportKeyCharacteristics,
+        /// Checked when the keys of the software Keymaster emulator are migrated to the restored
+        /// TEE KeyMint through IKeystoreMaintenance::migrateSoftwareKeys.
+        #[selinux(name = migrate_software_keys)]
+        MigrateSoftwareKeys,
+        /// Checked when the patch level policy is set or queried through
+        /// IKeystoreMaintenance::setPatchLevelPolicy or IKeystoreMaintenance::getPatchLevelPolicy.
+        #[selinux(name = manage_patch_level_policy)]
+        ManagePatchLevelPolicy,
     }
 );
 
@@ -263,7 +411,7 @@ impl IntoIterator for KeyPermSet {
 /// Uses `selinux::check_permission` to check if the given caller context `caller_cxt` may access
 /// the given permision `perm` of the `keystore2` security class.
 pub fn check_keystore_permission(caller_ctx: &CStr, perm: KeystorePerm) -> anyhow::Result<()> {
-    let target_context = getcon().context("check_keystore_permission: getcon failed.")?;
+    let target_context = keystore_context().context("check_keystore_permission: getcon failed.")?;
     selinux::check_permission(caller_ctx, &target_context, perm)
 }
 
@@ -285,9 +433,13 @@ pub fn check_grant_permission(
     access_vec: KeyPermSet,
     key: &KeyDescriptor,
 ) -> anyhow::Result<()> {
-    let target_context = match key.domain {
-        Domain::APP => getcon().context("check_grant_permission: getcon failed.")?,
-        Domain::SELINUX => lookup_keystore2_key_context(key.nspace)
+    let target_context = match Namespace::new(key.domain, key.nspace)
+        .context("check_grant_permission: Invalid key descriptor.")?
+    {
+        Namespace::App(_) => {
+            keystore_context().context("check_grant_permission: getcon failed.")?
+        }
+        Namespace::SeLinux(nspace) => keystore2_key_context(nspace)
             .context("check_grant_permission: Domain::SELINUX: Failed to lookup namespace.")?,
         _ => return Err(KsError::sys()).context(format!("Cannot grant {:?}.", key.domain)),
     };
@@ -309,6 +461,19 @@ pub fn check_grant_permission(
     Ok(())
 }
 
+/// Checks that a key owned by the `Domain::APP` namespace in `key` is not granted across the
+/// boundary between an app and its SDK sandbox. Keys created by an SDK running in the sandbox
+/// must not become visible to the host app and vice versa.
+pub fn check_sdk_sandbox_grant(key: &KeyDescriptor, grantee_uid: u32) -> anyhow::Result<()> {
+    match Namespace::new(key.domain, key.nspace) {
+        Ok(Namespace::App(owner_uid)) if is_sdk_sandbox_pair(owner_uid, grantee_uid) => {
+            Err(selinux::Error::perm())
+                .context(ks_err!("Cannot grant keys between an app and its SDK sandbox."))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Uses `selinux::check_permission` to check if the given caller context `caller_cxt`
 /// has the permissions indicated by `perm` for the target domain indicated by the key
 /// descriptor `key` in the security class `keystore2_key`.
@@ -322,6 +487,9 @@ pub fn check_grant_permission(
 ///  * `Domain::GRANT` Does not use selinux::check_permission. Instead the `access_vector`
 ///                    parameter is queried for permission, which must be supplied in this case.
 ///
+/// Access to a `Domain::APP` key is always denied if the caller and the owner are an app and
+/// its SDK sandbox, regardless of any access vector.
+///
 /// ## Return values.
 ///  * Ok(()) If the requested permissions were granted.
 ///  * Err(selinux::Error::perm()) If the requested permissions were denied.
@@ -335,6 +503,17 @@ pub fn check_key_permission(
     key: &KeyDescriptor,
     access_vector: &Option<KeyPermSet>,
 ) -> anyhow::Result<()> {
+    let namespace = Namespace::new(key.domain, key.nspace).context(ks_err!())?;
+
+    // SDK sandbox processes and their host apps must not see each other's keys, not even by
+    // way of a grant that predates this restriction.
+    if let Namespace::App(owner_uid) = namespace {
+        if is_sdk_sandbox_pair(caller_uid, owner_uid) {
+            return Err(selinux::Error::perm())
+                .context(ks_err!("SDK sandbox and host app namespaces are isolated."));
+        }
+    }
+
     // If an access vector was supplied, the key is either accessed by GRANT or by KEY_ID.
     // In the former case, key.domain was set to GRANT and we check the failure cases
     // further below. If the access is requested by KEY_ID, key.domain would have been
@@ -348,18 +527,18 @@ pub fn check_key_permission(
         }
     }
 
-    let target_context = match key.domain {
+    let target_context = match namespace {
         // apps get the default keystore context
-        Domain::APP => {
-            if caller_uid as i64 != key.nspace {
+        Namespace::App(owner_uid) => {
+            if caller_uid != owner_uid {
                 return Err(selinux::Error::perm())
                     .context("Trying to access key without ownership.");
             }
-            getcon().context(ks_err!("getcon failed."))?
+            keystore_context().context(ks_err!("getcon failed."))?
         }
-        Domain::SELINUX => lookup_keystore2_key_context(key.nspace)
+        Namespace::SeLinux(nspace) => keystore2_key_context(nspace)
             .context(ks_err!("Domain::SELINUX: Failed to lookup namespace."))?,
-        Domain::GRANT => {
+        Namespace::Grant(_) => {
             match access_vector {
                 Some(_) => {
                     return Err(selinux::Error::perm())
@@ -373,15 +552,15 @@ pub fn check_key_permission(
                 }
             }
         }
-        Domain::KEY_ID => {
+        Namespace::KeyId(_) => {
             // We should never be called with `Domain::KEY_ID. The database
             // lookup should have converted this into one of `Domain::APP`
             // or `Domain::SELINUX`.
             return Err(KsError::sys())
                 .context(ks_err!("Cannot check permission for Domain::KEY_ID.",));
         }
-        Domain::BLOB => {
-            let tctx = lookup_keystore2_key_context(key.nspace)
+        Namespace::Blob(nspace) => {
+            let tctx = keystore2_key_context(nspace)
                 .context(ks_err!("Domain::BLOB: Failed to lookup namespace."))?;
             // If DOMAIN_KEY_BLOB was specified, we check for the "manage_blob"
             // permission in addition to the requested permission.
@@ -389,10 +568,6 @@ pub fn check_key_permission(
 
             tctx
         }
-        _ => {
-            return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
-                .context(format!("Unknown domain value: \"{:?}\".", key.domain))
-        }
     };
 
     selinux::check_permission(caller_ctx, &target_context, perm)
@@ -401,6 +576,7 @@ pub fn check_key_permission(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
     use anyhow::anyhow;
     use anyhow::Result;
     use keystore2_selinux::*;
@@ -633,6 +809,53 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn check_key_permission_domain_app_sdk_sandbox() -> Result<()> {
+        let system_server_ctx = Context::new("u:r:system_server:s0")?;
+        let app_key = KeyDescriptor { domain: Domain::APP, nspace: 10123, alias: None, blob: None };
+        let sandbox_key =
+            KeyDescriptor { domain: Domain::APP, nspace: 20123, alias: None, blob: None };
+
+        // The SDK sandbox cannot use the host app's key, even with a grant.
+        assert_perm_failed!(check_key_permission(
+            20123,
+            &system_server_ctx,
+            KeyPerm::Use,
+            &app_key,
+            &Some(key_perm_set![KeyPerm::Use])
+        ));
+        // And the host app cannot use the SDK sandbox's key.
+        assert_perm_failed!(check_key_permission(
+            10123,
+            &system_server_ctx,
+            KeyPerm::Use,
+            &sandbox_key,
+            &Some(key_perm_set![KeyPerm::Use])
+        ));
+        // Unrelated apps are still governed by the access vector.
+        assert!(check_key_permission(
+            10124,
+            &system_server_ctx,
+            KeyPerm::Use,
+            &app_key,
+            &Some(key_perm_set![KeyPerm::Use])
+        )
+        .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn check_sdk_sandbox_grant_test() {
+        let app_key = KeyDescriptor { domain: Domain::APP, nspace: 10123, alias: None, blob: None };
+        let selinux_key =
+            KeyDescriptor { domain: Domain::SELINUX, nspace: 10123, alias: None, blob: None };
+
+        assert_perm_failed!(check_sdk_sandbox_grant(&app_key, 20123));
+        assert!(check_sdk_sandbox_grant(&app_key, 20124).is_ok());
+        assert!(check_sdk_sandbox_grant(&app_key, 10124).is_ok());
+        assert!(check_sdk_sandbox_grant(&selinux_key, 20123).is_ok());
+    }
+
     #[test]
     fn check_key_permission_domain_selinux() -> Result<()> {
         let (sctx, namespace, is_su) = check_context()?;
@@ -826,4 +1049,76 @@ mod tests {
         assert!(!v1.includes(v2));
         assert!(!v2.includes(v1));
     }
+
+    #[test]
+    fn target_context_cache_looks_up_once() -> Result<()> {
+        let cache = TargetContextCache::default();
+        let lookups = std::cell::Cell::new(0);
+        let lookup = |nspace: i64| {
+            lookups.set(lookups.get() + 1);
+            Context::new(&format!("u:object_r:test_key_{}:s0", nspace))
+        };
+
+        assert_eq!(&*cache.namespace(1, lookup)?, &*Context::new("u:object_r:test_key_1:s0")?);
+        let old_snapshot = cache.snapshot();
+        assert_eq!(&*cache.namespace(2, lookup)?, &*Context::new("u:object_r:test_key_2:s0")?);
+        assert_eq!(&*cache.namespace(1, lookup)?, &*Context::new("u:object_r:test_key_1:s0")?);
+        assert_eq!(2, lookups.get());
+        // Snapshots are never modified once published.
+        assert_eq!(1, old_snapshot.namespaces.len());
+
+        // Failed lookups are not cached.
+        assert!(cache.namespace(3, |_| Err(anyhow!("lookup failed"))).is_err());
+        assert!(!cache.snapshot().namespaces.contains_key(&3));
+
+        assert_eq!(&*cache.keystore(test_getcon)?, &*test_getcon()?);
+        assert_eq!(
+            &*cache.keystore(|| panic!("The keystore context must be cached."))?,
+            &*test_getcon()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn target_context_cache_concurrent_access() {
+        const THREADS: usize = 8;
+        const NAMESPACES: i64 = 16;
+
+        let cache = Arc::new(TargetContextCache::default());
+        let barrier = Arc::new(std::sync::Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let cache = cache.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    for i in 0..1000 {
+                        // Let the threads walk the namespaces in different orders, so that
+                        // misses and publications interleave with reads.
+                        let nspace = (i + t as i64) % NAMESPACES;
+                        let context = cache
+                            .namespace(nspace, |n| {
+                                Context::new(&format!("u:object_r:test_key_{}:s0", n))
+                            })
+                            .unwrap();
+                        assert_eq!(
+                            context.to_str().unwrap(),
+                            format!("u:object_r:test_key_{}:s0", nspace)
+                        );
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Racing misses must neither lose nor replace published contexts.
+        let snapshot = cache.snapshot();
+        assert_eq!(NAMESPACES as usize, snapshot.namespaces.len());
+        for nspace in 0..NAMESPACES {
+            let first = cache.namespace(nspace, |_| panic!("Context must be cached.")).unwrap();
+            assert!(Arc::ptr_eq(&first, &snapshot.namespaces[&nspace]));
+        }
+    }
 }
@@ -0,0 +1,186 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module files post-mortem reports of fatal internal errors with the DropBox of the system
+//! server, so that they show up in field feedback rather than only in logcat.
+//!
+//! Reports are redacted by construction. A report consists of the event, the name of the
+//! affected keystore component, e.g., a lock or a database table, and a list of integer fields
+//! with static names. Callers must never pass client provided data such as aliases as subject.
+//!
+//! Each event is reported at most once per `MIN_REPORT_INTERVAL` and at most
+//! `MAX_REPORTS_PER_EVENT` times per boot. The next report of an event includes the number of
+//! reports that were suppressed in between. Reports are filed by a dedicated thread, because
+//! filing blocks until the system server is up and the reporting code path may hold locks.
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// DropBox tag of the reports.
+const DROPBOX_TAG: &str = "keystore2_post_mortem";
+
+/// Minimum time between two reports of the same event.
+const MIN_REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum number of reports of the same event per boot.
+const MAX_REPORTS_PER_EVENT: u32 = 5;
+
+/// Fatal internal errors that are reported to DropBox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FatalEvent {
+    /// A thread panicked while holding a lock.
+    LockPoisoned,
+    /// SQLite found the database file to be malformed.
+    DatabaseCorrupted,
+    /// A blob did not match the checksum stored with it.
+    BlobChecksumMismatch,
+    /// A blob did not match its shadow in the blob encoding that is soaking.
+    ShadowBlobMismatch,
+    /// The database was not bound to this device and was set aside.
+    DatabaseBindingMismatch,
+}
+
+#[derive(Debug)]
+struct EventState {
+    last_report: Instant,
+    reports: u32,
+    suppressed: u64,
+}
+
+/// Decides which events are reported.
+#[derive(Debug, Default)]
+struct RateLimiter {
+    events: HashMap<FatalEvent, EventState>,
+}
+
+impl RateLimiter {
+    /// Returns the number of suppressed reports since the last report of `event` if `event`
+    /// should be reported at `now`, and None otherwise.
+    fn admit(&mut self, event: FatalEvent, now: Instant) -> Option<u64> {
+        match self.events.get_mut(&event) {
+            None => {
+                self.events
+                    .insert(event, EventState { last_report: now, reports: 1, suppressed: 0 });
+                Some(0)
+            }
+            Some(state) => {
+                if state.reports >= MAX_REPORTS_PER_EVENT
+                    || now.saturating_duration_since(state.last_report) < MIN_REPORT_INTERVAL
+                {
+                    state.suppressed += 1;
+                    return None;
+                }
+                state.last_report = now;
+                state.reports += 1;
+                Some(std::mem::take(&mut state.suppressed))
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref RATE_LIMITER: Mutex<RateLimiter> = Default::default();
+    /// Sends reports to the reporter thread, or None if the thread could not be started.
+    static ref REPORTER: Mutex<Option<Sender<String>>> = Mutex::new(start_reporter());
+}
+
+fn start_reporter() -> Option<Sender<String>> {
+    let (sender, receiver) = channel::<String>();
+    let result =
+        std::thread::Builder::new().name("keystore2_post_mortem".to_string()).spawn(move || {
+            for report in receiver {
+                if !keystore2_dropbox::add_text(DROPBOX_TAG, &report) {
+                    log::error!("Failed to add post-mortem report to DropBox:\n{}", report);
+                }
+            }
+        });
+    match result {
+        Ok(_) => Some(sender),
+        Err(e) => {
+            log::error!("Failed to start post-mortem reporter: {:?}", e);
+            None
+        }
+    }
+}
+
+fn format_report(
+    event: FatalEvent,
+    subject: &str,
+    fields: &[(&'static str, i64)],
+    suppressed: u64,
+) -> String {
+    let mut report = String::new();
+    // Writing to a String cannot fail.
+    let _ = writeln!(report, "event: {:?}", event);
+    let _ = writeln!(report, "subject: {}", subject);
+    for (name, value) in fields {
+        let _ = writeln!(report, "{}: {}", name, value);
+    }
+    let _ = writeln!(report, "suppressed_reports: {}", suppressed);
+    report
+}
+
+/// Reports `event`, which affected the keystore component `subject`, to DropBox, unless it was
+/// reported too recently or too often. `subject` and `fields` must not contain client data.
+pub fn report(event: FatalEvent, subject: &str, fields: &[(&'static str, i64)]) {
+    // The reporter must keep working after a panic, so poisoned locks are recovered.
+    let suppressed =
+        match RATE_LIMITER.lock().unwrap_or_else(|e| e.into_inner()).admit(event, Instant::now()) {
+            Some(suppressed) => suppressed,
+            None => return,
+        };
+    let report = format_report(event, subject, fields, suppressed);
+    log::error!("Filing post-mortem report:\n{}", report);
+    if let Some(sender) = REPORTER.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        if sender.send(report).is_err() {
+            log::error!("Post-mortem reporter is gone.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_are_rate_limited() {
+        let mut limiter = RateLimiter::default();
+        let start = Instant::now();
+        assert_eq!(Some(0), limiter.admit(FatalEvent::LockPoisoned, start));
+        assert_eq!(None, limiter.admit(FatalEvent::LockPoisoned, start));
+        assert_eq!(None, limiter.admit(FatalEvent::LockPoisoned, start + MIN_REPORT_INTERVAL / 2));
+        // Events are limited independently.
+        assert_eq!(Some(0), limiter.admit(FatalEvent::DatabaseCorrupted, start));
+        assert_eq!(Some(2), limiter.admit(FatalEvent::LockPoisoned, start + MIN_REPORT_INTERVAL));
+
+        let mut now = start + MIN_REPORT_INTERVAL;
+        for _ in 2..MAX_REPORTS_PER_EVENT {
+            now += MIN_REPORT_INTERVAL;
+            assert_eq!(Some(0), limiter.admit(FatalEvent::LockPoisoned, now));
+        }
+        assert_eq!(None, limiter.admit(FatalEvent::LockPoisoned, now + MIN_REPORT_INTERVAL * 10));
+    }
+
+    #[test]
+    fn report_contains_only_given_fields() {
+        assert_eq!(
+            "event: BlobChecksumMismatch\nsubject: blobentry\nblob_id: 42\nsuppressed_reports: 3\n",
+            format_report(FatalEvent::BlobChecksumMismatch, "blobentry", &[("blob_id", 42)], 3)
+        );
+    }
+}
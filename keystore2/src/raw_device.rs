@@ -170,6 +170,17 @@ impl KeyMintDevice {
         }
     }
 
+    /// Look up the id of an internal-use key in the database, and return None if it is absent.
+    pub fn lookup_key_id(
+        db: &mut KeystoreDB,
+        key_desc: &KeyDescriptor,
+        key_type: KeyType,
+    ) -> Result<Option<i64>> {
+        Self::not_found_is_none(Self::lookup_from_desc(db, key_desc, key_type))
+            .map(|lookup| lookup.map(|(key_id_guard, _)| key_id_guard.id()))
+            .context(ks_err!("lookup failed"))
+    }
+
     /// This does the lookup and store in separate transactions; caller must
     /// hold a lock before calling.
     pub fn lookup_or_generate_key<F>(
@@ -321,4 +332,39 @@ impl KeyMintDevice {
         })
         .context(ks_err!("Failed to finish operation."))
     }
+
+    /// Generate a KM key that is not stored in the database, and return its key blob. The
+    /// caller keeps the key blob in memory, so the key is lost when keystore restarts.
+    pub fn generate_transient_key(&self, params: &[KeyParameter]) -> Result<Vec<u8>> {
+        let creation_result = map_km_error({
+            let _wp = wd::watch_millis("In generate_transient_key: calling: generateKey", 500);
+            self.km_dev.generateKey(params, None)
+        })
+        .context(ks_err!("generateKey failed"))?;
+        Ok(creation_result.keyBlob)
+    }
+
+    /// Use a key blob from [`Self::generate_transient_key`] in an operation that can be done
+    /// with a call to begin followed by a call to finish. The key blob is never upgraded, since
+    /// it does not outlive the KeyMint instance that created it.
+    pub fn use_transient_key_in_one_step(
+        &self,
+        key_blob: &[u8],
+        purpose: KeyPurpose,
+        operation_parameters: &[KeyParameter],
+        input: &[u8],
+    ) -> Result<Vec<u8>> {
+        let begin_result = map_km_error({
+            let _wp = wd::watch_millis("In use_transient_key_in_one_step: calling: begin", 500);
+            self.km_dev.begin(purpose, key_blob, operation_parameters, None)
+        })
+        .context(ks_err!("Failed to begin operation."))?;
+        let operation: Strong<dyn IKeyMintOperation> =
+            begin_result.operation.ok_or_else(Error::sys).context(ks_err!("Operation missing"))?;
+        map_km_error({
+            let _wp = wd::watch_millis("In use_transient_key_in_one_step: calling: finish", 500);
+            operation.finish(Some(input), None, None, None, None)
+        })
+        .context(ks_err!("Failed to finish operation."))
+    }
 }
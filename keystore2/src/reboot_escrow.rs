@@ -0,0 +1,317 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the resume-on-reboot copy of a user's AfterFirstUnlock super key.
+//! Before an OTA reboot, LockSettings arms resume-on-reboot with a random secret that its
+//! RebootEscrow HAL keeps across the reboot. The super key is encrypted with a key derived from
+//! the secret and stored as a super key entry of its own, with `EncryptedBy::RebootEscrow`.
+//! After the reboot, LockSettings hands the secret back to unlock the super key without waiting
+//! for the user's LSKF.
+//!
+//! The secret is random, so the key is derived with HKDF instead of a password KDF. The copy is
+//! deleted when it is used, so it unlocks the user for a single boot.
+//!
+//! The encrypted copy is sealed once more with a KeyMint key of its own, see `Sealer`. The
+//! KeyMint key is deleted along with the copy, so that someone who kept the secret and a copy of
+//! the database cannot unlock the user later. A copy also expires after `LIFETIME`. The feature
+//! is gated by `Feature::RebootEscrow`.
+
+use crate::database::{
+    BlobMetaData, BlobMetaEntry, DateTime, EncryptedBy, KeyEntryLoadBits, KeyType, KeystoreDB,
+};
+use crate::error::{Error, ResponseCode};
+use crate::key_parameter::KeyParameterValue;
+use crate::ks_err;
+use crate::raw_device::KeyMintDevice;
+use crate::super_key::{SuperEncryptionAlgorithm, SuperKeyType};
+use crate::utils::AID_KEYSTORE;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, KeyParameter::KeyParameter as KmKeyParameter,
+    KeyPurpose::KeyPurpose, PaddingMode::PaddingMode, SecurityLevel::SecurityLevel,
+};
+use android_system_keystore2::aidl::android::system::keystore2::KeyDescriptor::KeyDescriptor;
+use anyhow::{Context, Result};
+use keystore2_crypto::{
+    aes_gcm_decrypt, aes_gcm_encrypt, generate_random_data, generate_salt, hkdf_expand,
+    hkdf_extract, ZVec, AES_256_KEY_LENGTH,
+};
+use std::time::Duration;
+
+/// The user's AfterFirstUnlock super key encrypted with the resume-on-reboot secret. The blob
+/// metadata holds the salt, iv, and tag of the encryption.
+pub const USER_SUPER_KEY_REBOOT_ESCROW: SuperKeyType = SuperKeyType {
+    alias: "USER_SUPER_KEY_REBOOT_ESCROW",
+    algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+};
+
+/// The minimum length of the resume-on-reboot secret in bytes.
+const MIN_SECRET_LENGTH: usize = 16;
+
+/// Separates the keys derived from the secret from other uses of it.
+const INFO: &[u8] = b"keystore2 reboot escrow v1";
+
+/// How long an armed copy stays usable. LockSettings arms resume-on-reboot shortly before the
+/// reboot, so an older copy was not used for the reboot that it was armed for.
+pub const LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns true if a copy that was armed at `armed` has expired at `now`. The wall clock is used,
+/// because the copy has to outlive a reboot. A copy armed in the future has expired, too.
+pub fn is_expired(armed: DateTime, now: DateTime) -> bool {
+    let age = now.to_millis_epoch() - armed.to_millis_epoch();
+    !(0..=LIFETIME.as_millis() as i64).contains(&age)
+}
+
+/// Seals the encrypted copy with a key that is destroyed by `destroy_sealing_key`.
+pub trait Sealer {
+    /// Creates a new sealing key for `user_id` that expires at `expires`, replacing any previous
+    /// one, and returns `data` sealed with it.
+    fn seal(
+        &self,
+        db: &mut KeystoreDB,
+        user_id: u32,
+        data: &[u8],
+        expires: DateTime,
+    ) -> Result<Vec<u8>>;
+    /// Returns the data that `sealed` holds. Fails if the sealing key of `user_id` was destroyed.
+    fn unseal(&self, db: &mut KeystoreDB, user_id: u32, sealed: &[u8]) -> Result<Vec<u8>>;
+}
+
+fn sealing_key_desc(user_id: u32) -> KeyDescriptor {
+    KeyMintDevice::internal_descriptor(format!("reboot_escrow_key_{}", user_id))
+}
+
+/// Deletes the sealing key of `user_id`, if any, which makes its sealed copy useless.
+pub fn destroy_sealing_key(db: &mut KeystoreDB, user_id: u32) -> Result<()> {
+    match db.unbind_key(&sealing_key_desc(user_id), KeyType::Client, AID_KEYSTORE, |_, _| Ok(())) {
+        Err(e) => match e.root_cause().downcast_ref::<Error>() {
+            Some(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => Ok(()),
+            _ => Err(e).context(ks_err!()),
+        },
+        Ok(()) => Ok(()),
+    }
+}
+
+/// A [`Sealer`] that encrypts with an AES-GCM key of the TEE KeyMint instance. The key is stored
+/// as an internal key of keystore, so that it survives the reboot.
+pub struct KeyMintSealer;
+
+impl KeyMintSealer {
+    const NONCE_LENGTH: usize = 12;
+    const MAC_LENGTH: i32 = 128;
+
+    fn operation_params(nonce: &[u8]) -> Vec<KmKeyParameter> {
+        vec![
+            KeyParameterValue::BlockMode(BlockMode::GCM).into(),
+            KeyParameterValue::PaddingMode(PaddingMode::NONE).into(),
+            KeyParameterValue::Nonce(nonce.to_vec()).into(),
+            KeyParameterValue::MacLength(Self::MAC_LENGTH).into(),
+        ]
+    }
+}
+
+impl Sealer for KeyMintSealer {
+    /// The sealed data is the nonce followed by the ciphertext and the tag.
+    fn seal(
+        &self,
+        db: &mut KeystoreDB,
+        user_id: u32,
+        data: &[u8],
+        expires: DateTime,
+    ) -> Result<Vec<u8>> {
+        destroy_sealing_key(db, user_id).context(ks_err!("Failed to delete old sealing key."))?;
+        let km_dev = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context(ks_err!("KeyMintDevice::get failed"))?;
+        // KeyMint enforces the expiry if it has a clock. Keystore checks it either way.
+        let params: Vec<KmKeyParameter> = vec![
+            KeyParameterValue::Algorithm(Algorithm::AES).into(),
+            KeyParameterValue::KeySize(256).into(),
+            KeyParameterValue::BlockMode(BlockMode::GCM).into(),
+            KeyParameterValue::PaddingMode(PaddingMode::NONE).into(),
+            KeyParameterValue::CallerNonce.into(),
+            KeyParameterValue::MinMacLength(Self::MAC_LENGTH).into(),
+            KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT).into(),
+            KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT).into(),
+            KeyParameterValue::UsageExpireDateTime(expires.to_millis_epoch()).into(),
+            KeyParameterValue::NoAuthRequired.into(),
+        ];
+        let (key_id_guard, key_blob) = km_dev
+            .lookup_or_generate_key(
+                db,
+                &sealing_key_desc(user_id),
+                KeyType::Client,
+                &params,
+                |_| true,
+            )
+            .context(ks_err!("Failed to generate sealing key."))?;
+        let mut sealed =
+            generate_random_data(Self::NONCE_LENGTH).context(ks_err!("Failed to get nonce."))?;
+        let ciphertext = km_dev
+            .use_key_in_one_step(
+                db,
+                &key_id_guard,
+                &key_blob,
+                KeyPurpose::ENCRYPT,
+                &Self::operation_params(&sealed),
+                None,
+                data,
+            )
+            .context(ks_err!("Failed to seal."))?;
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn unseal(&self, db: &mut KeystoreDB, user_id: u32, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < Self::NONCE_LENGTH {
+            return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context(ks_err!("Sealed copy is too short."));
+        }
+        let (nonce, ciphertext) = sealed.split_at(Self::NONCE_LENGTH);
+        let km_dev = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context(ks_err!("KeyMintDevice::get failed"))?;
+        let (key_id_guard, key_entry) = db
+            .load_key_entry(
+                &sealing_key_desc(user_id),
+                KeyType::Client,
+                KeyEntryLoadBits::KM,
+                AID_KEYSTORE,
+                |_, _| Ok(()),
+            )
+            .context(ks_err!("Failed to load sealing key."))?;
+        let (key_blob, _) = key_entry
+            .key_blob_info()
+            .as_ref()
+            .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            .context(ks_err!("Sealing key has no blob."))?;
+        km_dev
+            .use_key_in_one_step(
+                db,
+                &key_id_guard,
+                key_blob,
+                KeyPurpose::DECRYPT,
+                &Self::operation_params(nonce),
+                None,
+                ciphertext,
+            )
+            .context(ks_err!("Failed to unseal."))
+    }
+}
+
+fn derive_key(escrow_secret: &[u8], salt: &[u8]) -> Result<ZVec> {
+    if escrow_secret.len() < MIN_SECRET_LENGTH {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Escrow secret too short: {} bytes.", escrow_secret.len()));
+    }
+    let prk = hkdf_extract(escrow_secret, salt).context(ks_err!("Failed to extract key."))?;
+    hkdf_expand(AES_256_KEY_LENGTH, &prk, INFO).context(ks_err!("Failed to expand key."))
+}
+
+/// Encrypts `super_key` with a key derived from `escrow_secret`. Returns the blob and blob
+/// metadata of the resume-on-reboot entry.
+pub fn wrap(super_key: &[u8], escrow_secret: &[u8]) -> Result<(Vec<u8>, BlobMetaData)> {
+    let salt = generate_salt().context(ks_err!("Failed to generate salt."))?;
+    let key = derive_key(escrow_secret, &salt).context(ks_err!())?;
+    let (wrapped_key, iv, aead_tag) =
+        aes_gcm_encrypt(super_key, &key).context(ks_err!("Failed to encrypt super key."))?;
+    let mut blob_metadata = BlobMetaData::new();
+    blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::RebootEscrow));
+    blob_metadata.add(BlobMetaEntry::Salt(salt));
+    blob_metadata.add(BlobMetaEntry::Iv(iv));
+    blob_metadata.add(BlobMetaEntry::AeadTag(aead_tag));
+    Ok((wrapped_key, blob_metadata))
+}
+
+/// Decrypts the super key from a resume-on-reboot entry's blob and blob metadata with
+/// `escrow_secret`. Returns `INVALID_ARGUMENT` if the secret does not belong to the entry.
+pub fn unwrap(
+    wrapped_key: &[u8],
+    blob_metadata: &BlobMetaData,
+    escrow_secret: &[u8],
+) -> Result<ZVec> {
+    let (salt, iv, aead_tag) = match (
+        blob_metadata.encrypted_by(),
+        blob_metadata.salt(),
+        blob_metadata.iv(),
+        blob_metadata.aead_tag(),
+    ) {
+        (Some(EncryptedBy::RebootEscrow), Some(salt), Some(iv), Some(aead_tag)) => {
+            (salt, iv, aead_tag)
+        }
+        _ => {
+            return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context(ks_err!("Reboot escrow entry has incomplete metadata."));
+        }
+    };
+    let key = derive_key(escrow_secret, salt).context(ks_err!())?;
+    aes_gcm_decrypt(wrapped_key, iv, aead_tag, &key)
+        .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        .context(ks_err!("Escrow secret does not decrypt the super key."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_crypto::{generate_aes256_key, generate_random_data};
+
+    fn is_error(result: Result<ZVec>, rc: ResponseCode) -> bool {
+        result.unwrap_err().root_cause().downcast_ref::<Error>() == Some(&Error::Rc(rc))
+    }
+
+    #[test]
+    fn wrap_unwrap_roundtrip() -> Result<()> {
+        let super_key = generate_aes256_key()?;
+        let secret = generate_random_data(32)?;
+        let (wrapped_key, blob_metadata) = wrap(&super_key, &secret)?;
+        assert_eq!(Some(&EncryptedBy::RebootEscrow), blob_metadata.encrypted_by());
+
+        let unwrapped = unwrap(&wrapped_key, &blob_metadata, &secret)?;
+        assert_eq!(&super_key[..], &unwrapped[..]);
+
+        let other_secret = generate_random_data(32)?;
+        assert!(is_error(
+            unwrap(&wrapped_key, &blob_metadata, &other_secret),
+            ResponseCode::INVALID_ARGUMENT
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn copies_expire_after_their_lifetime() {
+        let armed = DateTime::from_millis_epoch(1_000_000);
+        let lifetime = LIFETIME.as_millis() as i64;
+        assert!(!is_expired(armed, armed));
+        assert!(!is_expired(armed, DateTime::from_millis_epoch(1_000_000 + lifetime)));
+        assert!(is_expired(armed, DateTime::from_millis_epoch(1_000_001 + lifetime)));
+        // The clock went backwards.
+        assert!(is_expired(armed, DateTime::from_millis_epoch(999_999)));
+    }
+
+    #[test]
+    fn refuses_short_secrets_and_foreign_metadata() -> Result<()> {
+        let super_key = generate_aes256_key()?;
+        assert!(wrap(&super_key, &[1u8; MIN_SECRET_LENGTH - 1]).is_err());
+
+        let secret = generate_random_data(32)?;
+        let (wrapped_key, blob_metadata) = wrap(&super_key, &secret)?;
+        let mut password_metadata = BlobMetaData::new();
+        password_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::Password));
+        password_metadata.add(BlobMetaEntry::Salt(blob_metadata.salt().unwrap().clone()));
+        password_metadata.add(BlobMetaEntry::Iv(blob_metadata.iv().unwrap().clone()));
+        password_metadata.add(BlobMetaEntry::AeadTag(blob_metadata.aead_tag().unwrap().clone()));
+        assert!(is_error(
+            unwrap(&wrapped_key, &password_metadata, &secret),
+            ResponseCode::VALUE_CORRUPTED
+        ));
+        Ok(())
+    }
+}
@@ -33,12 +33,13 @@ use keystore2_crypto::parse_subject_from_certificate;
 use crate::database::Uuid;
 use crate::ks_err;
 use crate::metrics_store::log_rkp_error_stats;
+use crate::rkp_roots::check_rkp_chain;
 use crate::rkpd_client::get_rkpd_attestation_key;
 use android_security_metrics::aidl::android::security::metrics::RkpError::RkpError as MetricsRkpError;
 
 /// Contains helper functions to check if remote provisioning is enabled on the system and, if so,
 /// to assign and retrieve attestation keys and certificate chains.
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct RemProvState {
     security_level: SecurityLevel,
     km_uuid: Uuid,
@@ -83,7 +84,9 @@ impl RemProvState {
         })
     }
 
-    /// Fetches attestation key and corresponding certificates from RKPD.
+    /// Fetches attestation key and corresponding certificates from RKPD. A certificate chain
+    /// that does not end in a pinned root is treated like any other RKPD failure, i.e., it is
+    /// rejected in RKP only mode and falls back to factory provisioned keys otherwise.
     pub fn get_rkpd_attestation_key_and_certs(
         &self,
         key: &KeyDescriptor,
@@ -93,7 +96,13 @@ impl RemProvState {
         if !self.is_asymmetric_key(params) || key.domain != Domain::APP {
             Ok(None)
         } else {
-            match get_rkpd_attestation_key(&self.security_level, caller_uid) {
+            let rkpd_key =
+                get_rkpd_attestation_key(&self.security_level, caller_uid).and_then(|rkpd_key| {
+                    check_rkp_chain(&rkpd_key.encodedCertChain, &self.security_level)
+                        .context(ks_err!("Untrusted RKP certificate chain."))?;
+                    Ok(rkpd_key)
+                });
+            match rkpd_key {
                 Err(e) => {
                     if self.is_rkp_only() {
                         log::error!("Error occurred: {:?}", e);
@@ -0,0 +1,170 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module reserves alias prefixes for system features that store keys in app namespaces.
+//! Such features find their keys by a well known alias prefix, so an app that creates a key
+//! with the same prefix first could make the feature pick up a key that the app controls.
+//!
+//! Apps, i.e., callers with an app id of `AID_APP_START` or above, cannot create keys with a
+//! reserved alias prefix in `Domain::APP`. App keys that were created before a prefix was
+//! reserved are moved to a quarantined alias when keystore starts. The app can still list and
+//! delete them, but the system feature no longer finds them under the reserved alias.
+//!
+//! Additional prefixes can be reserved with a comma separated list in the read-only system
+//! property `ro.keystore.reserved_alias_prefixes`. Quarantining renames keys irreversibly, so
+//! the list must be fixed at build time rather than settable on a running device.
+
+use crate::avf;
+use crate::database::KeystoreDB;
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use crate::utils::{AID_APP_START, AID_USER_OFFSET};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+
+/// Read-only system property with additional reserved alias prefixes, separated by commas.
+const RESERVED_PREFIXES_PROPERTY: &str = "ro.keystore.reserved_alias_prefixes";
+
+/// Alias prefixes that are always reserved.
+const DEFAULT_RESERVED_PREFIXES: &[&str] = &["android_system:"];
+
+/// Prefix of the aliases that squatting app keys are moved to. It is reserved as well, so that
+/// apps cannot create keys that look quarantined.
+const QUARANTINE_PREFIX: &str = "quarantined:";
+
+/// Returns all reserved alias prefixes, including the ones configured by system property.
+pub fn reserved_prefixes() -> Vec<String> {
+    let configured = match rustutils::system_properties::read(RESERVED_PREFIXES_PROPERTY) {
+        Ok(value) => value.unwrap_or_default(),
+        Err(e) => {
+            log::error!("Failed to read {}: {:?}", RESERVED_PREFIXES_PROPERTY, e);
+            String::new()
+        }
+    };
+    parse_prefixes(&configured)
+}
+
+fn parse_prefixes(configured: &str) -> Vec<String> {
+    let mut prefixes: Vec<String> =
+        DEFAULT_RESERVED_PREFIXES.iter().map(|p| p.to_string()).collect();
+    prefixes.push(QUARANTINE_PREFIX.to_string());
+    prefixes
+        .extend(configured.split(',').map(str::trim).filter(|p| !p.is_empty()).map(str::to_string));
+    prefixes
+}
+
+/// Returns true if `uid` belongs to an app rather than to a system component.
+fn is_app_uid(uid: u32) -> bool {
+    uid % AID_USER_OFFSET >= AID_APP_START
+}
+
+/// Checks that `caller_uid` may create a key under the alias of `key`. Fails with
+/// `PERMISSION_DENIED` if an app tries to create an app key with a reserved alias prefix, and
+/// with `INVALID_ARGUMENT` if a VM instance key alias in the AVF namespace is malformed, see
+/// `avf::check_vm_key_alias`. `key` must already have the namespace of the caller filled in.
+pub fn check_alias(key: &KeyDescriptor, caller_uid: u32) -> Result<()> {
+    avf::check_vm_key_alias(key)?;
+    check_alias_with_prefixes(key, caller_uid, &reserved_prefixes())
+}
+
+fn check_alias_with_prefixes(
+    key: &KeyDescriptor,
+    caller_uid: u32,
+    prefixes: &[String],
+) -> Result<()> {
+    if key.domain != Domain::APP || !is_app_uid(caller_uid) {
+        return Ok(());
+    }
+    match key.alias.as_deref().and_then(|a| prefixes.iter().find(|p| a.starts_with(p.as_str()))) {
+        Some(prefix) => Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+            .context(ks_err!("Alias prefix \"{}\" is reserved for system features.", prefix)),
+        None => Ok(()),
+    }
+}
+
+/// Returns the alias that the app key `key_id` with the squatting `alias` is moved to, or None
+/// if the key does not need to be moved.
+fn quarantined_alias(nspace: i64, key_id: i64, alias: &str) -> Option<String> {
+    let uid = u32::try_from(nspace).ok()?;
+    if !is_app_uid(uid) || alias.starts_with(QUARANTINE_PREFIX) {
+        return None;
+    }
+    // The key id keeps the alias unique within the namespace.
+    Some(format!("{}{}:{}", QUARANTINE_PREFIX, key_id, alias))
+}
+
+/// Moves all app keys that squat on a reserved alias prefix to a quarantined alias. Returns the
+/// number of moved keys.
+pub fn quarantine_squatted_aliases(db: &mut KeystoreDB) -> Result<usize> {
+    db.rename_app_aliases_with_prefix(&reserved_prefixes(), quarantined_alias)
+        .context(ks_err!("Failed to quarantine squatted aliases."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app_key(alias: &str) -> KeyDescriptor {
+        KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 10001,
+            alias: Some(alias.to_string()),
+            blob: None,
+        }
+    }
+
+    fn is_denied(result: Result<()>) -> bool {
+        matches!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::PERMISSION_DENIED))
+        )
+    }
+
+    #[test]
+    fn configured_prefixes_extend_defaults() {
+        assert_eq!(
+            vec!["android_system:", "quarantined:", "wifi_", "vpn:"],
+            parse_prefixes(" wifi_ ,, vpn:")
+        );
+        assert_eq!(vec!["android_system:", "quarantined:"], parse_prefixes(""));
+    }
+
+    #[test]
+    fn apps_cannot_use_reserved_prefixes() {
+        let prefixes = parse_prefixes("wifi_");
+        let check = |key: &KeyDescriptor, uid| check_alias_with_prefixes(key, uid, &prefixes);
+
+        assert!(is_denied(check(&app_key("wifi_cert"), 10001)));
+        assert!(is_denied(check(&app_key("quarantined:1:wifi_cert"), 1_010_001)));
+        assert!(check(&app_key("my_wifi_cert"), 10001).is_ok());
+        // System components own the reserved prefixes.
+        assert!(check(&app_key("wifi_cert"), 1010).is_ok());
+        // Only app namespaces are protected.
+        let selinux_key =
+            KeyDescriptor { domain: Domain::SELINUX, nspace: 102, ..app_key("wifi_") };
+        assert!(check(&selinux_key, 10001).is_ok());
+    }
+
+    #[test]
+    fn only_app_keys_are_quarantined() {
+        assert_eq!(
+            Some("quarantined:7:wifi_cert".to_string()),
+            quarantined_alias(10001, 7, "wifi_cert")
+        );
+        assert_eq!(None, quarantined_alias(1010, 7, "wifi_cert"));
+        assert_eq!(None, quarantined_alias(10001, 7, "quarantined:6:wifi_cert"));
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module pins the roots of the attestation certificate chains that are provided by
+//! remote key provisioning (RKP). The pinned roots are DER encoded certificates, one per
+//! `*.der` file, in a root store directory. The rkpd mainline module can ship an updated
+//! root store, which replaces the one in the system image. The root store is loaded once per
+//! boot, so an update takes effect when the updated module is activated.
+//!
+//! A chain is accepted if its last certificate is byte for byte equal to one of the pinned
+//! roots, and each certificate is issued by the next one, with a signature that verifies with
+//! the next certificate's public key. If no roots are configured, pinning is disabled and all
+//! chains are accepted.
+
+use crate::error::Error;
+use crate::ks_err;
+use crate::metrics_store::log_rkp_error_stats;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_security_metrics::aidl::android::security::metrics::RkpError::RkpError as MetricsRkpError;
+use anyhow::{Context, Result};
+use keystore2_crypto::certificate_issued_by;
+use lazy_static::lazy_static;
+use std::path::Path;
+
+/// Root store of the system image.
+const SYSTEM_ROOT_STORE_DIR: &str = "/system/etc/security/keystore2/rkp_roots";
+/// Root store of the rkpd mainline module. It takes precedence over the system root store.
+const MAINLINE_ROOT_STORE_DIR: &str = "/apex/com.android.rkpd/etc/keystore2/rkp_roots";
+
+lazy_static! {
+    static ref ROOT_STORE: RootStore = RootStore::load();
+}
+
+/// A set of pinned root certificates.
+#[derive(Debug, Default)]
+pub struct RootStore {
+    roots: Vec<Vec<u8>>,
+}
+
+impl RootStore {
+    /// Loads all `*.der` files in `dir` as pinned roots.
+    pub fn load_from_dir(dir: &Path) -> Result<Self> {
+        let mut roots = Vec::new();
+        for entry in std::fs::read_dir(dir).context(ks_err!("Failed to read {:?}.", dir))? {
+            let path = entry.context(ks_err!("Failed to read directory entry."))?.path();
+            if path.extension().map_or(false, |e| e == "der") {
+                roots.push(std::fs::read(&path).context(ks_err!("Failed to read {:?}.", path))?);
+            }
+        }
+        Ok(Self { roots })
+    }
+
+    /// Loads the mainline root store if it exists, or the system root store otherwise. An
+    /// unreadable root store is logged and results in an empty root store.
+    fn load() -> Self {
+        let dir = match [MAINLINE_ROOT_STORE_DIR, SYSTEM_ROOT_STORE_DIR]
+            .into_iter()
+            .map(Path::new)
+            .find(|dir| dir.is_dir())
+        {
+            Some(dir) => dir,
+            None => return Default::default(),
+        };
+        match Self::load_from_dir(dir) {
+            Ok(store) => {
+                log::info!("Loaded {} pinned RKP roots from {:?}.", store.roots.len(), dir);
+                store
+            }
+            Err(e) => {
+                log::error!("Failed to load pinned RKP roots: {:?}", e);
+                Default::default()
+            }
+        }
+    }
+
+    /// Checks that `chain`, a concatenation of DER encoded certificates with the root last,
+    /// ends in one of the pinned roots and that each certificate is issued by the next one.
+    /// Always succeeds if the root store is empty.
+    pub fn check_chain(&self, chain: &[u8]) -> Result<()> {
+        if self.roots.is_empty() {
+            return Ok(());
+        }
+        let certs = split_der_certificates(chain).context(ks_err!("Malformed chain."))?;
+        let root = certs.last().ok_or_else(Error::sys).context(ks_err!("Empty chain."))?;
+        if !self.roots.iter().any(|r| r == root) {
+            return Err(Error::sys()).context(ks_err!("Chain does not end in a pinned root."));
+        }
+        // A pinned root anchors the chain only if every link up to it is signed.
+        for (i, pair) in certs.windows(2).enumerate() {
+            let issued = certificate_issued_by(pair[0], pair[1])
+                .map_err(|_| Error::sys())
+                .context(ks_err!("Failed to check the issuer of certificate {}.", i))?;
+            if !issued {
+                return Err(Error::sys()).context(ks_err!(
+                    "Certificate {} is not issued by certificate {}.",
+                    i,
+                    i + 1
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks the RKP certificate chain of the given security level against the pinned roots, and
+/// logs a metric if it does not anchor in one of them.
+pub fn check_rkp_chain(chain: &[u8], security_level: &SecurityLevel) -> Result<()> {
+    ROOT_STORE.check_chain(chain).map_err(|e| {
+        log_rkp_error_stats(MetricsRkpError::UNTRUSTED_ROOT, security_level);
+        e
+    })
+}
+
+/// Splits a concatenation of DER encoded certificates into the individual certificates. Each
+/// certificate must be a SEQUENCE with a definite length.
+fn split_der_certificates(mut chain: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut certs = Vec::new();
+    while !chain.is_empty() {
+        let (header_len, content_len) = match chain {
+            [0x30, len, ..] if *len < 0x80 => (2, *len as usize),
+            [0x30, len, rest @ ..] if (0x81..=0x84).contains(len) => {
+                let n = (*len & 0x7f) as usize;
+                let len_bytes =
+                    rest.get(..n).ok_or_else(Error::sys).context(ks_err!("Truncated length."))?;
+                (2 + n, len_bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize))
+            }
+            _ => return Err(Error::sys()).context(ks_err!("Expected a DER SEQUENCE.")),
+        };
+        let cert_len = header_len
+            .checked_add(content_len)
+            .filter(|l| *l <= chain.len())
+            .ok_or_else(Error::sys)
+            .context(ks_err!("Truncated certificate."))?;
+        let (cert, rest) = chain.split_at(cert_len);
+        certs.push(cert);
+        chain = rest;
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_chain::tests::{LEAF_CERT, ROOT_CERT};
+    use keystore2_test_utils::TempDir;
+
+    fn der_sequence(content: &[u8]) -> Vec<u8> {
+        let mut der = vec![0x30];
+        if content.len() < 0x80 {
+            der.push(content.len() as u8);
+        } else {
+            der.extend_from_slice(&[0x82, (content.len() >> 8) as u8, content.len() as u8]);
+        }
+        der.extend_from_slice(content);
+        der
+    }
+
+    #[test]
+    fn split_chain() -> Result<()> {
+        let leaf = der_sequence(&[1; 10]);
+        let root = der_sequence(&[2; 300]);
+        let chain = [leaf.clone(), root.clone()].concat();
+        assert_eq!(vec![&leaf[..], &root[..]], split_der_certificates(&chain)?);
+        assert!(split_der_certificates(&chain[..chain.len() - 1]).is_err());
+        assert!(split_der_certificates(&[0x31, 0x00]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_chain_against_pinned_roots() -> Result<()> {
+        let other_root = der_sequence(&[3; 20]);
+
+        let temp_dir = TempDir::new("check_chain_against_pinned_roots")?;
+        std::fs::write(&*temp_dir.build().push("root.der"), ROOT_CERT)?;
+        std::fs::write(&*temp_dir.build().push("README"), b"not a root")?;
+        let store = RootStore::load_from_dir(temp_dir.path())?;
+        assert_eq!(vec![ROOT_CERT.to_vec()], store.roots);
+
+        assert!(store.check_chain(&[LEAF_CERT, ROOT_CERT].concat()).is_ok());
+        assert!(store.check_chain(ROOT_CERT).is_ok());
+        assert!(store.check_chain(&[LEAF_CERT, &other_root].concat()).is_err());
+        assert!(store.check_chain(&[]).is_err());
+
+        // Pinning is disabled without roots.
+        assert!(RootStore::default().check_chain(&[LEAF_CERT, &other_root].concat()).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn forged_chain_is_rejected() -> Result<()> {
+        let temp_dir = TempDir::new("forged_chain_is_rejected")?;
+        std::fs::write(&*temp_dir.build().push("root.der"), ROOT_CERT)?;
+        let store = RootStore::load_from_dir(temp_dir.path())?;
+
+        // A leaf whose signature does not verify with the pinned root's public key.
+        let mut forged_leaf = LEAF_CERT.to_vec();
+        *forged_leaf.last_mut().unwrap() ^= 0x01;
+        assert!(store.check_chain(&[&forged_leaf, ROOT_CERT].concat()).is_err());
+
+        // A chain that ends in the pinned root, but with a link that is not issued by the next.
+        assert!(store.check_chain(&[LEAF_CERT, LEAF_CERT, ROOT_CERT].concat()).is_err());
+        Ok(())
+    }
+}
@@ -14,22 +14,38 @@
 
 //! This crate implements the IKeystoreSecurityLevel interface.
 
+use crate::access_window::{self, AccessWindow};
+use crate::attestation_app_id;
 use crate::attestation_key_utils::{get_attest_key_info, AttestationKeyInfo};
 use crate::audit_log::{
     log_key_deleted, log_key_generated, log_key_imported, log_key_integrity_violation,
 };
-use crate::database::{BlobInfo, CertificateInfo, KeyIdGuard};
+use crate::authorization_diff::{self, AuthorizationDiff};
+use crate::database::{BlobInfo, CertificateInfo, GrantConstraints, KeyIdGuard};
+use crate::deadline::Deadline;
+use crate::digest_info;
 use crate::error::{self, map_km_error, map_or_log_err, Error, ErrorCode};
-use crate::globals::{DB, ENFORCEMENTS, LEGACY_IMPORTER, SUPER_KEY};
+use crate::feature_flags::{self, Feature};
+use crate::globals::{
+    ANOMALY_DETECTOR, DB, DEPRECATION_EVENTS, ENFORCEMENTS, HAL_HEALTH, KEY_OPERATION_STATS,
+    LEGACY_IMPORTER, MANAGED_NONCES, PATCH_LEVEL_POLICY, STRONGBOX_GENERATIONS, SUPER_KEY,
+    UNIQUE_ID_REQUESTS, USER_LIMITS,
+};
+use crate::hal_health::HalCall;
+use crate::import_fingerprint::{self, ImportedMaterial};
+use crate::key_backup;
 use crate::key_parameter::KeyParameter as KsKeyParam;
 use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
+use crate::key_validity;
 use crate::ks_err;
+use crate::managed_nonce;
 use crate::metrics_store::log_key_creation_event_stats;
 use crate::remote_provisioning::RemProvState;
+use crate::reserved_alias;
 use crate::rkpd_client::store_rkpd_attestation_key;
 use crate::super_key::{KeyBlob, SuperKeyManager};
 use crate::utils::{
-    check_device_attestation_permissions, check_key_permission,
+    check_device_attestation_permissions, check_key_permission, check_keystore_permission,
     check_unique_id_attestation_permissions, is_device_id_attestation_tag,
     key_characteristics_to_internal, uid_to_android_user, watchdog as wd,
 };
@@ -40,18 +56,29 @@ use crate::{
     },
     operation::KeystoreOperation,
     operation::LoggingInfo,
+    operation::Operation,
     operation::OperationDb,
-    permission::KeyPerm,
+    operation::{self, Replay},
+    permission::{KeyPerm, KeystorePerm},
+};
+use crate::{
+    globals::{get_keymint_dev_by_uuid, get_keymint_device},
+    id_rotation::IdRotationState,
 };
-use crate::{globals::get_keymint_device, id_rotation::IdRotationState};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    Algorithm::Algorithm, AttestationKey::AttestationKey,
+    Algorithm::Algorithm, AttestationKey::AttestationKey, Digest::Digest,
     HardwareAuthenticatorType::HardwareAuthenticatorType, IKeyMintDevice::IKeyMintDevice,
     KeyCreationResult::KeyCreationResult, KeyFormat::KeyFormat,
     KeyMintHardwareInfo::KeyMintHardwareInfo, KeyParameter::KeyParameter,
-    KeyParameterValue::KeyParameterValue, SecurityLevel::SecurityLevel, Tag::Tag,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
+    SecurityLevel::SecurityLevel, Tag::Tag,
 };
 use android_hardware_security_keymint::binder::{BinderFeatures, Strong, ThreadState};
+use android_security_keystoreextension::aidl::android::security::keystoreextension::{
+    GeneratedKey::GeneratedKey, IKeystoreSecurityLevelExtension::BnKeystoreSecurityLevelExtension,
+    IKeystoreSecurityLevelExtension::IKeystoreSecurityLevelExtension,
+    IOperationSlotListener::IOperationSlotListener,
+};
 use android_system_keystore2::aidl::android::system::keystore2::{
     AuthenticatorSpec::AuthenticatorSpec, CreateOperationResponse::CreateOperationResponse,
     Domain::Domain, EphemeralStorageKeyResponse::EphemeralStorageKeyResponse,
@@ -59,17 +86,21 @@ use android_system_keystore2::aidl::android::system::keystore2::{
     IKeystoreSecurityLevel::IKeystoreSecurityLevel, KeyDescriptor::KeyDescriptor,
     KeyMetadata::KeyMetadata, KeyParameters::KeyParameters, ResponseCode::ResponseCode,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
+use keystore2_crypto::{certificate_signature_algorithm, ZVec};
+use std::cell::Cell;
 use std::convert::TryInto;
+use std::sync::Arc;
 use std::time::SystemTime;
 
-/// Implementation of the IKeystoreSecurityLevel Interface.
+/// Implementation of the IKeystoreSecurityLevel and the IKeystoreSecurityLevelExtension
+/// Interfaces.
+#[derive(Clone)]
 pub struct KeystoreSecurityLevel {
     security_level: SecurityLevel,
-    keymint: Strong<dyn IKeyMintDevice>,
     hw_info: KeyMintHardwareInfo,
     km_uuid: Uuid,
-    operation_db: OperationDb,
+    operation_db: Arc<OperationDb>,
     rem_prov_state: RemProvState,
     id_rotation_state: IdRotationState,
 }
@@ -81,30 +112,63 @@ static ZERO_BLOB_32: &[u8] = &[0; 32];
 // 999912312359559, which is 253402300799000 ms from Jan 1, 1970.
 const UNDEFINED_NOT_AFTER: i64 = 253402300799000i64;
 
+// How often an operation with replay tries to begin a new KeyMint operation after it was pruned.
+const MAX_RESTART_ATTEMPTS: usize = 3;
+
+/// Optional metadata that is stored with a new key, depending on how it was created.
+#[derive(Default)]
+struct NewKeyMetadata {
+    /// The sealed key material of a backup eligible import.
+    backup_material: Option<Vec<u8>>,
+    /// The window of minutes of the day in which the key may be used.
+    access_window: Option<AccessWindow>,
+    /// The prefix of the nonces that keystore picks for the key.
+    managed_nonce_prefix: Option<Vec<u8>>,
+    /// The fingerprint of the imported key material.
+    import_fingerprint: Option<Vec<u8>>,
+}
+
 impl KeystoreSecurityLevel {
     /// Creates a new security level instance wrapped in a
     /// BnKeystoreSecurityLevel proxy object. It also enables
     /// `BinderFeatures::set_requesting_sid` on the new interface, because
-    /// we need it for checking keystore permissions.
+    /// we need it for checking keystore permissions. The second binder serves the extensions
+    /// of the security level with the same state.
     pub fn new_native_binder(
         security_level: SecurityLevel,
         id_rotation_state: IdRotationState,
-    ) -> Result<(Strong<dyn IKeystoreSecurityLevel>, Uuid)> {
-        let (dev, hw_info, km_uuid) = get_keymint_device(&security_level)
+    ) -> Result<(
+        Strong<dyn IKeystoreSecurityLevel>,
+        Strong<dyn IKeystoreSecurityLevelExtension>,
+        Uuid,
+    )> {
+        let (_, hw_info, km_uuid) = get_keymint_device(&security_level)
             .context(ks_err!("KeystoreSecurityLevel::new_native_binder."))?;
+        let sec_level = Self {
+            security_level,
+            hw_info,
+            km_uuid,
+            operation_db: Arc::new(OperationDb::new()),
+            rem_prov_state: RemProvState::new(security_level, km_uuid),
+            id_rotation_state,
+        };
+        let extension = BnKeystoreSecurityLevelExtension::new_binder(
+            sec_level.clone(),
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        );
         let result = BnKeystoreSecurityLevel::new_binder(
-            Self {
-                security_level,
-                keymint: dev,
-                hw_info,
-                km_uuid,
-                operation_db: OperationDb::new(),
-                rem_prov_state: RemProvState::new(security_level, km_uuid),
-                id_rotation_state,
-            },
+            sec_level,
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
         );
-        Ok((result, km_uuid))
+        Ok((result, extension, km_uuid))
+    }
+
+    /// Returns the current connection to the KeyMint device, which is replaced when its HAL is
+    /// restarted, see `hal_health`.
+    fn keymint(&self) -> Strong<dyn IKeyMintDevice> {
+        // Unwrap must succeed, because the device was cached when this instance was created,
+        // and cached devices are only ever replaced.
+        get_keymint_dev_by_uuid(&self.km_uuid).unwrap().0
     }
 
     fn watch_millis(&self, id: &'static str, millis: u64) -> Option<wd::WatchPoint> {
@@ -112,13 +176,25 @@ impl KeystoreSecurityLevel {
         wd::watch_millis_with(id, millis, move || format!("SecurityLevel {:?}", sec_level))
     }
 
+    /// Like `watch_millis`, but for calls into KeyMint, which are escalated if they get stuck.
+    fn watch_hal_call(&self, id: &'static str, millis: u64) -> Result<HalCall> {
+        HAL_HEALTH.watch_call(self.security_level, id, millis)
+    }
+
     fn store_new_key(
         &self,
         key: KeyDescriptor,
         creation_result: KeyCreationResult,
         user_id: u32,
         flags: Option<i32>,
+        new_key_metadata: NewKeyMetadata,
     ) -> Result<KeyMetadata> {
+        let NewKeyMetadata {
+            backup_material,
+            access_window,
+            managed_nonce_prefix,
+            import_fingerprint,
+        } = new_key_metadata;
         let KeyCreationResult {
             keyBlob: key_blob,
             keyCharacteristics: key_characteristics,
@@ -142,6 +218,20 @@ impl KeystoreSecurityLevel {
             },
         );
 
+        // Without attestation, KeyMint issues a self-signed certificate and picks its signature
+        // algorithm. Record what it picked, so that validators know what to expect. Back-level
+        // devices may not return a certificate at all.
+        let cert_signature_algorithm = match (cert_info.cert(), cert_info.cert_chain()) {
+            (Some(cert), None) => match certificate_signature_algorithm(cert) {
+                Ok(oid) => Some(oid),
+                Err(e) => {
+                    log::warn!("Failed to get the certificate signature algorithm: {e:?}");
+                    None
+                }
+            },
+            _ => None,
+        };
+
         let mut key_parameters = key_characteristics_to_internal(key_characteristics);
 
         key_parameters.push(KsKeyParam::new(
@@ -177,6 +267,21 @@ impl KeystoreSecurityLevel {
 
                     let mut key_metadata = KeyMetaData::new();
                     key_metadata.add(KeyMetaEntry::CreationDate(creation_date));
+                    if let Some(backup_material) = backup_material {
+                        key_metadata.add(KeyMetaEntry::BackupMaterial(backup_material));
+                    }
+                    if let Some(oid) = cert_signature_algorithm {
+                        key_metadata.add(KeyMetaEntry::CertSignatureAlgorithm(oid));
+                    }
+                    if let Some(window) = access_window {
+                        key_metadata.add(KeyMetaEntry::AccessWindow(window.encode()));
+                    }
+                    if let Some(prefix) = managed_nonce_prefix {
+                        managed_nonce::add_to_metadata(prefix, &mut key_metadata);
+                    }
+                    if let Some(fingerprint) = import_fingerprint {
+                        key_metadata.add(KeyMetaEntry::ImportFingerprint(fingerprint));
+                    }
                     blob_metadata.add(BlobMetaEntry::KmUuid(self.km_uuid));
 
                     let key_id = db
@@ -199,12 +304,15 @@ impl KeystoreSecurityLevel {
                 .context(ks_err!())?,
         };
 
+        let mut authorizations = crate::utils::key_parameters_to_authorizations(key_parameters);
+        authorizations.extend(access_window.map(AccessWindow::to_authorization));
+
         Ok(KeyMetadata {
             key,
             keySecurityLevel: self.security_level,
             certificate: cert_info.take_cert(),
             certificateChain: cert_info.take_cert_chain(),
-            authorizations: crate::utils::key_parameters_to_authorizations(key_parameters),
+            authorizations,
             modificationTimeMs: creation_date.to_millis_epoch(),
         })
     }
@@ -214,12 +322,106 @@ impl KeystoreSecurityLevel {
         key: &KeyDescriptor,
         operation_parameters: &[KeyParameter],
         forced: bool,
+        replay: bool,
+        deadline: &Deadline,
     ) -> Result<CreateOperationResponse> {
+        let (operation, response) =
+            self.begin_operation(key, operation_parameters, forced, replay, deadline)?;
+
+        let op_binder: binder::Strong<dyn IKeystoreOperation> =
+            KeystoreOperation::new_native_binder(operation)
+                .as_binder()
+                .into_interface()
+                .context(ks_err!("Failed to create IKeystoreOperation."))?;
+
+        Ok(CreateOperationResponse { iOperation: Some(op_binder), ..response })
+    }
+
+    fn transfer_operation(
+        &self,
+        operation: &Strong<dyn IKeystoreOperation>,
+        target_uid: i32,
+    ) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::TransferOperation).context(ks_err!())?;
+        let target = u32::try_from(target_uid)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Invalid target uid {}.", target_uid))?;
+        self.operation_db
+            .transfer_operation(&operation.as_binder(), ThreadState::get_calling_uid(), target)
+            .context(ks_err!())
+    }
+
+    /// Performs a complete operation with a single call. The operation is created, finished
+    /// with `input`, and freed before this returns, so it only occupies an operation slot for
+    /// the duration of the call. Keys that require per-operation authentication cannot be
+    /// used this way, because the client has no chance to authorize the operation challenge.
+    fn one_shot_operation(
+        &self,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        input: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        // Fail before occupying an operation slot if finish would reject the input anyway.
+        Operation::check_input_length(input).context(ks_err!())?;
+
+        let (operation, response) =
+            self.begin_operation(key, operation_parameters, false, false, &Deadline::none())?;
+        if response.operationChallenge.is_some() {
+            // Dropping the operation aborts it.
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                "Keys that require per-operation authentication cannot be used in one-shot \
+                operations."
+            ));
+        }
+        operation.finish(Some(input), None).context(ks_err!())
+    }
+
+    /// Signs a precomputed `hash` with an RSA key using RSASSA-PKCS1-v1_5. Keystore encodes the
+    /// hash as `DigestInfo` and signs the encoding with `Digest::NONE`, so the key must be
+    /// authorized for `Digest::NONE` and `PaddingMode::RSA_PKCS1_1_5_SIGN`. `digest` is the
+    /// algorithm that `hash` was computed with.
+    fn sign_digest(&self, key: &KeyDescriptor, digest: Digest, hash: &[u8]) -> Result<Vec<u8>> {
+        let digest_info = digest_info::encode(digest, hash).context(ks_err!())?;
+        let operation_parameters = [
+            KeyParameter {
+                tag: Tag::PURPOSE,
+                value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+            },
+            KeyParameter { tag: Tag::DIGEST, value: KeyParameterValue::Digest(Digest::NONE) },
+            KeyParameter {
+                tag: Tag::PADDING,
+                value: KeyParameterValue::PaddingMode(PaddingMode::RSA_PKCS1_1_5_SIGN),
+            },
+        ];
+        self.one_shot_operation(key, &operation_parameters, &digest_info)
+            .context(ks_err!())?
+            .ok_or_else(Error::sys)
+            .context(ks_err!("Signing succeeded, but no signature was returned."))
+    }
+
+    /// Begins a KeyMint operation and registers it in the operation database. Returns the
+    /// operation and the response for the client, without an `IKeystoreOperation`. If `replay`
+    /// is true and the operation qualifies, it is recreated after pruning, see
+    /// `crate::operation`.
+    fn begin_operation(
+        &self,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        forced: bool,
+        replay: bool,
+        deadline: &Deadline,
+    ) -> Result<(Arc<Operation>, CreateOperationResponse)> {
         let caller_uid = ThreadState::get_calling_uid();
         // We use `scoping_blob` to extend the life cycle of the blob loaded from the database,
         // so that we can use it by reference like the blob provided by the key descriptor.
         // Otherwise, we would have to clone the blob from the key descriptor.
         let scoping_blob: Vec<u8>;
+        // Set if the key is accessed through a grant.
+        let granted = Cell::new(false);
+        let mut grant_constraints = GrantConstraints::default();
+        let mut access_window: Option<AccessWindow> = None;
+        let mut managed_nonce_prefix: Option<Vec<u8>> = None;
         let (km_blob, key_properties, key_id_guard, blob_metadata) = match key.domain {
             Domain::BLOB => {
                 check_key_permission(KeyPerm::Use, key, &None)
@@ -244,6 +446,7 @@ impl KeystoreSecurityLevel {
                 )
             }
             _ => {
+                deadline.check("loading the key")?;
                 let super_key = SUPER_KEY
                     .read()
                     .unwrap()
@@ -261,6 +464,7 @@ impl KeystoreSecurityLevel {
                                     if forced {
                                         check_key_permission(KeyPerm::ReqForcedOp, k, &av)?;
                                     }
+                                    granted.set(av.is_some());
                                     Ok(())
                                 },
                             )
@@ -274,6 +478,16 @@ impl KeystoreSecurityLevel {
                         but KM blob was missing."
                     ))?;
                 scoping_blob = blob;
+                if granted.get() {
+                    grant_constraints = DB
+                        .with(|db| {
+                            db.borrow_mut().load_grant_constraints(caller_uid, key_id_guard.id())
+                        })
+                        .context(ks_err!("Failed to load grant constraints."))?;
+                }
+                access_window =
+                    access_window::from_metadata(key_entry.metadata()).context(ks_err!())?;
+                managed_nonce_prefix = managed_nonce::from_metadata(key_entry.metadata());
 
                 (
                     &scoping_blob,
@@ -284,6 +498,16 @@ impl KeystoreSecurityLevel {
             }
         };
 
+        // Rejections by enforcement and by KeyMint are counted in the operation statistics of
+        // the key.
+        let key_id = key_properties.as_ref().map(|(key_id, _)| *key_id);
+        let record_begin_failure = |e: anyhow::Error| {
+            if let Some(key_id) = key_id {
+                KEY_OPERATION_STATS.on_begin_failed(key_id, &e);
+            }
+            e
+        };
+
         let purpose = operation_parameters.iter().find(|p| p.tag == Tag::PURPOSE).map_or(
             Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
                 .context(ks_err!("No operation purpose specified.")),
@@ -294,6 +518,13 @@ impl KeystoreSecurityLevel {
             },
         )?;
 
+        if let Some(purposes) = &grant_constraints.purposes {
+            if !purposes.contains(&purpose) {
+                return Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                    .context(ks_err!("The grant does not allow purpose {:?}.", purpose));
+            }
+        }
+
         // Remove Tag::PURPOSE from the operation_parameters, since some keymaster devices return
         // an error on begin() if Tag::PURPOSE is in the operation_parameters.
         let op_params: Vec<KeyParameter> =
@@ -306,14 +537,73 @@ impl KeystoreSecurityLevel {
                 key_properties.as_ref(),
                 operation_parameters.as_ref(),
                 self.hw_info.timestampTokenRequired,
+                self.security_level,
             )
-            .context(ks_err!())?;
+            .context(ks_err!())
+            .map_err(record_begin_failure)?;
+
+        let allowed_use = match &key_properties {
+            Some((key_id, params)) => PATCH_LEVEL_POLICY
+                .check(*key_id, caller_uid, params)
+                .context(ks_err!())
+                .map_err(record_begin_failure)?,
+            None => None,
+        };
+
+        if let Some(window) = access_window {
+            window.check(SystemTime::now()).context(ks_err!()).map_err(record_begin_failure)?;
+        }
+
+        // Keystore picks the nonces of encryptions with keys that have managed nonces. Each
+        // nonce is handed out only once, so it is picked after the operation was authorized.
+        let managed_nonce = match (managed_nonce_prefix, key_id) {
+            (Some(prefix), Some(key_id)) if purpose == KeyPurpose::ENCRYPT => {
+                managed_nonce::check_operation_params(operation_parameters)
+                    .context(ks_err!())
+                    .map_err(record_begin_failure)?;
+                if feature_flags::is_enabled(Feature::ManagedNonces) {
+                    Some(
+                        DB.with(|db| {
+                            MANAGED_NONCES.next_nonce(&mut db.borrow_mut(), key_id, &prefix)
+                        })
+                        .context(ks_err!("Failed to pick a nonce."))?,
+                    )
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let mut op_params: Vec<KeyParameter> = operation_parameters.to_vec();
+        if let Some(nonce) = &managed_nonce {
+            op_params.push(managed_nonce::nonce_param(nonce.clone()));
+        }
+        let operation_parameters = op_params.as_slice();
+
+        if let Some(owner_sids) = &grant_constraints.auth_sids {
+            ENFORCEMENTS
+                .authorize_use_with_auth_only_grant(
+                    uid_to_android_user(caller_uid) as i32,
+                    self.security_level,
+                    owner_sids,
+                )
+                .context(ks_err!("Grant requires user authentication."))
+                .map_err(record_begin_failure)?;
+        }
+
+        if let Some(key_id) = key_id {
+            ANOMALY_DETECTOR
+                .check_use(caller_uid, key_id, self.security_level)
+                .context(ks_err!())
+                .map_err(record_begin_failure)?;
+        }
 
         let km_blob = SUPER_KEY
             .read()
             .unwrap()
             .unwrap_key_if_required(&blob_metadata, km_blob)
-            .context(ks_err!("Failed to handle super encryption."))?;
+            .context(ks_err!("Failed to handle super encryption."))
+            .map_err(record_begin_failure)?;
 
         let (begin_result, upgraded_blob) = self
             .upgrade_keyblob_if_required_with(
@@ -322,12 +612,14 @@ impl KeystoreSecurityLevel {
                 blob_metadata.km_uuid().copied(),
                 operation_parameters,
                 |blob| loop {
+                    // Checked on every attempt, because pruning and key upgrades take time.
+                    deadline.error_if_exceeded()?;
                     match map_km_error({
-                        let _wp = self.watch_millis(
+                        let _wp = self.watch_hal_call(
                             "In KeystoreSecurityLevel::create_operation: calling begin",
                             500,
-                        );
-                        self.keymint.begin(
+                        )?;
+                        self.keymint().begin(
                             purpose,
                             blob,
                             operation_parameters,
@@ -354,10 +646,39 @@ impl KeystoreSecurityLevel {
                     }
                 },
             )
-            .context(ks_err!("Failed to begin operation."))?;
+            .context(ks_err!("Failed to begin operation."))
+            .map_err(|e| error::add_retry_hint(e, || self.operation_db.retry_hint()))
+            .map_err(record_begin_failure)?;
+
+        if deadline.is_exceeded() {
+            // The client has given up, so the KeyMint operation would only occupy a slot until
+            // it is pruned.
+            if let Some(km_op) = &begin_result.operation {
+                if let Err(e) = map_km_error(km_op.abort()) {
+                    log::warn!("Failed to abort operation after deadline: {:?}", e);
+                }
+            }
+            deadline.check("creating the operation")?;
+        }
 
         let operation_challenge = auth_info.finalize_create_authorization(begin_result.challenge);
 
+        // A new KeyMint operation can only stand in for the pruned one if beginning it needs
+        // no authentication.
+        let replay = match &key_properties {
+            Some((_, params))
+                if replay
+                    && immediate_hat.is_none()
+                    && operation_challenge.is_none()
+                    && operation::is_replayable(purpose, params) =>
+            {
+                let blob = ZVec::try_from(upgraded_blob.as_deref().unwrap_or(&km_blob))
+                    .context(ks_err!("Failed to keep the key blob for replay."))?;
+                Some(self.replay(blob, purpose, operation_parameters.to_vec(), caller_uid, forced))
+            }
+            _ => None,
+        };
+
         let op_params: Vec<KeyParameter> = operation_parameters.to_vec();
 
         let operation = match begin_result.operation {
@@ -366,7 +687,14 @@ impl KeystoreSecurityLevel {
                 caller_uid,
                 auth_info,
                 forced,
-                LoggingInfo::new(self.security_level, purpose, op_params, upgraded_blob.is_some()),
+                LoggingInfo::new(
+                    self.security_level,
+                    purpose,
+                    op_params,
+                    upgraded_blob.is_some(),
+                    key_id,
+                ),
+                replay,
             ),
             None => {
                 return Err(Error::sys()).context(ks_err!(
@@ -376,24 +704,83 @@ impl KeystoreSecurityLevel {
             }
         };
 
-        let op_binder: binder::Strong<dyn IKeystoreOperation> =
-            KeystoreOperation::new_native_binder(operation)
-                .as_binder()
-                .into_interface()
-                .context(ks_err!("Failed to create IKeystoreOperation."))?;
+        if let Some(allowed_use) = allowed_use {
+            PATCH_LEVEL_POLICY.on_allowed_use(allowed_use);
+        }
+        if let Some(key_id) = key_id {
+            KEY_OPERATION_STATS.on_begin(key_id);
+            if purpose == KeyPurpose::SIGN {
+                ANOMALY_DETECTOR.on_sign(caller_uid, key_id);
+            }
+        }
+        DEPRECATION_EVENTS.on_begin(
+            caller_uid,
+            key_properties.as_ref().map(|(_, params)| params.as_slice()),
+            operation_parameters,
+        );
 
-        Ok(CreateOperationResponse {
-            iOperation: Some(op_binder),
+        // KeyMint returns only the nonces that it picked.
+        let mut begin_params = begin_result.params;
+        if let Some(nonce) = managed_nonce {
+            if !begin_params.iter().any(|p| p.tag == Tag::NONCE) {
+                begin_params.push(managed_nonce::nonce_param(nonce));
+            }
+        }
+
+        let response = CreateOperationResponse {
+            iOperation: None,
             operationChallenge: operation_challenge,
-            parameters: match begin_result.params.len() {
+            parameters: match begin_params.len() {
                 0 => None,
-                _ => Some(KeyParameters { keyParameter: begin_result.params }),
+                _ => Some(KeyParameters { keyParameter: begin_params }),
             },
             // An upgraded blob should only be returned if the caller has permission
             // to use Domain::BLOB keys. If we got to this point, we already checked
             // that the caller had that permission.
             upgradedBlob: if key.domain == Domain::BLOB { upgraded_blob } else { None },
-        })
+        };
+        Ok((operation, response))
+    }
+
+    /// Returns the replay state of a new operation, which begins new KeyMint operations with
+    /// `blob` and `params` on behalf of `caller_uid`.
+    fn replay(
+        &self,
+        blob: ZVec,
+        purpose: KeyPurpose,
+        params: Vec<KeyParameter>,
+        caller_uid: u32,
+        forced: bool,
+    ) -> Replay {
+        let km_uuid = self.km_uuid;
+        let security_level = self.security_level;
+        let operation_db = self.operation_db.clone();
+        Replay::new(Box::new(move || {
+            for _ in 0..MAX_RESTART_ATTEMPTS {
+                // Looked up on every attempt, because the HAL may have been restarted since.
+                let (keymint, _) = get_keymint_dev_by_uuid(&km_uuid).context(ks_err!())?;
+                let result = {
+                    let _wp = HAL_HEALTH.watch_call(
+                        security_level,
+                        "In KeystoreSecurityLevel::replay: calling begin",
+                        500,
+                    )?;
+                    map_km_error(keymint.begin(purpose, &blob, &params, None))
+                };
+                match result {
+                    Err(Error::Km(ErrorCode::TOO_MANY_OPERATIONS)) => {
+                        operation_db.prune(caller_uid, forced)?;
+                    }
+                    result => {
+                        return result?.operation.ok_or_else(Error::sys).context(ks_err!(
+                            "Begin operation returned successfully, \
+                            but did not return a valid operation."
+                        ));
+                    }
+                }
+            }
+            Err(Error::Rc(ResponseCode::BACKEND_BUSY)).context(ks_err!("No operation slot."))
+        }))
     }
 
     fn add_required_parameters(
@@ -416,27 +803,31 @@ impl KeystoreSecurityLevel {
         // quering the clock multiple times.
         let creation_datetime = SystemTime::now();
 
+        let creation_millis: i64 = creation_datetime
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .context(ks_err!(
+                "KeystoreSecurityLevel::add_required_parameters: \
+                    Failed to get epoch time."
+            ))?
+            .as_millis()
+            .try_into()
+            .context(ks_err!(
+                "KeystoreSecurityLevel::add_required_parameters: \
+                    Failed to convert epoch time."
+            ))?;
+
         // Add CREATION_DATETIME only if the backend version Keymint V1 (100) or newer.
         if self.hw_info.versionNumber >= 100 {
             result.push(KeyParameter {
                 tag: Tag::CREATION_DATETIME,
-                value: KeyParameterValue::DateTime(
-                    creation_datetime
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .context(ks_err!(
-                            "KeystoreSecurityLevel::add_required_parameters: \
-                                Failed to get epoch time."
-                        ))?
-                        .as_millis()
-                        .try_into()
-                        .context(ks_err!(
-                            "KeystoreSecurityLevel::add_required_parameters: \
-                                Failed to convert epoch time."
-                        ))?,
-                ),
+                value: KeyParameterValue::DateTime(creation_millis),
             });
         }
 
+        // Device policy may limit how long the keys of the caller's user stay valid.
+        key_validity::apply_policy(uid_to_android_user(uid), creation_millis, &mut result)
+            .context(ks_err!())?;
+
         // If there is an attestation challenge we need to get an application id.
         if params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
             let aaid = {
@@ -444,8 +835,7 @@ impl KeystoreSecurityLevel {
                     "In KeystoreSecurityLevel::add_required_parameters calling: get_aaid",
                     500,
                 );
-                keystore2_aaid::get_aaid(uid)
-                    .map_err(|e| anyhow!(ks_err!("get_aaid returned status {}.", e)))
+                attestation_app_id::get(uid).context(ks_err!("Failed to get aaid."))
             }?;
 
             result.push(KeyParameter {
@@ -462,9 +852,13 @@ impl KeystoreSecurityLevel {
                     "Caller does not have the permission to generate a unique ID"
                 ));
             }
-            if self
-                .id_rotation_state
-                .had_factory_reset_since_id_rotation(&creation_datetime)
+            if feature_flags::is_enabled(Feature::UniqueIdThrottling) {
+                UNIQUE_ID_REQUESTS.throttle(uid).context(ks_err!())?;
+            }
+            if UNIQUE_ID_REQUESTS
+                .reset_since_rotation(uid, &creation_datetime, || {
+                    self.id_rotation_state.had_factory_reset_since_id_rotation(&creation_datetime)
+                })
                 .context(ks_err!("Call to had_factory_reset_since_id_rotation failed."))?
             {
                 result.push(KeyParameter {
@@ -512,7 +906,8 @@ impl KeystoreSecurityLevel {
         params: &[KeyParameter],
         flags: i32,
         _entropy: &[u8],
-    ) -> Result<KeyMetadata> {
+        deadline: &Deadline,
+    ) -> Result<(KeyMetadata, AuthorizationDiff)> {
         if key.domain != Domain::BLOB && key.alias.is_none() {
             return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
                 .context(ks_err!("Alias must be specified"));
@@ -532,7 +927,17 @@ impl KeystoreSecurityLevel {
         // generate_key requires the rebind permission.
         // Must return on error for security reasons.
         check_key_permission(KeyPerm::Rebind, &key, &None).context(ks_err!())?;
+        reserved_alias::check_alias(&key, caller_uid).context(ks_err!())?;
+        USER_LIMITS.check(&key).context(ks_err!())?;
+
+        // Every StrongBox key generation wears the flash of the secure element.
+        if self.security_level == SecurityLevel::STRONGBOX
+            && feature_flags::is_enabled(Feature::StrongBoxGenerationBudget)
+        {
+            STRONGBOX_GENERATIONS.admit(caller_uid).context(ks_err!())?;
+        }
 
+        deadline.check("getting the attestation key")?;
         let attestation_key_info = match (key.domain, attest_key_descriptor) {
             (Domain::BLOB, _) => None,
             _ => DB
@@ -548,10 +953,17 @@ impl KeystoreSecurityLevel {
                 })
                 .context(ks_err!("Trying to get an attestation key"))?,
         };
+        let (requested_params, access_window) =
+            access_window::extract(&key, params).context(ks_err!())?;
+        let requested_params = requested_params.as_slice();
+        DEPRECATION_EVENTS.on_key_creation(caller_uid, requested_params);
         let params = self
-            .add_required_parameters(caller_uid, params, &key)
+            .add_required_parameters(caller_uid, requested_params, &key)
             .context(ks_err!("Trying to get aaid."))?;
+        let (params, managed_nonce_prefix) =
+            managed_nonce::prepare_new_key(&key, params).context(ks_err!())?;
 
+        deadline.check("calling generateKey")?;
         let creation_result = match attestation_key_info {
             Some(AttestationKeyInfo::UserGenerated {
                 key_id_guard,
@@ -565,20 +977,21 @@ impl KeystoreSecurityLevel {
                     blob_metadata.km_uuid().copied(),
                     &params,
                     |blob| {
+                        deadline.error_if_exceeded()?;
                         let attest_key = Some(AttestationKey {
                             keyBlob: blob.to_vec(),
                             attestKeyParams: vec![],
                             issuerSubjectName: issuer_subject.clone(),
                         });
                         map_km_error({
-                            let _wp = self.watch_millis(
+                            let _wp = self.watch_hal_call(
                                 concat!(
                                     "In KeystoreSecurityLevel::generate_key (UserGenerated): ",
                                     "calling generate_key."
                                 ),
                                 5000, // Generate can take a little longer.
-                            );
-                            self.keymint.generateKey(&params, attest_key.as_ref())
+                            )?;
+                            self.keymint().generateKey(&params, attest_key.as_ref())
                         })
                     },
                 )
@@ -586,20 +999,21 @@ impl KeystoreSecurityLevel {
                 .map(|(result, _)| result),
             Some(AttestationKeyInfo::RkpdProvisioned { attestation_key, attestation_certs }) => {
                 self.upgrade_rkpd_keyblob_if_required_with(&attestation_key.keyBlob, &[], |blob| {
+                    deadline.error_if_exceeded()?;
                     map_km_error({
-                        let _wp = self.watch_millis(
+                        let _wp = self.watch_hal_call(
                             concat!(
                                 "In KeystoreSecurityLevel::generate_key (RkpdProvisioned): ",
                                 "calling generate_key.",
                             ),
                             5000, // Generate can take a little longer.
-                        );
+                        )?;
                         let dynamic_attest_key = Some(AttestationKey {
                             keyBlob: blob.to_vec(),
                             attestKeyParams: vec![],
                             issuerSubjectName: attestation_key.issuerSubjectName.clone(),
                         });
-                        self.keymint.generateKey(&params, dynamic_attest_key.as_ref())
+                        self.keymint().generateKey(&params, dynamic_attest_key.as_ref())
                     })
                 })
                 .context(ks_err!("While generating Key with remote provisioned attestation key."))
@@ -609,21 +1023,37 @@ impl KeystoreSecurityLevel {
                 })
             }
             None => map_km_error({
-                let _wp = self.watch_millis(
+                let _wp = self.watch_hal_call(
                     concat!(
                         "In KeystoreSecurityLevel::generate_key (No attestation): ",
                         "calling generate_key.",
                     ),
                     5000, // Generate can take a little longer.
-                );
-                self.keymint.generateKey(&params, None)
+                )?;
+                self.keymint().generateKey(&params, None)
             })
             .context(ks_err!("While generating Key without explicit attestation key.")),
         }
         .context(ks_err!())?;
 
+        // The client would see an error, so the new key must not replace an existing key.
+        deadline.check("storing the new key")?;
+        let diff = authorization_diff::check_new_key(
+            &key,
+            requested_params,
+            &creation_result.keyCharacteristics,
+        );
         let user_id = uid_to_android_user(caller_uid);
-        self.store_new_key(key, creation_result, user_id, Some(flags)).context(ks_err!())
+        let metadata = self
+            .store_new_key(
+                key,
+                creation_result,
+                user_id,
+                Some(flags),
+                NewKeyMetadata { access_window, managed_nonce_prefix, ..Default::default() },
+            )
+            .context(ks_err!())?;
+        Ok((metadata, diff))
     }
 
     fn import_key(
@@ -633,6 +1063,8 @@ impl KeystoreSecurityLevel {
         params: &[KeyParameter],
         flags: i32,
         key_data: &[u8],
+        backup_eligible: bool,
+        deadline: &Deadline,
     ) -> Result<KeyMetadata> {
         if key.domain != Domain::BLOB && key.alias.is_none() {
             return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
@@ -652,37 +1084,103 @@ impl KeystoreSecurityLevel {
 
         // import_key requires the rebind permission.
         check_key_permission(KeyPerm::Rebind, &key, &None).context(ks_err!("In import_key."))?;
+        reserved_alias::check_alias(&key, caller_uid).context(ks_err!())?;
+        USER_LIMITS.check(&key).context(ks_err!())?;
 
+        let (requested_params, access_window) =
+            access_window::extract(&key, params).context(ks_err!())?;
+        let requested_params = requested_params.as_slice();
+        DEPRECATION_EVENTS.on_key_creation(caller_uid, requested_params);
         let params = self
-            .add_required_parameters(caller_uid, params, &key)
+            .add_required_parameters(caller_uid, requested_params, &key)
             .context(ks_err!("Trying to get aaid."))?;
+        let (params, managed_nonce_prefix) =
+            managed_nonce::prepare_new_key(&key, params).context(ks_err!())?;
 
-        let format = params
+        let (algorithm, format) = params
             .iter()
             .find(|p| p.tag == Tag::ALGORITHM)
             .ok_or(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
             .context(ks_err!("No KeyParameter 'Algorithm'."))
             .and_then(|p| match &p.value {
-                KeyParameterValue::Algorithm(Algorithm::AES)
-                | KeyParameterValue::Algorithm(Algorithm::HMAC)
-                | KeyParameterValue::Algorithm(Algorithm::TRIPLE_DES) => Ok(KeyFormat::RAW),
-                KeyParameterValue::Algorithm(Algorithm::RSA)
-                | KeyParameterValue::Algorithm(Algorithm::EC) => Ok(KeyFormat::PKCS8),
+                KeyParameterValue::Algorithm(
+                    algorithm @ (Algorithm::AES | Algorithm::HMAC | Algorithm::TRIPLE_DES),
+                ) => Ok((*algorithm, KeyFormat::RAW)),
+                KeyParameterValue::Algorithm(algorithm @ (Algorithm::RSA | Algorithm::EC)) => {
+                    Ok((*algorithm, KeyFormat::PKCS8))
+                }
                 v => Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
                     .context(ks_err!("Unknown Algorithm {:?}.", v)),
             })
             .context(ks_err!())?;
+        let import_fingerprint = import_fingerprint::check_import(
+            &key,
+            ImportedMaterial::Plain { algorithm, format, data: key_data },
+        )
+        .context(ks_err!())?;
 
-        let km_dev = &self.keymint;
+        deadline.check("calling importKey")?;
+        let km_dev = self.keymint();
         let creation_result = map_km_error({
-            let _wp =
-                self.watch_millis("In KeystoreSecurityLevel::import_key: calling importKey.", 500);
+            let _wp = self
+                .watch_hal_call("In KeystoreSecurityLevel::import_key: calling importKey.", 500)?;
             km_dev.importKey(&params, format, key_data, None /* attestKey */)
         })
         .context(ks_err!("Trying to call importKey"))?;
+        // The client would see an error, so the new key must not replace an existing key.
+        deadline.check("storing the imported key")?;
+        authorization_diff::check_new_key(
+            &key,
+            requested_params,
+            &creation_result.keyCharacteristics,
+        );
 
         let user_id = uid_to_android_user(caller_uid);
-        self.store_new_key(key, creation_result, user_id, Some(flags)).context(ks_err!())
+        let backup_material = if backup_eligible {
+            Some(
+                Self::seal_backup_material(&key, &creation_result, user_id, format, key_data)
+                    .context(ks_err!("Trying to seal key material for backup."))?,
+            )
+        } else {
+            None
+        };
+        self.store_new_key(
+            key,
+            creation_result,
+            user_id,
+            Some(flags),
+            NewKeyMetadata {
+                backup_material,
+                access_window,
+                managed_nonce_prefix,
+                import_fingerprint,
+            },
+        )
+        .context(ks_err!())
+    }
+
+    fn seal_backup_material(
+        key: &KeyDescriptor,
+        creation_result: &KeyCreationResult,
+        user_id: u32,
+        format: KeyFormat,
+        key_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        if key.domain != Domain::APP {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Only app keys can be backed up."));
+        }
+        if key_backup::is_hardware_bound(&creation_result.keyCharacteristics) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Hardware bound keys cannot be backed up."));
+        }
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(user_id)
+            .ok_or(Error::Rc(ResponseCode::LOCKED))
+            .context(ks_err!("User {user_id} has not unlocked the device since boot."))?;
+        key_backup::seal(super_key.as_ref(), format, key_data)
     }
 
     fn import_wrapped_key(
@@ -732,6 +1230,12 @@ impl KeystoreSecurityLevel {
 
         // Import_wrapped_key requires the rebind permission for the new key.
         check_key_permission(KeyPerm::Rebind, &key, &None).context(ks_err!())?;
+        reserved_alias::check_alias(&key, caller_uid).context(ks_err!())?;
+        USER_LIMITS.check(&key).context(ks_err!())?;
+
+        let import_fingerprint =
+            import_fingerprint::check_import(&key, ImportedMaterial::Wrapped(wrapped_data))
+                .context(ks_err!())?;
 
         let super_key = SUPER_KEY.read().unwrap().get_after_first_unlock_key_by_user_id(user_id);
 
@@ -789,11 +1293,11 @@ impl KeystoreSecurityLevel {
                 wrapping_blob_metadata.km_uuid().copied(),
                 &[],
                 |wrapping_blob| {
-                    let _wp = self.watch_millis(
+                    let _wp = self.watch_hal_call(
                         "In KeystoreSecurityLevel::import_wrapped_key: calling importWrappedKey.",
                         500,
-                    );
-                    let creation_result = map_km_error(self.keymint.importWrappedKey(
+                    )?;
+                    let creation_result = map_km_error(self.keymint().importWrappedKey(
                         wrapped_data,
                         wrapping_blob,
                         masking_key,
@@ -806,8 +1310,14 @@ impl KeystoreSecurityLevel {
             )
             .context(ks_err!())?;
 
-        self.store_new_key(key, creation_result, user_id, None)
-            .context(ks_err!("Trying to store the new key."))
+        self.store_new_key(
+            key,
+            creation_result,
+            user_id,
+            None,
+            NewKeyMetadata { import_fingerprint, ..Default::default() },
+        )
+        .context(ks_err!("Trying to store the new key."))
     }
 
     fn store_upgraded_keyblob(
@@ -849,7 +1359,7 @@ impl KeystoreSecurityLevel {
         F: Fn(&[u8]) -> Result<T, Error>,
     {
         let (v, upgraded_blob) = crate::utils::upgrade_keyblob_if_required_with(
-            &*self.keymint,
+            &*self.keymint(),
             self.hw_info.versionNumber,
             key_blob,
             params,
@@ -889,7 +1399,7 @@ impl KeystoreSecurityLevel {
         F: Fn(&[u8]) -> Result<T, Error>,
     {
         crate::utils::upgrade_keyblob_if_required_with(
-            &*self.keymint,
+            &*self.keymint(),
             self.hw_info.versionNumber,
             key_blob,
             params,
@@ -920,15 +1430,15 @@ impl KeystoreSecurityLevel {
         check_key_permission(KeyPerm::ConvertStorageKeyToEphemeral, storage_key, &None)
             .context(ks_err!("Check permission"))?;
 
-        let km_dev = &self.keymint;
+        let km_dev = self.keymint();
         match {
-            let _wp = self.watch_millis(
+            let _wp = self.watch_hal_call(
                 concat!(
                     "In IKeystoreSecurityLevel::convert_storage_key_to_ephemeral: ",
                     "calling convertStorageKeyToEphemeral (1)"
                 ),
                 500,
-            );
+            )?;
             map_km_error(km_dev.convertStorageKeyToEphemeral(key_blob))
         } {
             Ok(result) => {
@@ -936,18 +1446,18 @@ impl KeystoreSecurityLevel {
             }
             Err(error::Error::Km(ErrorCode::KEY_REQUIRES_UPGRADE)) => {
                 let upgraded_blob = {
-                    let _wp = self.watch_millis(
+                    let _wp = self.watch_hal_call(
                         "In convert_storage_key_to_ephemeral: calling upgradeKey",
                         500,
-                    );
+                    )?;
                     map_km_error(km_dev.upgradeKey(key_blob, &[]))
                 }
                 .context(ks_err!("Failed to upgrade key blob."))?;
                 let ephemeral_key = {
-                    let _wp = self.watch_millis(
+                    let _wp = self.watch_hal_call(
                         "In convert_storage_key_to_ephemeral: calling convertStorageKeyToEphemeral (2)",
                         500,
-                    );
+                    )?;
                     map_km_error(km_dev.convertStorageKeyToEphemeral(&upgraded_blob))
                 }
                     .context(ks_err!(
@@ -977,10 +1487,10 @@ impl KeystoreSecurityLevel {
         check_key_permission(KeyPerm::Delete, key, &None)
             .context(ks_err!("delete_key: Checking delete permissions"))?;
 
-        let km_dev = &self.keymint;
+        let km_dev = self.keymint();
         {
-            let _wp =
-                self.watch_millis("In KeystoreSecuritylevel::delete_key: calling deleteKey", 500);
+            let _wp = self
+                .watch_hal_call("In KeystoreSecuritylevel::delete_key: calling deleteKey", 500)?;
             map_km_error(km_dev.deleteKey(key_blob)).context(ks_err!("keymint device deleteKey"))
         }
     }
@@ -996,7 +1506,10 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         forced: bool,
     ) -> binder::Result<CreateOperationResponse> {
         let _wp = self.watch_millis("IKeystoreSecurityLevel::createOperation", 500);
-        map_or_log_err(self.create_operation(key, operation_parameters, forced), Ok)
+        map_or_log_err(
+            self.create_operation(key, operation_parameters, forced, false, &Deadline::none()),
+            Ok,
+        )
     }
     fn generateKey(
         &self,
@@ -1009,7 +1522,9 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         // Duration is set to 5 seconds, because generateKey - especially for RSA keys, takes more
         // time than other operations
         let _wp = self.watch_millis("IKeystoreSecurityLevel::generateKey", 5000);
-        let result = self.generate_key(key, attestation_key, params, flags, entropy);
+        let result = self
+            .generate_key(key, attestation_key, params, flags, entropy, &Deadline::none())
+            .map(|(metadata, _)| metadata);
         log_key_creation_event_stats(self.security_level, params, &result);
         log_key_generated(key, ThreadState::get_calling_uid(), result.is_ok());
         map_or_log_err(result, Ok)
@@ -1023,7 +1538,15 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         key_data: &[u8],
     ) -> binder::Result<KeyMetadata> {
         let _wp = self.watch_millis("IKeystoreSecurityLevel::importKey", 500);
-        let result = self.import_key(key, attestation_key, params, flags, key_data);
+        let result = self.import_key(
+            key,
+            attestation_key,
+            params,
+            flags,
+            key_data,
+            false, /* backup_eligible */
+            &Deadline::none(),
+        );
         log_key_creation_event_stats(self.security_level, params, &result);
         log_key_imported(key, ThreadState::get_calling_uid(), result.is_ok());
         map_or_log_err(result, Ok)
@@ -1057,3 +1580,164 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         map_or_log_err(result, Ok)
     }
 }
+
+// Implementation of IKeystoreSecurityLevelExtension. See AIDL spec at
+// system/security/keystore2/aidl/android/security/keystoreextension/IKeystoreSecurityLevelExtension.aidl
+impl IKeystoreSecurityLevelExtension for KeystoreSecurityLevel {
+    fn importBackupEligibleKey(
+        &self,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        key_data: &[u8],
+    ) -> binder::Result<KeyMetadata> {
+        let _wp =
+            self.watch_millis("IKeystoreSecurityLevelExtension::importBackupEligibleKey", 500);
+        let result = self.import_key(
+            key,
+            attestation_key,
+            params,
+            flags,
+            key_data,
+            true, /* backup_eligible */
+            &Deadline::none(),
+        );
+        log_key_creation_event_stats(self.security_level, params, &result);
+        log_key_imported(key, ThreadState::get_calling_uid(), result.is_ok());
+        map_or_log_err(result, Ok)
+    }
+    fn createOperationWithDeadline(
+        &self,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        forced: bool,
+        timeout_millis: i64,
+    ) -> binder::Result<CreateOperationResponse> {
+        let _wp =
+            self.watch_millis("IKeystoreSecurityLevelExtension::createOperationWithDeadline", 500);
+        let deadline = Deadline::from_timeout_millis(timeout_millis);
+        map_or_log_err(
+            self.create_operation(key, operation_parameters, forced, false, &deadline),
+            Ok,
+        )
+    }
+    fn generateKeyWithDeadline(
+        &self,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        entropy: &[u8],
+        timeout_millis: i64,
+    ) -> binder::Result<KeyMetadata> {
+        let _wp =
+            self.watch_millis("IKeystoreSecurityLevelExtension::generateKeyWithDeadline", 5000);
+        let deadline = Deadline::from_timeout_millis(timeout_millis);
+        let result = self
+            .generate_key(key, attestation_key, params, flags, entropy, &deadline)
+            .map(|(metadata, _)| metadata);
+        log_key_creation_event_stats(self.security_level, params, &result);
+        log_key_generated(key, ThreadState::get_calling_uid(), result.is_ok());
+        map_or_log_err(result, Ok)
+    }
+    fn importKeyWithDeadline(
+        &self,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        key_data: &[u8],
+        timeout_millis: i64,
+    ) -> binder::Result<KeyMetadata> {
+        let _wp = self.watch_millis("IKeystoreSecurityLevelExtension::importKeyWithDeadline", 500);
+        let deadline = Deadline::from_timeout_millis(timeout_millis);
+        let result = self.import_key(
+            key,
+            attestation_key,
+            params,
+            flags,
+            key_data,
+            false, /* backup_eligible */
+            &deadline,
+        );
+        log_key_creation_event_stats(self.security_level, params, &result);
+        log_key_imported(key, ThreadState::get_calling_uid(), result.is_ok());
+        map_or_log_err(result, Ok)
+    }
+    fn doOneShotOperation(
+        &self,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        input: &[u8],
+    ) -> binder::Result<Option<Vec<u8>>> {
+        let _wp = self.watch_millis("IKeystoreSecurityLevelExtension::doOneShotOperation", 1000);
+        map_or_log_err(self.one_shot_operation(key, operation_parameters, input), Ok)
+    }
+    fn signDigest(
+        &self,
+        key: &KeyDescriptor,
+        digest: Digest,
+        hash: &[u8],
+    ) -> binder::Result<Vec<u8>> {
+        let _wp = self.watch_millis("IKeystoreSecurityLevelExtension::signDigest", 1000);
+        map_or_log_err(self.sign_digest(key, digest, hash), Ok)
+    }
+    fn registerOperationSlotListener(
+        &self,
+        listener: &Strong<dyn IOperationSlotListener>,
+        threshold: i32,
+    ) -> binder::Result<()> {
+        let _wp = self
+            .watch_millis("IKeystoreSecurityLevelExtension::registerOperationSlotListener", 500);
+        map_or_log_err(
+            self.operation_db.register_slot_listener(
+                ThreadState::get_calling_uid(),
+                threshold,
+                listener,
+            ),
+            Ok,
+        )
+    }
+    fn transferOperation(
+        &self,
+        operation: &Strong<dyn IKeystoreOperation>,
+        target_uid: i32,
+    ) -> binder::Result<()> {
+        let _wp = self.watch_millis("IKeystoreSecurityLevelExtension::transferOperation", 500);
+        map_or_log_err(self.transfer_operation(operation, target_uid), Ok)
+    }
+    fn createOperationWithReplay(
+        &self,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        forced: bool,
+    ) -> binder::Result<CreateOperationResponse> {
+        let _wp =
+            self.watch_millis("IKeystoreSecurityLevelExtension::createOperationWithReplay", 500);
+        map_or_log_err(
+            self.create_operation(key, operation_parameters, forced, true, &Deadline::none()),
+            Ok,
+        )
+    }
+    fn generateKeyWithAuthorizationDiff(
+        &self,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        entropy: &[u8],
+    ) -> binder::Result<GeneratedKey> {
+        let _wp = self.watch_millis(
+            "IKeystoreSecurityLevelExtension::generateKeyWithAuthorizationDiff",
+            5000,
+        );
+        let result =
+            self.generate_key(key, attestation_key, params, flags, entropy, &Deadline::none());
+        log_key_creation_event_stats(self.security_level, params, &result);
+        log_key_generated(key, ThreadState::get_calling_uid(), result.is_ok());
+        map_or_log_err(result, |(metadata, diff)| {
+            Ok(GeneratedKey { metadata, authorizationDiff: diff.to_aidl() })
+        })
+    }
+}
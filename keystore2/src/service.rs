@@ -16,30 +16,60 @@
 //! AIDL spec.
 
 use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::Write;
 
+use crate::access_window::{self, AccessWindow};
 use crate::audit_log::log_key_deleted;
+use crate::cert_chain;
+use crate::database_binding;
+use crate::dump_fields;
+use crate::dump_schema::{self, DumpSection};
+use crate::feature_flags::{self, Feature};
+use crate::grant_cache::GRANT_CACHE;
+use crate::key_parameter::KeyParameter as KsKeyParam;
+use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
 use crate::ks_err;
+use crate::lock_stats;
 use crate::permission::{KeyPerm, KeystorePerm};
+use crate::reserved_alias;
 use crate::security_level::KeystoreSecurityLevel;
 use crate::utils::{
     check_grant_permission, check_key_permission, check_keystore_permission, count_key_entries,
-    key_parameters_to_authorizations, list_key_entries, uid_to_android_user, watchdog as wd,
+    key_parameters_to_authorizations, list_key_entries, stream_key_entries, uid_to_android_user,
+    watchdog as wd,
 };
 use crate::{
     database::Uuid,
-    globals::{create_thread_local_db, DB, LEGACY_BLOB_LOADER, LEGACY_IMPORTER, SUPER_KEY},
+    globals::{
+        create_thread_local_db, ANOMALY_DETECTOR, DB, DEPRECATION_EVENTS, HAL_HEALTH,
+        KEY_OPERATION_STATS, LEGACY_BLOB_LOADER, LEGACY_IMPORTER, PATCH_LEVEL_POLICY,
+        STRONGBOX_GENERATIONS, SUPER_KEY, USER_LIMITS,
+    },
 };
-use crate::{database::KEYSTORE_UUID, permission};
+use crate::{database::KEYSTORE_UUID, permission, shared_secret_negotiation};
 use crate::{
-    database::{KeyEntryLoadBits, KeyType, SubComponentType},
+    database::{shadow, GrantConstraints, KeyEntryLoadBits, KeyType, SubComponentType},
     error::ResponseCode,
 };
 use crate::{
-    error::{self, map_or_log_err, ErrorCode},
+    error::{self, map_binder_status, map_or_log_err, ErrorCode},
     id_rotation::IdRotationState,
 };
-use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
+    SecurityLevel::SecurityLevel,
+};
 use android_hardware_security_keymint::binder::{BinderFeatures, Strong, ThreadState};
+use android_security_keystoreextension::aidl::android::security::keystoreextension::{
+    DeprecationWarning::DeprecationWarning,
+    GrantConstraints::GrantConstraints as AidlGrantConstraints,
+    IKeystoreSecurityLevelExtension::IKeystoreSecurityLevelExtension,
+    IKeystoreServiceExtension::BnKeystoreServiceExtension,
+    IKeystoreServiceExtension::IKeystoreServiceExtension,
+    IListEntriesCallback::IListEntriesCallback,
+};
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
     IKeystoreService::BnKeystoreService, IKeystoreService::IKeystoreService,
@@ -47,34 +77,61 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 use anyhow::{Context, Result};
 use error::Error;
+use keystore2_crypto::{verify_signature_with_certificate, SignatureDigest, SignaturePadding};
 use keystore2_selinux as selinux;
 
-/// Implementation of the IKeystoreService.
-#[derive(Default)]
+/// The dump section of the key history.
+pub(crate) const KEY_HISTORY_SECTION: DumpSection = DumpSection {
+    name: "key_history",
+    header: "Key history (key id hash, event, domain, namespace, time):",
+    line: "  <key_id_hash>, <event>, <domain>, <namespace>, <time_millis>",
+    fields: dump_fields!(
+        key_id_hash: "int64",
+        event: "enum:KeyHistoryEvent",
+        domain: "enum:Domain",
+        namespace: "int64",
+        time_millis: "int64",
+    ),
+};
+
+/// The dump section of the blobs pending deletion. The header is followed by their number.
+pub(crate) const PENDING_BLOB_DELETIONS_SECTION: DumpSection = DumpSection {
+    name: "pending_blob_deletions",
+    header: "Rollback resistant key blobs pending deletion confirmation:",
+    line: "  blob id <blob_id>, <attempts> failed attempts",
+    fields: dump_fields!(blob_id: "int64", attempts: "int64"),
+};
+
+/// Implementation of the IKeystoreService and the IKeystoreServiceExtension.
+#[derive(Clone, Default)]
 pub struct KeystoreService {
     i_sec_level_by_uuid: HashMap<Uuid, Strong<dyn IKeystoreSecurityLevel>>,
+    i_sec_level_ext_by_uuid: HashMap<Uuid, Strong<dyn IKeystoreSecurityLevelExtension>>,
     uuid_by_sec_level: HashMap<SecurityLevel, Uuid>,
 }
 
 impl KeystoreService {
-    /// Create a new instance of the Keystore 2.0 service.
+    /// Create a new instance of the Keystore 2.0 service. The second binder serves the
+    /// extensions of the service with the same state.
     pub fn new_native_binder(
         id_rotation_state: IdRotationState,
-    ) -> Result<Strong<dyn IKeystoreService>> {
+    ) -> Result<(Strong<dyn IKeystoreService>, Strong<dyn IKeystoreServiceExtension>)> {
         let mut result: Self = Default::default();
-        let (dev, uuid) = KeystoreSecurityLevel::new_native_binder(
+        let (dev, dev_ext, uuid) = KeystoreSecurityLevel::new_native_binder(
             SecurityLevel::TRUSTED_ENVIRONMENT,
             id_rotation_state.clone(),
         )
         .context(ks_err!("Trying to construct mandatory security level TEE."))?;
         result.i_sec_level_by_uuid.insert(uuid, dev);
+        result.i_sec_level_ext_by_uuid.insert(uuid, dev_ext);
         result.uuid_by_sec_level.insert(SecurityLevel::TRUSTED_ENVIRONMENT, uuid);
 
         // Strongbox is optional, so we ignore errors and turn the result into an Option.
-        if let Ok((dev, uuid)) =
+        if let Ok((dev, dev_ext, uuid)) =
             KeystoreSecurityLevel::new_native_binder(SecurityLevel::STRONGBOX, id_rotation_state)
         {
             result.i_sec_level_by_uuid.insert(uuid, dev);
+            result.i_sec_level_ext_by_uuid.insert(uuid, dev_ext);
             result.uuid_by_sec_level.insert(SecurityLevel::STRONGBOX, uuid);
         }
 
@@ -85,10 +142,15 @@ impl KeystoreService {
             })
             .context(ks_err!("Trying to initialize the legacy migrator."))?;
 
-        Ok(BnKeystoreService::new_binder(
+        let extension = BnKeystoreServiceExtension::new_binder(
+            result.clone(),
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        );
+        let service = BnKeystoreService::new_binder(
             result,
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
-        ))
+        );
+        Ok((service, extension))
     }
 
     fn uuid_to_sec_level(&self, uuid: &Uuid) -> SecurityLevel {
@@ -123,6 +185,22 @@ impl KeystoreService {
         }
     }
 
+    fn get_security_level_extension(
+        &self,
+        sec_level: SecurityLevel,
+    ) -> Result<Strong<dyn IKeystoreSecurityLevelExtension>> {
+        if let Some(dev_ext) = self
+            .uuid_by_sec_level
+            .get(&sec_level)
+            .and_then(|uuid| self.i_sec_level_ext_by_uuid.get(uuid))
+        {
+            Ok(dev_ext.clone())
+        } else {
+            Err(error::Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+                .context(ks_err!("No such security level."))
+        }
+    }
+
     fn get_key_entry(&self, key: &KeyDescriptor) -> Result<KeyEntryResponse> {
         let caller_uid = ThreadState::get_calling_uid();
 
@@ -153,6 +231,8 @@ impl KeystoreService {
         } else {
             None
         };
+        let access_window =
+            access_window::from_metadata(key_entry.metadata()).context(ks_err!())?;
 
         Ok(KeyEntryResponse {
             iSecurityLevel: i_sec_level,
@@ -171,17 +251,181 @@ impl KeystoreService {
                     .map(|d| d.to_millis_epoch())
                     .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
                     .context(ks_err!("Trying to get creation date."))?,
-                authorizations: key_parameters_to_authorizations(key_entry.into_key_parameters()),
+                authorizations: key_parameters_to_authorizations(key_entry.into_key_parameters())
+                    .into_iter()
+                    .chain(access_window.map(AccessWindow::to_authorization))
+                    .collect(),
             },
         })
     }
 
+    fn get_key_id_for_alias(&self, key: &KeyDescriptor) -> Result<KeyMetadata> {
+        let caller_uid = ThreadState::get_calling_uid();
+
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(uid_to_android_user(caller_uid));
+
+        let (key_id, km_uuid) = DB
+            .with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().load_key_id(key, KeyType::Client, caller_uid, |k, av| {
+                        check_key_permission(KeyPerm::GetInfo, k, &av)
+                    })
+                })
+            })
+            .context(ks_err!("while trying to look up key id."))?;
+
+        Ok(KeyMetadata {
+            key: KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, ..Default::default() },
+            keySecurityLevel: self.uuid_to_sec_level(&km_uuid),
+            ..Default::default()
+        })
+    }
+
+    fn get_cert_signature_algorithm(&self, key: &KeyDescriptor) -> Result<Option<String>> {
+        let caller_uid = ThreadState::get_calling_uid();
+
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(uid_to_android_user(caller_uid));
+
+        let (_, key_entry) = DB
+            .with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().load_key_entry(
+                        key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::NONE,
+                        caller_uid,
+                        |k, av| check_key_permission(KeyPerm::GetInfo, k, &av),
+                    )
+                })
+            })
+            .context(ks_err!("while trying to load key info."))?;
+
+        Ok(key_entry.metadata().cert_signature_algorithm().cloned())
+    }
+
+    fn verify_signature(
+        &self,
+        key: &KeyDescriptor,
+        params: &[KeyParameter],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool> {
+        let caller_uid = ThreadState::get_calling_uid();
+
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(uid_to_android_user(caller_uid));
+
+        // The public key never leaves keystore here, so USE suffices and GET_INFO is not
+        // required. This lets grantees verify signatures of keys whose identity they must not
+        // learn.
+        let (_key_id_guard, mut key_entry) = DB
+            .with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().load_key_entry(
+                        key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::PUBLIC,
+                        caller_uid,
+                        |k, av| check_key_permission(KeyPerm::Use, k, &av),
+                    )
+                })
+            })
+            .context(ks_err!("while trying to load key info."))?;
+
+        let (digest, padding) = Self::get_signature_params(key_entry.key_parameters(), params)?;
+        let cert = key_entry
+            .take_cert()
+            .ok_or(Error::Km(ErrorCode::INCOMPATIBLE_ALGORITHM))
+            .context(ks_err!("Key has no certificate."))?;
+        verify_signature_with_certificate(&cert, digest, padding, message, signature)
+            .map_err(|_| Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            .context(ks_err!("Failed to verify signature with the key certificate."))
+    }
+
+    /// Selects the digest and padding of a signature verification from `params` and checks
+    /// that they fit the algorithm of the key, which must be a signing key.
+    fn get_signature_params(
+        key_params: &[KsKeyParam],
+        params: &[KeyParameter],
+    ) -> Result<(SignatureDigest, SignaturePadding)> {
+        if !key_params.iter().any(|p| {
+            matches!(
+                p.key_parameter_value(),
+                KsKeyParamValue::KeyPurpose(KeyPurpose::SIGN | KeyPurpose::VERIFY)
+            )
+        }) {
+            return Err(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE))
+                .context(ks_err!("Key is not a signing key."));
+        }
+        let algorithm = key_params.iter().find_map(|p| match p.key_parameter_value() {
+            KsKeyParamValue::Algorithm(algorithm) => Some(*algorithm),
+            _ => None,
+        });
+        let digest = match params.iter().find_map(|p| match p.value {
+            KeyParameterValue::Digest(digest) => Some(digest),
+            _ => None,
+        }) {
+            Some(Digest::SHA1) => SignatureDigest::Sha1,
+            Some(Digest::SHA_2_224) => SignatureDigest::Sha224,
+            Some(Digest::SHA_2_256) => SignatureDigest::Sha256,
+            Some(Digest::SHA_2_384) => SignatureDigest::Sha384,
+            Some(Digest::SHA_2_512) => SignatureDigest::Sha512,
+            digest => {
+                return Err(Error::Km(ErrorCode::UNSUPPORTED_DIGEST))
+                    .context(ks_err!("Unsupported digest {:?}.", digest));
+            }
+        };
+        let padding = params.iter().find_map(|p| match p.value {
+            KeyParameterValue::PaddingMode(padding) => Some(padding),
+            _ => None,
+        });
+        let padding = match (algorithm, padding) {
+            (Some(Algorithm::EC), None | Some(PaddingMode::NONE)) => SignaturePadding::None,
+            (Some(Algorithm::RSA), Some(PaddingMode::RSA_PKCS1_1_5_SIGN)) => {
+                SignaturePadding::RsaPkcs1_1_5
+            }
+            (Some(Algorithm::RSA), Some(PaddingMode::RSA_PSS)) => SignaturePadding::RsaPss,
+            (Some(Algorithm::EC | Algorithm::RSA), padding) => {
+                return Err(Error::Km(ErrorCode::UNSUPPORTED_PADDING_MODE))
+                    .context(ks_err!("Unsupported padding {padding:?} for {algorithm:?}."));
+            }
+            _ => {
+                return Err(Error::Km(ErrorCode::INCOMPATIBLE_ALGORITHM))
+                    .context(ks_err!("Cannot verify signatures of {:?} keys.", algorithm));
+            }
+        };
+        Ok((digest, padding))
+    }
+
     fn update_subcomponent(
         &self,
         key: &KeyDescriptor,
         public_cert: Option<&[u8]>,
         certificate_chain: Option<&[u8]>,
+        strict: bool,
     ) -> Result<()> {
+        // In strict mode, the certificates are validated and normalized before anything is
+        // stored. See `cert_chain`.
+        let normalized = if strict {
+            Some(
+                cert_chain::normalize(public_cert, certificate_chain)
+                    .context(ks_err!("Invalid certificates."))?,
+            )
+        } else {
+            None
+        };
+        let (public_cert, certificate_chain) = match &normalized {
+            Some((cert, chain)) => (cert.as_deref(), chain.as_deref()),
+            None => (public_cert, certificate_chain),
+        };
         let caller_uid = ThreadState::get_calling_uid();
         let super_key = SUPER_KEY
             .read()
@@ -193,7 +437,8 @@ impl KeystoreService {
                 db.borrow_mut().load_key_entry(
                     key,
                     KeyType::Client,
-                    KeyEntryLoadBits::NONE,
+                    // The current certificate holds the public key for the strict mode check.
+                    if strict { KeyEntryLoadBits::PUBLIC } else { KeyEntryLoadBits::NONE },
                     caller_uid,
                     |k, av| check_key_permission(KeyPerm::Update, k, &av).context(ks_err!()),
                 )
@@ -207,7 +452,11 @@ impl KeystoreService {
             .context(ks_err!("Failed to load key entry."))?;
 
             let mut db = db.borrow_mut();
-            if let Some((key_id_guard, _key_entry)) = entry {
+            if let Some((key_id_guard, key_entry)) = entry {
+                if let (true, Some(leaf)) = (strict, public_cert) {
+                    cert_chain::check_leaf_matches_key(leaf, key_entry.cert().as_deref())
+                        .context(ks_err!())?;
+                }
                 db.set_blob(&key_id_guard, SubComponentType::CERT, public_cert, None)
                     .context(ks_err!("Failed to update cert subcomponent."))?;
 
@@ -242,6 +491,7 @@ impl KeystoreService {
             // Security critical: This must return on failure. Do not remove the `?`;
             check_key_permission(KeyPerm::Rebind, &key, &None)
                 .context(ks_err!("Caller does not have permission to insert this certificate."))?;
+            reserved_alias::check_alias(&key, ThreadState::get_calling_uid()).context(ks_err!())?;
 
             db.store_new_certificate(
                 &key,
@@ -317,6 +567,47 @@ impl KeystoreService {
         DB.with(|db| list_key_entries(&mut db.borrow_mut(), k.domain, k.nspace, start_past_alias))
     }
 
+    fn list_entries_streamed(
+        &self,
+        domain: Domain,
+        namespace: i64,
+        callback: &Strong<dyn IListEntriesCallback>,
+    ) -> Result<()> {
+        if !feature_flags::is_enabled(Feature::StreamedListEntries) {
+            return Err(Error::Km(ErrorCode::UNIMPLEMENTED))
+                .context(ks_err!("listEntriesStreamed is not enabled."));
+        }
+        let k = self.get_key_descriptor_for_lookup(domain, namespace)?;
+        stream_key_entries(
+            |start_past_alias| {
+                DB.with(|db| {
+                    list_key_entries(&mut db.borrow_mut(), k.domain, k.nspace, start_past_alias)
+                })
+            },
+            // The callback is synchronous, so the next chunk is only listed once the client
+            // has taken the previous one.
+            |entries| {
+                map_binder_status(callback.onEntries(entries))
+                    .context(ks_err!("The callback failed."))
+            },
+        )
+        .context(ks_err!())?;
+        Ok(())
+    }
+
+    fn get_deprecation_warnings(&self) -> Vec<DeprecationWarning> {
+        DEPRECATION_EVENTS
+            .get(ThreadState::get_calling_uid())
+            .into_iter()
+            .map(|e| DeprecationWarning {
+                deprecationId: e.deprecation.id.to_string(),
+                removalTimeline: e.deprecation.removal.to_string(),
+                count: e.count as i64,
+                lastUsedMillis: e.last_used,
+            })
+            .collect()
+    }
+
     fn delete_key(&self, key: &KeyDescriptor) -> Result<()> {
         let caller_uid = ThreadState::get_calling_uid();
         let super_key = SUPER_KEY
@@ -336,12 +627,76 @@ impl KeystoreService {
         Ok(())
     }
 
+    fn rename_key(&self, key: &KeyDescriptor, new_alias: &str) -> Result<()> {
+        if !feature_flags::is_enabled(Feature::RenameKey) {
+            return Err(Error::Km(ErrorCode::UNIMPLEMENTED))
+                .context(ks_err!("renameKey is not enabled."));
+        }
+        let caller_uid = ThreadState::get_calling_uid();
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(uid_to_android_user(caller_uid));
+
+        DB.with(|db| {
+            LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                db.borrow_mut().rename_key(key, new_alias, caller_uid, |k| {
+                    check_key_permission(KeyPerm::Rebind, k, &None)
+                        .context(ks_err!("During rename_key."))?;
+                    reserved_alias::check_alias(k, caller_uid)
+                })
+            })
+        })
+        .context(ks_err!("Trying to rename the key."))
+    }
+
+    fn prewarm_key(&self, key: &KeyDescriptor) -> Result<()> {
+        if key.domain == Domain::BLOB {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Keys in Domain::BLOB cannot be prewarmed."));
+        }
+        let caller_uid = ThreadState::get_calling_uid();
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_after_first_unlock_key_by_user_id(uid_to_android_user(caller_uid));
+
+        // The permission check is the same as for creating an operation.
+        let (_key_id_guard, mut key_entry) = DB
+            .with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().load_key_entry(
+                        key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::KM,
+                        caller_uid,
+                        |k, av| check_key_permission(KeyPerm::Use, k, &av),
+                    )
+                })
+            })
+            .context(ks_err!("Failed to load key blob."))?;
+        let (blob, blob_metadata) = key_entry
+            .take_key_blob_info()
+            .ok_or_else(Error::sys)
+            .context(ks_err!("Successfully loaded key entry, but KM blob was missing."))?;
+        SUPER_KEY.read().unwrap().prewarm_key(&blob_metadata, &blob).context(ks_err!())
+    }
+
     fn grant(
         &self,
         key: &KeyDescriptor,
         grantee_uid: i32,
         access_vector: permission::KeyPermSet,
+        constraints: &GrantConstraints,
     ) -> Result<KeyDescriptor> {
+        if constraints.auth_sids.as_ref().map_or(false, |sids| sids.is_empty()) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("A grant that requires authentication needs secure user ids."));
+        }
+        if constraints.purposes.as_ref().map_or(false, |purposes| purposes.is_empty()) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("A grant that is limited to purposes needs at least one."));
+        }
         let caller_uid = ThreadState::get_calling_uid();
         let super_key = SUPER_KEY
             .read()
@@ -350,12 +705,17 @@ impl KeystoreService {
 
         DB.with(|db| {
             LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
-                db.borrow_mut().grant(
+                db.borrow_mut().grant_with_constraints(
                     key,
                     caller_uid,
                     grantee_uid as u32,
                     access_vector,
-                    |k, av| check_grant_permission(*av, k).context("During grant."),
+                    constraints,
+                    |k, av| {
+                        permission::check_sdk_sandbox_grant(k, grantee_uid as u32)
+                            .context("During grant.")?;
+                        check_grant_permission(*av, k).context("During grant.")
+                    },
                 )
             })
         })
@@ -370,9 +730,81 @@ impl KeystoreService {
         })
         .context(ks_err!("KeystoreService::ungrant."))
     }
+
+    /// Writes the key history to `writer`. A database failure is reported in the dump instead
+    /// of failing it, so that the remaining state is still dumped.
+    fn dump_key_history(writer: &mut dyn Write) -> std::io::Result<()> {
+        KEY_HISTORY_SECTION.write_header(writer)?;
+        match DB.with(|db| db.borrow_mut().get_key_history(None)) {
+            Ok(history) => {
+                for entry in history {
+                    KEY_HISTORY_SECTION.write_line(
+                        writer,
+                        &[
+                            &entry.key_id_hash,
+                            &format!("{:?}", entry.event),
+                            &format!("{:?}", entry.domain),
+                            &entry.namespace,
+                            &entry.time.to_millis_epoch(),
+                        ],
+                    )?;
+                }
+                Ok(())
+            }
+            Err(e) => writeln!(writer, "  Failed to load the key history: {:?}", e),
+        }
+    }
+
+    fn dump_pending_blob_deletions(writer: &mut dyn Write) -> std::io::Result<()> {
+        match DB.with(|db| db.borrow_mut().get_pending_blob_deletions()) {
+            Ok(pending) => {
+                writeln!(writer, "{} {}", PENDING_BLOB_DELETIONS_SECTION.header, pending.len())?;
+                for (blob_id, attempts) in pending {
+                    PENDING_BLOB_DELETIONS_SECTION.write_line(writer, &[&blob_id, &attempts])?;
+                }
+                Ok(())
+            }
+            Err(e) => writeln!(writer, "Failed to load pending blob deletions: {:?}", e),
+        }
+    }
 }
 
-impl binder::Interface for KeystoreService {}
+impl binder::Interface for KeystoreService {
+    fn dump(&self, writer: &mut dyn Write, args: &[&CStr]) -> binder::Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        if let Err(e) = check_keystore_permission(KeystorePerm::Dump) {
+            log::warn!("In KeystoreService::dump: {:?}", e);
+            return Err(binder::StatusCode::PERMISSION_DENIED);
+        }
+        if args.iter().any(|arg| arg.to_bytes() == b"--schema") {
+            return dump_schema::write_json(writer).map_err(|e| {
+                log::error!("In KeystoreService::dump: Failed to write schema: {:?}", e);
+                binder::StatusCode::UNKNOWN_ERROR
+            });
+        }
+        shared_secret_negotiation::dump_state(writer)
+            .and_then(|_| database_binding::dump(writer))
+            .and_then(|_| feature_flags::dump(writer))
+            .and_then(|_| lock_stats::dump(writer))
+            .and_then(|_| SUPER_KEY.read().unwrap().key_material_cache().dump(writer))
+            .and_then(|_| SUPER_KEY.read().unwrap().dump(writer))
+            .and_then(|_| GRANT_CACHE.dump(writer))
+            .and_then(|_| Self::dump_key_history(writer))
+            .and_then(|_| Self::dump_pending_blob_deletions(writer))
+            .and_then(|_| shadow::dump(writer))
+            .and_then(|_| STRONGBOX_GENERATIONS.dump(writer))
+            .and_then(|_| KEY_OPERATION_STATS.dump(writer))
+            .and_then(|_| USER_LIMITS.dump(writer))
+            .and_then(|_| DEPRECATION_EVENTS.dump(writer))
+            .and_then(|_| ANOMALY_DETECTOR.dump(writer))
+            .and_then(|_| PATCH_LEVEL_POLICY.dump(writer))
+            .and_then(|_| HAL_HEALTH.dump(writer))
+            .map_err(|e| {
+                log::error!("In KeystoreService::dump: Failed to write state: {:?}", e);
+                binder::StatusCode::UNKNOWN_ERROR
+            })
+    }
+}
 
 // Implementation of IKeystoreService. See AIDL spec at
 // system/security/keystore2/binder/android/security/keystore2/IKeystoreService.aidl
@@ -397,7 +829,7 @@ impl IKeystoreService for KeystoreService {
         certificate_chain: Option<&[u8]>,
     ) -> binder::Result<()> {
         let _wp = wd::watch_millis("IKeystoreService::updateSubcomponent", 500);
-        map_or_log_err(self.update_subcomponent(key, public_cert, certificate_chain), Ok)
+        map_or_log_err(self.update_subcomponent(key, public_cert, certificate_chain, false), Ok)
     }
     fn listEntries(&self, domain: Domain, namespace: i64) -> binder::Result<Vec<KeyDescriptor>> {
         let _wp = wd::watch_millis("IKeystoreService::listEntries", 500);
@@ -416,7 +848,10 @@ impl IKeystoreService for KeystoreService {
         access_vector: i32,
     ) -> binder::Result<KeyDescriptor> {
         let _wp = wd::watch_millis("IKeystoreService::grant", 500);
-        map_or_log_err(self.grant(key, grantee_uid, access_vector.into()), Ok)
+        map_or_log_err(
+            self.grant(key, grantee_uid, access_vector.into(), &GrantConstraints::default()),
+            Ok,
+        )
     }
     fn ungrant(&self, key: &KeyDescriptor, grantee_uid: i32) -> binder::Result<()> {
         let _wp = wd::watch_millis("IKeystoreService::ungrant", 500);
@@ -437,3 +872,82 @@ impl IKeystoreService for KeystoreService {
         map_or_log_err(self.count_num_entries(domain, namespace), Ok)
     }
 }
+
+// Implementation of IKeystoreServiceExtension. See AIDL spec at
+// system/security/keystore2/aidl/android/security/keystoreextension/IKeystoreServiceExtension.aidl
+impl IKeystoreServiceExtension for KeystoreService {
+    fn getKeyIdForAlias(&self, key: &KeyDescriptor) -> binder::Result<KeyMetadata> {
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::getKeyIdForAlias", 500);
+        map_or_log_err(self.get_key_id_for_alias(key), Ok)
+    }
+    fn getSecurityLevelExtension(
+        &self,
+        security_level: SecurityLevel,
+    ) -> binder::Result<Strong<dyn IKeystoreSecurityLevelExtension>> {
+        let _wp = wd::watch_millis_with(
+            "IKeystoreServiceExtension::getSecurityLevelExtension",
+            500,
+            move || format!("security_level: {}", security_level.0),
+        );
+        map_or_log_err(self.get_security_level_extension(security_level), Ok)
+    }
+    fn verifySignature(
+        &self,
+        key: &KeyDescriptor,
+        params: &[KeyParameter],
+        message: &[u8],
+        signature: &[u8],
+    ) -> binder::Result<bool> {
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::verifySignature", 500);
+        map_or_log_err(self.verify_signature(key, params, message, signature), Ok)
+    }
+    fn grantWithConstraints(
+        &self,
+        key: &KeyDescriptor,
+        grantee_uid: i32,
+        access_vector: i32,
+        constraints: &AidlGrantConstraints,
+    ) -> binder::Result<KeyDescriptor> {
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::grantWithConstraints", 500);
+        let constraints = GrantConstraints {
+            auth_sids: constraints.authSecureIds.clone(),
+            purposes: constraints.purposes.clone(),
+        };
+        map_or_log_err(self.grant(key, grantee_uid, access_vector.into(), &constraints), Ok)
+    }
+    fn updateSubcomponentStrict(
+        &self,
+        key: &KeyDescriptor,
+        public_cert: Option<&[u8]>,
+        certificate_chain: Option<&[u8]>,
+    ) -> binder::Result<()> {
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::updateSubcomponentStrict", 500);
+        map_or_log_err(self.update_subcomponent(key, public_cert, certificate_chain, true), Ok)
+    }
+    fn getCertSignatureAlgorithm(&self, key: &KeyDescriptor) -> binder::Result<Option<String>> {
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::getCertSignatureAlgorithm", 500);
+        map_or_log_err(self.get_cert_signature_algorithm(key), Ok)
+    }
+    fn renameKey(&self, key: &KeyDescriptor, new_alias: &str) -> binder::Result<()> {
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::renameKey", 500);
+        map_or_log_err(self.rename_key(key, new_alias), Ok)
+    }
+    fn getDeprecationWarnings(&self) -> binder::Result<Vec<DeprecationWarning>> {
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::getDeprecationWarnings", 500);
+        Ok(self.get_deprecation_warnings())
+    }
+    fn prewarmKey(&self, key: &KeyDescriptor) -> binder::Result<()> {
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::prewarmKey", 500);
+        map_or_log_err(self.prewarm_key(key), Ok)
+    }
+    fn listEntriesStreamed(
+        &self,
+        domain: Domain,
+        namespace: i64,
+        callback: &Strong<dyn IListEntriesCallback>,
+    ) -> binder::Result<()> {
+        // The call lasts until the client has consumed all chunks.
+        let _wp = wd::watch_millis("IKeystoreServiceExtension::listEntriesStreamed", 5000);
+        map_or_log_err(self.list_entries_streamed(domain, namespace, callback), Ok)
+    }
+}
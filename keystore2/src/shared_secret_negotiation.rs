@@ -13,10 +13,30 @@
 // limitations under the License.
 
 //! This module implements the shared secret negotiation.
+//!
+//! Usually all participants share a single HMAC key after the negotiation. Devices with hybrid
+//! authentication hardware may have participants that derive different keys, i.e., they end up
+//! in distinct HMAC domains which are told apart by the checksum each participant returns.
+//! Auth tokens issued by an authenticator can only be verified by KeyMint instances in the same
+//! HMAC domain. The role of an AIDL participant is derived from its instance name:
+//!  * "default" and "strongbox" are the TEE and StrongBox KeyMint instances,
+//!  * instance names starting with "gatekeeper" issue password auth tokens,
+//!  * instance names starting with "biometric" issue biometric auth tokens.
+//!
+//! Participants that are not ready yet, e.g., because their HAL starts late during boot, are
+//! retried with exponential backoff until a negotiation round succeeds with all of them. If an
+//! AIDL participant dies afterwards, its HAL lost the HMAC key, so all participants negotiate
+//! again once it is back. The state of every participant, including its consecutive failures,
+//! shows in the service dump.
 
+use crate::database::DateTime;
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
 use crate::error::{map_binder_status, map_binder_status_code, Error};
 use crate::globals::get_keymint_device;
-use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    HardwareAuthenticatorType::HardwareAuthenticatorType, SecurityLevel::SecurityLevel,
+};
 use android_hardware_security_keymint::binder::Strong;
 use android_hardware_security_sharedsecret::aidl::android::hardware::security::sharedsecret::{
     ISharedSecret::BpSharedSecret, ISharedSecret::ISharedSecret,
@@ -24,35 +44,94 @@ use android_hardware_security_sharedsecret::aidl::android::hardware::security::s
 };
 use android_security_compat::aidl::android::security::compat::IKeystoreCompatService::IKeystoreCompatService;
 use anyhow::Result;
-use binder::get_declared_instances;
+use binder::{get_declared_instances, DeathRecipient, IBinder};
 use keystore2_hal_names::get_hidl_instances;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
+use std::io::Write;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
 use std::time::Duration;
 
+lazy_static! {
+    /// Negotiation state of all shared secret participants.
+    static ref NEGOTIATION_STATE: Mutex<NegotiationState> = Default::default();
+}
+
+/// The number of consecutive failures after which a participant is reported as unhealthy.
+const UNHEALTHY_FAILURES: u64 = 10;
+
 /// This function initiates the shared secret negotiation. It starts a thread and then returns
 /// immediately. The thread gets hal names from the android ServiceManager. It then attempts
-/// to connect to all of these participants. If any connection fails the thread will retry once
-/// per second to connect to the failed instance(s) until all of the instances are connected.
-/// It then performs the negotiation.
+/// to connect to all of these participants, retrying the failed instance(s) with exponential
+/// backoff until all of the instances are connected. It then performs the negotiation.
 ///
-/// During the first phase of the negotiation it will again try every second until
-/// all instances have responded successfully to account for instances that register early but
-/// are not fully functioning at this time due to hardware delays or boot order dependency issues.
-/// An error during the second phase or a checksum mismatch leads to a panic.
+/// If a round of the negotiation fails on any participant, e.g., because it registered early
+/// but is not fully functioning yet due to hardware delays or boot order dependency issues,
+/// the thread reconnects and starts over after the next backoff delay. Once a round succeeded,
+/// the thread waits for an AIDL participant to die and then negotiates again.
 pub fn perform_shared_secret_negotiation() {
     std::thread::spawn(|| {
         let participants = list_participants()
             .expect("In perform_shared_secret_negotiation: Trying to list participants.");
-        let connected = connect_participants(participants);
-        negotiate_shared_secret(connected);
-        log::info!("Shared secret negotiation concluded successfully.");
+        let (restart_sender, restarts) = channel();
+        let mut backoff = Backoff::default();
+        loop {
+            // Deaths that were noticed before this round are handled by it.
+            while restarts.try_recv().is_ok() {}
+            for p in &participants {
+                set_participant_state(p, ParticipantState::Connecting);
+            }
+            let connected = connect_participants(participants.clone(), &mut backoff);
+            // The death recipients must stay alive until the next round.
+            let _death_recipients = watch_for_restarts(&connected, &restart_sender);
+            if let Err(e) = negotiate_shared_secret(&connected) {
+                let delay = backoff.next_delay();
+                log::warn!("{:?}", e);
+                log::warn!("Retrying shared secret negotiation in {:?}.", delay);
+                std::thread::sleep(delay);
+                continue;
+            }
+            backoff = Backoff::default();
+            let now = DateTime::now().map_or(0, |t| t.to_millis_epoch());
+            NEGOTIATION_STATE.lock().unwrap().conclude(now);
+            log::info!("Shared secret negotiation concluded successfully.");
+
+            // Once shared secret negotiation is done, the StrongBox and TEE have a common key
+            // that can be used to authenticate a possible RootOfTrust transfer.
+            transfer_root_of_trust();
 
-        // Once shared secret negotiation is done, the StrongBox and TEE have a common key that
-        // can be used to authenticate a possible RootOfTrust transfer.
-        transfer_root_of_trust();
+            // The receiver cannot fail while this thread holds the sender.
+            let restarted = restarts.recv().unwrap();
+            log::warn!("Shared secret participant {} died. Negotiating again.", restarted);
+        }
     });
 }
 
+/// Exponential backoff between attempts to reach participants that are not ready yet.
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(30);
+
+    /// Returns the delay before the next attempt and doubles the one after it.
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = std::cmp::min(self.next * 2, Self::MAX);
+        delay
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { next: Self::INITIAL }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 enum SharedSecretParticipant {
     /// Represents an instance of android.hardware.security.sharedsecret.ISharedSecret.
@@ -61,6 +140,199 @@ enum SharedSecretParticipant {
     Hidl { is_strongbox: bool, version: (usize, usize) },
 }
 
+/// The role a shared secret participant plays in user authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParticipantRole {
+    /// A KeyMint instance that verifies auth tokens.
+    KeyMint(SecurityLevel),
+    /// An authenticator that issues auth tokens of the given type.
+    Authenticator(HardwareAuthenticatorType),
+    /// The role could not be derived from the instance name.
+    Unknown,
+}
+
+impl SharedSecretParticipant {
+    fn role(&self) -> ParticipantRole {
+        match self {
+            Self::Hidl { is_strongbox: false, .. } => {
+                ParticipantRole::KeyMint(SecurityLevel::TRUSTED_ENVIRONMENT)
+            }
+            Self::Hidl { is_strongbox: true, .. } => {
+                ParticipantRole::KeyMint(SecurityLevel::STRONGBOX)
+            }
+            Self::Aidl(instance) => match instance.as_str() {
+                "default" => ParticipantRole::KeyMint(SecurityLevel::TRUSTED_ENVIRONMENT),
+                "strongbox" => ParticipantRole::KeyMint(SecurityLevel::STRONGBOX),
+                i if i.starts_with("gatekeeper") => {
+                    ParticipantRole::Authenticator(HardwareAuthenticatorType::PASSWORD)
+                }
+                i if i.starts_with("biometric") => {
+                    ParticipantRole::Authenticator(HardwareAuthenticatorType::FINGERPRINT)
+                }
+                _ => ParticipantRole::Unknown,
+            },
+        }
+    }
+}
+
+/// Negotiation state of a single shared secret participant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ParticipantState {
+    /// The participant is declared but not connected yet.
+    Connecting,
+    /// The participant is connected and the negotiation is in progress.
+    Connected,
+    /// The participant concluded the negotiation as part of the given HMAC domain.
+    Negotiated { hmac_domain: usize },
+    /// The negotiation failed on this participant.
+    Failed(String),
+    /// The participant died after the negotiation and is expected to restart.
+    Restarting,
+}
+
+impl Display for ParticipantState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "connecting"),
+            Self::Connected => write!(f, "connected"),
+            Self::Negotiated { hmac_domain } => write!(f, "negotiated (HMAC domain {hmac_domain})"),
+            Self::Failed(e) => write!(f, "failed: {e}"),
+            Self::Restarting => write!(f, "restarting"),
+        }
+    }
+}
+
+/// The dump section of the participants.
+pub(crate) const PARTICIPANTS_SECTION: DumpSection = DumpSection {
+    name: "shared_secret_participants",
+    header: "Shared secret participants:",
+    line: "  <participant> (<role>): <failures> failures, <state>",
+    fields: dump_fields!(
+        participant: "string",
+        role: "string",
+        failures: "uint64",
+        state: "string",
+    ),
+};
+
+/// The dump section of the concluded rounds.
+pub(crate) const NEGOTIATION_SECTION: DumpSection = DumpSection {
+    name: "shared_secret_negotiation",
+    header: "Shared secret negotiation (concluded rounds, last concluded):",
+    line: "  <rounds>, <time_millis>",
+    fields: dump_fields!(rounds: "uint64", time_millis: "int64"),
+};
+
+/// Negotiation state and health of the shared secret participants.
+#[derive(Default)]
+struct NegotiationState {
+    /// The state of each participant, in the order they were listed.
+    participants: Vec<(SharedSecretParticipant, ParticipantState)>,
+    /// The number of consecutive failures of each participant.
+    failures: HashMap<SharedSecretParticipant, u64>,
+    /// The number of negotiation rounds that succeeded.
+    rounds: u64,
+    /// The time of the last successful round in milliseconds since the epoch, or 0.
+    last_concluded: i64,
+}
+
+impl NegotiationState {
+    fn set(&mut self, participant: &SharedSecretParticipant, state: ParticipantState) {
+        if let ParticipantState::Negotiated { .. } = state {
+            self.failures.remove(participant);
+        }
+        match self.participants.iter_mut().find(|(p, _)| p == participant) {
+            Some((_, s)) => *s = state,
+            None => self.participants.push((participant.clone(), state)),
+        }
+    }
+
+    /// Records a failure of `participant` and returns the number of consecutive failures.
+    fn fail(&mut self, participant: &SharedSecretParticipant, state: ParticipantState) -> u64 {
+        self.set(participant, state);
+        let failures = self.failures.entry(participant.clone()).or_default();
+        *failures += 1;
+        *failures
+    }
+
+    fn conclude(&mut self, now: i64) {
+        self.rounds += 1;
+        self.last_concluded = now;
+    }
+
+    fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        PARTICIPANTS_SECTION.write_header(writer)?;
+        for (p, s) in &self.participants {
+            let failures = self.failures.get(p).copied().unwrap_or_default();
+            PARTICIPANTS_SECTION
+                .write_line(writer, &[p, &format!("{:?}", p.role()), &failures, s])?;
+        }
+        NEGOTIATION_SECTION.write_header(writer)?;
+        NEGOTIATION_SECTION.write_line(writer, &[&self.rounds, &self.last_concluded])
+    }
+}
+
+fn set_participant_state(participant: &SharedSecretParticipant, state: ParticipantState) {
+    NEGOTIATION_STATE.lock().unwrap().set(participant, state);
+}
+
+fn record_participant_failure(participant: &SharedSecretParticipant, state: ParticipantState) {
+    let failures = NEGOTIATION_STATE.lock().unwrap().fail(participant, state);
+    if failures == UNHEALTHY_FAILURES {
+        log::error!(
+            "Shared secret participant {} failed {} times in a row. Auth tokens may not be \
+             verifiable until it recovers.",
+            participant,
+            failures
+        );
+    }
+}
+
+/// Returns false if the given participants show that auth tokens of type `auth_type` are
+/// issued in an HMAC domain that the KeyMint instance at `security_level` is not part of.
+fn shares_hmac_domain(
+    participants: &[(SharedSecretParticipant, ParticipantState)],
+    auth_type: HardwareAuthenticatorType,
+    security_level: SecurityLevel,
+) -> bool {
+    let domain_of = |pred: &dyn Fn(ParticipantRole) -> bool| {
+        participants
+            .iter()
+            .filter(|(p, _)| pred(p.role()))
+            .filter_map(|(_, s)| match s {
+                ParticipantState::Negotiated { hmac_domain } => Some(*hmac_domain),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let keymint_domains = domain_of(&|role| role == ParticipantRole::KeyMint(security_level));
+    let authenticator_domains = domain_of(&|role| match role {
+        ParticipantRole::Authenticator(t) => (t.0 & auth_type.0) != 0,
+        _ => false,
+    });
+    // Without any information about either side, we cannot rule anything out and leave it
+    // to KeyMint to verify the token.
+    if keymint_domains.is_empty() || authenticator_domains.is_empty() {
+        return true;
+    }
+    authenticator_domains.iter().any(|d| keymint_domains.contains(d))
+}
+
+/// Returns false if auth tokens of type `auth_type` are known to be issued in an HMAC domain
+/// other than the one of the KeyMint instance at `security_level`, which therefore cannot
+/// verify them. Returns true otherwise, including if the negotiation has not concluded yet.
+pub fn authenticator_shares_hmac_domain(
+    auth_type: HardwareAuthenticatorType,
+    security_level: SecurityLevel,
+) -> bool {
+    shares_hmac_domain(&NEGOTIATION_STATE.lock().unwrap().participants, auth_type, security_level)
+}
+
+/// Writes the negotiation state of all shared secret participants to `writer`.
+pub fn dump_state(writer: &mut dyn Write) -> std::io::Result<()> {
+    NEGOTIATION_STATE.lock().unwrap().dump(writer)
+}
+
 impl Display for SharedSecretParticipant {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
@@ -151,6 +423,7 @@ fn list_participants() -> Result<Vec<SharedSecretParticipant>> {
 
 fn connect_participants(
     mut participants: Vec<SharedSecretParticipant>,
+    backoff: &mut Backoff,
 ) -> Vec<(Strong<dyn ISharedSecret>, SharedSecretParticipant)> {
     let mut connected_participants: Vec<(Strong<dyn ISharedSecret>, SharedSecretParticipant)> =
         vec![];
@@ -172,10 +445,15 @@ fn connect_participants(
                                     service_name,
                                     e
                                 );
-                                failed.push(SharedSecretParticipant::Aidl(instance_name));
+                                let p = SharedSecretParticipant::Aidl(instance_name);
+                                record_participant_failure(&p, ParticipantState::Connecting);
+                                failed.push(p);
+                            }
+                            Ok(service) => {
+                                let p = SharedSecretParticipant::Aidl(instance_name);
+                                set_participant_state(&p, ParticipantState::Connected);
+                                connected.push((service, p))
                             }
-                            Ok(service) => connected
-                                .push((service, SharedSecretParticipant::Aidl(instance_name))),
                         }
                     }
                     SharedSecretParticipant::Hidl { is_strongbox, version } => {
@@ -207,13 +485,15 @@ fn connect_participants(
                                     if is_strongbox { "strongbox" } else { "TEE" },
                                     e
                                 );
-                                failed
-                                    .push(SharedSecretParticipant::Hidl { is_strongbox, version });
+                                let p = SharedSecretParticipant::Hidl { is_strongbox, version };
+                                record_participant_failure(&p, ParticipantState::Connecting);
+                                failed.push(p);
+                            }
+                            Ok(service) => {
+                                let p = SharedSecretParticipant::Hidl { is_strongbox, version };
+                                set_participant_state(&p, ParticipantState::Connected);
+                                connected.push((service, p))
                             }
-                            Ok(service) => connected.push((
-                                service,
-                                SharedSecretParticipant::Hidl { is_strongbox, version },
-                            )),
                         }
                     }
                 }
@@ -225,61 +505,104 @@ fn connect_participants(
         if participants.is_empty() {
             break;
         }
-        std::thread::sleep(Duration::from_millis(1000));
+        std::thread::sleep(backoff.next_delay());
     }
     connected_participants
 }
 
+/// Notifies `restarts` when one of the AIDL `participants` dies. HIDL participants are served
+/// by the compatibility service in this process, so they are not watched. The returned death
+/// recipients must be kept alive for as long as the participants are watched.
+fn watch_for_restarts(
+    participants: &[(Strong<dyn ISharedSecret>, SharedSecretParticipant)],
+    restarts: &Sender<SharedSecretParticipant>,
+) -> Vec<DeathRecipient> {
+    participants
+        .iter()
+        .filter(|(_, p)| matches!(p, SharedSecretParticipant::Aidl(_)))
+        .filter_map(|(s, p)| {
+            let (participant, sender) = (p.clone(), restarts.clone());
+            let mut recipient = DeathRecipient::new(move || {
+                set_participant_state(&participant, ParticipantState::Restarting);
+                // The negotiation thread never drops the receiver.
+                let _ = sender.send(participant.clone());
+            });
+            match s.as_binder().link_to_death(&mut recipient) {
+                Ok(()) => Some(recipient),
+                Err(e) => {
+                    log::warn!("Unable to watch \"{}\" for restarts: {:?}", p, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Performs one round of the negotiation with all `participants`. Fails if any participant
+/// fails to take part, in which case the round must be repeated.
 fn negotiate_shared_secret(
-    participants: Vec<(Strong<dyn ISharedSecret>, SharedSecretParticipant)>,
-) {
+    participants: &[(Strong<dyn ISharedSecret>, SharedSecretParticipant)],
+) -> Result<(), SharedSecretError> {
     // Phase 1: Get the sharing parameters from all participants.
-    let mut params = loop {
-        let result: Result<Vec<SharedSecretParameters>, SharedSecretError> = participants
-            .iter()
-            .map(|(s, p)| {
-                map_binder_status(s.getSharedSecretParameters())
-                    .map_err(|e| SharedSecretError::ParameterRetrieval { e, p: (*p).clone() })
+    let mut params = participants
+        .iter()
+        .map(|(s, p)| {
+            map_binder_status(s.getSharedSecretParameters()).map_err(|e| {
+                let e = SharedSecretError::ParameterRetrieval { e, p: p.clone() };
+                record_participant_failure(p, ParticipantState::Failed(e.to_string()));
+                e
             })
-            .collect();
-
-        match result {
-            Err(e) => {
-                log::warn!("{:?}", e);
-                log::warn!("Retrying in one second.");
-                std::thread::sleep(Duration::from_millis(1000));
-            }
-            Ok(params) => break params,
-        }
-    };
+        })
+        .collect::<Result<Vec<SharedSecretParameters>, _>>()?;
 
     params.sort_unstable();
 
-    // Phase 2: Send the sorted sharing parameters to all participants.
-    let negotiation_result = participants.into_iter().try_fold(None, |acc, (s, p)| {
-        match (acc, map_binder_status(s.computeSharedSecret(&params))) {
-            (None, Ok(new_sum)) => Ok(Some(new_sum)),
-            (Some(old_sum), Ok(new_sum)) => {
-                if old_sum == new_sum {
-                    Ok(Some(old_sum))
-                } else {
-                    Err(SharedSecretError::Checksum(p))
+    // Phase 2: Send the sorted sharing parameters to all participants. Participants that
+    // return the same checksum share an HMAC domain.
+    let mut checksums: Vec<Vec<u8>> = vec![];
+    let mut keymint_domains: Vec<(SharedSecretParticipant, usize)> = vec![];
+    let mut first_error = None;
+    for (s, p) in participants {
+        match map_binder_status(s.computeSharedSecret(&params)) {
+            Ok(sum) => {
+                let hmac_domain = match checksums.iter().position(|c| *c == sum) {
+                    Some(d) => d,
+                    None => {
+                        checksums.push(sum);
+                        checksums.len() - 1
+                    }
+                };
+                if let ParticipantRole::KeyMint(_) = p.role() {
+                    keymint_domains.push((p.clone(), hmac_domain));
                 }
+                set_participant_state(p, ParticipantState::Negotiated { hmac_domain });
+            }
+            Err(e) => {
+                let e = SharedSecretError::Computation { e, p: p.clone() };
+                record_participant_failure(p, ParticipantState::Failed(e.to_string()));
+                first_error.get_or_insert(e);
             }
-            (_, Err(e)) => Err(SharedSecretError::Computation { e, p }),
         }
-    });
+    }
+    if let Some(e) = first_error {
+        return Err(e);
+    }
 
-    if let Err(e) = negotiation_result {
-        log::error!("In negotiate_shared_secret: {:?}.", e);
-        if let SharedSecretError::Checksum(_) = e {
-            log::error!(concat!(
-                "This means that this device is NOT PROVISIONED CORRECTLY.\n",
-                "User authorization and other security functions will not work\n",
-                "as expected. Please contact your OEM for instructions.",
-            ));
-        }
+    if checksums.len() > 1 {
+        log::info!("Shared secret negotiation resulted in {} HMAC domains.", checksums.len());
+    }
+
+    // All KeyMint instances must share an HMAC domain, otherwise they cannot verify each
+    // other's timestamp tokens nor transfer the root of trust.
+    if let Some((p, _)) = keymint_domains.iter().find(|(_, d)| *d != keymint_domains[0].1) {
+        log::error!("In negotiate_shared_secret: {:?}.", SharedSecretError::Checksum(p.clone()));
+        log::error!(concat!(
+            "This means that this device is NOT PROVISIONED CORRECTLY.\n",
+            "User authorization and other security functions will not work\n",
+            "as expected. Please contact your OEM for instructions.",
+        ));
     }
+    Ok(())
 }
 
 /// Perform RootOfTrust transfer from TEE to StrongBox (if available).
@@ -326,3 +649,127 @@ pub fn transfer_root_of_trust() {
     }
     log::info!("RootOfTrust transfer process complete");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiated(
+        instance: &str,
+        hmac_domain: usize,
+    ) -> (SharedSecretParticipant, ParticipantState) {
+        (
+            SharedSecretParticipant::Aidl(instance.to_string()),
+            ParticipantState::Negotiated { hmac_domain },
+        )
+    }
+
+    #[test]
+    fn participant_roles() {
+        assert_eq!(
+            SharedSecretParticipant::Aidl("default".to_string()).role(),
+            ParticipantRole::KeyMint(SecurityLevel::TRUSTED_ENVIRONMENT)
+        );
+        assert_eq!(
+            SharedSecretParticipant::Hidl { is_strongbox: true, version: (4, 1) }.role(),
+            ParticipantRole::KeyMint(SecurityLevel::STRONGBOX)
+        );
+        assert_eq!(
+            SharedSecretParticipant::Aidl("gatekeeper".to_string()).role(),
+            ParticipantRole::Authenticator(HardwareAuthenticatorType::PASSWORD)
+        );
+        assert_eq!(
+            SharedSecretParticipant::Aidl("biometric.face".to_string()).role(),
+            ParticipantRole::Authenticator(HardwareAuthenticatorType::FINGERPRINT)
+        );
+        assert_eq!(
+            SharedSecretParticipant::Aidl("other".to_string()).role(),
+            ParticipantRole::Unknown
+        );
+    }
+
+    #[test]
+    fn single_hmac_domain() {
+        let participants =
+            [negotiated("default", 0), negotiated("strongbox", 0), negotiated("gatekeeper", 0)];
+        for sec_level in [SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX] {
+            assert!(shares_hmac_domain(
+                &participants,
+                HardwareAuthenticatorType::PASSWORD,
+                sec_level
+            ));
+            // Nothing is known about biometric authenticators.
+            assert!(shares_hmac_domain(
+                &participants,
+                HardwareAuthenticatorType::FINGERPRINT,
+                sec_level
+            ));
+        }
+    }
+
+    #[test]
+    fn distinct_hmac_domains() {
+        let participants = [
+            negotiated("default", 0),
+            negotiated("strongbox", 1),
+            negotiated("gatekeeper", 0),
+            negotiated("biometric", 1),
+        ];
+        let tee = SecurityLevel::TRUSTED_ENVIRONMENT;
+        let sb = SecurityLevel::STRONGBOX;
+        assert!(shares_hmac_domain(&participants, HardwareAuthenticatorType::PASSWORD, tee));
+        assert!(!shares_hmac_domain(&participants, HardwareAuthenticatorType::PASSWORD, sb));
+        assert!(!shares_hmac_domain(&participants, HardwareAuthenticatorType::FINGERPRINT, tee));
+        assert!(shares_hmac_domain(&participants, HardwareAuthenticatorType::FINGERPRINT, sb));
+        assert!(shares_hmac_domain(&participants, HardwareAuthenticatorType::ANY, tee));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::default();
+        assert_eq!(Duration::from_millis(100), backoff.next_delay());
+        assert_eq!(Duration::from_millis(200), backoff.next_delay());
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        assert_eq!(Backoff::MAX, backoff.next_delay());
+    }
+
+    #[test]
+    fn tracks_consecutive_failures() {
+        let mut state = NegotiationState::default();
+        let tee = SharedSecretParticipant::Aidl("default".to_string());
+        let gatekeeper = SharedSecretParticipant::Aidl("gatekeeper".to_string());
+        state.set(&tee, ParticipantState::Connecting);
+        assert_eq!(1, state.fail(&gatekeeper, ParticipantState::Connecting));
+        assert_eq!(2, state.fail(&gatekeeper, ParticipantState::Failed("busy".to_string())));
+        state.set(&tee, ParticipantState::Negotiated { hmac_domain: 0 });
+        state.conclude(1234);
+
+        let mut out = Vec::new();
+        state.dump(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with(concat!(
+            "/default (KeyMint(TRUSTED_ENVIRONMENT)): 0 failures, negotiated (HMAC domain 0)\n",
+            "  android.hardware.security.sharedsecret.ISharedSecret/gatekeeper ",
+            "(Authenticator(PASSWORD)): 2 failures, failed: busy\n",
+            "Shared secret negotiation (concluded rounds, last concluded):\n",
+            "  1, 1234\n",
+        )));
+
+        state.set(&gatekeeper, ParticipantState::Negotiated { hmac_domain: 0 });
+        assert!(state.failures.is_empty());
+    }
+
+    #[test]
+    fn pending_negotiation() {
+        let participants = [
+            negotiated("default", 0),
+            (SharedSecretParticipant::Aidl("gatekeeper".to_string()), ParticipantState::Connected),
+        ];
+        assert!(shares_hmac_domain(
+            &participants,
+            HardwareAuthenticatorType::PASSWORD,
+            SecurityLevel::TRUSTED_ENVIRONMENT
+        ));
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module migrates the keys of the software Keymaster emulator to the TEE KeyMint.
+//!
+//! Refurbished devices sometimes boot without a working TEE, e.g., after a board repair. Then
+//! km_compat stands in for the TEE with a software Keymaster emulator, and the keys that apps
+//! create carry the km_compat software prefix and no hardware enforced authorizations. Once the
+//! TEE is restored, its KeyMint rejects these blobs. The software blobs are not encrypted, so
+//! their key material can be imported into the TEE KeyMint with the authorizations of the
+//! original key. The key keeps its id, alias, grants, and certificates, whose public key does
+//! not change.
+//!
+//! The material of keys that are bound to an application id or application data cannot be
+//! read without them, and super-encrypted keys can only be migrated while their owner is
+//! unlocked. Such keys are reported and left as they are.
+//!
+//! Both the detection at startup and the migration are gated by `Feature::SoftKeyMigration`.
+
+use crate::database::{BlobMetaEntry, KeyEntryLoadBits, KeyType, KeystoreDB, Uuid};
+use crate::error::{map_km_error, Error, ErrorCode, ResponseCode};
+use crate::feature_flags::{self, Feature};
+use crate::globals::{get_keymint_device, DB, SUPER_KEY};
+use crate::km_compat;
+use crate::ks_err;
+use crate::raw_device::KeyMintDevice;
+use crate::super_key::SuperKeyManager;
+use crate::sw_keyblob;
+use crate::utils::{key_characteristics_to_internal, watchdog as wd, AID_KEYSTORE};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, IKeyMintDevice::IKeyMintDevice, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+use binder::Strong;
+
+/// The not-after date of certificates without expiration, 9999-12-31T23:59:59Z.
+const UNDEFINED_NOT_AFTER: i64 = 253402300799000;
+
+/// Authorizations of a software key that describe the device or a single operation rather than
+/// the key. KeyMint adds the former itself and rejects them on import.
+const NON_IMPORTABLE_TAGS: &[Tag] = &[
+    Tag::ORIGIN,
+    Tag::ROOT_OF_TRUST,
+    Tag::OS_VERSION,
+    Tag::OS_PATCHLEVEL,
+    Tag::VENDOR_PATCHLEVEL,
+    Tag::BOOT_PATCHLEVEL,
+    Tag::UNIQUE_ID,
+    Tag::ATTESTATION_CHALLENGE,
+    Tag::ATTESTATION_APPLICATION_ID,
+    Tag::ATTESTATION_ID_BRAND,
+    Tag::ATTESTATION_ID_DEVICE,
+    Tag::ATTESTATION_ID_PRODUCT,
+    Tag::ATTESTATION_ID_SERIAL,
+    Tag::ATTESTATION_ID_IMEI,
+    Tag::ATTESTATION_ID_MEID,
+    Tag::ATTESTATION_ID_MANUFACTURER,
+    Tag::ATTESTATION_ID_MODEL,
+    Tag::DEVICE_UNIQUE_ATTESTATION,
+    Tag::NONCE,
+    Tag::MAC_LENGTH,
+    Tag::CERTIFICATE_SERIAL,
+    Tag::CERTIFICATE_SUBJECT,
+    Tag::CERTIFICATE_NOT_BEFORE,
+    Tag::CERTIFICATE_NOT_AFTER,
+];
+
+/// Why the material of a key was not preserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The key is super-encrypted and its owner has not unlocked the device since boot. The
+    /// key can be migrated later.
+    Locked,
+    /// The key blob was not created by the software Keymaster emulator.
+    UnknownBlobFormat,
+    /// The key blob cannot be read, e.g., because the key is bound to an application id.
+    BlobUnreadable,
+    /// The TEE KeyMint rejected the key material with the given error code.
+    ImportRejected(ErrorCode),
+}
+
+/// The outcome of the migration of one key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationOutcome {
+    /// The id of the key entry.
+    pub key_id: i64,
+    /// The domain, namespace, and alias of the key.
+    pub key: KeyDescriptor,
+    /// None if the key was migrated.
+    pub failure: Option<Failure>,
+}
+
+/// Returns the TEE KeyMint and its uuid, if the TEE is backed by a KeyMint device rather than
+/// by km_compat, which may stand in with the software Keymaster emulator.
+fn hardware_tee() -> Result<Option<(Strong<dyn IKeyMintDevice>, Uuid)>> {
+    let (km_dev, hw_info, km_uuid) = get_keymint_device(&SecurityLevel::TRUSTED_ENVIRONMENT)
+        .context(ks_err!("Failed to get the TEE KeyMint."))?;
+    if hw_info.securityLevel == SecurityLevel::TRUSTED_ENVIRONMENT
+        && hw_info.versionNumber >= KeyMintDevice::KEY_MINT_V1
+    {
+        Ok(Some((km_dev, km_uuid)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the parameters to import the key material of a software key with the
+/// authorizations `characteristics` into KeyMint.
+fn import_params(characteristics: Vec<KeyParameter>) -> Vec<KeyParameter> {
+    let asymmetric = characteristics.iter().any(|p| {
+        matches!(
+            p.value,
+            KeyParameterValue::Algorithm(Algorithm::RSA)
+                | KeyParameterValue::Algorithm(Algorithm::EC)
+        )
+    });
+    let mut params: Vec<KeyParameter> =
+        characteristics.into_iter().filter(|p| !NON_IMPORTABLE_TAGS.contains(&p.tag)).collect();
+    if asymmetric {
+        // KeyMint requires a validity for the certificate that it creates for the key.
+        params.push(KeyParameter {
+            tag: Tag::CERTIFICATE_NOT_BEFORE,
+            value: KeyParameterValue::DateTime(0),
+        });
+        params.push(KeyParameter {
+            tag: Tag::CERTIFICATE_NOT_AFTER,
+            value: KeyParameterValue::DateTime(UNDEFINED_NOT_AFTER),
+        });
+    }
+    params
+}
+
+/// Imports the material of the software key with id `key_id` into `km_dev` and replaces the
+/// blob and the authorizations of the key.
+fn migrate_key(
+    db: &mut KeystoreDB,
+    km_dev: &dyn IKeyMintDevice,
+    km_uuid: Uuid,
+    key_id: i64,
+) -> Result<Option<Failure>> {
+    let key = KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, ..Default::default() };
+    let (key_id_guard, mut key_entry) = db
+        .load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, AID_KEYSTORE, |_, _| Ok(()))
+        .context(ks_err!("Failed to load key entry."))?;
+    let (blob, blob_metadata) = key_entry
+        .take_key_blob_info()
+        .ok_or_else(Error::sys)
+        .context(ks_err!("Key has no key blob."))?;
+
+    let key_blob = match SUPER_KEY.read().unwrap().unwrap_key_if_required(&blob_metadata, &blob) {
+        Ok(key_blob) => key_blob,
+        Err(e) => match e.root_cause().downcast_ref::<Error>() {
+            Some(Error::Rc(ResponseCode::LOCKED)) => return Ok(Some(Failure::Locked)),
+            _ => return Err(e).context(ks_err!("Failed to unwrap key blob.")),
+        },
+    };
+    let software_blob = match key_blob.strip_prefix(km_compat::KEYMASTER_BLOB_SW_PREFIX) {
+        Some(software_blob) => software_blob,
+        None => return Ok(Some(Failure::UnknownBlobFormat)),
+    };
+    let (format, key_material, characteristics) = match sw_keyblob::export_key(software_blob, &[]) {
+        Ok(exported) => exported,
+        Err(e) => {
+            log::warn!("Cannot read software key blob of key {key_id}: {e:?}");
+            return Ok(Some(Failure::BlobUnreadable));
+        }
+    };
+
+    let params = import_params(characteristics);
+    let creation_result = {
+        let _wp = wd::watch_millis("In soft_key_migration::migrate_key: calling importKey.", 500);
+        match map_km_error(km_dev.importKey(&params, format, &key_material, None)) {
+            Ok(creation_result) => creation_result,
+            Err(Error::Km(error_code)) => return Ok(Some(Failure::ImportRejected(error_code))),
+            Err(e) => return Err(e).context(ks_err!("Failed to import key material.")),
+        }
+    };
+
+    let (new_blob, new_blob_metadata) =
+        SuperKeyManager::reencrypt_if_required(&key_blob, &creation_result.keyBlob)
+            .context(ks_err!("Failed to handle super encryption."))?;
+    let mut new_blob_metadata = new_blob_metadata.unwrap_or_default();
+    new_blob_metadata.add(BlobMetaEntry::KmUuid(km_uuid));
+
+    // Keystore's own parameters, like the user id, are not part of the key characteristics.
+    let mut key_parameters = key_characteristics_to_internal(creation_result.keyCharacteristics);
+    key_parameters.extend(
+        key_entry.into_key_parameters().into_iter().filter(|p| p.get_tag() == Tag::USER_ID),
+    );
+    db.replace_key_material(&key_id_guard, &new_blob, &new_blob_metadata, &key_parameters)
+        .context(ks_err!("Failed to store the migrated key."))?;
+    Ok(None)
+}
+
+/// Returns the number of keys that the software Keymaster emulator created in place of the TEE
+/// KeyMint, if the TEE is backed by KeyMint now. The keys are counted up to `limit`.
+pub fn count_pending_keys(db: &mut KeystoreDB, limit: usize) -> Result<usize> {
+    let km_uuid = match hardware_tee().context(ks_err!())? {
+        Some((_, km_uuid)) => km_uuid,
+        None => return Ok(0),
+    };
+    let keys = db
+        .list_keys_without_hardware_enforcement(&km_uuid, 0, limit)
+        .context(ks_err!("Failed to list software keys."))?;
+    Ok(keys.len())
+}
+
+/// Migrates up to `limit` keys with an id greater than `after_key_id` that the software Keymaster
+/// emulator created in place of the TEE KeyMint, and returns the outcome for each of them,
+/// ordered by key id.
+pub fn migrate_keys(
+    db: &mut KeystoreDB,
+    after_key_id: i64,
+    limit: usize,
+) -> Result<Vec<MigrationOutcome>> {
+    let (km_dev, km_uuid) = hardware_tee()
+        .context(ks_err!())?
+        .ok_or(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+        .context(ks_err!("The TEE is not backed by KeyMint."))?;
+    let keys = db
+        .list_keys_without_hardware_enforcement(&km_uuid, after_key_id, limit)
+        .context(ks_err!("Failed to list software keys."))?;
+    let mut outcomes = Vec::with_capacity(keys.len());
+    for (key_id, key) in keys {
+        let failure = migrate_key(db, km_dev.as_ref(), km_uuid, key_id)
+            .with_context(|| ks_err!("Failed to migrate key {key_id}."))?;
+        match failure {
+            None => log::info!("Migrated software key {key_id} to the TEE."),
+            Some(failure) => log::warn!("Software key {key_id} not migrated: {failure:?}"),
+        }
+        outcomes.push(MigrationOutcome { key_id, key, failure });
+    }
+    Ok(outcomes)
+}
+
+/// Logs whether keys of the software Keymaster emulator wait for migration to the TEE KeyMint.
+/// This is called when keystore starts.
+pub fn detect() {
+    if !feature_flags::is_enabled(Feature::SoftKeyMigration) {
+        return;
+    }
+    match DB.with(|db| count_pending_keys(&mut db.borrow_mut(), 1)) {
+        Ok(0) => {}
+        Ok(_) => log::warn!(
+            "Found keys of the software Keymaster emulator while the TEE is backed by KeyMint. \
+             They can be migrated with IKeystoreMaintenance::migrateSoftwareKeys."
+        ),
+        Err(e) => log::error!("Failed to look for keys of the software Keymaster emulator: {e:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::KeyOrigin::KeyOrigin;
+
+    fn kp(tag: Tag, value: KeyParameterValue) -> KeyParameter {
+        KeyParameter { tag, value }
+    }
+
+    #[test]
+    fn import_params_drop_device_authorizations() {
+        let characteristics = vec![
+            kp(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::AES)),
+            kp(Tag::KEY_SIZE, KeyParameterValue::Integer(256)),
+            kp(Tag::OS_PATCHLEVEL, KeyParameterValue::Integer(202401)),
+            kp(Tag::ORIGIN, KeyParameterValue::Origin(KeyOrigin::GENERATED)),
+        ];
+        assert_eq!(characteristics[..2].to_vec(), import_params(characteristics));
+    }
+
+    #[test]
+    fn import_params_add_certificate_validity() {
+        let params =
+            import_params(vec![kp(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC))]);
+        assert_eq!(
+            vec![
+                kp(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+                kp(Tag::CERTIFICATE_NOT_BEFORE, KeyParameterValue::DateTime(0)),
+                kp(Tag::CERTIFICATE_NOT_AFTER, KeyParameterValue::DateTime(UNDEFINED_NOT_AFTER)),
+            ],
+            params
+        );
+    }
+}
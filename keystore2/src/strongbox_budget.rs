@@ -0,0 +1,179 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module limits the number of StrongBox key generations per app and day. Secure elements
+//! store state in flash that wears with every write, so an app that churns keys can wear out
+//! the chip of a device.
+//!
+//! OEMs set the budget through the system property `BUDGET_PROPERTY`. If it is unset or 0,
+//! generations are counted but not limited. Apps that have used up their budget within the
+//! last `BUDGET_WINDOW` get `BACKEND_BUSY` with a retry hint, i.e., their request is deferred
+//! until their oldest generation leaves the window. The window is kept in memory, so it starts
+//! over when keystore restarts.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::error::{Error, RetryAfter};
+use crate::ks_err;
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The system property that holds the number of StrongBox key generations that an app may
+/// make per `BUDGET_WINDOW`.
+const BUDGET_PROPERTY: &str = "ro.keystore.strongbox.daily_generation_budget";
+
+/// The window over which StrongBox key generations are counted.
+const BUDGET_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The dump section of the budget.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "strongbox_generation_budget",
+    header: "StrongBox generation budget (budget per day, admitted, deferred):",
+    line: "  <budget>, <admitted>, <deferred>",
+    fields: dump_fields!(budget: "string", admitted: "uint64", deferred: "uint64"),
+};
+
+/// Counts StrongBox key generations per app and defers those that exceed the budget.
+#[derive(Default)]
+pub struct GenerationBudget {
+    /// The number of generations per app and window, or None if generations are not limited.
+    budget: Option<usize>,
+    /// The times of the generations of each uid within the current window, oldest first.
+    recent: Mutex<HashMap<u32, VecDeque<Instant>>>,
+    admitted: AtomicU64,
+    deferred: AtomicU64,
+}
+
+impl GenerationBudget {
+    /// Creates a tracker that admits `budget` generations per app and window, or any number
+    /// if `budget` is None.
+    pub fn new(budget: Option<usize>) -> Self {
+        Self { budget, ..Default::default() }
+    }
+
+    /// Creates a tracker with the budget configured in `BUDGET_PROPERTY`.
+    pub fn from_property() -> Self {
+        let budget = match rustutils::system_properties::read(BUDGET_PROPERTY) {
+            Ok(Some(value)) => match value.parse::<usize>() {
+                Ok(0) => None,
+                Ok(budget) => Some(budget),
+                Err(e) => {
+                    log::error!("Ignoring invalid {}={:?}: {:?}", BUDGET_PROPERTY, value, e);
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("Failed to read {}: {:?}", BUDGET_PROPERTY, e);
+                None
+            }
+        };
+        Self::new(budget)
+    }
+
+    /// Records a StrongBox key generation of `uid`. Fails with `BACKEND_BUSY` and a retry hint
+    /// if `uid` has used up its budget in the current window.
+    pub fn admit(&self, uid: u32) -> Result<()> {
+        self.admit_at(uid, Instant::now())
+    }
+
+    fn admit_at(&self, uid: u32, now: Instant) -> Result<()> {
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => {
+                self.admitted.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+        };
+        let mut recent = self.recent.lock().unwrap();
+        let generations = recent.entry(uid).or_default();
+        while generations.front().map_or(false, |t| now.duration_since(*t) >= BUDGET_WINDOW) {
+            generations.pop_front();
+        }
+        if let Some(oldest) = generations.front().filter(|_| generations.len() >= budget) {
+            let retry_after = BUDGET_WINDOW.saturating_sub(now.duration_since(*oldest));
+            self.deferred.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(ks_err!("Uid {uid} used up its budget of {budget} StrongBox keys."))
+                .context(RetryAfter(retry_after));
+        }
+        generations.push_back(now);
+        self.admitted.fetch_add(1, Ordering::Relaxed);
+        // Forget apps whose generations have all left the window.
+        recent.retain(|_, generations| {
+            generations.back().map_or(false, |t| now.duration_since(*t) < BUDGET_WINDOW)
+        });
+        Ok(())
+    }
+
+    /// Returns the number of admitted and deferred generations.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.admitted.load(Ordering::Relaxed), self.deferred.load(Ordering::Relaxed))
+    }
+
+    /// Writes the budget and the statistics of StrongBox key generations to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let (admitted, deferred) = self.stats();
+        let budget = self.budget.map_or_else(|| "none".to_string(), |b| b.to_string());
+        DUMP_SECTION.write_header(writer)?;
+        DUMP_SECTION.write_line(writer, &[&budget, &admitted, &deferred])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defers_per_uid() {
+        let budget = GenerationBudget::new(Some(3));
+        let start = Instant::now();
+        for i in 0..3 {
+            budget.admit_at(10001, start + Duration::from_secs(i)).unwrap();
+        }
+        let later = start + Duration::from_secs(60);
+        let e = budget.admit_at(10001, later).unwrap_err();
+        assert!(matches!(
+            e.root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::BACKEND_BUSY))
+        ));
+        assert_eq!(Some(&RetryAfter(BUDGET_WINDOW - Duration::from_secs(60))), e.downcast_ref());
+
+        // Other apps are not affected.
+        budget.admit_at(10002, later).unwrap();
+        // Once the oldest generation left the window, the app may generate again.
+        budget.admit_at(10001, start + BUDGET_WINDOW).unwrap();
+        assert!(budget.admit_at(10001, start + BUDGET_WINDOW).is_err());
+        assert_eq!((5, 2), budget.stats());
+    }
+
+    #[test]
+    fn counts_without_budget() {
+        let budget = GenerationBudget::new(None);
+        let now = Instant::now();
+        for _ in 0..100 {
+            budget.admit_at(10001, now).unwrap();
+        }
+        assert_eq!((100, 0), budget.stats());
+
+        let mut out = Vec::new();
+        budget.dump(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("\n  none, 100, 0\n"));
+    }
+}
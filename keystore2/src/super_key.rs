@@ -13,21 +13,34 @@
 // limitations under the License.
 
 use crate::{
-    boot_level_keys::{get_level_zero_key, BootLevelKeyCache},
+    boot_level_keys::{get_level_zero_key, BootLevelKeyCache, KeyMintKeyWrapper, KeyWrapper},
     database::BlobMetaData,
     database::BlobMetaEntry,
     database::EncryptedBy,
     database::KeyEntry,
     database::KeyType,
-    database::{KeyEntryLoadBits, KeyIdGuard, KeyMetaData, KeyMetaEntry, KeystoreDB},
+    database::{
+        DateTime, KeyEntryLoadBits, KeyIdGuard, KeyMetaData, KeyMetaEntry, KeystoreDB,
+        SubComponentType, UserKeyFilter,
+    },
+    dump_fields,
+    dump_schema::DumpSection,
     ec_crypto::ECDHPrivateKey,
     enforcements::Enforcements,
     error::Error,
     error::ResponseCode,
+    feature_flags::{self, Feature},
+    key_material_cache::KeyMaterialCache,
     key_parameter::{KeyParameter, KeyParameterValue},
     ks_err,
     legacy_importer::LegacyImporter,
+    lock_stats::ProfiledRwLock,
+    metrics_store::log_user_unlock_stats,
     raw_device::KeyMintDevice,
+    reboot_escrow::{self, USER_SUPER_KEY_REBOOT_ESCROW},
+    super_key_escrow::{self, USER_SUPER_KEY_ESCROW},
+    super_key_secret,
+    synthetic_password::{SyntheticPasswordToken, TokenType},
     utils::{watchdog as wd, AesGcm, AID_KEYSTORE},
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
@@ -41,16 +54,22 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 use anyhow::{Context, Result};
 use keystore2_crypto::{
-    aes_gcm_decrypt, aes_gcm_encrypt, generate_aes256_key, generate_salt, Password, ZVec,
-    AES_256_KEY_LENGTH,
+    aes_gcm_decrypt, aes_gcm_encrypt, generate_aes256_key, generate_salt, Argon2idParams, Password,
+    ZVec, AES_256_KEY_LENGTH,
 };
 use rustutils::system_properties::PropertyWatcher;
 use std::{
+    cell::Cell,
     collections::HashMap,
     sync::Arc,
-    sync::{Mutex, RwLock, Weak},
+    sync::{Mutex, Weak},
+};
+use std::{
+    convert::TryFrom,
+    io::Write,
+    ops::Deref,
+    time::{Duration, Instant},
 };
-use std::{convert::TryFrom, ops::Deref};
 
 const MAX_MAX_BOOT_LEVEL: usize = 1_000_000_000;
 /// Allow up to 15 seconds between the user unlocking using a biometric, and the auth
@@ -59,8 +78,61 @@ const MAX_MAX_BOOT_LEVEL: usize = 1_000_000_000;
 /// very slowest device will present the auth token in time.
 const BIOMETRIC_AUTH_TIMEOUT_S: i32 = 15; // seconds
 
+/// The number of stale key blobs that are re-wrapped in one database transaction.
+const REWRAP_BATCH_SIZE: usize = 32;
+
+/// How long key material that was decrypted by `SuperKeyManager::prewarm_key` stays cached if
+/// no operation uses it. This covers the time between, e.g., showing a payment sheet and the tap.
+const PREWARM_TTL: Duration = Duration::from_secs(30);
+
+/// Set by init while a userspace reboot is in progress. See `userspace_reboot_in_progress`.
+const USERSPACE_REBOOT_IN_PROGRESS_PROPERTY: &str = "sys.init.userspace_reboot.in_progress";
+
 type UserId = u32;
 
+/// During a userspace reboot the authentication state of the users is torn down and
+/// re-established, so keys that are bound to the LSKF must not be created in this window.
+fn userspace_reboot_in_progress() -> bool {
+    rustutils::system_properties::read_bool(USERSPACE_REBOOT_IN_PROGRESS_PROPERTY, false)
+        .unwrap_or_else(|e| {
+            log::warn!("Failed to read {}: {:?}", USERSPACE_REBOOT_IN_PROGRESS_PROPERTY, e);
+            false
+        })
+}
+
+/// The time that the unlock in progress on this thread spent in key derivation and in database
+/// loads. It is collected by `SuperKeyManager::unlock_user` and reported to the metrics store.
+#[derive(Debug, Default, Clone, Copy)]
+struct UnlockTimings {
+    kdf: Duration,
+    db_load: Duration,
+}
+
+thread_local! {
+    static UNLOCK_TIMINGS: Cell<UnlockTimings> = const {
+        Cell::new(UnlockTimings { kdf: Duration::ZERO, db_load: Duration::ZERO })
+    };
+}
+
+impl UnlockTimings {
+    /// Runs `f` and adds its duration to the field of the timings that `field` selects.
+    fn measure<T>(field: fn(&mut Self) -> &mut Duration, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        UNLOCK_TIMINGS.with(|timings| {
+            let mut t = timings.get();
+            *field(&mut t) += start.elapsed();
+            timings.set(t);
+        });
+        result
+    }
+
+    /// Returns the timings collected on this thread so far, and starts over.
+    fn take() -> Self {
+        UNLOCK_TIMINGS.with(|timings| timings.take())
+    }
+}
+
 /// Encryption algorithm used by a particular type of superencryption key
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SuperEncryptionAlgorithm {
@@ -100,6 +172,15 @@ pub const USER_UNLOCKED_DEVICE_REQUIRED_P521_SUPER_KEY: SuperKeyType = SuperKeyT
     algorithm: SuperEncryptionAlgorithm::EcdhP521,
 };
 
+/// The user's biometric-bound super key. It is stored encrypted with a KeyMint key that requires
+/// the authentication of one of the user's biometrics, so it is unlocked by the auth token of a
+/// successful biometric rather than by the LSKF. It is cleared from memory when the device is
+/// locked. This is used to encrypt keys that only a biometric can authorize.
+pub const USER_BIOMETRIC_BOUND_SUPER_KEY: SuperKeyType = SuperKeyType {
+    alias: "USER_BIOMETRIC_BOUND_KEY",
+    algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+};
+
 /// Superencryption to apply to a new key.
 #[derive(Debug, Clone, Copy)]
 pub enum SuperEncryptionType {
@@ -111,6 +192,9 @@ pub enum SuperEncryptionType {
     UnlockedDeviceRequired,
     /// Superencrypt with a key based on the desired boot level
     BootLevel(i32),
+    /// Superencrypt with the biometric-bound super key if the user has one, and with the
+    /// AfterFirstUnlock super key otherwise.
+    BiometricBound,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -154,6 +238,24 @@ pub struct SuperKey {
     reencrypt_with: Option<Arc<SuperKey>>,
 }
 
+impl SuperKey {
+    /// Creates a super key. The key material is copied into a guarded ZVec, so that it is locked
+    /// in memory, excluded from core dumps, and surrounded by guard pages.
+    fn new(
+        algorithm: SuperEncryptionAlgorithm,
+        key: ZVec,
+        id: SuperKeyIdentifier,
+        reencrypt_with: Option<Arc<SuperKey>>,
+    ) -> Result<Arc<Self>> {
+        let key = if key.is_guarded() {
+            key
+        } else {
+            key.try_clone_guarded().context(ks_err!("Failed to guard the super key."))?
+        };
+        Ok(Arc::new(Self { algorithm, key, id, reencrypt_with }))
+    }
+}
+
 impl AesGcm for SuperKey {
     fn decrypt(&self, data: &[u8], iv: &[u8], tag: &[u8]) -> Result<ZVec> {
         if self.algorithm == SuperEncryptionAlgorithm::Aes256Gcm {
@@ -221,7 +323,7 @@ impl LockedKey {
             Some(auth_token),
             &self.ciphertext,
         )?)?;
-        Ok(Arc::new(SuperKey { algorithm: self.algorithm, key, id: self.id, reencrypt_with }))
+        SuperKey::new(self.algorithm, key, self.id, reencrypt_with)
     }
 }
 
@@ -237,6 +339,53 @@ struct BiometricUnlock {
     private: LockedKey,
 }
 
+/// The AfterFirstUnlock super key of a background user that was evicted from memory under
+/// memory pressure. It is kept wrapped by a KeyMint key, and unwrapped again when it is used.
+struct EvictedSuperKey {
+    id: SuperKeyIdentifier,
+    wrapped: Vec<u8>,
+    wrapper: Arc<Mutex<Box<dyn KeyWrapper>>>,
+    /// The unwrapped key, from its first use after the eviction until the next trim.
+    restored: Mutex<Option<Arc<SuperKey>>>,
+}
+
+impl EvictedSuperKey {
+    fn new(super_key: &SuperKey, wrapper: Arc<Mutex<Box<dyn KeyWrapper>>>) -> Result<Self> {
+        let wrapped = wrapper.lock().unwrap().wrap(&super_key.key).context(ks_err!())?;
+        Ok(Self { id: super_key.id, wrapped, wrapper, restored: Mutex::new(None) })
+    }
+
+    fn database_id(&self) -> Option<i64> {
+        match self.id {
+            SuperKeyIdentifier::DatabaseId(id) => Some(id),
+            SuperKeyIdentifier::BootLevel(_) => None,
+        }
+    }
+
+    /// Returns the unwrapped super key.
+    fn restore(&self) -> Result<Arc<SuperKey>> {
+        let mut restored = self.restored.lock().unwrap();
+        if let Some(super_key) = restored.as_ref() {
+            return Ok(super_key.clone());
+        }
+        let key = self
+            .wrapper
+            .lock()
+            .unwrap()
+            .unwrap(&self.wrapped)
+            .context(ks_err!("Failed to unwrap evicted super key."))?;
+        let super_key =
+            SuperKey::new(USER_AFTER_FIRST_UNLOCK_SUPER_KEY.algorithm, key, self.id, None)?;
+        *restored = Some(super_key.clone());
+        Ok(super_key)
+    }
+
+    /// Drops the unwrapped key, if any. Returns true if there was one.
+    fn drop_restored(&self) -> bool {
+        self.restored.lock().unwrap().take().is_some()
+    }
+}
+
 #[derive(Default)]
 struct UserSuperKeys {
     /// The AfterFirstUnlock super key is used for LSKF binding of authentication bound keys. There
@@ -253,13 +402,87 @@ struct UserSuperKeys {
     unlocked_device_required_private: Option<Arc<SuperKey>>,
     /// Versions of the above two keys, locked behind a biometric.
     biometric_unlock: Option<BiometricUnlock>,
+    /// The biometric-bound super key is unlocked by the auth token of a biometric and cleared from
+    /// memory when the device is locked. Unlike the keys above, it never depends on the LSKF.
+    biometric_bound: Option<Arc<SuperKey>>,
+    /// The AfterFirstUnlock super key, if it was evicted while the user was locked.
+    evicted_after_first_unlock: Option<EvictedSuperKey>,
+}
+
+impl UserSuperKeys {
+    /// Returns the database ids of the given super keys that are in memory.
+    fn database_ids(keys: &[&Option<Arc<SuperKey>>]) -> Vec<i64> {
+        keys.iter()
+            .filter_map(|key| match key.as_ref().map(|key| key.id) {
+                Some(SuperKeyIdentifier::DatabaseId(id)) => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the database ids of the super keys in memory that are cleared when the device is
+    /// locked.
+    fn unlocked_device_required_key_ids(&self) -> Vec<i64> {
+        Self::database_ids(&[
+            &self.unlocked_device_required_symmetric,
+            &self.unlocked_device_required_private,
+            &self.biometric_bound,
+        ])
+    }
+
+    /// Returns the database ids of all super keys in memory.
+    fn key_ids(&self) -> Vec<i64> {
+        let mut ids = self.unlocked_device_required_key_ids();
+        ids.extend(Self::database_ids(&[&self.after_first_unlock]));
+        ids.extend(self.evicted_after_first_unlock.as_ref().and_then(|e| e.database_id()));
+        ids
+    }
+
+    /// Returns true if the user is locked, i.e., only the AfterFirstUnlock super key of the user
+    /// may be in memory.
+    fn is_background(&self) -> bool {
+        self.unlocked_device_required_symmetric.is_none()
+            && self.unlocked_device_required_private.is_none()
+            && self.biometric_bound.is_none()
+    }
+
+    /// Returns true if nothing is held for the user.
+    fn is_empty(&self) -> bool {
+        self.is_background()
+            && self.after_first_unlock.is_none()
+            && self.biometric_unlock.is_none()
+            && self.evicted_after_first_unlock.is_none()
+    }
+
+    /// Returns the AfterFirstUnlock super key, unwrapping it if it was evicted. An eviction that
+    /// cannot be undone is logged, and the user appears as if it had not unlocked yet.
+    fn after_first_unlock_key(&self) -> Option<Arc<SuperKey>> {
+        self.after_first_unlock.clone().or_else(|| {
+            self.evicted_after_first_unlock.as_ref().and_then(|evicted| {
+                evicted
+                    .restore()
+                    .map_err(|e| log::error!("Failed to restore evicted super key: {:?}", e))
+                    .ok()
+            })
+        })
+    }
 }
 
+/// The super keys in memory, per Android user. A managed profile, e.g., a work profile, is an
+/// Android user of its own, with its own super keys and its own lock state. Locking or
+/// forgetting the keys of a user leaves those of other users alone, including the parent user
+/// of a profile.
 #[derive(Default)]
 struct SkmState {
     user_keys: HashMap<UserId, UserSuperKeys>,
     key_index: HashMap<i64, Weak<SuperKey>>,
     boot_level_key_cache: Option<Mutex<BootLevelKeyCache>>,
+    /// Wraps the super keys of background users that are evicted under memory pressure. It is
+    /// created on the first eviction.
+    eviction_wrapper: Option<Arc<Mutex<Box<dyn KeyWrapper>>>>,
+    /// Incremented whenever biometric-bound super keys are cleared from memory, so that a key that
+    /// was decrypted before is not put back afterwards.
+    biometric_bound_epoch: u64,
 }
 
 impl SkmState {
@@ -273,13 +496,25 @@ impl SkmState {
     }
 }
 
+/// The dump section of the cache of super keys.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "super_key_cache",
+    header: "Super key cache (users, super keys, evicted users):",
+    line: "  <users>, <keys>, <evicted>",
+    fields: dump_fields!(users: "uint64", keys: "uint64", evicted: "uint64"),
+};
+
 #[derive(Default)]
 pub struct SuperKeyManager {
     data: SkmState,
+    material_cache: KeyMaterialCache,
 }
 
 impl SuperKeyManager {
-    pub fn set_up_boot_level_cache(skm: &Arc<RwLock<Self>>, db: &mut KeystoreDB) -> Result<()> {
+    pub fn set_up_boot_level_cache(
+        skm: &Arc<ProfiledRwLock<Self>>,
+        db: &mut KeystoreDB,
+    ) -> Result<()> {
         let mut skm_guard = skm.write().unwrap();
         if skm_guard.data.boot_level_key_cache.is_some() {
             log::info!("In set_up_boot_level_cache: called for a second time");
@@ -287,8 +522,21 @@ impl SuperKeyManager {
         }
         let level_zero_key =
             get_level_zero_key(db).context(ks_err!("get_level_zero_key failed"))?;
-        skm_guard.data.boot_level_key_cache =
-            Some(Mutex::new(BootLevelKeyCache::new(level_zero_key)));
+        let cache = if feature_flags::is_enabled(Feature::WrappedBootLevelKeys) {
+            // Without the wrapper, boot level keys still work, but are exposed in memory.
+            match KeyMintKeyWrapper::new().and_then(|wrapper| {
+                BootLevelKeyCache::new_wrapped(level_zero_key.try_clone()?, Box::new(wrapper))
+            }) {
+                Ok(cache) => cache,
+                Err(e) => {
+                    log::error!("Failed to wrap boot level keys: {:?}", e);
+                    BootLevelKeyCache::new(level_zero_key)
+                }
+            }
+        } else {
+            BootLevelKeyCache::new(level_zero_key)
+        };
+        skm_guard.data.boot_level_key_cache = Some(Mutex::new(cache));
         log::info!("Starting boot level watcher.");
         let clone = skm.clone();
         std::thread::spawn(move || {
@@ -300,7 +548,7 @@ impl SuperKeyManager {
 
     /// Watch the `keystore.boot_level` system property, and keep boot level up to date.
     /// Blocks waiting for system property changes, so must be run in its own thread.
-    fn watch_boot_level(skm: Arc<RwLock<Self>>) -> Result<()> {
+    fn watch_boot_level(skm: Arc<ProfiledRwLock<Self>>) -> Result<()> {
         let mut w = PropertyWatcher::new("keystore.boot_level")
             .context(ks_err!("PropertyWatcher::new failed"))?;
         loop {
@@ -348,7 +596,120 @@ impl SuperKeyManager {
     }
 
     pub fn forget_all_keys_for_user(&mut self, user: UserId) {
-        self.data.user_keys.remove(&user);
+        if let Some(keys) = self.data.user_keys.remove(&user) {
+            self.material_cache.evict_super_keys(&keys.key_ids());
+        }
+        self.data.biometric_bound_epoch += 1;
+    }
+
+    /// Returns the cache of decrypted key blobs.
+    pub fn key_material_cache(&self) -> &KeyMaterialCache {
+        &self.material_cache
+    }
+
+    /// Drops the index entries of super keys that are no longer held anywhere. Returns the
+    /// number of dropped entries.
+    pub fn prune_key_index(&mut self) -> u64 {
+        let before = self.data.key_index.len();
+        self.data.key_index.retain(|_, key| key.strong_count() > 0);
+        (before - self.data.key_index.len()) as u64
+    }
+
+    /// Returns the number of users with an entry, the number of super keys held for them, and the
+    /// number of users whose AfterFirstUnlock super key is evicted.
+    pub fn cache_size(&self) -> (usize, usize, usize) {
+        let user_keys = self.data.user_keys.values();
+        (
+            self.data.user_keys.len(),
+            user_keys.clone().map(|keys| keys.key_ids().len()).sum(),
+            user_keys.filter(|keys| keys.evicted_after_first_unlock.is_some()).count(),
+        )
+    }
+
+    /// Writes the size of the cache of super keys to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let (users, keys, evicted) = self.cache_size();
+        DUMP_SECTION.write_header(writer)?;
+        DUMP_SECTION.write_line(writer, &[&users, &keys, &evicted])
+    }
+
+    fn eviction_wrapper(&mut self) -> Result<Arc<Mutex<Box<dyn KeyWrapper>>>> {
+        if let Some(wrapper) = &self.data.eviction_wrapper {
+            return Ok(wrapper.clone());
+        }
+        let wrapper: Arc<Mutex<Box<dyn KeyWrapper>>> = Arc::new(Mutex::new(Box::new(
+            KeyMintKeyWrapper::new().context(ks_err!("Failed to create wrapping key."))?,
+        )));
+        self.data.eviction_wrapper = Some(wrapper.clone());
+        Ok(wrapper)
+    }
+
+    /// Releases memory held for users. Super keys that were unwrapped since the last trim are
+    /// dropped again, and entries that hold nothing are removed. If `evict_background` is true,
+    /// the AfterFirstUnlock super keys of locked users are also evicted, i.e., kept only wrapped
+    /// by a KeyMint key, and unwrapped on their next use. Returns the number of evicted keys and
+    /// removed entries.
+    pub fn trim_user_keys(&mut self, evict_background: bool) -> u64 {
+        let mut evicted = self
+            .data
+            .user_keys
+            .values()
+            .filter_map(|keys| keys.evicted_after_first_unlock.as_ref())
+            .filter(|e| e.drop_restored())
+            .count() as u64;
+        let background: Vec<UserId> = self
+            .data
+            .user_keys
+            .iter()
+            .filter(|(_, keys)| keys.is_background() && keys.after_first_unlock.is_some())
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        if evict_background && !background.is_empty() {
+            match self.eviction_wrapper() {
+                Ok(wrapper) => {
+                    for user_id in background {
+                        let keys = self.data.user_keys.get_mut(&user_id).unwrap();
+                        let super_key = keys.after_first_unlock.as_ref().unwrap();
+                        match EvictedSuperKey::new(super_key, wrapper.clone()) {
+                            Ok(evicted_key) => {
+                                self.material_cache.evict_super_keys(&keys.key_ids());
+                                keys.evicted_after_first_unlock = Some(evicted_key);
+                                keys.after_first_unlock = None;
+                                evicted += 1;
+                            }
+                            Err(e) => {
+                                log::error!("Failed to evict super key of user {user_id}: {e:?}")
+                            }
+                        }
+                    }
+                }
+                Err(e) => log::error!("Cannot evict super keys: {:?}", e),
+            }
+        }
+        let users = self.data.user_keys.len();
+        self.data.user_keys.retain(|_, keys| !keys.is_empty());
+        evicted + (users - self.data.user_keys.len()) as u64
+    }
+
+    /// Moves the evicted AfterFirstUnlock super key of a user that is no longer in the
+    /// background back into memory.
+    fn reinstate_evicted_key(&mut self, user_id: UserId) {
+        let evicted = match self.data.user_keys.get(&user_id) {
+            Some(UserSuperKeys { evicted_after_first_unlock: Some(evicted), .. }) => evicted,
+            _ => return,
+        };
+        match evicted.restore() {
+            Ok(super_key) => {
+                if let Err(e) = self.data.add_key_to_key_index(&super_key) {
+                    log::error!("Failed to index reinstated super key: {:?}", e);
+                    return;
+                }
+                let entry = self.data.user_keys.entry(user_id).or_default();
+                entry.after_first_unlock = Some(super_key);
+                entry.evicted_after_first_unlock = None;
+            }
+            Err(e) => log::error!("Failed to reinstate evicted super key: {:?}", e),
+        }
     }
 
     fn install_after_first_unlock_key_for_user(
@@ -359,14 +720,27 @@ impl SuperKeyManager {
         self.data
             .add_key_to_key_index(&super_key)
             .context(ks_err!("add_key_to_key_index failed"))?;
-        self.data.user_keys.entry(user).or_default().after_first_unlock = Some(super_key);
+        let entry = self.data.user_keys.entry(user).or_default();
+        entry.after_first_unlock = Some(super_key);
+        entry.evicted_after_first_unlock = None;
         Ok(())
     }
 
     fn lookup_key(&self, key_id: &SuperKeyIdentifier) -> Result<Option<Arc<SuperKey>>> {
         Ok(match key_id {
             SuperKeyIdentifier::DatabaseId(id) => {
-                self.data.key_index.get(id).and_then(|k| k.upgrade())
+                match self.data.key_index.get(id).and_then(|k| k.upgrade()) {
+                    Some(super_key) => Some(super_key),
+                    None => self
+                        .data
+                        .user_keys
+                        .values()
+                        .filter_map(|keys| keys.evicted_after_first_unlock.as_ref())
+                        .find(|evicted| evicted.database_id() == Some(*id))
+                        .map(|evicted| evicted.restore())
+                        .transpose()
+                        .context(ks_err!("Failed to restore evicted super key."))?,
+                }
             }
             SuperKeyIdentifier::BootLevel(level) => self
                 .data
@@ -376,14 +750,8 @@ impl SuperKeyManager {
                 .transpose()
                 .context(ks_err!("aes_key failed"))?
                 .flatten()
-                .map(|key| {
-                    Arc::new(SuperKey {
-                        algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
-                        key,
-                        id: *key_id,
-                        reencrypt_with: None,
-                    })
-                }),
+                .map(|key| SuperKey::new(SuperEncryptionAlgorithm::Aes256Gcm, key, *key_id, None))
+                .transpose()?,
         })
     }
 
@@ -401,7 +769,7 @@ impl SuperKeyManager {
         &self,
         user_id: UserId,
     ) -> Option<Arc<SuperKey>> {
-        self.data.user_keys.get(&user_id).and_then(|e| e.after_first_unlock.as_ref().cloned())
+        self.data.user_keys.get(&user_id).and_then(|e| e.after_first_unlock_key())
     }
 
     /// Check if a given key is super-encrypted, from its metadata. If so, unwrap the key using
@@ -418,8 +786,9 @@ impl SuperKeyManager {
                 .ok_or(Error::Rc(ResponseCode::LOCKED))
                 .context(ks_err!("Required super decryption key is not in memory."))?;
             KeyBlob::Sensitive {
-                key: Self::unwrap_key_with_key(blob, metadata, &super_key)
-                    .context(ks_err!("unwrap_key_with_key failed"))?,
+                key: self
+                    .unwrap_key_with_cache(blob, metadata, &super_key)
+                    .context(ks_err!("unwrap_key_with_cache failed"))?,
                 reencrypt_with: super_key.reencrypt_with.as_ref().unwrap_or(&super_key).clone(),
                 force_reencrypt: super_key.reencrypt_with.is_some(),
             }
@@ -428,6 +797,68 @@ impl SuperKeyManager {
         })
     }
 
+    /// Returns the key of a blob encrypted with `key` in the key material cache, or None if the
+    /// blob cannot be cached. Blobs encrypted with a boot level key are not cached.
+    fn material_cache_key<'a>(
+        metadata: &'a BlobMetaData,
+        key: &SuperKey,
+    ) -> Option<(i64, &'a [u8], &'a [u8])> {
+        match (key.id, metadata.iv(), metadata.aead_tag()) {
+            (SuperKeyIdentifier::DatabaseId(id), Some(iv), Some(tag))
+                if feature_flags::is_enabled(Feature::KeyMaterialCache) =>
+            {
+                Some((id, iv, tag))
+            }
+            _ => None,
+        }
+    }
+
+    /// Like `unwrap_key_with_key`, but returns the result of an earlier call for the same blob
+    /// if it is still cached.
+    fn unwrap_key_with_cache(
+        &self,
+        blob: &[u8],
+        metadata: &BlobMetaData,
+        key: &SuperKey,
+    ) -> Result<ZVec> {
+        let cache_key = Self::material_cache_key(metadata, key);
+        if let Some((id, iv, tag)) = cache_key {
+            if let Some(material) = self.material_cache.get(id, iv, tag) {
+                return Ok(material);
+            }
+        }
+        let material = Self::unwrap_key_with_key(blob, metadata, key)?;
+        if let Some((id, iv, tag)) = cache_key {
+            self.material_cache.insert(id, iv, tag, &material);
+        }
+        Ok(material)
+    }
+
+    /// Unwraps a super-encrypted key blob into the key material cache ahead of an operation on
+    /// the key, which then skips the decryption. The material is dropped if no operation uses
+    /// it within `PREWARM_TTL`. Blobs that are not super-encrypted, that cannot be cached, or
+    /// that are cached already are left alone.
+    pub fn prewarm_key(&self, metadata: &BlobMetaData, blob: &[u8]) -> Result<()> {
+        let key_id = match SuperKeyIdentifier::from_metadata(metadata) {
+            Some(key_id) => key_id,
+            None => return Ok(()),
+        };
+        let super_key = self
+            .lookup_key(&key_id)
+            .context(ks_err!("lookup_key failed"))?
+            .ok_or(Error::Rc(ResponseCode::LOCKED))
+            .context(ks_err!("Required super decryption key is not in memory."))?;
+        match Self::material_cache_key(metadata, &super_key) {
+            Some((id, iv, tag)) if !self.material_cache.contains(id, iv, tag) => {
+                let material = Self::unwrap_key_with_key(blob, metadata, &super_key)
+                    .context(ks_err!("Failed to unwrap key."))?;
+                self.material_cache.insert_prewarmed(id, iv, tag, &material, PREWARM_TTL);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     /// Unwraps an encrypted key blob given an encryption key.
     fn unwrap_key_with_key(blob: &[u8], metadata: &BlobMetaData, key: &SuperKey) -> Result<ZVec> {
         match key.algorithm {
@@ -493,20 +924,24 @@ impl SuperKeyManager {
     // Helper function to populate super key cache from the super key blob loaded from the database.
     fn populate_cache_from_super_key_blob(
         &mut self,
+        db: &mut KeystoreDB,
         user_id: UserId,
         algorithm: SuperEncryptionAlgorithm,
         entry: KeyEntry,
         pw: &Password,
     ) -> Result<Arc<SuperKey>> {
-        let super_key = Self::extract_super_key_from_key_entry(algorithm, entry, pw, None)
+        let super_key = Self::extract_super_key_from_key_entry(db, algorithm, entry, pw, None)
             .context(ks_err!("Failed to extract super key from key entry"))?;
         self.install_after_first_unlock_key_for_user(user_id, super_key.clone())
             .context(ks_err!("Failed to install AfterFirstUnlock super key for user!"))?;
         Ok(super_key)
     }
 
-    /// Extracts super key from the entry loaded from the database.
+    /// Extracts super key from the entry loaded from the database. If the key that encrypts the
+    /// super key was derived from the password with an outdated KDF, the super key is
+    /// re-encrypted with the current one, see `upgrade_kdf_if_outdated`.
     pub fn extract_super_key_from_key_entry(
+        db: &mut KeystoreDB,
         algorithm: SuperEncryptionAlgorithm,
         entry: KeyEntry,
         pw: &Password,
@@ -521,12 +956,24 @@ impl SuperKeyManager {
             ) {
                 (Some(&EncryptedBy::Password), Some(salt), Some(iv), Some(tag)) => {
                     // Note that password encryption is AES no matter the value of algorithm.
-                    let key = pw
-                        .derive_key(salt, AES_256_KEY_LENGTH)
+                    let argon2id = Self::argon2id_params(metadata).context(ks_err!())?;
+                    let key = Self::derive_key_from_password(pw, salt, argon2id)
                         .context(ks_err!("Failed to generate key from password."))?;
+                    let key =
+                        super_key_secret::unbind(key, metadata.secret_handle().map(|h| &h[..]))
+                            .context(ks_err!("Failed to unbind key from secret."))?;
 
-                    aes_gcm_decrypt(blob, iv, tag, &key)
-                        .context(ks_err!("Failed to decrypt key blob."))?
+                    let key = aes_gcm_decrypt(blob, iv, tag, &key)
+                        .context(ks_err!("Failed to decrypt key blob."))?;
+                    Self::upgrade_kdf_if_outdated(
+                        db,
+                        entry.id(),
+                        (blob, metadata),
+                        &key,
+                        pw,
+                        Self::current_argon2id_params(),
+                    );
+                    key
                 }
                 (enc_by, salt, iv, tag) => {
                     return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED)).context(ks_err!(
@@ -541,29 +988,139 @@ impl SuperKeyManager {
                     ));
                 }
             };
-            Ok(Arc::new(SuperKey {
+            SuperKey::new(
                 algorithm,
                 key,
-                id: SuperKeyIdentifier::DatabaseId(entry.id()),
+                SuperKeyIdentifier::DatabaseId(entry.id()),
                 reencrypt_with,
-            }))
+            )
         } else {
             Err(Error::Rc(ResponseCode::VALUE_CORRUPTED)).context(ks_err!("No key blob info."))
         }
     }
 
+    /// Returns the Argon2id cost parameters of a password encrypted super key, or None if the
+    /// key was derived from the password with PBKDF2.
+    fn argon2id_params(metadata: &BlobMetaData) -> Result<Option<Argon2idParams>> {
+        let param = |p: Option<&i32>| p.map(|p| u32::try_from(*p)).transpose();
+        match (
+            param(metadata.argon2id_iterations()),
+            param(metadata.argon2id_memory_kib()),
+            param(metadata.argon2id_parallelism()),
+        ) {
+            (Ok(None), Ok(None), Ok(None)) => Ok(None),
+            (Ok(Some(iterations)), Ok(Some(memory_kib)), Ok(Some(parallelism))) => {
+                Ok(Some(Argon2idParams { iterations, memory_kib, parallelism }))
+            }
+            _ => Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context(ks_err!("Super key has invalid Argon2id parameters.")),
+        }
+    }
+
+    /// Returns the Argon2id cost parameters of new password encryptions of super keys, or None if
+    /// they use PBKDF2.
+    fn current_argon2id_params() -> Option<Argon2idParams> {
+        feature_flags::is_enabled(Feature::Argon2idSuperKeys).then_some(Argon2idParams::DEFAULT)
+    }
+
+    /// Returns whether a key derived with `argon2id` is weaker than one derived with `current`.
+    /// Super keys are never moved from Argon2id back to PBKDF2.
+    fn kdf_is_outdated(argon2id: Option<Argon2idParams>, current: Option<Argon2idParams>) -> bool {
+        match (argon2id, current) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(old), Some(new)) => {
+                old.iterations < new.iterations || old.memory_kib < new.memory_kib
+            }
+        }
+    }
+
+    /// Re-encrypts the super key `key_id` with a key derived from the password with `current`,
+    /// if the key derivation of its blob `old` is outdated. The blob is only replaced if it is
+    /// still the current blob of the super key, so a concurrent password change is not undone.
+    /// This is best effort: the super key is usable either way, and the upgrade is retried the
+    /// next time the super key is decrypted with the password.
+    fn upgrade_kdf_if_outdated(
+        db: &mut KeystoreDB,
+        key_id: i64,
+        old: (&[u8], &BlobMetaData),
+        key: &[u8],
+        pw: &Password,
+        current: Option<Argon2idParams>,
+    ) {
+        let (old_blob, old_metadata) = old;
+        match Self::argon2id_params(old_metadata) {
+            Ok(argon2id) if Self::kdf_is_outdated(argon2id, current) => {}
+            _ => return,
+        }
+        let result =
+            Self::encrypt_with_password_using(key, pw, current).and_then(|(blob, metadata)| {
+                let result = db.replace_super_key_blob(key_id, old_blob, &blob, &metadata);
+                let released = match result {
+                    Ok(true) => old_metadata.secret_handle(),
+                    _ => metadata.secret_handle(),
+                };
+                super_key_secret::release(&released.into_iter().cloned().collect::<Vec<_>>());
+                result
+            });
+        match result {
+            Ok(true) => log::info!("Upgraded the password KDF of super key {key_id}."),
+            Ok(false) => log::info!("Super key {key_id} changed while upgrading its KDF."),
+            Err(e) => {
+                log::error!("Failed to upgrade the password KDF of super key {key_id}: {e:?}")
+            }
+        }
+    }
+
+    /// Derives the key that encrypts a super key from the password, with Argon2id if `argon2id`
+    /// holds its cost parameters, and with PBKDF2 otherwise.
+    fn derive_key_from_password(
+        pw: &Password,
+        salt: &[u8],
+        argon2id: Option<Argon2idParams>,
+    ) -> Result<ZVec> {
+        UnlockTimings::measure(
+            |t| &mut t.kdf,
+            || match argon2id {
+                Some(params) => pw
+                    .derive_key_argon2id(salt, AES_256_KEY_LENGTH, &params)
+                    .context(ks_err!("Failed to derive key with Argon2id.")),
+                None => pw
+                    .derive_key(salt, AES_256_KEY_LENGTH)
+                    .context(ks_err!("Failed to derive key with PBKDF2.")),
+            },
+        )
+    }
+
     /// Encrypts the super key from a key derived from the password, before storing in the database.
     pub fn encrypt_with_password(
         super_key: &[u8],
         pw: &Password,
+    ) -> Result<(Vec<u8>, BlobMetaData)> {
+        Self::encrypt_with_password_using(super_key, pw, Self::current_argon2id_params())
+    }
+
+    fn encrypt_with_password_using(
+        super_key: &[u8],
+        pw: &Password,
+        argon2id: Option<Argon2idParams>,
     ) -> Result<(Vec<u8>, BlobMetaData)> {
         let salt = generate_salt().context("In encrypt_with_password: Failed to generate salt.")?;
-        let derived_key = pw
-            .derive_key(&salt, AES_256_KEY_LENGTH)
+        let derived_key = Self::derive_key_from_password(pw, &salt, argon2id)
             .context(ks_err!("Failed to derive password."))?;
+        let (derived_key, secret_handle) = super_key_secret::bind(derived_key)
+            .context(ks_err!("Failed to bind key to secret."))?;
         let mut metadata = BlobMetaData::new();
         metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::Password));
         metadata.add(BlobMetaEntry::Salt(salt));
+        if let Some(params) = argon2id {
+            metadata.add(BlobMetaEntry::Argon2idIterations(params.iterations as i32));
+            metadata.add(BlobMetaEntry::Argon2idMemoryKib(params.memory_kib as i32));
+            metadata.add(BlobMetaEntry::Argon2idParallelism(params.parallelism as i32));
+        }
+        if let Some(handle) = secret_handle {
+            metadata.add(BlobMetaEntry::SecretHandle(handle));
+        }
         let (encrypted_key, iv, tag) = aes_gcm_encrypt(super_key, &derived_key)
             .context(ks_err!("Failed to encrypt new super key."))?;
         metadata.add(BlobMetaEntry::Iv(iv));
@@ -637,7 +1194,8 @@ impl SuperKeyManager {
     }
 
     /// Check if super encryption is required and if so, super-encrypt the key to be stored in
-    /// the database.
+    /// the database. Keys that are bound to the LSKF are rejected with `BACKEND_BUSY` while a
+    /// userspace reboot is in progress.
     #[allow(clippy::too_many_arguments)]
     pub fn handle_super_encryption_on_key_init(
         &self,
@@ -649,26 +1207,23 @@ impl SuperKeyManager {
         user_id: UserId,
         key_blob: &[u8],
     ) -> Result<(Vec<u8>, BlobMetaData)> {
-        match Enforcements::super_encryption_required(domain, key_parameters, flags) {
+        let encryption_type =
+            Enforcements::super_encryption_required(domain, key_parameters, flags);
+        if matches!(
+            encryption_type,
+            SuperEncryptionType::AfterFirstUnlock
+                | SuperEncryptionType::UnlockedDeviceRequired
+                | SuperEncryptionType::BiometricBound
+        ) && userspace_reboot_in_progress()
+        {
+            // The client may retry once the user state has been re-established.
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(ks_err!("Userspace reboot in progress for user {user_id}."));
+        }
+        match encryption_type {
             SuperEncryptionType::None => Ok((key_blob.to_vec(), BlobMetaData::new())),
             SuperEncryptionType::AfterFirstUnlock => {
-                // Encrypt the given key blob with the user's AfterFirstUnlock super key. If the
-                // user has not unlocked the device since boot or has no LSKF, an error is returned.
-                match self
-                    .get_user_state(db, legacy_importer, user_id)
-                    .context(ks_err!("Failed to get user state for user {user_id}"))?
-                {
-                    UserState::AfterFirstUnlock(super_key) => {
-                        Self::encrypt_with_aes_super_key(key_blob, &super_key).context(ks_err!(
-                            "Failed to encrypt with AfterFirstUnlock super key for user {user_id}"
-                        ))
-                    }
-                    UserState::BeforeFirstUnlock => {
-                        Err(Error::Rc(ResponseCode::LOCKED)).context(ks_err!("Device is locked."))
-                    }
-                    UserState::Uninitialized => Err(Error::Rc(ResponseCode::UNINITIALIZED))
-                        .context(ks_err!("LSKF is not setup for user {user_id}")),
-                }
+                self.encrypt_with_after_first_unlock_key(db, legacy_importer, user_id, key_blob)
             }
             SuperEncryptionType::UnlockedDeviceRequired => {
                 let symmetric_key = self
@@ -696,6 +1251,48 @@ impl SuperKeyManager {
                 Self::encrypt_with_aes_super_key(key_blob, &super_key)
                     .context(ks_err!("Failed to encrypt with BootLevel key."))
             }
+            SuperEncryptionType::BiometricBound => {
+                let biometric_bound =
+                    self.data.user_keys.get(&user_id).and_then(|e| e.biometric_bound.clone());
+                match biometric_bound {
+                    Some(super_key) => Self::encrypt_with_aes_super_key(key_blob, &super_key)
+                        .context(ks_err!("Failed to encrypt with biometric-bound super key.")),
+                    // Until the user has a biometric-bound super key and unlocked it with a
+                    // biometric, the key is bound to the LSKF like other auth-bound keys.
+                    None => self.encrypt_with_after_first_unlock_key(
+                        db,
+                        legacy_importer,
+                        user_id,
+                        key_blob,
+                    ),
+                }
+            }
+        }
+    }
+
+    // Encrypts the given key blob with the user's AfterFirstUnlock super key. If the user has not
+    // unlocked the device since boot or has no LSKF, an error is returned.
+    fn encrypt_with_after_first_unlock_key(
+        &self,
+        db: &mut KeystoreDB,
+        legacy_importer: &LegacyImporter,
+        user_id: UserId,
+        key_blob: &[u8],
+    ) -> Result<(Vec<u8>, BlobMetaData)> {
+        match self
+            .get_user_state(db, legacy_importer, user_id)
+            .context(ks_err!("Failed to get user state for user {user_id}"))?
+        {
+            UserState::AfterFirstUnlock(super_key) => {
+                Self::encrypt_with_aes_super_key(key_blob, &super_key).context(ks_err!(
+                    "Failed to encrypt with AfterFirstUnlock super key for user {user_id}"
+                ))
+            }
+            UserState::BeforeFirstUnlock => {
+                Err(Error::Rc(ResponseCode::LOCKED)).context(ks_err!("Device is locked."))
+            }
+            UserState::Uninitialized => Err(Error::Rc(ResponseCode::UNINITIALIZED))
+                .context(ks_err!("LSKF is not setup for user {user_id}")),
         }
     }
 
@@ -728,9 +1325,11 @@ impl SuperKeyManager {
         password: &Password,
         reencrypt_with: Option<Arc<SuperKey>>,
     ) -> Result<Arc<SuperKey>> {
-        let loaded_key = db.load_super_key(key_type, user_id)?;
+        let loaded_key =
+            UnlockTimings::measure(|t| &mut t.db_load, || db.load_super_key(key_type, user_id))?;
         if let Some((_, key_entry)) = loaded_key {
             Ok(Self::extract_super_key_from_key_entry(
+                db,
                 key_type.algorithm,
                 key_entry,
                 password,
@@ -768,12 +1367,12 @@ impl SuperKeyManager {
                     &key_metadata,
                 )
                 .context(ks_err!("Failed to store super key."))?;
-            Ok(Arc::new(SuperKey {
-                algorithm: key_type.algorithm,
-                key: super_key,
-                id: SuperKeyIdentifier::DatabaseId(key_entry.id()),
+            SuperKey::new(
+                key_type.algorithm,
+                super_key,
+                SuperKeyIdentifier::DatabaseId(key_entry.id()),
                 reencrypt_with,
-            }))
+            )
         }
     }
 
@@ -837,6 +1436,9 @@ impl SuperKeyManager {
         let entry = self.data.user_keys.entry(user_id).or_default();
         entry.unlocked_device_required_symmetric = Some(aes);
         entry.unlocked_device_required_private = Some(ecdh);
+        self.reinstate_evicted_key(user_id);
+        // Resume a re-wrap that a password change did not finish.
+        self.rewrap_stale_key_blobs(db, user_id);
         Ok(())
     }
 
@@ -853,6 +1455,9 @@ impl SuperKeyManager {
             unlocking_sids
         );
         let entry = self.data.user_keys.entry(user_id).or_default();
+        // Only the material of this user's keys goes, so that locking a work profile does not
+        // affect its parent user.
+        self.material_cache.evict_super_keys(&entry.unlocked_device_required_key_ids());
         if !unlocking_sids.is_empty() {
             if let (Some(aes), Some(ecdh)) = (
                 entry.unlocked_device_required_symmetric.as_ref().cloned(),
@@ -867,24 +1472,7 @@ impl SuperKeyManager {
                     let km_dev: KeyMintDevice =
                         KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
                             .context(ks_err!("KeyMintDevice::get failed"))?;
-                    let mut key_params = vec![
-                        KeyParameterValue::Algorithm(Algorithm::AES),
-                        KeyParameterValue::KeySize(256),
-                        KeyParameterValue::BlockMode(BlockMode::GCM),
-                        KeyParameterValue::PaddingMode(PaddingMode::NONE),
-                        KeyParameterValue::CallerNonce,
-                        KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT),
-                        KeyParameterValue::MinMacLength(128),
-                        KeyParameterValue::AuthTimeout(BIOMETRIC_AUTH_TIMEOUT_S),
-                        KeyParameterValue::HardwareAuthenticatorType(
-                            HardwareAuthenticatorType::FINGERPRINT,
-                        ),
-                    ];
-                    for sid in unlocking_sids {
-                        key_params.push(KeyParameterValue::UserSecureID(*sid));
-                    }
-                    let key_params: Vec<KmKeyParameter> =
-                        key_params.into_iter().map(|x| x.into()).collect();
+                    let key_params = Self::biometric_key_params(unlocking_sids);
                     km_dev.create_and_store_key(
                         db,
                         &key_desc,
@@ -919,6 +1507,240 @@ impl SuperKeyManager {
         }
         entry.unlocked_device_required_symmetric = None;
         entry.unlocked_device_required_private = None;
+        entry.biometric_bound = None;
+        self.data.biometric_bound_epoch += 1;
+        if !unlocking_sids.is_empty() && feature_flags::is_enabled(Feature::BiometricBoundSuperKeys)
+        {
+            if let Err(e) = Self::enroll_biometric_bound_key(db, user_id, unlocking_sids) {
+                log::error!("Error setting up the biometric-bound super key: {:?}", e);
+            }
+        }
+    }
+
+    /// Parameters of a KeyMint AES key that decrypts only after one of the biometrics with the
+    /// given SIDs authenticated the user.
+    fn biometric_key_params(sids: &[i64]) -> Vec<KmKeyParameter> {
+        let mut key_params = vec![
+            KeyParameterValue::Algorithm(Algorithm::AES),
+            KeyParameterValue::KeySize(256),
+            KeyParameterValue::BlockMode(BlockMode::GCM),
+            KeyParameterValue::PaddingMode(PaddingMode::NONE),
+            KeyParameterValue::CallerNonce,
+            KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT),
+            KeyParameterValue::MinMacLength(128),
+            KeyParameterValue::AuthTimeout(BIOMETRIC_AUTH_TIMEOUT_S),
+            KeyParameterValue::HardwareAuthenticatorType(HardwareAuthenticatorType::FINGERPRINT),
+        ];
+        for sid in sids {
+            key_params.push(KeyParameterValue::UserSecureID(*sid));
+        }
+        key_params.into_iter().map(|x| x.into()).collect()
+    }
+
+    /// Descriptor of the KeyMint key that encrypts the biometric-bound super key of the user.
+    fn biometric_bound_key_desc(user_id: UserId) -> KeyDescriptor {
+        KeyMintDevice::internal_descriptor(format!("biometric_bound_key_{}", user_id))
+    }
+
+    /// Returns the SIDs of the biometrics that can unlock the user's biometric-bound super key,
+    /// together with the KeyMint key that encrypts it, or None if the user has none.
+    fn load_biometric_bound_key(
+        db: &mut KeystoreDB,
+        user_id: UserId,
+    ) -> Result<Option<(Vec<i64>, KeyIdGuard, KeyEntry)>> {
+        let result = db.load_key_entry(
+            &Self::biometric_bound_key_desc(user_id),
+            KeyType::Client,
+            KeyEntryLoadBits::KM,
+            AID_KEYSTORE,
+            |_, _| Ok(()),
+        );
+        let (key_id_guard, key_entry) = match result {
+            Ok(loaded) => loaded,
+            Err(e) => match e.root_cause().downcast_ref::<Error>() {
+                Some(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => return Ok(None),
+                _ => return Err(e).context(ks_err!()),
+            },
+        };
+        let mut sids: Vec<i64> = key_entry
+            .key_parameters()
+            .iter()
+            .filter_map(|p| match p.key_parameter_value() {
+                KeyParameterValue::UserSecureID(sid) => Some(*sid),
+                _ => None,
+            })
+            .collect();
+        sids.sort_unstable();
+        Ok(Some((sids, key_id_guard, key_entry)))
+    }
+
+    /// Creates the biometric-bound super key of the user, unless the user has one that the
+    /// biometrics with the given SIDs can unlock. A super key for other SIDs is replaced. The keys
+    /// that it encrypts become unusable, like other keys that are bound to removed biometrics.
+    fn enroll_biometric_bound_key(
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        sids: &[i64],
+    ) -> Result<()> {
+        let mut sids = sids.to_vec();
+        sids.sort_unstable();
+        let enrolled_sids = Self::load_biometric_bound_key(db, user_id)
+            .context(ks_err!("Failed to load biometric-bound key."))?
+            .map(|(enrolled_sids, _, _)| enrolled_sids);
+        if enrolled_sids.as_ref() == Some(&sids)
+            && db
+                .load_super_key(&USER_BIOMETRIC_BOUND_SUPER_KEY, user_id)
+                .context(ks_err!("Failed to load biometric-bound super key."))?
+                .is_some()
+        {
+            return Ok(());
+        }
+
+        let encrypting_key = generate_aes256_key()?;
+        let km_dev: KeyMintDevice = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context(ks_err!("KeyMintDevice::get failed"))?;
+        let key_params = Self::biometric_key_params(&sids);
+        km_dev
+            .create_and_store_key(
+                db,
+                &Self::biometric_bound_key_desc(user_id),
+                KeyType::Client,
+                |dev| {
+                    let _wp =
+                        wd::watch_millis("In enroll_biometric_bound_key: calling importKey.", 500);
+                    dev.importKey(key_params.as_slice(), KeyFormat::RAW, &encrypting_key, None)
+                },
+            )
+            .context(ks_err!("Failed to create biometric-bound key."))?;
+
+        let super_key = generate_aes256_key()?;
+        let (mut encrypted_key, iv, mut tag) = aes_gcm_encrypt(&super_key, &encrypting_key)
+            .context(ks_err!("Failed to encrypt biometric-bound super key."))?;
+        encrypted_key.append(&mut tag);
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::Iv(iv));
+        Self::delete_super_key(db, user_id, &USER_BIOMETRIC_BOUND_SUPER_KEY)
+            .context(ks_err!("Failed to delete the previous biometric-bound super key."))?;
+        db.store_super_key(
+            user_id,
+            &USER_BIOMETRIC_BOUND_SUPER_KEY,
+            &encrypted_key,
+            &blob_metadata,
+            &KeyMetaData::new(),
+        )
+        .context(ks_err!("Failed to store biometric-bound super key."))?;
+        log::info!("Created biometric-bound super key for user {user_id}.");
+        Ok(())
+    }
+
+    /// Returns the users whose biometric-bound super key is not in memory, along with the epoch
+    /// that `add_biometric_bound_keys` expects. The list is empty if the biometric-bound tier was
+    /// not enabled when keystore started. The kill switch of the tier is not checked, so that the
+    /// keys that were encrypted with a biometric-bound super key stay usable while it is engaged.
+    pub fn users_without_biometric_bound_key(&self) -> (Vec<UserId>, u64) {
+        if !feature_flags::is_flag_set(Feature::BiometricBoundSuperKeys) {
+            return (Vec::new(), self.data.biometric_bound_epoch);
+        }
+        let users = self
+            .data
+            .user_keys
+            .iter()
+            .filter(|(_, keys)| keys.biometric_bound.is_none())
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        (users, self.data.biometric_bound_epoch)
+    }
+
+    /// Decrypts the biometric-bound super keys of the given users that the biometric of the auth
+    /// token can unlock. This is called when an auth token is added, so that keys that only a
+    /// biometric can authorize become usable after the biometric, even if the user unlocked the
+    /// device with the LSKF. It loads the keys from the database and calls KeyMint, so callers
+    /// must not hold the lock of the `SuperKeyManager`. Failures are logged.
+    pub fn decrypt_biometric_bound_keys(
+        db: &mut KeystoreDB,
+        users: &[UserId],
+        auth_token: &HardwareAuthToken,
+    ) -> Vec<(UserId, Arc<SuperKey>)> {
+        users
+            .iter()
+            .filter_map(|user_id| {
+                match Self::decrypt_biometric_bound_key(db, *user_id, auth_token) {
+                    Ok(super_key) => super_key.map(|super_key| (*user_id, super_key)),
+                    Err(e) => {
+                        log::warn!(
+                            "Failed to unlock biometric-bound super key of user {user_id}: {e:?}"
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Decrypts the biometric-bound super key of the user with the given auth token. Returns None
+    /// if the user has no biometric-bound super key, or if it is bound to other biometrics.
+    fn decrypt_biometric_bound_key(
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        auth_token: &HardwareAuthToken,
+    ) -> Result<Option<Arc<SuperKey>>> {
+        let (sids, key_id_guard, key_entry) = match Self::load_biometric_bound_key(db, user_id)? {
+            Some(loaded) => loaded,
+            None => return Ok(None),
+        };
+        if !sids.iter().any(|sid| *sid == auth_token.userId || *sid == auth_token.authenticatorId) {
+            return Ok(None);
+        }
+        let (_, super_key_entry) = match db
+            .load_super_key(&USER_BIOMETRIC_BOUND_SUPER_KEY, user_id)
+            .context(ks_err!("Failed to load biometric-bound super key."))?
+        {
+            Some(loaded) => loaded,
+            None => return Ok(None),
+        };
+        let (encrypted_key, metadata) = super_key_entry
+            .key_blob_info()
+            .as_ref()
+            .ok_or_else(Error::sys)
+            .context(ks_err!("Missing biometric-bound super key blob."))?;
+        let locked_key = LockedKey {
+            algorithm: USER_BIOMETRIC_BOUND_SUPER_KEY.algorithm,
+            id: SuperKeyIdentifier::DatabaseId(super_key_entry.id()),
+            nonce: metadata
+                .iv()
+                .ok_or_else(Error::sys)
+                .context(ks_err!("Missing biometric-bound super key nonce."))?
+                .clone(),
+            ciphertext: encrypted_key.clone(),
+        };
+        let km_dev: KeyMintDevice = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context(ks_err!("KeyMintDevice::get failed"))?;
+        locked_key.decrypt(db, &km_dev, &key_id_guard, &key_entry, auth_token, None).map(Some)
+    }
+
+    /// Puts the super keys returned by `decrypt_biometric_bound_keys` in memory. `epoch` is the
+    /// one returned by `users_without_biometric_bound_key` before the keys were decrypted. If
+    /// biometric-bound super keys were cleared since then, e.g., because a user locked the
+    /// device, the keys are dropped, and so are the keys of users that have one in memory.
+    pub fn add_biometric_bound_keys(&mut self, keys: Vec<(UserId, Arc<SuperKey>)>, epoch: u64) {
+        if epoch != self.data.biometric_bound_epoch {
+            log::info!("Biometric-bound super keys were cleared while they were decrypted.");
+            return;
+        }
+        for (user_id, super_key) in keys {
+            match self.data.user_keys.get(&user_id) {
+                Some(entry) if entry.biometric_bound.is_none() => {}
+                _ => continue,
+            }
+            if let Err(e) = self.data.add_key_to_key_index(&super_key) {
+                log::error!("Failed to add biometric-bound super key of user {user_id}: {e:?}");
+                continue;
+            }
+            if let Some(entry) = self.data.user_keys.get_mut(&user_id) {
+                entry.biometric_bound = Some(super_key);
+            }
+            log::info!("Unlocked biometric-bound super key of user {user_id}.");
+        }
     }
 
     /// User has unlocked, not using a password. See if any of our stored auth tokens can be used
@@ -1027,27 +1849,33 @@ impl SuperKeyManager {
         user_id: UserId,
     ) -> Result<()> {
         log::info!("remove_user(user={user_id})");
+        let secret_handles = Self::secret_handles(db, user_id)?;
         // Mark keys created on behalf of the user as unreferenced.
         legacy_importer
             .bulk_delete_user(user_id, false)
             .context(ks_err!("Trying to delete legacy keys."))?;
-        db.unbind_keys_for_user(user_id, false).context(ks_err!("Error in unbinding keys."))?;
+        db.unbind_keys_for_user(user_id, UserKeyFilter::APP_AND_SUPER_KEYS)
+            .context(ks_err!("Error in unbinding keys."))?;
+        super_key_secret::release(&secret_handles);
 
         // Delete super key in cache, if exists.
         self.forget_all_keys_for_user(user_id);
         Ok(())
     }
 
-    /// Deletes all authentication bound keys and super keys for the given user.  The user must be
-    /// unlocked before this function is called.  This function is used to transition a user to
-    /// swipe.
+    /// Deletes the keys of the given user that pass `filter`. The user must be unlocked before
+    /// this function is called. With `UserKeyFilter::APP_AND_SUPER_KEYS.super_encrypted_only()`,
+    /// i.e., all authentication bound keys and super keys, this function is used to transition a
+    /// user to swipe. The user stays unlocked if its super keys are not deleted. Client keys
+    /// that are encrypted with deleted super keys can no longer be used.
     pub fn reset_user(
         &mut self,
         db: &mut KeystoreDB,
         legacy_importer: &LegacyImporter,
         user_id: UserId,
+        filter: UserKeyFilter,
     ) -> Result<()> {
-        log::info!("reset_user(user={user_id})");
+        log::info!("reset_user(user={user_id}, filter={filter:?})");
         match self.get_user_state(db, legacy_importer, user_id)? {
             UserState::Uninitialized => {
                 Err(Error::sys()).context(ks_err!("Tried to reset an uninitialized user!"))
@@ -1056,12 +1884,25 @@ impl SuperKeyManager {
                 Err(Error::sys()).context(ks_err!("Tried to reset a locked user's password!"))
             }
             UserState::AfterFirstUnlock(_) => {
-                // Mark keys created on behalf of the user as unreferenced.
-                legacy_importer
-                    .bulk_delete_user(user_id, true)
-                    .context(ks_err!("Trying to delete legacy keys."))?;
-                db.unbind_keys_for_user(user_id, true)
+                let secret_handles = Self::secret_handles(db, user_id)?;
+                // Mark keys created on behalf of the user as unreferenced. Legacy keys are all
+                // client keys of apps.
+                if filter.includes(KeyType::Client, Domain::APP) {
+                    legacy_importer
+                        .bulk_delete_user(user_id, filter.keep_non_super_encrypted_keys)
+                        .context(ks_err!("Trying to delete legacy keys."))?;
+                }
+                db.unbind_keys_for_user(user_id, filter)
                     .context(ks_err!("Error in unbinding keys."))?;
+                if !filter.includes(KeyType::Super, Domain::APP) {
+                    return Ok(());
+                }
+                super_key_secret::release(&secret_handles);
+                // The escrow entries are not password encrypted and may survive the above.
+                Self::delete_super_key(db, user_id, &USER_SUPER_KEY_ESCROW)
+                    .context(ks_err!("Error in deleting super key escrow."))?;
+                Self::delete_reboot_escrow(db, user_id)
+                    .context(ks_err!("Error in deleting reboot escrow."))?;
 
                 // Delete super key in cache, if exists.
                 self.forget_all_keys_for_user(user_id);
@@ -1106,6 +1947,7 @@ impl SuperKeyManager {
                     .context(ks_err!("Failed to store super key."))?;
 
                 self.populate_cache_from_super_key_blob(
+                    db,
                     user_id,
                     USER_AFTER_FIRST_UNLOCK_SUPER_KEY.algorithm,
                     key_entry,
@@ -1117,15 +1959,184 @@ impl SuperKeyManager {
         }
     }
 
-    /// Unlocks the given user with the given password.
-    ///
-    /// If the user state is BeforeFirstUnlock:
-    /// - Unlock the user's AfterFirstUnlock super key
-    /// - Unlock the user's UnlockedDeviceRequired super keys
+    /// Re-encrypts the super keys of the given user with `password`, after the user changed
+    /// their LSKF. The super keys themselves do not change, so keys that are encrypted with them
+    /// stay usable throughout, and the copies in memory remain in place. All super keys are
+    /// rewrapped in one database transaction, so that they never end up encrypted with different
+    /// passwords. The user must be unlocked, including the UnlockedDeviceRequired super keys if
+    /// the user has them. Afterwards, key blobs that are still encrypted with the private
+    /// UnlockedDeviceRequired super key are re-wrapped, see `try_rewrap_stale_key_blobs`.
+    pub fn change_user_password(
+        &mut self,
+        db: &mut KeystoreDB,
+        legacy_importer: &LegacyImporter,
+        user_id: UserId,
+        password: &Password,
+    ) -> Result<()> {
+        log::info!("change_user_password(user={user_id})");
+        let after_first_unlock = match self.get_user_state(db, legacy_importer, user_id)? {
+            UserState::AfterFirstUnlock(super_key) => super_key,
+            UserState::BeforeFirstUnlock => {
+                return Err(Error::Rc(ResponseCode::LOCKED))
+                    .context(ks_err!("Tried to change a locked user's password!"));
+            }
+            UserState::Uninitialized => {
+                return Err(Error::sys())
+                    .context(ks_err!("Tried to change an uninitialized user's password!"));
+            }
+        };
+
+        let (symmetric, private) = self
+            .data
+            .user_keys
+            .get(&user_id)
+            .map(|e| {
+                (
+                    e.unlocked_device_required_symmetric.clone(),
+                    e.unlocked_device_required_private.clone(),
+                )
+            })
+            .unwrap_or((None, None));
+
+        let mut super_keys = vec![after_first_unlock];
+        for (key_type, super_key) in [
+            (&USER_UNLOCKED_DEVICE_REQUIRED_SYMMETRIC_SUPER_KEY, symmetric),
+            (&USER_UNLOCKED_DEVICE_REQUIRED_P521_SUPER_KEY, private),
+        ] {
+            match super_key {
+                Some(super_key) => super_keys.push(super_key),
+                None => {
+                    // A super key that exists but is locked cannot be rewrapped, and leaving it
+                    // encrypted with the old password would lose it.
+                    if db.load_super_key(key_type, user_id).context(ks_err!())?.is_some() {
+                        return Err(Error::Rc(ResponseCode::LOCKED)).context(ks_err!(
+                            "UnlockedDeviceRequired super key {} is locked.",
+                            key_type.alias
+                        ));
+                    }
+                }
+            }
+        }
+
+        let old_secret_handles = Self::secret_handles(db, user_id)?;
+        let mut blobs = vec![];
+        for super_key in super_keys {
+            let key_id = match super_key.id {
+                SuperKeyIdentifier::DatabaseId(id) => id,
+                SuperKeyIdentifier::BootLevel(_) => {
+                    return Err(Error::sys()).context(ks_err!("Unexpected boot level key."));
+                }
+            };
+            let (blob, blob_metadata) = Self::encrypt_with_password(&super_key.key, password)
+                .context(ks_err!("Failed to encrypt super key with password!"))?;
+            blobs.push((key_id, blob, blob_metadata));
+        }
+        if let Err(e) = db.rewrap_super_keys(user_id, &blobs) {
+            let new_secret_handles: Vec<Vec<u8>> =
+                blobs.iter().filter_map(|(_, _, m)| m.secret_handle().cloned()).collect();
+            super_key_secret::release(&new_secret_handles);
+            return Err(e).context(ks_err!("Failed to store super keys."));
+        }
+        super_key_secret::release(&old_secret_handles);
+        self.rewrap_stale_key_blobs(db, user_id);
+        Ok(())
+    }
+
+    /// Re-wraps the key blobs of the given user that are still encrypted with the private
+    /// UnlockedDeviceRequired super key, because they were created while the device was locked,
+    /// with the symmetric super key. Each batch of blobs is re-wrapped in its own database
+    /// transaction, and only stale blobs are selected, so an interrupted walk picks up where it
+    /// stopped the next time. Blobs that cannot be decrypted are left alone. Returns the number
+    /// of re-wrapped blobs.
+    fn try_rewrap_stale_key_blobs(&self, db: &mut KeystoreDB, user_id: UserId) -> Result<usize> {
+        let private = match self
+            .data
+            .user_keys
+            .get(&user_id)
+            .and_then(|e| e.unlocked_device_required_private.clone())
+        {
+            Some(private) => private,
+            None => return Ok(0),
+        };
+        let (private_id, symmetric) = match (private.id, private.reencrypt_with.clone()) {
+            (SuperKeyIdentifier::DatabaseId(id), Some(symmetric)) => (id, symmetric),
+            _ => return Ok(0),
+        };
+        let mut after_blob_id = 0;
+        let mut rewrapped = 0;
+        loop {
+            let blobs = db
+                .load_key_blobs_encrypted_by(private_id, after_blob_id, REWRAP_BATCH_SIZE)
+                .context(ks_err!("Failed to load stale key blobs."))?;
+            after_blob_id = match blobs.last() {
+                Some((_, blob_id, _, _)) => *blob_id,
+                None => break,
+            };
+            let mut new_blobs = vec![];
+            for (key_id, blob_id, blob, metadata) in blobs {
+                let key = match Self::unwrap_key_with_key(&blob, &metadata, &private) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        log::error!("Cannot re-wrap blob of key {key_id}: {e:?}");
+                        continue;
+                    }
+                };
+                let (new_blob, mut new_metadata) =
+                    Self::encrypt_with_aes_super_key(&key, &symmetric).context(ks_err!())?;
+                if let Some(uuid) = metadata.km_uuid() {
+                    new_metadata.add(BlobMetaEntry::KmUuid(*uuid));
+                }
+                new_blobs.push((key_id, blob_id, new_blob, new_metadata));
+            }
+            rewrapped += db
+                .rewrap_key_blobs(&new_blobs)
+                .context(ks_err!("Failed to store re-wrapped key blobs."))?;
+        }
+        Ok(rewrapped)
+    }
+
+    /// Like `try_rewrap_stale_key_blobs`, but only logs the outcome. Blobs that are not re-wrapped
+    /// stay usable, and are re-wrapped on their next use or by the next walk.
+    fn rewrap_stale_key_blobs(&self, db: &mut KeystoreDB, user_id: UserId) {
+        match self.try_rewrap_stale_key_blobs(db, user_id) {
+            Ok(0) => {}
+            Ok(n) => log::info!("Re-wrapped {n} stale key blobs of user {user_id}."),
+            Err(e) => log::error!("Failed to re-wrap stale key blobs of user {user_id}: {e:?}"),
+        }
+    }
+
+    /// Returns the handles of the secrets that the password encrypted super keys of the given
+    /// user are bound to.
+    fn secret_handles(db: &mut KeystoreDB, user_id: UserId) -> Result<Vec<Vec<u8>>> {
+        let mut handles = vec![];
+        for key_type in [
+            &USER_AFTER_FIRST_UNLOCK_SUPER_KEY,
+            &USER_UNLOCKED_DEVICE_REQUIRED_SYMMETRIC_SUPER_KEY,
+            &USER_UNLOCKED_DEVICE_REQUIRED_P521_SUPER_KEY,
+        ] {
+            if let Some((_, entry)) = db
+                .load_super_key(key_type, user_id)
+                .context(ks_err!("Failed to load super key {}.", key_type.alias))?
+            {
+                if let Some((_, metadata)) = entry.key_blob_info() {
+                    handles.extend(metadata.secret_handle().cloned());
+                }
+            }
+        }
+        Ok(handles)
+    }
+
+    /// Unlocks the given user with the given password.
+    ///
+    /// If the user state is BeforeFirstUnlock:
+    /// - Unlock the user's AfterFirstUnlock super key
+    /// - Unlock the user's UnlockedDeviceRequired super keys
     ///
     /// If the user state is AfterFirstUnlock:
     /// - Unlock the user's UnlockedDeviceRequired super keys only
     ///
+    /// The outcome of the unlock and the time spent in key derivation and database loads are
+    /// reported to the metrics store.
     pub fn unlock_user(
         &mut self,
         db: &mut KeystoreDB,
@@ -1134,6 +2145,46 @@ impl SuperKeyManager {
         password: &Password,
     ) -> Result<()> {
         log::info!("unlock_user(user={user_id})");
+        UnlockTimings::take();
+        let result = self.unlock_user_internal(db, legacy_importer, user_id, password);
+        let timings = UnlockTimings::take();
+        log_user_unlock_stats(&result, timings.kdf, timings.db_load);
+        result
+    }
+
+    /// Unlocks the given user with a synthetic password token from LockSettings instead of a
+    /// raw password. The secret of the token is the input of the key derivation, so this unlocks
+    /// the same super keys as `unlock_user` with the password that LockSettings used to pass.
+    /// A weak token was unwrapped with an escrow token rather than the user's LSKF, so it can
+    /// only unlock a user that was unlocked since boot. Before the first unlock it is refused
+    /// with `LOCKED`.
+    pub fn unlock_user_with_token(
+        &mut self,
+        db: &mut KeystoreDB,
+        legacy_importer: &LegacyImporter,
+        user_id: UserId,
+        token: &SyntheticPasswordToken,
+    ) -> Result<()> {
+        log::info!("unlock_user_with_token(user={user_id}, type={:?})", token.token_type());
+        if token.token_type() == TokenType::Weak
+            && !matches!(
+                self.get_user_state(db, legacy_importer, user_id)?,
+                UserState::AfterFirstUnlock(_)
+            )
+        {
+            return Err(Error::Rc(ResponseCode::LOCKED))
+                .context(ks_err!("A weak token cannot unlock user {user_id} for the first time."));
+        }
+        self.unlock_user(db, legacy_importer, user_id, &token.password())
+    }
+
+    fn unlock_user_internal(
+        &mut self,
+        db: &mut KeystoreDB,
+        legacy_importer: &LegacyImporter,
+        user_id: UserId,
+        password: &Password,
+    ) -> Result<()> {
         match self.get_user_state(db, legacy_importer, user_id)? {
             UserState::AfterFirstUnlock(_) => {
                 self.unlock_unlocked_device_required_keys(db, user_id, password)
@@ -1145,20 +2196,38 @@ impl SuperKeyManager {
                 let alias = &USER_AFTER_FIRST_UNLOCK_SUPER_KEY;
                 let result = legacy_importer
                     .with_try_import_super_key(user_id, password, || {
-                        db.load_super_key(alias, user_id)
+                        UnlockTimings::measure(
+                            |t| &mut t.db_load,
+                            || db.load_super_key(alias, user_id),
+                        )
                     })
                     .context(ks_err!("Failed to load super key"))?;
 
                 match result {
                     Some((_, entry)) => {
+                        let is_bound = entry
+                            .key_blob_info()
+                            .as_ref()
+                            .map_or(false, |(_, m)| m.secret_handle().is_some());
                         self.populate_cache_from_super_key_blob(
+                            db,
                             user_id,
                             alias.algorithm,
                             entry,
                             password,
                         )
                         .context(ks_err!("Failed when unlocking user."))?;
-                        self.unlock_unlocked_device_required_keys(db, user_id, password)
+                        self.unlock_unlocked_device_required_keys(db, user_id, password)?;
+                        // Bind the super keys of users from before the secret store became
+                        // available. This is best effort, the user stays unlocked either way.
+                        if !is_bound && super_key_secret::is_available() {
+                            if let Err(e) =
+                                self.change_user_password(db, legacy_importer, user_id, password)
+                            {
+                                log::error!("Failed to bind super keys to secrets: {:?}", e);
+                            }
+                        }
+                        Ok(())
                     }
                     None => {
                         Err(Error::sys()).context(ks_err!("Locked user does not have a super key!"))
@@ -1167,6 +2236,284 @@ impl SuperKeyManager {
             }
         }
     }
+
+    /// Deletes the super key of the given type of the given user, if it exists.
+    fn delete_super_key(
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        key_type: &SuperKeyType,
+    ) -> Result<()> {
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: user_id as i64,
+            alias: Some(key_type.alias.into()),
+            blob: None,
+        };
+        match db.unbind_key(&key, KeyType::Super, user_id, |_, _| Ok(())) {
+            Err(e) => match e.root_cause().downcast_ref::<Error>() {
+                Some(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => Ok(()),
+                _ => Err(e).context(ks_err!()),
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Wraps the user's AfterFirstUnlock super key to the given escrow public key, replacing any
+    /// previously provisioned escrow. The user must have unlocked the device since boot, and
+    /// must consent to the escrow by presenting their current password, which must decrypt the
+    /// stored super key.
+    pub fn provision_super_key_escrow(
+        &mut self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        escrow_public_key: &[u8],
+        password: &Password,
+    ) -> Result<()> {
+        log::info!("provision_super_key_escrow(user={user_id})");
+        let super_key = self
+            .get_after_first_unlock_key_by_user_id_internal(user_id)
+            .ok_or(Error::Rc(ResponseCode::LOCKED))
+            .context(ks_err!("User has not unlocked the device since boot."))?;
+        let (_, entry) = db
+            .load_super_key(&USER_AFTER_FIRST_UNLOCK_SUPER_KEY, user_id)
+            .context(ks_err!("Failed to load super key."))?
+            .ok_or_else(Error::sys)
+            .context(ks_err!("Unlocked user does not have a super key!"))?;
+        let consented = Self::extract_super_key_from_key_entry(
+            db,
+            USER_AFTER_FIRST_UNLOCK_SUPER_KEY.algorithm,
+            entry,
+            password,
+            None,
+        )
+        .map_or(false, |key| key.key[..] == super_key.key[..]);
+        if !consented {
+            return Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                .context(ks_err!("The password does not decrypt the super key of the user."));
+        }
+        let (wrapped_key, blob_metadata, key_metadata) =
+            super_key_escrow::wrap(&super_key.key, escrow_public_key)
+                .context(ks_err!("Failed to wrap super key."))?;
+        Self::delete_super_key(db, user_id, &USER_SUPER_KEY_ESCROW)
+            .context(ks_err!("Failed to delete previous escrow."))?;
+        db.store_super_key(
+            user_id,
+            &USER_SUPER_KEY_ESCROW,
+            &wrapped_key,
+            &blob_metadata,
+            &key_metadata,
+        )
+        .context(ks_err!("Failed to store super key escrow."))?;
+        Ok(())
+    }
+
+    /// Deletes the user's super key escrow, if any.
+    pub fn clear_super_key_escrow(&mut self, db: &mut KeystoreDB, user_id: UserId) -> Result<()> {
+        log::info!("clear_super_key_escrow(user={user_id})");
+        Self::delete_super_key(db, user_id, &USER_SUPER_KEY_ESCROW).context(ks_err!())
+    }
+
+    /// Recovers the user's AfterFirstUnlock super key from its escrow with the escrow private key
+    /// and re-encrypts it with `new_password`. The super key is installed in memory, so the user
+    /// is in the AfterFirstUnlock state afterwards.
+    ///
+    /// The UnlockedDeviceRequired super keys are encrypted with the forgotten password and are
+    /// excluded from the escrow. They are deleted, together with the keys that they encrypt, and
+    /// recreated on the next unlock with the new password. So keys that use
+    /// UnlockedDeviceRequired do not survive the recovery.
+    pub fn recover_user_from_escrow(
+        &mut self,
+        db: &mut KeystoreDB,
+        legacy_importer: &LegacyImporter,
+        user_id: UserId,
+        escrow_private_key: &[u8],
+        new_password: &Password,
+    ) -> Result<()> {
+        log::info!("recover_user_from_escrow(user={user_id})");
+        if let UserState::Uninitialized = self.get_user_state(db, legacy_importer, user_id)? {
+            return Err(Error::sys()).context(ks_err!("Tried to recover an uninitialized user!"));
+        }
+
+        let (_, escrow_entry) = db
+            .load_super_key(&USER_SUPER_KEY_ESCROW, user_id)
+            .context(ks_err!("Failed to load super key escrow."))?
+            .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context(ks_err!("User has no super key escrow."))?;
+        let (wrapped_key, blob_metadata) = escrow_entry
+            .key_blob_info()
+            .as_ref()
+            .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            .context(ks_err!("Escrow entry has no blob."))?;
+        let key = super_key_escrow::unwrap(wrapped_key, blob_metadata, escrow_private_key)
+            .context(ks_err!("Failed to unwrap super key."))?;
+
+        let secret_handles = Self::secret_handles(db, user_id)?;
+        let (key_id_guard, entry) = db
+            .load_super_key(&USER_AFTER_FIRST_UNLOCK_SUPER_KEY, user_id)
+            .context(ks_err!("Failed to load super key."))?
+            .ok_or_else(Error::sys)
+            .context(ks_err!("Escrowed user does not have a super key!"))?;
+        let (encrypted_super_key, blob_metadata) =
+            Self::encrypt_with_password(&key, new_password).context(ks_err!())?;
+        db.set_blob(
+            &key_id_guard,
+            SubComponentType::KEY_BLOB,
+            Some(&encrypted_super_key),
+            Some(&blob_metadata),
+        )
+        .context(ks_err!("Failed to store re-encrypted super key."))?;
+
+        for key_type in [
+            &USER_UNLOCKED_DEVICE_REQUIRED_SYMMETRIC_SUPER_KEY,
+            &USER_UNLOCKED_DEVICE_REQUIRED_P521_SUPER_KEY,
+        ] {
+            // The keys encrypted with the super key could never be decrypted again.
+            let super_key_id = db
+                .load_super_key(key_type, user_id)
+                .context(ks_err!("Failed to load UnlockedDeviceRequired super key."))?
+                .map(|(_, entry)| entry.id());
+            if let Some(super_key_id) = super_key_id {
+                let deleted = db
+                    .unbind_keys_encrypted_by(super_key_id)
+                    .context(ks_err!("Failed to delete UnlockedDeviceRequired keys."))?;
+                log::info!("Deleted {deleted} keys encrypted with {}.", key_type.alias);
+            }
+            Self::delete_super_key(db, user_id, key_type)
+                .context(ks_err!("Failed to delete UnlockedDeviceRequired super key."))?;
+        }
+        super_key_secret::release(&secret_handles);
+
+        self.forget_all_keys_for_user(user_id);
+        self.install_after_first_unlock_key_for_user(
+            user_id,
+            SuperKey::new(
+                USER_AFTER_FIRST_UNLOCK_SUPER_KEY.algorithm,
+                key,
+                SuperKeyIdentifier::DatabaseId(entry.id()),
+                None,
+            )?,
+        )
+        .context(ks_err!("Failed to install AfterFirstUnlock super key for user!"))
+    }
+
+    /// Stores a copy of the user's AfterFirstUnlock super key encrypted with the
+    /// resume-on-reboot secret and sealed by `sealer`, replacing any previous copy. The user must
+    /// have unlocked the device since boot. The copy expires after `reboot_escrow::LIFETIME`.
+    pub fn arm_reboot_escrow(
+        &mut self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        escrow_secret: &[u8],
+        sealer: &dyn reboot_escrow::Sealer,
+    ) -> Result<()> {
+        log::info!("arm_reboot_escrow(user={user_id})");
+        let super_key = self
+            .get_after_first_unlock_key_by_user_id_internal(user_id)
+            .ok_or(Error::Rc(ResponseCode::LOCKED))
+            .context(ks_err!("User has not unlocked the device since boot."))?;
+        let (wrapped_key, blob_metadata) = reboot_escrow::wrap(&super_key.key, escrow_secret)
+            .context(ks_err!("Failed to wrap super key."))?;
+        Self::delete_reboot_escrow(db, user_id)
+            .context(ks_err!("Failed to delete previous reboot escrow."))?;
+        let armed = DateTime::now().context(ks_err!("Failed to get the current time."))?;
+        let expires = DateTime::from_millis_epoch(
+            armed.to_millis_epoch() + reboot_escrow::LIFETIME.as_millis() as i64,
+        );
+        let sealed_key = sealer
+            .seal(db, user_id, &wrapped_key, expires)
+            .context(ks_err!("Failed to seal super key."))?;
+        let mut key_metadata = KeyMetaData::new();
+        key_metadata.add(KeyMetaEntry::CreationDate(armed));
+        db.store_super_key(
+            user_id,
+            &USER_SUPER_KEY_REBOOT_ESCROW,
+            &sealed_key,
+            &blob_metadata,
+            &key_metadata,
+        )
+        .context(ks_err!("Failed to store reboot escrow."))?;
+        Ok(())
+    }
+
+    /// Deletes the user's resume-on-reboot copy of the super key, if any.
+    pub fn clear_reboot_escrow(&mut self, db: &mut KeystoreDB, user_id: UserId) -> Result<()> {
+        log::info!("clear_reboot_escrow(user={user_id})");
+        Self::delete_reboot_escrow(db, user_id).context(ks_err!())
+    }
+
+    /// Deletes the resume-on-reboot copy and the key that seals it.
+    fn delete_reboot_escrow(db: &mut KeystoreDB, user_id: UserId) -> Result<()> {
+        Self::delete_super_key(db, user_id, &USER_SUPER_KEY_REBOOT_ESCROW)
+            .context(ks_err!("Failed to delete reboot escrow."))?;
+        reboot_escrow::destroy_sealing_key(db, user_id)
+            .context(ks_err!("Failed to delete reboot escrow sealing key."))
+    }
+
+    /// Unlocks the user's AfterFirstUnlock super key with the resume-on-reboot secret. The copy
+    /// is deleted before the key is installed, so it unlocks the user once. If the user is
+    /// already unlocked, the copy is only deleted.
+    ///
+    /// The UnlockedDeviceRequired super keys are not escrowed, so keys that use
+    /// UnlockedDeviceRequired stay unusable until the user unlocks the device with the LSKF.
+    pub fn unlock_user_with_reboot_escrow(
+        &mut self,
+        db: &mut KeystoreDB,
+        legacy_importer: &LegacyImporter,
+        user_id: UserId,
+        escrow_secret: &[u8],
+        sealer: &dyn reboot_escrow::Sealer,
+    ) -> Result<()> {
+        log::info!("unlock_user_with_reboot_escrow(user={user_id})");
+        match self.get_user_state(db, legacy_importer, user_id)? {
+            UserState::Uninitialized => {
+                Err(Error::sys()).context(ks_err!("Tried to unlock an uninitialized user!"))
+            }
+            UserState::AfterFirstUnlock(_) => {
+                Self::delete_reboot_escrow(db, user_id).context(ks_err!())
+            }
+            UserState::BeforeFirstUnlock => {
+                let (_, escrow_entry) = db
+                    .load_super_key(&USER_SUPER_KEY_REBOOT_ESCROW, user_id)
+                    .context(ks_err!("Failed to load reboot escrow."))?
+                    .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                    .context(ks_err!("User has no reboot escrow."))?;
+                let armed = escrow_entry.metadata().creation_date().copied();
+                let now = DateTime::now().context(ks_err!("Failed to get the current time."))?;
+                if armed.map_or(true, |armed| reboot_escrow::is_expired(armed, now)) {
+                    Self::delete_reboot_escrow(db, user_id).context(ks_err!())?;
+                    return Err(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                        .context(ks_err!("Reboot escrow has expired."));
+                }
+                let (sealed_key, blob_metadata) = escrow_entry
+                    .key_blob_info()
+                    .as_ref()
+                    .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                    .context(ks_err!("Reboot escrow entry has no blob."))?;
+                let wrapped_key = sealer
+                    .unseal(db, user_id, sealed_key)
+                    .context(ks_err!("Failed to unseal super key."))?;
+                let key = reboot_escrow::unwrap(&wrapped_key, blob_metadata, escrow_secret)
+                    .context(ks_err!("Failed to unwrap super key."))?;
+                let (_, entry) = db
+                    .load_super_key(&USER_AFTER_FIRST_UNLOCK_SUPER_KEY, user_id)
+                    .context(ks_err!("Failed to load super key."))?
+                    .ok_or_else(Error::sys)
+                    .context(ks_err!("Locked user does not have a super key!"))?;
+                // Do not unlock the user if the copy could be used again.
+                Self::delete_reboot_escrow(db, user_id).context(ks_err!())?;
+                self.install_after_first_unlock_key_for_user(
+                    user_id,
+                    SuperKey::new(
+                        USER_AFTER_FIRST_UNLOCK_SUPER_KEY.algorithm,
+                        key,
+                        SuperKeyIdentifier::DatabaseId(entry.id()),
+                        None,
+                    )?,
+                )
+                .context(ks_err!("Failed to install AfterFirstUnlock super key for user!"))
+            }
+        }
+    }
 }
 
 /// This enum represents different states of the user's life cycle in the device.
@@ -1234,10 +2581,15 @@ mod tests {
     use crate::database::tests::make_bootlevel_key_entry;
     use crate::database::tests::make_test_key_entry;
     use crate::database::tests::new_test_db;
+    use crate::metrics_store::METRICS_STORE;
+    use android_security_metrics::aidl::android::security::metrics::{
+        AtomID::AtomID, KeystoreAtomPayload::KeystoreAtomPayload, UnlockOutcome::UnlockOutcome,
+    };
     use rand::prelude::*;
     const USER_ID: u32 = 0;
     const TEST_KEY_ALIAS: &str = "TEST_KEY";
     const TEST_BOOT_KEY_ALIAS: &str = "TEST_BOOT_KEY";
+    const SWIPE_FILTER: UserKeyFilter = UserKeyFilter::APP_AND_SUPER_KEYS.super_encrypted_only();
 
     pub fn generate_password_blob() -> Password<'static> {
         let mut rng = rand::thread_rng();
@@ -1250,11 +2602,13 @@ mod tests {
         Password::Owned(zvec)
     }
 
-    fn setup_test(pw: &Password) -> (Arc<RwLock<SuperKeyManager>>, KeystoreDB, LegacyImporter) {
+    fn setup_test(
+        pw: &Password,
+    ) -> (Arc<ProfiledRwLock<SuperKeyManager>>, KeystoreDB, LegacyImporter) {
         let mut keystore_db = new_test_db().unwrap();
         let mut legacy_importer = LegacyImporter::new(Arc::new(Default::default()));
         legacy_importer.set_empty();
-        let skm: Arc<RwLock<SuperKeyManager>> = Default::default();
+        let skm: Arc<ProfiledRwLock<SuperKeyManager>> = Default::default();
         assert!(skm
             .write()
             .unwrap()
@@ -1264,7 +2618,7 @@ mod tests {
     }
 
     fn assert_unlocked(
-        skm: &Arc<RwLock<SuperKeyManager>>,
+        skm: &Arc<ProfiledRwLock<SuperKeyManager>>,
         keystore_db: &mut KeystoreDB,
         legacy_importer: &LegacyImporter,
         user_id: u32,
@@ -1279,7 +2633,7 @@ mod tests {
     }
 
     fn assert_locked(
-        skm: &Arc<RwLock<SuperKeyManager>>,
+        skm: &Arc<ProfiledRwLock<SuperKeyManager>>,
         keystore_db: &mut KeystoreDB,
         legacy_importer: &LegacyImporter,
         user_id: u32,
@@ -1294,7 +2648,7 @@ mod tests {
     }
 
     fn assert_uninitialized(
-        skm: &Arc<RwLock<SuperKeyManager>>,
+        skm: &Arc<ProfiledRwLock<SuperKeyManager>>,
         keystore_db: &mut KeystoreDB,
         legacy_importer: &LegacyImporter,
         user_id: u32,
@@ -1308,6 +2662,90 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_password_kdfs() -> Result<()> {
+        let pw = generate_password_blob();
+        let super_key = generate_aes256_key()?;
+        let cheap = Argon2idParams { iterations: 1, memory_kib: 64, parallelism: 1 };
+        for argon2id in [None, Some(cheap)] {
+            let (blob, metadata) =
+                SuperKeyManager::encrypt_with_password_using(&super_key, &pw, argon2id)?;
+            assert_eq!(argon2id, SuperKeyManager::argon2id_params(&metadata)?);
+            let key =
+                SuperKeyManager::derive_key_from_password(&pw, metadata.salt().unwrap(), argon2id)?;
+            let decrypted =
+                aes_gcm_decrypt(&blob, metadata.iv().unwrap(), metadata.aead_tag().unwrap(), &key)?;
+            assert_eq!(super_key[..], decrypted[..]);
+        }
+
+        let mut metadata = BlobMetaData::new();
+        metadata.add(BlobMetaEntry::Argon2idIterations(1));
+        assert!(SuperKeyManager::argon2id_params(&metadata).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_kdf_is_outdated() {
+        let cheap = Argon2idParams { iterations: 1, memory_kib: 64, parallelism: 1 };
+        let current = Some(Argon2idParams::DEFAULT);
+        assert!(SuperKeyManager::kdf_is_outdated(None, current));
+        assert!(SuperKeyManager::kdf_is_outdated(Some(cheap), current));
+        assert!(!SuperKeyManager::kdf_is_outdated(current, current));
+        assert!(!SuperKeyManager::kdf_is_outdated(None, None));
+        assert!(!SuperKeyManager::kdf_is_outdated(Some(cheap), None));
+    }
+
+    #[test]
+    fn test_upgrade_kdf() -> Result<()> {
+        let mut db = new_test_db()?;
+        let pw = generate_password_blob();
+        let super_key = generate_aes256_key()?;
+        let cheap = Argon2idParams { iterations: 1, memory_kib: 64, parallelism: 1 };
+        let (blob, metadata) = SuperKeyManager::encrypt_with_password_using(&super_key, &pw, None)?;
+        let entry = db.store_super_key(
+            1,
+            &USER_AFTER_FIRST_UNLOCK_SUPER_KEY,
+            &blob,
+            &metadata,
+            &KeyMetaData::new(),
+        )?;
+
+        SuperKeyManager::upgrade_kdf_if_outdated(
+            &mut db,
+            entry.id(),
+            (&blob, &metadata),
+            &super_key,
+            &pw,
+            Some(cheap),
+        );
+        let (_, entry) = db.load_super_key(&USER_AFTER_FIRST_UNLOCK_SUPER_KEY, 1)?.unwrap();
+        let (new_blob, new_metadata) = entry.key_blob_info().as_ref().unwrap();
+        assert_eq!(Some(cheap), SuperKeyManager::argon2id_params(new_metadata)?);
+        let new_blob = new_blob.clone();
+
+        // A blob that is no longer current is not replaced.
+        SuperKeyManager::upgrade_kdf_if_outdated(
+            &mut db,
+            entry.id(),
+            (&blob, &metadata),
+            &super_key,
+            &pw,
+            Some(Argon2idParams::DEFAULT),
+        );
+        let (_, entry) = db.load_super_key(&USER_AFTER_FIRST_UNLOCK_SUPER_KEY, 1)?.unwrap();
+        assert_eq!(Some(&new_blob), entry.key_blob_info().as_ref().map(|(b, _)| b));
+
+        let loaded = SuperKeyManager::extract_super_key_from_key_entry(
+            &mut db,
+            USER_AFTER_FIRST_UNLOCK_SUPER_KEY.algorithm,
+            entry,
+            &pw,
+            None,
+        )?;
+        assert_eq!(super_key[..], loaded.key[..]);
+        Ok(())
+    }
+
     #[test]
     fn test_init_user() {
         let pw: Password = generate_password_blob();
@@ -1356,6 +2794,567 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_unlock_user_with_token() -> Result<()> {
+        let secret = [0x42u8; 64];
+        let pw = Password::from(&secret[..]);
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        skm.write().unwrap().data.user_keys.clear();
+
+        let mut token = vec![1, 0, 0, secret.len() as u8];
+        token.extend_from_slice(&secret);
+        let token = SyntheticPasswordToken::parse(&token)?;
+        skm.write().unwrap().unlock_user_with_token(
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            &token,
+        )?;
+        assert_unlocked(
+            &skm,
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            "The token did not unlock the user!",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_weak_token_requires_first_unlock() -> Result<()> {
+        let secret = [0x42u8; 64];
+        let pw = Password::from(&secret[..]);
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        skm.write().unwrap().data.user_keys.clear();
+
+        let mut token = vec![1, 1, 0, secret.len() as u8];
+        token.extend_from_slice(&secret);
+        let token = SyntheticPasswordToken::parse(&token)?;
+        let result = skm.write().unwrap().unlock_user_with_token(
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            &token,
+        );
+        assert!(matches!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::LOCKED))
+        ));
+
+        // After the first unlock with the LSKF, the weak token is accepted.
+        skm.write().unwrap().unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &pw)?;
+        skm.write().unwrap().unlock_user_with_token(
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            &token,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_biometric_bound_falls_back_to_after_first_unlock() -> Result<()> {
+        let pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        let key_parameters = [
+            KeyParameter::new(KeyParameterValue::UserSecureID(1), SecurityLevel::STRONGBOX),
+            KeyParameter::new(
+                KeyParameterValue::HardwareAuthenticatorType(
+                    HardwareAuthenticatorType::FINGERPRINT,
+                ),
+                SecurityLevel::STRONGBOX,
+            ),
+        ];
+        let skm = skm.read().unwrap();
+        // Without a biometric-bound super key in memory, the key is bound to the LSKF.
+        let (_, metadata) = skm.handle_super_encryption_on_key_init(
+            &mut keystore_db,
+            &legacy_importer,
+            &Domain::APP,
+            &key_parameters,
+            None,
+            USER_ID,
+            b"key blob",
+        )?;
+        let after_first_unlock =
+            skm.get_after_first_unlock_key_by_user_id_internal(USER_ID).unwrap();
+        let expected_id = match after_first_unlock.id {
+            SuperKeyIdentifier::DatabaseId(id) => id,
+            id => panic!("Unexpected super key identifier {id:?}"),
+        };
+        assert!(matches!(
+            SuperKeyIdentifier::from_metadata(&metadata),
+            Some(SuperKeyIdentifier::DatabaseId(id)) if id == expected_id
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_biometric_bound_keys_decrypted_before_lock_are_dropped() -> Result<()> {
+        let pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, _) = setup_test(&pw);
+        let mut skm = skm.write().unwrap();
+        // Stands in for a biometric-bound super key that KeyMint decrypted.
+        let super_key = skm.get_after_first_unlock_key_by_user_id_internal(USER_ID).unwrap();
+        let epoch = skm.data.biometric_bound_epoch;
+
+        skm.lock_unlocked_device_required_keys(&mut keystore_db, USER_ID, &[]);
+        skm.add_biometric_bound_keys(vec![(USER_ID, super_key.clone())], epoch);
+        assert!(skm.data.user_keys[&USER_ID].biometric_bound.is_none());
+
+        let epoch = skm.data.biometric_bound_epoch;
+        skm.add_biometric_bound_keys(vec![(USER_ID, super_key)], epoch);
+        assert!(skm.data.user_keys[&USER_ID].biometric_bound.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_locking_profile_keeps_parent_keys() -> Result<()> {
+        const PROFILE_ID: u32 = 10;
+        let pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        let mut skm = skm.write().unwrap();
+        skm.init_user(&mut keystore_db, &legacy_importer, PROFILE_ID, &pw)?;
+        let mut cached_key_ids = Vec::new();
+        for user_id in [USER_ID, PROFILE_ID] {
+            skm.unlock_unlocked_device_required_keys(&mut keystore_db, user_id, &pw)?;
+            let id = skm.data.user_keys[&user_id].unlocked_device_required_key_ids()[0];
+            skm.key_material_cache().insert(id, b"iv", b"tag", &ZVec::try_from(vec![1; 16])?);
+            cached_key_ids.push(id);
+        }
+
+        skm.lock_unlocked_device_required_keys(&mut keystore_db, PROFILE_ID, &[]);
+        assert!(skm.data.user_keys[&USER_ID].unlocked_device_required_symmetric.is_some());
+        assert!(skm.data.user_keys[&PROFILE_ID].unlocked_device_required_symmetric.is_none());
+        assert!(skm.key_material_cache().contains(cached_key_ids[0], b"iv", b"tag"));
+        assert!(!skm.key_material_cache().contains(cached_key_ids[1], b"iv", b"tag"));
+
+        skm.forget_all_keys_for_user(USER_ID);
+        assert!(!skm.key_material_cache().contains(cached_key_ids[0], b"iv", b"tag"));
+        assert!(skm.data.user_keys[&PROFILE_ID].after_first_unlock.is_some());
+        Ok(())
+    }
+
+    /// Stands in for KeyMint in tests.
+    struct XorWrapper;
+
+    impl KeyWrapper for XorWrapper {
+        fn wrap(&self, key: &[u8]) -> Result<Vec<u8>> {
+            Ok(key.iter().map(|b| b ^ 0x5a).collect())
+        }
+
+        fn unwrap(&self, wrapped: &[u8]) -> Result<ZVec> {
+            Ok(ZVec::try_from(wrapped.iter().map(|b| b ^ 0x5a).collect::<Vec<u8>>())?)
+        }
+    }
+
+    #[test]
+    fn test_trim_evicts_background_users() -> Result<()> {
+        const FOREGROUND_ID: u32 = 10;
+        const EMPTY_ID: u32 = 11;
+        let pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        let mut skm = skm.write().unwrap();
+        skm.data.eviction_wrapper = Some(Arc::new(Mutex::new(Box::new(XorWrapper))));
+        skm.init_user(&mut keystore_db, &legacy_importer, FOREGROUND_ID, &pw)?;
+        skm.unlock_unlocked_device_required_keys(&mut keystore_db, FOREGROUND_ID, &pw)?;
+        skm.data.user_keys.insert(EMPTY_ID, Default::default());
+        let (background_key_id, background_key) = {
+            let key = skm.get_after_first_unlock_key_by_user_id_internal(USER_ID).unwrap();
+            (key.id, key.key.to_vec())
+        };
+        let (users, keys, _) = skm.cache_size();
+
+        // Only trims under critical pressure evict, but empty entries always go.
+        assert_eq!(1, skm.trim_user_keys(false));
+        assert_eq!(1, skm.trim_user_keys(true));
+        assert_eq!((users - 1, keys, 1), skm.cache_size());
+        assert!(skm.data.user_keys[&USER_ID].after_first_unlock.is_none());
+        assert!(skm.data.user_keys[&FOREGROUND_ID].after_first_unlock.is_some());
+
+        // The evicted key is unwrapped on demand, and dropped again by the next trim.
+        let restored = skm.lookup_key(&background_key_id)?.unwrap();
+        assert_eq!(background_key, restored.key.to_vec());
+        drop(restored);
+        assert!(matches!(
+            skm.get_user_state(&mut keystore_db, &legacy_importer, USER_ID)?,
+            UserState::AfterFirstUnlock(_)
+        ));
+        assert_eq!(1, skm.trim_user_keys(false));
+
+        // Unlocking the user moves the key back into memory.
+        skm.unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &pw)?;
+        assert_eq!((users - 1, keys + 2, 0), skm.cache_size());
+        assert!(skm.lookup_key(&background_key_id)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_key_index() {
+        let pw: Password = generate_password_blob();
+        let (skm, _keystore_db, _legacy_importer) = setup_test(&pw);
+        let indexed = skm.read().unwrap().data.key_index.len();
+        assert!(indexed > 0);
+        assert_eq!(0, skm.write().unwrap().prune_key_index());
+
+        skm.write().unwrap().data.user_keys.clear();
+        assert_eq!(indexed as u64, skm.write().unwrap().prune_key_index());
+        assert!(skm.read().unwrap().data.key_index.is_empty());
+    }
+
+    #[test]
+    fn test_recover_user_from_escrow() {
+        let pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        let escrow_key = ECDHPrivateKey::generate().unwrap();
+        assert!(skm
+            .write()
+            .unwrap()
+            .provision_super_key_escrow(
+                &mut keystore_db,
+                USER_ID,
+                &escrow_key.public_key().unwrap(),
+                &generate_password_blob()
+            )
+            .is_err());
+        assert!(skm
+            .write()
+            .unwrap()
+            .provision_super_key_escrow(
+                &mut keystore_db,
+                USER_ID,
+                &escrow_key.public_key().unwrap(),
+                &pw
+            )
+            .is_ok());
+
+        skm.write().unwrap().data.user_keys.clear();
+        let new_pw: Password = generate_password_blob();
+        let other_key = ECDHPrivateKey::generate().unwrap();
+        assert!(skm
+            .write()
+            .unwrap()
+            .recover_user_from_escrow(
+                &mut keystore_db,
+                &legacy_importer,
+                USER_ID,
+                &other_key.private_key().unwrap(),
+                &new_pw
+            )
+            .is_err());
+        assert!(skm
+            .write()
+            .unwrap()
+            .recover_user_from_escrow(
+                &mut keystore_db,
+                &legacy_importer,
+                USER_ID,
+                &escrow_key.private_key().unwrap(),
+                &new_pw
+            )
+            .is_ok());
+        assert_unlocked(
+            &skm,
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            "The user was not unlocked after recovery!",
+        );
+
+        skm.write().unwrap().data.user_keys.clear();
+        assert!(skm
+            .write()
+            .unwrap()
+            .unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &pw)
+            .is_err());
+        assert!(skm
+            .write()
+            .unwrap()
+            .unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &new_pw)
+            .is_ok());
+    }
+
+    /// Stands in for KeyMint in tests.
+    struct XorSealer;
+
+    impl reboot_escrow::Sealer for XorSealer {
+        fn seal(
+            &self,
+            _db: &mut KeystoreDB,
+            _user_id: u32,
+            data: &[u8],
+            _expires: DateTime,
+        ) -> Result<Vec<u8>> {
+            Ok(data.iter().map(|b| b ^ 0x5a).collect())
+        }
+        fn unseal(&self, _db: &mut KeystoreDB, _user_id: u32, sealed: &[u8]) -> Result<Vec<u8>> {
+            Ok(sealed.iter().map(|b| b ^ 0x5a).collect())
+        }
+    }
+
+    #[test]
+    fn test_unlock_user_with_reboot_escrow() {
+        let pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        let secret = [0x5au8; 32];
+        let super_key_id = after_first_unlock_key(&skm).id;
+        assert!(skm
+            .write()
+            .unwrap()
+            .arm_reboot_escrow(&mut keystore_db, USER_ID, &secret, &XorSealer)
+            .is_ok());
+
+        skm.write().unwrap().data.user_keys.clear();
+        assert!(skm
+            .write()
+            .unwrap()
+            .unlock_user_with_reboot_escrow(
+                &mut keystore_db,
+                &legacy_importer,
+                USER_ID,
+                &[1u8; 32],
+                &XorSealer
+            )
+            .is_err());
+        assert!(skm
+            .write()
+            .unwrap()
+            .unlock_user_with_reboot_escrow(
+                &mut keystore_db,
+                &legacy_importer,
+                USER_ID,
+                &secret,
+                &XorSealer
+            )
+            .is_ok());
+        assert_unlocked(
+            &skm,
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            "The user was not unlocked with the reboot escrow!",
+        );
+        assert!(matches!(
+            (super_key_id, after_first_unlock_key(&skm).id),
+            (SuperKeyIdentifier::DatabaseId(a), SuperKeyIdentifier::DatabaseId(b)) if a == b
+        ));
+
+        // The copy is deleted when it is used.
+        skm.write().unwrap().data.user_keys.clear();
+        assert!(skm
+            .write()
+            .unwrap()
+            .unlock_user_with_reboot_escrow(
+                &mut keystore_db,
+                &legacy_importer,
+                USER_ID,
+                &secret,
+                &XorSealer
+            )
+            .is_err());
+        assert_locked(
+            &skm,
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            "The reboot escrow unlocked the user twice!",
+        );
+        assert!(skm
+            .write()
+            .unwrap()
+            .unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &pw)
+            .is_ok());
+    }
+
+    fn after_first_unlock_key(skm: &Arc<ProfiledRwLock<SuperKeyManager>>) -> Arc<SuperKey> {
+        skm.read().unwrap().get_after_first_unlock_key_by_user_id_internal(USER_ID).unwrap()
+    }
+
+    #[test]
+    fn test_change_user_password() {
+        let pw: Password = generate_password_blob();
+        let new_pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        skm.write()
+            .unwrap()
+            .unlock_unlocked_device_required_keys(&mut keystore_db, USER_ID, &pw)
+            .unwrap();
+        let super_key_id = after_first_unlock_key(&skm).id;
+
+        skm.write()
+            .unwrap()
+            .change_user_password(&mut keystore_db, &legacy_importer, USER_ID, &new_pw)
+            .unwrap();
+        // The super key stays in memory and keeps its id.
+        assert!(matches!(
+            (super_key_id, after_first_unlock_key(&skm).id),
+            (SuperKeyIdentifier::DatabaseId(a), SuperKeyIdentifier::DatabaseId(b)) if a == b
+        ));
+
+        skm.write().unwrap().data.user_keys.clear();
+        assert!(skm
+            .write()
+            .unwrap()
+            .unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &pw)
+            .is_err());
+        skm.write()
+            .unwrap()
+            .unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &new_pw)
+            .unwrap();
+        let skm = skm.read().unwrap();
+        let keys = skm.data.user_keys.get(&USER_ID).unwrap();
+        assert!(keys.unlocked_device_required_symmetric.is_some());
+        assert!(keys.unlocked_device_required_private.is_some());
+    }
+
+    #[test]
+    fn test_change_user_password_rewraps_stale_key_blobs() -> Result<()> {
+        let pw: Password = generate_password_blob();
+        let new_pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        skm.write().unwrap().unlock_unlocked_device_required_keys(
+            &mut keystore_db,
+            USER_ID,
+            &pw,
+        )?;
+        // A blob as it is stored for a key that was created while the device was locked.
+        let (blob, blob_metadata) = SuperKeyManager::encrypt_with_hybrid_super_key(
+            b"key material",
+            None,
+            &USER_UNLOCKED_DEVICE_REQUIRED_P521_SUPER_KEY,
+            &mut keystore_db,
+            USER_ID,
+        )?;
+        let private_id = match SuperKeyIdentifier::from_metadata(&blob_metadata) {
+            Some(SuperKeyIdentifier::DatabaseId(id)) => id,
+            id => panic!("Unexpected super key {id:?}."),
+        };
+        let key_id = make_test_key_entry(&mut keystore_db, Domain::APP, 10001, "stale", None)?;
+        keystore_db.set_blob(
+            &key_id,
+            SubComponentType::KEY_BLOB,
+            Some(&blob),
+            Some(&blob_metadata),
+        )?;
+        assert_eq!(1, keystore_db.load_key_blobs_encrypted_by(private_id, 0, 10)?.len());
+
+        skm.write().unwrap().change_user_password(
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            &new_pw,
+        )?;
+        assert!(keystore_db.load_key_blobs_encrypted_by(private_id, 0, 10)?.is_empty());
+
+        let (_, entry) = keystore_db.load_key_entry(
+            &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id.id(), ..Default::default() },
+            KeyType::Client,
+            KeyEntryLoadBits::KM,
+            10001,
+            |_, _| Ok(()),
+        )?;
+        let (blob, blob_metadata) = entry.key_blob_info().as_ref().unwrap();
+        let skm = skm.read().unwrap();
+        match skm.unwrap_key_if_required(blob_metadata, blob)? {
+            KeyBlob::Sensitive { key, force_reencrypt: false, .. } => {
+                assert_eq!(b"key material", &key[..])
+            }
+            _ => panic!("Expected a symmetrically encrypted blob."),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_user_password_with_locked_unlocked_device_required_keys() {
+        let pw: Password = generate_password_blob();
+        let new_pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        skm.write()
+            .unwrap()
+            .unlock_unlocked_device_required_keys(&mut keystore_db, USER_ID, &pw)
+            .unwrap();
+        {
+            let mut skm = skm.write().unwrap();
+            let keys = skm.data.user_keys.get_mut(&USER_ID).unwrap();
+            keys.unlocked_device_required_symmetric = None;
+            keys.unlocked_device_required_private = None;
+        }
+
+        assert_eq!(
+            Some(&Error::Rc(ResponseCode::LOCKED)),
+            skm.write()
+                .unwrap()
+                .change_user_password(&mut keystore_db, &legacy_importer, USER_ID, &new_pw)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>()
+        );
+
+        // Nothing was rewrapped.
+        skm.write().unwrap().data.user_keys.clear();
+        skm.write().unwrap().unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &pw).unwrap();
+    }
+
+    // Unwraps a key encrypted with the AfterFirstUnlock super key on several threads while the
+    // password is changed repeatedly, and checks that the key never becomes inaccessible.
+    #[test]
+    fn test_change_user_password_with_live_operations() {
+        const THREADS: usize = 4;
+        const PASSWORD_CHANGES: usize = 10;
+        let pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        let key_material = b"key material".to_vec();
+        let (blob, metadata) = SuperKeyManager::encrypt_with_aes_super_key(
+            &key_material,
+            &after_first_unlock_key(&skm),
+        )
+        .unwrap();
+        let encrypted = Arc::new((blob, metadata));
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let skm = skm.clone();
+                let encrypted = encrypted.clone();
+                let done = done.clone();
+                let key_material = key_material.clone();
+                std::thread::spawn(move || {
+                    let mut unwraps = 0;
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) || unwraps == 0 {
+                        let skm = skm.read().unwrap();
+                        let key = skm.unwrap_key_if_required(&encrypted.1, &encrypted.0).unwrap();
+                        assert_eq!(&key_material[..], &key[..]);
+                        unwraps += 1;
+                    }
+                })
+            })
+            .collect();
+
+        let mut last_pw = pw;
+        for _ in 0..PASSWORD_CHANGES {
+            let new_pw = generate_password_blob();
+            skm.write()
+                .unwrap()
+                .change_user_password(&mut keystore_db, &legacy_importer, USER_ID, &new_pw)
+                .unwrap();
+            last_pw = new_pw;
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // After a reboot, the key can be unwrapped with the last password.
+        skm.write().unwrap().data.user_keys.clear();
+        skm.write()
+            .unwrap()
+            .unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &last_pw)
+            .unwrap();
+        let skm = skm.read().unwrap();
+        let key = skm.unwrap_key_if_required(&encrypted.1, &encrypted.0).unwrap();
+        assert_eq!(&key_material[..], &key[..]);
+    }
+
     #[test]
     fn test_unlock_wrong_password() {
         let pw: Password = generate_password_blob();
@@ -1392,6 +3391,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_unlock_reports_metrics() {
+        let pw: Password = generate_password_blob();
+        let wrong_pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        // Counts the reported unlocks by outcome, over all latency buckets.
+        let unlock_outcomes = || {
+            let mut counts = HashMap::new();
+            for atom in METRICS_STORE.get_atoms(AtomID::USER_UNLOCK_STATS).unwrap() {
+                if let KeystoreAtomPayload::UserUnlockStats(stats) = atom.payload {
+                    *counts.entry(stats.outcome).or_insert(0) += atom.count;
+                }
+            }
+            counts
+        };
+
+        skm.write().unwrap().data.user_keys.clear();
+        let before = unlock_outcomes();
+        assert!(skm
+            .write()
+            .unwrap()
+            .unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &wrong_pw)
+            .is_err());
+        skm.write().unwrap().unlock_user(&mut keystore_db, &legacy_importer, USER_ID, &pw).unwrap();
+        let after = unlock_outcomes();
+
+        // Other tests unlock users concurrently, so only check that the counts went up.
+        for outcome in [UnlockOutcome::DECRYPTION_FAILED, UnlockOutcome::SUCCESS] {
+            assert!(
+                after.get(&outcome).copied().unwrap_or(0)
+                    > before.get(&outcome).copied().unwrap_or(0),
+                "{outcome:?} was not reported."
+            );
+        }
+        // The timings of an unlock do not carry over to the next one.
+        assert_eq!(Duration::ZERO, UnlockTimings::take().kdf);
+    }
+
     #[test]
     fn test_unlock_user_idempotent() {
         let pw: Password = generate_password_blob();
@@ -1548,7 +3585,7 @@ mod tests {
             assert!(skm
                 .write()
                 .unwrap()
-                .reset_user(&mut keystore_db, &legacy_importer, USER_ID)
+                .reset_user(&mut keystore_db, &legacy_importer, USER_ID, SWIPE_FILTER)
                 .is_err());
             assert_locked(
                 &skm,
@@ -1569,7 +3606,7 @@ mod tests {
             assert!(skm
                 .write()
                 .unwrap()
-                .reset_user(&mut keystore_db, &legacy_importer, USER_ID)
+                .reset_user(&mut keystore_db, &legacy_importer, USER_ID, SWIPE_FILTER)
                 .is_ok());
             assert_uninitialized(
                 &skm,
@@ -1604,6 +3641,53 @@ mod tests {
         test_user_removal(true);
     }
 
+    #[test]
+    fn test_reset_user_client_keys_only() {
+        let pw: Password = generate_password_blob();
+        let (skm, mut keystore_db, legacy_importer) = setup_test(&pw);
+        assert!(make_test_key_entry(
+            &mut keystore_db,
+            Domain::APP,
+            USER_ID.into(),
+            TEST_KEY_ALIAS,
+            None
+        )
+        .is_ok());
+        assert!(make_bootlevel_key_entry(
+            &mut keystore_db,
+            Domain::APP,
+            USER_ID.into(),
+            TEST_BOOT_KEY_ALIAS,
+            false
+        )
+        .is_ok());
+
+        let filter = UserKeyFilter { key_type: Some(KeyType::Client), ..SWIPE_FILTER };
+        assert!(skm
+            .write()
+            .unwrap()
+            .reset_user(&mut keystore_db, &legacy_importer, USER_ID, filter)
+            .is_ok());
+        assert_unlocked(
+            &skm,
+            &mut keystore_db,
+            &legacy_importer,
+            USER_ID,
+            "Resetting only client keys locked the user!",
+        );
+        assert!(skm
+            .write()
+            .unwrap()
+            .super_key_exists_in_db_for_user(&mut keystore_db, &legacy_importer, USER_ID)
+            .unwrap());
+        assert!(!keystore_db
+            .key_exists(Domain::APP, USER_ID.into(), TEST_KEY_ALIAS, KeyType::Client)
+            .unwrap());
+        assert!(keystore_db
+            .key_exists(Domain::APP, USER_ID.into(), TEST_BOOT_KEY_ALIAS, KeyType::Client)
+            .unwrap());
+    }
+
     #[test]
     fn test_reset_unlocked_user() {
         test_user_reset(false);
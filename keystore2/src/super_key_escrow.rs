@@ -0,0 +1,117 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the optional escrow of a user's AfterFirstUnlock super key for
+//! enterprise recovery agents. When an agent provisions an escrow public key for a user, the
+//! super key is additionally wrapped to that key with ECDH P-521 and stored as a super key entry
+//! of its own. If the user forgets their password, the agent can present the escrow private key,
+//! which allows Keystore to unwrap the super key and to re-encrypt it with a new password.
+//!
+//! Escrow is off unless an agent provisions it. Provisioning requires the user's super key to be
+//! in memory, i.e., the user must have unlocked the device since boot, and the user's consent,
+//! which the agent proves by presenting the password that the user's credential derives.
+//!
+//! Only the AfterFirstUnlock super key is escrowed. The UnlockedDeviceRequired super keys and
+//! the keys that they encrypt are deleted when a user is recovered from the escrow.
+
+use crate::database::{BlobMetaData, BlobMetaEntry, KeyMetaData, KeyMetaEntry};
+use crate::ec_crypto::ECDHPrivateKey;
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use crate::super_key::{SuperEncryptionAlgorithm, SuperKeyType};
+use anyhow::{Context, Result};
+use keystore2_crypto::ZVec;
+
+/// The user's AfterFirstUnlock super key wrapped to the escrow public key. The blob metadata
+/// holds the ephemeral public key, salt, iv, and tag of the ECDH encryption, and the key
+/// metadata holds the escrow public key itself.
+pub const USER_SUPER_KEY_ESCROW: SuperKeyType =
+    SuperKeyType { alias: "USER_SUPER_KEY_ESCROW", algorithm: SuperEncryptionAlgorithm::EcdhP521 };
+
+/// Wraps `super_key` to `escrow_public_key`, which must be an encoded X9.62 point on P-521.
+/// Returns the blob, blob metadata, and key metadata of the escrow entry.
+pub fn wrap(
+    super_key: &[u8],
+    escrow_public_key: &[u8],
+) -> Result<(Vec<u8>, BlobMetaData, KeyMetaData)> {
+    let (sender_public_key, salt, iv, wrapped_key, aead_tag) =
+        ECDHPrivateKey::encrypt_message(escrow_public_key, super_key)
+            .context(ks_err!("Failed to wrap super key to the escrow public key."))?;
+    let mut blob_metadata = BlobMetaData::new();
+    blob_metadata.add(BlobMetaEntry::PublicKey(sender_public_key));
+    blob_metadata.add(BlobMetaEntry::Salt(salt));
+    blob_metadata.add(BlobMetaEntry::Iv(iv));
+    blob_metadata.add(BlobMetaEntry::AeadTag(aead_tag));
+    let mut key_metadata = KeyMetaData::new();
+    key_metadata.add(KeyMetaEntry::Sec1PublicKey(escrow_public_key.to_vec()));
+    Ok((wrapped_key, blob_metadata, key_metadata))
+}
+
+/// Unwraps the super key from an escrow entry's blob and blob metadata with the escrow private
+/// key. Returns `INVALID_ARGUMENT` if the private key does not belong to the escrow.
+pub fn unwrap(
+    wrapped_key: &[u8],
+    blob_metadata: &BlobMetaData,
+    escrow_private_key: &[u8],
+) -> Result<ZVec> {
+    let (sender_public_key, salt, iv, aead_tag) = match (
+        blob_metadata.public_key(),
+        blob_metadata.salt(),
+        blob_metadata.iv(),
+        blob_metadata.aead_tag(),
+    ) {
+        (Some(public_key), Some(salt), Some(iv), Some(aead_tag)) => {
+            (public_key, salt, iv, aead_tag)
+        }
+        _ => {
+            return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context(ks_err!("Escrow entry has incomplete metadata."));
+        }
+    };
+    let escrow_key = ECDHPrivateKey::from_private_key(escrow_private_key)
+        .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        .context(ks_err!("Failed to parse escrow private key."))?;
+    escrow_key
+        .decrypt_message(sender_public_key, salt, iv, wrapped_key, aead_tag)
+        .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        .context(ks_err!("Escrow private key does not unwrap the super key."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_crypto::generate_aes256_key;
+
+    #[test]
+    fn wrap_unwrap_roundtrip() -> Result<()> {
+        let super_key = generate_aes256_key()?;
+        let escrow_key = ECDHPrivateKey::generate()?;
+        let (wrapped_key, blob_metadata, key_metadata) =
+            wrap(&super_key, &escrow_key.public_key()?)?;
+        assert_eq!(Some(&escrow_key.public_key()?), key_metadata.sec1_public_key());
+
+        let unwrapped = unwrap(&wrapped_key, &blob_metadata, &escrow_key.private_key()?)?;
+        assert_eq!(&super_key[..], &unwrapped[..]);
+
+        let other_key = ECDHPrivateKey::generate()?;
+        assert_eq!(
+            Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT)),
+            unwrap(&wrapped_key, &blob_metadata, &other_key.private_key()?)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module binds the password encryption of super keys to secrets in a rollback protected
+//! store, e.g., Secretkeeper. Without it, an attacker who saved a copy of the database and
+//! learned an old password of the user can decrypt the super keys of the copy even after the
+//! password was changed. With a store in place, the key that encrypts a super key is derived
+//! from both the password and a random secret held by the store. The secret is deleted when the
+//! super key is re-encrypted or deleted, which makes old copies of the database useless.
+//!
+//! The store is optional. If none is registered, or if storing a secret fails, super keys are
+//! encrypted with the password alone, as before. Super keys of existing users are bound to a
+//! secret the next time the user unlocks with their password. A super key that is bound to a
+//! secret can only be decrypted while the store is available.
+//!
+//! No store is registered yet, so super keys are still encrypted with the password alone.
+//! Secretkeeper only serves clients that authenticate with their DICE chain over an AuthGraph
+//! session, and keystore has no DICE chain of its own. A Secretkeeper-backed store can be
+//! registered with `set_store` once Secretkeeper accepts keystore as a client. Boot level keys
+//! do not use this module; KeyMint enforces their boot level.
+
+use crate::error::Error;
+use crate::ks_err;
+use anyhow::{Context, Result};
+use keystore2_crypto::{generate_aes256_key, hkdf_expand, hkdf_extract, ZVec, AES_256_KEY_LENGTH};
+use lazy_static::lazy_static;
+use std::sync::{Arc, RwLock};
+
+/// The info of the key derivation that combines the password derived key with the secret.
+const KDF_INFO: &[u8] = b"keystore2 rollback protected super key";
+
+/// A rollback protected store of secrets.
+pub trait SecretStore: Send + Sync {
+    /// Names the store in logs.
+    fn name(&self) -> &'static str;
+    /// Stores `secret` and returns a handle to it.
+    fn store(&self, secret: &[u8]) -> Result<Vec<u8>>;
+    /// Loads the secret that is stored under `handle`.
+    fn load(&self, handle: &[u8]) -> Result<ZVec>;
+    /// Deletes the secret that is stored under `handle`.
+    fn delete(&self, handle: &[u8]) -> Result<()>;
+}
+
+lazy_static! {
+    /// The store of the secrets, if any.
+    static ref STORE: RwLock<Option<Arc<dyn SecretStore>>> = RwLock::new(None);
+}
+
+/// Registers the store that super keys are bound to from now on, or unregisters it if `store`
+/// is None. A store is meant to be registered during startup, but none exists yet, see the
+/// module documentation.
+pub fn set_store(store: Option<Arc<dyn SecretStore>>) {
+    if let Some(store) = &store {
+        log::info!("Binding super keys to secrets in {}.", store.name());
+    }
+    *STORE.write().unwrap() = store;
+}
+
+fn store() -> Option<Arc<dyn SecretStore>> {
+    STORE.read().unwrap().clone()
+}
+
+/// Returns true if new super keys are bound to secrets.
+pub fn is_available() -> bool {
+    store().is_some()
+}
+
+fn combine(password_key: &[u8], secret: &[u8]) -> Result<ZVec> {
+    let prk = hkdf_extract(secret, password_key).context(ks_err!("hkdf_extract failed."))?;
+    hkdf_expand(AES_256_KEY_LENGTH, &prk, KDF_INFO).context(ks_err!("hkdf_expand failed."))
+}
+
+fn bind_with(
+    store: Option<&dyn SecretStore>,
+    password_key: ZVec,
+) -> Result<(ZVec, Option<Vec<u8>>)> {
+    let store = match store {
+        Some(store) => store,
+        None => return Ok((password_key, None)),
+    };
+    let secret = generate_aes256_key().context(ks_err!("Failed to generate secret."))?;
+    match store.store(&secret) {
+        Ok(handle) => Ok((combine(&password_key, &secret)?, Some(handle))),
+        Err(e) => {
+            log::warn!("Encrypting super key with the password only: {:?}", e);
+            Ok((password_key, None))
+        }
+    }
+}
+
+/// Returns the key that encrypts a super key, given the key derived from the password. If a
+/// store is available, the key is bound to a new secret, and the handle of the secret is
+/// returned along with it.
+pub fn bind(password_key: ZVec) -> Result<(ZVec, Option<Vec<u8>>)> {
+    bind_with(store().as_deref(), password_key)
+}
+
+fn unbind_with(
+    store: Option<&dyn SecretStore>,
+    password_key: ZVec,
+    handle: Option<&[u8]>,
+) -> Result<ZVec> {
+    let handle = match handle {
+        Some(handle) => handle,
+        None => return Ok(password_key),
+    };
+    let store = store
+        .ok_or_else(Error::sys)
+        .context(ks_err!("Super key is bound to a secret, but there is no secret store."))?;
+    let secret = store.load(handle).context(ks_err!("Failed to load secret."))?;
+    combine(&password_key, &secret)
+}
+
+/// Returns the key that decrypts a super key, given the key derived from the password and the
+/// handle of the secret that the super key is bound to, if any.
+pub fn unbind(password_key: ZVec, handle: Option<&[u8]>) -> Result<ZVec> {
+    unbind_with(store().as_deref(), password_key, handle)
+}
+
+/// Deletes the secrets under `handles` after the super keys that were bound to them were
+/// re-encrypted or deleted. Failures are logged, because the super keys are gone either way.
+pub fn release(handles: &[Vec<u8>]) {
+    if handles.is_empty() {
+        return;
+    }
+    match store() {
+        Some(store) => {
+            for handle in handles {
+                if let Err(e) = store.delete(handle) {
+                    log::error!("Failed to delete secret from {}: {:?}", store.name(), e);
+                }
+            }
+        }
+        None => log::error!("Cannot delete {} secrets without a secret store.", handles.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct FakeStore {
+        secrets: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+        broken: bool,
+    }
+
+    impl SecretStore for FakeStore {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+        fn store(&self, secret: &[u8]) -> Result<Vec<u8>> {
+            if self.broken {
+                return Err(Error::sys()).context("Store is broken.");
+            }
+            let mut secrets = self.secrets.lock().unwrap();
+            let handle = secrets.len().to_be_bytes().to_vec();
+            secrets.insert(handle.clone(), secret.to_vec());
+            Ok(handle)
+        }
+        fn load(&self, handle: &[u8]) -> Result<ZVec> {
+            let secret = self.secrets.lock().unwrap().get(handle).cloned();
+            ZVec::try_from(secret.ok_or_else(Error::sys).context("No such secret.")?)
+                .context("ZVec failed.")
+        }
+        fn delete(&self, handle: &[u8]) -> Result<()> {
+            self.secrets.lock().unwrap().remove(handle);
+            Ok(())
+        }
+    }
+
+    fn password_key() -> ZVec {
+        ZVec::try_from(vec![7u8; AES_256_KEY_LENGTH]).unwrap()
+    }
+
+    #[test]
+    fn bound_key_needs_the_secret() -> Result<()> {
+        let store = FakeStore::default();
+        let (key, handle) = bind_with(Some(&store), password_key())?;
+        let handle = handle.unwrap();
+        assert_ne!(&password_key()[..], &key[..]);
+        assert_eq!(&key[..], &unbind_with(Some(&store), password_key(), Some(&handle))?[..]);
+
+        // Without the store or after the secret was deleted, the key cannot be recovered.
+        assert!(unbind_with(None, password_key(), Some(&handle)).is_err());
+        store.delete(&handle)?;
+        assert!(unbind_with(Some(&store), password_key(), Some(&handle)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_the_password() -> Result<()> {
+        let (key, handle) = bind_with(None, password_key())?;
+        assert_eq!((&password_key()[..], None), (&key[..], handle));
+
+        let broken = FakeStore { broken: true, ..Default::default() };
+        let (key, handle) = bind_with(Some(&broken), password_key())?;
+        assert_eq!((&password_key()[..], None), (&key[..], handle));
+
+        // Super keys that are not bound do not need a store.
+        assert_eq!(&password_key()[..], &unbind_with(None, password_key(), None)?[..]);
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module parses the synthetic password tokens that LockSettings hands to keystore to
+//! unlock a user, so that keystore does not need to receive the user's credential as a bare
+//! byte array.
+//!
+//! A token is laid out as follows:
+//!
+//! | offset | length | field                                       |
+//! |--------|--------|---------------------------------------------|
+//! | 0      | 1      | version, `TOKEN_VERSION`                    |
+//! | 1      | 1      | token type, see `TokenType`                 |
+//! | 2      | 2      | length of the secret, big endian            |
+//! | 4      | n      | secret                                      |
+//!
+//! The secret is the keystore password that LockSettings derives from the synthetic password,
+//! i.e., the bytes that it passed to `onLockScreenEvent` before. It is used as input of the
+//! key derivation of the super keys, so that users that were unlocked with a raw password can be
+//! unlocked with a token.
+
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use anyhow::{Context, Result};
+use keystore2_crypto::{Password, ZVec};
+
+/// The only version of the token format.
+const TOKEN_VERSION: u8 = 1;
+
+/// The length of the header that precedes the secret.
+const HEADER_LENGTH: usize = 4;
+
+/// The maximum length of the secret, generously above the 64 bytes of the hex encoded keystore
+/// password that LockSettings derives.
+const MAX_SECRET_LENGTH: usize = 256;
+
+/// How the synthetic password was protected, as in the SyntheticPasswordManager of
+/// LockSettings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenType {
+    /// The synthetic password was unwrapped with the user's LSKF.
+    Strong,
+    /// The synthetic password was unwrapped with an escrow token.
+    Weak,
+}
+
+impl TokenType {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(Self::Strong),
+            1 => Some(Self::Weak),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed synthetic password token. The secret is zeroed when the token is dropped.
+pub struct SyntheticPasswordToken {
+    token_type: TokenType,
+    secret: ZVec,
+}
+
+impl std::fmt::Debug for SyntheticPasswordToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyntheticPasswordToken").field("token_type", &self.token_type).finish()
+    }
+}
+
+impl SyntheticPasswordToken {
+    /// Parses and verifies `token`. Malformed tokens are refused with `INVALID_ARGUMENT`.
+    pub fn parse(token: &[u8]) -> Result<Self> {
+        let invalid = || Error::Rc(ResponseCode::INVALID_ARGUMENT);
+        if token.len() < HEADER_LENGTH {
+            return Err(invalid()).context(ks_err!("Token too short: {} bytes.", token.len()));
+        }
+        if token[0] != TOKEN_VERSION {
+            return Err(invalid()).context(ks_err!("Unsupported token version {}.", token[0]));
+        }
+        let token_type = TokenType::from_byte(token[1])
+            .ok_or_else(invalid)
+            .context(ks_err!("Unknown token type {}.", token[1]))?;
+        let length = u16::from_be_bytes([token[2], token[3]]) as usize;
+        let secret = &token[HEADER_LENGTH..];
+        if length == 0 || length > MAX_SECRET_LENGTH || secret.len() != length {
+            return Err(invalid()).context(ks_err!(
+                "Invalid secret length {} with {} bytes remaining.",
+                length,
+                secret.len()
+            ));
+        }
+        if secret.iter().all(|b| *b == 0) {
+            return Err(invalid()).context(ks_err!("The secret is all zeros."));
+        }
+        let secret = ZVec::try_from(secret).context(ks_err!("Failed to copy the secret."))?;
+        Ok(Self { token_type, secret })
+    }
+
+    /// Returns how the synthetic password was protected.
+    pub fn token_type(&self) -> TokenType {
+        self.token_type
+    }
+
+    /// Returns the input of the key derivation of the super keys.
+    pub fn password(&self) -> Password<'_> {
+        Password::Ref(&self.secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(version: u8, token_type: u8, secret: &[u8]) -> Vec<u8> {
+        let mut token = vec![version, token_type];
+        token.extend_from_slice(&(secret.len() as u16).to_be_bytes());
+        token.extend_from_slice(secret);
+        token
+    }
+
+    fn is_invalid_argument(result: Result<SyntheticPasswordToken>) -> bool {
+        matches!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        )
+    }
+
+    #[test]
+    fn parses_valid_tokens() -> Result<()> {
+        let secret = [0x5au8; 64];
+        let strong = SyntheticPasswordToken::parse(&token(TOKEN_VERSION, 0, &secret))?;
+        assert_eq!(TokenType::Strong, strong.token_type());
+        let weak = SyntheticPasswordToken::parse(&token(TOKEN_VERSION, 1, &secret))?;
+        assert_eq!(TokenType::Weak, weak.token_type());
+        // The secret is used as is, so that it derives the same keys as the raw password.
+        let salt = [1u8; 16];
+        assert_eq!(
+            Password::from(&secret[..]).derive_key(&salt, 32)?[..],
+            strong.password().derive_key(&salt, 32)?[..]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_malformed_tokens() {
+        let secret = [0x5au8; 32];
+        let too_long = [0x5au8; MAX_SECRET_LENGTH + 1];
+        let mut truncated = token(TOKEN_VERSION, 0, &secret);
+        truncated.pop();
+        let mut trailing = token(TOKEN_VERSION, 0, &secret);
+        trailing.push(0);
+        for malformed in [
+            vec![TOKEN_VERSION, 0, 0],
+            token(TOKEN_VERSION + 1, 0, &secret),
+            token(TOKEN_VERSION, 2, &secret),
+            token(TOKEN_VERSION, 0, &[]),
+            token(TOKEN_VERSION, 0, &[0u8; 32]),
+            token(TOKEN_VERSION, 0, &too_long),
+            truncated,
+            trailing,
+        ] {
+            assert!(is_invalid_argument(SyntheticPasswordToken::parse(&malformed)));
+        }
+    }
+}
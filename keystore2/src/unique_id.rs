@@ -0,0 +1,160 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module keeps per app state for key generations that request Tag::INCLUDE_UNIQUE_ID.
+//!
+//! KeyMint derives the unique ID from the application id, the ID rotation period of
+//! Tag::CREATION_DATETIME, and Tag::RESET_SINCE_ID_ROTATION. Keystore decides the latter once
+//! per app and ID rotation period and hands out the cached decision afterwards, so all keys of
+//! an app in one period carry the same unique ID. The cache moves on with the period, so the
+//! ID still rotates.
+//!
+//! Every such request costs an attestation in the TEE. Apps that request unique IDs more often
+//! than `MAX_REQUESTS_PER_WINDOW` times per `THROTTLE_WINDOW` get `BACKEND_BUSY` with a retry
+//! hint until their oldest request leaves the window.
+
+use crate::error::{Error, RetryAfter};
+use crate::id_rotation::id_rotation_period;
+use crate::ks_err;
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The number of unique ID requests that an app may make per `THROTTLE_WINDOW`.
+const MAX_REQUESTS_PER_WINDOW: usize = 10;
+
+/// The window over which unique ID requests are counted.
+const THROTTLE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct State {
+    /// Tag::RESET_SINCE_ID_ROTATION decisions by uid and ID rotation period.
+    reset_since_rotation: HashMap<(u32, u64), bool>,
+    /// The times of the requests of each uid within the current window, oldest first.
+    recent_requests: HashMap<u32, VecDeque<Instant>>,
+}
+
+/// Caches unique ID decisions and throttles unique ID requests per app.
+#[derive(Default)]
+pub struct UniqueIdTracker {
+    state: Mutex<State>,
+}
+
+impl UniqueIdTracker {
+    /// Records a unique ID request of `uid`. Fails with `BACKEND_BUSY` and a retry hint if `uid`
+    /// has used up its requests in the current window.
+    pub fn throttle(&self, uid: u32) -> Result<()> {
+        self.throttle_at(uid, Instant::now())
+    }
+
+    fn throttle_at(&self, uid: u32, now: Instant) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let requests = state.recent_requests.entry(uid).or_default();
+        while requests.front().map_or(false, |t| now.duration_since(*t) >= THROTTLE_WINDOW) {
+            requests.pop_front();
+        }
+        if let Some(oldest) = requests.front().filter(|_| requests.len() >= MAX_REQUESTS_PER_WINDOW)
+        {
+            let retry_after = THROTTLE_WINDOW.saturating_sub(now.duration_since(*oldest));
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(ks_err!("Too many unique ID requests by uid {uid}."))
+                .context(RetryAfter(retry_after));
+        }
+        requests.push_back(now);
+        // Forget apps whose requests have all left the window.
+        state.recent_requests.retain(|_, requests| {
+            requests.back().map_or(false, |t| now.duration_since(*t) < THROTTLE_WINDOW)
+        });
+        Ok(())
+    }
+
+    /// Returns whether the unique ID of a key of `uid` created at `creation_datetime` gets
+    /// Tag::RESET_SINCE_ID_ROTATION. `check` is only called for the first request of `uid` in
+    /// an ID rotation period.
+    pub fn reset_since_rotation<F>(
+        &self,
+        uid: u32,
+        creation_datetime: &SystemTime,
+        check: F,
+    ) -> Result<bool>
+    where
+        F: FnOnce() -> Result<bool>,
+    {
+        let period = id_rotation_period(creation_datetime).context(ks_err!())?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(reset) = state.reset_since_rotation.get(&(uid, period)) {
+            return Ok(*reset);
+        }
+        let reset = check().context(ks_err!())?;
+        // Decisions of past periods are never needed again.
+        state.reset_since_rotation.retain(|(_, p), _| *p >= period);
+        state.reset_since_rotation.insert((uid, period), reset);
+        Ok(reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    #[test]
+    fn throttles_per_uid() {
+        let tracker = UniqueIdTracker::default();
+        let start = Instant::now();
+        for i in 0..MAX_REQUESTS_PER_WINDOW {
+            tracker.throttle_at(10001, start + Duration::from_secs(i as u64)).unwrap();
+        }
+        let later = start + Duration::from_secs(20);
+        let e = tracker.throttle_at(10001, later).unwrap_err();
+        assert!(matches!(
+            e.root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::BACKEND_BUSY))
+        ));
+        assert_eq!(Some(&RetryAfter(THROTTLE_WINDOW - Duration::from_secs(20))), e.downcast_ref());
+
+        // Other apps are not affected.
+        tracker.throttle_at(10002, later).unwrap();
+        // Once the oldest request left the window, the app may request again.
+        tracker.throttle_at(10001, start + THROTTLE_WINDOW).unwrap();
+        assert!(tracker.throttle_at(10001, start + THROTTLE_WINDOW).is_err());
+    }
+
+    #[test]
+    fn caches_reset_decision_per_uid_and_period() {
+        let tracker = UniqueIdTracker::default();
+        let first_period = SystemTime::UNIX_EPOCH + PERIOD * 100;
+        let same_period = first_period + PERIOD / 2;
+        let next_period = first_period + PERIOD;
+
+        assert!(tracker.reset_since_rotation(10001, &first_period, || Ok(true)).unwrap());
+        // The decision sticks for the rest of the period.
+        assert!(tracker
+            .reset_since_rotation(10001, &same_period, || panic!("Decision must be cached."))
+            .unwrap());
+        // Other apps and later periods are decided anew.
+        assert!(!tracker.reset_since_rotation(10002, &same_period, || Ok(false)).unwrap());
+        assert!(!tracker.reset_since_rotation(10001, &next_period, || Ok(false)).unwrap());
+        // Failed checks are not cached.
+        assert!(tracker
+            .reset_since_rotation(10003, &first_period, || Err(Error::sys().into()))
+            .is_err());
+        assert!(tracker.reset_since_rotation(10003, &next_period, || Ok(true)).unwrap());
+        // Past periods were dropped.
+        assert!(!tracker.reset_since_rotation(10001, &first_period, || Ok(false)).unwrap());
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module caps the keys of each Android user, so that the apps of one user of a shared
+//! device, e.g., a multi-user tablet, cannot fill the keystore database on their own.
+//!
+//! OEMs set the caps through the system properties `KEY_LIMIT_PROPERTY`, the number of live
+//! keys, and `STORAGE_LIMIT_PROPERTY`, the number of bytes of their blobs, e.g., key blobs and
+//! certificates. A cap that is unset or 0 is not enforced. Only the keys that apps create in
+//! `Domain::APP` count against the caps of their user. Keys in `Domain::SELINUX` namespaces and
+//! keys of system uids are exempt. A user that reached a cap gets `PERMISSION_DENIED` when it
+//! generates or imports another key, even if the new key replaces an existing alias, until keys
+//! are deleted. Enforcement is gated by `Feature::UserKeyLimits`.
+
+use crate::dump_fields;
+use crate::dump_schema::DumpSection;
+use crate::error::Error;
+use crate::feature_flags::{self, Feature};
+use crate::globals::DB;
+use crate::ks_err;
+use crate::utils::{uid_to_android_user, AID_APP_START, AID_USER_OFFSET};
+use android_security_maintenance::aidl::android::security::maintenance::UserKeyUsage::UserKeyUsage;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
+};
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The system property that holds the number of live keys that the apps of a user may have.
+const KEY_LIMIT_PROPERTY: &str = "ro.keystore.user_key_limit";
+
+/// The system property that holds the number of bytes of blobs that the keys of the apps of a
+/// user may have.
+const STORAGE_LIMIT_PROPERTY: &str = "ro.keystore.user_storage_limit_bytes";
+
+/// The dump section of the limits.
+pub(crate) const DUMP_SECTION: DumpSection = DumpSection {
+    name: "user_key_limits",
+    header: "User key limits (key limit, storage limit in bytes, denied):",
+    line: "  <key_limit>, <storage_limit>, <denied>",
+    fields: dump_fields!(key_limit: "string", storage_limit: "string", denied: "uint64"),
+};
+
+/// Enforces the per user caps of keys and key storage.
+#[derive(Default)]
+pub struct UserLimits {
+    /// The number of keys per user, or None if keys are not limited.
+    max_keys: Option<u64>,
+    /// The number of blob bytes per user, or None if storage is not limited.
+    max_bytes: Option<u64>,
+    denied: AtomicU64,
+}
+
+fn read_limit(property: &str) -> Option<u64> {
+    match rustutils::system_properties::read(property) {
+        Ok(Some(value)) => match value.parse::<u64>() {
+            Ok(0) => None,
+            Ok(limit) => Some(limit),
+            Err(e) => {
+                log::error!("Ignoring invalid {}={:?}: {:?}", property, value, e);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            log::error!("Failed to read {}: {:?}", property, e);
+            None
+        }
+    }
+}
+
+impl UserLimits {
+    /// Creates limits of `max_keys` keys and `max_bytes` blob bytes per user. None means
+    /// unlimited.
+    pub fn new(max_keys: Option<u64>, max_bytes: Option<u64>) -> Self {
+        Self { max_keys, max_bytes, ..Default::default() }
+    }
+
+    /// Creates the limits that are configured in `KEY_LIMIT_PROPERTY` and
+    /// `STORAGE_LIMIT_PROPERTY`.
+    pub fn from_properties() -> Self {
+        Self::new(read_limit(KEY_LIMIT_PROPERTY), read_limit(STORAGE_LIMIT_PROPERTY))
+    }
+
+    /// Returns true if the key `key` does not count against the limits of its user.
+    fn is_exempt(key: &KeyDescriptor) -> bool {
+        key.domain != Domain::APP || (key.nspace as u32) % AID_USER_OFFSET < AID_APP_START
+    }
+
+    /// Checks that the user of the new key `key` has not reached its limits. `key` must have
+    /// been resolved, i.e., the namespace of a `Domain::APP` key is the uid of its owner.
+    pub fn check(&self, key: &KeyDescriptor) -> Result<()> {
+        if (self.max_keys.is_none() && self.max_bytes.is_none())
+            || Self::is_exempt(key)
+            || !feature_flags::is_enabled(Feature::UserKeyLimits)
+        {
+            return Ok(());
+        }
+        let user_id = uid_to_android_user(key.nspace as u32);
+        let (keys, bytes) = DB
+            .with(|db| db.borrow_mut().get_user_key_usage(user_id))
+            .context(ks_err!("Failed to get the key usage of user {user_id}."))?;
+        self.check_usage(user_id, keys, bytes)
+    }
+
+    fn check_usage(&self, user_id: u32, keys: u64, bytes: u64) -> Result<()> {
+        let exceeded = match (self.max_keys, self.max_bytes) {
+            (Some(max_keys), _) if keys >= max_keys => Some(format!("{keys} of {max_keys} keys")),
+            (_, Some(max_bytes)) if bytes >= max_bytes => {
+                Some(format!("{bytes} of {max_bytes} bytes"))
+            }
+            _ => None,
+        };
+        match exceeded {
+            Some(exceeded) => {
+                self.denied.fetch_add(1, Ordering::Relaxed);
+                Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                    .context(ks_err!("User {user_id} has used {exceeded}."))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the number of keys and blob bytes that count against the limits of `user_id`,
+    /// along with the limits.
+    pub fn usage(&self, user_id: u32) -> Result<UserKeyUsage> {
+        let (keys, bytes) = DB
+            .with(|db| db.borrow_mut().get_user_key_usage(user_id))
+            .context(ks_err!("Failed to get the key usage of user {user_id}."))?;
+        Ok(UserKeyUsage {
+            userId: user_id as i32,
+            keyCount: keys as i64,
+            keyLimit: self.max_keys.map_or(0, |l| l as i64),
+            storageBytes: bytes as i64,
+            storageLimitBytes: self.max_bytes.map_or(0, |l| l as i64),
+        })
+    }
+
+    /// Writes the limits and the number of denied key creations to `writer`.
+    pub fn dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let limit = |l: Option<u64>| l.map_or_else(|| "none".to_string(), |l| l.to_string());
+        DUMP_SECTION.write_header(writer)?;
+        DUMP_SECTION.write_line(
+            writer,
+            &[&limit(self.max_keys), &limit(self.max_bytes), &self.denied.load(Ordering::Relaxed)],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denies_at_either_limit() {
+        let limits = UserLimits::new(Some(10), Some(1000));
+        assert!(limits.check_usage(10, 9, 999).is_ok());
+        for (keys, bytes) in [(10, 0), (0, 1000)] {
+            let e = limits.check_usage(10, keys, bytes).unwrap_err();
+            assert!(matches!(
+                e.root_cause().downcast_ref::<Error>(),
+                Some(Error::Rc(ResponseCode::PERMISSION_DENIED))
+            ));
+        }
+
+        let mut out = Vec::new();
+        limits.dump(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("\n  10, 1000, 2\n"));
+    }
+
+    #[test]
+    fn exempts_system_namespaces() {
+        let app = |nspace| KeyDescriptor { domain: Domain::APP, nspace, ..Default::default() };
+        assert!(!UserLimits::is_exempt(&app(10 * AID_USER_OFFSET as i64 + 10001)));
+        assert!(UserLimits::is_exempt(&app(10 * AID_USER_OFFSET as i64 + 1000)));
+        assert!(UserLimits::is_exempt(&KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 100,
+            ..Default::default()
+        }));
+    }
+}
@@ -309,6 +309,54 @@ pub fn uid_to_android_user(uid: u32) -> u32 {
     rustutils::users::multiuser_get_user_id(uid)
 }
 
+/// First app id assigned to regular applications.
+pub const AID_APP_START: u32 = 10000;
+
+/// Last app id assigned to regular applications.
+pub const AID_APP_END: u32 = 19999;
+
+/// First app id of the range reserved for SDK sandbox processes. Each app id in
+/// `AID_APP_START..=AID_APP_END` has a corresponding SDK sandbox app id at the same offset
+/// within this range.
+pub const AID_SDK_SANDBOX_PROCESS_START: u32 = 20000;
+
+/// Last app id of the range reserved for SDK sandbox processes.
+pub const AID_SDK_SANDBOX_PROCESS_END: u32 = 29999;
+
+/// Returns true if the given uid belongs to an SDK sandbox process.
+pub fn is_sdk_sandbox_uid(uid: u32) -> bool {
+    let app_id = uid % AID_USER_OFFSET;
+    (AID_SDK_SANDBOX_PROCESS_START..=AID_SDK_SANDBOX_PROCESS_END).contains(&app_id)
+}
+
+/// Returns the uid of the SDK sandbox process paired with the app `uid`, or None if `uid`
+/// is not a regular application uid.
+pub fn app_uid_to_sdk_sandbox_uid(uid: u32) -> Option<u32> {
+    let app_id = uid % AID_USER_OFFSET;
+    if (AID_APP_START..=AID_APP_END).contains(&app_id) {
+        Some(uid - AID_APP_START + AID_SDK_SANDBOX_PROCESS_START)
+    } else {
+        None
+    }
+}
+
+/// Returns the uid of the app hosting the SDK sandbox process `uid`, or None if `uid` is
+/// not an SDK sandbox uid.
+pub fn sdk_sandbox_uid_to_app_uid(uid: u32) -> Option<u32> {
+    if is_sdk_sandbox_uid(uid) {
+        Some(uid - AID_SDK_SANDBOX_PROCESS_START + AID_APP_START)
+    } else {
+        None
+    }
+}
+
+/// Returns true if `uid_a` and `uid_b` are an app and its SDK sandbox, in either order.
+/// Key namespaces of such pairs are kept isolated from each other.
+pub fn is_sdk_sandbox_pair(uid_a: u32, uid_b: u32) -> bool {
+    app_uid_to_sdk_sandbox_uid(uid_a) == Some(uid_b)
+        || app_uid_to_sdk_sandbox_uid(uid_b) == Some(uid_a)
+}
+
 /// Merges and filters two lists of key descriptors. The first input list, legacy_descriptors,
 /// is assumed to not be sorted or filtered. As such, all key descriptors in that list whose
 /// alias is less than, or equal to, start_past_alias (if provided) will be removed.
@@ -410,6 +458,36 @@ pub fn list_key_entries(
     Ok(merged_key_entries[..safe_amount_to_return].to_vec())
 }
 
+/// Delivers key entries chunk by chunk, in alias order. `list` returns the entries past the
+/// given alias, as `list_key_entries` does, and `deliver` is called with each chunk. The next
+/// chunk is only listed once `deliver` returned, so that a slow consumer is not overrun. Stops
+/// after the last chunk, or when `deliver` returns false. Returns the number of delivered
+/// entries.
+pub fn stream_key_entries<L, D>(mut list: L, mut deliver: D) -> Result<usize>
+where
+    L: FnMut(Option<&str>) -> Result<Vec<KeyDescriptor>>,
+    D: FnMut(&[KeyDescriptor]) -> Result<bool>,
+{
+    let mut start_past_alias: Option<String> = None;
+    let mut delivered = 0;
+    loop {
+        let entries = list(start_past_alias.as_deref()).context(ks_err!())?;
+        let last_alias = match entries.last() {
+            Some(last) => last.alias.clone(),
+            None => return Ok(delivered),
+        };
+        delivered += entries.len();
+        if !deliver(&entries).context(ks_err!())? {
+            return Ok(delivered);
+        }
+        // Listed entries always have an alias. Without one, there is no way to continue.
+        match last_alias {
+            Some(alias) => start_past_alias = Some(alias),
+            None => return Ok(delivered),
+        }
+    }
+}
+
 /// Count all key aliases for a given domain + namespace.
 pub fn count_key_entries(db: &mut KeystoreDB, domain: Domain, namespace: i64) -> Result<i32> {
     let legacy_keys = LEGACY_IMPORTER
@@ -449,6 +527,25 @@ pub mod watchdog {
     ) -> Option<WatchPoint> {
         Watchdog::watch_with(&WD, id, Duration::from_millis(millis), callback)
     }
+
+    /// Like `watch_millis_with` but calls `escalation` once if the watch point is still armed
+    /// after `hard_limit`.
+    pub fn watch_millis_with_escalation(
+        id: &'static str,
+        millis: u64,
+        callback: impl Fn() -> String + Send + 'static,
+        hard_limit: Duration,
+        escalation: impl FnOnce() + Send + 'static,
+    ) -> Option<WatchPoint> {
+        Watchdog::watch_with_escalation(
+            &WD,
+            id,
+            Duration::from_millis(millis),
+            callback,
+            hard_limit,
+            escalation,
+        )
+    }
 }
 
 /// Trait implemented by objects that can be used to decrypt cipher text using AES-GCM.
@@ -497,11 +594,27 @@ pub mod watchdog {
     ) -> Option<WatchPoint> {
         None
     }
+
+    pub fn watch_millis_with_escalation(
+        _: &'static str,
+        _: u64,
+        _: impl Fn() -> String + Send + 'static,
+        _: std::time::Duration,
+        _: impl FnOnce() + Send + 'static,
+    ) -> Option<WatchPoint> {
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        AttestationKey::AttestationKey, BeginResult::BeginResult,
+        HardwareAuthToken::HardwareAuthToken, KeyCreationResult::KeyCreationResult,
+        KeyFormat::KeyFormat, KeyMintHardwareInfo::KeyMintHardwareInfo, KeyPurpose::KeyPurpose,
+    };
+    use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::TimeStampToken::TimeStampToken;
     use anyhow::Result;
 
     #[test]
@@ -543,6 +656,30 @@ mod tests {
             .collect::<Vec<String>>()
     }
 
+    #[test]
+    fn test_sdk_sandbox_uid_mapping() {
+        let user_10 = 10 * AID_USER_OFFSET;
+        assert!(!is_sdk_sandbox_uid(10123));
+        assert!(is_sdk_sandbox_uid(20123));
+        assert!(is_sdk_sandbox_uid(user_10 + 20123));
+        assert!(!is_sdk_sandbox_uid(AID_KEYSTORE));
+
+        assert_eq!(app_uid_to_sdk_sandbox_uid(10123), Some(20123));
+        assert_eq!(app_uid_to_sdk_sandbox_uid(user_10 + 10123), Some(user_10 + 20123));
+        assert_eq!(app_uid_to_sdk_sandbox_uid(20123), None);
+        assert_eq!(app_uid_to_sdk_sandbox_uid(AID_KEYSTORE), None);
+
+        assert_eq!(sdk_sandbox_uid_to_app_uid(20123), Some(10123));
+        assert_eq!(sdk_sandbox_uid_to_app_uid(user_10 + 20123), Some(user_10 + 10123));
+        assert_eq!(sdk_sandbox_uid_to_app_uid(10123), None);
+
+        assert!(is_sdk_sandbox_pair(10123, 20123));
+        assert!(is_sdk_sandbox_pair(20123, 10123));
+        assert!(!is_sdk_sandbox_pair(10123, 20124));
+        assert!(!is_sdk_sandbox_pair(10123, user_10 + 20123));
+        assert!(!is_sdk_sandbox_pair(10123, 10123));
+    }
+
     #[test]
     fn test_safe_amount_to_return() -> Result<()> {
         let key_aliases = vec!["key1", "key2", "key3"];
@@ -554,6 +691,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_stream_key_entries() -> Result<()> {
+        let key_aliases = vec!["key_a", "key_b", "key_c", "key_d", "key_e"];
+        let key_descriptors = create_key_descriptors_from_aliases(&key_aliases);
+        let list = |start_past_alias: Option<&str>| -> Result<Vec<KeyDescriptor>> {
+            Ok(key_descriptors
+                .iter()
+                .filter(|kd| start_past_alias.map_or(true, |a| kd.alias.as_deref() > Some(a)))
+                .take(2)
+                .cloned()
+                .collect())
+        };
+
+        let mut chunks = Vec::new();
+        let delivered = stream_key_entries(list, |chunk| {
+            chunks.push(chunk.len());
+            Ok(true)
+        })?;
+        assert_eq!(5, delivered);
+        assert_eq!(vec![2, 2, 1], chunks);
+
+        // The consumer can stop early.
+        let delivered = stream_key_entries(list, |_| Ok(false))?;
+        assert_eq!(2, delivered);
+        Ok(())
+    }
+
     #[test]
     fn test_merge_and_sort_lists_without_filtering() -> Result<()> {
         let legacy_key_aliases = vec!["key_c", "key_a", "key_b"];
@@ -595,4 +759,242 @@ mod tests {
         assert_eq!(aliases_from_key_descriptors(&result), vec!["key_d", "key_e", "key_f", "key_g"]);
         Ok(())
     }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum KmCall {
+        Op(Vec<u8>),
+        Upgrade(Vec<u8>),
+    }
+
+    /// A fake KeyMint device for blobs of the form `old:<key>` and `new:<key>`. Operations on an
+    /// `old:` blob require an upgrade, which turns it into a `new:` blob. Like a real KeyMint
+    /// device, it rejects blobs that carry a km_compat prefix.
+    #[derive(Default)]
+    struct FakeKeyMint {
+        calls: std::sync::Mutex<Vec<KmCall>>,
+    }
+
+    impl FakeKeyMint {
+        fn km_error<T>(error_code: ErrorCode) -> binder::Result<T> {
+            Err(binder::Status::new_service_specific_error(error_code.0, None))
+        }
+
+        fn op(&self, key_blob: &[u8]) -> Result<(), Error> {
+            self.calls.lock().unwrap().push(KmCall::Op(key_blob.to_vec()));
+            map_km_error(if key_blob.starts_with(b"new:") {
+                Ok(())
+            } else if key_blob.starts_with(b"old:") {
+                Self::km_error(ErrorCode::KEY_REQUIRES_UPGRADE)
+            } else {
+                Self::km_error(ErrorCode::INVALID_KEY_BLOB)
+            })
+        }
+
+        fn take_calls(&self) -> Vec<KmCall> {
+            std::mem::take(&mut *self.calls.lock().unwrap())
+        }
+    }
+
+    impl binder::Interface for FakeKeyMint {}
+
+    impl IKeyMintDevice for FakeKeyMint {
+        fn getHardwareInfo(&self) -> binder::Result<KeyMintHardwareInfo> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn addRngEntropy(&self, _: &[u8]) -> binder::Result<()> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn generateKey(
+            &self,
+            _: &[KmKeyParameter],
+            _: Option<&AttestationKey>,
+        ) -> binder::Result<KeyCreationResult> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn importKey(
+            &self,
+            _: &[KmKeyParameter],
+            _: KeyFormat,
+            _: &[u8],
+            _: Option<&AttestationKey>,
+        ) -> binder::Result<KeyCreationResult> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn importWrappedKey(
+            &self,
+            _: &[u8],
+            _: &[u8],
+            _: &[u8],
+            _: &[KmKeyParameter],
+            _: i64,
+            _: i64,
+        ) -> binder::Result<KeyCreationResult> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn upgradeKey(&self, key_blob: &[u8], _: &[KmKeyParameter]) -> binder::Result<Vec<u8>> {
+            self.calls.lock().unwrap().push(KmCall::Upgrade(key_blob.to_vec()));
+            match key_blob.strip_prefix(b"old:") {
+                Some(key) => Ok([b"new:", key].concat()),
+                None => Self::km_error(ErrorCode::INVALID_KEY_BLOB),
+            }
+        }
+        fn deleteKey(&self, _: &[u8]) -> binder::Result<()> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn deleteAllKeys(&self) -> binder::Result<()> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn destroyAttestationIds(&self) -> binder::Result<()> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn begin(
+            &self,
+            _: KeyPurpose,
+            _: &[u8],
+            _: &[KmKeyParameter],
+            _: Option<&HardwareAuthToken>,
+        ) -> binder::Result<BeginResult> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn deviceLocked(&self, _: bool, _: Option<&TimeStampToken>) -> binder::Result<()> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn earlyBootEnded(&self) -> binder::Result<()> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn convertStorageKeyToEphemeral(&self, _: &[u8]) -> binder::Result<Vec<u8>> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn getKeyCharacteristics(
+            &self,
+            _: &[u8],
+            _: &[u8],
+            _: &[u8],
+        ) -> binder::Result<Vec<KeyCharacteristics>> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn getRootOfTrustChallenge(&self) -> binder::Result<[u8; 16]> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn getRootOfTrust(&self, _: &[u8; 16]) -> binder::Result<Vec<u8>> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+        fn sendRootOfTrust(&self, _: &[u8]) -> binder::Result<()> {
+            Self::km_error(ErrorCode::UNIMPLEMENTED)
+        }
+    }
+
+    /// Runs `upgrade_keyblob_if_required_with` on `key_blob` against `km_dev`. Returns the
+    /// result and the blob that was passed to the new blob handler, if it was called.
+    fn upgrade_with(
+        km_dev: &FakeKeyMint,
+        km_dev_version: i32,
+        key_blob: &[u8],
+    ) -> (Result<Option<Vec<u8>>>, Option<Vec<u8>>) {
+        let mut handled_blob = None;
+        let result = upgrade_keyblob_if_required_with(
+            km_dev,
+            km_dev_version,
+            key_blob,
+            &[],
+            |blob| km_dev.op(blob),
+            |new_blob| {
+                handled_blob = Some(new_blob.to_vec());
+                Ok(())
+            },
+        )
+        .map(|((), upgraded_blob)| upgraded_blob);
+        (result, handled_blob)
+    }
+
+    fn km_error_of<T>(result: &Result<T>) -> Option<ErrorCode> {
+        match result.as_ref().err().and_then(|e| e.root_cause().downcast_ref::<Error>()) {
+            Some(Error::Km(ec)) => Some(*ec),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_upgrade_keyblob_not_required() -> Result<()> {
+        let km_dev = FakeKeyMint::default();
+        let (result, handled_blob) = upgrade_with(&km_dev, KeyMintDevice::KEY_MINT_V1, b"new:key");
+        assert_eq!(None, result?);
+        assert_eq!(None, handled_blob);
+        assert_eq!(vec![KmCall::Op(b"new:key".to_vec())], km_dev.take_calls());
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_keyblob_requires_upgrade() -> Result<()> {
+        let km_dev = FakeKeyMint::default();
+        let (result, handled_blob) = upgrade_with(&km_dev, KeyMintDevice::KEY_MINT_V1, b"old:key");
+        assert_eq!(Some(b"new:key".to_vec()), result?);
+        assert_eq!(Some(b"new:key".to_vec()), handled_blob);
+        assert_eq!(
+            vec![
+                KmCall::Op(b"old:key".to_vec()),
+                KmCall::Upgrade(b"old:key".to_vec()),
+                KmCall::Op(b"new:key".to_vec()),
+            ],
+            km_dev.take_calls()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_keyblob_strips_km_compat_prefix() -> Result<()> {
+        let key_blob = [km_compat::KEYMASTER_BLOB_HW_PREFIX, b"old:key"].concat();
+        for version in [KeyMintDevice::KEY_MINT_V1, KeyMintDevice::KEY_MINT_V3] {
+            let km_dev = FakeKeyMint::default();
+            let (result, handled_blob) = upgrade_with(&km_dev, version, &key_blob);
+            assert_eq!(Some(b"new:key".to_vec()), result?);
+            assert_eq!(Some(b"new:key".to_vec()), handled_blob);
+            assert_eq!(
+                vec![
+                    KmCall::Op(key_blob.clone()),
+                    KmCall::Upgrade(b"old:key".to_vec()),
+                    KmCall::Op(b"new:key".to_vec()),
+                ],
+                km_dev.take_calls()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_keyblob_strip_then_upgrade_fails() {
+        let key_blob = [km_compat::KEYMASTER_BLOB_HW_PREFIX, b"garbage"].concat();
+        let km_dev = FakeKeyMint::default();
+        let (result, handled_blob) = upgrade_with(&km_dev, KeyMintDevice::KEY_MINT_V1, &key_blob);
+        assert_eq!(Some(ErrorCode::INVALID_KEY_BLOB), km_error_of(&result));
+        assert_eq!(None, handled_blob);
+        assert_eq!(
+            vec![KmCall::Op(key_blob), KmCall::Upgrade(b"garbage".to_vec())],
+            km_dev.take_calls()
+        );
+    }
+
+    #[test]
+    fn test_upgrade_keyblob_keeps_software_emulated_prefix() {
+        // Software-emulated Keymaster keys are not owned by the KeyMint device, so stripping
+        // the prefix would hand it a blob that it cannot upgrade.
+        let key_blob = [km_compat::KEYMASTER_BLOB_SW_PREFIX, b"old:key"].concat();
+        let km_dev = FakeKeyMint::default();
+        let (result, handled_blob) = upgrade_with(&km_dev, KeyMintDevice::KEY_MINT_V1, &key_blob);
+        assert_eq!(Some(ErrorCode::INVALID_KEY_BLOB), km_error_of(&result));
+        assert_eq!(None, handled_blob);
+        assert_eq!(vec![KmCall::Op(key_blob)], km_dev.take_calls());
+    }
+
+    #[test]
+    fn test_upgrade_keyblob_keeps_prefix_for_keymaster() {
+        // A Keymaster device is wrapped by km_compat, which strips the prefix itself.
+        let key_blob = [km_compat::KEYMASTER_BLOB_HW_PREFIX, b"old:key"].concat();
+        let km_dev = FakeKeyMint::default();
+        let (result, handled_blob) =
+            upgrade_with(&km_dev, KeyMintDevice::KEY_MASTER_V4_1, &key_blob);
+        assert_eq!(Some(ErrorCode::INVALID_KEY_BLOB), km_error_of(&result));
+        assert_eq!(None, handled_blob);
+        assert_eq!(vec![KmCall::Op(key_blob)], km_dev.take_calls());
+    }
 }
@@ -29,8 +29,9 @@ use std::{
     time::{Duration, Instant},
 };
 
-/// Represents a Watchdog record. It can be created with `Watchdog::watch` or
-/// `Watchdog::watch_with`. It disarms the record when dropped.
+/// Represents a Watchdog record. It can be created with `Watchdog::watch`,
+/// `Watchdog::watch_with`, or `Watchdog::watch_with_escalation`. It disarms the record when
+/// dropped.
 pub struct WatchPoint {
     id: &'static str,
     wd: Arc<Watchdog>,
@@ -55,10 +56,18 @@ struct Index {
     id: &'static str,
 }
 
+/// An action that the watchdog thread runs once when a watch point is still armed at its hard
+/// deadline.
+struct Escalation {
+    hard_deadline: Instant,
+    action: Box<dyn FnOnce() + Send + 'static>,
+}
+
 struct Record {
     started: Instant,
     deadline: Instant,
     callback: Option<Box<dyn Fn() -> String + Send + 'static>>,
+    escalation: Option<Escalation>,
 }
 
 struct WatchdogState {
@@ -76,6 +85,14 @@ impl WatchdogState {
         let mut next_timeout: Option<Duration> = None;
         let mut has_overdue = false;
         for (_, r) in self.records.iter() {
+            // Pending escalations must wake the thread at their hard deadline, even if the
+            // record is already overdue.
+            if let Some(escalation) = &r.escalation {
+                let timeout = escalation.hard_deadline.saturating_duration_since(now);
+                if timeout != Duration::new(0, 0) {
+                    next_timeout = Some(next_timeout.map_or(timeout, |nt| min(nt, timeout)));
+                }
+            }
             let timeout = r.deadline.saturating_duration_since(now);
             if timeout == Duration::new(0, 0) {
                 has_overdue = true;
@@ -95,6 +112,21 @@ impl WatchdogState {
         (has_overdue, next_timeout)
     }
 
+    /// Removes the escalations whose hard deadline has passed from their records and returns
+    /// their actions. The records stay armed, so that they are still reported.
+    fn take_due_escalations(&mut self) -> Vec<(Index, Box<dyn FnOnce() + Send + 'static>)> {
+        let now = Instant::now();
+        self.records
+            .iter_mut()
+            .filter_map(|(i, r)| match &r.escalation {
+                Some(e) if e.hard_deadline <= now => {
+                    r.escalation.take().map(|e| (i.clone(), e.action))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
     fn log_report(&mut self, has_overdue: bool) -> bool {
         match (self.has_overdue, has_overdue) {
             (true, true) => {
@@ -221,16 +253,29 @@ impl Watchdog {
     fn watch_with_optional(
         wd: &Arc<Self>,
         callback: Option<Box<dyn Fn() -> String + Send + 'static>>,
+        escalation: Option<(Duration, Box<dyn FnOnce() + Send + 'static>)>,
         id: &'static str,
         timeout: Duration,
     ) -> Option<WatchPoint> {
-        let deadline = Instant::now().checked_add(timeout);
+        let now = Instant::now();
+        let deadline = now.checked_add(timeout);
         if deadline.is_none() {
             log::warn!("Deadline computation failed for WatchPoint \"{}\"", id);
             log::warn!("WatchPoint not armed.");
             return None;
         }
-        wd.arm(callback, id, deadline.unwrap());
+        let escalation = match escalation {
+            Some((hard_limit, action)) => match now.checked_add(hard_limit) {
+                Some(hard_deadline) => Some(Escalation { hard_deadline, action }),
+                None => {
+                    log::warn!("Hard deadline computation failed for WatchPoint \"{}\"", id);
+                    log::warn!("WatchPoint armed without escalation.");
+                    None
+                }
+            },
+            None => None,
+        };
+        wd.arm(callback, escalation, id, deadline.unwrap());
         Some(WatchPoint { id, wd: wd.clone(), not_send: Default::default() })
     }
 
@@ -243,23 +288,45 @@ impl Watchdog {
         timeout: Duration,
         callback: impl Fn() -> String + Send + 'static,
     ) -> Option<WatchPoint> {
-        Self::watch_with_optional(wd, Some(Box::new(callback)), id, timeout)
+        Self::watch_with_optional(wd, Some(Box::new(callback)), None, id, timeout)
+    }
+
+    /// Like `watch_with`, but if the WatchPoint is still not dropped when `hard_limit` has
+    /// elapsed, the watchdog thread calls `escalation` once. The escalation runs on the
+    /// watchdog thread without holding the watchdog state, so it may arm and disarm watch
+    /// points, but it must not block for long, because it delays all reports.
+    pub fn watch_with_escalation(
+        wd: &Arc<Self>,
+        id: &'static str,
+        timeout: Duration,
+        callback: impl Fn() -> String + Send + 'static,
+        hard_limit: Duration,
+        escalation: impl FnOnce() + Send + 'static,
+    ) -> Option<WatchPoint> {
+        Self::watch_with_optional(
+            wd,
+            Some(Box::new(callback)),
+            Some((hard_limit, Box::new(escalation))),
+            id,
+            timeout,
+        )
     }
 
     /// Like `watch_with`, but without a callback.
     pub fn watch(wd: &Arc<Self>, id: &'static str, timeout: Duration) -> Option<WatchPoint> {
-        Self::watch_with_optional(wd, None, id, timeout)
+        Self::watch_with_optional(wd, None, None, id, timeout)
     }
 
     fn arm(
         &self,
         callback: Option<Box<dyn Fn() -> String + Send + 'static>>,
+        escalation: Option<Escalation>,
         id: &'static str,
         deadline: Instant,
     ) {
         let tid = thread::current().id();
         let index = Index { tid, id };
-        let record = Record { started: Instant::now(), deadline, callback };
+        let record = Record { started: Instant::now(), deadline, callback, escalation };
 
         let (ref condvar, ref state) = *self.state;
 
@@ -292,11 +359,22 @@ impl Watchdog {
         let cloned_state = self.state.clone();
 
         state.thread = Some(thread::spawn(move || {
-            let (ref condvar, ref state) = *cloned_state;
+            let (ref condvar, ref state_mutex) = *cloned_state;
 
-            let mut state = state.lock().unwrap();
+            let mut state = state_mutex.lock().unwrap();
 
             loop {
+                let escalations = state.take_due_escalations();
+                if !escalations.is_empty() {
+                    // Escalations may take locks that the stuck thread is waiting for, so they
+                    // must not run with the state locked.
+                    drop(state);
+                    for (index, action) in escalations {
+                        log::error!("Escalating watch point {:?} {}.", index.tid, index.id);
+                        action();
+                    }
+                    state = state_mutex.lock().unwrap();
+                }
                 let (has_overdue, next_timeout) = state.update_overdue_and_find_next_timeout();
                 state.log_report(has_overdue);
                 let (next_timeout, idle) = match (has_overdue, next_timeout) {
@@ -357,4 +435,43 @@ mod tests {
         let state = state.lock().unwrap();
         assert_eq!(state.state, State::NotRunning);
     }
+
+    #[test]
+    fn test_escalation() {
+        let wd = Watchdog::new(Watchdog::NOISY_REPORT_TIMEOUT);
+        let escalations = Arc::new(atomic::AtomicU8::new(0));
+
+        let escalations_clone = escalations.clone();
+        let wp = Watchdog::watch_with_escalation(
+            &wd,
+            "test_escalation_stuck",
+            Duration::from_millis(50),
+            || String::new(),
+            Duration::from_millis(200),
+            move || {
+                escalations_clone.fetch_add(1, atomic::Ordering::Relaxed);
+            },
+        );
+        let escalations_clone = escalations.clone();
+        let returned = Watchdog::watch_with_escalation(
+            &wd,
+            "test_escalation_returned",
+            Duration::from_millis(50),
+            || String::new(),
+            Duration::from_millis(200),
+            move || {
+                escalations_clone.fetch_add(10, atomic::Ordering::Relaxed);
+            },
+        );
+        thread::sleep(Duration::from_millis(100));
+        // Overdue watch points are only reported before their hard limit.
+        assert_eq!(0, escalations.load(atomic::Ordering::Relaxed));
+        drop(returned);
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(1, escalations.load(atomic::Ordering::Relaxed));
+        // The escalation runs once, even though the watch point is still reported.
+        thread::sleep(Watchdog::NOISY_REPORT_TIMEOUT.checked_mul(2).unwrap());
+        assert_eq!(1, escalations.load(atomic::Ordering::Relaxed));
+        drop(wp);
+    }
 }
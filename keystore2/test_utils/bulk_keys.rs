@@ -0,0 +1,348 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module generates large numbers of keys for load and pagination tests.
+//!
+//! `generate_keys` spreads the keys of a `BulkKeySpec` round robin over its algorithms and
+//! namespaces, and generates them in batches on several worker threads, because Keystore has
+//! no batch generation API. The returned `BulkKeys` deletes all generated keys when it is
+//! dropped, including when the test panics. If generation fails part way, the keys generated
+//! so far are deleted before the error is returned. If a worker thread panics, all keys of the
+//! spec are deleted before the panic is propagated.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
+    KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
+};
+use binder::Strong;
+
+use crate::authorizations::AuthSetBuilder;
+use crate::key_generations::{map_ks_error, Error};
+
+/// Key types that `generate_keys` can generate. They are chosen to be cheap to generate, except
+/// for `Rsa2048`, which can be used to put load on the TEE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkKeyAlgorithm {
+    /// AES-128 encryption key.
+    Aes128,
+    /// HMAC-SHA256 key.
+    HmacSha256,
+    /// EC P-256 signing key.
+    EcP256,
+    /// RSA-2048 signing key.
+    Rsa2048,
+}
+
+impl BulkKeyAlgorithm {
+    fn gen_params(self) -> AuthSetBuilder {
+        let gen_params = AuthSetBuilder::new().no_auth_required();
+        match self {
+            Self::Aes128 => gen_params
+                .algorithm(Algorithm::AES)
+                .purpose(KeyPurpose::ENCRYPT)
+                .purpose(KeyPurpose::DECRYPT)
+                .key_size(128)
+                .block_mode(BlockMode::ECB)
+                .padding_mode(PaddingMode::PKCS7),
+            Self::HmacSha256 => gen_params
+                .algorithm(Algorithm::HMAC)
+                .purpose(KeyPurpose::SIGN)
+                .purpose(KeyPurpose::VERIFY)
+                .key_size(256)
+                .digest(Digest::SHA_2_256)
+                .min_mac_length(256),
+            Self::EcP256 => gen_params
+                .algorithm(Algorithm::EC)
+                .purpose(KeyPurpose::SIGN)
+                .purpose(KeyPurpose::VERIFY)
+                .digest(Digest::SHA_2_256)
+                .ec_curve(EcCurve::P_256),
+            Self::Rsa2048 => gen_params
+                .algorithm(Algorithm::RSA)
+                .purpose(KeyPurpose::SIGN)
+                .purpose(KeyPurpose::VERIFY)
+                .digest(Digest::SHA_2_256)
+                .padding_mode(PaddingMode::RSA_PKCS1_1_5_SIGN)
+                .rsa_public_exponent(65537)
+                .key_size(2048),
+        }
+    }
+}
+
+/// Describes the keys that `generate_keys` generates.
+#[derive(Debug, Clone)]
+pub struct BulkKeySpec {
+    /// Total number of keys.
+    pub count: usize,
+    /// Algorithms of the keys. Key *i* uses algorithm *i* modulo the number of algorithms.
+    pub algorithms: Vec<BulkKeyAlgorithm>,
+    /// Domains and namespaces of the keys, assigned like the algorithms. Domain::APP keys use
+    /// the caller's namespace, so their namespace should be -1.
+    pub namespaces: Vec<(Domain, i64)>,
+    /// Aliases are of the form `<alias_prefix>_<i>`.
+    pub alias_prefix: String,
+    /// Number of worker threads that generate keys concurrently.
+    pub threads: usize,
+}
+
+impl BulkKeySpec {
+    /// Describes `count` AES keys in the caller's app namespace, generated on four threads.
+    pub fn new(alias_prefix: &str, count: usize) -> Self {
+        Self {
+            count,
+            algorithms: vec![BulkKeyAlgorithm::Aes128],
+            namespaces: vec![(Domain::APP, -1)],
+            alias_prefix: alias_prefix.to_string(),
+            threads: 4,
+        }
+    }
+
+    /// Returns the algorithm and descriptor of each key.
+    fn plan(&self) -> Vec<(BulkKeyAlgorithm, KeyDescriptor)> {
+        assert!(!self.algorithms.is_empty(), "BulkKeySpec needs at least one algorithm.");
+        assert!(!self.namespaces.is_empty(), "BulkKeySpec needs at least one namespace.");
+        (0..self.count)
+            .map(|i| {
+                let (domain, nspace) = self.namespaces[i % self.namespaces.len()];
+                let key = KeyDescriptor {
+                    domain,
+                    nspace,
+                    alias: Some(format!("{}_{}", self.alias_prefix, i)),
+                    blob: None,
+                };
+                (self.algorithms[i % self.algorithms.len()], key)
+            })
+            .collect()
+    }
+}
+
+/// Generation time of the keys of one algorithm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlgorithmTiming {
+    /// The algorithm.
+    pub algorithm: BulkKeyAlgorithm,
+    /// Number of generated keys.
+    pub count: usize,
+    /// Sum of the generation times of the keys, across all worker threads.
+    pub total: Duration,
+    /// Longest generation time of a single key.
+    pub max: Duration,
+}
+
+/// Timing report of a bulk generation. It is logged by `generate_keys` and can be printed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BulkKeyReport {
+    /// Wall clock time of the whole generation.
+    pub elapsed: Duration,
+    /// Per algorithm timings, in the order of first use.
+    pub algorithms: Vec<AlgorithmTiming>,
+}
+
+impl BulkKeyReport {
+    fn add(&mut self, algorithm: BulkKeyAlgorithm, duration: Duration) {
+        let pos = match self.algorithms.iter().position(|t| t.algorithm == algorithm) {
+            Some(pos) => pos,
+            None => {
+                self.algorithms.push(AlgorithmTiming {
+                    algorithm,
+                    count: 0,
+                    total: Duration::ZERO,
+                    max: Duration::ZERO,
+                });
+                self.algorithms.len() - 1
+            }
+        };
+        let timing = &mut self.algorithms[pos];
+        timing.count += 1;
+        timing.total += duration;
+        timing.max = timing.max.max(duration);
+    }
+
+    /// Returns the total number of generated keys.
+    pub fn count(&self) -> usize {
+        self.algorithms.iter().map(|t| t.count).sum()
+    }
+}
+
+impl fmt::Display for BulkKeyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let count = self.count();
+        let secs = self.elapsed.as_secs_f64();
+        let rate = if secs > 0.0 { count as f64 / secs } else { 0.0 };
+        write!(f, "Generated {} keys in {:?} ({:.1} keys/s)", count, self.elapsed, rate)?;
+        for t in &self.algorithms {
+            let avg = t.total / t.count.max(1) as u32;
+            write!(f, "\n  {:?}: {} keys, avg {:?}, max {:?}", t.algorithm, t.count, avg, t.max)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keys generated by `generate_keys`. All keys are deleted when this is dropped.
+pub struct BulkKeys {
+    keystore2: Strong<dyn IKeystoreService>,
+    keys: Vec<KeyDescriptor>,
+    report: BulkKeyReport,
+}
+
+impl BulkKeys {
+    /// Returns the descriptors of the generated keys in the order of the spec.
+    pub fn keys(&self) -> &[KeyDescriptor] {
+        &self.keys
+    }
+
+    /// Returns the timing report of the generation.
+    pub fn report(&self) -> &BulkKeyReport {
+        &self.report
+    }
+}
+
+impl Drop for BulkKeys {
+    fn drop(&mut self) {
+        delete_keys(&self.keystore2, &self.keys);
+    }
+}
+
+/// Deletes `keys`, ignoring keys that do not exist, and logs all other failures.
+fn delete_keys(keystore2: &Strong<dyn IKeystoreService>, keys: &[KeyDescriptor]) {
+    for key in keys {
+        match map_ks_error(keystore2.deleteKey(key)) {
+            Ok(()) | Err(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => {}
+            Err(e) => log::error!("Failed to delete {:?}: {:?}", key.alias, e),
+        }
+    }
+}
+
+/// Generates the keys described by `spec` on `sec_level`. Returns the first error if any key
+/// could not be generated, after deleting the keys that were generated.
+pub fn generate_keys(
+    keystore2: &Strong<dyn IKeystoreService>,
+    sec_level: &Strong<dyn IKeystoreSecurityLevel>,
+    spec: &BulkKeySpec,
+) -> Result<BulkKeys, Error> {
+    let plan = spec.plan();
+    let batch_size = plan.len().div_ceil(spec.threads.max(1)).max(1);
+    let start = Instant::now();
+    let batches: Vec<std::thread::Result<Vec<Result<_, Error>>>> = std::thread::scope(|s| {
+        let workers: Vec<_> = plan
+            .chunks(batch_size)
+            .map(|batch| {
+                s.spawn(move || {
+                    batch
+                        .iter()
+                        .map(|(algorithm, key)| -> Result<_, Error> {
+                            let key_start = Instant::now();
+                            let metadata = map_ks_error(sec_level.generateKey(
+                                key,
+                                None,
+                                &algorithm.gen_params(),
+                                0,
+                                b"entropy",
+                            ))?;
+                            Ok((metadata.key, *algorithm, key_start.elapsed()))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join()).collect()
+    });
+    let batches = match batches.into_iter().collect::<std::thread::Result<Vec<_>>>() {
+        Ok(batches) => batches,
+        Err(panic) => {
+            // The keys that the panicking worker generated are unknown, so all keys of the plan
+            // are deleted. Keys that were never generated are skipped by `delete_keys`.
+            let keys: Vec<KeyDescriptor> = plan.into_iter().map(|(_, key)| key).collect();
+            delete_keys(keystore2, &keys);
+            std::panic::resume_unwind(panic);
+        }
+    };
+    let mut report = BulkKeyReport { elapsed: start.elapsed(), algorithms: Vec::new() };
+
+    let mut keys = Vec::with_capacity(plan.len());
+    let mut first_error = None;
+    for result in batches.into_iter().flatten() {
+        match result {
+            Ok((key, algorithm, duration)) => {
+                report.add(algorithm, duration);
+                keys.push(key);
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = first_error {
+        delete_keys(keystore2, &keys);
+        return Err(e);
+    }
+    log::info!("{}", report);
+    Ok(BulkKeys { keystore2: keystore2.clone(), keys, report })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_spreads_keys_round_robin() {
+        let spec = BulkKeySpec {
+            algorithms: vec![BulkKeyAlgorithm::Aes128, BulkKeyAlgorithm::EcP256],
+            namespaces: vec![(Domain::APP, -1), (Domain::SELINUX, 100), (Domain::SELINUX, 101)],
+            ..BulkKeySpec::new("bulk", 7)
+        };
+        let plan = spec.plan();
+        assert_eq!(7, plan.len());
+        assert_eq!(
+            vec![
+                (BulkKeyAlgorithm::Aes128, Domain::APP, -1, "bulk_0"),
+                (BulkKeyAlgorithm::EcP256, Domain::SELINUX, 100, "bulk_1"),
+                (BulkKeyAlgorithm::Aes128, Domain::SELINUX, 101, "bulk_2"),
+                (BulkKeyAlgorithm::EcP256, Domain::APP, -1, "bulk_3"),
+            ],
+            plan[..4]
+                .iter()
+                .map(|(a, k)| (*a, k.domain, k.nspace, k.alias.as_deref().unwrap()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn report_aggregates_per_algorithm() {
+        let mut report = BulkKeyReport { elapsed: Duration::from_secs(2), ..Default::default() };
+        report.add(BulkKeyAlgorithm::Rsa2048, Duration::from_millis(300));
+        report.add(BulkKeyAlgorithm::Aes128, Duration::from_millis(10));
+        report.add(BulkKeyAlgorithm::Rsa2048, Duration::from_millis(100));
+        assert_eq!(3, report.count());
+        assert_eq!(
+            AlgorithmTiming {
+                algorithm: BulkKeyAlgorithm::Rsa2048,
+                count: 2,
+                total: Duration::from_millis(400),
+                max: Duration::from_millis(300),
+            },
+            report.algorithms[0]
+        );
+        let text = report.to_string();
+        assert!(text.starts_with("Generated 3 keys in 2s (1.5 keys/s)"), "{}", text);
+        assert!(text.contains("Rsa2048: 2 keys, avg 200ms, max 300ms"), "{}", text);
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module enumerates the KeyMint and Keymaster devices that back the security levels of
+//! Keystore 2.0 and runs client tests against each of them. Keymaster devices are reached
+//! through the km_compat service, which also reports their HAL version.
+//!
+//! A test that does not apply to a device returns `MatrixOutcome::Skipped` with a reason.
+//! Skips are logged per device, so that a test run shows which combinations were not covered.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    IKeyMintDevice::IKeyMintDevice, SecurityLevel::SecurityLevel,
+};
+use android_security_compat::aidl::android::security::compat::IKeystoreCompatService::IKeystoreCompatService;
+use android_system_keystore2::aidl::android::system::keystore2::IKeystoreSecurityLevel::IKeystoreSecurityLevel;
+use binder::Strong;
+use std::fmt;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+use crate::get_keystore_service;
+
+static KEYMINT_SERVICE_NAME: &str = "android.hardware.security.keymint.IKeyMintDevice";
+static COMPAT_SERVICE_NAME: &str = "android.security.compat";
+
+/// HAL versions of the devices that Keystore 2.0 supports. The order of the variants is the
+/// order of the releases, so versions can be compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyMintVersion {
+    /// Keymaster 4.0, reached through km_compat.
+    Keymaster4_0,
+    /// Keymaster 4.1, reached through km_compat.
+    Keymaster4_1,
+    /// KeyMint AIDL version 1.
+    KeyMint1,
+    /// KeyMint AIDL version 2.
+    KeyMint2,
+    /// KeyMint AIDL version 3.
+    KeyMint3,
+}
+
+impl KeyMintVersion {
+    /// Maps the version number reported by km_compat, i.e., 10 * <major> + <minor>.
+    fn from_keymaster_version(version: i32) -> Option<Self> {
+        match version {
+            40 => Some(Self::Keymaster4_0),
+            41 => Some(Self::Keymaster4_1),
+            _ => None,
+        }
+    }
+
+    /// Maps the AIDL interface version of a KeyMint device.
+    fn from_keymint_version(version: i32) -> Option<Self> {
+        match version {
+            1 => Some(Self::KeyMint1),
+            2 => Some(Self::KeyMint2),
+            3 => Some(Self::KeyMint3),
+            _ => None,
+        }
+    }
+}
+
+/// A device that backs one security level of Keystore 2.0.
+#[derive(Debug, Clone)]
+pub struct DeviceUnderTest {
+    /// The security level through which Keystore 2.0 exposes the device.
+    pub security_level: SecurityLevel,
+    /// The HAL version of the device.
+    pub version: KeyMintVersion,
+}
+
+impl DeviceUnderTest {
+    /// Returns true if the device implements at least the given HAL version.
+    pub fn is_at_least(&self, version: KeyMintVersion) -> bool {
+        self.version >= version
+    }
+
+    /// Returns true if the device is a StrongBox.
+    pub fn is_strongbox(&self) -> bool {
+        self.security_level == SecurityLevel::STRONGBOX
+    }
+}
+
+impl fmt::Display for DeviceUnderTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({:?})", self.security_level, self.version)
+    }
+}
+
+/// The outcome of a test run against a single device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatrixOutcome {
+    /// The test ran and passed. Failures are reported by panicking as in any other test.
+    Passed,
+    /// The test does not apply to the device for the given reason.
+    Skipped(String),
+}
+
+fn keymint_version(security_level: SecurityLevel) -> Option<KeyMintVersion> {
+    let instance = match security_level {
+        SecurityLevel::TRUSTED_ENVIRONMENT => "default",
+        SecurityLevel::STRONGBOX => "strongbox",
+        _ => return None,
+    };
+    let service_name = format!("{}/{}", KEYMINT_SERVICE_NAME, instance);
+    if binder::is_declared(&service_name).expect("Could not check for declared keymint interface") {
+        let keymint: Strong<dyn IKeyMintDevice> = binder::get_interface(&service_name).ok()?;
+        return KeyMintVersion::from_keymint_version(keymint.getInterfaceVersion().ok()?);
+    }
+
+    let compat: Strong<dyn IKeystoreCompatService> =
+        binder::get_interface(COMPAT_SERVICE_NAME).ok()?;
+    let keymaster = compat.getKeyMintDevice(security_level).ok()?;
+    KeyMintVersion::from_keymaster_version(keymaster.getHardwareInfo().ok()?.versionNumber)
+}
+
+/// Returns all devices that Keystore 2.0 exposes through one of its security levels.
+pub fn enumerate_devices() -> Vec<DeviceUnderTest> {
+    let keystore2 = get_keystore_service();
+    [SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX]
+        .into_iter()
+        .filter(|security_level| keystore2.getSecurityLevel(*security_level).is_ok())
+        .filter_map(|security_level| match keymint_version(security_level) {
+            Some(version) => Some(DeviceUnderTest { security_level, version }),
+            None => {
+                log::warn!("Could not determine the HAL version for {:?}.", security_level);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Runs `test` once for every device returned by `enumerate_devices`. Skipped devices are
+/// logged. If the test panics for a device, the device is logged before the panic is
+/// propagated, so that the failing combination can be identified.
+pub fn run_on_all_devices<F>(test_name: &str, test: F)
+where
+    F: Fn(&DeviceUnderTest, &Strong<dyn IKeystoreSecurityLevel>) -> MatrixOutcome,
+{
+    let keystore2 = get_keystore_service();
+    let devices = enumerate_devices();
+    assert!(!devices.is_empty(), "{}: No devices found.", test_name);
+
+    for device in devices {
+        let sec_level = keystore2.getSecurityLevel(device.security_level).unwrap();
+        match catch_unwind(AssertUnwindSafe(|| test(&device, &sec_level))) {
+            Ok(MatrixOutcome::Passed) => {}
+            Ok(MatrixOutcome::Skipped(reason)) => {
+                println!("{}: Skipped on {}: {}", test_name, device, reason);
+                log::info!("{}: Skipped on {}: {}", test_name, device, reason);
+            }
+            Err(e) => {
+                println!("{}: Failed on {}.", test_name, device);
+                log::error!("{}: Failed on {}.", test_name, device);
+                resume_unwind(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_ordered() {
+        assert!(KeyMintVersion::Keymaster4_0 < KeyMintVersion::Keymaster4_1);
+        assert!(KeyMintVersion::Keymaster4_1 < KeyMintVersion::KeyMint1);
+        assert!(KeyMintVersion::KeyMint1 < KeyMintVersion::KeyMint3);
+        assert_eq!(Some(KeyMintVersion::Keymaster4_1), KeyMintVersion::from_keymaster_version(41));
+        assert_eq!(None, KeyMintVersion::from_keymaster_version(30));
+        assert_eq!(Some(KeyMintVersion::KeyMint2), KeyMintVersion::from_keymint_version(2));
+    }
+}
@@ -366,6 +366,151 @@ pub enum Error {
     /// Error code to indicate error in getting value from attest record.
     #[error("Failed to get value from attest record.")]
     AttestRecordGetValueFailed,
+    /// Error code to indicate that a call did not complete within its timeout.
+    #[error("Call timed out.")]
+    Timeout,
+}
+
+/// Broad classes of `Error`s, so that tests and clients can react to a kind of failure without
+/// listing every error code that belongs to it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCategory {
+    /// The call may succeed if it is retried later, e.g., because the backend was busy.
+    Transient,
+    /// The caller is not allowed to perform the call, or the key is not usable in the current
+    /// device or user state.
+    Permission,
+    /// The device does not support the requested algorithm, parameter or feature.
+    Unsupported,
+    /// The arguments of the call are invalid or do not fit together.
+    InvalidInput,
+    /// Anything else. Retrying the call unchanged will not help.
+    Fatal,
+}
+
+impl Error {
+    /// Returns the category of the error.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::Rc(rc) => match *rc {
+                ResponseCode::BACKEND_BUSY
+                | ResponseCode::OPERATION_BUSY
+                | ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR
+                | ResponseCode::OUT_OF_KEYS_PENDING_INTERNET_CONNECTIVITY => {
+                    ErrorCategory::Transient
+                }
+                ResponseCode::PERMISSION_DENIED
+                | ResponseCode::LOCKED
+                | ResponseCode::UNINITIALIZED
+                | ResponseCode::KEY_PERMANENTLY_INVALIDATED => ErrorCategory::Permission,
+                ResponseCode::INVALID_ARGUMENT
+                | ResponseCode::KEY_NOT_FOUND
+                | ResponseCode::TOO_MUCH_DATA => ErrorCategory::InvalidInput,
+                _ => ErrorCategory::Fatal,
+            },
+            Error::Km(ec) => match *ec {
+                ErrorCode::TOO_MANY_OPERATIONS
+                | ErrorCode::CONCURRENT_ACCESS_CONFLICT
+                | ErrorCode::SECURE_HW_BUSY
+                | ErrorCode::SECURE_HW_COMMUNICATION_FAILED
+                | ErrorCode::KEY_RATE_LIMIT_EXCEEDED => ErrorCategory::Transient,
+                ErrorCode::KEY_USER_NOT_AUTHENTICATED
+                | ErrorCode::SECURE_HW_ACCESS_DENIED
+                | ErrorCode::DEVICE_LOCKED
+                | ErrorCode::KEY_NOT_YET_VALID
+                | ErrorCode::KEY_EXPIRED
+                | ErrorCode::KEY_MAX_OPS_EXCEEDED
+                | ErrorCode::EARLY_BOOT_ENDED
+                | ErrorCode::BOOT_LEVEL_EXCEEDED => ErrorCategory::Permission,
+                ErrorCode::UNSUPPORTED_PURPOSE
+                | ErrorCode::UNSUPPORTED_ALGORITHM
+                | ErrorCode::UNSUPPORTED_KEY_SIZE
+                | ErrorCode::UNSUPPORTED_BLOCK_MODE
+                | ErrorCode::UNSUPPORTED_MAC_LENGTH
+                | ErrorCode::UNSUPPORTED_PADDING_MODE
+                | ErrorCode::UNSUPPORTED_DIGEST
+                | ErrorCode::UNSUPPORTED_KEY_FORMAT
+                | ErrorCode::UNSUPPORTED_KEY_ENCRYPTION_ALGORITHM
+                | ErrorCode::UNSUPPORTED_KEY_VERIFICATION_ALGORITHM
+                | ErrorCode::UNSUPPORTED_TAG
+                | ErrorCode::UNSUPPORTED_EC_FIELD
+                | ErrorCode::UNSUPPORTED_MIN_MAC_LENGTH
+                | ErrorCode::UNSUPPORTED_KDF
+                | ErrorCode::UNSUPPORTED_EC_CURVE
+                | ErrorCode::UNSUPPORTED_MGF_DIGEST
+                | ErrorCode::STORAGE_KEY_UNSUPPORTED
+                | ErrorCode::CANNOT_ATTEST_IDS
+                | ErrorCode::ROLLBACK_RESISTANCE_UNAVAILABLE
+                | ErrorCode::HARDWARE_TYPE_UNAVAILABLE
+                | ErrorCode::UNIMPLEMENTED => ErrorCategory::Unsupported,
+                ErrorCode::INCOMPATIBLE_PURPOSE
+                | ErrorCode::INCOMPATIBLE_ALGORITHM
+                | ErrorCode::INCOMPATIBLE_BLOCK_MODE
+                | ErrorCode::INCOMPATIBLE_PADDING_MODE
+                | ErrorCode::INCOMPATIBLE_DIGEST
+                | ErrorCode::INCOMPATIBLE_KEY_FORMAT
+                | ErrorCode::INCOMPATIBLE_MGF_DIGEST
+                | ErrorCode::INVALID_EXPIRATION_TIME
+                | ErrorCode::INVALID_USER_ID
+                | ErrorCode::INVALID_AUTHORIZATION_TIMEOUT
+                | ErrorCode::INVALID_INPUT_LENGTH
+                | ErrorCode::INVALID_ARGUMENT
+                | ErrorCode::INVALID_TAG
+                | ErrorCode::INVALID_NONCE
+                | ErrorCode::INVALID_MAC_LENGTH
+                | ErrorCode::INVALID_ISSUER_SUBJECT
+                | ErrorCode::IMPORT_PARAMETER_MISMATCH
+                | ErrorCode::CALLER_NONCE_PROHIBITED
+                | ErrorCode::MISSING_NONCE
+                | ErrorCode::MISSING_MAC_LENGTH
+                | ErrorCode::MISSING_MIN_MAC_LENGTH
+                | ErrorCode::MISSING_NOT_BEFORE
+                | ErrorCode::MISSING_NOT_AFTER
+                | ErrorCode::MISSING_ISSUER_SUBJECT
+                | ErrorCode::ATTESTATION_CHALLENGE_MISSING
+                | ErrorCode::ATTESTATION_APPLICATION_ID_MISSING => ErrorCategory::InvalidInput,
+                _ => ErrorCategory::Fatal,
+            },
+            Error::Binder(ExceptionCode::TRANSACTION_FAILED) | Error::Timeout => {
+                ErrorCategory::Transient
+            }
+            Error::Binder(ExceptionCode::SECURITY) => ErrorCategory::Permission,
+            Error::Binder(ExceptionCode::UNSUPPORTED_OPERATION) => ErrorCategory::Unsupported,
+            Error::Binder(ExceptionCode::ILLEGAL_ARGUMENT)
+            | Error::Binder(ExceptionCode::NULL_POINTER) => ErrorCategory::InvalidInput,
+            Error::Binder(_)
+            | Error::ValidateCertChainFailed
+            | Error::DerEncodeFailed
+            | Error::Keystore2EngineOpFailed
+            | Error::ValidateAttestIdFailed
+            | Error::AttestRecordGetValueFailed => ErrorCategory::Fatal,
+        }
+    }
+
+    /// Returns true if retrying the call later may succeed.
+    pub fn is_transient(&self) -> bool {
+        self.category() == ErrorCategory::Transient
+    }
+
+    /// Returns true if the caller was not allowed to perform the call.
+    pub fn is_permission_denied(&self) -> bool {
+        self.category() == ErrorCategory::Permission
+    }
+
+    /// Returns true if the device does not support what was requested.
+    pub fn is_unsupported(&self) -> bool {
+        self.category() == ErrorCategory::Unsupported
+    }
+
+    /// Returns true if the arguments of the call were rejected.
+    pub fn is_invalid_input(&self) -> bool {
+        self.category() == ErrorCategory::InvalidInput
+    }
+
+    /// Returns true if the error falls into no other category.
+    pub fn is_fatal(&self) -> bool {
+        self.category() == ErrorCategory::Fatal
+    }
 }
 
 /// Keystore2 error mapping.
@@ -1460,3 +1605,32 @@ pub fn create_key_and_operation(
 
     sec_level.createOperation(&key_metadata.key, op_params, false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_categories() {
+        assert!(Error::Rc(ResponseCode::BACKEND_BUSY).is_transient());
+        assert!(Error::Km(ErrorCode::TOO_MANY_OPERATIONS).is_transient());
+        assert!(Error::Timeout.is_transient());
+        assert!(Error::Rc(ResponseCode::PERMISSION_DENIED).is_permission_denied());
+        assert!(Error::Km(ErrorCode::KEY_USER_NOT_AUTHENTICATED).is_permission_denied());
+        assert!(Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE).is_unsupported());
+        assert!(Error::Binder(ExceptionCode::UNSUPPORTED_OPERATION).is_unsupported());
+        assert!(Error::Rc(ResponseCode::INVALID_ARGUMENT).is_invalid_input());
+        assert!(Error::Km(ErrorCode::INCOMPATIBLE_DIGEST).is_invalid_input());
+        assert!(Error::Rc(ResponseCode::SYSTEM_ERROR).is_fatal());
+        assert!(Error::Km(ErrorCode::UNKNOWN_ERROR).is_fatal());
+        assert!(Error::DerEncodeFailed.is_fatal());
+
+        // Service specific errors are categorized after they are mapped.
+        let busy: BinderResult<()> =
+            Err(binder::Status::new_service_specific_error(ResponseCode::OPERATION_BUSY.0, None));
+        assert!(map_ks_error(busy).unwrap_err().is_transient());
+        let unsupported: BinderResult<()> =
+            Err(binder::Status::new_service_specific_error(ErrorCode::UNSUPPORTED_DIGEST.0, None));
+        assert_eq!(ErrorCategory::Unsupported, map_ks_error(unsupported).unwrap_err().category());
+    }
+}
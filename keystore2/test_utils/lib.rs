@@ -19,14 +19,19 @@ use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::{env::temp_dir, ops::Deref};
 
+use android_security_keystoreextension::aidl::android::security::keystoreextension::IKeystoreServiceExtension::IKeystoreServiceExtension;
 use android_system_keystore2::aidl::android::system::keystore2::IKeystoreService::IKeystoreService;
 
 pub mod authorizations;
+pub mod bulk_keys;
+pub mod device_matrix;
 pub mod ffi_test_utils;
 pub mod key_generations;
 pub mod run_as;
+pub mod timeouts;
 
 static KS2_SERVICE_NAME: &str = "android.system.keystore2.IKeystoreService/default";
+static KS2_EXTENSION_SERVICE_NAME: &str = "android.security.keystoreextension";
 
 /// Represents the lifecycle of a temporary directory for testing.
 #[derive(Debug)]
@@ -116,3 +121,8 @@ impl Deref for PathBuilder {
 pub fn get_keystore_service() -> binder::Strong<dyn IKeystoreService> {
     binder::get_interface(KS2_SERVICE_NAME).unwrap()
 }
+
+/// Get the platform only extension of the Keystore2 service.
+pub fn get_keystore_service_extension() -> binder::Strong<dyn IKeystoreServiceExtension> {
+    binder::get_interface(KS2_EXTENSION_SERVICE_NAME).unwrap()
+}
@@ -0,0 +1,248 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module provides timeout aware variants of the key generation and operation helpers.
+//! A slow or wedged TEE otherwise makes a test hang until the whole suite is killed, which
+//! hides which test and which call got stuck.
+//!
+//! Each helper comes in two variants. The `spawn_*` variant starts the call on a worker thread
+//! and returns a `PendingCall` right away, so that a test can issue several calls before it
+//! waits for any of them. The sync variant spawns the call and waits for it with the given
+//! timeout. If the timeout expires, the result is `Error::Timeout`. The worker thread cannot be
+//! cancelled and is left to finish or hang on its own.
+
+use std::panic::resume_unwind;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use android_system_keystore2::aidl::android::system::keystore2::{
+    CreateOperationResponse::CreateOperationResponse, IKeystoreOperation::IKeystoreOperation,
+    IKeystoreSecurityLevel::IKeystoreSecurityLevel, KeyDescriptor::KeyDescriptor,
+    KeyMetadata::KeyMetadata,
+};
+use binder::Strong;
+
+use crate::authorizations::AuthSetBuilder;
+use crate::key_generations::{self, map_ks_error, Error};
+
+/// A timeout that is generous enough for any single call on a healthy device, including
+/// RSA key generation on StrongBox.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A call that runs on a worker thread.
+pub struct PendingCall<T> {
+    name: String,
+    receiver: Receiver<Result<T, Error>>,
+    handle: JoinHandle<()>,
+}
+
+impl<T: Send + 'static> PendingCall<T> {
+    /// Starts `f` on a worker thread. `name` identifies the call in logs.
+    pub fn spawn<F>(name: &str, f: F) -> Self
+    where
+        F: FnOnce() -> Result<T, Error> + Send + 'static,
+    {
+        let (sender, receiver) = sync_channel(1);
+        let handle = std::thread::spawn(move || {
+            // The receiver is gone if the caller has stopped waiting.
+            let _ = sender.send(f());
+        });
+        Self { name: name.to_string(), receiver, handle }
+    }
+
+    /// Waits up to `timeout` for the call to complete. A panic on the worker thread, e.g., a
+    /// failed assertion in a helper, is resumed on the calling thread.
+    pub fn wait(self, timeout: Duration) -> Result<T, Error> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                log::error!("{} did not complete within {:?}.", self.name, timeout);
+                Err(Error::Timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => match self.handle.join() {
+                Err(panic) => resume_unwind(panic),
+                Ok(()) => unreachable!("{} exited without a result.", self.name),
+            },
+        }
+    }
+}
+
+/// Runs `f` on a worker thread and waits up to `timeout` for it to complete.
+pub fn with_timeout<T, F>(name: &str, timeout: Duration, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    PendingCall::spawn(name, f).wait(timeout)
+}
+
+/// Starts `key_generations::generate_key` on a worker thread.
+pub fn spawn_generate_key(
+    sec_level: &Strong<dyn IKeystoreSecurityLevel>,
+    gen_params: &AuthSetBuilder,
+    alias: &str,
+) -> PendingCall<KeyMetadata> {
+    let sec_level = sec_level.clone();
+    let gen_params = gen_params.clone();
+    let alias = alias.to_string();
+    PendingCall::spawn(&format!("generateKey({alias})"), move || {
+        map_ks_error(key_generations::generate_key(&sec_level, &gen_params, &alias))
+    })
+}
+
+/// Like `key_generations::generate_key`, but fails with `Error::Timeout` if the key is not
+/// generated within `timeout`.
+pub fn generate_key(
+    sec_level: &Strong<dyn IKeystoreSecurityLevel>,
+    gen_params: &AuthSetBuilder,
+    alias: &str,
+    timeout: Duration,
+) -> Result<KeyMetadata, Error> {
+    spawn_generate_key(sec_level, gen_params, alias).wait(timeout)
+}
+
+/// Starts `key_generations::create_key_and_operation` on a worker thread.
+pub fn spawn_create_key_and_operation(
+    sec_level: &Strong<dyn IKeystoreSecurityLevel>,
+    gen_params: &AuthSetBuilder,
+    op_params: &AuthSetBuilder,
+    alias: &str,
+) -> PendingCall<CreateOperationResponse> {
+    let sec_level = sec_level.clone();
+    let gen_params = gen_params.clone();
+    let op_params = op_params.clone();
+    let alias = alias.to_string();
+    PendingCall::spawn(&format!("createKeyAndOperation({alias})"), move || {
+        map_ks_error(key_generations::create_key_and_operation(
+            &sec_level,
+            &gen_params,
+            &op_params,
+            &alias,
+        ))
+    })
+}
+
+/// Like `key_generations::create_key_and_operation`, but fails with `Error::Timeout` if the
+/// operation is not created within `timeout`.
+pub fn create_key_and_operation(
+    sec_level: &Strong<dyn IKeystoreSecurityLevel>,
+    gen_params: &AuthSetBuilder,
+    op_params: &AuthSetBuilder,
+    alias: &str,
+    timeout: Duration,
+) -> Result<CreateOperationResponse, Error> {
+    spawn_create_key_and_operation(sec_level, gen_params, op_params, alias).wait(timeout)
+}
+
+/// Starts `IKeystoreSecurityLevel::createOperation` on a worker thread.
+pub fn spawn_create_operation(
+    sec_level: &Strong<dyn IKeystoreSecurityLevel>,
+    key: &KeyDescriptor,
+    op_params: &AuthSetBuilder,
+    forced: bool,
+) -> PendingCall<CreateOperationResponse> {
+    let sec_level = sec_level.clone();
+    let key = key.clone();
+    let op_params = op_params.clone();
+    PendingCall::spawn("createOperation", move || {
+        map_ks_error(sec_level.createOperation(&key, &op_params, forced))
+    })
+}
+
+/// Like `IKeystoreSecurityLevel::createOperation`, but fails with `Error::Timeout` if the
+/// operation is not created within `timeout`.
+pub fn create_operation(
+    sec_level: &Strong<dyn IKeystoreSecurityLevel>,
+    key: &KeyDescriptor,
+    op_params: &AuthSetBuilder,
+    forced: bool,
+    timeout: Duration,
+) -> Result<CreateOperationResponse, Error> {
+    spawn_create_operation(sec_level, key, op_params, forced).wait(timeout)
+}
+
+/// Starts an update of `op` with `input` followed by a finish on a worker thread.
+pub fn spawn_update_and_finish(
+    op: &Strong<dyn IKeystoreOperation>,
+    input: &[u8],
+) -> PendingCall<Option<Vec<u8>>> {
+    let op = op.clone();
+    let input = input.to_vec();
+    PendingCall::spawn("updateAndFinish", move || {
+        let mut output = map_ks_error(op.update(&input))?.unwrap_or_default();
+        if let Some(last) = map_ks_error(op.finish(None, None))? {
+            output.extend(last);
+        }
+        Ok(if output.is_empty() { None } else { Some(output) })
+    })
+}
+
+/// Updates `op` with `input` and finishes it. Returns the concatenated output of both calls,
+/// or `Error::Timeout` if they do not complete within `timeout`.
+pub fn update_and_finish(
+    op: &Strong<dyn IKeystoreOperation>,
+    input: &[u8],
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>, Error> {
+    spawn_update_and_finish(op, input).wait(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+
+    #[test]
+    fn call_completes_within_timeout() {
+        assert_eq!(Ok(42), with_timeout("answer", DEFAULT_TIMEOUT, || Ok(42)));
+        assert_eq!(
+            Err::<(), _>(Error::Rc(ResponseCode::KEY_NOT_FOUND)),
+            with_timeout("not found", DEFAULT_TIMEOUT, || Err(Error::Rc(
+                ResponseCode::KEY_NOT_FOUND
+            )))
+        );
+    }
+
+    #[test]
+    fn call_times_out() {
+        let result = with_timeout("sleeper", Duration::from_millis(10), || {
+            std::thread::sleep(Duration::from_secs(5));
+            Ok(())
+        });
+        assert_eq!(Err(Error::Timeout), result);
+    }
+
+    #[test]
+    fn pending_calls_run_concurrently() {
+        let calls: Vec<_> = (0..4)
+            .map(|i| {
+                PendingCall::spawn("sleeper", move || {
+                    std::thread::sleep(Duration::from_millis(200));
+                    Ok(i)
+                })
+            })
+            .collect();
+        // Sequential calls would take 800ms, so the last ones would time out.
+        let results: Vec<_> =
+            calls.into_iter().map(|c| c.wait(Duration::from_millis(600))).collect();
+        assert_eq!(vec![Ok(0), Ok(1), Ok(2), Ok(3)], results);
+    }
+
+    #[test]
+    #[should_panic(expected = "helper assertion")]
+    fn worker_panic_is_resumed() {
+        let _ = with_timeout::<(), _>("panicker", DEFAULT_TIMEOUT, || panic!("helper assertion"));
+    }
+}
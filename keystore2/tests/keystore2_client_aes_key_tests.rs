@@ -22,7 +22,10 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 
 use keystore2_test_utils::{
-    authorizations, get_keystore_service, key_generations, key_generations::Error,
+    authorizations,
+    device_matrix::{run_on_all_devices, MatrixOutcome},
+    get_keystore_service, key_generations,
+    key_generations::Error,
 };
 
 use crate::keystore2_client_test_utils::{
@@ -77,65 +80,66 @@ fn create_aes_key_and_operation(
     Ok(())
 }
 
-/// Generate AES keys with various block modes and paddings.
+/// Generate AES keys with various block modes and paddings on every available device.
 ///  - Block Modes: ECB, CBC
 ///  - Padding Modes: NONE, PKCS7
 /// Test should generate keys and perform operation successfully.
 #[test]
 fn keystore2_aes_ecb_cbc_generate_key() {
-    let keystore2 = get_keystore_service();
     let key_sizes = [128, 256];
     let block_modes = [BlockMode::ECB, BlockMode::CBC];
     let padding_modes = [PaddingMode::PKCS7, PaddingMode::NONE];
 
-    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-    for key_size in key_sizes {
-        for block_mode in block_modes {
-            for padding_mode in padding_modes {
-                assert_eq!(
-                    Ok(()),
-                    create_aes_key_and_operation(
-                        &sec_level,
-                        key_size,
-                        padding_mode,
-                        block_mode,
-                        None,
-                        None,
-                        &mut None,
-                    )
-                );
+    run_on_all_devices("keystore2_aes_ecb_cbc_generate_key", |_device, sec_level| {
+        for key_size in key_sizes {
+            for block_mode in block_modes {
+                for padding_mode in padding_modes {
+                    assert_eq!(
+                        Ok(()),
+                        create_aes_key_and_operation(
+                            sec_level,
+                            key_size,
+                            padding_mode,
+                            block_mode,
+                            None,
+                            None,
+                            &mut None,
+                        )
+                    );
+                }
             }
         }
-    }
+        MatrixOutcome::Passed
+    });
 }
 
-/// Generate AES keys with -
+/// Generate AES keys with the following parameters on every available device.
 ///  - Block Modes: `CTR, GCM`
 ///  - Padding Modes: `NONE`
 /// Test should generate keys and perform operation successfully.
 #[test]
 fn keystore2_aes_ctr_gcm_generate_key_success() {
-    let keystore2 = get_keystore_service();
     let key_sizes = [128, 256];
     let key_params = [(BlockMode::CTR, None, None), (BlockMode::GCM, Some(128), Some(128))];
 
-    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-
-    for key_size in key_sizes {
-        for (block_mode, mac_len, min_mac_len) in key_params {
-            let result = key_generations::map_ks_error(create_aes_key_and_operation(
-                &sec_level,
-                key_size,
-                PaddingMode::NONE,
-                block_mode,
-                mac_len,
-                min_mac_len,
-                &mut None,
-            ));
-
-            assert_eq!(Ok(()), result);
-        } // End of block mode.
-    } // End of key size.
+    run_on_all_devices("keystore2_aes_ctr_gcm_generate_key_success", |_device, sec_level| {
+        for key_size in key_sizes {
+            for (block_mode, mac_len, min_mac_len) in key_params {
+                let result = key_generations::map_ks_error(create_aes_key_and_operation(
+                    sec_level,
+                    key_size,
+                    PaddingMode::NONE,
+                    block_mode,
+                    mac_len,
+                    min_mac_len,
+                    &mut None,
+                ));
+
+                assert_eq!(Ok(()), result);
+            } // End of block mode.
+        } // End of key size.
+        MatrixOutcome::Passed
+    });
 }
 
 /// Generate AES keys with -
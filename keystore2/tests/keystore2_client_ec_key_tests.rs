@@ -241,7 +241,7 @@ fn keystore2_generate_key_invalid_domain() {
 }
 
 /// Try to generate a EC key without providing the curve.
-/// `UNSUPPORTED_EC_CURVE or UNSUPPORTED_KEY_SIZE` error response is expected.
+/// An unsupported error, i.e., `UNSUPPORTED_EC_CURVE` or `UNSUPPORTED_KEY_SIZE`, is expected.
 #[test]
 fn keystore2_generate_ec_key_missing_curve() {
     let keystore2 = get_keystore_service();
@@ -270,10 +270,7 @@ fn keystore2_generate_ec_key_missing_curve() {
     ));
     assert!(result.is_err());
     let err = result.unwrap_err();
-    assert!(matches!(
-        err,
-        Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE) | Error::Km(ErrorCode::UNSUPPORTED_KEY_SIZE)
-    ));
+    assert!(err.is_unsupported(), "Unexpected error: {:?}", err);
 }
 
 /// Try to generate a EC key with curve `CURVE_25519` having `SIGN and AGREE_KEY` purposes.
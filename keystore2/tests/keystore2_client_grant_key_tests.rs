@@ -29,7 +29,7 @@ use keystore2_test_utils::{
 };
 
 use crate::keystore2_client_test_utils::{
-    generate_ec_key_and_grant_to_users, perform_sample_sign_operation,
+    generate_ec_key_and_grant_to_users, perform_sample_sign_operation, BarrierReached, TestOutcome,
 };
 
 /// Generate an EC signing key and grant it to the user with given access vector.
@@ -772,3 +772,254 @@ fn keystore2_grant_key_to_multi_users_delete_fails_with_key_not_found_error() {
         )
     };
 }
+
+/// Generate a key and grant it to the user with `GET_INFO` and `USE` permissions. In grantee
+/// context begin an operation with the granted key, and ungrant the key from the owner context
+/// while the operation is in flight. The operation was authorized when it began, so it should
+/// complete successfully. Afterwards the grantee should fail to load the key through the grant
+/// with `KEY_NOT_FOUND`, and to begin an operation with the key id with `PERMISSION_DENIED`.
+#[test]
+fn keystore2_ungrant_key_with_op_in_flight() {
+    static GRANTOR_SU_CTX: &str = "u:r:su:s0";
+    static GRANTEE_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+    const USER_ID: u32 = 99;
+    const APPLICATION_ID: u32 = 10001;
+    static GRANTEE_UID: u32 = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
+    static GRANTEE_GID: u32 = GRANTEE_UID;
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    let (grant_key_nspace, key_id) = unsafe {
+        run_as::run_as(GRANTOR_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            let alias = format!("ks_ungrant_in_flight_key{}", getuid());
+            let access_vector = KeyPermission::GET_INFO.0 | KeyPermission::USE.0;
+            let grant_keys = generate_ec_key_and_grant_to_users(
+                &keystore2,
+                &sec_level,
+                Some(alias.to_string()),
+                vec![GRANTEE_UID.try_into().unwrap()],
+                access_vector,
+            )
+            .unwrap();
+
+            let key_entry_response = keystore2
+                .getKeyEntry(&KeyDescriptor {
+                    domain: Domain::SELINUX,
+                    nspace: key_generations::SELINUX_SHELL_NAMESPACE,
+                    alias: Some(alias),
+                    blob: None,
+                })
+                .unwrap();
+            assert_eq!(Domain::KEY_ID, key_entry_response.metadata.key.domain);
+
+            (grant_keys[0], key_entry_response.metadata.key.nspace)
+        })
+    };
+
+    // Begin an operation with the granted key in grantee context, and finish it once the key has
+    // been ungranted.
+    // SAFETY: The test is run in a separate process with no other threads.
+    let mut child_handle = unsafe {
+        run_as::run_as_child(
+            GRANTEE_CTX,
+            Uid::from_raw(GRANTEE_UID),
+            Gid::from_raw(GRANTEE_GID),
+            move |reader, writer| {
+                let keystore2 = get_keystore_service();
+                let sec_level =
+                    keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+                let key_entry_response = keystore2
+                    .getKeyEntry(&KeyDescriptor {
+                        domain: Domain::GRANT,
+                        nspace: grant_key_nspace,
+                        alias: None,
+                        blob: None,
+                    })
+                    .unwrap();
+                let op = sec_level
+                    .createOperation(
+                        &key_entry_response.metadata.key,
+                        &authorizations::AuthSetBuilder::new()
+                            .purpose(KeyPurpose::SIGN)
+                            .digest(Digest::SHA_2_256),
+                        false,
+                    )
+                    .unwrap()
+                    .iOperation
+                    .unwrap();
+
+                writer.send(&BarrierReached {});
+                reader.recv();
+
+                match key_generations::map_ks_error(perform_sample_sign_operation(&op)) {
+                    Ok(()) => TestOutcome::Ok,
+                    Err(e) => panic!("In-flight operation failed after ungrant: {:?}", e),
+                }
+            },
+        )
+        .expect("Failed to create a grantee child.")
+    };
+
+    // Wait until the operation has begun, then ungrant the key.
+    child_handle.recv();
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(GRANTOR_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), move || {
+            let keystore2 = get_keystore_service();
+            keystore2
+                .ungrant(
+                    &KeyDescriptor {
+                        domain: Domain::KEY_ID,
+                        nspace: key_id,
+                        alias: None,
+                        blob: None,
+                    },
+                    GRANTEE_UID.try_into().unwrap(),
+                )
+                .unwrap();
+        })
+    };
+    child_handle.send(&BarrierReached {});
+    assert_eq!(TestOutcome::Ok, child_handle.get_result());
+
+    // The grantee can no longer use the key.
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(
+            GRANTEE_CTX,
+            Uid::from_raw(GRANTEE_UID),
+            Gid::from_raw(GRANTEE_GID),
+            move || {
+                let keystore2 = get_keystore_service();
+                let sec_level =
+                    keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+                let result = key_generations::map_ks_error(keystore2.getKeyEntry(&KeyDescriptor {
+                    domain: Domain::GRANT,
+                    nspace: grant_key_nspace,
+                    alias: None,
+                    blob: None,
+                }));
+                assert!(result.is_err());
+                assert_eq!(Error::Rc(ResponseCode::KEY_NOT_FOUND), result.unwrap_err());
+
+                let result = key_generations::map_ks_error(
+                    sec_level.createOperation(
+                        &KeyDescriptor {
+                            domain: Domain::KEY_ID,
+                            nspace: key_id,
+                            alias: None,
+                            blob: None,
+                        },
+                        &authorizations::AuthSetBuilder::new()
+                            .purpose(KeyPurpose::SIGN)
+                            .digest(Digest::SHA_2_256),
+                        false,
+                    ),
+                );
+                assert!(result.is_err());
+                assert_eq!(Error::Rc(ResponseCode::PERMISSION_DENIED), result.unwrap_err());
+            },
+        )
+    };
+}
+
+/// Generate a key and grant it to the user with `GET_INFO` and `USE` permissions. In grantee
+/// context use the granted key repeatedly, while the owner ungrants the key concurrently. Every
+/// attempt should either complete its operation or fail with `KEY_NOT_FOUND` (loading the grant)
+/// or `PERMISSION_DENIED` (beginning an operation with the key id loaded before the ungrant).
+/// Once an attempt failed, all later attempts should fail with `KEY_NOT_FOUND` as well.
+#[test]
+fn keystore2_ungrant_key_races_grantee_operations() {
+    static GRANTOR_SU_CTX: &str = "u:r:su:s0";
+    static GRANTEE_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+    const USER_ID: u32 = 99;
+    const APPLICATION_ID: u32 = 10001;
+    static GRANTEE_UID: u32 = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
+    static GRANTEE_GID: u32 = GRANTEE_UID;
+    const MAX_ATTEMPTS: usize = 1000;
+    static ALIAS: &str = "ks_ungrant_race_key";
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    let grant_key_nspace = unsafe {
+        run_as::run_as(GRANTOR_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            let access_vector = KeyPermission::GET_INFO.0 | KeyPermission::USE.0;
+            let mut grant_keys = generate_ec_key_and_grant_to_users(
+                &keystore2,
+                &sec_level,
+                Some(ALIAS.to_string()),
+                vec![GRANTEE_UID.try_into().unwrap()],
+                access_vector,
+            )
+            .unwrap();
+            grant_keys.remove(0)
+        })
+    };
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    let mut child_handle = unsafe {
+        run_as::run_as_child(
+            GRANTEE_CTX,
+            Uid::from_raw(GRANTEE_UID),
+            Gid::from_raw(GRANTEE_GID),
+            move |_reader, writer| {
+                let keystore2 = get_keystore_service();
+                let sec_level =
+                    keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+                writer.send(&BarrierReached {});
+
+                for _ in 0..MAX_ATTEMPTS {
+                    match key_generations::map_ks_error(load_grant_key_and_perform_sign_operation(
+                        &keystore2,
+                        &sec_level,
+                        grant_key_nspace,
+                    )) {
+                        Ok(()) => continue,
+                        Err(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                        | Err(Error::Rc(ResponseCode::PERMISSION_DENIED)) => {
+                            // The ungrant took effect, it must stay in effect.
+                            let result = key_generations::map_ks_error(
+                                load_grant_key_and_perform_sign_operation(
+                                    &keystore2,
+                                    &sec_level,
+                                    grant_key_nspace,
+                                ),
+                            );
+                            return match result {
+                                Err(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => TestOutcome::Ok,
+                                _ => TestOutcome::OtherErr,
+                            };
+                        }
+                        Err(e) => panic!("Unexpected error while using the granted key: {:?}", e),
+                    }
+                }
+                // The ungrant never took effect.
+                TestOutcome::OtherErr
+            },
+        )
+        .expect("Failed to create a grantee child.")
+    };
+
+    // Ungrant the key while the grantee is using it.
+    child_handle.recv();
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(GRANTOR_SU_CTX, Uid::from_raw(0), Gid::from_raw(0), || {
+            let keystore2 = get_keystore_service();
+            keystore2
+                .ungrant(
+                    &KeyDescriptor {
+                        domain: Domain::SELINUX,
+                        nspace: key_generations::SELINUX_SHELL_NAMESPACE,
+                        alias: Some(ALIAS.to_string()),
+                        blob: None,
+                    },
+                    GRANTEE_UID.try_into().unwrap(),
+                )
+                .unwrap();
+        })
+    };
+    assert_eq!(TestOutcome::Ok, child_handle.get_result());
+}
@@ -31,19 +31,44 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 
 use keystore2_test_utils::{
-    authorizations, get_keystore_service, key_generations, key_generations::Error,
+    authorizations,
+    device_matrix::{run_on_all_devices, DeviceUnderTest, KeyMintVersion, MatrixOutcome},
+    get_keystore_service, key_generations,
+    key_generations::Error,
 };
 
-/// This macro is used to verify that the key agreement works for the given curve.
+/// This macro is used to verify that the key agreement works for the given curve on every
+/// available device.
 macro_rules! test_ec_key_agree {
     ( $test_name:ident, $ec_curve:expr ) => {
         #[test]
         fn $test_name() {
-            perform_ec_key_agreement($ec_curve);
+            run_on_all_devices(stringify!($test_name), |device, sec_level| {
+                if let Some(skip) = skip_unsupported_curve(device, $ec_curve) {
+                    return skip;
+                }
+                perform_ec_key_agreement(sec_level, $ec_curve);
+                MatrixOutcome::Passed
+            });
         }
     };
 }
 
+/// Key agreement was introduced with KeyMint, so Keymaster devices behind km_compat do not support
+/// it, and `CURVE_25519` was introduced with KeyMint V2. StrongBox implementations are only
+/// required to support the P-256 curve.
+fn skip_unsupported_curve(device: &DeviceUnderTest, ec_curve: EcCurve) -> Option<MatrixOutcome> {
+    if !device.is_at_least(KeyMintVersion::KeyMint1) {
+        Some(MatrixOutcome::Skipped(format!("{} does not support key agreement.", device)))
+    } else if ec_curve == EcCurve::CURVE_25519 && !device.is_at_least(KeyMintVersion::KeyMint2) {
+        Some(MatrixOutcome::Skipped(format!("{} does not support {:?}.", device, ec_curve)))
+    } else if device.is_strongbox() && ec_curve != EcCurve::P_256 {
+        Some(MatrixOutcome::Skipped(format!("StrongBox does not support {:?}.", ec_curve)))
+    } else {
+        None
+    }
+}
+
 // Get the KeyMint key's public part.
 fn get_keymint_public_key(keymint_key: &KeyMetadata) -> Result<PKey<Public>, ErrorStack> {
     let cert_bytes = keymint_key.certificate.as_ref().unwrap();
@@ -88,14 +113,15 @@ fn ec_curve_to_openrssl_curve_name(ec_curve: &EcCurve) -> Nid {
 
 /// Generate two EC keys with given curve from KeyMint and OpeanSSL. Perform local ECDH between
 /// them and verify that the derived secrets are the same.
-fn perform_ec_key_agreement(ec_curve: EcCurve) {
-    let keystore2 = get_keystore_service();
-    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+fn perform_ec_key_agreement(
+    sec_level: &binder::Strong<dyn IKeystoreSecurityLevel>,
+    ec_curve: EcCurve,
+) {
     let openssl_ec_curve = ec_curve_to_openrssl_curve_name(&ec_curve);
 
     let alias = format!("ks_ec_test_key_agree_{}", getuid());
     let keymint_key = key_generations::generate_ec_agree_key(
-        &sec_level,
+        sec_level,
         ec_curve,
         Digest::SHA_2_256,
         Domain::APP,
@@ -111,7 +137,7 @@ fn perform_ec_key_agreement(ec_curve: EcCurve) {
     let local_key = PKey::from_ec_key(ec_key).unwrap();
     let local_pub_key = local_key.public_key_to_der().unwrap();
 
-    check_agreement(&sec_level, &keymint_key.key, &keymint_pub_key, &local_key, &local_pub_key);
+    check_agreement(sec_level, &keymint_key.key, &keymint_pub_key, &local_key, &local_pub_key);
 }
 
 test_ec_key_agree!(test_ec_p224_key_agreement, EcCurve::P_224);
@@ -119,30 +145,34 @@ test_ec_key_agree!(test_ec_p256_key_agreement, EcCurve::P_256);
 test_ec_key_agree!(test_ec_p384_key_agreement, EcCurve::P_384);
 test_ec_key_agree!(test_ec_p521_key_agreement, EcCurve::P_521);
 
-/// Generate two EC keys with curve `CURVE_25519` from KeyMint and OpeanSSL.
-/// Perform local ECDH between them and verify that the derived secrets are the same.
+/// Generate two EC keys with curve `CURVE_25519` from KeyMint and OpeanSSL on every available
+/// device. Perform local ECDH between them and verify that the derived secrets are the same.
 #[test]
 fn keystore2_ec_25519_agree_key_success() {
-    let keystore2 = get_keystore_service();
-    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
-
-    let alias = format!("ks_ec_25519_test_key_agree_{}", getuid());
-    let keymint_key = key_generations::generate_ec_agree_key(
-        &sec_level,
-        EcCurve::CURVE_25519,
-        Digest::NONE,
-        Domain::APP,
-        -1,
-        Some(alias),
-    )
-    .unwrap();
-
-    let keymint_pub_key = get_keymint_public_key(&keymint_key).unwrap();
-
-    let local_key = PKey::generate_x25519().unwrap();
-    let local_pub_key = local_key.public_key_to_der().unwrap();
+    run_on_all_devices("keystore2_ec_25519_agree_key_success", |device, sec_level| {
+        if let Some(skip) = skip_unsupported_curve(device, EcCurve::CURVE_25519) {
+            return skip;
+        }
 
-    check_agreement(&sec_level, &keymint_key.key, &keymint_pub_key, &local_key, &local_pub_key);
+        let alias = format!("ks_ec_25519_test_key_agree_{}", getuid());
+        let keymint_key = key_generations::generate_ec_agree_key(
+            sec_level,
+            EcCurve::CURVE_25519,
+            Digest::NONE,
+            Domain::APP,
+            -1,
+            Some(alias),
+        )
+        .unwrap();
+
+        let keymint_pub_key = get_keymint_public_key(&keymint_key).unwrap();
+
+        let local_key = PKey::generate_x25519().unwrap();
+        let local_pub_key = local_key.public_key_to_der().unwrap();
+
+        check_agreement(sec_level, &keymint_key.key, &keymint_pub_key, &local_key, &local_pub_key);
+        MatrixOutcome::Passed
+    });
 }
 
 /// Generate two EC keys with different curves and try to perform local ECDH. Since keys are using
@@ -0,0 +1,288 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Negative path tests for the `keystore2_key` permissions. Every `KeyPermission` bit is
+//! exercised on its own: through grants for the bits that are checked on existing keys, and
+//! through the SELinux policy of an untrusted app for the bits that are checked on key
+//! creation. `GRANT` cannot be granted and is covered by the grant tests. `USE_DEV_ID` is not
+//! checked by keystore, so a grant that only carries it must not allow anything.
+
+use nix::unistd::{Gid, Uid};
+use rustutils::users::AID_USER_OFFSET;
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, KeyPurpose::KeyPurpose,
+    SecurityLevel::SecurityLevel,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor,
+    KeyPermission::KeyPermission, ResponseCode::ResponseCode,
+};
+
+use keystore2_test_utils::{
+    authorizations, get_keystore_service, key_generations, key_generations::Error, run_as,
+};
+
+use crate::keystore2_client_test_utils::{delete_app_key, generate_ec_key_and_grant_to_users};
+
+static SU_CTX: &str = "u:r:su:s0";
+static UNTRUSTED_APP_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+const USER_ID: u32 = 99;
+const APPLICATION_ID: u32 = 10001;
+static UNTRUSTED_APP_UID: u32 = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
+static UNTRUSTED_APP_GID: u32 = UNTRUSTED_APP_UID;
+
+/// All permissions that can be granted, i.e., all but `GRANT`.
+const GRANTABLE_PERMS: &[KeyPermission] = &[
+    KeyPermission::CONVERT_STORAGE_KEY_TO_EPHEMERAL,
+    KeyPermission::DELETE,
+    KeyPermission::GEN_UNIQUE_ID,
+    KeyPermission::GET_INFO,
+    KeyPermission::MANAGE_BLOB,
+    KeyPermission::REBIND,
+    KeyPermission::REQ_FORCED_OP,
+    KeyPermission::UPDATE,
+    KeyPermission::USE,
+    KeyPermission::USE_DEV_ID,
+];
+
+/// A call on a granted key.
+#[derive(Debug, Clone, Copy)]
+enum GrantedKeyCall {
+    GetKeyEntry,
+    CreateOperation,
+    CreateForcedOperation,
+    UpdateSubcomponent,
+    DeleteKey,
+}
+
+impl GrantedKeyCall {
+    /// All calls, in an order in which `DeleteKey` comes last, because it removes the key.
+    const ALL: [GrantedKeyCall; 5] = [
+        Self::GetKeyEntry,
+        Self::CreateOperation,
+        Self::CreateForcedOperation,
+        Self::UpdateSubcomponent,
+        Self::DeleteKey,
+    ];
+
+    /// The access vector that the grant must include for the call to succeed.
+    fn required_access_vector(self) -> i32 {
+        match self {
+            Self::GetKeyEntry => KeyPermission::GET_INFO.0,
+            Self::CreateOperation => KeyPermission::USE.0,
+            Self::CreateForcedOperation => KeyPermission::USE.0 | KeyPermission::REQ_FORCED_OP.0,
+            Self::UpdateSubcomponent => KeyPermission::UPDATE.0,
+            Self::DeleteKey => KeyPermission::DELETE.0,
+        }
+    }
+
+    fn run(
+        self,
+        keystore2: &binder::Strong<dyn IKeystoreService>,
+        key: &KeyDescriptor,
+    ) -> Result<(), Error> {
+        let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+        let op_params = authorizations::AuthSetBuilder::new()
+            .purpose(KeyPurpose::SIGN)
+            .digest(Digest::SHA_2_256);
+        key_generations::map_ks_error(match self {
+            Self::GetKeyEntry => keystore2.getKeyEntry(key).map(|_| ()),
+            Self::CreateOperation | Self::CreateForcedOperation => sec_level
+                .createOperation(key, &op_params, matches!(self, Self::CreateForcedOperation))
+                .and_then(|response| response.iOperation.unwrap().abort()),
+            Self::UpdateSubcomponent => keystore2.updateSubcomponent(key, Some(&[1; 32][..]), None),
+            Self::DeleteKey => keystore2.deleteKey(key),
+        })
+    }
+}
+
+/// Generates a key as root, grants it to the untrusted app with `access_vector`, and performs
+/// every `GrantedKeyCall` on it as the untrusted app. Each call must succeed if `access_vector`
+/// includes its required permissions and fail with `PERMISSION_DENIED` otherwise.
+fn check_granted_key_calls(alias: String, access_vector: i32) {
+    let grantor_alias = alias.clone();
+    // SAFETY: The test is run in a separate process with no other threads.
+    let grant_key_nspace = unsafe {
+        run_as::run_as(SU_CTX, Uid::from_raw(0), Gid::from_raw(0), move || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            generate_ec_key_and_grant_to_users(
+                &keystore2,
+                &sec_level,
+                Some(grantor_alias),
+                vec![UNTRUSTED_APP_UID.try_into().unwrap()],
+                access_vector,
+            )
+            .unwrap()
+            .remove(0)
+        })
+    };
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(
+            UNTRUSTED_APP_CTX,
+            Uid::from_raw(UNTRUSTED_APP_UID),
+            Gid::from_raw(UNTRUSTED_APP_GID),
+            move || {
+                let keystore2 = get_keystore_service();
+                let key = KeyDescriptor {
+                    domain: Domain::GRANT,
+                    nspace: grant_key_nspace,
+                    alias: None,
+                    blob: None,
+                };
+                for call in GrantedKeyCall::ALL {
+                    let required = call.required_access_vector();
+                    let result = call.run(&keystore2, &key);
+                    if access_vector & required == required {
+                        assert_eq!(
+                            Ok(()),
+                            result,
+                            "{call:?} with access vector {access_vector:#x}"
+                        );
+                    } else {
+                        assert_eq!(
+                            Err(Error::Rc(ResponseCode::PERMISSION_DENIED)),
+                            result,
+                            "{call:?} with access vector {access_vector:#x}"
+                        );
+                    }
+                }
+            },
+        )
+    };
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(SU_CTX, Uid::from_raw(0), Gid::from_raw(0), move || {
+            // The key is already gone if the grant included DELETE.
+            let _ = delete_app_key(&get_keystore_service(), &alias);
+        })
+    };
+}
+
+/// Generates a key with `domain` and `nspace` as the given identity. The generation must fail
+/// with `PERMISSION_DENIED` if `expect_denied` is set, and succeed otherwise.
+fn generate_key_as(
+    ctx: &str,
+    uid: u32,
+    domain: Domain,
+    nspace: i64,
+    gen_params: authorizations::AuthSetBuilder,
+    expect_denied: bool,
+) {
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        run_as::run_as(ctx, Uid::from_raw(uid), Gid::from_raw(uid), move || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            let alias = match domain {
+                Domain::BLOB => None,
+                _ => Some("ks_key_perm_test_gen_key".to_string()),
+            };
+            let key = KeyDescriptor { domain, nspace, alias, blob: None };
+            let result = key_generations::map_ks_error(sec_level.generateKey(
+                &key,
+                None,
+                &gen_params,
+                0,
+                b"entropy",
+            ));
+            if expect_denied {
+                assert_eq!(Some(Error::Rc(ResponseCode::PERMISSION_DENIED)), result.err());
+            } else {
+                assert!(result.is_ok(), "{:?}", result.err());
+                if domain != Domain::BLOB {
+                    keystore2.deleteKey(&key).unwrap();
+                }
+            }
+        })
+    }
+}
+
+fn ec_signing_key_params() -> authorizations::AuthSetBuilder {
+    authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256)
+}
+
+/// Grant a key with each grantable permission on its own. In grantee context only the calls that
+/// need exactly that permission should succeed, all others should fail with
+/// `PERMISSION_DENIED`.
+#[test]
+fn keystore2_key_perm_single_perm_grant() {
+    for perm in GRANTABLE_PERMS {
+        check_granted_key_calls(format!("ks_key_perm_single_{}", perm.0), perm.0);
+    }
+}
+
+/// Grant a key with all grantable permissions but one. In grantee context every call that needs
+/// the missing permission should fail with `PERMISSION_DENIED`, all others should succeed.
+#[test]
+fn keystore2_key_perm_all_but_one_perm_grant() {
+    let all = GRANTABLE_PERMS.iter().fold(0, |acc, perm| acc | perm.0);
+    for perm in GRANTABLE_PERMS {
+        check_granted_key_calls(format!("ks_key_perm_all_but_{}", perm.0), all & !perm.0);
+    }
+}
+
+/// Generate a key in the shell SELinux namespace. Root should succeed, an untrusted app lacks
+/// the `rebind` permission on the namespace and should fail with `PERMISSION_DENIED`.
+#[test]
+fn keystore2_key_perm_rebind_denied() {
+    let nspace = key_generations::SELINUX_SHELL_NAMESPACE;
+    generate_key_as(SU_CTX, 0, Domain::SELINUX, nspace, ec_signing_key_params(), false);
+    generate_key_as(
+        UNTRUSTED_APP_CTX,
+        UNTRUSTED_APP_UID,
+        Domain::SELINUX,
+        nspace,
+        ec_signing_key_params(),
+        true,
+    );
+}
+
+/// Generate a key with `Domain::BLOB` in the shell SELinux namespace. Root should succeed, an
+/// untrusted app lacks the `manage_blob` permission on the namespace and should fail with
+/// `PERMISSION_DENIED`.
+#[test]
+fn keystore2_key_perm_manage_blob_denied() {
+    let nspace = key_generations::SELINUX_SHELL_NAMESPACE;
+    generate_key_as(SU_CTX, 0, Domain::BLOB, nspace, ec_signing_key_params(), false);
+    generate_key_as(
+        UNTRUSTED_APP_CTX,
+        UNTRUSTED_APP_UID,
+        Domain::BLOB,
+        nspace,
+        ec_signing_key_params(),
+        true,
+    );
+}
+
+/// Generate an app key with `Tag::INCLUDE_UNIQUE_ID`. Root should succeed, an untrusted app
+/// lacks the `gen_unique_id` permission and should fail with `PERMISSION_DENIED`. No
+/// attestation is requested, because the untrusted app has no package to derive an
+/// attestation application id from.
+#[test]
+fn keystore2_key_perm_gen_unique_id_denied() {
+    let gen_params = || ec_signing_key_params().include_unique_id();
+    generate_key_as(SU_CTX, 0, Domain::APP, -1, gen_params(), false);
+    generate_key_as(UNTRUSTED_APP_CTX, UNTRUSTED_APP_UID, Domain::APP, -1, gen_params(), true);
+}
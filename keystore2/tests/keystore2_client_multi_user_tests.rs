@@ -0,0 +1,327 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use nix::unistd::{Gid, Uid};
+use rustutils::users::AID_USER_OFFSET;
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
+    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+};
+use android_security_authorization::aidl::android::security::authorization::{
+    IKeystoreAuthorization::IKeystoreAuthorization, LockScreenEvent::LockScreenEvent,
+};
+use android_security_maintenance::aidl::android::security::maintenance::IKeystoreMaintenance::IKeystoreMaintenance;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
+};
+
+use keystore2_test_utils::{
+    authorizations, get_keystore_service, key_generations, key_generations::Error, run_as,
+};
+
+use crate::keystore2_client_test_utils::{
+    execute_op_run_as_child, perform_sample_sign_operation, BarrierReached, ForcedOp, TestOutcome,
+};
+
+static MAINTENANCE_SERVICE_NAME: &str = "android.security.maintenance";
+static AUTH_SERVICE_NAME: &str = "android.security.authorization";
+static TARGET_CTX: &str = "u:r:untrusted_app:s0:c91,c256,c10,c20";
+const PASSWORD: &[u8] = b"multi user test password";
+const APPLICATION_ID: u32 = 10001;
+
+fn get_maintenance() -> binder::Strong<dyn IKeystoreMaintenance> {
+    binder::get_interface(MAINTENANCE_SERVICE_NAME).unwrap()
+}
+
+fn get_authorization() -> binder::Strong<dyn IKeystoreAuthorization> {
+    binder::get_interface(AUTH_SERVICE_NAME).unwrap()
+}
+
+/// Creates the given user afresh with an LSKF and unlocks it, so that it has all super keys.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn setup_user(user_id: i32) {
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                let maint_service = get_maintenance();
+                if let Err(e) = maint_service.onUserRemoved(user_id) {
+                    println!("onUserRemoved error: {:#?}", e);
+                }
+                maint_service
+                    .onUserPasswordChanged(user_id, Some(PASSWORD))
+                    .expect("Failed to set the LSKF of the user.");
+                get_authorization()
+                    .onLockScreenEvent(LockScreenEvent::UNLOCK, user_id, Some(PASSWORD), None)
+                    .expect("Failed to unlock the user.");
+            },
+        )
+    }
+}
+
+/// Removes the given user along with its keys.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn remove_user(user_id: i32) {
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                get_maintenance().onUserRemoved(user_id).expect("Failed to remove the user.");
+            },
+        )
+    }
+}
+
+/// Locks the given user, or unlocks it with its LSKF.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn set_user_locked(user_id: i32, locked: bool) {
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe {
+        run_as::run_as(
+            key_generations::TARGET_SU_CTX,
+            Uid::from_raw(0),
+            Gid::from_raw(0),
+            move || {
+                let auth_service = get_authorization();
+                if locked {
+                    auth_service.onLockScreenEvent(LockScreenEvent::LOCK, user_id, None, None)
+                } else {
+                    auth_service.onLockScreenEvent(
+                        LockScreenEvent::UNLOCK,
+                        user_id,
+                        Some(PASSWORD),
+                        None,
+                    )
+                }
+                .expect("Lock screen event failed.");
+            },
+        )
+    }
+}
+
+fn app_uid(user_id: i32, application_id: u32) -> u32 {
+    user_id as u32 * AID_USER_OFFSET + application_id
+}
+
+fn udr_key_descriptor(alias: &str) -> KeyDescriptor {
+    KeyDescriptor { domain: Domain::APP, nspace: -1, alias: Some(alias.to_string()), blob: None }
+}
+
+/// Runs `f` as the app `APPLICATION_ID` of the given user.
+///
+/// # Safety
+///
+/// Must be called from a process with no other threads.
+unsafe fn run_as_app<F, R>(user_id: i32, f: F) -> R
+where
+    R: serde::Serialize + serde::de::DeserializeOwned,
+    F: 'static + Send + FnOnce() -> R,
+{
+    let uid = app_uid(user_id, APPLICATION_ID);
+    // SAFETY: The caller guarantees that there are no other threads.
+    unsafe { run_as::run_as(TARGET_CTX, Uid::from_raw(uid), Gid::from_raw(uid), f) }
+}
+
+/// Generates an UNLOCKED_DEVICE_REQUIRED signing key with the given alias for the calling app.
+fn generate_udr_key(alias: &str) {
+    let sec_level =
+        get_keystore_service().getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let gen_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .purpose(KeyPurpose::SIGN)
+        .digest(Digest::SHA_2_256)
+        .ec_curve(EcCurve::P_256)
+        .unlocked_device_required();
+    key_generations::generate_key(&sec_level, &gen_params, alias).unwrap();
+}
+
+/// Signs a sample message with the key with the given alias of the calling app.
+fn sign_with_key(alias: &str) -> Result<(), Error> {
+    let sec_level =
+        get_keystore_service().getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+    let op_response = key_generations::map_ks_error(sec_level.createOperation(
+        &udr_key_descriptor(alias),
+        &op_params,
+        false,
+    ))?;
+    key_generations::map_ks_error(perform_sample_sign_operation(
+        op_response.iOperation.as_ref().unwrap(),
+    ))
+}
+
+/// Locks and unlocks user 99 while apps of user 0 have operations in flight. The operations of
+/// user 0 must not be affected and finish successfully.
+#[test]
+fn keystore2_lock_other_user_with_ops_in_flight() {
+    const USER_ID: i32 = 99;
+    const NUM_OPS: u32 = 3;
+    let alias = "ks_multi_user_in_flight_key";
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe { setup_user(USER_ID) };
+
+    let mut child_handles: Vec<_> = (0..NUM_OPS)
+        // SAFETY: The test is run in a separate process with no other threads.
+        .map(|i| unsafe {
+            execute_op_run_as_child(
+                TARGET_CTX,
+                Domain::APP,
+                key_generations::SELINUX_SHELL_NAMESPACE,
+                Some(alias.to_string()),
+                Uid::from_raw(app_uid(0, APPLICATION_ID + i)),
+                Gid::from_raw(app_uid(0, APPLICATION_ID + i)),
+                ForcedOp(false),
+            )
+        })
+        .collect();
+
+    // Wait until all operations of user 0 have begun.
+    for ch in child_handles.iter_mut() {
+        ch.recv();
+    }
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        set_user_locked(USER_ID, true);
+        set_user_locked(USER_ID, false);
+        set_user_locked(USER_ID, true);
+        set_user_locked(USER_ID, false);
+    }
+
+    for ch in child_handles.iter_mut() {
+        ch.send(&BarrierReached {});
+    }
+    for ch in child_handles.into_iter() {
+        assert_eq!(TestOutcome::Ok, ch.get_result());
+    }
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe { remove_user(USER_ID) };
+}
+
+/// Locking one user must only lock the UNLOCKED_DEVICE_REQUIRED keys of that user.
+///  1. Set up users 98 and 99, and generate an UNLOCKED_DEVICE_REQUIRED key in an app of each.
+///  2. Lock user 99. The key of user 99 must fail with `DEVICE_LOCKED`, while the key of user 98
+///     stays usable.
+///  3. Unlock user 99. Its key must be usable again.
+#[test]
+fn keystore2_lock_isolates_users() {
+    const LOCKED_USER_ID: i32 = 99;
+    const OTHER_USER_ID: i32 = 98;
+    static ALIAS: &str = "ks_multi_user_udr_key";
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    unsafe {
+        setup_user(LOCKED_USER_ID);
+        setup_user(OTHER_USER_ID);
+        for user_id in [LOCKED_USER_ID, OTHER_USER_ID] {
+            run_as_app(user_id, || generate_udr_key(ALIAS));
+        }
+
+        set_user_locked(LOCKED_USER_ID, true);
+        run_as_app(LOCKED_USER_ID, || {
+            assert_eq!(Err(Error::Km(ErrorCode::DEVICE_LOCKED)), sign_with_key(ALIAS));
+        });
+        run_as_app(OTHER_USER_ID, || assert_eq!(Ok(()), sign_with_key(ALIAS)));
+
+        set_user_locked(LOCKED_USER_ID, false);
+        run_as_app(LOCKED_USER_ID, || assert_eq!(Ok(()), sign_with_key(ALIAS)));
+
+        remove_user(LOCKED_USER_ID);
+        remove_user(OTHER_USER_ID);
+    }
+}
+
+/// An app of user 99 that exhausts the operation slots must not be able to prune the young
+/// operation of an app of user 0. Pruning is decided by the number and age of the operations of
+/// each owner, regardless of the user that the owner belongs to.
+#[test]
+fn keystore2_no_cross_user_pruning() {
+    const FLOODING_USER_ID: i32 = 99;
+    const MAX_OPS: i32 = 100;
+    let alias = "ks_multi_user_prune_key";
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    let mut child_handle = unsafe {
+        execute_op_run_as_child(
+            TARGET_CTX,
+            Domain::APP,
+            key_generations::SELINUX_SHELL_NAMESPACE,
+            Some(alias.to_string()),
+            Uid::from_raw(app_uid(0, APPLICATION_ID)),
+            Gid::from_raw(app_uid(0, APPLICATION_ID)),
+            ForcedOp(false),
+        )
+    };
+    child_handle.recv();
+
+    // SAFETY: The test is run in a separate process with no other threads.
+    let busy_count = unsafe {
+        run_as_app(FLOODING_USER_ID, move || {
+            let sec_level = get_keystore_service()
+                .getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT)
+                .unwrap();
+            let key_metadata = key_generations::generate_ec_p256_signing_key(
+                &sec_level,
+                Domain::APP,
+                -1,
+                Some(alias.to_string()),
+                None,
+            )
+            .unwrap();
+            let op_params = authorizations::AuthSetBuilder::new()
+                .purpose(KeyPurpose::SIGN)
+                .digest(Digest::SHA_2_256);
+
+            let mut ops = vec![];
+            let mut busy_count = 0;
+            for _ in 0..MAX_OPS {
+                match key_generations::map_ks_error(sec_level.createOperation(
+                    &key_metadata.key,
+                    &op_params,
+                    false,
+                )) {
+                    Ok(op_response) => ops.push(op_response),
+                    Err(Error::Rc(ResponseCode::BACKEND_BUSY)) => busy_count += 1,
+                    Err(e) => panic!("Unexpected error: {:?}", e),
+                }
+            }
+            busy_count
+        })
+    };
+    assert!(busy_count > 0);
+
+    // The operation of user 0 survived the flood.
+    child_handle.send(&BarrierReached {});
+    assert_eq!(TestOutcome::Ok, child_handle.get_result());
+}
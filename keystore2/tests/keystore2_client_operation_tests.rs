@@ -26,7 +26,8 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 
 use keystore2_test_utils::{
-    authorizations, get_keystore_service, key_generations, key_generations::Error, run_as,
+    authorizations, get_keystore_service, get_keystore_service_extension, key_generations,
+    key_generations::Error, run_as,
 };
 
 use crate::keystore2_client_test_utils::{
@@ -464,3 +465,37 @@ fn keystore2_op_fails_operation_busy() {
 
     assert!(result1 || result2);
 }
+
+/// Sign a message with a single `doOneShotOperation` call. A signature should be returned
+/// without creating an operation explicitly. Input that exceeds the operation data limit should
+/// be rejected with `TOO_MUCH_DATA`.
+#[test]
+fn keystore2_one_shot_sign_op_success() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some("ks_one_shot_op_test_key".to_string()),
+        None,
+    )
+    .unwrap();
+    let op_params =
+        authorizations::AuthSetBuilder::new().purpose(KeyPurpose::SIGN).digest(Digest::SHA_2_256);
+
+    let sec_level_ext = get_keystore_service_extension()
+        .getSecurityLevelExtension(SecurityLevel::TRUSTED_ENVIRONMENT)
+        .unwrap();
+
+    let signature =
+        sec_level_ext.doOneShotOperation(&key_metadata.key, &op_params, b"my message").unwrap();
+    assert!(signature.is_some());
+
+    let result = key_generations::map_ks_error(sec_level_ext.doOneShotOperation(
+        &key_metadata.key,
+        &op_params,
+        &[0; 0x8001],
+    ));
+    assert_eq!(Err(Error::Rc(ResponseCode::TOO_MUCH_DATA)), result);
+}
@@ -18,11 +18,12 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     CreateOperationResponse::CreateOperationResponse, Domain::Domain,
-    IKeystoreSecurityLevel::IKeystoreSecurityLevel,
+    IKeystoreSecurityLevel::IKeystoreSecurityLevel, ResponseCode::ResponseCode,
 };
 
 use keystore2_test_utils::{
-    authorizations, get_keystore_service, key_generations, key_generations::Error,
+    authorizations, get_keystore_service, get_keystore_service_extension, key_generations,
+    key_generations::Error,
 };
 
 use crate::keystore2_client_test_utils::{delete_app_key, perform_sample_sign_operation, ForcedOp};
@@ -1893,3 +1894,47 @@ fn keystore2_rsa_gen_keys_unsupported_size() {
     assert!(result.is_err());
     assert_eq!(Error::Km(ErrorCode::UNSUPPORTED_KEY_SIZE), result.unwrap_err());
 }
+
+/// Generate an RSA key for signing with `Digest::NONE` and PKCS#1 padding and sign a SHA-256
+/// hash with `signDigest`, which encodes the hash as `DigestInfo` in keystore. A hash with the
+/// wrong length for the given digest should be rejected with `INVALID_ARGUMENT`.
+#[test]
+fn keystore2_rsa_sign_digest_success() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+
+    let alias = "ks_rsa_sign_digest_test_key";
+    let key_metadata = key_generations::generate_rsa_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias.to_string()),
+        &key_generations::KeyParams {
+            key_size: 2048,
+            purpose: vec![KeyPurpose::SIGN],
+            padding: Some(PaddingMode::RSA_PKCS1_1_5_SIGN),
+            digest: Some(Digest::NONE),
+            mgf_digest: None,
+            block_mode: None,
+            att_challenge: None,
+        },
+        None,
+    )
+    .unwrap();
+
+    let sec_level_ext = get_keystore_service_extension()
+        .getSecurityLevelExtension(SecurityLevel::TRUSTED_ENVIRONMENT)
+        .unwrap();
+    let signature =
+        sec_level_ext.signDigest(&key_metadata.key, Digest::SHA_2_256, &[0x5a; 32]).unwrap();
+    assert_eq!(256, signature.len());
+
+    let result = key_generations::map_ks_error(sec_level_ext.signDigest(
+        &key_metadata.key,
+        Digest::SHA_2_256,
+        &[0x5a; 20],
+    ));
+    assert_eq!(Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)), result);
+
+    delete_app_key(&keystore2, alias).unwrap();
+}
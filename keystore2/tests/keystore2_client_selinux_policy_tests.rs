@@ -0,0 +1,325 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests generated from the SELinux policy loaded on the device. The app domains are taken from
+//! the `seapp_contexts` files and the `keystore2_key` namespaces from the
+//! `keystore2_key_contexts` files of all partitions. For every pair, the test asks the loaded
+//! policy whether the domain may list and create keys in the namespace and then checks that
+//! keystore agrees when called from that domain. This catches divergence between the policy and
+//! keystore's permission checks before CTS does.
+
+use nix::unistd::{Gid, Uid};
+use rustutils::users::AID_USER_OFFSET;
+use std::ffi::CString;
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
+};
+
+use keystore2_test_utils::{get_keystore_service, key_generations, key_generations::Error, run_as};
+
+static SU_CTX: &str = "u:r:su:s0";
+static KEYSTORE_CTX: &str = "u:r:keystore:s0";
+static KEYSTORE_SERVICE_CTX: &str = "u:object_r:keystore_service:s0";
+const USER_ID: u32 = 99;
+const APPLICATION_ID: u32 = 10001;
+static TEST_UID: u32 = USER_ID * AID_USER_OFFSET + APPLICATION_ID;
+static TEST_ALIAS: &str = "ks_selinux_policy_test_key";
+
+/// Directories that hold the policy files of the partitions.
+const POLICY_DIRS: &[&str] = &[
+    "/system/etc/selinux",
+    "/system_ext/etc/selinux",
+    "/product/etc/selinux",
+    "/vendor/etc/selinux",
+];
+
+/// All permissions of the `keystore2_key` class.
+const KEYSTORE2_KEY_PERMS: &[&str] = &[
+    "convert_storage_key_to_ephemeral",
+    "delete",
+    "gen_unique_id",
+    "get_info",
+    "grant",
+    "manage_blob",
+    "rebind",
+    "req_forced_op",
+    "update",
+    "use",
+    "use_dev_id",
+];
+
+/// Prefix of the domains of untrusted apps. Apps keep their keys in their own `Domain::APP`
+/// namespace, so the policy must never give them access to a `keystore2_key` namespace.
+static UNTRUSTED_APP_DOMAIN_PREFIX: &str = "untrusted_app";
+
+/// A `keystore2_key` namespace as listed in a `keystore2_key_contexts` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KeyNamespace {
+    nspace: i64,
+    context: String,
+}
+
+/// A check that keystore must agree with, as generated from the policy.
+#[derive(Debug, Clone)]
+struct AccessCase {
+    namespace: KeyNamespace,
+    /// Whether listing the namespace must succeed.
+    may_list: bool,
+    /// Whether generating a key in the namespace must succeed.
+    may_generate: bool,
+}
+
+/// Returns the contents of all policy files whose name ends with `suffix`.
+fn read_policy_files(suffix: &str) -> Vec<String> {
+    POLICY_DIRS
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(suffix))
+        .filter_map(|entry| std::fs::read_to_string(entry.path()).ok())
+        .collect()
+}
+
+/// Returns the lines of `contents` without comments and empty lines.
+fn policy_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Returns the domains that `seapp_contexts` assigns to apps.
+fn parse_seapp_domains(contents: &str) -> Vec<String> {
+    policy_lines(contents)
+        .flat_map(str::split_whitespace)
+        .filter_map(|token| token.strip_prefix("domain="))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Returns the namespaces listed in a `keystore2_key_contexts` file.
+fn parse_key_namespaces(contents: &str) -> Vec<KeyNamespace> {
+    policy_lines(contents)
+        .filter_map(|line| {
+            let mut tokens = line.split_whitespace();
+            let nspace = tokens.next()?.parse().ok()?;
+            let context = tokens.next()?.to_string();
+            Some(KeyNamespace { nspace, context })
+        })
+        .collect()
+}
+
+fn app_domains() -> Vec<String> {
+    let mut domains: Vec<String> =
+        read_policy_files("seapp_contexts").iter().flat_map(|c| parse_seapp_domains(c)).collect();
+    domains.sort();
+    domains.dedup();
+    domains
+}
+
+fn key_namespaces() -> Vec<KeyNamespace> {
+    let mut namespaces: Vec<KeyNamespace> = read_policy_files("keystore2_key_contexts")
+        .iter()
+        .flat_map(|c| parse_key_namespaces(c))
+        .collect();
+    namespaces.sort_by_key(|n| n.nspace);
+    namespaces.dedup_by_key(|n| n.nspace);
+    namespaces
+}
+
+fn domain_context(domain: &str) -> String {
+    format!("u:r:{domain}:s0")
+}
+
+/// Asks the loaded policy whether `source` has `perm` of `tclass` on `target`.
+fn policy_allows(source: &str, target: &str, tclass: &str, perm: &str) -> bool {
+    let source = CString::new(source).unwrap();
+    let target = CString::new(target).unwrap();
+    match keystore2_selinux::check_access(&source, &target, tclass, perm) {
+        Ok(()) => true,
+        Err(e) => match e.root_cause().downcast_ref::<keystore2_selinux::Error>() {
+            Some(keystore2_selinux::Error::PermissionDenied) => false,
+            _ => panic!("Access check failed: {e:?}"),
+        },
+    }
+}
+
+/// Returns true if the test can run code in `domain` that talks to keystore.
+fn can_reach_keystore(domain_ctx: &str) -> bool {
+    policy_allows(SU_CTX, domain_ctx, "process", "dyntransition")
+        && policy_allows(domain_ctx, KEYSTORE_SERVICE_CTX, "service_manager", "find")
+        && policy_allows(domain_ctx, KEYSTORE_CTX, "binder", "call")
+}
+
+/// Generates the access cases of `domain_ctx` from the loaded policy. Listing is also allowed
+/// by the `keystore2` permission `list`, see `IKeystoreService::listEntries`.
+fn access_cases(domain_ctx: &str, namespaces: &[KeyNamespace]) -> Vec<AccessCase> {
+    let may_list_all = policy_allows(domain_ctx, KEYSTORE_CTX, "keystore2", "list");
+    namespaces
+        .iter()
+        .map(|namespace| AccessCase {
+            namespace: namespace.clone(),
+            may_list: may_list_all
+                || policy_allows(domain_ctx, &namespace.context, "keystore2_key", "get_info"),
+            may_generate: policy_allows(domain_ctx, &namespace.context, "keystore2_key", "rebind"),
+        })
+        .collect()
+}
+
+/// Runs `cases` in `domain_ctx` and returns a description of every case in which keystore
+/// disagreed with the policy.
+fn run_access_cases(domain_ctx: &str, cases: Vec<AccessCase>) -> Vec<String> {
+    let ctx = domain_ctx.to_string();
+    // SAFETY: The test is run in a separate process with no other threads.
+    let (mismatches, generated) = unsafe {
+        run_as::run_as(domain_ctx, Uid::from_raw(TEST_UID), Gid::from_raw(TEST_UID), move || {
+            let keystore2 = get_keystore_service();
+            let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+            let mut mismatches = Vec::new();
+            let mut generated = Vec::new();
+            for case in cases {
+                let nspace = case.namespace.nspace;
+                let listed =
+                    key_generations::map_ks_error(keystore2.listEntries(Domain::SELINUX, nspace));
+                if let Some(mismatch) = compare(&ctx, &case, "listEntries", case.may_list, listed) {
+                    mismatches.push(mismatch);
+                }
+                let result =
+                    key_generations::map_ks_error(key_generations::generate_ec_p256_signing_key(
+                        &sec_level,
+                        Domain::SELINUX,
+                        nspace,
+                        Some(TEST_ALIAS.to_string()),
+                        None,
+                    ));
+                if result.is_ok() {
+                    generated.push(nspace);
+                }
+                if let Some(mismatch) =
+                    compare(&ctx, &case, "generateKey", case.may_generate, result)
+                {
+                    mismatches.push(mismatch);
+                }
+            }
+            (mismatches, generated)
+        })
+    };
+
+    if !generated.is_empty() {
+        // SAFETY: The test is run in a separate process with no other threads.
+        unsafe {
+            run_as::run_as(SU_CTX, Uid::from_raw(0), Gid::from_raw(0), move || {
+                let keystore2 = get_keystore_service();
+                for nspace in generated {
+                    let _ = keystore2.deleteKey(&KeyDescriptor {
+                        domain: Domain::SELINUX,
+                        nspace,
+                        alias: Some(TEST_ALIAS.to_string()),
+                        blob: None,
+                    });
+                }
+            })
+        };
+    }
+    mismatches
+}
+
+/// Returns a description of the mismatch if `result` does not match `allowed`. A call that the
+/// policy denies must fail with `PERMISSION_DENIED`.
+fn compare<T>(
+    domain_ctx: &str,
+    case: &AccessCase,
+    call: &str,
+    allowed: bool,
+    result: Result<T, Error>,
+) -> Option<String> {
+    match (allowed, result) {
+        (true, Ok(_)) => None,
+        (false, Err(Error::Rc(ResponseCode::PERMISSION_DENIED))) => None,
+        (_, result) => Some(format!(
+            "{call} from {domain_ctx} in namespace {} ({}): policy allows: {allowed}, \
+             keystore returned: {:?}",
+            case.namespace.nspace,
+            case.namespace.context,
+            result.err()
+        )),
+    }
+}
+
+#[test]
+fn keystore2_selinux_policy_parse_policy_files() {
+    let seapp = "# comment\n\
+                 user=_app seinfo=platform name=com.android.vpndialogs domain=vpn_app type=app_data_file\n\
+                 \n\
+                 user=_app isPrivApp=true domain=priv_app type=privapp_data_file levelFrom=user\n";
+    assert_eq!(vec!["vpn_app", "priv_app"], parse_seapp_domains(seapp));
+
+    let key_contexts = "# comment\n\
+                        100 u:object_r:wifi_key:s0\n\
+                        not_a_number u:object_r:bogus_key:s0\n\
+                        102   u:object_r:vold_key:s0\n";
+    assert_eq!(
+        vec![
+            KeyNamespace { nspace: 100, context: "u:object_r:wifi_key:s0".to_string() },
+            KeyNamespace { nspace: 102, context: "u:object_r:vold_key:s0".to_string() },
+        ],
+        parse_key_namespaces(key_contexts)
+    );
+}
+
+/// Untrusted apps keep their keys in their own app namespace. The policy must not grant them any
+/// permission on a `keystore2_key` namespace.
+#[test]
+fn keystore2_selinux_policy_untrusted_apps_have_no_key_namespaces() {
+    let namespaces = key_namespaces();
+    assert!(!namespaces.is_empty(), "No keystore2_key_contexts found.");
+    let violations: Vec<String> = app_domains()
+        .iter()
+        .filter(|domain| domain.starts_with(UNTRUSTED_APP_DOMAIN_PREFIX))
+        .flat_map(|domain| {
+            let domain_ctx = domain_context(domain);
+            namespaces.iter().flat_map(move |namespace| {
+                let domain_ctx = domain_ctx.clone();
+                KEYSTORE2_KEY_PERMS
+                    .iter()
+                    .filter(move |perm| {
+                        policy_allows(&domain_ctx, &namespace.context, "keystore2_key", perm)
+                    })
+                    .map(move |perm| format!("{domain} has {perm} on {}", namespace.context))
+            })
+        })
+        .collect();
+    assert!(violations.is_empty(), "{}", violations.join("\n"));
+}
+
+/// For every app domain that can talk to keystore and every `keystore2_key` namespace, listing
+/// and generating keys must succeed exactly if the loaded policy allows it.
+#[test]
+fn keystore2_selinux_policy_matches_keystore_decisions() {
+    let namespaces = key_namespaces();
+    assert!(!namespaces.is_empty(), "No keystore2_key_contexts found.");
+    let domains = app_domains();
+    assert!(!domains.is_empty(), "No seapp_contexts found.");
+
+    let mut mismatches = Vec::new();
+    for domain in domains {
+        let domain_ctx = domain_context(&domain);
+        if !can_reach_keystore(&domain_ctx) {
+            continue;
+        }
+        let cases = access_cases(&domain_ctx, &namespaces);
+        mismatches.extend(run_access_cases(&domain_ctx, cases));
+    }
+    assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+}
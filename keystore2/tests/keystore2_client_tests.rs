@@ -23,9 +23,12 @@ pub mod keystore2_client_hmac_key_tests;
 pub mod keystore2_client_import_keys_tests;
 pub mod keystore2_client_key_agreement_tests;
 pub mod keystore2_client_key_id_domain_tests;
+pub mod keystore2_client_key_permission_tests;
 pub mod keystore2_client_keystore_engine_tests;
 pub mod keystore2_client_list_entries_tests;
+pub mod keystore2_client_multi_user_tests;
 pub mod keystore2_client_operation_tests;
 pub mod keystore2_client_rsa_key_tests;
+pub mod keystore2_client_selinux_policy_tests;
 pub mod keystore2_client_test_utils;
 pub mod keystore2_client_update_subcomponent_tests;